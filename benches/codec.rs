@@ -0,0 +1,108 @@
+//! Decoder and encoder benchmarks on representative ZODB records.
+//!
+//! Run with `cargo bench`; compare against a saved baseline with
+//! `cargo bench -- --save-baseline main` and `--baseline main`.
+//! The records are built from their JSON form, so each one benchmarks
+//! the same document in both directions.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use serde_json::{json, Value};
+use zodb_json_codec::{decode_zodb_pickles, decode_zodb_record, encode_zodb_record};
+
+/// A small persistent object: a few scalars, a list and two references.
+fn small_persistent() -> Value {
+    json!({
+        "@cls": ["myapp.content", "Document"],
+        "@s": {
+            "title": "Quarterly report",
+            "description": "Numbers for the third quarter",
+            "count": 42,
+            "ratio": 0.75,
+            "published": true,
+            "tags": ["finance", "report", "q3"],
+            "__parent__": {"@ref": ["0000000000000001", "myapp.Folder"]},
+            "owner": {"@ref": ["00000000000000a2", "myapp.User"]},
+        },
+    })
+}
+
+/// A large `OOBucket` holding 5000 string pairs.
+fn oobucket_5k() -> Value {
+    let pairs: Vec<Value> = (0..5000)
+        .map(|i| json!([format!("key-{i:05}"), format!("value number {i}")]))
+        .collect();
+    json!({"@cls": ["BTrees.OOBTree", "OOBucket"], "@s": {"@kv": pairs}})
+}
+
+/// A catalog metadata bucket: record ids mapped to tuples full of dates.
+fn datetime_catalog() -> Value {
+    let pairs: Vec<Value> = (0..500)
+        .map(|i| {
+            json!([
+                i,
+                {"@t": [
+                    format!("Item {i}"),
+                    {"@dt": format!("2025-{:02}-{:02}T12:{:02}:00", i % 12 + 1, i % 28 + 1, i % 60)},
+                    {"@dt": format!("2025-06-15T08:{:02}:30.123456+02:00", i % 60)},
+                    {"@date": format!("2024-{:02}-01", i % 12 + 1)},
+                    null,
+                ]},
+            ])
+        })
+        .collect();
+    json!({"@cls": ["BTrees.IOBTree", "IOBucket"], "@s": {"@kv": pairs}})
+}
+
+/// A blob record: the state is a stub, the data lives in a file.
+fn blob_stub() -> Value {
+    json!({"@blob": true})
+}
+
+fn records() -> Vec<(&'static str, Value, Vec<u8>)> {
+    [
+        ("small_persistent", small_persistent()),
+        ("oobucket_5k", oobucket_5k()),
+        ("datetime_catalog", datetime_catalog()),
+        ("blob_stub", blob_stub()),
+    ]
+    .into_iter()
+    .map(|(name, doc)| {
+        let bytes = encode_zodb_record(doc.clone()).expect("encode fixture");
+        (name, doc, bytes)
+    })
+    .collect()
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for (name, _, bytes) in records() {
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function(format!("{name}/json"), |b| {
+            b.iter(|| decode_zodb_record(black_box(&bytes)).unwrap())
+        });
+        group.bench_function(format!("{name}/ast"), |b| {
+            b.iter(|| decode_zodb_pickles(black_box(&bytes)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for (name, doc, bytes) in records() {
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || doc.clone(),
+                |doc| encode_zodb_record(black_box(doc)).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode, bench_encode);
+criterion_main!(benches);
@@ -162,6 +162,18 @@ def _build_deep_dict(depth: int) -> dict:
     }
 
 
+def _file_state(size: int) -> dict:
+    """State of a blob-less OFS File carrying `size` bytes of binary data."""
+    data = hashlib.sha256(b"seed").digest() * (size // 32)
+    return {
+        "__name__": "report.pdf",
+        "title": "Quarterly report",
+        "content_type": "application/pdf",
+        "size": len(data),
+        "data": data,
+    }
+
+
 def generate_synthetic_data() -> dict[str, bytes]:
     """Generate named test datasets as ZODB record bytes."""
     return {
@@ -220,6 +232,14 @@ def generate_synthetic_data() -> dict[str, bytes]:
         "deep_nesting": make_zodb_record(
             "myapp", "DeepObj", _build_deep_dict(depth=10)
         ),
+        # OFS.Image.File without a blob: the payload lives inline in the
+        # record (one Pdata chunk is at most 64 KB, small files up to ~1 MB).
+        "file_inline_64k": make_zodb_record(
+            "OFS.Image", "File", _file_state(64 * 1024)
+        ),
+        "file_inline_1mb": make_zodb_record(
+            "OFS.Image", "File", _file_state(1024 * 1024)
+        ),
     }
 
 
@@ -0,0 +1,167 @@
+"""pytest-benchmark suite: the codec vs. pickle.loads + manual JSON.
+
+Uses the same representative records as the Rust criterion benches
+(``benches/codec.rs``).  Run against a release build::
+
+    maturin develop --release
+    pytest benchmarks/
+
+Save a run with ``--benchmark-autosave`` and compare two runs with
+``pytest-benchmark compare``.
+"""
+
+from datetime import date
+from datetime import datetime
+from datetime import timedelta
+from datetime import timezone
+
+import base64
+import io
+import json
+import pickle
+import pytest
+import sys
+import types
+import zodb_json_codec
+
+
+# Classes for the persistent reference hints, importable as myapp.Folder
+# and myapp.User like those of a real application.
+_myapp = types.ModuleType("myapp")
+for _name in ("Folder", "User"):
+    setattr(_myapp, _name, type(_name, (), {"__module__": "myapp"}))
+sys.modules.setdefault("myapp", _myapp)
+
+
+class _Ref:
+    """Stand-in for a persistent object, pickled as a persistent id."""
+
+    def __init__(self, oid, cls):
+        self.oid = oid
+        self.cls = cls
+
+
+class _RecordPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, _Ref):
+            return (obj.oid, obj.cls)
+        return None
+
+
+def _record(module, name, state):
+    """A ZODB record: class pickle + state pickle sharing one memo."""
+    f = io.BytesIO()
+    p = _RecordPickler(f, protocol=3)
+    p.dump((module, name))
+    p.dump(state)
+    return f.getvalue()
+
+
+def _small_persistent():
+    return _record(
+        "myapp.content",
+        "Document",
+        {
+            "title": "Quarterly report",
+            "description": "Numbers for the third quarter",
+            "count": 42,
+            "ratio": 0.75,
+            "published": True,
+            "tags": ["finance", "report", "q3"],
+            "__parent__": _Ref(b"\x00" * 7 + b"\x01", _myapp.Folder),
+            "owner": _Ref(b"\x00" * 7 + b"\xa2", _myapp.User),
+        },
+    )
+
+
+def _oobucket_5k():
+    items = []
+    for i in range(5000):
+        items += [f"key-{i:05}", f"value number {i}"]
+    return _record("BTrees.OOBTree", "OOBucket", (tuple(items),))
+
+
+def _datetime_catalog():
+    tz = timezone(timedelta(hours=2))
+    items = []
+    for i in range(500):
+        items += [
+            i,
+            (
+                f"Item {i}",
+                datetime(2025, i % 12 + 1, i % 28 + 1, 12, i % 60),
+                datetime(2025, 6, 15, 8, i % 60, 30, 123456, tzinfo=tz),
+                date(2024, i % 12 + 1, 1),
+                None,
+            ),
+        ]
+    return _record("BTrees.IOBTree", "IOBucket", (tuple(items),))
+
+
+def _blob_stub():
+    return _record("ZODB.blob", "Blob", None)
+
+
+RECORDS = {
+    "small_persistent": _small_persistent(),
+    "oobucket_5k": _oobucket_5k(),
+    "datetime_catalog": _datetime_catalog(),
+    "blob_stub": _blob_stub(),
+}
+
+
+def _to_json(obj):
+    if isinstance(obj, (datetime, date)):
+        return obj.isoformat()
+    if isinstance(obj, bytes):
+        return base64.b64encode(obj).decode("ascii")
+    if isinstance(obj, type):
+        return [obj.__module__, obj.__qualname__]
+    raise TypeError(type(obj).__name__)
+
+
+def _pickle_loads_json(data):
+    """The baseline: unpickle both pickles, then dump the state to JSON."""
+    up = pickle.Unpickler(io.BytesIO(data))
+    up.persistent_load = lambda ref: ref
+    cls = up.load()
+    state = up.load()
+    return json.dumps({"@cls": cls, "@s": state}, default=_to_json)
+
+
+def _pickle_dumps(cls, state):
+    f = io.BytesIO()
+    p = pickle.Pickler(f, protocol=3)
+    p.dump(cls)
+    p.dump(state)
+    return f.getvalue()
+
+
+@pytest.fixture(params=sorted(RECORDS))
+def name(request):
+    return request.param
+
+
+def test_decode_codec(benchmark, name):
+    benchmark.group = f"decode {name}"
+    benchmark(zodb_json_codec.decode_zodb_record_for_pg_json, RECORDS[name])
+
+
+def test_decode_pickle_json(benchmark, name):
+    benchmark.group = f"decode {name}"
+    benchmark(_pickle_loads_json, RECORDS[name])
+
+
+def test_encode_codec(benchmark, name):
+    benchmark.group = f"encode {name}"
+    record = zodb_json_codec.decode_zodb_record(RECORDS[name])
+    benchmark(zodb_json_codec.encode_zodb_record, record)
+
+
+def test_encode_pickle(benchmark, name):
+    benchmark.group = f"encode {name}"
+    up = pickle.Unpickler(io.BytesIO(RECORDS[name]))
+    up.persistent_load = lambda ref: ref
+    cls = up.load()
+    state = up.load()
+    benchmark(_pickle_dumps, cls, state)
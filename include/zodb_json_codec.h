@@ -0,0 +1,39 @@
+/*
+ * C API of zodb-json-codec (build with `cargo build --release --features capi`).
+ *
+ * Every function returns a ZJC_* status. On success *out holds the result,
+ * on failure a UTF-8 error message; either way release it with zjc_free().
+ * Output buffers are NUL-terminated; the terminator is not counted in
+ * *out_len.
+ */
+#ifndef ZODB_JSON_CODEC_H
+#define ZODB_JSON_CODEC_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define ZJC_OK 0
+#define ZJC_ERR_ARGUMENT 1 /* NULL pointer, or JSON input not UTF-8 */
+#define ZJC_ERR_CODEC 2    /* record or JSON could not be converted */
+#define ZJC_ERR_INTERNAL 3 /* internal error */
+
+/* ZODB record bytes -> {"@cls": ["module", "name"], "@s": {...}} */
+int32_t zjc_decode_record(const uint8_t *data, size_t len,
+                          uint8_t **out, size_t *out_len);
+
+/* {"@cls": [...], "@s": ...} JSON (UTF-8) -> ZODB record bytes */
+int32_t zjc_encode_record(const uint8_t *json, size_t len,
+                          uint8_t **out, size_t *out_len);
+
+/* Release a buffer returned by zjc_decode_record/zjc_encode_record. */
+void zjc_free(uint8_t *ptr);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ZODB_JSON_CODEC_H */
@@ -1,22 +1,134 @@
 """Fast pickle <-> JSON transcoder for ZODB, implemented in Rust."""
 
+from zodb_json_codec._rust import ClassQuotas
+from zodb_json_codec._rust import ClassRenames
+from zodb_json_codec._rust import CodecError
+from zodb_json_codec._rust import DecodeLimits
+from zodb_json_codec._rust import DecodePolicy
+from zodb_json_codec._rust import EncodeLimits
+from zodb_json_codec._rust import LineLimits
+from zodb_json_codec._rust import RawPicklePolicy
+from zodb_json_codec._rust import analyze_pickle
+from zodb_json_codec._rust import apply_patch_to_record
+from zodb_json_codec._rust import canonicalize_json
+from zodb_json_codec._rust import canonicalize_pickle
+from zodb_json_codec._rust import cbor_to_pickle
+from zodb_json_codec._rust import classify_btree
+from zodb_json_codec._rust import codec_info
+from zodb_json_codec._rust import clear_btree_registrations
+from zodb_json_codec._rust import collect_refs_ex
+from zodb_json_codec._rust import configure_logging
+from zodb_json_codec._rust import count_refs
+from zodb_json_codec._rust import decode_batch_async
+from zodb_json_codec._rust import decode_persistent_id
+from zodb_json_codec._rust import decode_pickle_ast
+from zodb_json_codec._rust import decode_transaction
 from zodb_json_codec._rust import decode_zodb_record
 from zodb_json_codec._rust import decode_zodb_record_for_pg
 from zodb_json_codec._rust import decode_zodb_record_for_pg_json
+from zodb_json_codec._rust import decode_zodb_state
+from zodb_json_codec._rust import diff_zodb_records
 from zodb_json_codec._rust import dict_to_pickle
+from zodb_json_codec._rust import encode_persistent_id
+from zodb_json_codec._rust import encode_pickle_ast
 from zodb_json_codec._rust import encode_zodb_record
+from zodb_json_codec._rust import encode_zodb_records_batch
+from zodb_json_codec._rust import encode_zodb_state
+from zodb_json_codec._rust import estimate_decoded_size
+from zodb_json_codec._rust import extract_paths
+from zodb_json_codec._rust import extract_subtree
+from zodb_json_codec._rust import find_class_references
+from zodb_json_codec._rust import graft_subtree
+from zodb_json_codec._rust import has_ref_to
+from zodb_json_codec._rust import hex_to_oid
+from zodb_json_codec._rust import iter_pickle_events
 from zodb_json_codec._rust import json_to_pickle
+from zodb_json_codec._rust import lint_record
+from zodb_json_codec._rust import oid_to_hex
+from zodb_json_codec._rust import open_filestorage
+from zodb_json_codec._rust import pickle_to_cbor
 from zodb_json_codec._rust import pickle_to_dict
 from zodb_json_codec._rust import pickle_to_json
+from zodb_json_codec._rust import raw_pickle_sha256
+from zodb_json_codec._rust import reachable_oids
+from zodb_json_codec._rust import read_zeo_cache
+from zodb_json_codec._rust import record_refs_to_edges
+from zodb_json_codec._rust import record_refs_to_edges_batch
+from zodb_json_codec._rust import register_btree_class
+from zodb_json_codec._rust import register_btree_module_prefix
+from zodb_json_codec._rust import register_type_handler
+from zodb_json_codec._rust import remap_oids
+from zodb_json_codec._rust import remap_storage
+from zodb_json_codec._rust import state_fingerprint
+from zodb_json_codec._rust import tid_to_timestamp
+from zodb_json_codec._rust import timestamp_to_tid
+from zodb_json_codec._rust import unregister_type_handler
+from zodb_json_codec._rust import verify_roundtrip
 
 
 __all__ = [
+    "ClassQuotas",
+    "ClassRenames",
+    "CodecError",
+    "DecodeLimits",
+    "DecodePolicy",
+    "EncodeLimits",
+    "LineLimits",
+    "RawPicklePolicy",
+    "analyze_pickle",
+    "apply_patch_to_record",
+    "canonicalize_json",
+    "canonicalize_pickle",
+    "cbor_to_pickle",
+    "classify_btree",
+    "codec_info",
+    "clear_btree_registrations",
+    "collect_refs_ex",
+    "configure_logging",
+    "count_refs",
+    "decode_batch_async",
+    "decode_persistent_id",
+    "decode_pickle_ast",
+    "decode_transaction",
     "decode_zodb_record",
     "decode_zodb_record_for_pg",
     "decode_zodb_record_for_pg_json",
+    "decode_zodb_state",
+    "diff_zodb_records",
     "dict_to_pickle",
+    "encode_persistent_id",
+    "encode_pickle_ast",
     "encode_zodb_record",
+    "encode_zodb_records_batch",
+    "encode_zodb_state",
+    "estimate_decoded_size",
+    "extract_paths",
+    "extract_subtree",
+    "find_class_references",
+    "graft_subtree",
+    "has_ref_to",
+    "hex_to_oid",
+    "iter_pickle_events",
     "json_to_pickle",
+    "lint_record",
+    "oid_to_hex",
+    "open_filestorage",
+    "pickle_to_cbor",
     "pickle_to_dict",
     "pickle_to_json",
+    "raw_pickle_sha256",
+    "reachable_oids",
+    "read_zeo_cache",
+    "record_refs_to_edges",
+    "record_refs_to_edges_batch",
+    "register_btree_class",
+    "register_btree_module_prefix",
+    "register_type_handler",
+    "remap_oids",
+    "remap_storage",
+    "state_fingerprint",
+    "tid_to_timestamp",
+    "timestamp_to_tid",
+    "unregister_type_handler",
+    "verify_roundtrip",
 ]
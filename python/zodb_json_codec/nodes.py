@@ -0,0 +1,48 @@
+"""Node classes of the pickle AST returned by ``decode_pickle_ast``."""
+
+from zodb_json_codec._rust import BackRef
+from zodb_json_codec._rust import Blocked
+from zodb_json_codec._rust import Bool
+from zodb_json_codec._rust import Bytes
+from zodb_json_codec._rust import Dict
+from zodb_json_codec._rust import Float
+from zodb_json_codec._rust import FrozenSet
+from zodb_json_codec._rust import Global
+from zodb_json_codec._rust import Instance
+from zodb_json_codec._rust import Int
+from zodb_json_codec._rust import List
+from zodb_json_codec._rust import NewObjEx
+from zodb_json_codec._rust import Null
+from zodb_json_codec._rust import PersistentRef
+from zodb_json_codec._rust import PickleNode
+from zodb_json_codec._rust import RawPickle
+from zodb_json_codec._rust import Reduce
+from zodb_json_codec._rust import Set
+from zodb_json_codec._rust import Shared
+from zodb_json_codec._rust import Str
+from zodb_json_codec._rust import Tuple
+
+
+__all__ = [
+    "BackRef",
+    "Blocked",
+    "Bool",
+    "Bytes",
+    "Dict",
+    "Float",
+    "FrozenSet",
+    "Global",
+    "Instance",
+    "Int",
+    "List",
+    "NewObjEx",
+    "Null",
+    "PersistentRef",
+    "PickleNode",
+    "RawPickle",
+    "Reduce",
+    "Set",
+    "Shared",
+    "Str",
+    "Tuple",
+]
@@ -0,0 +1,475 @@
+//! Pickle statistics without decoding.
+//!
+//! `analyze_pickle` walks the opcode stream with `skip_opcode` and models
+//! only the shape of the pickle machine: the stack holds placeholders (and
+//! the text strings STACK_GLOBAL needs to name a class), never decoded
+//! values. That makes it cheap enough to run over a whole storage for
+//! capacity planning or to find pathological records.
+//!
+//! `find_class_references` runs the same walk to list the classes a record
+//! needs, e.g. to find references to removed add-ons before a conversion.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::error::CodecError;
+use crate::info::opcode_name;
+use crate::limits::LineLimits;
+use crate::opcodes::*;
+use crate::zodb::skip_opcode;
+
+/// Statistics about one pickle or a whole ZODB record.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PickleStats {
+    /// Input size in bytes.
+    pub size: usize,
+    /// Number of pickles (STOP opcodes); 2 for a ZODB record.
+    pub pickles: usize,
+    /// Number of occurrences of each opcode, by `pickletools` name.
+    pub opcodes: BTreeMap<&'static str, usize>,
+    /// Most values on the pickle stack at any time (marks not counted).
+    pub max_stack_depth: usize,
+    /// Number of distinct memo entries stored.
+    pub memo_size: usize,
+    /// Number of persistent references (BINPERSID and PERSID).
+    pub persistent_refs: usize,
+    /// Length of the longest text string, in UTF-8 bytes as pickled.
+    pub max_string: usize,
+    /// Length of the longest byte string (including Python 2 `str`).
+    pub max_bytes: usize,
+    /// Classes and callables referenced by GLOBAL/STACK_GLOBAL/INST, as
+    /// `(module, name)` in order of first appearance.
+    pub classes: Vec<(String, String)>,
+}
+
+/// A stack entry: text strings are kept so STACK_GLOBAL can be resolved,
+/// and values naming a class so the class of a ZODB record can be read.
+#[derive(Clone, Copy)]
+enum Slot<'a> {
+    Str(&'a str),
+    /// A class, a `(module, name)` string pair or a `(class, args)` tuple.
+    Class(&'a str, &'a str),
+    Other,
+}
+
+struct Machine<'a> {
+    stack: Vec<Slot<'a>>,
+    marks: Vec<usize>,
+    max_depth: usize,
+}
+
+impl<'a> Machine<'a> {
+    fn push(&mut self, slot: Slot<'a>) {
+        self.stack.push(slot);
+        self.max_depth = self.max_depth.max(self.stack.len());
+    }
+
+    fn pop(&mut self) -> Result<Slot<'a>, CodecError> {
+        if self.marks.last() == Some(&self.stack.len()) {
+            return Err(CodecError::StackUnderflow);
+        }
+        self.stack.pop().ok_or(CodecError::StackUnderflow)
+    }
+
+    fn pop_n(&mut self, n: usize) -> Result<(), CodecError> {
+        for _ in 0..n {
+            self.pop()?;
+        }
+        Ok(())
+    }
+
+    fn top(&self) -> Result<Slot<'a>, CodecError> {
+        self.stack.last().copied().ok_or(CodecError::StackUnderflow)
+    }
+
+    fn pop_mark(&mut self) -> Result<(), CodecError> {
+        let mark = self.marks.pop().ok_or(CodecError::StackUnderflow)?;
+        self.stack.truncate(mark);
+        Ok(())
+    }
+}
+
+/// Collect statistics about `data`, which may hold one pickle or a whole
+/// ZODB record (the memo is shared across pickles, as in ZODB).
+///
+/// ```
+/// let stats = zodb_json_codec::analyze_pickle(b"\x80\x02]q\x00(K\x01K\x02e.").unwrap();
+/// assert_eq!(stats.opcodes["BININT1"], 2);
+/// assert_eq!(stats.max_stack_depth, 3);
+/// ```
+pub fn analyze_pickle(data: &[u8]) -> Result<PickleStats, CodecError> {
+    walk(data).map(|(stats, _)| stats)
+}
+
+/// The classes a pickle or ZODB record references, as `(module, name)`
+/// in order of first appearance, without decoding it.
+///
+/// Besides GLOBAL/STACK_GLOBAL/INST, which also cover the class hints of
+/// persistent references, this includes the class of a ZODB record when
+/// its class pickle names it by strings (`((module, name), None)`).
+///
+/// ```
+/// // (("myapp", "Doc"), None) and {"kind": myapp.Kind}
+/// let record = b"\x80\x02X\x05\x00\x00\x00myappX\x03\x00\x00\x00Doc\x86N\x86.\
+///                \x80\x02}X\x04\x00\x00\x00kindcmyapp\nKind\ns.";
+/// let classes = zodb_json_codec::find_class_references(record).unwrap();
+/// assert_eq!(classes, [("myapp".into(), "Doc".into()), ("myapp".into(), "Kind".into())]);
+/// ```
+pub fn find_class_references(data: &[u8]) -> Result<Vec<(String, String)>, CodecError> {
+    let (stats, record_class) = walk(data)?;
+    let mut classes = Vec::with_capacity(stats.classes.len() + 1);
+    if let Some(class) = record_class.filter(|_| stats.pickles > 1) {
+        classes.push(class);
+    }
+    for class in stats.classes {
+        if !classes.contains(&class) {
+            classes.push(class);
+        }
+    }
+    Ok(classes)
+}
+
+/// The opcode walk behind `analyze_pickle`, also returning the class the
+/// first pickle names, if any.
+fn walk(data: &[u8]) -> Result<(PickleStats, Option<(String, String)>), CodecError> {
+    let limits = LineLimits::default();
+    let mut stats = PickleStats {
+        size: data.len(),
+        ..PickleStats::default()
+    };
+    let mut counts = [0usize; 256];
+    let mut m = Machine {
+        stack: Vec::with_capacity(16),
+        marks: Vec::new(),
+        max_depth: 0,
+    };
+    let mut memo: HashMap<u32, Slot> = HashMap::new();
+    let mut seen: HashSet<(String, String)> = HashSet::new();
+    let mut first_class = None;
+    let mut pos = 0;
+    let mut op = STOP;
+    while pos < data.len() {
+        let next;
+        (op, next) = skip_opcode(data, pos, &limits)?;
+        if next > data.len() {
+            return Err(CodecError::UnexpectedEof);
+        }
+        counts[op as usize] += 1;
+        // The argument bytes, after the opcode
+        let arg = &data[pos + 1..next];
+        match op {
+            STOP => {
+                if let (Slot::Class(module, name), 0) = (m.pop()?, stats.pickles) {
+                    first_class = Some((module.to_string(), name.to_string()));
+                }
+                m.stack.clear();
+                m.marks.clear();
+                stats.pickles += 1;
+            }
+            PROTO | FRAME | READONLY_BUFFER => {}
+            MARK => m.marks.push(m.stack.len()),
+            POP => {
+                if m.marks.last() == Some(&m.stack.len()) {
+                    m.marks.pop();
+                } else {
+                    m.pop()?;
+                }
+            }
+            DUP => {
+                let top = m.top()?;
+                m.push(top);
+            }
+
+            NONE | NEWTRUE | NEWFALSE | INT | BININT | BININT1 | BININT2 | LONG | LONG1 | LONG4
+            | FLOAT | BINFLOAT | EMPTY_DICT | EMPTY_LIST | EMPTY_TUPLE | EMPTY_SET
+            | NEXT_BUFFER => m.push(Slot::Other),
+            UNICODE => {
+                // Raw-unicode-escape line: kept when it needs no unescaping
+                let line = &arg[..arg.len().saturating_sub(1)];
+                match std::str::from_utf8(line) {
+                    Ok(s) if !s.contains('\\') => m.push(Slot::Str(s)),
+                    _ => m.push(Slot::Other),
+                }
+            }
+            SHORT_BINUNICODE | BINUNICODE | BINUNICODE8 => {
+                let payload = &arg[length_prefix(op)..];
+                stats.max_string = stats.max_string.max(payload.len());
+                let s = std::str::from_utf8(payload).map_err(|_| CodecError::InvalidUtf8)?;
+                m.push(Slot::Str(s));
+            }
+            SHORT_BINBYTES | BINBYTES | BINBYTES8 | BYTEARRAY8 | SHORT_BINSTRING | BINSTRING => {
+                let payload = &arg[length_prefix(op)..];
+                stats.max_bytes = stats.max_bytes.max(payload.len());
+                // A Python 2 str may name the class of a record
+                match std::str::from_utf8(payload) {
+                    Ok(s) if op == SHORT_BINSTRING || op == BINSTRING => m.push(Slot::Str(s)),
+                    _ => m.push(Slot::Other),
+                }
+            }
+            STRING => {
+                // repr()-quoted text line: measure without quotes and newline
+                let len = arg.len().saturating_sub(3);
+                stats.max_bytes = stats.max_bytes.max(len);
+                let quoted = arg.get(..arg.len().saturating_sub(1)).unwrap_or_default();
+                let text = match quoted {
+                    [b'\'', s @ .., b'\''] | [b'"', s @ .., b'"'] if !s.contains(&b'\\') => {
+                        std::str::from_utf8(s).ok()
+                    }
+                    _ => None,
+                };
+                m.push(text.map_or(Slot::Other, Slot::Str));
+            }
+
+            APPEND | BUILD => m.pop_n(1)?,
+            SETITEM => m.pop_n(2)?,
+            APPENDS | SETITEMS | ADDITEMS => m.pop_mark()?,
+            TUPLE => {
+                let mark = *m.marks.last().ok_or(CodecError::StackUnderflow)?;
+                let slot = tuple_slot(&m.stack[mark..]);
+                m.pop_mark()?;
+                m.push(slot);
+            }
+            LIST | DICT | FROZENSET | OBJ => {
+                m.pop_mark()?;
+                m.push(Slot::Other);
+            }
+            TUPLE2 => {
+                let second = m.pop()?;
+                let first = m.pop()?;
+                m.push(tuple_slot(&[first, second]));
+            }
+            TUPLE1 | TUPLE3 => {
+                m.pop_n((op - TUPLE1 + 1) as usize)?;
+                m.push(Slot::Other);
+            }
+            REDUCE | NEWOBJ => {
+                m.pop_n(2)?;
+                m.push(Slot::Other);
+            }
+            NEWOBJ_EX => {
+                m.pop_n(3)?;
+                m.push(Slot::Other);
+            }
+
+            GLOBAL | INST => {
+                let text = std::str::from_utf8(arg).map_err(|_| CodecError::InvalidUtf8)?;
+                let mut lines = text.split('\n');
+                let module = lines.next().unwrap_or_default();
+                let name = lines.next().unwrap_or_default();
+                add_class(&mut stats.classes, &mut seen, module, name);
+                if op == INST {
+                    m.pop_mark()?;
+                    m.push(Slot::Other);
+                } else {
+                    m.push(Slot::Class(module, name));
+                }
+            }
+            STACK_GLOBAL => {
+                let name = m.pop()?;
+                let module = m.pop()?;
+                if let (Slot::Str(module), Slot::Str(name)) = (module, name) {
+                    add_class(&mut stats.classes, &mut seen, module, name);
+                    m.push(Slot::Class(module, name));
+                } else {
+                    m.push(Slot::Other);
+                }
+            }
+
+            BINPERSID => {
+                m.pop()?;
+                m.push(Slot::Other);
+                stats.persistent_refs += 1;
+            }
+            PERSID => {
+                m.push(Slot::Other);
+                stats.persistent_refs += 1;
+            }
+
+            PUT | BINPUT | LONG_BINPUT | MEMOIZE => {
+                let idx = match op {
+                    MEMOIZE => memo.len() as u32,
+                    _ => memo_index(op, arg)?,
+                };
+                memo.insert(idx, m.top()?);
+            }
+            GET | BINGET | LONG_BINGET => {
+                let idx = memo_index(op, arg)?;
+                let slot = *memo.get(&idx).ok_or_else(|| {
+                    CodecError::InvalidData(format!("memo index {idx} not found"))
+                })?;
+                m.push(slot);
+            }
+
+            _ => return Err(CodecError::UnknownOpcode(op)),
+        }
+        pos = next;
+    }
+    if op != STOP {
+        return Err(CodecError::UnexpectedEof);
+    }
+    stats.opcodes = counts
+        .iter()
+        .enumerate()
+        .filter(|(_, &n)| n > 0)
+        .map(|(op, &n)| (opcode_name(op as u8).unwrap_or("?"), n))
+        .collect();
+    stats.max_stack_depth = m.max_depth;
+    stats.memo_size = memo.len();
+    Ok((stats, first_class))
+}
+
+/// The slot of a tuple: a class for `(module, name)` strings and for
+/// `(class, args)`.
+fn tuple_slot<'a>(items: &[Slot<'a>]) -> Slot<'a> {
+    match items {
+        [Slot::Str(module), Slot::Str(name)] | [Slot::Class(module, name), _] => {
+            Slot::Class(module, name)
+        }
+        _ => Slot::Other,
+    }
+}
+
+/// Size of the length prefix of a counted string opcode.
+pub(crate) fn length_prefix(op: u8) -> usize {
+    match op {
+        SHORT_BINUNICODE | SHORT_BINBYTES | SHORT_BINSTRING => 1,
+        BINUNICODE8 | BINBYTES8 | BYTEARRAY8 => 8,
+        _ => 4,
+    }
+}
+
+/// The memo index argument of a PUT or GET opcode.
+pub(crate) fn memo_index(op: u8, arg: &[u8]) -> Result<u32, CodecError> {
+    match op {
+        BINPUT | BINGET => Ok(arg[0] as u32),
+        LONG_BINPUT | LONG_BINGET => Ok(u32::from_le_bytes(arg[..4].try_into().unwrap())),
+        _ => std::str::from_utf8(arg)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .ok_or_else(|| CodecError::InvalidData("invalid memo index".to_string())),
+    }
+}
+
+fn add_class(
+    classes: &mut Vec<(String, String)>,
+    seen: &mut HashSet<(String, String)>,
+    module: &str,
+    name: &str,
+) {
+    let key = (module.to_string(), name.to_string());
+    if seen.insert(key.clone()) {
+        classes.push(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        // Class pickle, then a state dict with a persistent reference:
+        // (("myapp", "Doc"), None) and {"title": "Hi", "ref": (b"\0"*7+b"\x03", None)}
+        let mut data = b"\x80\x03cmyapp\nDoc\nq\x00N\x86q\x01.".to_vec();
+        data.extend_from_slice(
+            b"\x80\x03}q\x02(X\x05\x00\x00\x00titleq\x03X\x02\x00\x00\x00Hiq\x04X\x03\x00\x00\x00refq\x05C\x08\x00\x00\x00\x00\x00\x00\x00\x03q\x06N\x86q\x07Qu.",
+        );
+        let stats = analyze_pickle(&data).unwrap();
+        assert_eq!(stats.pickles, 2);
+        assert_eq!(stats.size, data.len());
+        assert_eq!(stats.persistent_refs, 1);
+        assert_eq!(stats.memo_size, 8);
+        assert_eq!(stats.max_string, 5);
+        assert_eq!(stats.max_bytes, 8);
+        assert_eq!(
+            stats.classes,
+            vec![("myapp".to_string(), "Doc".to_string())]
+        );
+        assert_eq!(stats.opcodes["BINPUT"], 8);
+        assert_eq!(stats.opcodes["PROTO"], 2);
+        // dict, "title", "Hi", "ref", oid, None
+        assert_eq!(stats.max_stack_depth, 6);
+    }
+
+    #[test]
+    fn test_stack_global_through_memo() {
+        // Protocol 4: the module and name strings come back from the memo
+        // the second time the class is referenced.
+        let data = b"\x80\x04\x8c\x05myapp\x94\x8c\x03Doc\x94\x93\x94h\x00h\x01\x93\x86.";
+        let stats = analyze_pickle(data).unwrap();
+        assert_eq!(
+            stats.classes,
+            vec![("myapp".to_string(), "Doc".to_string())]
+        );
+        assert_eq!(stats.opcodes["STACK_GLOBAL"], 2);
+        assert_eq!(stats.memo_size, 3);
+    }
+
+    #[test]
+    fn test_inst_and_obj() {
+        // Python 2 classic class instances: INST and OBJ
+        let data = b"((I1\nimyapp\nP\n(cmyapp\nDoc\noI2\nl.";
+        let stats = analyze_pickle(data).unwrap();
+        assert_eq!(
+            stats.classes,
+            vec![
+                ("myapp".to_string(), "P".to_string()),
+                ("myapp".to_string(), "Doc".to_string())
+            ]
+        );
+        assert_eq!((stats.opcodes["INST"], stats.opcodes["OBJ"]), (1, 1));
+        // P instance, Doc instance, 2
+        assert_eq!(stats.max_stack_depth, 3);
+    }
+
+    #[test]
+    fn test_find_class_references() {
+        let class = |m: &str, n: &str| (m.to_string(), n.to_string());
+        // Class pickle (("myapp", "Doc"), None), then a state referencing
+        // a removed add-on and holding a persistent ref (oid, old.Folder)
+        let mut data = b"\x80\x02X\x05\x00\x00\x00myappX\x03\x00\x00\x00Doc\x86N\x86.".to_vec();
+        data.extend_from_slice(
+            b"\x80\x02}(X\x01\x00\x00\x00acgone.addon\nMarker\n\
+              X\x01\x00\x00\x00bU\x08\x00\x00\x00\x00\x00\x00\x00\x03cold\nFolder\n\x86Qu.",
+        );
+        assert_eq!(
+            find_class_references(&data).unwrap(),
+            vec![
+                class("myapp", "Doc"),
+                class("gone.addon", "Marker"),
+                class("old", "Folder")
+            ]
+        );
+        // Python 2, protocol 1: (("myapp", "Doc"), None) with SHORT_BINSTRING
+        let data = b"((U\x05myappq\x01U\x03Docq\x02tq\x03Ntq\x04.}q\x05.";
+        assert_eq!(find_class_references(data).unwrap(), vec![class("myapp", "Doc")]);
+        // Protocol 0, Python 2 and 3 strings
+        for data in [&b"((S'myapp'\nS'Doc'\ntNt.(dp0\n."[..], b"(Vmyapp\nVDoc\nt.(d."] {
+            assert_eq!(find_class_references(data).unwrap(), vec![class("myapp", "Doc")]);
+        }
+        // GLOBAL class pickle (klass, None)
+        let data = b"\x80\x02cmyapp\nDoc\nN\x86.\x80\x02}.";
+        assert_eq!(find_class_references(data).unwrap(), vec![class("myapp", "Doc")]);
+        // A lone pickle of a string pair is not a record class
+        let data = b"\x80\x02X\x01\x00\x00\x00aX\x01\x00\x00\x00b\x86.";
+        assert!(find_class_references(data).unwrap().is_empty());
+        assert!(find_class_references(b"\x80\x02").is_err());
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(matches!(
+            analyze_pickle(b"\x80\x02K\x01"),
+            Err(CodecError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            analyze_pickle(b"\x80\x02e."),
+            Err(CodecError::StackUnderflow)
+        ));
+        assert!(matches!(
+            analyze_pickle(b"\x80\x02h\x05."),
+            Err(CodecError::InvalidData(_))
+        ));
+        assert!(analyze_pickle(b"").unwrap().opcodes.is_empty());
+    }
+}
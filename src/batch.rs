@@ -0,0 +1,290 @@
+//! Batch decoding and encoding on a Rust thread pool.
+//!
+//! asyncio-based storage servers must not run CPU-bound decoding on the
+//! event loop thread. `decode_batch_async` hands a whole batch of records
+//! to the rayon pool, where they are decoded in parallel without the GIL,
+//! and resolves an asyncio future with the results. The caller needs no
+//! executor of its own.
+//!
+//! `encode_zodb_records_batch` goes the other way for bulk imports: the
+//! Python dicts are converted to `PickleValue` trees with the GIL held,
+//! then all records are encoded in parallel with the GIL released.
+
+use std::sync::OnceLock;
+
+use rayon::prelude::*;
+use rayon::ThreadPool;
+
+use crate::decode::{decode_zodb_pickles_with_options, DecodeOptions};
+use crate::encode::encode_value_into;
+use crate::error::CodecError;
+use crate::opcodes::{PROTO, STOP};
+use crate::json::pickle_value_to_json_string_pg;
+use crate::refscan::collect_refs_from_pickle_value;
+use crate::strict::check_strict;
+use crate::types::PickleValue;
+use crate::zodb::{self, build_class_pickle, extract_class_info};
+
+/// Stack size of the batch worker threads. Conversion is recursive up to
+/// the nesting limit of 1000 levels, which needs more than the 2 MB
+/// default stack in unoptimized builds.
+const WORKER_STACK_SIZE: usize = 16 * 1024 * 1024;
+
+/// The thread pool running batch work, created on first use with one
+/// thread per CPU.
+pub(crate) fn pool() -> &'static ThreadPool {
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        rayon::ThreadPoolBuilder::new()
+            .thread_name(|i| format!("zodb-json-codec-{i}"))
+            .stack_size(WORKER_STACK_SIZE)
+            .build()
+            .expect("failed to start the batch thread pool")
+    })
+}
+
+/// One record decoded for PostgreSQL JSONB storage.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PgJsonRecord {
+    pub module: String,
+    pub name: String,
+    pub state_json: String,
+    pub refs: Vec<i64>,
+}
+
+/// Decode a ZODB record to class info, PG-safe state JSON and ref OIDs.
+pub(crate) fn decode_for_pg_json(
+    data: &[u8],
+    strict: bool,
+    options: &DecodeOptions,
+) -> Result<PgJsonRecord, CodecError> {
+    let span = tracing::debug_span!(
+        "decode_zodb_record_for_pg_json",
+        size = data.len(),
+        module = tracing::field::Empty,
+        name = tracing::field::Empty,
+    );
+    let _entered = span.enter();
+    let (class_val, state_val) = decode_zodb_pickles_with_options(data, options)?;
+    if strict {
+        check_strict(&state_val)?;
+    }
+    let (module, name) = extract_class_info(&class_val);
+    span.record("module", module.as_str());
+    span.record("name", name.as_str());
+    let mut refs = Vec::new();
+    collect_refs_from_pickle_value(&state_val, &mut refs);
+    let state_json = pickle_value_to_json_string_pg(&state_val, &module, &name)?;
+    Ok(PgJsonRecord {
+        module,
+        name,
+        state_json,
+        refs,
+    })
+}
+
+/// Decode `records` in parallel, keeping their order.
+///
+/// `scopes` enters the caller's conversion settings (ref format and the
+/// like) on the worker that converts a record, and its guards are dropped
+/// once the record is done.
+///
+/// On failure, returns the index of a failing record with its error
+/// (with several failures, which one is reported is unspecified).
+pub(crate) fn decode_batch_for_pg_json<S>(
+    records: &[Vec<u8>],
+    options: &DecodeOptions,
+    scopes: impl Fn() -> S + Sync,
+) -> Result<Vec<PgJsonRecord>, (usize, CodecError)> {
+    pool().install(|| {
+        records
+            .par_iter()
+            .enumerate()
+            .map(|(i, data)| {
+                // Workers run on pool threads, which have their own scopes
+                let _scopes = scopes();
+                decode_for_pg_json(data, false, options).map_err(|e| (i, e))
+            })
+            .collect()
+    })
+}
+
+/// One ZODB record converted from its JSON form, ready for encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RecordToEncode {
+    pub module: String,
+    pub name: String,
+    pub state: PickleValue,
+    /// The `@cls_raw` class pickle to write instead of building one.
+    pub class_pickle: Option<Vec<u8>>,
+}
+
+/// Encode a record as class pickle + state pickle, framed like
+/// `encode_zodb_record_direct` output.
+pub(crate) fn encode_record(record: &RecordToEncode) -> Result<Vec<u8>, CodecError> {
+    if zodb::is_blob(&record.module, &record.name, record.state == PickleValue::None) {
+        return Ok(zodb::BLOB_RECORD.to_vec());
+    }
+    let mut buf = match &record.class_pickle {
+        Some(class_pickle) => class_pickle.clone(),
+        None => build_class_pickle(&record.module, &record.name),
+    };
+    if let PickleValue::RawPickle(data) = &record.state {
+        // A state kept as @pkl by lenient decoding is the state pickle itself
+        buf.extend_from_slice(data);
+        return Ok(buf);
+    }
+    buf.extend_from_slice(&[PROTO, 2]);
+    encode_value_into(&record.state, &mut buf)?;
+    buf.push(STOP);
+    Ok(buf)
+}
+
+/// Encode `records` in parallel, keeping their order.
+///
+/// `scopes` enters the caller's encoding settings (class renames and the
+/// like) on the worker that encodes a record, as for
+/// `decode_batch_for_pg_json`.
+///
+/// On failure, returns the index of a failing record with its error
+/// (with several failures, which one is reported is unspecified).
+pub(crate) fn encode_batch<S>(
+    records: &[RecordToEncode],
+    scopes: impl Fn() -> S + Sync,
+) -> Result<Vec<Vec<u8>>, (usize, CodecError)> {
+    pool().install(|| {
+        records
+            .par_iter()
+            .enumerate()
+            .map(|(i, record)| {
+                let _scopes = scopes();
+                encode_record(record).map_err(|e| (i, e))
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::decode::decode_zodb_pickles;
+    use crate::encode::encode_pickle;
+    use crate::rename::{ClassRenames, EncodeRenamesScope};
+    use crate::types::PickleValue;
+    use crate::zodb::{RefFormat, RefFormatScope};
+
+    fn record(n: i64) -> Vec<u8> {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::String("myapp".into()),
+            PickleValue::String("Doc".into()),
+        ]);
+        let state = PickleValue::Dict(vec![(PickleValue::String("n".into()), PickleValue::Int(n))]);
+        let mut out = encode_pickle(&class).unwrap();
+        out.extend(encode_pickle(&state).unwrap());
+        out
+    }
+
+    #[test]
+    fn test_batch_keeps_order() {
+        let records: Vec<Vec<u8>> = (0..100).map(record).collect();
+        let decoded = decode_batch_for_pg_json(&records, &DecodeOptions::new(), || ()).unwrap();
+        assert_eq!(decoded.len(), 100);
+        for (n, rec) in decoded.iter().enumerate() {
+            assert_eq!((rec.module.as_str(), rec.name.as_str()), ("myapp", "Doc"));
+            assert_eq!(rec.state_json, format!("{{\"n\":{n}}}"));
+            assert!(rec.refs.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_batch_reports_failing_index() {
+        let mut records: Vec<Vec<u8>> = (0..10).map(record).collect();
+        records[3] = b"\x80\x03".to_vec();
+        records[7] = b"garbage".to_vec();
+        let (index, _) =
+            decode_batch_for_pg_json(&records, &DecodeOptions::new(), || ()).unwrap_err();
+        assert!(index == 3 || index == 7, "{index}");
+    }
+
+    #[test]
+    fn test_batch_ref_format() {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::String("myapp".into()),
+            PickleValue::String("Doc".into()),
+        ]);
+        let oid = PickleValue::Bytes(42u64.to_be_bytes().to_vec());
+        let pid = PickleValue::Tuple(vec![oid, PickleValue::None]);
+        let state = PickleValue::Dict(vec![(
+            PickleValue::String("r".into()),
+            PickleValue::PersistentRef(Box::new(pid)),
+        )]);
+        let mut data = encode_pickle(&class).unwrap();
+        data.extend(encode_pickle(&state).unwrap());
+        let records = vec![data; 4];
+        let int_refs = || RefFormatScope::enter(RefFormat::Int);
+        let decoded = decode_batch_for_pg_json(&records, &DecodeOptions::new(), int_refs).unwrap();
+        for rec in decoded {
+            assert_eq!(rec.state_json, "{\"r\":{\"@ref\":42}}");
+        }
+    }
+
+    #[test]
+    fn test_encode_batch_roundtrip() {
+        let records: Vec<RecordToEncode> = (0..50)
+            .map(|n| RecordToEncode {
+                module: "myapp".into(),
+                name: "Doc".into(),
+                state: PickleValue::Dict(vec![(PickleValue::String("n".into()), PickleValue::Int(n))]),
+                class_pickle: None,
+            })
+            .collect();
+        let encoded = encode_batch(&records, || ()).unwrap();
+        assert_eq!(encoded.len(), 50);
+        for (record, data) in records.iter().zip(&encoded) {
+            let (class_val, state) = decode_zodb_pickles(data).unwrap();
+            assert_eq!(extract_class_info(&class_val), ("myapp".into(), "Doc".into()));
+            assert_eq!(state, record.state);
+        }
+    }
+
+    #[test]
+    fn test_encode_batch_renames() {
+        let renames = Arc::new(ClassRenames::new().rename_module("myapp", "newapp"));
+        let records = vec![
+            RecordToEncode {
+                module: "myapp".into(),
+                name: "Doc".into(),
+                state: PickleValue::None,
+                class_pickle: None,
+            };
+            4
+        ];
+        let scopes = || EncodeRenamesScope::enter(Some(Arc::clone(&renames)));
+        for data in encode_batch(&records, scopes).unwrap() {
+            let (class_val, _) = decode_zodb_pickles(&data).unwrap();
+            assert_eq!(extract_class_info(&class_val), ("newapp".into(), "Doc".into()));
+        }
+    }
+
+    #[test]
+    fn test_encode_batch_reports_failing_index() {
+        let mut deep = PickleValue::None;
+        for _ in 0..2000 {
+            deep = PickleValue::List(vec![deep]);
+        }
+        let mut records: Vec<RecordToEncode> = (0..5)
+            .map(|_| RecordToEncode {
+                module: "myapp".into(),
+                name: "Doc".into(),
+                state: PickleValue::None,
+                class_pickle: None,
+            })
+            .collect();
+        records[2].state = deep;
+        let (index, err) = encode_batch(&records, || ()).unwrap_err();
+        assert_eq!(index, 2);
+        assert!(err.to_string().contains("nesting depth"), "{err}");
+    }
+}
@@ -0,0 +1,141 @@
+//! JSON representation of integers outside the i64 range.
+//!
+//! Python ints are unbounded, JSON numbers are not: JavaScript and most
+//! JSON libraries read them as doubles, exact only up to 2^53. The codec
+//! therefore writes integers beyond i64 as `{"@bi": "digits"}` strings by
+//! default. Consumers that read big JSON integers exactly (orjson,
+//! PostgreSQL `numeric`, Python's `json`) can opt in to plain numbers up to
+//! a configurable magnitude for the conversions run inside
+//! [`with_bigint_policy`].
+//!
+//! Parsing is symmetric and independent of the policy: an integer JSON
+//! number outside i64 always decodes to an exact Python int, never to a
+//! float.
+
+use std::cell::Cell;
+
+use num_bigint::BigInt;
+
+use crate::error::CodecError;
+use crate::types::PickleValue;
+
+/// Largest `max_bits` accepted by [`with_bigint_policy`]: numbers are
+/// emitted through `i128`.
+pub const MAX_BIGINT_NUMBER_BITS: u32 = 127;
+
+thread_local! {
+    /// Magnitude bound for plain-number output on this thread; 0 means
+    /// always `@bi`.
+    static NUMBER_BITS: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Run `f` with the conversions it makes on this thread writing integers
+/// outside the i64 range as `max_bits` says.
+///
+/// With `Some(max_bits)`, integers whose magnitude is below
+/// `2**max_bits` are written as plain JSON numbers; larger ones still use
+/// `@bi`. `max_bits` must be between 64 and [`MAX_BIGINT_NUMBER_BITS`],
+/// otherwise `f` is not run. `None` is the default (always `@bi`).
+///
+/// ```
+/// use zodb_json_codec::{pickle_value_to_json, with_bigint_policy, PickleValue};
+///
+/// let big = PickleValue::BigInt(num_bigint::BigInt::from(1u128 << 70));
+/// let json = with_bigint_policy(Some(127), || pickle_value_to_json(&big))??;
+/// assert_eq!(json.to_string(), (1u128 << 70).to_string());
+/// assert!(with_bigint_policy(Some(200), || ()).is_err());
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn with_bigint_policy<R>(
+    max_bits: Option<u32>,
+    f: impl FnOnce() -> R,
+) -> Result<R, CodecError> {
+    let _scope = BigIntScope::enter(number_bits(max_bits)?);
+    Ok(f())
+}
+
+/// The plain-number magnitude bound for `max_bits`, 0 for `None`.
+pub(crate) fn number_bits(max_bits: Option<u32>) -> Result<u32, CodecError> {
+    match max_bits {
+        None => Ok(0),
+        Some(bits @ 64..=MAX_BIGINT_NUMBER_BITS) => Ok(bits),
+        Some(bits) => Err(CodecError::InvalidData(format!(
+            "bigint max_bits must be between 64 and {MAX_BIGINT_NUMBER_BITS}, got {bits}"
+        ))),
+    }
+}
+
+/// Sets the plain-number magnitude bound on the current thread while
+/// alive.
+pub(crate) struct BigIntScope {
+    previous: u32,
+}
+
+impl BigIntScope {
+    pub(crate) fn enter(bits: u32) -> Self {
+        BigIntScope {
+            previous: NUMBER_BITS.with(|b| b.replace(bits)),
+        }
+    }
+}
+
+impl Drop for BigIntScope {
+    fn drop(&mut self) {
+        NUMBER_BITS.with(|b| b.set(self.previous));
+    }
+}
+
+/// The value of `bi` if the policy allows writing it as a JSON number.
+#[inline]
+pub(crate) fn bigint_as_number(bi: &BigInt) -> Option<i128> {
+    number_within(bi, NUMBER_BITS.with(Cell::get))
+}
+
+#[inline]
+fn number_within(bi: &BigInt, max_bits: u32) -> Option<i128> {
+    if max_bits == 0 || bi.bits() > u64::from(max_bits) {
+        return None;
+    }
+    i128::try_from(bi).ok()
+}
+
+/// Parse the text of an integer JSON number that does not fit in i64.
+///
+/// Returns `None` for non-integer literals (fraction or exponent), which
+/// are read as floats.
+pub(crate) fn parse_big_json_integer(text: &str) -> Option<PickleValue> {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok().map(PickleValue::BigInt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_bounds() {
+        assert!(number_bits(Some(63)).is_err());
+        assert!(number_bits(Some(128)).is_err());
+        assert_eq!(number_bits(None).unwrap(), 0);
+        let bi = BigInt::from(1u128 << 100);
+        assert_eq!(number_within(&bi, 0), None);
+        assert_eq!(number_within(&bi, 101), Some(1i128 << 100));
+        assert_eq!(number_within(&-bi.clone(), 101), Some(-(1i128 << 100)));
+        assert_eq!(number_within(&bi, 100), None);
+        assert_eq!(number_within(&BigInt::from(u128::MAX), MAX_BIGINT_NUMBER_BITS), None);
+    }
+
+    #[test]
+    fn test_parse_big_json_integer() {
+        assert_eq!(
+            parse_big_json_integer("-18446744073709551616"),
+            Some(PickleValue::BigInt("-18446744073709551616".parse().unwrap()))
+        );
+        assert_eq!(parse_big_json_integer("1e30"), None);
+        assert_eq!(parse_big_json_integer("1.0"), None);
+        assert_eq!(parse_big_json_integer("-"), None);
+    }
+}
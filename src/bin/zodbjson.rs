@@ -0,0 +1,210 @@
+//! `zodbjson` -- inspect pickles and ZODB records from the command line.
+//!
+//! Reads a single pickle or a ZODB record (class pickle + state pickle)
+//! from a file or stdin, without a Python interpreter:
+//!
+//! ```text
+//! zodbjson decode [--indent N] [FILE]   pickle or record -> JSON
+//! zodbjson encode [FILE]                JSON -> pickle or record bytes
+//! zodbjson refs [FILE]                  persistent references as JSON
+//! zodbjson analyze [FILE]               opcode statistics as JSON
+//! ```
+//!
+//! Input with a second pickle after the first STOP is taken as a record;
+//! JSON with `@cls` (or `@blob`) is encoded as a record. Built with
+//! `cargo build --no-default-features --features cli`.
+
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+use serde_json::{json, Value};
+use zodb_json_codec::{
+    analyze_pickle, collect_refs_ex, decode_pickle, decode_zodb_pickles, decode_zodb_record,
+    encode_pickle, encode_zodb_record, find_pickle_end, json_to_pickle_value,
+    pickle_value_to_json_string, CodecError, PickleValue,
+};
+
+const USAGE: &str = "\
+usage: zodbjson <command> [options] [FILE]
+
+Reads FILE, or stdin if FILE is missing or '-'.
+
+commands:
+  decode [--indent N]  decode a pickle or ZODB record to JSON
+  encode               encode JSON to a pickle, or to a record if it has @cls
+  refs                 list the persistent references as JSON
+  analyze              print opcode statistics as JSON
+";
+
+/// Why the program stopped: bad arguments (exit 2) or a failed command
+/// (exit 1).
+enum Failure {
+    Usage(String),
+    Error(String),
+}
+
+impl From<CodecError> for Failure {
+    fn from(err: CodecError) -> Self {
+        Failure::Error(err.to_string())
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Error(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Failure {
+    fn from(err: serde_json::Error) -> Self {
+        Failure::Error(format!("invalid JSON: {err}"))
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::Usage(msg)) => {
+            eprintln!("zodbjson: {msg}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(Failure::Error(msg)) => {
+            eprintln!("zodbjson: error: {msg}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Failure> {
+    let Some((command, rest)) = args.split_first() else {
+        return Err(Failure::Usage("missing command".into()));
+    };
+    if command == "-h" || command == "--help" {
+        print!("{USAGE}");
+        return Ok(());
+    }
+    if !matches!(command.as_str(), "decode" | "encode" | "refs" | "analyze") {
+        return Err(Failure::Usage(format!("unknown command {command}")));
+    }
+    let mut indent = None;
+    let mut file = None;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--indent" if command == "decode" => {
+                let n = rest
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| Failure::Usage("--indent needs a number".into()))?;
+                indent = Some(n);
+            }
+            "-h" | "--help" => {
+                print!("{USAGE}");
+                return Ok(());
+            }
+            opt if opt.starts_with("--") => {
+                return Err(Failure::Usage(format!("unknown option {opt} for {command}")));
+            }
+            path if file.is_none() => file = Some(path),
+            extra => return Err(Failure::Usage(format!("unexpected argument {extra}"))),
+        }
+    }
+    let input = read_input(file)?;
+    let mut out = match command.as_str() {
+        "decode" => decode(&input, indent)?.into_bytes(),
+        "encode" => encode(&input)?,
+        "refs" => serde_json::to_string(&refs(&input)?)?.into_bytes(),
+        _ => serde_json::to_string(&analyze(&input)?)?.into_bytes(),
+    };
+    if command != "encode" {
+        out.push(b'\n');
+    }
+    io::stdout().lock().write_all(&out)?;
+    Ok(())
+}
+
+fn read_input(file: Option<&str>) -> Result<Vec<u8>, Failure> {
+    match file {
+        None | Some("-") => {
+            let mut data = Vec::new();
+            io::stdin().lock().read_to_end(&mut data)?;
+            Ok(data)
+        }
+        Some(path) => std::fs::read(path).map_err(|e| Failure::Error(format!("{path}: {e}"))),
+    }
+}
+
+/// Whether `data` holds more than one pickle, i.e. is a ZODB record.
+fn is_record(data: &[u8]) -> Result<bool, CodecError> {
+    Ok(find_pickle_end(data)? < data.len())
+}
+
+fn decode(data: &[u8], indent: Option<usize>) -> Result<String, Failure> {
+    if is_record(data)? {
+        let record = decode_zodb_record(data)?;
+        Ok(match indent {
+            Some(n) => {
+                let indent = " ".repeat(n);
+                let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+                let mut buf = Vec::new();
+                let mut ser = serde_json::Serializer::with_formatter(&mut buf, formatter);
+                serde::Serialize::serialize(&record, &mut ser)?;
+                String::from_utf8(buf).map_err(|e| Failure::Error(e.to_string()))?
+            }
+            None => serde_json::to_string(&record)?,
+        })
+    } else {
+        Ok(pickle_value_to_json_string(&decode_pickle(data)?, indent)?)
+    }
+}
+
+fn encode(data: &[u8]) -> Result<Vec<u8>, Failure> {
+    let doc: Value = serde_json::from_slice(data)?;
+    let is_record = doc
+        .as_object()
+        .is_some_and(|obj| obj.contains_key("@cls") || obj.contains_key("@blob"));
+    if is_record {
+        Ok(encode_zodb_record(doc)?)
+    } else {
+        Ok(encode_pickle(&json_to_pickle_value(&doc)?)?)
+    }
+}
+
+fn refs(data: &[u8]) -> Result<Value, Failure> {
+    let state: PickleValue = if is_record(data)? {
+        decode_zodb_pickles(data)?.1
+    } else {
+        decode_pickle(data)?
+    };
+    let refs = collect_refs_ex(&state)
+        .into_iter()
+        .map(|r| {
+            json!({
+                "oid": hex(&r.oid),
+                "class": r.class,
+                "database": r.database,
+            })
+        })
+        .collect();
+    Ok(Value::Array(refs))
+}
+
+fn analyze(data: &[u8]) -> Result<Value, Failure> {
+    let stats = analyze_pickle(data)?;
+    Ok(json!({
+        "size": stats.size,
+        "pickles": stats.pickles,
+        "opcodes": stats.opcodes,
+        "max_stack_depth": stats.max_stack_depth,
+        "memo_size": stats.memo_size,
+        "persistent_refs": stats.persistent_refs,
+        "max_string": stats.max_string,
+        "max_bytes": stats.max_bytes,
+        "classes": stats.classes,
+    }))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,103 @@
+//! Base64 and hex text encodings of binary data.
+//!
+//! Every `@b`, `@ns`, `@pkl` payload and every hex OID/TID goes through
+//! these helpers. They use SIMD implementations (`base64-simd`,
+//! `faster-hex`) that pick the best instruction set at runtime, which
+//! matters for records carrying large inline binary fields, e.g.
+//! `OFS.Image.File` objects without a blob. Short inputs such as 8-byte
+//! OIDs take a scalar path.
+//!
+//! Base64 is the standard alphabet with padding; hex encodes lowercase and
+//! decodes either case.
+
+use base64_simd::STANDARD;
+
+use crate::error::CodecError;
+
+fn b64_error() -> CodecError {
+    CodecError::Json("base64 decode: invalid base64 data".to_string())
+}
+
+/// Base64-encode `data`.
+#[inline]
+pub(crate) fn b64_encode(data: impl AsRef<[u8]>) -> String {
+    STANDARD.encode_to_string(data)
+}
+
+/// Append the base64 encoding of `data` to `out`.
+#[inline]
+pub(crate) fn b64_encode_into(data: &[u8], out: &mut String) {
+    STANDARD.encode_append(data, out);
+}
+
+/// Decode standard padded base64.
+#[inline]
+pub(crate) fn b64_decode(s: impl AsRef<[u8]>) -> Result<Vec<u8>, CodecError> {
+    STANDARD.decode_to_vec(s).map_err(|_| b64_error())
+}
+
+/// Decode standard padded base64, appending the bytes to `out`.
+#[inline]
+pub(crate) fn b64_decode_into(s: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError> {
+    STANDARD.decode_append(s, out).map_err(|_| b64_error())
+}
+
+/// Lowercase hex encoding of `data`.
+#[inline]
+pub(crate) fn hex_encode(data: impl AsRef<[u8]>) -> String {
+    faster_hex::hex_string(data.as_ref())
+}
+
+/// Decode hex digits (either case).
+pub(crate) fn hex_decode(s: impl AsRef<[u8]>) -> Result<Vec<u8>, CodecError> {
+    let s = s.as_ref();
+    if s.len() % 2 != 0 {
+        return Err(CodecError::Json("hex decode: odd number of digits".to_string()));
+    }
+    let mut out = vec![0u8; s.len() / 2];
+    faster_hex::hex_decode(s, &mut out)
+        .map_err(|_| CodecError::Json("hex decode: invalid hex digit".to_string()))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_b64_roundtrip() {
+        assert_eq!(b64_encode(b""), "");
+        assert_eq!(b64_encode(b"\x00\x00\x00\x00\x00\x00\x00\x03"), "AAAAAAAAAAM=");
+        assert_eq!(b64_decode("aGVsbG8=").unwrap(), b"hello");
+        // Long enough for the vectorized path, with every byte value
+        let data: Vec<u8> = (0..=255u8).cycle().take(100_003).collect();
+        let mut s = String::from("x");
+        b64_encode_into(&data, &mut s);
+        assert_eq!(&s[1..], b64_encode(&data));
+        assert_eq!(b64_decode(&s[1..]).unwrap(), data);
+        let mut out = b"y".to_vec();
+        b64_decode_into(&s.as_bytes()[1..], &mut out).unwrap();
+        assert_eq!(&out[1..], &data[..]);
+    }
+
+    #[test]
+    fn test_b64_invalid() {
+        for bad in ["aGVsbG8", "aGVs!G8=", "aGVsbG8=="] {
+            let err = b64_decode(bad).unwrap_err();
+            assert!(matches!(err.root(), CodecError::Json(_)), "{bad}: {err}");
+            assert!(err.to_string().contains("base64 decode"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex_encode(b"\x00\x01\xab\xff"), "0001abff");
+        assert_eq!(hex_decode("0001abff").unwrap(), b"\x00\x01\xab\xff");
+        assert_eq!(hex_decode("0001ABFF").unwrap(), b"\x00\x01\xab\xff");
+        assert_eq!(hex_decode("").unwrap(), b"");
+        let data: Vec<u8> = (0..=255u8).cycle().take(4099).collect();
+        assert_eq!(hex_decode(hex_encode(&data)).unwrap(), data);
+        assert!(hex_decode("abc").is_err());
+        assert!(hex_decode("zz").is_err());
+    }
+}
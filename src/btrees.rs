@@ -8,6 +8,9 @@
 //! This module recognizes these patterns and produces flat, queryable JSON
 //! with `@kv` (key-value pairs) and `@ks` (keys) markers.
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
 use serde_json::{json, Map, Value};
 
 use crate::error::CodecError;
@@ -31,53 +34,372 @@ pub enum BTreeNodeKind {
     Set,
 }
 
+/// Key or value type of a BTree family, from the two-letter class prefix
+/// (`IO` in `IOBTree`: integer keys, object values).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BTreeValueType {
+    /// `O` — any picklable object
+    Object,
+    /// `I` — signed 32-bit integer
+    Int32,
+    /// `L` — signed 64-bit integer
+    Int64,
+    /// `U` — unsigned 32-bit integer
+    UInt32,
+    /// `Q` — unsigned 64-bit integer
+    UInt64,
+    /// `F` — 32-bit float
+    Float32,
+    /// `fs` — fixed-size bytes (2-byte keys, 6-byte values in `fsBTree`)
+    Bytes(usize),
+}
+
+impl BTreeValueType {
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            b'O' => Some(Self::Object),
+            b'I' => Some(Self::Int32),
+            b'L' => Some(Self::Int64),
+            b'U' => Some(Self::UInt32),
+            b'Q' => Some(Self::UInt64),
+            b'F' => Some(Self::Float32),
+            _ => None,
+        }
+    }
+
+    /// The type code as it appears in class names (`"O"`, `"I"`, ...,
+    /// `"fs"`).
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Object => "O",
+            Self::Int32 => "I",
+            Self::Int64 => "L",
+            Self::UInt32 => "U",
+            Self::UInt64 => "Q",
+            Self::Float32 => "F",
+            Self::Bytes(_) => "fs",
+        }
+    }
+
+    /// The JSON type such a key or value is rendered as: `"int"`,
+    /// `"float"`, `"bytes"` (an `@b` marker) or `"any"`.
+    pub fn json_type(&self) -> &'static str {
+        match self {
+            Self::Object => "any",
+            Self::Int32 | Self::Int64 | Self::UInt32 | Self::UInt64 => "int",
+            Self::Float32 => "float",
+            Self::Bytes(_) => "bytes",
+        }
+    }
+
+    /// Whether `value` can be stored as a key or value of this type.
+    ///
+    /// Integers must fit the type's range, floats also accept integers and
+    /// fixed-size bytes must have the exact length. Booleans count as
+    /// integers, as in Python.
+    ///
+    /// ```
+    /// use zodb_json_codec::{BTreeValueType, PickleValue};
+    ///
+    /// assert!(BTreeValueType::Int32.accepts(&PickleValue::Int(7)));
+    /// assert!(!BTreeValueType::Int32.accepts(&PickleValue::Int(1 << 40)));
+    /// assert!(!BTreeValueType::Int32.accepts(&PickleValue::String("7".into())));
+    /// assert!(BTreeValueType::Object.accepts(&PickleValue::String("7".into())));
+    /// ```
+    pub fn accepts(&self, value: &PickleValue) -> bool {
+        match (self, value) {
+            (Self::Object, _) => true,
+            (
+                Self::Int32 | Self::Int64 | Self::UInt32 | Self::UInt64 | Self::Float32,
+                PickleValue::Bool(_),
+            ) => true,
+            (Self::Int32, PickleValue::Int(i)) => i32::try_from(*i).is_ok(),
+            (Self::Int64, PickleValue::Int(_)) => true,
+            (Self::UInt32, PickleValue::Int(i)) => u32::try_from(*i).is_ok(),
+            (Self::UInt64, PickleValue::Int(i)) => *i >= 0,
+            (Self::UInt64, PickleValue::BigInt(b)) => u64::try_from(b).is_ok(),
+            (Self::Float32, PickleValue::Int(_) | PickleValue::Float(_)) => true,
+            (Self::Bytes(n), PickleValue::Bytes(b)) => b.len() == *n,
+            _ => false,
+        }
+    }
+
+    /// What [`accepts`](Self::accepts) expects, for error messages.
+    fn expected(&self) -> String {
+        match self {
+            Self::Object => "any object".to_string(),
+            Self::Int32 => "a signed 32-bit int".to_string(),
+            Self::Int64 => "a signed 64-bit int".to_string(),
+            Self::UInt32 => "an unsigned 32-bit int".to_string(),
+            Self::UInt64 => "an unsigned 64-bit int".to_string(),
+            Self::Float32 => "a float".to_string(),
+            Self::Bytes(n) => format!("{n} bytes"),
+        }
+    }
+}
+
+/// Short description of a value for type errors.
+fn describe_value(value: &PickleValue) -> String {
+    match value {
+        PickleValue::None => "null".to_string(),
+        PickleValue::Bool(_) => "bool".to_string(),
+        PickleValue::Int(i) => format!("int {i}"),
+        PickleValue::BigInt(b) => format!("int {b}"),
+        PickleValue::Float(f) => format!("float {f}"),
+        PickleValue::String(_) => "string".to_string(),
+        PickleValue::Bytes(b) => format!("{} bytes", b.len()),
+        PickleValue::List(_) => "list".to_string(),
+        PickleValue::Tuple(_) => "tuple".to_string(),
+        PickleValue::Dict(_) => "dict".to_string(),
+        PickleValue::PersistentRef(_) => "persistent ref".to_string(),
+        _ => "object".to_string(),
+    }
+}
+
+/// Check a key or value against its BTree family type, naming the JSON
+/// path of the item (e.g. `@kv[3][0]`) in the error.
+///
+/// `path` is only evaluated on failure.
+pub(crate) fn check_typed_item(
+    ty: Option<BTreeValueType>,
+    role: &str,
+    value: &PickleValue,
+    path: &dyn Fn() -> String,
+) -> Result<(), CodecError> {
+    match ty {
+        Some(ty) if !ty.accepts(value) => Err(CodecError::InvalidData(format!(
+            "{}: BTree {role} of type {} must be {}, got {}",
+            path(),
+            ty.code(),
+            ty.expected(),
+            describe_value(value)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a family type needs checking at all (`O` accepts anything).
+pub(crate) fn is_typed(ty: Option<BTreeValueType>) -> bool {
+    matches!(ty, Some(ty) if ty != BTreeValueType::Object)
+}
+
+/// Check flat bucket data against the key and value types of the family,
+/// so that corrupt JSON fails at encode time instead of producing a pickle
+/// the C BTree code rejects at load time.
+///
+/// Map items alternate key and value (`@kv`), set items are keys (`@ks`).
+pub fn validate_flat_data(info: &BTreeClassInfo, items: &[PickleValue]) -> Result<(), CodecError> {
+    if !is_typed(info.key_type) && !is_typed(info.value_type) {
+        return Ok(());
+    }
+    for (i, item) in items.iter().enumerate() {
+        if !info.is_map {
+            check_typed_item(info.key_type, "key", item, &|| format!("@ks[{i}]"))?;
+        } else if i % 2 == 0 {
+            check_typed_item(info.key_type, "key", item, &|| format!("@kv[{}][0]", i / 2))?;
+        } else {
+            check_typed_item(info.value_type, "value", item, &|| format!("@kv[{}][1]", i / 2))?;
+        }
+    }
+    Ok(())
+}
+
+/// Check the separator keys of a large BTree's `@children`
+/// (`[child, key, child, ..., child]`) against the family's key type.
+pub fn validate_children(
+    info: &BTreeClassInfo,
+    children: &[PickleValue],
+) -> Result<(), CodecError> {
+    if !is_typed(info.key_type) {
+        return Ok(());
+    }
+    for (i, item) in children.iter().enumerate().skip(1).step_by(2) {
+        check_typed_item(info.key_type, "key", item, &|| format!("@children[{i}]"))?;
+    }
+    Ok(())
+}
+
 /// Classification result for a BTree class.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct BTreeClassInfo {
     pub kind: BTreeNodeKind,
     /// Whether this is a map type (has values) vs set type (keys only).
     pub is_map: bool,
+    /// Key type, parsed from the class name prefix. `None` if the name
+    /// carries no known family prefix (e.g. explicitly registered classes).
+    pub key_type: Option<BTreeValueType>,
+    /// Value type for map types; always `None` for set types.
+    pub value_type: Option<BTreeValueType>,
+}
+
+impl BTreeClassInfo {
+    /// The number of entries BTrees keeps in one bucket of this family
+    /// before splitting it: 30 for `OO`, 60 with one object side, 120 for
+    /// numeric families and 500 for `fs`. Unknown types count as objects.
+    pub fn max_bucket_size(&self) -> usize {
+        use BTreeValueType::{Bytes, Object};
+        let is_object = |ty: Option<BTreeValueType>| matches!(ty, None | Some(Object));
+        match (self.key_type, self.value_type) {
+            (Some(Bytes(_)), _) => 500,
+            (key, _) if !self.is_map => if is_object(key) { 30 } else { 120 },
+            (key, value) => match (is_object(key), is_object(value)) {
+                (true, true) => 30,
+                (false, false) => 120,
+                _ => 60,
+            },
+        }
+    }
 }
 
 /// Check if a class is a BTree type and classify it.
 /// Returns None for non-BTree classes (including BTrees.Length.Length).
+///
+/// Besides the `BTrees.*` modules, classes registered with
+/// [`register_btree_class`] or living under a module prefix registered with
+/// [`register_btree_module_prefix`] are classified too, so BTree subclasses
+/// from other packages flatten the same way.
+///
+/// ```
+/// use zodb_json_codec::{classify_btree, BTreeNodeKind, BTreeValueType};
+///
+/// let info = classify_btree("BTrees.OOBTree", "OOBucket").unwrap();
+/// assert_eq!(info.kind, BTreeNodeKind::Bucket);
+/// assert!(info.is_map);
+/// assert_eq!(info.key_type, Some(BTreeValueType::Object));
+/// assert!(classify_btree("BTrees.Length", "Length").is_none());
+/// ```
 pub fn classify_btree(module: &str, name: &str) -> Option<BTreeClassInfo> {
     // Must be in a BTrees.* module
     if !module.starts_with("BTrees.") {
-        return None;
+        return classify_registered(module, name);
     }
 
-    // Skip BTrees.Length — it stores a scalar int, already works fine
+    // Skip BTrees.Length — it stores a scalar int, handled as `@len`
     if module == "BTrees.Length" {
         return None;
     }
 
-    // Determine kind from class name suffix
-    if name.ends_with("BTree") {
-        Some(BTreeClassInfo {
-            kind: BTreeNodeKind::BTree,
-            is_map: true,
-        })
-    } else if name.ends_with("Bucket") {
-        Some(BTreeClassInfo {
-            kind: BTreeNodeKind::Bucket,
-            is_map: true,
-        })
-    } else if name.ends_with("TreeSet") {
-        Some(BTreeClassInfo {
-            kind: BTreeNodeKind::TreeSet,
-            is_map: false,
-        })
-    } else if name.ends_with("Set") {
-        Some(BTreeClassInfo {
-            kind: BTreeNodeKind::Set,
-            is_map: false,
-        })
+    classify_by_name(name)
+}
+
+/// Determine the node kind from the class name suffix and the key/value
+/// types from the prefix.
+fn classify_by_name(name: &str) -> Option<BTreeClassInfo> {
+    let (prefix, kind) = if let Some(p) = name.strip_suffix("BTree") {
+        (p, BTreeNodeKind::BTree)
+    } else if let Some(p) = name.strip_suffix("Bucket") {
+        (p, BTreeNodeKind::Bucket)
+    } else if let Some(p) = name.strip_suffix("TreeSet") {
+        (p, BTreeNodeKind::TreeSet)
+    } else if let Some(p) = name.strip_suffix("Set") {
+        (p, BTreeNodeKind::Set)
     } else {
-        None
+        return None;
+    };
+    let mut info = BTreeClassInfo::from(kind);
+    let (key_type, value_type) = match prefix.as_bytes() {
+        b"fs" => (Some(BTreeValueType::Bytes(2)), Some(BTreeValueType::Bytes(6))),
+        &[k, v] => match (BTreeValueType::from_code(k), BTreeValueType::from_code(v)) {
+            (Some(k), Some(v)) => (Some(k), Some(v)),
+            _ => (None, None),
+        },
+        _ => (None, None),
+    };
+    info.key_type = key_type;
+    if info.is_map {
+        info.value_type = value_type;
+    }
+    Some(info)
+}
+
+impl From<BTreeNodeKind> for BTreeClassInfo {
+    fn from(kind: BTreeNodeKind) -> Self {
+        BTreeClassInfo {
+            kind,
+            is_map: matches!(kind, BTreeNodeKind::BTree | BTreeNodeKind::Bucket),
+            key_type: None,
+            value_type: None,
+        }
     }
 }
 
+// ---------------------------------------------------------------------------
+// Registration of BTree-compatible classes outside the BTrees package
+// ---------------------------------------------------------------------------
+
+/// Additional BTree-compatible classes (e.g. `zc.intid` or catalog
+/// subclasses). Their state has the same shape as the BTrees base class,
+/// so they are flattened and reconstructed identically.
+struct BTreeRegistry {
+    /// Module prefixes whose classes are classified by name suffix.
+    prefixes: Vec<String>,
+    /// Explicit (module, name, kind) registrations; take precedence.
+    classes: Vec<(String, String, BTreeNodeKind)>,
+}
+
+static REGISTRY: RwLock<BTreeRegistry> = RwLock::new(BTreeRegistry {
+    prefixes: Vec::new(),
+    classes: Vec::new(),
+});
+
+/// Fast check so unregistered deployments never touch the lock.
+static HAS_REGISTRATIONS: AtomicBool = AtomicBool::new(false);
+
+/// Register a single class as BTree-compatible with the given node kind.
+pub fn register_btree_class(module: &str, name: &str, kind: BTreeNodeKind) {
+    let mut reg = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+    reg.classes.retain(|(m, n, _)| m != module || n != name);
+    reg.classes.push((module.to_string(), name.to_string(), kind));
+    HAS_REGISTRATIONS.store(true, Ordering::Release);
+}
+
+/// Register a module prefix: classes in that module and its submodules are
+/// classified by their name suffix (`*BTree`, `*Bucket`, `*TreeSet`,
+/// `*Set`), like the `BTrees.*` modules. `"zc.intid"` matches `zc.intid`
+/// and `zc.intid.utility`, not `zc.intidfoo`.
+pub fn register_btree_module_prefix(prefix: &str) {
+    let mut reg = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+    if !reg.prefixes.iter().any(|p| p == prefix) {
+        reg.prefixes.push(prefix.to_string());
+    }
+    HAS_REGISTRATIONS.store(true, Ordering::Release);
+}
+
+/// Remove all BTree class and module prefix registrations.
+pub fn clear_btree_registrations() {
+    let mut reg = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+    reg.prefixes.clear();
+    reg.classes.clear();
+    HAS_REGISTRATIONS.store(false, Ordering::Release);
+}
+
+fn classify_registered(module: &str, name: &str) -> Option<BTreeClassInfo> {
+    if !HAS_REGISTRATIONS.load(Ordering::Acquire) {
+        return None;
+    }
+    let reg = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, _, kind)) = reg
+        .classes
+        .iter()
+        .find(|(m, n, _)| m == module && n == name)
+    {
+        return Some((*kind).into());
+    }
+    if reg.prefixes.iter().any(|p| in_package(module, p)) {
+        return classify_by_name(name);
+    }
+    None
+}
+
+/// Whether `module` is the module `prefix` or one of its submodules.
+fn in_package(module: &str, prefix: &str) -> bool {
+    module
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
 // ---------------------------------------------------------------------------
 // Forward direction: PickleValue state → JSON
 // ---------------------------------------------------------------------------
@@ -183,7 +505,7 @@ fn format_flat_data(
 
     if info.is_map {
         // Map type: alternating key-value pairs → @kv: [[k, v], ...]
-        if items.len() % 2 != 0 {
+        if !items.len().is_multiple_of(2) {
             return Err(CodecError::InvalidData(
                 "BTree bucket has odd number of items for key-value pairs".to_string(),
             ));
@@ -199,7 +521,7 @@ fn format_flat_data(
         map.insert("@kv".to_string(), Value::Array(pairs));
     } else {
         // Set type: keys only → @ks: [k1, k2, ...]
-        let keys: Result<Vec<Value>, _> = items.iter().map(|item| to_json(item)).collect();
+        let keys: Result<Vec<Value>, _> = items.iter().map(to_json).collect();
         map.insert("@ks".to_string(), Value::Array(keys?));
     }
 
@@ -213,7 +535,7 @@ fn format_large_btree(
     to_json: &dyn Fn(&PickleValue) -> Result<Value, CodecError>,
 ) -> Result<Value, CodecError> {
     let children_json: Result<Vec<Value>, _> =
-        children.iter().map(|item| to_json(item)).collect();
+        children.iter().map(to_json).collect();
     let first_json = to_json(firstbucket)?;
 
     let mut map = Map::new();
@@ -263,7 +585,7 @@ fn bucket_state_to_json(
                 result_map.insert("@kv".to_string(), Value::Array(pairs));
             } else {
                 let keys: Result<Vec<Value>, _> =
-                    flat_data.iter().map(|item| to_json(item)).collect();
+                    flat_data.iter().map(to_json).collect();
                 result_map.insert("@ks".to_string(), Value::Array(keys?));
             }
 
@@ -408,7 +730,7 @@ fn write_flat_data(
 ) -> Result<(), CodecError> {
     w.begin_object();
     if info.is_map {
-        if items.len() % 2 != 0 {
+        if !items.len().is_multiple_of(2) {
             return Err(CodecError::InvalidData(
                 "BTree bucket has odd number of items for key-value pairs".to_string(),
             ));
@@ -485,7 +807,7 @@ pub fn json_to_btree_state(
 
     let map = match state_json {
         Value::Object(m) => m,
-        // Not a JSON object — use generic decoder (e.g., scalar state for Length)
+        // Not a JSON object — use generic decoder
         _ => return from_json(state_json),
     };
 
@@ -516,7 +838,7 @@ pub fn json_to_btree_state(
         let first_val = map
             .get("@first")
             .ok_or_else(|| CodecError::InvalidData("@children without @first".into()))?;
-        return decode_large_btree(children_val, first_val, from_json);
+        return decode_large_btree(info, children_val, first_val, from_json);
     }
 
     // Not a BTree marker — fallback to generic decoder
@@ -555,7 +877,7 @@ fn decode_keys(
         .as_array()
         .ok_or_else(|| CodecError::InvalidData("@ks must be an array".into()))?;
 
-    arr.iter().map(|item| from_json(item)).collect()
+    arr.iter().map(from_json).collect()
 }
 
 /// Wrap flat data items in the appropriate tuple nesting for the BTree kind.
@@ -564,17 +886,11 @@ pub fn wrap_flat_data(
     flat_data: Vec<PickleValue>,
     next_ref: Option<PickleValue>,
 ) -> Result<PickleValue, CodecError> {
+    validate_flat_data(info, &flat_data)?;
     let data_tuple = PickleValue::Tuple(flat_data);
 
     match info.kind {
-        BTreeNodeKind::BTree | BTreeNodeKind::TreeSet => {
-            // 4-level nesting: ((((<data>,),),),)
-            // data_tuple is the innermost, then 3 more wrapping tuples
-            let level3 = PickleValue::Tuple(vec![data_tuple]);
-            let level2 = PickleValue::Tuple(vec![level3]);
-            let level1 = PickleValue::Tuple(vec![level2]);
-            Ok(level1)
-        }
+        BTreeNodeKind::BTree | BTreeNodeKind::TreeSet => Ok(nest_inline(data_tuple)),
         BTreeNodeKind::Bucket | BTreeNodeKind::Set => {
             // 2-level nesting: ((<data>,),) or ((<data>,), next_ref)
             if let Some(next) = next_ref {
@@ -586,8 +902,23 @@ pub fn wrap_flat_data(
     }
 }
 
+/// The state of a small BTree/TreeSet holding `flat_data` in its single
+/// inline bucket.
+pub fn inline_btree_state(flat_data: Vec<PickleValue>) -> PickleValue {
+    nest_inline(PickleValue::Tuple(flat_data))
+}
+
+fn nest_inline(data_tuple: PickleValue) -> PickleValue {
+    // 4-level nesting: ((((<data>,),),),)
+    // data_tuple is the innermost, then 3 more wrapping tuples
+    let level3 = PickleValue::Tuple(vec![data_tuple]);
+    let level2 = PickleValue::Tuple(vec![level3]);
+    PickleValue::Tuple(vec![level2])
+}
+
 /// Decode a large BTree state with @children and @first.
 fn decode_large_btree(
+    info: &BTreeClassInfo,
     children_val: &Value,
     first_val: &Value,
     from_json: &dyn Fn(&Value) -> Result<PickleValue, CodecError>,
@@ -596,9 +927,10 @@ fn decode_large_btree(
         .as_array()
         .ok_or_else(|| CodecError::InvalidData("@children must be an array".into()))?;
 
-    let children: Result<Vec<PickleValue>, _> =
-        children_arr.iter().map(|item| from_json(item)).collect();
-    let children_tuple = PickleValue::Tuple(children?);
+    let children: Vec<PickleValue> =
+        children_arr.iter().map(from_json).collect::<Result<_, _>>()?;
+    validate_children(info, &children)?;
+    let children_tuple = PickleValue::Tuple(children);
     let firstbucket = from_json(first_val)?;
 
     Ok(PickleValue::Tuple(vec![children_tuple, firstbucket]))
@@ -653,11 +985,74 @@ mod tests {
         assert!(classify_btree("myapp.models", "Document").is_none());
     }
 
+    #[test]
+    fn test_classify_registered_class() {
+        assert!(classify_btree("test_registry.explicit", "Index").is_none());
+        register_btree_class("test_registry.explicit", "Index", BTreeNodeKind::TreeSet);
+        let info = classify_btree("test_registry.explicit", "Index").unwrap();
+        assert_eq!(info.kind, BTreeNodeKind::TreeSet);
+        assert!(!info.is_map);
+        // Only the exact class is registered
+        assert!(classify_btree("test_registry.explicit", "Other").is_none());
+    }
+
+    #[test]
+    fn test_classify_registered_module_prefix() {
+        assert!(classify_btree("test_registry.prefix.sub", "IOBTree").is_none());
+        register_btree_module_prefix("test_registry.prefix");
+        let info = classify_btree("test_registry.prefix.sub", "IOBTree").unwrap();
+        assert_eq!(info.kind, BTreeNodeKind::BTree);
+        assert!(info.is_map);
+        let info = classify_btree("test_registry.prefix", "OOSet").unwrap();
+        assert_eq!(info.kind, BTreeNodeKind::Set);
+        // Name suffix still decides
+        assert!(classify_btree("test_registry.prefix", "Utility").is_none());
+    }
+
+    #[test]
+    fn test_module_prefix_matches_whole_segments() {
+        register_btree_module_prefix("zc.intid");
+        assert!(classify_btree("zc.intid", "IFBTree").is_some());
+        assert!(classify_btree("zc.intid.utility", "IFBTree").is_some());
+        assert!(classify_btree("zc.intidfoo", "IFBTree").is_none());
+        assert!(classify_btree("zc.intidfoo.utility", "IFBTree").is_none());
+    }
+
+    #[test]
+    fn test_classify_key_value_types() {
+        let info = classify_btree("BTrees.IOBTree", "IOBTree").unwrap();
+        assert_eq!(info.key_type, Some(BTreeValueType::Int32));
+        assert_eq!(info.value_type, Some(BTreeValueType::Object));
+        let info = classify_btree("BTrees.LFBTree", "LFBucket").unwrap();
+        assert_eq!(info.key_type, Some(BTreeValueType::Int64));
+        assert_eq!(info.value_type, Some(BTreeValueType::Float32));
+        assert_eq!(info.value_type.unwrap().json_type(), "float");
+        let info = classify_btree("BTrees.QQBTree", "QQTreeSet").unwrap();
+        assert_eq!(info.key_type, Some(BTreeValueType::UInt64));
+        // Sets carry keys only
+        assert_eq!(info.value_type, None);
+        let info = classify_btree("BTrees.OUBTree", "OUSet").unwrap();
+        assert_eq!(info.key_type.unwrap().code(), "O");
+        assert_eq!(info.value_type, None);
+    }
+
+    #[test]
+    fn test_classify_unknown_prefix_has_no_types() {
+        register_btree_class("test_registry.types", "Catalog", BTreeNodeKind::BTree);
+        let info = classify_btree("test_registry.types", "Catalog").unwrap();
+        assert_eq!((info.key_type, info.value_type), (None, None));
+        let info = classify_btree("BTrees.OOBTree", "XYBTree").unwrap();
+        assert_eq!((info.key_type, info.value_type), (None, None));
+    }
+
     #[test]
     fn test_classify_fsbtree() {
         let info = classify_btree("BTrees.fsBTree", "fsBucket").unwrap();
         assert_eq!(info.kind, BTreeNodeKind::Bucket);
         assert!(info.is_map);
+        assert_eq!(info.key_type, Some(BTreeValueType::Bytes(2)));
+        assert_eq!(info.value_type, Some(BTreeValueType::Bytes(6)));
+        assert_eq!(info.key_type.unwrap().json_type(), "bytes");
     }
 
     #[test]
@@ -953,22 +1348,92 @@ mod tests {
         assert_eq!(json, json!({"@kv": []}));
     }
 
+    // -- typed key/value validation --
+
+    #[test]
+    fn test_value_type_accepts() {
+        use BTreeValueType::*;
+        assert!(Int32.accepts(&PickleValue::Int(i32::MIN as i64)));
+        assert!(!Int32.accepts(&PickleValue::Int(i32::MAX as i64 + 1)));
+        assert!(UInt32.accepts(&PickleValue::Int(u32::MAX as i64)));
+        assert!(!UInt32.accepts(&PickleValue::Int(-1)));
+        assert!(Int64.accepts(&PickleValue::Bool(true)));
+        assert!(UInt64.accepts(&PickleValue::BigInt(num_bigint::BigInt::from(u64::MAX))));
+        assert!(!UInt64.accepts(&PickleValue::BigInt(num_bigint::BigInt::from(i128::MAX))));
+        assert!(Float32.accepts(&PickleValue::Int(3)));
+        assert!(Float32.accepts(&PickleValue::Float(0.5)));
+        assert!(!Float32.accepts(&PickleValue::None));
+        assert!(Bytes(2).accepts(&PickleValue::Bytes(vec![0, 1])));
+        assert!(!Bytes(2).accepts(&PickleValue::Bytes(vec![0, 1, 2])));
+        assert!(Object.accepts(&PickleValue::List(vec![])));
+    }
+
+    #[test]
+    fn test_typed_bucket_rejects_string_key() {
+        let info = classify_btree("BTrees.IIBTree", "IIBucket").unwrap();
+        let err = json_to_btree_state(
+            &info,
+            &json!({"@kv": [[1, 10], ["two", 20]]}),
+            &json_to_pickle_value,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("@kv[1][0]"), "{err}");
+        assert!(err.contains("key of type I"), "{err}");
+    }
+
+    #[test]
+    fn test_typed_bucket_rejects_out_of_range_value() {
+        let info = classify_btree("BTrees.OIBTree", "OIBTree").unwrap();
+        let err = json_to_btree_state(
+            &info,
+            &json!({"@kv": [["a", 1], ["b", 1_u64 << 33]]}),
+            &json_to_pickle_value,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("@kv[1][1]"), "{err}");
+        // Object keys take anything
+        assert!(json_to_btree_state(
+            &info,
+            &json!({"@kv": [[[1, 2], 1]]}),
+            &json_to_pickle_value
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_typed_set_and_children_keys() {
+        let info = classify_btree("BTrees.LFBTree", "LFTreeSet").unwrap();
+        let err = json_to_btree_state(&info, &json!({"@ks": [1, 2.5]}), &json_to_pickle_value)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("@ks[1]"), "{err}");
+
+        let info = classify_btree("BTrees.IOBTree", "IOBTree").unwrap();
+        let state = json!({
+            "@children": [{"@ref": "0000000000000002"}, "sep", {"@ref": "0000000000000003"}],
+            "@first": {"@ref": "0000000000000002"},
+        });
+        let err = json_to_btree_state(&info, &state, &json_to_pickle_value)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("@children[1]"), "{err}");
+    }
+
     #[test]
     fn test_format_flat_data_odd_items_error() {
-        let info = BTreeClassInfo {
-            kind: BTreeNodeKind::Bucket,
-            is_map: true,
-        };
+        let info = BTreeClassInfo::from(BTreeNodeKind::Bucket);
         // 3 items — odd number for key-value pairs
         let items = vec![
             PickleValue::Int(1),
-            PickleValue::String("one".to_string()),
+            PickleValue::String("one".into()),
             PickleValue::Int(2),
         ];
         let to_json = |v: &PickleValue| -> Result<serde_json::Value, CodecError> {
             match v {
                 PickleValue::Int(i) => Ok(serde_json::json!(*i)),
-                PickleValue::String(s) => Ok(serde_json::json!(s)),
+                PickleValue::String(s) => Ok(serde_json::json!(&**s)),
                 _ => Err(CodecError::InvalidData("unexpected".to_string())),
             }
         };
@@ -0,0 +1,142 @@
+//! Promotion of Python 2 byte-string dict keys to JSON object keys.
+//!
+//! Python 2 `str` keys decode as `PickleValue::Bytes`, so every legacy
+//! dict ends up as an `{"@d": [[{"@b": ...}, value], ...]}` pair list that
+//! JSON queries cannot index. With promotion enabled for a call (see
+//! [`with_bytes_key_promotion`]), a dict whose keys are *all* ASCII-clean
+//! byte strings is written as a plain object with an extra `"@bk": true`
+//! entry instead:
+//!
+//! ```text
+//! {b'title': 'x'}  ->  {"@bk": true, "title": "x"}
+//! ```
+//!
+//! Encoding always recognizes `"@bk": true` and restores every key of
+//! that dict to bytes, so the round trip is exact. Dicts mixing key types
+//! keep the `@d` form. A key is ASCII-clean when it has no NUL or non-ASCII
+//! bytes and does not start with `@` (which could be mistaken for a marker).
+
+use std::cell::Cell;
+
+use crate::types::PickleValue;
+
+/// Dict-level annotation marking promoted byte-string keys.
+pub const BYTES_KEYS_MARKER: &str = "@bk";
+
+thread_local! {
+    /// Whether byte-string keys are promoted on decode (off by default).
+    static PROMOTE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f` with ASCII-clean byte-string dict keys promoted to string keys
+/// with a `"@bk": true` annotation by the conversions it makes on this
+/// thread. Only affects decoding.
+///
+/// ```
+/// use zodb_json_codec::{decode_pickle, pickle_value_to_json, with_bytes_key_promotion};
+///
+/// // Python 2: {'title': 'x'}
+/// let data = b"\x80\x02}U\x05titleX\x01\x00\x00\x00xs.";
+/// let val = decode_pickle(data)?;
+/// let json = with_bytes_key_promotion(|| pickle_value_to_json(&val))?;
+/// assert_eq!(json, serde_json::json!({"@bk": true, "title": "x"}));
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn with_bytes_key_promotion<R>(f: impl FnOnce() -> R) -> R {
+    let _scope = PromotionScope::enter(true);
+    f()
+}
+
+/// Sets byte-string key promotion on the current thread while alive.
+pub(crate) struct PromotionScope {
+    previous: bool,
+}
+
+impl PromotionScope {
+    pub(crate) fn enter(enabled: bool) -> Self {
+        PromotionScope {
+            previous: PROMOTE.with(|p| p.replace(enabled)),
+        }
+    }
+}
+
+impl Drop for PromotionScope {
+    fn drop(&mut self) {
+        PROMOTE.with(|p| p.set(self.previous));
+    }
+}
+
+/// Whether the keys of `pairs` are written as promoted string keys.
+#[inline]
+pub(crate) fn promote_keys(pairs: &[(PickleValue, PickleValue)]) -> bool {
+    PROMOTE.with(Cell::get) && promotable(pairs)
+}
+
+fn promotable(pairs: &[(PickleValue, PickleValue)]) -> bool {
+    !pairs.is_empty()
+        && pairs.iter().all(|(k, _)| match k {
+            PickleValue::Bytes(b) => is_clean_key(b),
+            _ => false,
+        })
+}
+
+#[inline]
+fn is_clean_key(b: &[u8]) -> bool {
+    b.first() != Some(&b'@') && b.iter().all(|&c| c != 0 && c.is_ascii())
+}
+
+/// Text of a string key, or of a byte key that passed `promote_keys`.
+#[inline]
+pub(crate) fn key_text(key: &PickleValue) -> Option<&str> {
+    match key {
+        PickleValue::String(s) => Some(s),
+        PickleValue::Bytes(b) => std::str::from_utf8(b).ok(),
+        _ => None,
+    }
+}
+
+/// Turn the string keys of a dict annotated with `"@bk": true` back
+/// into byte strings.
+pub(crate) fn restore_bytes_keys(pairs: &mut [(PickleValue, PickleValue)]) {
+    for (k, _) in pairs.iter_mut() {
+        if let PickleValue::String(s) = k {
+            *k = PickleValue::Bytes(s.as_bytes().to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict(keys: &[PickleValue]) -> Vec<(PickleValue, PickleValue)> {
+        keys.iter().map(|k| (k.clone(), PickleValue::None)).collect()
+    }
+
+    #[test]
+    fn test_promotable() {
+        let b = |s: &[u8]| PickleValue::Bytes(s.to_vec());
+        assert!(promotable(&dict(&[b(b"title"), b(b"_objects"), b(b"")])));
+        assert!(!promotable(&dict(&[])));
+        // Mixed key types keep the @d form
+        assert!(!promotable(&dict(&[b(b"a"), PickleValue::String("b".into())])));
+        assert!(!promotable(&dict(&[b(b"caf\xe9")])));
+        assert!(!promotable(&dict(&[b(b"a\0b")])));
+        assert!(!promotable(&dict(&[b(b"@cls")])));
+    }
+
+    #[test]
+    fn test_promotion_scope() {
+        let pairs = dict(&[PickleValue::Bytes(b"id".to_vec())]);
+        assert!(!promote_keys(&pairs));
+        assert!(with_bytes_key_promotion(|| promote_keys(&pairs)));
+        assert!(!promote_keys(&pairs));
+    }
+
+    #[test]
+    fn test_restore_bytes_keys() {
+        let mut pairs = vec![(PickleValue::String("id".into()), PickleValue::Int(1))];
+        restore_bytes_keys(&mut pairs);
+        assert_eq!(pairs, vec![(PickleValue::Bytes(b"id".to_vec()), PickleValue::Int(1))]);
+    }
+}
@@ -0,0 +1,201 @@
+//! Canonical pickle bytes for content addressing.
+//!
+//! CPython's pickler memoizes by object identity, so two equal states can
+//! pickle to different bytes: a string that happens to be the same object
+//! in one process is written once and fetched with `BINGET`, in another
+//! it is written twice. Protocol, framing and integer opcode choice vary
+//! as well. Hashing such pickles does not find duplicates.
+//!
+//! [`canonicalize_pickle`] decodes a pickle or ZODB record and encodes it
+//! again with the codec's own encoder, whose output depends only on the
+//! decoded value: protocol 3, the smallest opcode for every value, and
+//! memo entries planned by content (see `memo.rs`) and numbered in write
+//! order. Dict items keep their order. Aliased containers stay aliased,
+//! since sharing a container is part of the logical state.
+//!
+//! [`state_fingerprint`] hashes the canonical state pickle of a record.
+//! Set items are sorted first: their pickled order follows the string hash
+//! seed of the process that wrote them and is not part of the state.
+
+use sha2::{Digest, Sha256};
+
+use crate::binenc::hex_encode;
+use crate::decode::decode_pickles_keeping_aliases;
+use crate::encode::{encode_pickle, MAX_DEPTH};
+use crate::error::CodecError;
+use crate::shared::for_each_child_mut;
+use crate::types::PickleValue;
+
+/// Re-encode a pickle, or a ZODB record of two pickles, in canonical form.
+///
+/// Equal decoded states give byte-identical output, whatever pickler,
+/// protocol or memo usage produced the input. The pickles of a record are
+/// encoded separately, so the state pickle no longer refers to the class
+/// pickle's memo. Canonicalizing is idempotent.
+///
+/// ```
+/// use zodb_json_codec::canonicalize_pickle;
+///
+/// // ["a", "a"] with and without a memoized string
+/// let memoized = b"\x80\x03]q\x00(X\x01\x00\x00\x00aq\x01h\x01e.";
+/// let plain = b"\x80\x02]X\x01\x00\x00\x00aaX\x01\x00\x00\x00aa.";
+/// assert_eq!(canonicalize_pickle(memoized)?, canonicalize_pickle(plain)?);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn canonicalize_pickle(data: &[u8]) -> Result<Vec<u8>, CodecError> {
+    let values = decode_pickles_keeping_aliases(data)?;
+    if values.len() > 2 {
+        return Err(CodecError::InvalidData(format!(
+            "expected a pickle or a ZODB record, found {} pickles",
+            values.len()
+        )));
+    }
+    let mut out = Vec::with_capacity(data.len());
+    for val in &values {
+        out.extend_from_slice(&encode_pickle(val)?);
+    }
+    Ok(out)
+}
+
+/// Hex SHA-256 of the logical state of a ZODB record, or of a single
+/// pickle taken as the state.
+///
+/// The fingerprint ignores how the state was pickled (protocol, memo
+/// usage, opcode choice, the order of set items) and changes whenever the
+/// decoded state does. The class pickle is not part of it. Fingerprints
+/// depend on the codec's canonical encoding, so only compare fingerprints
+/// computed by the same major version.
+///
+/// ```
+/// use zodb_json_codec::state_fingerprint;
+///
+/// let memoized = b"\x80\x03]q\x00(X\x01\x00\x00\x00aq\x01h\x01e.";
+/// let plain = b"\x80\x02]X\x01\x00\x00\x00aaX\x01\x00\x00\x00aa.";
+/// assert_eq!(state_fingerprint(memoized)?, state_fingerprint(plain)?);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn state_fingerprint(record: &[u8]) -> Result<String, CodecError> {
+    let mut values = decode_pickles_keeping_aliases(record)?;
+    if values.len() > 2 {
+        return Err(CodecError::InvalidData(format!(
+            "expected a pickle or a ZODB record, found {} pickles",
+            values.len()
+        )));
+    }
+    let mut state = values.pop().unwrap_or(PickleValue::None);
+    sort_set_items(&mut state, 0);
+    Ok(hex_encode(Sha256::digest(encode_pickle(&state)?)))
+}
+
+/// Sort the items of every set and frozenset in `val` by their canonical
+/// pickle. Values nested deeper than the encoder accepts are left alone;
+/// encoding them fails anyway.
+fn sort_set_items(val: &mut PickleValue, depth: usize) {
+    if depth > MAX_DEPTH {
+        return;
+    }
+    for_each_child_mut(val, &mut |child| sort_set_items(child, depth + 1));
+    if let PickleValue::Set(items) | PickleValue::FrozenSet(items) = val {
+        items.sort_by_cached_key(|item| encode_pickle(item).ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{decode_pickle, decode_zodb_pickles};
+    use crate::encode::encode_pickle_protocol;
+    use crate::types::PickleValue;
+
+    #[test]
+    fn test_equal_states_give_equal_bytes() {
+        let val = PickleValue::Dict(vec![
+            (PickleValue::String("b".into()), PickleValue::Int(300)),
+            (
+                PickleValue::String("a".into()),
+                PickleValue::String("b".into()),
+            ),
+        ]);
+        let canonical = canonicalize_pickle(&encode_pickle(&val).unwrap()).unwrap();
+        for protocol in [2, 3, 4] {
+            let data = encode_pickle_protocol(&val, protocol).unwrap();
+            assert_eq!(canonicalize_pickle(&data).unwrap(), canonical);
+        }
+        // Unmemoized, LONG1 for a small int, and a redundant PUT
+        let odd =
+            b"\x80\x02}q\x05(X\x01\x00\x00\x00b\x8a\x02,\x01X\x01\x00\x00\x00aX\x01\x00\x00\x00bu.";
+        assert_eq!(canonicalize_pickle(odd).unwrap(), canonical);
+        assert_eq!(canonicalize_pickle(&canonical).unwrap(), canonical);
+        // Dict order is part of the state
+        let PickleValue::Dict(mut items) = val else {
+            unreachable!()
+        };
+        items.reverse();
+        let reversed = encode_pickle(&PickleValue::Dict(items)).unwrap();
+        assert_ne!(canonicalize_pickle(&reversed).unwrap(), canonical);
+    }
+
+    #[test]
+    fn test_aliasing_is_kept() {
+        // a = []; [a, a] versus [[], []]
+        let aliased = b"\x80\x03]q\x00(]q\x01h\x01e.";
+        let copies = b"\x80\x03](]]e.";
+        let canonical = canonicalize_pickle(aliased).unwrap();
+        assert_ne!(canonical, canonicalize_pickle(copies).unwrap());
+        assert_eq!(canonicalize_pickle(&canonical).unwrap(), canonical);
+        assert_eq!(
+            decode_pickle(&canonical).unwrap(),
+            decode_pickle(aliased).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        // The state pickle fetches the class name from the class pickle's memo
+        let record = b"\x80\x03X\x05\x00\x00\x00myappq\x00X\x03\x00\x00\x00Docq\x01\x86q\x02N\x86.\
+                       \x80\x03}q\x03X\x04\x00\x00\x00kindq\x04h\x01s.";
+        let canonical = canonicalize_pickle(record).unwrap();
+        assert_eq!(
+            decode_zodb_pickles(&canonical).unwrap(),
+            decode_zodb_pickles(record).unwrap()
+        );
+        let (_, state_pickle) = crate::zodb::split_zodb_record(&canonical).unwrap();
+        assert!(decode_pickle(state_pickle).is_ok());
+        assert_eq!(canonicalize_pickle(&canonical).unwrap(), canonical);
+    }
+
+    #[test]
+    fn test_state_fingerprint() {
+        let s = |v: &str| PickleValue::String(v.into());
+        let state = |items: Vec<PickleValue>| {
+            PickleValue::Dict(vec![(s("tags"), PickleValue::Set(items))])
+        };
+        let one = encode_pickle(&state(vec![s("a"), s("b")])).unwrap();
+        let other = encode_pickle_protocol(&state(vec![s("b"), s("a")]), 2).unwrap();
+        let fingerprint = state_fingerprint(&one).unwrap();
+        assert_eq!(fingerprint.len(), 64);
+        assert_eq!(state_fingerprint(&other).unwrap(), fingerprint);
+        let changed = encode_pickle(&state(vec![s("a"), s("c")])).unwrap();
+        assert_ne!(state_fingerprint(&changed).unwrap(), fingerprint);
+        // The class pickle is ignored
+        let class = |module: &str| {
+            encode_pickle(&PickleValue::Tuple(vec![
+                PickleValue::Tuple(vec![s(module), s("Doc")]),
+                PickleValue::None,
+            ]))
+            .unwrap()
+        };
+        let record = [class("myapp"), one.clone()].concat();
+        assert_eq!(state_fingerprint(&record).unwrap(), fingerprint);
+        let moved = [class("newapp"), one].concat();
+        assert_eq!(state_fingerprint(&moved).unwrap(), fingerprint);
+        assert!(state_fingerprint(b"").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(canonicalize_pickle(b"").is_err());
+        assert!(canonicalize_pickle(b"\x80\x03N.\x80\x03N.\x80\x03N.").is_err());
+        assert!(canonicalize_pickle(b"\x80\x03N.\x80").is_err());
+    }
+}
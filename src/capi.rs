@@ -0,0 +1,207 @@
+//! C ABI for non-Python consumers (feature `capi`).
+//!
+//! Go, Node and other storage tooling link the cdylib and call these
+//! functions directly. The interface is deliberately tiny: bytes in,
+//! bytes out, one free function.
+//!
+//! - Every function returns a `ZJC_*` status code.
+//! - On success `*out` holds the result. On failure it holds a UTF-8
+//!   error message. Either way the caller releases it with `zjc_free`.
+//! - Output buffers are NUL-terminated. The terminator is not counted in
+//!   `*out_len`, so JSON output and messages can be used as C strings.
+//!
+//! Buffers carry their allocation size in a hidden header in front of the
+//! returned pointer, which lets `zjc_free` take the pointer alone.
+//!
+//! The matching header is `include/zodb_json_codec.h`.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::error::CodecError;
+use crate::zodb::{decode_zodb_record, encode_zodb_record};
+
+/// Success.
+pub const ZJC_OK: i32 = 0;
+/// A required pointer was NULL, or JSON input was not UTF-8.
+pub const ZJC_ERR_ARGUMENT: i32 = 1;
+/// The record or JSON could not be decoded or encoded.
+pub const ZJC_ERR_CODEC: i32 = 2;
+/// Internal error (a Rust panic was caught at the boundary).
+pub const ZJC_ERR_INTERNAL: i32 = 3;
+
+const HEADER: usize = std::mem::size_of::<usize>();
+
+/// Copy `bytes` into a NUL-terminated buffer owned by the caller.
+fn alloc_buffer(bytes: &[u8]) -> *mut u8 {
+    let total = HEADER + bytes.len() + 1;
+    let mut buf = Vec::with_capacity(total);
+    buf.extend_from_slice(&total.to_ne_bytes());
+    buf.extend_from_slice(bytes);
+    buf.push(0);
+    let base = Box::into_raw(buf.into_boxed_slice()) as *mut u8;
+    // SAFETY: the allocation is at least HEADER bytes long.
+    unsafe { base.add(HEADER) }
+}
+
+/// Run `f` and hand its result (or error message) to the caller.
+///
+/// # Safety
+///
+/// `out` and `out_len` must be NULL or valid for writes.
+unsafe fn respond(
+    out: *mut *mut u8,
+    out_len: *mut usize,
+    f: impl FnOnce() -> Result<Vec<u8>, (i32, String)>,
+) -> i32 {
+    if out.is_null() || out_len.is_null() {
+        return ZJC_ERR_ARGUMENT;
+    }
+    let (status, bytes) = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(bytes)) => (ZJC_OK, bytes),
+        Ok(Err((status, msg))) => (status, msg.into_bytes()),
+        Err(_) => (ZJC_ERR_INTERNAL, b"internal error".to_vec()),
+    };
+    *out_len = bytes.len();
+    *out = alloc_buffer(&bytes);
+    status
+}
+
+fn codec_err(e: CodecError) -> (i32, String) {
+    (ZJC_ERR_CODEC, e.to_string())
+}
+
+/// View `(ptr, len)` as a byte slice; NULL is only valid for `len == 0`.
+///
+/// # Safety
+///
+/// A non-NULL `ptr` must be valid for reads of `len` bytes.
+unsafe fn input<'a>(ptr: *const u8, len: usize) -> Result<&'a [u8], (i32, String)> {
+    if ptr.is_null() {
+        if len == 0 {
+            return Ok(&[]);
+        }
+        return Err((ZJC_ERR_ARGUMENT, "input pointer is NULL".to_string()));
+    }
+    Ok(std::slice::from_raw_parts(ptr, len))
+}
+
+/// Decode a ZODB record (class pickle + state pickle) to JSON:
+/// `{"@cls": ["module", "name"], "@s": {...}}`.
+///
+/// # Safety
+///
+/// `data` must be valid for reads of `len` bytes (or NULL with `len` 0).
+/// `out` and `out_len` must be valid for writes. The buffer stored in
+/// `*out` must be released with [`zjc_free`].
+#[no_mangle]
+pub unsafe extern "C" fn zjc_decode_record(
+    data: *const u8,
+    len: usize,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> i32 {
+    respond(out, out_len, || {
+        let data = input(data, len)?;
+        let json = decode_zodb_record(data).map_err(codec_err)?;
+        serde_json::to_vec(&json).map_err(|e| codec_err(e.into()))
+    })
+}
+
+/// Encode a JSON ZODB record (`{"@cls": [...], "@s": ...}`) back to
+/// record bytes.
+///
+/// # Safety
+///
+/// `json` must be valid for reads of `len` bytes (or NULL with `len` 0).
+/// `out` and `out_len` must be valid for writes. The buffer stored in
+/// `*out` must be released with [`zjc_free`].
+#[no_mangle]
+pub unsafe extern "C" fn zjc_encode_record(
+    json: *const u8,
+    len: usize,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> i32 {
+    respond(out, out_len, || {
+        let json = std::str::from_utf8(input(json, len)?)
+            .map_err(|_| (ZJC_ERR_ARGUMENT, "JSON input is not valid UTF-8".to_string()))?;
+        let value: serde_json::Value =
+            serde_json::from_str(json).map_err(|e| codec_err(e.into()))?;
+        encode_zodb_record(value).map_err(codec_err)
+    })
+}
+
+/// Release a buffer returned by any `zjc_*` function. NULL is ignored.
+///
+/// # Safety
+///
+/// `ptr` must be NULL or a buffer returned by this library that has not
+/// been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn zjc_free(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    let base = ptr.sub(HEADER);
+    let mut size = [0u8; HEADER];
+    std::ptr::copy_nonoverlapping(base, size.as_mut_ptr(), HEADER);
+    let total = usize::from_ne_bytes(size);
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(base, total)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe fn call(
+        f: unsafe extern "C" fn(*const u8, usize, *mut *mut u8, *mut usize) -> i32,
+        input: &[u8],
+    ) -> (i32, Vec<u8>) {
+        let mut out = std::ptr::null_mut();
+        let mut out_len = 0;
+        let status = f(input.as_ptr(), input.len(), &mut out, &mut out_len);
+        let bytes = std::slice::from_raw_parts(out, out_len).to_vec();
+        assert_eq!(*out.add(out_len), 0, "output is NUL-terminated");
+        zjc_free(out);
+        (status, bytes)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let json = r#"{"@cls":["myapp","Doc"],"@s":{"title":"Café","ref":{"@ref":"0000000000000003"}}}"#
+            .as_bytes();
+        unsafe {
+            let (status, record) = call(zjc_encode_record, json);
+            assert_eq!(status, ZJC_OK);
+            let (status, decoded) = call(zjc_decode_record, &record);
+            assert_eq!(status, ZJC_OK);
+            let decoded: serde_json::Value = serde_json::from_slice(&decoded).unwrap();
+            let expected: serde_json::Value = serde_json::from_slice(json).unwrap();
+            assert_eq!(decoded, expected);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        unsafe {
+            let (status, msg) = call(zjc_decode_record, b"\x80\x03");
+            assert_eq!(status, ZJC_ERR_CODEC);
+            assert!(String::from_utf8(msg).unwrap().contains("end of pickle"));
+            let (status, _) = call(zjc_encode_record, b"{\"@s\": {}}");
+            assert_eq!(status, ZJC_ERR_CODEC);
+            let (status, _) = call(zjc_encode_record, b"\xff");
+            assert_eq!(status, ZJC_ERR_ARGUMENT);
+
+            let mut out = std::ptr::null_mut();
+            let mut out_len = 0;
+            let status = zjc_decode_record(std::ptr::null(), 4, &mut out, &mut out_len);
+            assert_eq!(status, ZJC_ERR_ARGUMENT);
+            zjc_free(out);
+            assert_eq!(
+                zjc_decode_record(std::ptr::null(), 0, std::ptr::null_mut(), &mut out_len),
+                ZJC_ERR_ARGUMENT
+            );
+            zjc_free(std::ptr::null_mut());
+        }
+    }
+}
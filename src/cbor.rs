@@ -0,0 +1,1020 @@
+//! CBOR (RFC 8949) encoding of the `PickleValue` AST.
+//!
+//! JSON has no binary or big integer type, so the JSON form carries bytes
+//! as base64 `@b` strings and large integers as `@bi` strings. CBOR has
+//! both natively: byte strings stay raw and integers beyond 64 bits use
+//! the bignum tags 2 and 3. Everything JSON expresses with a marker object
+//! is a CBOR semantic tag around the value's natural encoding instead.
+//!
+//! | PickleValue            | CBOR                                   |
+//! |------------------------|----------------------------------------|
+//! | None, Bool, Float      | simple values, float64                 |
+//! | Int, BigInt            | major type 0/1, tag 2/3 bignum         |
+//! | String, Bytes          | text string, byte string               |
+//! | List, Dict             | array, map (any keys, order kept)      |
+//! | Set                    | tag 258 array                          |
+//! | Tuple, FrozenSet       | tag 55800, 55801 array                 |
+//! | Global, Blocked        | tag 55802, 55803 `[module, name]`      |
+//! | PersistentRef          | tag 55804 persistent id                |
+//! | Instance               | tag 55805 `[module, name, state, ..]`  |
+//! | Reduce                 | tag 55806 `[callable, args, ..]`       |
+//! | Reduce (NEWOBJ)        | tag 55812 `[cls, args, ..]`            |
+//! | NewObjEx               | tag 55811 `[cls, args, kwargs]`        |
+//! | RawPickle              | tag 55807 byte string                  |
+//! | SurrogateString        | tag 55813 byte string                  |
+//! | Shared, BackRef        | tag 55808 `[id, value]`, tag 55809 id  |
+//!
+//! Known types follow the JSON form: an aware datetime whose `@dt` string
+//! has a UTC offset is a tag 0 date/time string, `set`/`frozenset` REDUCEs
+//! are tag 258/55801 arrays, and any other known type (naive datetimes,
+//! `@dec`, `@uuid`, registered handlers, ...) is its JSON marker object,
+//! e.g. `{"@date": "2025-01-02"}`, as a map under tag 55810.
+//!
+//! The codec's own tags are taken from the first-come-first-served range
+//! of the IANA registry but are not registered; they only have meaning
+//! between this encoder and [`cbor_to_pickle_value`].
+
+use num_bigint::{BigInt, Sign};
+use serde_json::{Map, Number, Value};
+
+use crate::decode::decode_pickle;
+use crate::encode::encode_pickle;
+use crate::error::CodecError;
+use crate::json::{json_to_pickle_value, pickle_value_to_json};
+use crate::known_types;
+use crate::surrogates::for_each_piece;
+use crate::types::{InstanceData, PickleValue};
+
+const TAG_DATETIME: u64 = 0;
+const TAG_POS_BIGNUM: u64 = 2;
+const TAG_NEG_BIGNUM: u64 = 3;
+const TAG_SET: u64 = 258;
+const TAG_TUPLE: u64 = 55800;
+const TAG_FROZENSET: u64 = 55801;
+const TAG_GLOBAL: u64 = 55802;
+const TAG_BLOCKED: u64 = 55803;
+const TAG_REF: u64 = 55804;
+const TAG_INSTANCE: u64 = 55805;
+const TAG_REDUCE: u64 = 55806;
+const TAG_RAW_PICKLE: u64 = 55807;
+const TAG_SHARED: u64 = 55808;
+const TAG_BACKREF: u64 = 55809;
+const TAG_MARKER: u64 = 55810;
+const TAG_NEWOBJ_EX: u64 = 55811;
+const TAG_NEWOBJ: u64 = 55812;
+const TAG_SURROGATES: u64 = 55813;
+
+const MAJOR_UINT: u8 = 0;
+const MAJOR_NINT: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+const NULL: u8 = 0xf6;
+const FLOAT64: u8 = 0xfb;
+const BREAK: u8 = 0xff;
+
+const MAX_DEPTH: usize = 1000;
+
+/// Convert pickle bytes to CBOR.
+///
+/// ```
+/// use zodb_json_codec::{cbor_to_pickle, pickle_to_cbor};
+///
+/// // pickle.dumps(b"\x00\x01", protocol=3)
+/// let data = b"\x80\x03C\x02\x00\x01q\x00.";
+/// let cbor = pickle_to_cbor(data)?;
+/// assert_eq!(cbor, b"\x42\x00\x01");
+/// assert_eq!(cbor_to_pickle(&cbor)?, b"\x80\x03C\x02\x00\x01.");
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn pickle_to_cbor(data: &[u8]) -> Result<Vec<u8>, CodecError> {
+    pickle_value_to_cbor(&decode_pickle(data)?)
+}
+
+/// Convert CBOR written by [`pickle_to_cbor`] back to pickle bytes.
+pub fn cbor_to_pickle(data: &[u8]) -> Result<Vec<u8>, CodecError> {
+    encode_pickle(&cbor_to_pickle_value(data)?)
+}
+
+/// Encode a `PickleValue` AST as a single CBOR data item.
+pub fn pickle_value_to_cbor(val: &PickleValue) -> Result<Vec<u8>, CodecError> {
+    let mut w = CborWriter { out: Vec::new() };
+    w.value(val, 0)?;
+    Ok(w.out)
+}
+
+/// Decode a single CBOR data item to a `PickleValue` AST.
+pub fn cbor_to_pickle_value(data: &[u8]) -> Result<PickleValue, CodecError> {
+    let mut r = CborReader { data, pos: 0 };
+    let val = r.value(0)?;
+    if r.pos != data.len() {
+        return Err(invalid(format!(
+            "{} trailing bytes after CBOR data item",
+            data.len() - r.pos
+        )));
+    }
+    Ok(val)
+}
+
+fn invalid(msg: impl Into<String>) -> CodecError {
+    CodecError::InvalidData(msg.into())
+}
+
+fn depth_exceeded() -> CodecError {
+    invalid("maximum nesting depth exceeded in CBOR conversion")
+}
+
+// ---------------------------------------------------------------------------
+// Encoding
+// ---------------------------------------------------------------------------
+
+struct CborWriter {
+    out: Vec<u8>,
+}
+
+impl CborWriter {
+    fn head(&mut self, major: u8, n: u64) {
+        let m = major << 5;
+        match n {
+            0..=23 => self.out.push(m | n as u8),
+            24..=0xff => self.out.extend_from_slice(&[m | 24, n as u8]),
+            0x100..=0xffff => {
+                self.out.push(m | 25);
+                self.out.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.out.push(m | 26);
+                self.out.extend_from_slice(&(n as u32).to_be_bytes());
+            }
+            _ => {
+                self.out.push(m | 27);
+                self.out.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+    }
+
+    fn int(&mut self, i: i64) {
+        if i >= 0 {
+            self.head(MAJOR_UINT, i as u64);
+        } else {
+            self.head(MAJOR_NINT, !i as u64);
+        }
+    }
+
+    fn bigint(&mut self, bi: &BigInt) {
+        // CBOR stores a negative n as -1 - n
+        let (major, tag, magnitude) = match bi.sign() {
+            Sign::Minus => (MAJOR_NINT, TAG_NEG_BIGNUM, -bi - 1),
+            _ => (MAJOR_UINT, TAG_POS_BIGNUM, bi.clone()),
+        };
+        if let Ok(n) = u64::try_from(&magnitude) {
+            self.head(major, n);
+        } else {
+            self.head(MAJOR_TAG, tag);
+            self.bytes(&magnitude.to_bytes_be().1);
+        }
+    }
+
+    fn float(&mut self, f: f64) {
+        self.out.push(FLOAT64);
+        self.out.extend_from_slice(&f.to_bits().to_be_bytes());
+    }
+
+    fn text(&mut self, s: &str) {
+        self.head(MAJOR_TEXT, s.len() as u64);
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.head(MAJOR_BYTES, b.len() as u64);
+        self.out.extend_from_slice(b);
+    }
+
+    fn array(&mut self, items: &[PickleValue], depth: usize) -> Result<(), CodecError> {
+        self.head(MAJOR_ARRAY, items.len() as u64);
+        for item in items {
+            self.value(item, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn map(
+        &mut self,
+        pairs: &[(PickleValue, PickleValue)],
+        depth: usize,
+    ) -> Result<(), CodecError> {
+        self.head(MAJOR_MAP, pairs.len() as u64);
+        for (k, v) in pairs {
+            self.value(k, depth + 1)?;
+            self.value(v, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn class(&mut self, tag: u64, module: &str, name: &str) {
+        self.head(MAJOR_TAG, tag);
+        self.head(MAJOR_ARRAY, 2);
+        self.text(module);
+        self.text(name);
+    }
+
+    /// The optional subclass items of an instance or REDUCE, as trailing
+    /// array elements: a map, then an array (`null` for absent items).
+    fn trailing_items(
+        &mut self,
+        dict_items: Option<&[(PickleValue, PickleValue)]>,
+        list_items: Option<&[PickleValue]>,
+        depth: usize,
+    ) -> Result<(), CodecError> {
+        if dict_items.is_none() && list_items.is_none() {
+            return Ok(());
+        }
+        match dict_items {
+            Some(pairs) => self.map(pairs, depth)?,
+            None => self.out.push(NULL),
+        }
+        match list_items {
+            Some(items) => self.array(items, depth),
+            None => {
+                self.out.push(NULL);
+                Ok(())
+            }
+        }
+    }
+
+    /// Length of an instance or REDUCE array with `base` fixed elements.
+    fn item_count(
+        base: u64,
+        dict_items: Option<&[(PickleValue, PickleValue)]>,
+        list_items: Option<&[PickleValue]>,
+    ) -> u64 {
+        if dict_items.is_none() && list_items.is_none() {
+            base
+        } else {
+            base + 2
+        }
+    }
+
+    /// Write a known type's JSON marker: tag 0 for a datetime with a UTC
+    /// offset, the marker tag otherwise.
+    fn marker(&mut self, typed: &Value) {
+        if let Some(Value::String(iso)) = typed.get("@dt") {
+            if typed.as_object().is_some_and(|m| m.len() == 1) && has_utc_offset(iso) {
+                self.head(MAJOR_TAG, TAG_DATETIME);
+                self.text(iso);
+                return;
+            }
+        }
+        self.head(MAJOR_TAG, TAG_MARKER);
+        self.json(typed);
+    }
+
+    fn json(&mut self, val: &Value) {
+        match val {
+            Value::Null => self.out.push(NULL),
+            Value::Bool(b) => self.out.push(if *b { TRUE } else { FALSE }),
+            Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    self.int(i);
+                } else if let Some(u) = n.as_u64() {
+                    self.head(MAJOR_UINT, u);
+                } else if let Ok(bi) = n.to_string().parse::<BigInt>() {
+                    self.bigint(&bi);
+                } else {
+                    self.float(n.as_f64().unwrap_or(f64::NAN));
+                }
+            }
+            Value::String(s) => self.text(s),
+            Value::Array(items) => {
+                self.head(MAJOR_ARRAY, items.len() as u64);
+                for item in items {
+                    self.json(item);
+                }
+            }
+            Value::Object(map) => {
+                self.head(MAJOR_MAP, map.len() as u64);
+                for (k, v) in map {
+                    self.text(k);
+                    self.json(v);
+                }
+            }
+        }
+    }
+
+    fn value(&mut self, val: &PickleValue, depth: usize) -> Result<(), CodecError> {
+        if depth > MAX_DEPTH {
+            return Err(depth_exceeded());
+        }
+        match val {
+            PickleValue::None => self.out.push(NULL),
+            PickleValue::Bool(b) => self.out.push(if *b { TRUE } else { FALSE }),
+            PickleValue::Int(i) => self.int(*i),
+            PickleValue::BigInt(bi) => self.bigint(bi),
+            PickleValue::Float(f) => self.float(*f),
+            PickleValue::String(s) => self.text(s),
+            PickleValue::SurrogateString(b) => {
+                self.head(MAJOR_TAG, TAG_SURROGATES);
+                self.bytes(b);
+            }
+            PickleValue::Bytes(b) => self.bytes(b),
+            PickleValue::List(items) => self.array(items, depth)?,
+            PickleValue::Dict(pairs) => self.map(pairs, depth)?,
+            PickleValue::Tuple(items) => {
+                self.head(MAJOR_TAG, TAG_TUPLE);
+                self.array(items, depth)?;
+            }
+            PickleValue::Set(items) => {
+                self.head(MAJOR_TAG, TAG_SET);
+                self.array(items, depth)?;
+            }
+            PickleValue::FrozenSet(items) => {
+                self.head(MAJOR_TAG, TAG_FROZENSET);
+                self.array(items, depth)?;
+            }
+            PickleValue::Global { module, name } => self.class(TAG_GLOBAL, module, name),
+            PickleValue::Blocked { module, name } => self.class(TAG_BLOCKED, module, name),
+            PickleValue::PersistentRef(pid) => {
+                self.head(MAJOR_TAG, TAG_REF);
+                self.value(pid, depth + 1)?;
+            }
+            PickleValue::Instance(inst) => {
+                let InstanceData {
+                    module,
+                    name,
+                    state,
+                    dict_items,
+                    list_items,
+                } = inst.as_ref();
+                if let Some(typed) = known_types::try_instance_to_typed_json(
+                    module,
+                    name,
+                    state,
+                    &pickle_value_to_json,
+                )? {
+                    self.marker(&typed);
+                    return Ok(());
+                }
+                let dict_items = dict_items.as_deref().map(Vec::as_slice);
+                let list_items = list_items.as_deref().map(Vec::as_slice);
+                self.head(MAJOR_TAG, TAG_INSTANCE);
+                self.head(MAJOR_ARRAY, Self::item_count(3, dict_items, list_items));
+                self.text(module);
+                self.text(name);
+                self.value(state, depth + 1)?;
+                self.trailing_items(dict_items, list_items, depth + 1)?;
+            }
+            PickleValue::Reduce {
+                callable,
+                args,
+                dict_items,
+                list_items,
+                newobj,
+            } => {
+                if dict_items.is_none() && list_items.is_none() {
+                    if let Some((tag, items)) = set_reduce(callable, args) {
+                        self.head(MAJOR_TAG, tag);
+                        return self.array(items, depth);
+                    }
+                }
+                let dict_items = dict_items.as_deref().map(Vec::as_slice);
+                let list_items = list_items.as_deref().map(Vec::as_slice);
+                if let Some(typed) = known_types::try_reduce_to_typed_json(
+                    callable,
+                    args,
+                    dict_items,
+                    &pickle_value_to_json,
+                )? {
+                    self.marker(&typed);
+                    return Ok(());
+                }
+                self.head(MAJOR_TAG, if *newobj { TAG_NEWOBJ } else { TAG_REDUCE });
+                self.head(MAJOR_ARRAY, Self::item_count(2, dict_items, list_items));
+                self.value(callable, depth + 1)?;
+                self.value(args, depth + 1)?;
+                self.trailing_items(dict_items, list_items, depth + 1)?;
+            }
+            PickleValue::NewObjEx { cls, args, kwargs } => {
+                self.head(MAJOR_TAG, TAG_NEWOBJ_EX);
+                self.head(MAJOR_ARRAY, 3);
+                self.value(cls, depth + 1)?;
+                self.value(args, depth + 1)?;
+                self.value(kwargs, depth + 1)?;
+            }
+            PickleValue::RawPickle(data) => {
+                self.head(MAJOR_TAG, TAG_RAW_PICKLE);
+                self.bytes(data);
+            }
+            PickleValue::Shared { id, value } => {
+                self.head(MAJOR_TAG, TAG_SHARED);
+                self.head(MAJOR_ARRAY, 2);
+                self.head(MAJOR_UINT, u64::from(*id));
+                self.value(value, depth + 1)?;
+            }
+            PickleValue::BackRef(id) => {
+                self.head(MAJOR_TAG, TAG_BACKREF);
+                self.head(MAJOR_UINT, u64::from(*id));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The set tag and items of a `builtins.set([...])` or
+/// `builtins.frozenset([...])` REDUCE (protocols 2 and 3).
+fn set_reduce<'a>(
+    callable: &PickleValue,
+    args: &'a PickleValue,
+) -> Option<(u64, &'a [PickleValue])> {
+    let PickleValue::Global { module, name } = callable else {
+        return None;
+    };
+    if !known_types::is_builtins_module(module) {
+        return None;
+    }
+    let tag = match name.as_str() {
+        "set" => TAG_SET,
+        "frozenset" => TAG_FROZENSET,
+        _ => return None,
+    };
+    match args {
+        PickleValue::Tuple(args) => match args.as_slice() {
+            [PickleValue::List(items)] => Some((tag, items)),
+            [] => Some((tag, &[])),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether an ISO datetime ends in a `+HH:MM`/`-HH:MM` offset, as RFC 3339
+/// (and so tag 0) requires.
+fn has_utc_offset(iso: &str) -> bool {
+    let b = iso.as_bytes();
+    b.len() > 6 && matches!(b[b.len() - 6], b'+' | b'-') && b[b.len() - 3] == b':'
+}
+
+// ---------------------------------------------------------------------------
+// Decoding
+// ---------------------------------------------------------------------------
+
+/// Length of a data item: definite, or indefinite up to a break.
+enum Len {
+    Definite(u64),
+    Indefinite,
+}
+
+struct CborReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> CborReader<'a> {
+    fn byte(&mut self) -> Result<u8, CodecError> {
+        let b = *self.data.get(self.pos).ok_or(CodecError::UnexpectedEof)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn take(&mut self, n: u64) -> Result<&'a [u8], CodecError> {
+        let n = usize::try_from(n).map_err(|_| CodecError::UnexpectedEof)?;
+        let end = self.pos.checked_add(n).ok_or(CodecError::UnexpectedEof)?;
+        let slice = self
+            .data
+            .get(self.pos..end)
+            .ok_or(CodecError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn argument(&mut self, info: u8) -> Result<Len, CodecError> {
+        let n = match info {
+            0..=23 => u64::from(info),
+            24 => u64::from(self.byte()?),
+            25 => u64::from(u16::from_be_bytes(self.take(2)?.try_into().unwrap())),
+            26 => u64::from(u32::from_be_bytes(self.take(4)?.try_into().unwrap())),
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            31 => return Ok(Len::Indefinite),
+            _ => return Err(invalid(format!("reserved CBOR additional info {info}"))),
+        };
+        Ok(Len::Definite(n))
+    }
+
+    fn definite(&mut self, info: u8) -> Result<u64, CodecError> {
+        match self.argument(info)? {
+            Len::Definite(n) => Ok(n),
+            Len::Indefinite => Err(invalid("unexpected indefinite length in CBOR")),
+        }
+    }
+
+    /// Whether the next byte is a break, consuming it if so.
+    fn at_break(&mut self) -> Result<bool, CodecError> {
+        if *self.data.get(self.pos).ok_or(CodecError::UnexpectedEof)? == BREAK {
+            self.pos += 1;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Capacity hint that cannot exceed what the remaining input can hold.
+    fn capacity(&self, n: u64) -> usize {
+        usize::try_from(n)
+            .unwrap_or(usize::MAX)
+            .min(self.data.len() - self.pos)
+    }
+
+    /// A byte or text string's contents, joining indefinite chunks.
+    fn string_bytes(&mut self, major: u8, info: u8) -> Result<Vec<u8>, CodecError> {
+        match self.argument(info)? {
+            Len::Definite(n) => Ok(self.take(n)?.to_vec()),
+            Len::Indefinite => {
+                let mut out = Vec::new();
+                while !self.at_break()? {
+                    let ib = self.byte()?;
+                    if ib >> 5 != major {
+                        return Err(invalid("mixed chunk types in CBOR string"));
+                    }
+                    let n = self.definite(ib & 0x1f)?;
+                    out.extend_from_slice(self.take(n)?);
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    fn text(&mut self, info: u8) -> Result<String, CodecError> {
+        String::from_utf8(self.string_bytes(MAJOR_TEXT, info)?).map_err(|_| CodecError::InvalidUtf8)
+    }
+
+    fn float(&mut self, info: u8) -> Result<f64, CodecError> {
+        Ok(match info {
+            25 => f16_to_f64(u16::from_be_bytes(self.take(2)?.try_into().unwrap())),
+            26 => f64::from(f32::from_be_bytes(self.take(4)?.try_into().unwrap())),
+            _ => f64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+        })
+    }
+
+    /// Read the elements of an array or the pairs of a map with `item`.
+    fn items<T>(
+        &mut self,
+        info: u8,
+        mut item: impl FnMut(&mut Self) -> Result<T, CodecError>,
+    ) -> Result<Vec<T>, CodecError> {
+        match self.argument(info)? {
+            Len::Definite(n) => {
+                let mut out = Vec::with_capacity(self.capacity(n));
+                for _ in 0..n {
+                    out.push(item(self)?);
+                }
+                Ok(out)
+            }
+            Len::Indefinite => {
+                let mut out = Vec::new();
+                while !self.at_break()? {
+                    out.push(item(self)?);
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Vec<PickleValue>, CodecError> {
+        let ib = self.byte()?;
+        if ib >> 5 != MAJOR_ARRAY {
+            return Err(invalid("expected a CBOR array"));
+        }
+        self.items(ib & 0x1f, |r| r.value(depth + 1))
+    }
+
+    fn map_items(
+        &mut self,
+        info: u8,
+        depth: usize,
+    ) -> Result<Vec<(PickleValue, PickleValue)>, CodecError> {
+        self.items(info, |r| Ok((r.value(depth + 1)?, r.value(depth + 1)?)))
+    }
+
+    fn value(&mut self, depth: usize) -> Result<PickleValue, CodecError> {
+        if depth > MAX_DEPTH {
+            return Err(depth_exceeded());
+        }
+        let ib = self.byte()?;
+        let (major, info) = (ib >> 5, ib & 0x1f);
+        Ok(match major {
+            MAJOR_UINT => {
+                let n = self.definite(info)?;
+                i64::try_from(n).map_or_else(|_| PickleValue::BigInt(n.into()), PickleValue::Int)
+            }
+            MAJOR_NINT => {
+                let n = self.definite(info)?;
+                match i64::try_from(n) {
+                    Ok(i) => PickleValue::Int(!i),
+                    Err(_) => PickleValue::BigInt(-BigInt::from(n) - 1),
+                }
+            }
+            MAJOR_BYTES => PickleValue::Bytes(self.string_bytes(MAJOR_BYTES, info)?),
+            MAJOR_TEXT => PickleValue::String(self.text(info)?.into()),
+            MAJOR_ARRAY => PickleValue::List(self.items(info, |r| r.value(depth + 1))?),
+            MAJOR_MAP => PickleValue::Dict(self.map_items(info, depth)?),
+            MAJOR_TAG => {
+                let tag = self.definite(info)?;
+                self.tagged(tag, depth)?
+            }
+            _ => match info {
+                20 => PickleValue::Bool(false),
+                21 => PickleValue::Bool(true),
+                22 => PickleValue::None,
+                25..=27 => PickleValue::Float(self.float(info)?),
+                _ => return Err(invalid(format!("unsupported CBOR simple value {info}"))),
+            },
+        })
+    }
+
+    fn class(&mut self, depth: usize) -> Result<(String, String), CodecError> {
+        match <[PickleValue; 2]>::try_from(self.array(depth)?) {
+            Ok([PickleValue::String(module), PickleValue::String(name)]) => {
+                Ok((module.to_string(), name.to_string()))
+            }
+            _ => Err(invalid("expected a CBOR [module, name] array")),
+        }
+    }
+
+    /// The trailing dict and list items of an instance or REDUCE array.
+    #[allow(clippy::type_complexity)]
+    fn trailing_items(
+        rest: &mut std::vec::IntoIter<PickleValue>,
+    ) -> Result<
+        (
+            Option<Box<Vec<(PickleValue, PickleValue)>>>,
+            Option<Box<Vec<PickleValue>>>,
+        ),
+        CodecError,
+    > {
+        let dict_items = match rest.next() {
+            None | Some(PickleValue::None) => None,
+            Some(PickleValue::Dict(pairs)) => Some(Box::new(pairs)),
+            Some(_) => return Err(invalid("CBOR dict items must be a map")),
+        };
+        let list_items = match rest.next() {
+            None | Some(PickleValue::None) => None,
+            Some(PickleValue::List(items)) => Some(Box::new(items)),
+            Some(_) => return Err(invalid("CBOR list items must be an array")),
+        };
+        if rest.next().is_some() {
+            return Err(invalid("too many CBOR array elements"));
+        }
+        Ok((dict_items, list_items))
+    }
+
+    fn tagged(&mut self, tag: u64, depth: usize) -> Result<PickleValue, CodecError> {
+        Ok(match tag {
+            TAG_DATETIME => {
+                let ib = self.byte()?;
+                if ib >> 5 != MAJOR_TEXT {
+                    return Err(invalid("CBOR tag 0 must hold a text string"));
+                }
+                let mut map = Map::new();
+                map.insert("@dt".to_string(), Value::String(self.text(ib & 0x1f)?));
+                marker_to_pickle_value(&map)?
+            }
+            TAG_POS_BIGNUM | TAG_NEG_BIGNUM => {
+                let ib = self.byte()?;
+                if ib >> 5 != MAJOR_BYTES {
+                    return Err(invalid("CBOR bignum must hold a byte string"));
+                }
+                let n =
+                    BigInt::from_bytes_be(Sign::Plus, &self.string_bytes(MAJOR_BYTES, ib & 0x1f)?);
+                let n = if tag == TAG_NEG_BIGNUM { -n - 1 } else { n };
+                i64::try_from(&n).map_or(PickleValue::BigInt(n), PickleValue::Int)
+            }
+            TAG_SET => PickleValue::Set(self.array(depth)?),
+            TAG_TUPLE => PickleValue::Tuple(self.array(depth)?),
+            TAG_FROZENSET => PickleValue::FrozenSet(self.array(depth)?),
+            TAG_GLOBAL => {
+                let (module, name) = self.class(depth)?;
+                PickleValue::Global { module, name }
+            }
+            TAG_BLOCKED => {
+                let (module, name) = self.class(depth)?;
+                PickleValue::Blocked { module, name }
+            }
+            TAG_REF => PickleValue::PersistentRef(Box::new(self.value(depth + 1)?)),
+            TAG_INSTANCE => {
+                let mut items = self.array(depth)?.into_iter();
+                let (
+                    Some(PickleValue::String(module)),
+                    Some(PickleValue::String(name)),
+                    Some(state),
+                ) = (items.next(), items.next(), items.next())
+                else {
+                    return Err(invalid("CBOR instance must be [module, name, state, ...]"));
+                };
+                let (dict_items, list_items) = Self::trailing_items(&mut items)?;
+                let mut inst = InstanceData::new(&*module, &*name, state);
+                inst.dict_items = dict_items;
+                inst.list_items = list_items;
+                PickleValue::Instance(Box::new(inst))
+            }
+            TAG_REDUCE | TAG_NEWOBJ => {
+                let mut items = self.array(depth)?.into_iter();
+                let (Some(callable), Some(args)) = (items.next(), items.next()) else {
+                    return Err(invalid("CBOR reduce must be [callable, args, ...]"));
+                };
+                let (dict_items, list_items) = Self::trailing_items(&mut items)?;
+                PickleValue::Reduce {
+                    callable: Box::new(callable),
+                    args: Box::new(args),
+                    dict_items,
+                    list_items,
+                    newobj: tag == TAG_NEWOBJ,
+                }
+            }
+            TAG_NEWOBJ_EX => match <[PickleValue; 3]>::try_from(self.array(depth)?) {
+                Ok([cls, args, kwargs]) => PickleValue::NewObjEx {
+                    cls: Box::new(cls),
+                    args: Box::new(args),
+                    kwargs: Box::new(kwargs),
+                },
+                _ => return Err(invalid("CBOR NEWOBJ_EX must be [cls, args, kwargs]")),
+            },
+            TAG_RAW_PICKLE => match self.value(depth + 1)? {
+                PickleValue::Bytes(data) => PickleValue::RawPickle(data),
+                _ => return Err(invalid("CBOR raw pickle must be a byte string")),
+            },
+            TAG_SURROGATES => match self.value(depth + 1)? {
+                PickleValue::Bytes(data) => {
+                    for_each_piece(&data, |_| {})?;
+                    PickleValue::SurrogateString(data)
+                }
+                _ => return Err(invalid("CBOR surrogate string must be a byte string")),
+            },
+            TAG_SHARED => match <[PickleValue; 2]>::try_from(self.array(depth)?) {
+                Ok([PickleValue::Int(id), value]) => PickleValue::Shared {
+                    id: shared_id(id)?,
+                    value: Box::new(value),
+                },
+                _ => return Err(invalid("CBOR shared value must be [id, value]")),
+            },
+            TAG_BACKREF => match self.value(depth + 1)? {
+                PickleValue::Int(id) => PickleValue::BackRef(shared_id(id)?),
+                _ => return Err(invalid("CBOR back reference must be an integer")),
+            },
+            TAG_MARKER => match self.json(depth + 1)? {
+                Value::Object(map) => marker_to_pickle_value(&map)?,
+                _ => return Err(invalid("CBOR marker must be a map")),
+            },
+            _ => return Err(invalid(format!("unsupported CBOR tag {tag}"))),
+        })
+    }
+
+    /// Decode the JSON marker object of a known type.
+    fn json(&mut self, depth: usize) -> Result<Value, CodecError> {
+        if depth > MAX_DEPTH {
+            return Err(depth_exceeded());
+        }
+        let start = self.pos;
+        let ib = self.byte()?;
+        let (major, info) = (ib >> 5, ib & 0x1f);
+        Ok(match major {
+            MAJOR_TEXT => Value::String(self.text(info)?),
+            MAJOR_ARRAY => Value::Array(self.items(info, |r| r.json(depth + 1))?),
+            MAJOR_MAP => {
+                let pairs = self.items(info, |r| {
+                    let Value::String(key) = r.json(depth + 1)? else {
+                        return Err(invalid("CBOR marker keys must be text"));
+                    };
+                    Ok((key, r.json(depth + 1)?))
+                })?;
+                Value::Object(pairs.into_iter().collect())
+            }
+            _ => {
+                self.pos = start;
+                match self.value(depth)? {
+                    PickleValue::None => Value::Null,
+                    PickleValue::Bool(b) => Value::Bool(b),
+                    PickleValue::Int(i) => Value::from(i),
+                    PickleValue::BigInt(bi) => bi
+                        .to_string()
+                        .parse::<Number>()
+                        .map(Value::Number)
+                        .map_err(|e| CodecError::Json(e.to_string()))?,
+                    PickleValue::Float(f) => Number::from_f64(f).map_or(Value::Null, Value::Number),
+                    _ => return Err(invalid("unsupported CBOR value in a marker")),
+                }
+            }
+        })
+    }
+}
+
+fn shared_id(id: i64) -> Result<u32, CodecError> {
+    u32::try_from(id).map_err(|_| invalid("CBOR shared id out of range"))
+}
+
+fn marker_to_pickle_value(map: &Map<String, Value>) -> Result<PickleValue, CodecError> {
+    known_types::try_typed_json_to_pickle_value(map, &json_to_pickle_value)?
+        .ok_or_else(|| invalid("unknown marker in CBOR"))
+}
+
+/// Widen an IEEE 754 half-precision float.
+fn f16_to_f64(half: u16) -> f64 {
+    let exp = (half >> 10) & 0x1f;
+    let mant = f64::from(half & 0x3ff);
+    let val = match exp {
+        0 => mant * 2f64.powi(-24),
+        31 if mant == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (mant + 1024.0) * 2f64.powi(i32::from(exp) - 25),
+    };
+    if half & 0x8000 != 0 {
+        -val
+    } else {
+        val
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(val: &PickleValue) -> PickleValue {
+        cbor_to_pickle_value(&pickle_value_to_cbor(val).unwrap()).unwrap()
+    }
+
+    fn s(v: &str) -> PickleValue {
+        PickleValue::String(v.into())
+    }
+
+    #[test]
+    fn test_scalars() {
+        for (val, cbor) in [
+            (PickleValue::None, &b"\xf6"[..]),
+            (PickleValue::Bool(true), b"\xf5"),
+            (PickleValue::Int(0), b"\x00"),
+            (PickleValue::Int(24), b"\x18\x18"),
+            (PickleValue::Int(-1), b"\x20"),
+            (PickleValue::Int(-500), b"\x39\x01\xf3"),
+            (
+                PickleValue::Int(i64::MIN),
+                b"\x3b\x7f\xff\xff\xff\xff\xff\xff\xff",
+            ),
+            (
+                PickleValue::Float(1.5),
+                b"\xfb\x3f\xf8\x00\x00\x00\x00\x00\x00",
+            ),
+            (s("a"), b"\x61a"),
+            (PickleValue::Bytes(vec![0, 255]), b"\x42\x00\xff"),
+        ] {
+            assert_eq!(pickle_value_to_cbor(&val).unwrap(), cbor, "{val:?}");
+            assert_eq!(cbor_to_pickle_value(cbor).unwrap(), val);
+        }
+    }
+
+    #[test]
+    fn test_bigint() {
+        let u64_max = PickleValue::BigInt(u64::MAX.into());
+        assert_eq!(
+            pickle_value_to_cbor(&u64_max).unwrap(),
+            b"\x1b\xff\xff\xff\xff\xff\xff\xff\xff"
+        );
+        assert_eq!(roundtrip(&u64_max), u64_max);
+        // 2**64 and -2**64 - 1 need the bignum tags
+        let big = BigInt::from(u64::MAX) + 1u8;
+        assert_eq!(
+            pickle_value_to_cbor(&PickleValue::BigInt(big.clone())).unwrap(),
+            b"\xc2\x49\x01\x00\x00\x00\x00\x00\x00\x00\x00"
+        );
+        for n in [big.clone(), -big - 1, BigInt::from(i64::MIN) - 1] {
+            let val = PickleValue::BigInt(n);
+            assert_eq!(roundtrip(&val), val);
+        }
+    }
+
+    #[test]
+    fn test_structures_roundtrip() {
+        let mut inst = InstanceData::new(
+            "myapp",
+            "Doc",
+            PickleValue::Dict(vec![(PickleValue::Int(1), s("one"))]),
+        );
+        inst.list_items = Some(Box::new(vec![PickleValue::None]));
+        let val = PickleValue::List(vec![
+            PickleValue::Tuple(vec![s("a"), PickleValue::Bytes(vec![1; 8])]),
+            PickleValue::Set(vec![PickleValue::Int(1)]),
+            PickleValue::FrozenSet(vec![]),
+            PickleValue::Global {
+                module: "builtins".into(),
+                name: "len".into(),
+            },
+            PickleValue::Blocked {
+                module: "os".into(),
+                name: "system".into(),
+            },
+            PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+                PickleValue::Bytes(vec![0; 8]),
+                PickleValue::None,
+            ]))),
+            PickleValue::Instance(Box::new(inst)),
+            PickleValue::Reduce {
+                callable: Box::new(PickleValue::Global {
+                    module: "myapp".into(),
+                    name: "make".into(),
+                }),
+                args: Box::new(PickleValue::Tuple(vec![])),
+                dict_items: Some(Box::new(vec![(s("k"), s("v"))])),
+                list_items: None,
+                newobj: false,
+            },
+            PickleValue::Reduce {
+                callable: Box::new(PickleValue::Global {
+                    module: "myapp".into(),
+                    name: "Tag".into(),
+                }),
+                args: Box::new(PickleValue::Tuple(vec![s("x")])),
+                dict_items: None,
+                list_items: None,
+                newobj: true,
+            },
+            PickleValue::NewObjEx {
+                cls: Box::new(PickleValue::Global {
+                    module: "myapp".into(),
+                    name: "Point".into(),
+                }),
+                args: Box::new(PickleValue::Tuple(vec![PickleValue::Int(1)])),
+                kwargs: Box::new(PickleValue::Dict(vec![(s("y"), PickleValue::Int(2))])),
+            },
+            PickleValue::RawPickle(b"\x80\x03N.".to_vec()),
+            PickleValue::Shared {
+                id: 1,
+                value: Box::new(PickleValue::List(vec![PickleValue::BackRef(1)])),
+            },
+        ]);
+        assert_eq!(roundtrip(&val), val);
+    }
+
+    #[test]
+    fn test_known_types() {
+        let json = serde_json::json!([
+            {"@dt": "2025-06-15T12:00:00+05:30"},
+            {"@dt": "2025-06-15T12:00:00"},
+            {"@date": "2025-01-02"},
+            {"@set": [1, 2]},
+            {"@dec": "1.50"},
+        ]);
+        let val = json_to_pickle_value(&json).unwrap();
+        let cbor = pickle_value_to_cbor(&val).unwrap();
+        // An aware datetime is a tag 0 string
+        let tagged = b"\xc0\x78\x192025-06-15T12:00:00+05:30";
+        assert!(cbor.windows(tagged.len()).any(|w| w == tagged));
+        let back = cbor_to_pickle_value(&cbor).unwrap();
+        assert_eq!(pickle_value_to_json(&back).unwrap(), json);
+    }
+
+    #[test]
+    fn test_foreign_encodings() {
+        // Indefinite-length array and text, half floats
+        let cbor = b"\x9f\x7f\x61a\x61b\xff\xf9\x3c\x00\xf9\xc4\x00\xff";
+        assert_eq!(
+            cbor_to_pickle_value(cbor).unwrap(),
+            PickleValue::List(vec![
+                s("ab"),
+                PickleValue::Float(1.0),
+                PickleValue::Float(-4.0)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        for cbor in [
+            &b""[..],
+            b"\x82\x01",
+            b"\x01\x02",
+            b"\xd9\x01\x00\x01",
+            b"\xd9\xd9\xf8\x01",
+            b"\x62\xff\xfe",
+            b"\xf7",
+            b"\x1c",
+        ] {
+            assert!(cbor_to_pickle_value(cbor).is_err(), "{cbor:?}");
+        }
+    }
+
+    #[test]
+    fn test_max_depth_exceeded() {
+        // Arrays nested deeper than MAX_DEPTH; a larger stack as in encode.rs
+        let result = std::thread::Builder::new()
+            .stack_size(16 * 1024 * 1024)
+            .spawn(|| {
+                let deep = [vec![0x81; MAX_DEPTH + 2], vec![0xf6]].concat();
+                let err = cbor_to_pickle_value(&deep).unwrap_err();
+                assert!(err.to_string().contains("nesting depth"));
+            })
+            .unwrap()
+            .join();
+        assert!(result.is_ok(), "test thread panicked");
+    }
+}
@@ -0,0 +1,71 @@
+//! Encoding the `@dangling` key of lenient decoding.
+//!
+//! Lenient decoding keeps the unpaired last item of a corrupted dict
+//! under the [`DANGLING_KEY`] key. That item was never an entry of the
+//! original dict, so writing it back as one would give the repaired
+//! object an attribute it never had. The encoders therefore refuse a dict
+//! holding the key, unless the caller asks for it to be dropped (see
+//! [`with_dangling_dropped`]).
+
+use std::cell::Cell;
+
+use crate::decode::DANGLING_KEY;
+use crate::error::CodecError;
+
+thread_local! {
+    /// Whether encoding drops `@dangling` keys (off by default).
+    static DROP: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f` with the encodings it makes on this thread dropping the
+/// `@dangling` key and its item from every dict, instead of failing.
+///
+/// ```
+/// use zodb_json_codec::{json_to_pickle_value, with_dangling_dropped, PickleValue};
+///
+/// let json = serde_json::json!({"a": 1, "@dangling": "b"});
+/// assert!(json_to_pickle_value(&json).is_err());
+/// let val = with_dangling_dropped(|| json_to_pickle_value(&json))?;
+/// let a = PickleValue::String("a".into());
+/// assert_eq!(val, PickleValue::Dict(vec![(a, PickleValue::Int(1))]));
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn with_dangling_dropped<R>(f: impl FnOnce() -> R) -> R {
+    let _scope = DropDanglingScope::enter(true);
+    f()
+}
+
+/// Sets `@dangling` dropping on the current thread while alive.
+pub(crate) struct DropDanglingScope {
+    previous: bool,
+}
+
+impl DropDanglingScope {
+    pub(crate) fn enter(enabled: bool) -> Self {
+        DropDanglingScope {
+            previous: DROP.with(|d| d.replace(enabled)),
+        }
+    }
+}
+
+impl Drop for DropDanglingScope {
+    fn drop(&mut self) {
+        DROP.with(|d| d.set(self.previous));
+    }
+}
+
+/// Whether the dict key `key` is left out of the encoding: `@dangling` is
+/// dropped inside [`with_dangling_dropped`] and rejected outside it.
+#[inline]
+pub(crate) fn skip_key(key: &str) -> Result<bool, CodecError> {
+    if key != DANGLING_KEY {
+        return Ok(false);
+    }
+    if DROP.with(Cell::get) {
+        return Ok(true);
+    }
+    Err(CodecError::InvalidData(format!(
+        "{DANGLING_KEY} holds the unpaired item of a dict repaired by lenient decoding; \
+         remove it or drop it when encoding"
+    )))
+}
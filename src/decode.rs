@@ -1,10 +1,128 @@
+use crate::analyze::memo_index;
 use crate::error::CodecError;
+use crate::known_types::is_timestamp_module;
+use crate::limits::{find_line_end, DecodeLimits, LineLimits, DEFAULT_MAX_MEMO_ENTRIES};
+#[cfg(test)]
+use crate::limits::DEFAULT_MAX_NAME_LINE;
 use crate::opcodes::*;
+use crate::policy::DecodePolicy;
+use crate::quotas::{ClassQuotas, Deadline};
+use crate::rename::{self, ClassRenames};
+use crate::shared::{self, is_shareable};
+use crate::surrogates::{self, decode_text, SurrogatePolicy};
 use crate::types::{InstanceData, PickleValue};
+use crate::warnings::{self, WarningCode};
+use crate::zodb::{extract_class_info, find_pickle_end, skip_opcode};
 use num_bigint::BigInt;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Memo entries the encoders may use, so that their output decodes with
+/// the default limits.
+pub(crate) const MAX_MEMO_SIZE: usize = DEFAULT_MAX_MEMO_ENTRIES;
+/// Approximate size of one decoded value, not counting its payload.
+const VALUE_SIZE: usize = std::mem::size_of::<PickleValue>();
+/// Opcodes executed between two checks of a time quota deadline.
+const DEADLINE_CHECK_INTERVAL: u32 = 4096;
+
+/// Key under which lenient decoding keeps the unpaired last item of a
+/// `DICT` or `SETITEMS` with an odd item count.
+pub const DANGLING_KEY: &str = "@dangling";
+
+/// How the decoder reads Python 2 `str` values (the STRING, BINSTRING
+/// and SHORT_BINSTRING opcodes).
+///
+/// The text modes give Python 2 attribute values and keys as strings,
+/// like `zodbpickle` with an `encoding`, instead of `@b` blobs; they then
+/// encode back as Python 3 `str`. The binary `str` values ZODB and the
+/// standard library rely on stay bytes: OIDs in persistent ids and the
+/// packed arguments of `datetime` and `TimeStamp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Py2Strings {
+    /// Keep them as bytes (`@b`), losslessly.
+    #[default]
+    Bytes,
+    /// Decode them as Latin-1, which never fails.
+    Latin1,
+    /// Decode them as UTF-8; values that are not valid UTF-8 stay bytes.
+    Utf8,
+}
+
+impl Py2Strings {
+    /// The value of a Python 2 `str`.
+    fn decode(self, bytes: Vec<u8>) -> PickleValue {
+        match self {
+            Py2Strings::Bytes => PickleValue::Bytes(bytes),
+            Py2Strings::Latin1 => {
+                PickleValue::String(bytes.iter().map(|&b| char::from(b)).collect::<String>().into())
+            }
+            Py2Strings::Utf8 => match String::from_utf8(bytes) {
+                Ok(s) => PickleValue::String(s.into()),
+                Err(e) => PickleValue::Bytes(e.into_bytes()),
+            },
+        }
+    }
 
-const MAX_MEMO_SIZE: usize = 100_000;
-const MAX_BINARY_SIZE: u64 = 256 * 1024 * 1024; // 256 MB
+    /// Turn `val` back into the bytes it was decoded from, if it is text.
+    fn restore(self, val: &mut PickleValue) {
+        let PickleValue::String(s) = val else {
+            return;
+        };
+        let bytes = match self {
+            Py2Strings::Bytes => return,
+            Py2Strings::Latin1 => match s.chars().map(|c| u8::try_from(c).ok()).collect() {
+                Some(bytes) => bytes,
+                None => return,
+            },
+            Py2Strings::Utf8 => s.as_bytes().to_vec(),
+        };
+        *val = PickleValue::Bytes(bytes);
+    }
+
+    /// Restore the OID of a ZODB persistent id: `oid`, `(oid, class)`,
+    /// `[oid]`, `['w', (oid, ..)]`, `['n', (db, oid)]` or
+    /// `['m', (db, oid, class)]`.
+    fn restore_oid(self, pid: &mut PickleValue) {
+        if matches!(pid, PickleValue::String(_)) {
+            return self.restore(pid);
+        }
+        let oid = match pid {
+            PickleValue::Tuple(items) => items.first_mut(),
+            PickleValue::List(items) => match items.as_mut_slice() {
+                [oid] => Some(oid),
+                [PickleValue::String(tag), PickleValue::Tuple(args)] => {
+                    match (&**tag, args.as_mut_slice()) {
+                        ("w", [oid, ..]) | ("n" | "m", [_, oid, ..]) => Some(oid),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(oid) = oid {
+            self.restore(oid);
+        }
+    }
+
+    /// Restore the packed argument of `datetime.datetime/date/time(...)`
+    /// and `TimeStamp(...)`.
+    fn restore_packed_arg(self, callable: &PickleValue, args: &mut PickleValue) {
+        let PickleValue::Global { module, name } = callable else {
+            return;
+        };
+        let packed = match (module.as_str(), name.as_str()) {
+            ("datetime", "datetime" | "date" | "time") => true,
+            (m, "TimeStamp") => is_timestamp_module(m),
+            _ => false,
+        };
+        if let (true, PickleValue::Tuple(items)) = (packed, args) {
+            if let Some(first) = items.first_mut() {
+                self.restore(first);
+            }
+        }
+    }
+}
 
 /// Decode pickle bytes into a PickleValue AST.
 ///
@@ -13,24 +131,264 @@ const MAX_BINARY_SIZE: u64 = 256 * 1024 * 1024; // 256 MB
 /// No Python objects are constructed — only our intermediate AST.
 pub fn decode_pickle(data: &[u8]) -> Result<PickleValue, CodecError> {
     let mut decoder = Decoder::new(data);
-    decoder.run()
+    decoder.run_or_raw(true)
+}
+
+/// `decode_pickle` with per-call `options`.
+///
+/// ```
+/// use zodb_json_codec::{decode_pickle_with_options, DecodeOptions, PickleValue};
+///
+/// // Unknown opcode 0xff: kept as a raw pickle instead of failing
+/// let data = b"\x80\x03\xff.";
+/// let options = DecodeOptions::new().with_lenient(true);
+/// let val = decode_pickle_with_options(data, &options)?;
+/// assert_eq!(val, PickleValue::RawPickle(data.to_vec()));
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn decode_pickle_with_options(
+    data: &[u8],
+    options: &DecodeOptions,
+) -> Result<PickleValue, CodecError> {
+    decode_pickle_with(data, &[], options)
+}
+
+/// Decode a protocol 5 pickle whose buffers were pickled out-of-band.
+///
+/// `buffers` are the contents of the buffers CPython passed to
+/// `buffer_callback`, in order; each `NEXT_BUFFER` opcode takes the next
+/// one. Buffers decode to bytes values, as `BYTEARRAY8` in-band data does.
+///
+/// ```
+/// use zodb_json_codec::{decode_pickle_with_buffers, PickleValue};
+///
+/// // pickle.dumps(PickleBuffer(b"abc"), protocol=5, buffer_callback=...)
+/// let data = b"\x80\x05\x97\x98.";
+/// let val = decode_pickle_with_buffers(data, &[b"abc"])?;
+/// assert_eq!(val, PickleValue::Bytes(b"abc".to_vec()));
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn decode_pickle_with_buffers(
+    data: &[u8],
+    buffers: &[&[u8]],
+) -> Result<PickleValue, CodecError> {
+    decode_pickle_with(data, buffers, &DecodeOptions::new())
+}
+
+/// Decode a pickle with out-of-band `buffers` and per-call `options`.
+pub(crate) fn decode_pickle_with(
+    data: &[u8],
+    buffers: &[&[u8]],
+    options: &DecodeOptions,
+) -> Result<PickleValue, CodecError> {
+    let mut decoder = Decoder::with_options(data, options);
+    decoder.buffers = buffers;
+    decoder.run_or_raw(true)
 }
 
 /// Decode a ZODB record (two concatenated pickles) with shared memo.
 /// ZODB shares the pickler memo between the class and state pickles,
 /// so state pickles can reference memo entries from the class pickle.
 /// Returns (class_value, state_value).
+///
+/// ```
+/// use zodb_json_codec::{decode_zodb_pickles, extract_class_info};
+///
+/// // Class pickle ((module, name), None) followed by an empty state dict
+/// let record = b"\x80\x02X\x03\x00\x00\x00modX\x03\x00\x00\x00Cls\x86N\x86.\x80\x02}.";
+/// let (class_val, state) = decode_zodb_pickles(record)?;
+/// assert_eq!(extract_class_info(&class_val), ("mod".to_string(), "Cls".to_string()));
+/// assert_eq!(state, zodb_json_codec::PickleValue::Dict(vec![]));
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+///
+/// Decodes with the default `DecodeOptions`; see
+/// `decode_zodb_pickles_with_options`.
 pub fn decode_zodb_pickles(data: &[u8]) -> Result<(PickleValue, PickleValue), CodecError> {
-    let mut decoder = Decoder::new(data);
-    let class_val = decoder.run()?;
+    decode_zodb_pickles_with_options(data, &DecodeOptions::new())
+}
+
+/// Options of a single decoding call.
+///
+/// These apply only to the call they are passed to, so callers decoding
+/// for different tenants cannot see each other's options.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct DecodeOptions {
+    /// Class quotas enforced on ZODB records.
+    pub quotas: Option<Arc<ClassQuotas>>,
+    /// Repair recoverable corruption instead of failing the whole pickle,
+    /// for rescuing corrupted records.
+    ///
+    /// A `DICT` or `SETITEMS` with an odd number of items keeps its
+    /// complete pairs and stores the unpaired last item under the
+    /// [`DANGLING_KEY`] key, emitting a `tracing` warning, instead of
+    /// failing.
+    ///
+    /// A pickle that cannot be decoded at all (an unknown opcode, a stack
+    /// underflow, a missing memo entry, ...) decodes to a
+    /// `PickleValue::RawPickle` of its bytes, with a warning: up to its
+    /// STOP if an opcode walk finds one, otherwise to the end of the data,
+    /// which for a record only works for the state pickle. Configured
+    /// limits and decode policy errors still fail.
+    pub lenient: bool,
+    /// How Python 2 `str` values are decoded.
+    pub py2_strings: Py2Strings,
+    /// Keep aliased containers shared: a container referenced more than
+    /// once decodes to one `@shared` node and `@backref`s instead of one
+    /// copy per reference, so encoding restores the aliasing. Cycles are
+    /// kept either way.
+    pub shared_references: bool,
+    /// Class allowlist/denylist checked at every class reference.
+    pub policy: Option<Arc<DecodePolicy>>,
+    /// How strings with lone surrogates are decoded.
+    pub surrogates: SurrogatePolicy,
+    /// Class renames applied to every class reference read.
+    pub renames: Option<Arc<ClassRenames>>,
+    /// Maximum line lengths of text-mode opcodes.
+    pub line_limits: LineLimits,
+    /// Resource limits of the decoder.
+    pub limits: DecodeLimits,
+}
+
+impl DecodeOptions {
+    /// The defaults: no quotas, not lenient, Python 2 `str` as bytes,
+    /// aliased containers copied, no decode policy, lone surrogates
+    /// rejected, no class renames, the default line and resource limits.
+    pub const fn new() -> Self {
+        DecodeOptions {
+            quotas: None,
+            lenient: false,
+            py2_strings: Py2Strings::Bytes,
+            shared_references: false,
+            policy: None,
+            surrogates: SurrogatePolicy::Error,
+            renames: None,
+            line_limits: LineLimits::new(),
+            limits: DecodeLimits::new(),
+        }
+    }
+
+    /// Enforce `quotas` on the records decoded with these options.
+    pub fn with_quotas(mut self, quotas: impl Into<Arc<ClassQuotas>>) -> Self {
+        self.quotas = Some(quotas.into());
+        self
+    }
+
+    /// Enable or disable lenient decoding (see [`DecodeOptions::lenient`]).
+    pub fn with_lenient(mut self, enabled: bool) -> Self {
+        self.lenient = enabled;
+        self
+    }
+
+    /// Decode Python 2 `str` values as `mode` says.
+    pub fn with_py2_strings(mut self, mode: Py2Strings) -> Self {
+        self.py2_strings = mode;
+        self
+    }
+
+    /// Keep or copy aliased containers (see
+    /// [`DecodeOptions::shared_references`]).
+    pub fn with_shared_references(mut self, enabled: bool) -> Self {
+        self.shared_references = enabled;
+        self
+    }
+
+    /// Check class references against `policy` (see [`DecodePolicy`]).
+    pub fn with_policy(mut self, policy: impl Into<Arc<DecodePolicy>>) -> Self {
+        self.policy = Some(policy.into());
+        self
+    }
+
+    /// Decode strings with lone surrogates as `policy` says.
+    pub fn with_surrogates(mut self, policy: SurrogatePolicy) -> Self {
+        self.surrogates = policy;
+        self
+    }
+
+    /// Rename class references as `renames` says (see [`ClassRenames`]).
+    pub fn with_renames(mut self, renames: impl Into<Arc<ClassRenames>>) -> Self {
+        self.renames = Some(renames.into());
+        self
+    }
+
+    /// Bound the lines of text-mode opcodes by `limits`.
+    pub fn with_line_limits(mut self, limits: LineLimits) -> Self {
+        self.line_limits = limits;
+        self
+    }
+
+    /// Bound the resources the decoder may use by `limits`.
+    pub fn with_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+/// `decode_zodb_pickles` with per-call `options`.
+///
+/// Class quotas are enforced once the class pickle is decoded, before
+/// the state pickle is.
+///
+/// ```
+/// use zodb_json_codec::{
+///     decode_zodb_pickles_with_options, ClassQuota, ClassQuotas, DecodeOptions,
+/// };
+///
+/// let record = b"\x80\x02X\x03\x00\x00\x00modX\x03\x00\x00\x00Cls\x86N\x86.\x80\x02}.";
+/// let quotas = ClassQuotas::new(ClassQuota::new(Some(16), None));
+/// let options = DecodeOptions::new().with_quotas(quotas);
+/// assert!(decode_zodb_pickles_with_options(record, &options).is_err());
+/// ```
+pub fn decode_zodb_pickles_with_options(
+    data: &[u8],
+    options: &DecodeOptions,
+) -> Result<(PickleValue, PickleValue), CodecError> {
+    let quotas = options.quotas.as_deref();
+    let started = quotas.map(|_| std::time::Instant::now());
+    let mut decoder = Decoder::with_options(data, options);
+    let mut class_val = decoder.run_or_raw(false)?;
+    if let Some(renames) = &decoder.renames {
+        rename::rename_class_pickle(renames, &mut class_val);
+    }
+    if let (Some(quotas), Some(started)) = (quotas, started) {
+        let (module, name) = extract_class_info(&class_val);
+        decoder.deadline = quotas.admit(&module, &name, data.len(), started)?;
+    }
     // Continue with same memo — ZODB shares memo between both pickles
-    let state_val = decoder.run()?;
+    let state_val = decoder.run_or_raw(true)?;
     Ok((class_val, state_val))
 }
 
+/// The `(module, name)` of a ZODB class pickle as written, without the
+/// class renames. Fails unless `data` is exactly one pickle.
+pub(crate) fn class_pickle_info(data: &[u8]) -> Result<(String, String), CodecError> {
+    let mut decoder = Decoder::new(data);
+    let class_val = decoder.run()?;
+    if decoder.pos != data.len() {
+        return Err(CodecError::InvalidData("data after the class pickle".to_string()));
+    }
+    Ok(extract_class_info(&class_val))
+}
+
+/// Decode every pickle in `data` (one pickle or a ZODB record) with a
+/// shared memo, keeping aliased containers shared.
+pub(crate) fn decode_pickles_keeping_aliases(data: &[u8]) -> Result<Vec<PickleValue>, CodecError> {
+    let mut decoder = Decoder::new(data);
+    decoder.aliasing = true;
+    decoder.sharing = true;
+    let mut values = Vec::with_capacity(2);
+    while values.is_empty() || decoder.pos < data.len() {
+        values.push(decoder.run()?);
+    }
+    Ok(values)
+}
+
 struct Decoder<'a> {
     data: &'a [u8],
     pos: usize,
+    /// Offset of the opcode being executed, for error locations.
+    op_start: usize,
     stack: Vec<PickleValue>,
     memo: Vec<PickleValue>,
     /// Metastack for MARK-based operations (saves/restores stack at MARK)
@@ -40,10 +398,62 @@ struct Decoder<'a> {
     stack_memo: Vec<Vec<usize>>,
     /// Saved stack_memo during MARK (parallel to metastack).
     meta_stack_memo: Vec<Vec<Vec<usize>>>,
+    /// Emptied frames of the stack and of stack_memo, reused by the next
+    /// MARK instead of growing a new frame from nothing. Only frames that
+    /// do not become a container's items come back here.
+    spare_frames: Vec<Vec<PickleValue>>,
+    spare_memo_frames: Vec<Vec<Vec<usize>>>,
     /// Dirty flags parallel to memo: true means the memo entry is stale
     /// (the owning stack slot was mutated after BINPUT stored the value).
     /// Resolved lazily at BINGET or eagerly when the slot is popped.
     dirty_memo: Vec<bool>,
+    /// GETs left in the data for each memo index, counted at the first
+    /// PUT, or `None` if the opcode scan failed and any entry may be read.
+    /// Entries no GET reads are never copied, and the last GET moves its
+    /// entry out of the memo.
+    memo_reads: Option<Vec<u32>>,
+    /// Whether `memo_reads` was counted.
+    memo_scanned: bool,
+    /// Line length limits for text-mode opcodes.
+    line_limits: LineLimits,
+    /// Resource limits.
+    limits: DecodeLimits,
+    /// Approximate bytes allocated for values so far.
+    allocated: usize,
+    /// Containers created so far.
+    containers: usize,
+    /// Values in the stacks saved by MARKs.
+    saved_items: usize,
+    /// Lenient decoding (snapshot at creation).
+    lenient: bool,
+    /// Python 2 `str` decoding (snapshot at creation).
+    py2_strings: Py2Strings,
+    /// Handling of lone surrogates in text.
+    surrogates: SurrogatePolicy,
+    /// Set by an error that lenient mode must not turn into a `RawPickle`
+    /// (a decode policy violation).
+    fatal: bool,
+    /// Time quota deadline, checked every `DEADLINE_CHECK_INTERVAL` opcodes.
+    deadline: Option<Deadline>,
+    /// Opcodes left until the next deadline check.
+    deadline_countdown: u32,
+    /// Out-of-band buffers for NEXT_BUFFER (protocol 5), in order.
+    buffers: &'a [&'a [u8]],
+    /// Index of the buffer the next NEXT_BUFFER takes.
+    next_buffer: usize,
+    /// Class allowlist/denylist.
+    policy: Option<Arc<DecodePolicy>>,
+    /// Class renames.
+    renames: Option<Arc<ClassRenames>>,
+    /// Keep aliased containers shared.
+    aliasing: bool,
+    /// Flags parallel to memo: true if a `BackRef` to the entry was pushed.
+    backrefs: Vec<bool>,
+    /// Whether `Shared`/`BackRef` nodes may be in the output, so that
+    /// `shared::resolve` must run at STOP.
+    sharing: bool,
+    /// Memo indices bound to the same container as another, flagged one.
+    shared_aliases: HashMap<u32, u32>,
 }
 
 impl<'a> Decoder<'a> {
@@ -51,21 +461,145 @@ impl<'a> Decoder<'a> {
         Self {
             data,
             pos: 0,
+            op_start: 0,
             stack: Vec::with_capacity(16),
             memo: Vec::with_capacity(16),
             metastack: Vec::with_capacity(4),
             stack_memo: Vec::with_capacity(16),
             meta_stack_memo: Vec::with_capacity(4),
+            spare_frames: Vec::new(),
+            spare_memo_frames: Vec::new(),
             dirty_memo: Vec::with_capacity(16),
+            memo_reads: None,
+            memo_scanned: false,
+            line_limits: LineLimits::new(),
+            limits: DecodeLimits::new(),
+            allocated: 0,
+            containers: 0,
+            saved_items: 0,
+            lenient: false,
+            py2_strings: Py2Strings::Bytes,
+            surrogates: SurrogatePolicy::Error,
+            fatal: false,
+            deadline: None,
+            deadline_countdown: DEADLINE_CHECK_INTERVAL,
+            buffers: &[],
+            next_buffer: 0,
+            policy: None,
+            renames: None,
+            aliasing: false,
+            backrefs: Vec::new(),
+            sharing: false,
+            shared_aliases: HashMap::new(),
+        }
+    }
+
+    fn with_options(data: &'a [u8], options: &DecodeOptions) -> Self {
+        let mut decoder = Self::new(data);
+        decoder.lenient = options.lenient;
+        decoder.py2_strings = options.py2_strings;
+        decoder.aliasing = options.shared_references;
+        decoder.sharing = options.shared_references;
+        decoder.policy = options.policy.clone();
+        decoder.surrogates = options.surrogates;
+        decoder.renames = options.renames.clone();
+        decoder.line_limits = options.line_limits;
+        decoder.limits = options.limits;
+        decoder
+    }
+
+    /// Push a class reference, applying the decode policy.
+    fn push_global(&mut self, module: String, name: String) -> Result<(), CodecError> {
+        let cls = self.class_value(module, name)?;
+        self.push(cls);
+        Ok(())
+    }
+
+    /// The `Global` for a class reference, or `Blocked` if the decode
+    /// policy rejects it. Renames apply first, so the policy sees the
+    /// class that ends up in the output.
+    fn class_value(&mut self, module: String, name: String) -> Result<PickleValue, CodecError> {
+        let (module, name) = match self.renames.as_ref().and_then(|r| r.lookup(&module, &name)) {
+            Some(renamed) => renamed,
+            None => (module, name),
+        };
+        if let Some(policy) = &self.policy {
+            match policy.check(&module, &name) {
+                Ok(true) => {}
+                Ok(false) => return Ok(PickleValue::Blocked { module, name }),
+                Err(e) => {
+                    self.fatal = true;
+                    return Err(e);
+                }
+            }
         }
+        Ok(PickleValue::Global { module, name })
     }
 
+    /// Decode the next pickle; in lenient mode, keep one that fails as a
+    /// `RawPickle`. Without a STOP to end it, the raw pickle extends to the
+    /// end of the data if `last`, else the error stands.
+    fn run_or_raw(&mut self, last: bool) -> Result<PickleValue, CodecError> {
+        let start = self.pos;
+        let err = match self.run() {
+            Ok(val) => return Ok(val),
+            Err(e) if !self.lenient || self.fatal || matches!(e.root(), CodecError::LimitExceeded(_)) => {
+                return Err(e)
+            }
+            Err(e) => e,
+        };
+        let end = match find_pickle_end(&self.data[start..]) {
+            Ok(len) => start + len,
+            Err(_) if last => self.data.len(),
+            Err(_) => return Err(err),
+        };
+        tracing::warn!(
+            offset = start,
+            size = end - start,
+            error = %err,
+            "undecodable pickle kept as @pkl"
+        );
+        warnings::warn(WarningCode::RawPickle, || {
+            format!("undecodable pickle at byte {start} ({} bytes) kept as @pkl: {err}", end - start)
+        });
+        self.stack.clear();
+        self.stack_memo.clear();
+        self.metastack.clear();
+        self.meta_stack_memo.clear();
+        self.saved_items = 0;
+        self.pos = end;
+        Ok(PickleValue::RawPickle(self.data[start..end].to_vec()))
+    }
+
+    /// Decode the next pickle; errors carry the offset of the failing opcode.
     fn run(&mut self) -> Result<PickleValue, CodecError> {
+        self.run_ops().map_err(|e| e.at_offset(self.data, self.op_start))
+    }
+
+    fn run_ops(&mut self) -> Result<PickleValue, CodecError> {
         loop {
+            self.op_start = self.pos;
+            if let Some(deadline) = &self.deadline {
+                self.deadline_countdown -= 1;
+                if self.deadline_countdown == 0 {
+                    self.deadline_countdown = DEADLINE_CHECK_INTERVAL;
+                    deadline.check()?;
+                }
+            }
+            self.check_limits()?;
             let op = self.read_u8()?;
             match op {
                 STOP => {
-                    return self.pop_value();
+                    let val = self.pop_value()?;
+                    if self.sharing {
+                        return Ok(shared::resolve(
+                            val,
+                            &self.memo,
+                            self.aliasing,
+                            &self.shared_aliases,
+                        ));
+                    }
+                    return Ok(val);
                 }
                 PROTO => {
                     // Skip protocol byte
@@ -95,7 +629,7 @@ impl<'a> Decoder<'a> {
                     self.push(PickleValue::Int(val as i64));
                 }
                 INT => {
-                    let line = self.read_line()?;
+                    let line = self.read_line(INT)?;
                     let s = std::str::from_utf8(line).map_err(|_| CodecError::InvalidUtf8)?;
                     let s = s.trim();
                     // INT can encode booleans too: "00" = False, "01" = True
@@ -111,7 +645,7 @@ impl<'a> Decoder<'a> {
                     }
                 }
                 LONG => {
-                    let line = self.read_line()?;
+                    let line = self.read_line(LONG)?;
                     let s = std::str::from_utf8(line).map_err(|_| CodecError::InvalidUtf8)?;
                     let s = s.trim().trim_end_matches('L');
                     if s.len() > 10_000 {
@@ -159,7 +693,7 @@ impl<'a> Decoder<'a> {
                     self.push(PickleValue::Float(val));
                 }
                 FLOAT => {
-                    let line = self.read_line()?;
+                    let line = self.read_line(FLOAT)?;
                     let s = std::str::from_utf8(line).map_err(|_| CodecError::InvalidUtf8)?;
                     let val: f64 = s
                         .trim()
@@ -174,89 +708,120 @@ impl<'a> Decoder<'a> {
                     if n < 0 {
                         return Err(CodecError::InvalidData("negative length in BINSTRING".to_string()));
                     }
+                    self.limits.check_string(n as u64, "BINSTRING")?;
                     let n = n as usize;
                     let bytes = self.read_bytes(n)?.to_vec();
-                    self.push(PickleValue::Bytes(bytes));
+                    self.push(self.py2_strings.decode(bytes));
                 }
                 SHORT_BINSTRING => {
-                    let n = self.read_u8()? as usize;
+                    let n = self.read_u8()?;
+                    self.limits.check_string(n as u64, "SHORT_BINSTRING")?;
+                    let n = n as usize;
                     let bytes = self.read_bytes(n)?.to_vec();
-                    self.push(PickleValue::Bytes(bytes));
+                    self.push(self.py2_strings.decode(bytes));
                 }
                 STRING => {
-                    let line = self.read_line()?;
-                    let s = std::str::from_utf8(line).map_err(|_| CodecError::InvalidUtf8)?;
-                    let s = s.trim();
-                    // STRING values are repr'd: strip quotes
-                    let inner = if (s.starts_with('\'') && s.ends_with('\''))
-                        || (s.starts_with('"') && s.ends_with('"'))
-                    {
-                        &s[1..s.len() - 1]
-                    } else {
-                        s
+                    let line = self.read_line(STRING)?.trim_ascii();
+                    self.limits.check_string(line.len() as u64, "STRING")?;
+                    // STRING values are repr'd: strip quotes, then escapes
+                    let inner = match line {
+                        [q @ (b'\'' | b'"'), inner @ .., last] if last == q => inner,
+                        _ => line,
                     };
-                    self.push(PickleValue::Bytes(inner.as_bytes().to_vec()));
+                    let bytes = unescape_string_repr(inner)?;
+                    self.push(self.py2_strings.decode(bytes));
                 }
 
                 // -- Unicode strings --
                 BINUNICODE => {
-                    let n = self.read_u32()? as usize;
+                    let n = self.read_u32()?;
+                    self.limits.check_string(n as u64, "BINUNICODE")?;
+                    let n = n as usize;
                     let bytes = self.read_bytes(n)?;
-                    let s =
-                        std::str::from_utf8(bytes).map_err(|_| CodecError::InvalidUtf8)?;
-                    self.push(PickleValue::String(s.to_string()));
+                    let val = decode_text(bytes, self.surrogates)?;
+                    self.push(val);
                 }
                 SHORT_BINUNICODE => {
-                    let n = self.read_u8()? as usize;
+                    let n = self.read_u8()?;
+                    self.limits.check_string(n as u64, "SHORT_BINUNICODE")?;
+                    let n = n as usize;
                     let bytes = self.read_bytes(n)?;
-                    let s =
-                        std::str::from_utf8(bytes).map_err(|_| CodecError::InvalidUtf8)?;
-                    self.push(PickleValue::String(s.to_string()));
+                    let val = decode_text(bytes, self.surrogates)?;
+                    self.push(val);
                 }
                 UNICODE => {
-                    let line = self.read_line()?;
-                    let s = std::str::from_utf8(line).map_err(|_| CodecError::InvalidUtf8)?;
-                    self.push(PickleValue::String(s.to_string()));
+                    let line = self.read_line(UNICODE)?;
+                    self.limits.check_string(line.len() as u64, "UNICODE")?;
+                    let val = decode_text(&decode_raw_unicode_escape(line)?, self.surrogates)?;
+                    self.push(val);
                 }
                 BINUNICODE8 => {
                     let n = self.read_u64()?;
-                    if n > MAX_BINARY_SIZE {
-                        return Err(CodecError::InvalidData("BINUNICODE8 data too large".to_string()));
-                    }
+                    self.limits.check_string(n, "BINUNICODE8")?;
                     let n = n as usize;
                     let bytes = self.read_bytes(n)?;
-                    let s =
-                        std::str::from_utf8(bytes).map_err(|_| CodecError::InvalidUtf8)?;
-                    self.push(PickleValue::String(s.to_string()));
+                    let val = decode_text(bytes, self.surrogates)?;
+                    self.push(val);
                 }
 
                 // -- Bytes --
                 BINBYTES => {
-                    let n = self.read_u32()? as usize;
+                    let n = self.read_u32()?;
+                    self.limits.check_string(n as u64, "BINBYTES")?;
+                    let n = n as usize;
                     let bytes = self.read_bytes(n)?.to_vec();
                     self.push(PickleValue::Bytes(bytes));
                 }
                 SHORT_BINBYTES => {
-                    let n = self.read_u8()? as usize;
+                    let n = self.read_u8()?;
+                    self.limits.check_string(n as u64, "SHORT_BINBYTES")?;
+                    let n = n as usize;
                     let bytes = self.read_bytes(n)?.to_vec();
                     self.push(PickleValue::Bytes(bytes));
                 }
                 BINBYTES8 => {
                     let n = self.read_u64()?;
-                    if n > MAX_BINARY_SIZE {
-                        return Err(CodecError::InvalidData("BINBYTES8 data too large".to_string()));
-                    }
+                    self.limits.check_string(n, "BINBYTES8")?;
                     let n = n as usize;
                     let bytes = self.read_bytes(n)?.to_vec();
                     self.push(PickleValue::Bytes(bytes));
                 }
 
+                // -- Protocol 5 buffers --
+                // A bytearray has no marker of its own; it decodes (and
+                // re-encodes) as bytes.
+                BYTEARRAY8 => {
+                    let n = self.read_u64()?;
+                    self.limits.check_string(n, "BYTEARRAY8")?;
+                    let bytes = self.read_bytes(n as usize)?.to_vec();
+                    self.push(PickleValue::Bytes(bytes));
+                }
+                NEXT_BUFFER => {
+                    let Some(buffer) = self.buffers.get(self.next_buffer) else {
+                        return Err(CodecError::InvalidData(if self.buffers.is_empty() {
+                            "pickle refers to out-of-band buffers but none were given".to_string()
+                        } else {
+                            "not enough out-of-band buffers".to_string()
+                        }));
+                    };
+                    self.next_buffer += 1;
+                    self.limits.check_string(buffer.len() as u64, "out-of-band buffer")?;
+                    self.push(PickleValue::Bytes(buffer.to_vec()));
+                }
+                READONLY_BUFFER => {
+                    // Bytes are immutable already
+                    self.peek_value()?;
+                }
+
                 // -- Mark --
                 MARK => {
                     // Save current stack, start a new one
-                    let old_stack = std::mem::take(&mut self.stack);
+                    let frame = self.spare_frames.pop().unwrap_or_default();
+                    let old_stack = std::mem::replace(&mut self.stack, frame);
+                    self.saved_items += old_stack.len();
                     self.metastack.push(old_stack);
-                    let old_sm = std::mem::take(&mut self.stack_memo);
+                    let frame = self.spare_memo_frames.pop().unwrap_or_default();
+                    let old_sm = std::mem::replace(&mut self.stack_memo, frame);
                     self.meta_stack_memo.push(old_sm);
                     // Don't push Mark itself; everything above the mark
                     // is captured by the current stack being empty
@@ -320,16 +885,16 @@ impl<'a> Decoder<'a> {
                     let top = self.top_value_mut()?;
                     match top {
                         PickleValue::List(ref mut list_items) => {
-                            list_items.extend(items);
+                            append_items(list_items, items);
                         }
                         PickleValue::Reduce {
                             ref mut list_items, ..
                         } => match list_items {
-                            Some(ref mut existing) => existing.extend(items),
+                            Some(ref mut existing) => append_items(existing, items),
                             None => *list_items = Some(Box::new(items)),
                         },
                         PickleValue::Instance(ref mut inst) => match inst.list_items {
-                            Some(ref mut existing) => existing.extend(items),
+                            Some(ref mut existing) => append_items(existing, items),
                             None => inst.list_items = Some(Box::new(items)),
                         },
                         _ => {
@@ -344,8 +909,7 @@ impl<'a> Decoder<'a> {
                 // -- Dict --
                 EMPTY_DICT => self.push(PickleValue::Dict(Vec::new())),
                 DICT => {
-                    let items = self.pop_mark()?;
-                    let pairs = items_to_pairs(items)?;
+                    let pairs = self.pop_mark_pairs("DICT")?;
                     self.push(PickleValue::Dict(pairs));
                 }
                 SETITEM => {
@@ -375,21 +939,20 @@ impl<'a> Decoder<'a> {
                     self.mark_top_dirty();
                 }
                 SETITEMS => {
-                    let items = self.pop_mark()?;
-                    let new_pairs = items_to_pairs(items)?;
+                    let new_pairs = self.pop_mark_pairs("SETITEMS")?;
                     let top = self.top_value_mut()?;
                     match top {
                         PickleValue::Dict(ref mut pairs) => {
-                            pairs.extend(new_pairs);
+                            append_items(pairs, new_pairs);
                         }
                         PickleValue::Reduce {
                             ref mut dict_items, ..
                         } => match dict_items {
-                            Some(ref mut existing) => existing.extend(new_pairs),
+                            Some(ref mut existing) => append_items(existing, new_pairs),
                             None => *dict_items = Some(Box::new(new_pairs)),
                         },
                         PickleValue::Instance(ref mut inst) => match inst.dict_items {
-                            Some(ref mut existing) => existing.extend(new_pairs),
+                            Some(ref mut existing) => append_items(existing, new_pairs),
                             None => inst.dict_items = Some(Box::new(new_pairs)),
                         },
                         _ => {
@@ -407,7 +970,7 @@ impl<'a> Decoder<'a> {
                     let items = self.pop_mark()?;
                     let set = self.top_value_mut()?;
                     if let PickleValue::Set(ref mut set_items) = set {
-                        set_items.extend(items);
+                        append_items(set_items, items);
                     } else {
                         return Err(CodecError::InvalidData(
                             "ADDITEMS on non-set".to_string(),
@@ -422,15 +985,8 @@ impl<'a> Decoder<'a> {
 
                 // -- Global (class reference) --
                 GLOBAL => {
-                    let module_line = self.read_line()?;
-                    let name_line = self.read_line()?;
-                    let module = std::str::from_utf8(module_line)
-                        .map_err(|_| CodecError::InvalidUtf8)?
-                        .to_string();
-                    let name = std::str::from_utf8(name_line)
-                        .map_err(|_| CodecError::InvalidUtf8)?
-                        .to_string();
-                    self.push(PickleValue::Global { module, name });
+                    let (module, name) = self.read_class_lines(GLOBAL)?;
+                    self.push_global(module, name)?;
                 }
                 STACK_GLOBAL => {
                     let name_val = self.pop_value()?;
@@ -451,18 +1007,21 @@ impl<'a> Decoder<'a> {
                             ))
                         }
                     };
-                    self.push(PickleValue::Global { module, name });
+                    self.push_global(module.to_string(), name.to_string())?;
                 }
 
                 // -- Object construction --
                 REDUCE => {
-                    let args = self.pop_value()?;
+                    let mut args = self.pop_value()?;
                     let callable = self.pop_value()?;
+                    self.py2_strings.restore_packed_arg(&callable, &mut args);
                     // Recognize set/frozenset REDUCE pattern (protocol 3).
                     // Uses two-step check: borrow callable first, then consume
                     // args by value to move list items instead of cloning.
                     let set_variant = match &callable {
-                        PickleValue::Global { module, name } if module == "builtins" => {
+                        PickleValue::Global { module, name }
+                            if module == "builtins" || module == "__builtin__" =>
+                        {
                             match name.as_str() {
                                 "set" => Some(true),
                                 "frozenset" => Some(false),
@@ -474,7 +1033,7 @@ impl<'a> Decoder<'a> {
                     if let Some(is_set) = set_variant {
                         match args {
                             PickleValue::Tuple(mut tuple_items) if tuple_items.len() == 1 => {
-                                match tuple_items.swap_remove(0) {
+                                match self.unshare(tuple_items.swap_remove(0)) {
                                     PickleValue::List(items) => {
                                         self.push(if is_set {
                                             PickleValue::Set(items)
@@ -488,6 +1047,7 @@ impl<'a> Decoder<'a> {
                                             args: Box::new(PickleValue::Tuple(vec![other])),
                                             dict_items: None,
                                             list_items: None,
+                                            newobj: false,
                                         });
                                     }
                                 }
@@ -498,15 +1058,19 @@ impl<'a> Decoder<'a> {
                                     args: Box::new(args),
                                     dict_items: None,
                                     list_items: None,
+                                    newobj: false,
                                 });
                             }
                         }
+                    } else if let Some(val) = legacy_reduce(&callable, &args) {
+                        self.push(val);
                     } else {
                         self.push(PickleValue::Reduce {
                             callable: Box::new(callable),
                             args: Box::new(args),
                             dict_items: None,
                             list_items: None,
+                            newobj: false,
                         });
                     }
                 }
@@ -525,6 +1089,13 @@ impl<'a> Decoder<'a> {
                                 list_items: None,
                             })));
                         }
+                        PickleValue::Instance(inst) if inst.is_anonymous() => {
+                            // Its state records how it was built: keep that
+                            // and apply the new state on top
+                            self.push(PickleValue::Instance(Box::new(
+                                InstanceData::anonymous_object(PickleValue::Instance(inst), state),
+                            )));
+                        }
                         PickleValue::Instance(inst) => {
                             // BUILD on an existing instance updates its state
                             self.push(PickleValue::Instance(Box::new(InstanceData {
@@ -540,6 +1111,30 @@ impl<'a> Decoder<'a> {
                             args,
                             dict_items,
                             list_items,
+                            newobj: true,
+                        } if !matches!(
+                            *callable,
+                            PickleValue::Global { .. } | PickleValue::Blocked { .. }
+                        ) =>
+                        {
+                            // Only REDUCE has an anonymous form: keep the NEWOBJ
+                            let obj = PickleValue::Reduce {
+                                callable,
+                                args,
+                                dict_items,
+                                list_items,
+                                newobj: true,
+                            };
+                            self.push(PickleValue::Instance(Box::new(
+                                InstanceData::anonymous_object(obj, state),
+                            )));
+                        }
+                        PickleValue::Reduce {
+                            callable,
+                            args,
+                            dict_items,
+                            list_items,
+                            ..
                         } => {
                             // REDUCE followed by BUILD: the common pattern.
                             // Extract class info if callable is a Global.
@@ -551,11 +1146,11 @@ impl<'a> Decoder<'a> {
                                     } else {
                                         PickleValue::Dict(vec![
                                             (
-                                                PickleValue::String("@args".to_string()),
+                                                PickleValue::String("@args".into()),
                                                 *args,
                                             ),
                                             (
-                                                PickleValue::String("@state".to_string()),
+                                                PickleValue::String("@state".into()),
                                                 state,
                                             ),
                                         ])
@@ -568,43 +1163,21 @@ impl<'a> Decoder<'a> {
                                         list_items,
                                     })));
                                 }
-                                _ => {
+                                callable => {
                                     // Can't decompose further — wrap as-is
-                                    self.push(PickleValue::Instance(Box::new(InstanceData {
-                                        module: String::new(),
-                                        name: String::new(),
-                                        dict_items,
-                                        list_items,
-                                        state: Box::new(PickleValue::Dict(vec![
-                                            (
-                                                PickleValue::String("@callable".to_string()),
-                                                *callable,
-                                            ),
-                                            (
-                                                PickleValue::String("@args".to_string()),
-                                                *args,
-                                            ),
-                                            (
-                                                PickleValue::String("@state".to_string()),
-                                                state,
-                                            ),
-                                        ])),
-                                    })));
+                                    let mut inst =
+                                        InstanceData::anonymous_reduce(callable, *args, state);
+                                    inst.dict_items = dict_items;
+                                    inst.list_items = list_items;
+                                    self.push(PickleValue::Instance(Box::new(inst)));
                                 }
                             }
                         }
-                        _ => {
+                        obj => {
                             // BUILD on something unexpected — keep both
-                            self.push(PickleValue::Instance(Box::new(InstanceData {
-                                module: String::new(),
-                                name: String::new(),
-                                state: Box::new(PickleValue::Dict(vec![
-                                    (PickleValue::String("@obj".to_string()), obj),
-                                    (PickleValue::String("@state".to_string()), state),
-                                ])),
-                                dict_items: None,
-                                list_items: None,
-                            })));
+                            self.push(PickleValue::Instance(Box::new(
+                                InstanceData::anonymous_object(obj, state),
+                            )));
                         }
                     }
                     // Transfer memo bindings from the old object to the new
@@ -622,89 +1195,86 @@ impl<'a> Decoder<'a> {
                         args: Box::new(args),
                         dict_items: None,
                         list_items: None,
+                        newobj: true,
                     });
                 }
+                INST => {
+                    let (module, name) = self.read_class_lines(INST)?;
+                    let args = self.pop_mark()?;
+                    let cls = self.class_value(module, name)?;
+                    self.push(instantiate(cls, args));
+                }
+                OBJ => {
+                    let mut items = self.pop_mark()?;
+                    if items.is_empty() {
+                        return Err(CodecError::StackUnderflow);
+                    }
+                    let cls = items.remove(0);
+                    self.push(instantiate(cls, items));
+                }
                 NEWOBJ_EX => {
                     let kwargs = self.pop_value()?;
                     let args = self.pop_value()?;
                     let cls = self.pop_value()?;
-                    // For now, combine args and kwargs
-                    let combined_args = PickleValue::Dict(vec![
-                        (PickleValue::String("@args".to_string()), args),
-                        (PickleValue::String("@kwargs".to_string()), kwargs),
-                    ]);
-                    self.push(PickleValue::Reduce {
-                        callable: Box::new(cls),
-                        args: Box::new(combined_args),
-                        dict_items: None,
-                        list_items: None,
+                    self.push(PickleValue::NewObjEx {
+                        cls: Box::new(cls),
+                        args: Box::new(args),
+                        kwargs: Box::new(kwargs),
                     });
                 }
 
                 // -- Persistent references (ZODB) --
                 BINPERSID => {
-                    let pid = self.pop_value()?;
+                    let mut pid = self.pop_value()?;
+                    self.py2_strings.restore_oid(&mut pid);
                     self.push(PickleValue::PersistentRef(Box::new(pid)));
                 }
                 PERSID => {
-                    let line = self.read_line()?;
-                    let s = std::str::from_utf8(line)
-                        .map_err(|_| CodecError::InvalidUtf8)?
-                        .to_string();
+                    let line = self.read_line(PERSID)?;
+                    let s = std::str::from_utf8(line).map_err(|_| CodecError::InvalidUtf8)?;
                     self.push(PickleValue::PersistentRef(Box::new(
-                        PickleValue::String(s),
+                        PickleValue::String(s.into()),
                     )));
                 }
 
                 // -- Memo --
                 BINPUT => {
                     let idx = self.read_u8()? as usize;
-                    let val = self.peek_value()?.clone();
-                    self.memo_put(idx, val)?;
-                    self.record_memo_binding(idx);
+                    self.memo_put_top(idx)?;
                 }
                 LONG_BINPUT => {
                     let idx = self.read_u32()? as usize;
-                    let val = self.peek_value()?.clone();
-                    self.memo_put(idx, val)?;
-                    self.record_memo_binding(idx);
+                    self.memo_put_top(idx)?;
                 }
                 MEMOIZE => {
-                    let val = self.peek_value()?.clone();
                     let idx = self.memo.len();
-                    self.memo_put(idx, val)?;
-                    self.record_memo_binding(idx);
+                    self.memo_put_top(idx)?;
                 }
                 BINGET => {
                     let idx = self.read_u8()? as usize;
-                    let val = self.memo_get(idx)?;
-                    self.push(val);
+                    self.push_memo(idx)?;
                 }
                 LONG_BINGET => {
                     let idx = self.read_u32()? as usize;
-                    let val = self.memo_get(idx)?;
-                    self.push(val);
+                    self.push_memo(idx)?;
                 }
                 PUT => {
-                    let line = self.read_line()?;
+                    let line = self.read_line(PUT)?;
                     let s = std::str::from_utf8(line).map_err(|_| CodecError::InvalidUtf8)?;
                     let idx: usize = s
                         .trim()
                         .parse()
                         .map_err(|e| CodecError::InvalidData(format!("PUT index: {e}")))?;
-                    let val = self.peek_value()?.clone();
-                    self.memo_put(idx, val)?;
-                    self.record_memo_binding(idx);
+                    self.memo_put_top(idx)?;
                 }
                 GET => {
-                    let line = self.read_line()?;
+                    let line = self.read_line(GET)?;
                     let s = std::str::from_utf8(line).map_err(|_| CodecError::InvalidUtf8)?;
                     let idx: usize = s
                         .trim()
                         .parse()
                         .map_err(|e| CodecError::InvalidData(format!("GET index: {e}")))?;
-                    let val = self.memo_get(idx)?;
-                    self.push(val);
+                    self.push_memo(idx)?;
                 }
 
                 // -- Stack manipulation --
@@ -713,6 +1283,7 @@ impl<'a> Decoder<'a> {
                 }
                 DUP => {
                     let val = self.peek_value()?.clone();
+                    self.count_nested(&val);
                     self.push(val);
                 }
 
@@ -763,23 +1334,34 @@ impl<'a> Decoder<'a> {
         Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
     }
 
-    fn read_line(&mut self) -> Result<&'a [u8], CodecError> {
+    /// Read a newline-terminated argument of text-mode opcode `op`,
+    /// bounded by the configured line limit for that opcode.
+    fn read_line(&mut self, op: u8) -> Result<&'a [u8], CodecError> {
         let start = self.pos;
-        while self.pos < self.data.len() {
-            if self.data[self.pos] == b'\n' {
-                let line = &self.data[start..self.pos];
-                self.pos += 1; // skip newline
-                return Ok(line);
-            }
-            self.pos += 1;
-        }
-        Err(CodecError::UnexpectedEof)
+        let max = self.line_limits.for_opcode(op);
+        let end = find_line_end(self.data, start, max, op)?;
+        self.pos = end + 1; // skip newline
+        Ok(&self.data[start..end])
+    }
+
+    /// Read the module and name lines of a GLOBAL or INST.
+    fn read_class_lines(&mut self, op: u8) -> Result<(String, String), CodecError> {
+        let module_line = self.read_line(op)?;
+        let name_line = self.read_line(op)?;
+        let module = std::str::from_utf8(module_line)
+            .map_err(|_| CodecError::InvalidUtf8)?
+            .to_string();
+        let name = std::str::from_utf8(name_line)
+            .map_err(|_| CodecError::InvalidUtf8)?
+            .to_string();
+        Ok((module, name))
     }
 
     // -- Stack operations --
 
     #[inline]
     fn push(&mut self, val: PickleValue) {
+        self.count(&val);
         self.stack.push(val);
         self.stack_memo.push(Vec::new());
     }
@@ -790,23 +1372,17 @@ impl<'a> Decoder<'a> {
         let val = self.stack.pop().ok_or(CodecError::StackUnderflow)?;
         // Sync any dirty memo entries before the value leaves the stack
         if !bindings.is_empty() {
-            let mut need_clone = false;
             for &idx in &bindings {
                 if idx < self.dirty_memo.len() && self.dirty_memo[idx] {
-                    need_clone = true;
-                    break;
-                }
-            }
-            if need_clone {
-                for &idx in &bindings {
-                    if idx < self.dirty_memo.len() && self.dirty_memo[idx] {
-                        if idx < self.memo.len() {
-                            self.memo[idx] = val.clone();
-                        }
-                        self.dirty_memo[idx] = false;
+                    if idx < self.memo.len() && self.memo_read(idx) {
+                        self.memo[idx] = val.clone();
                     }
+                    self.dirty_memo[idx] = false;
                 }
             }
+            if self.sharing {
+                return Ok(self.wrap_shared(val, &bindings));
+            }
         }
         Ok(val)
     }
@@ -821,18 +1397,53 @@ impl<'a> Decoder<'a> {
         self.stack.last_mut().ok_or(CodecError::StackUnderflow)
     }
 
+    /// Pop the items above the last MARK and pair them up, for DICT and
+    /// SETITEMS, honouring lenient mode. The emptied frame is kept for
+    /// reuse.
+    fn pop_mark_pairs(&mut self, op: &str) -> Result<Vec<(PickleValue, PickleValue)>, CodecError> {
+        let mut items = self.pop_frame()?;
+        if self.lenient && !items.len().is_multiple_of(2) {
+            tracing::warn!(
+                opcode = op,
+                offset = self.pos - 1,
+                items = items.len(),
+                "odd number of items for dict, keeping the last under @dangling"
+            );
+        }
+        let pairs = items_to_pairs(&mut items, self.lenient)?;
+        self.spare_frames.push(items);
+        Ok(pairs)
+    }
+
     /// Pop all items above the last MARK from the stack.
+    ///
+    /// The items usually become a container's items, so they keep the
+    /// frame as their storage, unless it was reused and has much more room
+    /// than they need: they then get an exact copy and the frame goes back
+    /// to the spares.
     fn pop_mark(&mut self) -> Result<Vec<PickleValue>, CodecError> {
+        let mut items = self.pop_frame()?;
+        if items.capacity() <= (2 * items.len()).max(4) {
+            return Ok(items);
+        }
+        let mut fitted = Vec::with_capacity(items.len());
+        fitted.append(&mut items);
+        self.spare_frames.push(items);
+        Ok(fitted)
+    }
+
+    /// Pop the frame above the last MARK, restoring the one below it.
+    fn pop_frame(&mut self) -> Result<Vec<PickleValue>, CodecError> {
         // Take the current stack (everything since MARK) as the result.
         // This is a pointer swap — no element-by-element drain needed.
         let items = std::mem::take(&mut self.stack);
-        let slot_memos = std::mem::take(&mut self.stack_memo);
+        let mut slot_memos = std::mem::take(&mut self.stack_memo);
 
         // Sync dirty memo entries for all popped slots before values are consumed
         for (val, bindings) in items.iter().zip(slot_memos.iter()) {
             for &idx in bindings {
                 if idx < self.dirty_memo.len() && self.dirty_memo[idx] {
-                    if idx < self.memo.len() {
+                    if idx < self.memo.len() && self.memo_read(idx) {
                         self.memo[idx] = val.clone();
                     }
                     self.dirty_memo[idx] = false;
@@ -840,8 +1451,21 @@ impl<'a> Decoder<'a> {
             }
         }
 
+        let items = if self.sharing {
+            items
+                .into_iter()
+                .zip(slot_memos.iter())
+                .map(|(val, bindings)| self.wrap_shared(val, bindings))
+                .collect()
+        } else {
+            items
+        };
+        slot_memos.clear();
+        self.spare_memo_frames.push(slot_memos);
+
         // Restore the previous stack from metastack
         if let Some(old_stack) = self.metastack.pop() {
+            self.saved_items -= old_stack.len();
             self.stack = old_stack;
         }
         if let Some(old_sm) = self.meta_stack_memo.pop() {
@@ -851,30 +1475,208 @@ impl<'a> Decoder<'a> {
         Ok(items)
     }
 
+    // -- Resource accounting --
+
+    /// Count a new value (not its children) towards the limits.
+    #[inline]
+    fn count(&mut self, val: &PickleValue) {
+        let payload = match val {
+            PickleValue::String(s) => s.len(),
+            PickleValue::Bytes(b) | PickleValue::RawPickle(b) => b.len(),
+            PickleValue::BigInt(i) => i.bits().div_ceil(8) as usize,
+            PickleValue::List(_)
+            | PickleValue::Tuple(_)
+            | PickleValue::Dict(_)
+            | PickleValue::Set(_)
+            | PickleValue::FrozenSet(_) => {
+                self.containers += 1;
+                0
+            }
+            _ => 0,
+        };
+        self.allocated = self.allocated.saturating_add(VALUE_SIZE + payload);
+    }
+
+    /// Count the values nested in a copy of a memoized value.
+    fn count_nested(&mut self, val: &PickleValue) {
+        if !self.limits.counts_copies() {
+            return;
+        }
+        let mut pending = Vec::new();
+        shared::for_each_child(val, &mut |child| pending.push(child));
+        while let Some(child) = pending.pop() {
+            self.count(child);
+            shared::for_each_child(child, &mut |c| pending.push(c));
+        }
+    }
+
+    /// Fail once the values built so far exceed a limit.
+    #[inline]
+    fn check_limits(&self) -> Result<(), CodecError> {
+        let limits = &self.limits;
+        let stack_items = self.stack.len() + self.saved_items;
+        if self.allocated > limits.max_allocation {
+            return Err(CodecError::LimitExceeded(format!(
+                "decoded values exceed {} bytes",
+                limits.max_allocation
+            )));
+        }
+        if stack_items > limits.max_stack_items {
+            return Err(CodecError::LimitExceeded(format!(
+                "more than {} values on the pickle stack",
+                limits.max_stack_items
+            )));
+        }
+        if self.containers > limits.max_containers {
+            return Err(CodecError::LimitExceeded(format!(
+                "more than {} containers",
+                limits.max_containers
+            )));
+        }
+        Ok(())
+    }
+
     // -- Memo operations --
 
-    fn memo_put(&mut self, idx: usize, val: PickleValue) -> Result<(), CodecError> {
-        if idx >= MAX_MEMO_SIZE {
-            return Err(CodecError::InvalidData(format!("memo index {idx} exceeds maximum {MAX_MEMO_SIZE}")));
+    /// Store the stack top in memo entry `idx` (PUT opcodes). Only entries
+    /// a later GET reads get a copy.
+    fn memo_put_top(&mut self, idx: usize) -> Result<(), CodecError> {
+        let max = self.limits.max_memo_entries;
+        if idx >= max {
+            return Err(CodecError::InvalidData(format!("memo index {idx} exceeds maximum {max}")));
+        }
+        if !self.memo_scanned {
+            self.memo_reads = memo_reads(self.data, &self.line_limits);
+            self.memo_scanned = true;
         }
+        let val = if self.memo_read(idx) {
+            let val = self.peek_value()?.clone();
+            if self.limits.counts_copies() {
+                self.count(&val);
+                self.count_nested(&val);
+            }
+            Some(val)
+        } else {
+            self.peek_value()?;
+            None
+        };
         if idx >= self.memo.len() {
             self.memo.resize(idx + 1, PickleValue::None);
             self.dirty_memo.resize(idx + 1, false);
         }
-        self.memo[idx] = val;
         self.dirty_memo[idx] = false;
+        if let Some(val) = val {
+            self.memo[idx] = val;
+            self.record_memo_binding(idx);
+        }
         Ok(())
     }
 
-    /// Get a memo entry, lazily resolving dirty (stale) entries first.
+    /// Whether a GET still to come reads memo entry `idx`.
+    #[inline]
+    fn memo_read(&self, idx: usize) -> bool {
+        match &self.memo_reads {
+            Some(reads) => reads.get(idx).is_some_and(|&n| n > 0),
+            None => true,
+        }
+    }
+
+    /// Get a memo entry, lazily resolving dirty (stale) entries first. The
+    /// last GET of an entry takes it instead of copying it.
     fn memo_get(&mut self, idx: usize) -> Result<PickleValue, CodecError> {
         if idx < self.dirty_memo.len() && self.dirty_memo[idx] {
             self.resolve_dirty_memo(idx);
         }
-        self.memo
-            .get(idx)
-            .cloned()
-            .ok_or_else(|| CodecError::InvalidData(format!("memo index {idx} not found")))
+        let last = match self.memo_reads.as_mut().and_then(|reads| reads.get_mut(idx)) {
+            Some(n) => {
+                *n = n.saturating_sub(1);
+                *n == 0
+            }
+            None => false,
+        };
+        match self.memo.get_mut(idx) {
+            Some(val) if last && !self.sharing => Ok(std::mem::replace(val, PickleValue::None)),
+            Some(val) => Ok(val.clone()),
+            None => Err(CodecError::InvalidData(format!("memo index {idx} not found"))),
+        }
+    }
+
+    /// Push memo entry `idx` for a GET opcode.
+    ///
+    /// A container that is still on the stack is being built: the GET is a
+    /// cycle, so a `BackRef` placeholder is pushed instead of a copy of its
+    /// unfinished contents. So is a container that turned out to be cyclic,
+    /// and with aliasing enabled any container. See `shared.rs`.
+    fn push_memo(&mut self, idx: usize) -> Result<(), CodecError> {
+        let Some(val) = self.memo.get(idx) else {
+            return Err(CodecError::InvalidData(format!("memo index {idx} not found")));
+        };
+        let dirty = self.dirty_memo[idx];
+        let back_ref = self.backrefs.get(idx).copied().unwrap_or(false)
+            || ((is_shareable(val) || dirty) && self.live_value(idx).is_some_and(is_shareable))
+            || (self.aliasing && !dirty && is_shareable(val));
+        if !back_ref {
+            let val = self.memo_get(idx)?;
+            self.count_nested(&val);
+            self.push(val);
+            return Ok(());
+        }
+        if idx >= self.backrefs.len() {
+            self.backrefs.resize(idx + 1, false);
+        }
+        self.backrefs[idx] = true;
+        self.sharing = true;
+        self.push(PickleValue::BackRef(idx as u32));
+        Ok(())
+    }
+
+    /// The stack slot memo entry `idx` is bound to, if still on the stack.
+    fn live_value(&self, memo_idx: usize) -> Option<&PickleValue> {
+        let stacks = self
+            .meta_stack_memo
+            .iter()
+            .zip(self.metastack.iter())
+            .chain(std::iter::once((&self.stack_memo, &self.stack)));
+        for (bindings, values) in stacks {
+            if let Some(si) = bindings.iter().position(|b| b.contains(&memo_idx)) {
+                return values.get(si);
+            }
+        }
+        None
+    }
+
+    /// Wrap a container leaving the stack in a provisional `Shared` node
+    /// if it may be referred to by a `BackRef`.
+    fn wrap_shared(&mut self, val: PickleValue, bindings: &[usize]) -> PickleValue {
+        if bindings.is_empty() || !is_shareable(&val) {
+            return val;
+        }
+        let flagged = |idx: &&usize| self.backrefs.get(**idx).copied().unwrap_or(false);
+        let id = match bindings.iter().find(flagged) {
+            Some(&id) => id,
+            None if self.aliasing => bindings[0],
+            None => return val,
+        };
+        for &other in bindings.iter().filter(|&&idx| idx != id) {
+            self.shared_aliases.insert(other as u32, id as u32);
+        }
+        PickleValue::Shared {
+            id: id as u32,
+            value: Box::new(val),
+        }
+    }
+
+    /// Unwrap a provisional `Shared` node that nothing refers to, such as
+    /// the memoized item list of a set REDUCE in aliasing mode.
+    fn unshare(&self, val: PickleValue) -> PickleValue {
+        match val {
+            PickleValue::Shared { id, value }
+                if !self.backrefs.get(id as usize).copied().unwrap_or(false) =>
+            {
+                *value
+            }
+            val => val,
+        }
     }
 
     /// Resolve a dirty memo entry by finding its live value on the stack.
@@ -924,20 +1726,243 @@ impl<'a> Decoder<'a> {
     }
 }
 
+/// Add the items of an APPENDS, SETITEMS or ADDITEMS batch. The first
+/// batch usually fills an empty container, which then takes the batch's
+/// storage instead of copying it.
+#[inline]
+fn append_items<T>(target: &mut Vec<T>, items: Vec<T>) {
+    if target.is_empty() {
+        *target = items;
+    } else {
+        target.extend(items);
+    }
+}
+
+/// How many GET opcodes read each memo index, from an opcode walk over
+/// all of `data`. `None` if the walk fails: the decoder then keeps every
+/// memo entry, and reports the error itself.
+fn memo_reads(data: &[u8], limits: &LineLimits) -> Option<Vec<u32>> {
+    let mut reads = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (op, next) = skip_opcode(data, pos, limits).ok()?;
+        if next > data.len() {
+            return None;
+        }
+        if matches!(op, GET | BINGET | LONG_BINGET) {
+            let idx = memo_index(op, &data[pos + 1..next]).ok()? as usize;
+            if idx >= MAX_MEMO_SIZE {
+                return None;
+            }
+            if idx >= reads.len() {
+                reads.resize(idx + 1, 0);
+            }
+            reads[idx] += 1;
+        }
+        pos = next;
+    }
+    Some(reads)
+}
+
+/// The object INST or OBJ creates, as `pickle._instantiate` does for a
+/// class: `cls(*args)`, which is a REDUCE, or `cls.__new__(cls)` without
+/// arguments, which is a NEWOBJ. A BUILD after either makes an `Instance`.
+fn instantiate(cls: PickleValue, args: Vec<PickleValue>) -> PickleValue {
+    PickleValue::Reduce {
+        callable: Box::new(cls),
+        newobj: args.is_empty(),
+        args: Box::new(PickleValue::Tuple(args)),
+        dict_items: None,
+        list_items: None,
+    }
+}
+
+/// Recognize the REDUCE forms Python uses below protocol 3 for values
+/// that have a direct representation.
+///
+/// - `copy_reg._reconstructor(cls, object, None)` is `object.__new__(cls)`,
+///   how protocols 0 and 1 create instances: same as `NEWOBJ(cls, ())`.
+/// - `_codecs.encode(text, "latin1")` and `bytes()` are how Python 3
+///   pickles `bytes` for protocols 0 to 2.
+/// - `copyreg.__newobj__(cls, *args)` and
+///   `copyreg.__newobj_ex__(cls, args, kwargs)` are how the encoder writes
+///   NEWOBJ for protocol 0 and NEWOBJ_EX below protocol 4.
+fn legacy_reduce(callable: &PickleValue, args: &PickleValue) -> Option<PickleValue> {
+    let PickleValue::Global { module, name } = callable else {
+        return None;
+    };
+    let PickleValue::Tuple(args) = args else {
+        return None;
+    };
+    match (module.as_str(), name.as_str(), args.as_slice()) {
+        (
+            "copy_reg" | "copyreg",
+            "_reconstructor",
+            [cls @ PickleValue::Global { .. }, PickleValue::Global { module: base_module, name: base }, PickleValue::None],
+        ) if (base_module == "__builtin__" || base_module == "builtins") && base == "object" => {
+            Some(PickleValue::Reduce {
+                callable: Box::new(cls.clone()),
+                args: Box::new(PickleValue::Tuple(Vec::new())),
+                dict_items: None,
+                list_items: None,
+                newobj: true,
+            })
+        }
+        ("copy_reg" | "copyreg", "__newobj__", [cls, args @ ..]) => Some(PickleValue::Reduce {
+            callable: Box::new(cls.clone()),
+            args: Box::new(PickleValue::Tuple(args.to_vec())),
+            dict_items: None,
+            list_items: None,
+            newobj: true,
+        }),
+        ("_codecs", "encode", [PickleValue::String(text), PickleValue::String(encoding)])
+            if &**encoding == "latin1" || &**encoding == "latin-1" =>
+        {
+            text.chars()
+                .map(|c| u8::try_from(c as u32).ok())
+                .collect::<Option<Vec<u8>>>()
+                .map(PickleValue::Bytes)
+        }
+        ("__builtin__" | "builtins", "bytes", []) => Some(PickleValue::Bytes(Vec::new())),
+        ("copy_reg" | "copyreg", "__newobj_ex__", [cls, args, kwargs]) => {
+            Some(PickleValue::NewObjEx {
+                cls: Box::new(cls.clone()),
+                args: Box::new(args.clone()),
+                kwargs: Box::new(kwargs.clone()),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Undo the escapes of a Python 2 `repr()`ed byte string (the STRING
+/// argument without its quotes).
+fn unescape_string_repr(data: &[u8]) -> Result<Vec<u8>, CodecError> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        i += 1;
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        let Some(&esc) = data.get(i) else {
+            return Err(CodecError::InvalidData("trailing backslash in STRING".to_string()));
+        };
+        i += 1;
+        match esc {
+            b'\n' => {} // line continuation
+            b'\\' | b'\'' | b'"' => out.push(esc),
+            b'a' => out.push(0x07),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'v' => out.push(0x0b),
+            b'x' => {
+                let hex = data
+                    .get(i..i + 2)
+                    .and_then(|h| std::str::from_utf8(h).ok())
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| CodecError::InvalidData("invalid \\x escape in STRING".to_string()))?;
+                out.push(hex);
+                i += 2;
+            }
+            b'0'..=b'7' => {
+                // Up to three octal digits
+                let mut val = u32::from(esc - b'0');
+                for _ in 0..2 {
+                    match data.get(i) {
+                        Some(&d @ b'0'..=b'7') => {
+                            val = val * 8 + u32::from(d - b'0');
+                            i += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                out.push(val as u8);
+            }
+            _ => {
+                // Unknown escapes are kept verbatim, as Python does
+                out.push(b'\\');
+                out.push(esc);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Decode the raw-unicode-escape argument of a UNICODE opcode to UTF-8:
+/// each byte is a Latin-1 character, except `\uXXXX` and `\UXXXXXXXX`
+/// escapes. Escaped surrogates get their `surrogatepass` form, for
+/// `decode_text` to handle.
+fn decode_raw_unicode_escape(data: &[u8]) -> Result<Vec<u8>, CodecError> {
+    if !data.contains(&b'\\') && data.is_ascii() {
+        // Fast path: plain ASCII
+        return Ok(data.to_vec());
+    }
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        i += 1;
+        if b != b'\\' {
+            surrogates::push_code_point(&mut out, u32::from(b));
+            continue;
+        }
+        // A backslash escapes only when preceded by an even number of
+        // backslashes and followed by `u` or `U`
+        let mut run = 1;
+        while data.get(i) == Some(&b'\\') {
+            run += 1;
+            i += 1;
+        }
+        let width = match data.get(i) {
+            Some(b'u') if run % 2 == 1 => 4,
+            Some(b'U') if run % 2 == 1 => 8,
+            _ => 0,
+        };
+        let literal = if width > 0 { run - 1 } else { run };
+        out.extend(std::iter::repeat_n(b'\\', literal));
+        if width == 0 {
+            continue;
+        }
+        let cp = data
+            .get(i + 1..i + 1 + width)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u32::from_str_radix(h, 16).ok())
+            .filter(|&cp| cp <= 0x10FFFF)
+            .ok_or_else(|| CodecError::InvalidData("invalid \\u escape in UNICODE".to_string()))?;
+        surrogates::push_code_point(&mut out, cp);
+        i += 1 + width;
+    }
+    Ok(out)
+}
+
 /// Convert a flat list [k1, v1, k2, v2, ...] into pairs [(k1, v1), (k2, v2), ...].
 fn items_to_pairs(
-    items: Vec<PickleValue>,
+    items: &mut Vec<PickleValue>,
+    lenient: bool,
 ) -> Result<Vec<(PickleValue, PickleValue)>, CodecError> {
-    if items.len() % 2 != 0 {
+    let dangling = if items.len().is_multiple_of(2) {
+        None
+    } else if lenient {
+        items.pop()
+    } else {
         return Err(CodecError::InvalidData(
             "odd number of items for dict".to_string(),
         ));
-    }
-    let mut pairs = Vec::with_capacity(items.len() / 2);
-    let mut iter = items.into_iter();
+    };
+    let mut pairs = Vec::with_capacity(items.len() / 2 + dangling.is_some() as usize);
+    let mut iter = items.drain(..);
     while let (Some(k), Some(v)) = (iter.next(), iter.next()) {
         pairs.push((k, v));
     }
+    if let Some(item) = dangling {
+        pairs.push((PickleValue::String(DANGLING_KEY.into()), item));
+    }
     Ok(pairs)
 }
 
@@ -975,10 +2000,59 @@ mod tests {
         let data = b"\x80\x02\x8c\x05hello.";
         assert_eq!(
             decode_pickle(data).unwrap(),
-            PickleValue::String("hello".to_string())
+            PickleValue::String("hello".into())
         );
     }
 
+    #[test]
+    fn test_memo_hits_share_strings() {
+        // ["title", <BINGET 1>]
+        let data = b"\x80\x02]q\x00(X\x05\x00\x00\x00titleq\x01h\x01e.";
+        let PickleValue::List(items) = decode_pickle(data).unwrap() else {
+            panic!("expected a list");
+        };
+        let [PickleValue::String(a), PickleValue::String(b)] = items.as_slice() else {
+            panic!("expected two strings: {items:?}");
+        };
+        assert_eq!(&**a, "title");
+        assert!(std::sync::Arc::ptr_eq(a, b));
+    }
+
+    #[test]
+    fn test_memo_copies_only_read_entries() {
+        // ([[1], [2]], <BINGET 2>): entries 0 and 1 are never read
+        let data = b"\x80\x02]q\x00(]q\x01K\x01a]q\x02K\x02aeh\x02\x86.";
+        assert_eq!(memo_reads(data, &LineLimits::default()), Some(vec![0, 0, 1]));
+        let mut decoder = Decoder::new(data);
+        let list = |i| PickleValue::List(vec![PickleValue::Int(i)]);
+        assert_eq!(
+            decoder.run().unwrap(),
+            PickleValue::Tuple(vec![PickleValue::List(vec![list(1), list(2)]), list(2)])
+        );
+        // The last GET took entry 2; the others were never copied
+        assert!(decoder.memo.iter().all(|val| *val == PickleValue::None));
+        assert_eq!(memo_reads(b"\x80\x02]q\x00h", &LineLimits::default()), None);
+    }
+
+    #[test]
+    fn test_mark_frames_reused() {
+        // ({1: 2, ...}, [1]): the list's MARK reuses the dict's frame
+        let mut data = b"\x80\x02(".to_vec();
+        data.extend(b"K\x01K\x02".repeat(20));
+        data.extend(b"d(K\x01l\x86.");
+        let mut decoder = Decoder::new(&data);
+        let PickleValue::Tuple(items) = decoder.run().unwrap() else {
+            panic!("expected a tuple");
+        };
+        let [PickleValue::Dict(pairs), PickleValue::List(list)] = items.as_slice() else {
+            panic!("expected a dict and a list: {items:?}");
+        };
+        assert_eq!((pairs.len(), list.as_slice()), (20, &[PickleValue::Int(1)][..]));
+        // The list got its own storage, not the 40 item frame
+        assert!(list.capacity() < 4, "{}", list.capacity());
+        assert!(decoder.spare_frames[0].capacity() >= 40);
+    }
+
     #[test]
     fn test_decode_empty_list() {
         let data = b"\x80\x02].";
@@ -1015,7 +2089,7 @@ mod tests {
         assert_eq!(
             result,
             PickleValue::Dict(vec![(
-                PickleValue::String("a".to_string()),
+                PickleValue::String("a".into()),
                 PickleValue::Int(1)
             )])
         );
@@ -1067,8 +2141,8 @@ mod tests {
                 module: "mymod".to_string(),
                 name: "MyCls".to_string(),
                 state: Box::new(PickleValue::Dict(vec![(
-                    PickleValue::String("name".to_string()),
-                    PickleValue::String("test".to_string()),
+                    PickleValue::String("name".into()),
+                    PickleValue::String("test".into()),
                 )])),
                 dict_items: None,
                 list_items: None,
@@ -1129,12 +2203,12 @@ mod tests {
         let result = decode_pickle(data).unwrap();
 
         let inner = PickleValue::Dict(vec![(
-            PickleValue::String("x".to_string()),
+            PickleValue::String("x".into()),
             PickleValue::Int(1),
         )]);
         let expected = PickleValue::Dict(vec![
-            (PickleValue::String("a".to_string()), inner.clone()),
-            (PickleValue::String("b".to_string()), inner),
+            (PickleValue::String("a".into()), inner.clone()),
+            (PickleValue::String("b".into()), inner),
         ]);
         assert_eq!(
             result, expected,
@@ -1263,6 +2337,7 @@ mod tests {
                 args,
                 dict_items,
                 list_items,
+                newobj: false,
             } => {
                 if let PickleValue::Global { module, name } = callable.as_ref() {
                     assert_eq!(module, "collections");
@@ -1273,10 +2348,10 @@ mod tests {
                 assert_eq!(**args, PickleValue::Tuple(vec![]));
                 let items = dict_items.as_ref().expect("dict_items should be Some");
                 assert_eq!(items.len(), 2);
-                assert_eq!(items[0].0, PickleValue::String("key1".to_string()));
-                assert_eq!(items[0].1, PickleValue::String("val1".to_string()));
-                assert_eq!(items[1].0, PickleValue::String("key2".to_string()));
-                assert_eq!(items[1].1, PickleValue::String("val2".to_string()));
+                assert_eq!(items[0].0, PickleValue::String("key1".into()));
+                assert_eq!(items[0].1, PickleValue::String("val1".into()));
+                assert_eq!(items[1].0, PickleValue::String("key2".into()));
+                assert_eq!(items[1].1, PickleValue::String("val2".into()));
                 assert!(list_items.is_none());
             }
             _ => panic!("expected Reduce, got {:?}", result),
@@ -1300,8 +2375,8 @@ mod tests {
         if let PickleValue::Reduce { dict_items, .. } = &result {
             let items = dict_items.as_ref().unwrap();
             assert_eq!(items.len(), 1);
-            assert_eq!(items[0].0, PickleValue::String("key".to_string()));
-            assert_eq!(items[0].1, PickleValue::String("val".to_string()));
+            assert_eq!(items[0].0, PickleValue::String("key".into()));
+            assert_eq!(items[0].1, PickleValue::String("val".into()));
         } else {
             panic!("expected Reduce");
         }
@@ -1362,10 +2437,640 @@ mod tests {
             assert_eq!(inst.name, "MyDict");
             let items = inst.dict_items.as_ref().expect("dict_items should carry through BUILD");
             assert_eq!(items.len(), 1);
-            assert_eq!(items[0].0, PickleValue::String("a".to_string()));
+            assert_eq!(items[0].0, PickleValue::String("a".into()));
             assert_eq!(items[0].1, PickleValue::Int(1));
         } else {
             panic!("expected Instance, got {:?}", result);
         }
     }
+
+    #[test]
+    fn test_overlong_global_line_rejected() {
+        let mut data = b"c".to_vec();
+        data.extend(std::iter::repeat_n(b'm', DEFAULT_MAX_NAME_LINE + 1));
+        data.extend_from_slice(b"\nName\n.");
+        let err = decode_pickle(&data).unwrap_err();
+        assert!(matches!(err.root(), CodecError::LimitExceeded(_)), "{err}");
+
+        // Unterminated overlong line: the limit triggers, not EOF
+        let err = decode_pickle(&data[..DEFAULT_MAX_NAME_LINE + 2]).unwrap_err();
+        assert!(matches!(err.root(), CodecError::LimitExceeded(_)), "{err}");
+    }
+
+    #[test]
+    fn test_line_limits_per_category() {
+        let limits = LineLimits {
+            max_name: 8,
+            max_number: 3,
+            max_string: 5,
+        };
+        let options = DecodeOptions::new().with_line_limits(limits);
+        let decode_with = |data: &[u8]| decode_pickle_with_options(data, &options);
+        assert_eq!(decode_with(b"I123\n.").unwrap(), PickleValue::Int(123));
+        assert!(matches!(decode_with(b"I1234\n.").unwrap_err().root(), CodecError::LimitExceeded(_)));
+        assert!(decode_with(b"V12345\n.").is_ok());
+        assert!(matches!(decode_with(b"V123456\n.").unwrap_err().root(), CodecError::LimitExceeded(_)));
+        assert!(decode_with(b"cmod\nName\n.").is_ok());
+        assert!(matches!(
+            decode_with(b"cmodule.sub\nName\n.").unwrap_err().root(),
+            CodecError::LimitExceeded(_)
+        ));
+    }
+
+    fn decode_lenient(data: &[u8]) -> Result<PickleValue, CodecError> {
+        let mut decoder = Decoder::new(data);
+        decoder.lenient = true;
+        decoder.run_or_raw(true)
+    }
+
+    fn decode_record_lenient(data: &[u8]) -> Result<(PickleValue, PickleValue), CodecError> {
+        let mut decoder = Decoder::new(data);
+        decoder.lenient = true;
+        Ok((decoder.run_or_raw(false)?, decoder.run_or_raw(true)?))
+    }
+
+    #[test]
+    fn test_lenient_keeps_undecodable_pickle_raw() {
+        // Unknown opcode 0xff: no STOP can be found, the rest is kept
+        let data = b"\x80\x03]K\x01\xffa.";
+        assert!(matches!(decode_pickle(data).unwrap_err().root(), CodecError::UnknownOpcode(0xff)));
+        assert_eq!(decode_lenient(data).unwrap(), PickleValue::RawPickle(data.to_vec()));
+        // APPEND on an empty stack, before a well-formed STOP
+        let data = b"\x80\x03a.";
+        assert_eq!(decode_lenient(data).unwrap(), PickleValue::RawPickle(data.to_vec()));
+    }
+
+    #[test]
+    fn test_lenient_fallback_warning() {
+        let data = b"\x80\x03]K\x01\xffa.";
+        let (val, warnings) = crate::warnings::collect_warnings(|| decode_lenient(data));
+        assert!(matches!(val.unwrap(), PickleValue::RawPickle(_)));
+        assert_eq!(warnings[0].code, WarningCode::RawPickle);
+        assert!(warnings[0].message.starts_with("undecodable pickle at byte 0 (8 bytes) kept as @pkl"));
+    }
+
+    #[test]
+    fn test_lenient_record() {
+        let class = b"\x80\x03X\x03\x00\x00\x00modX\x03\x00\x00\x00Cls\x86N\x86.";
+        let state = b"\x80\x03}X\x01\x00\x00\x00a\xff.";
+        let record = [&class[..], state].concat();
+        let (class_val, state_val) = decode_record_lenient(&record).unwrap();
+        assert_eq!(extract_class_info(&class_val), ("mod".into(), "Cls".into()));
+        assert_eq!(state_val, PickleValue::RawPickle(state.to_vec()));
+
+        // A class pickle that fails is cut at its STOP
+        let bad_class = b"\x80\x03h\x05.";
+        let record = [&bad_class[..], b"\x80\x03N."].concat();
+        let (class_val, state_val) = decode_record_lenient(&record).unwrap();
+        assert_eq!(class_val, PickleValue::RawPickle(bad_class.to_vec()));
+        assert_eq!(state_val, PickleValue::None);
+
+        // ...and without a STOP the record cannot be split
+        let record = [&b"\x80\x03\xff."[..], b"\x80\x03N."].concat();
+        assert!(decode_record_lenient(&record).is_err());
+    }
+
+    #[test]
+    fn test_lenient_keeps_limits() {
+        let mut decoder = Decoder::new(b"\x80\x03]].");
+        decoder.lenient = true;
+        decoder.limits.max_containers = 1;
+        assert!(matches!(
+            decoder.run_or_raw(true).unwrap_err().root(),
+            CodecError::LimitExceeded(_)
+        ));
+    }
+
+    #[test]
+    fn test_odd_setitems_strict_fails() {
+        // EMPTY_DICT MARK 'a' 1 'b' SETITEMS
+        let data = b"\x80\x02}(X\x01\x00\x00\x00aK\x01X\x01\x00\x00\x00bu.";
+        assert!(decode_pickle(data).is_err());
+    }
+
+    #[test]
+    fn test_odd_setitems_lenient_keeps_dangling() {
+        let data = b"\x80\x02}(X\x01\x00\x00\x00aK\x01X\x01\x00\x00\x00bu.";
+        assert_eq!(
+            decode_lenient(data).unwrap(),
+            PickleValue::Dict(vec![
+                (PickleValue::String("a".into()), PickleValue::Int(1)),
+                (
+                    PickleValue::String(DANGLING_KEY.into()),
+                    PickleValue::String("b".into())
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_odd_dict_lenient_keeps_dangling() {
+        // MARK 1 DICT
+        let data = b"\x80\x02(K\x01d.";
+        assert_eq!(
+            decode_lenient(data).unwrap(),
+            PickleValue::Dict(vec![(
+                PickleValue::String(DANGLING_KEY.into()),
+                PickleValue::Int(1)
+            )])
+        );
+        // Even counts are unaffected
+        let data = b"\x80\x02(K\x01K\x02d.";
+        assert_eq!(
+            decode_lenient(data).unwrap(),
+            PickleValue::Dict(vec![(PickleValue::Int(1), PickleValue::Int(2))])
+        );
+    }
+
+    #[test]
+    fn test_string_repr_escapes() {
+        // Python 2: cPickle.dumps("it's\n\x00\xe9", 0)
+        let data = b"S'it\\'s\\n\\x00\\xe9'\np1\n.";
+        assert_eq!(decode_pickle(data).unwrap(), PickleValue::Bytes(b"it's\n\x00\xe9".to_vec()));
+        let data = b"S\"it's \\\\ \\101\"\n.";
+        assert_eq!(decode_pickle(data).unwrap(), PickleValue::Bytes(b"it's \\ A".to_vec()));
+        assert!(decode_pickle(b"S'bad\\x4'\n.").is_err());
+    }
+
+    #[test]
+    fn test_unicode_raw_escape() {
+        // Python: pickle.dumps("caf\xe9 \u20ac\\\n\U0001f600", 0)
+        let data = b"Vcaf\xe9 \\u20ac\\u005c\\u000a\\U0001f600\np0\n.";
+        assert_eq!(
+            decode_pickle(data).unwrap(),
+            PickleValue::String("caf\u{e9} \u{20ac}\\\n\u{1f600}".into())
+        );
+        // An escaped backslash before `u` is not an escape
+        assert_eq!(
+            decode_raw_unicode_escape(b"a\\\\u0041").unwrap(),
+            b"a\\\\u0041"
+        );
+        assert!(decode_pickle(b"V\\u12\n.").is_err());
+    }
+
+    #[test]
+    fn test_protocol0_text_flows() {
+        // Python 2: cPickle.dumps({'a': [1, 2L, 1.5], 'b': ('x', None)}, 0)
+        let data = b"(dp1\nS'a'\np2\n(lp3\nI1\naL2L\naF1.5\nasS'b'\np4\n(S'x'\np5\nNtp6\ns.";
+        assert_eq!(
+            decode_pickle(data).unwrap(),
+            PickleValue::Dict(vec![
+                (
+                    PickleValue::Bytes(b"a".to_vec()),
+                    PickleValue::List(vec![
+                        PickleValue::Int(1),
+                        PickleValue::Int(2),
+                        PickleValue::Float(1.5),
+                    ])
+                ),
+                (
+                    PickleValue::Bytes(b"b".to_vec()),
+                    PickleValue::Tuple(vec![PickleValue::Bytes(b"x".to_vec()), PickleValue::None])
+                ),
+            ])
+        );
+        // Memo GET of a text PUT
+        let data = b"(lp0\nS'x'\np1\nag1\na.";
+        assert_eq!(
+            decode_pickle(data).unwrap(),
+            PickleValue::List(vec![PickleValue::Bytes(b"x".to_vec()); 2])
+        );
+    }
+
+    #[test]
+    fn test_legacy_reduce_forms() {
+        // Python 2 protocol 0 new-style instance
+        let data = b"ccopy_reg\n_reconstructor\np0\n(cmyapp\nDoc\np1\nc__builtin__\nobject\np2\nNtp3\nRp4\n(dp5\nS'x'\np6\nI1\nsb.";
+        assert_eq!(
+            decode_pickle(data).unwrap(),
+            PickleValue::Instance(Box::new(InstanceData {
+                module: "myapp".into(),
+                name: "Doc".into(),
+                state: Box::new(PickleValue::Dict(vec![(
+                    PickleValue::Bytes(b"x".to_vec()),
+                    PickleValue::Int(1)
+                )])),
+                dict_items: None,
+                list_items: None,
+            }))
+        );
+        // Python 2 set
+        let data = b"c__builtin__\nset\np0\n((lp1\nI1\naI2\natp2\nRp3\n.";
+        assert_eq!(
+            decode_pickle(data).unwrap(),
+            PickleValue::Set(vec![PickleValue::Int(1), PickleValue::Int(2)])
+        );
+        // Python 3 bytes below protocol 3
+        let data = b"c_codecs\nencode\np0\n(V\xff\\u0000\np1\nVlatin1\np2\ntp3\nRp4\n.";
+        assert_eq!(decode_pickle(data).unwrap(), PickleValue::Bytes(vec![0xff, 0]));
+        let data = b"c__builtin__\nbytes\np0\n(tRp1\n.";
+        assert_eq!(decode_pickle(data).unwrap(), PickleValue::Bytes(Vec::new()));
+        // Other encodings and bases stay generic
+        let data = b"c_codecs\nencode\n(Vx\nVutf8\ntR.";
+        assert!(matches!(decode_pickle(data).unwrap(), PickleValue::Reduce { .. }));
+        let data = b"ccopy_reg\n_reconstructor\n(cmyapp\nD\nc__builtin__\ndict\n(dtR.";
+        assert!(matches!(decode_pickle(data).unwrap(), PickleValue::Reduce { .. }));
+        // NEWOBJ_EX as the encoder writes it below protocol 4
+        let data = b"\x80\x03ccopyreg\n__newobj_ex__\ncmyapp\nP\n)}\x87R.";
+        assert!(matches!(decode_pickle(data).unwrap(), PickleValue::NewObjEx { .. }));
+    }
+
+    #[test]
+    fn test_newobj_ex() {
+        // P(1, y=2) for a class with __getnewargs_ex__, protocol 4
+        let data = b"\x80\x04\x8c\x05myapp\x8c\x01P\x93K\x01\x85}\x8c\x01y\x94K\x02s\x92\
+                     }(\x8c\x01xK\x01h\x00K\x02ub.";
+        let s = |v: &str| PickleValue::String(v.into());
+        let obj = PickleValue::NewObjEx {
+            cls: Box::new(PickleValue::Global { module: "myapp".into(), name: "P".into() }),
+            args: Box::new(PickleValue::Tuple(vec![PickleValue::Int(1)])),
+            kwargs: Box::new(PickleValue::Dict(vec![(s("y"), PickleValue::Int(2))])),
+        };
+        let state =
+            PickleValue::Dict(vec![(s("x"), PickleValue::Int(1)), (s("y"), PickleValue::Int(2))]);
+        assert_eq!(
+            decode_pickle(data).unwrap(),
+            PickleValue::Instance(Box::new(InstanceData::anonymous_object(obj, state)))
+        );
+    }
+
+    #[test]
+    fn test_inst_and_obj() {
+        let doc = PickleValue::Instance(Box::new(InstanceData {
+            module: "myapp".into(),
+            name: "Doc".into(),
+            state: Box::new(PickleValue::Dict(vec![(
+                PickleValue::Bytes(b"x".to_vec()),
+                PickleValue::Int(1),
+            )])),
+            dict_items: None,
+            list_items: None,
+        }));
+        // Python 2 classic class instance, protocol 0 and 1
+        let data = b"(imyapp\nDoc\np0\n(dp1\nS'x'\np2\nI1\nsb.";
+        assert_eq!(decode_pickle(data).unwrap(), doc);
+        let data = b"(cmyapp\nDoc\nq\x00oq\x01}q\x02U\x01xK\x01sb.";
+        assert_eq!(decode_pickle(data).unwrap(), doc);
+        // With __getinitargs__: cls(*args)
+        let point = PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global { module: "myapp".into(), name: "P".into() }),
+            args: Box::new(PickleValue::Tuple(vec![PickleValue::Int(1)])),
+            dict_items: None,
+            list_items: None,
+            newobj: false,
+        };
+        assert_eq!(decode_pickle(b"(I1\nimyapp\nP\n.").unwrap(), point);
+        assert_eq!(decode_pickle(b"(cmyapp\nP\nK\x01o.").unwrap(), point);
+        // No BUILD: cls.__new__(cls)
+        assert!(matches!(
+            decode_pickle(b"(imyapp\nP\n.").unwrap(),
+            PickleValue::Reduce { newobj: true, .. }
+        ));
+        assert!(matches!(decode_pickle(b"(o.").unwrap_err().root(), CodecError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_py2_strings() {
+        let decode_with = |data: &[u8], mode| {
+            let mut decoder = Decoder::new(data);
+            decoder.py2_strings = mode;
+            decoder.run()
+        };
+        let s = |v: &str| PickleValue::String(v.into());
+        // Protocol 0 and 1 str, one of them not UTF-8
+        let data = b"(lp0\nS'caf\\xc3\\xa9'\np1\naU\x02\xff\xfeaT\x01\x00\x00\x00xa.";
+        assert_eq!(
+            decode_with(data, Py2Strings::Utf8).unwrap(),
+            PickleValue::List(vec![s("café"), PickleValue::Bytes(vec![0xff, 0xfe]), s("x")])
+        );
+        assert_eq!(
+            decode_with(data, Py2Strings::Latin1).unwrap(),
+            PickleValue::List(vec![s("cafÃ©"), s("ÿþ"), s("x")])
+        );
+        assert_eq!(
+            decode_with(data, Py2Strings::Bytes).unwrap(),
+            decode_pickle(data).unwrap()
+        );
+        // OIDs and packed datetime arguments stay bytes
+        let data = b"\x80\x02]q\x00(U\x08\x00\x00\x00\x00\x00\x00\x00\x07cmyapp\nDoc\n\x86Q\
+                     (U\x01wU\x08\x00\x00\x00\x00\x00\x00\x00\x08\x85lQ\
+                     cdatetime\ndate\nU\x04\x07\xd0\x01\x01\x85Re.";
+        let oid = |n| PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, n]);
+        for mode in [Py2Strings::Latin1, Py2Strings::Utf8] {
+            let PickleValue::List(items) = decode_with(data, mode).unwrap() else {
+                panic!("expected a list");
+            };
+            assert_eq!(
+                items[0],
+                PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+                    oid(7),
+                    PickleValue::Global { module: "myapp".into(), name: "Doc".into() }
+                ])))
+            );
+            assert_eq!(
+                items[1],
+                PickleValue::PersistentRef(Box::new(PickleValue::List(vec![
+                    s("w"),
+                    PickleValue::Tuple(vec![oid(8)])
+                ])))
+            );
+            let PickleValue::Reduce { args, .. } = &items[2] else {
+                panic!("expected a date, got {:?}", items[2]);
+            };
+            assert_eq!(**args, PickleValue::Tuple(vec![PickleValue::Bytes(vec![7, 0xd0, 1, 1])]));
+        }
+    }
+
+    #[test]
+    fn test_persid_text_id() {
+        let data = b"(lp0\nP0000000000000001\na.";
+        assert_eq!(
+            decode_pickle(data).unwrap(),
+            PickleValue::List(vec![PickleValue::PersistentRef(Box::new(PickleValue::String(
+                "0000000000000001".into()
+            )))])
+        );
+    }
+
+    fn quota_record(class_name: &str, items: i64) -> Vec<u8> {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::String("myapp".into()),
+            PickleValue::String(class_name.into()),
+        ]);
+        let state = PickleValue::List((0..items).map(PickleValue::Int).collect());
+        let mut out = crate::encode::encode_pickle(&class).unwrap();
+        out.extend(crate::encode::encode_pickle(&state).unwrap());
+        out
+    }
+
+    #[test]
+    fn test_class_quota_size() {
+        use crate::quotas::ClassQuota;
+        let quotas = ClassQuotas::new(ClassQuota::new(Some(64), None))
+            .with_class("myapp.File", ClassQuota::new(Some(1 << 20), None));
+        let options = DecodeOptions::new().with_quotas(quotas);
+        let data = quota_record("File", 1000);
+        assert!(decode_zodb_pickles_with_options(&data, &options).is_ok());
+        let data = quota_record("Document", 1000);
+        let err = decode_zodb_pickles_with_options(&data, &options).unwrap_err();
+        assert!(matches!(err.root(), CodecError::LimitExceeded(_)), "{err}");
+        assert!(err.to_string().contains("myapp.Document"), "{err}");
+        // Small records of any class pass
+        let data = quota_record("Document", 2);
+        assert!(decode_zodb_pickles_with_options(&data, &options).is_ok());
+    }
+
+    #[test]
+    fn test_class_quota_time() {
+        use crate::quotas::ClassQuota;
+        use std::time::Duration;
+        let quotas = ClassQuotas::new(ClassQuota::new(None, Some(Duration::ZERO)))
+            .with_class("myapp.File", ClassQuota::default());
+        let options = DecodeOptions::new().with_quotas(quotas);
+        let data = quota_record("Document", 20_000);
+        let err = decode_zodb_pickles_with_options(&data, &options).unwrap_err();
+        assert!(err.to_string().contains("time quota"), "{err}");
+        let data = quota_record("File", 20_000);
+        assert!(decode_zodb_pickles_with_options(&data, &options).is_ok());
+        // Without quotas nothing is enforced
+        let data = quota_record("Document", 20_000);
+        assert!(decode_zodb_pickles(&data).is_ok());
+    }
+
+    #[test]
+    fn test_bytearray8() {
+        // pickle.dumps(bytearray(b"ab"), protocol=5)
+        let data = b"\x80\x05\x95\r\x00\x00\x00\x00\x00\x00\x00\x96\x02\x00\x00\x00\x00\x00\x00\x00ab\x94.";
+        assert_eq!(decode_pickle(data).unwrap(), PickleValue::Bytes(b"ab".to_vec()));
+        assert!(matches!(
+            decode_pickle(b"\x96\xff\xff\xff\xff\xff\xff\xff\xff").unwrap_err().root(),
+            CodecError::LimitExceeded(_)
+        ));
+    }
+
+    #[test]
+    fn test_out_of_band_buffers() {
+        // [PickleBuffer(b"x"), PickleBuffer(bytearray(b"yz"))] with buffer_callback
+        let data = b"\x80\x05\x95\x08\x00\x00\x00\x00\x00\x00\x00]\x94(\x97\x98\x97e.";
+        let val = decode_pickle_with_buffers(data, &[b"x", b"yz"]).unwrap();
+        assert_eq!(
+            val,
+            PickleValue::List(vec![
+                PickleValue::Bytes(b"x".to_vec()),
+                PickleValue::Bytes(b"yz".to_vec()),
+            ])
+        );
+        let err = decode_pickle(data).unwrap_err().to_string();
+        assert!(err.contains("none were given"), "{err}");
+        let err = decode_pickle_with_buffers(data, &[b"x"]).unwrap_err().to_string();
+        assert!(err.contains("not enough out-of-band buffers"), "{err}");
+        assert!(matches!(decode_pickle(b"\x98.").unwrap_err().root(), CodecError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_decode_policy() {
+        use crate::policy::PolicyViolation;
+        let decode_with = |data: &[u8], on_violation| {
+            let policy = DecodePolicy::new(on_violation).allow("builtins", "*");
+            decode_pickle_with_options(data, &DecodeOptions::new().with_policy(policy))
+        };
+        let global = b"\x80\x02cos\nsystem\n.";
+        let stack_global = b"\x80\x04\x8c\x02os\x8c\x06system\x93.";
+        let blocked = PickleValue::Blocked {
+            module: "os".to_string(),
+            name: "system".to_string(),
+        };
+        for data in [&global[..], &stack_global[..]] {
+            let err = decode_with(data, PolicyViolation::Error).unwrap_err();
+            assert!(err.to_string().contains("os.system"), "{err}");
+            assert_eq!(decode_with(data, PolicyViolation::Block).unwrap(), blocked);
+        }
+        let inst = decode_with(b"(ios\nsystem\n.", PolicyViolation::Block).unwrap();
+        assert!(matches!(inst, PickleValue::Reduce { callable, .. } if *callable == blocked));
+        assert!(decode_with(b"(ios\nsystem\n.", PolicyViolation::Error).is_err());
+        assert!(decode_with(b"\x80\x02c__builtin__\nset\n.", PolicyViolation::Error).is_err());
+        assert!(decode_with(b"\x80\x02cbuiltins\nset\n.", PolicyViolation::Error).is_ok());
+        // A blocked class encodes back to the original GLOBAL
+        let encoded = crate::encode::encode_pickle(&blocked).unwrap();
+        assert_eq!(
+            decode_pickle(&encoded).unwrap(),
+            PickleValue::Global {
+                module: "os".to_string(),
+                name: "system".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_surrogates_option() {
+        // BINUNICODE of "\udce9" in its surrogatepass form
+        let data = b"\x80\x03X\x03\x00\x00\x00\xed\xb3\xa9.";
+        assert!(matches!(decode_pickle(data).unwrap_err().root(), CodecError::InvalidUtf8));
+        let options = DecodeOptions::new().with_surrogates(SurrogatePolicy::Preserve);
+        assert_eq!(
+            decode_pickle_with_options(data, &options).unwrap(),
+            PickleValue::SurrogateString(b"\xed\xb3\xa9".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_class_renames() {
+        use crate::policy::PolicyViolation;
+        let renames = ClassRenames::new()
+            .rename_class(("Products.Archetypes", "Foo"), ("plone.app", "Foo"))
+            .rename_module("old", "new");
+        let global = |module: &str, name: &str| PickleValue::Global {
+            module: module.to_string(),
+            name: name.to_string(),
+        };
+        let decode_with = |data: &[u8], policy: Option<DecodePolicy>| {
+            let mut options = DecodeOptions::new().with_renames(renames.clone());
+            if let Some(policy) = policy {
+                options = options.with_policy(policy);
+            }
+            decode_pickle_with_options(data, &options)
+        };
+        // GLOBAL, STACK_GLOBAL, and the class of a persistent reference
+        let data = b"\x80\x02cProducts.Archetypes\nFoo\n.";
+        assert_eq!(decode_with(data, None).unwrap(), global("plone.app", "Foo"));
+        let data = b"\x80\x04\x8c\x03old\x94\x8c\x03Bar\x94\x93.";
+        assert_eq!(decode_with(data, None).unwrap(), global("new", "Bar"));
+        let data = b"\x80\x02C\x01\x07cold\nBar\n\x86Q.";
+        assert_eq!(
+            decode_with(data, None).unwrap(),
+            PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+                PickleValue::Bytes(vec![7]),
+                global("new", "Bar"),
+            ])))
+        );
+        // The policy checks the renamed class
+        let policy = DecodePolicy::new(PolicyViolation::Error).allow("new", "*");
+        assert!(decode_with(b"\x80\x02cold\nBar\n.", Some(policy)).is_ok());
+        let policy = DecodePolicy::new(PolicyViolation::Error).allow("old", "*");
+        assert!(decode_with(b"\x80\x02cold\nBar\n.", Some(policy)).is_err());
+        assert_eq!(decode_pickle(b"\x80\x02cold\nBar\n.").unwrap(), global("old", "Bar"));
+    }
+
+    #[test]
+    fn test_self_reference_kept_as_shared() {
+        // l = []; l.append(l)
+        let val = decode_pickle(b"\x80\x02]q\x00h\x00a.").unwrap();
+        assert_eq!(
+            val,
+            PickleValue::Shared {
+                id: 0,
+                value: Box::new(PickleValue::List(vec![PickleValue::BackRef(0)])),
+            }
+        );
+        // a = []; [a, a] -- aliasing without a cycle is copied
+        let val = decode_pickle(b"\x80\x02]q\x00(]q\x01h\x01e.").unwrap();
+        assert_eq!(
+            val,
+            PickleValue::List(vec![PickleValue::List(vec![]), PickleValue::List(vec![])])
+        );
+        // ... unless the call keeps shared references
+        let options = DecodeOptions::new().with_shared_references(true);
+        let val = decode_pickle_with_options(b"\x80\x02]q\x00(]q\x01h\x01e.", &options).unwrap();
+        assert_eq!(
+            val,
+            PickleValue::List(vec![
+                PickleValue::Shared {
+                    id: 0,
+                    value: Box::new(PickleValue::List(vec![])),
+                },
+                PickleValue::BackRef(0),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_memoized_set_items_with_aliasing() {
+        // pickle.dumps({1, 2}, protocol=2): the item list is memoized
+        let data = b"\x80\x02c__builtin__\nset\nq\x00]q\x01(K\x01K\x02e\x85q\x02Rq\x03.";
+        let mut decoder = Decoder::new(data);
+        decoder.aliasing = true;
+        decoder.sharing = true;
+        assert_eq!(
+            decoder.run().unwrap(),
+            PickleValue::Set(vec![PickleValue::Int(1), PickleValue::Int(2)])
+        );
+    }
+
+    fn decode_limited(data: &[u8], limits: DecodeLimits) -> Result<PickleValue, CodecError> {
+        decode_pickle_with_options(data, &DecodeOptions::new().with_limits(limits))
+    }
+
+    /// Nested lists of two memo GETs each: 2**levels copies of "a".
+    fn doubling_pickle(levels: u8) -> Vec<u8> {
+        let mut data = b"\x80\x02(X\x01\x00\x00\x00alq\x000".to_vec();
+        for i in 1..=levels {
+            data.extend_from_slice(&[b'(', b'h', i - 1, b'h', i - 1, b'l', b'q', i, b'0']);
+        }
+        data.extend_from_slice(&[b'h', levels, b'.']);
+        data
+    }
+
+    #[test]
+    fn test_allocation_limit_stops_memo_expansion() {
+        let limits = DecodeLimits {
+            max_allocation: 1 << 20,
+            ..DecodeLimits::default()
+        };
+        let val = decode_limited(&doubling_pickle(3), limits).unwrap();
+        let PickleValue::List(items) = val else {
+            panic!("expected a list");
+        };
+        assert_eq!(items.len(), 2);
+        let err = decode_limited(&doubling_pickle(40), limits).unwrap_err();
+        assert!(matches!(err.root(), CodecError::LimitExceeded(_)), "{err}");
+        assert!(err.to_string().contains("exceed 1048576 bytes"));
+    }
+
+    #[test]
+    fn test_container_limit_counts_copies() {
+        let limits = DecodeLimits {
+            max_containers: 1000,
+            ..DecodeLimits::default()
+        };
+        assert!(decode_limited(&doubling_pickle(4), limits).is_ok());
+        let err = decode_limited(&doubling_pickle(8), limits).unwrap_err();
+        assert!(err.to_string().contains("more than 1000 containers"), "{err}");
+    }
+
+    #[test]
+    fn test_stack_limit_includes_marks() {
+        let limits = DecodeLimits {
+            max_stack_items: 4,
+            ..DecodeLimits::default()
+        };
+        // 1 2 MARK 3 4 TUPLE TUPLE3
+        let data = b"\x80\x02K\x01K\x02(K\x03K\x04t\x87.";
+        assert!(decode_limited(data, limits).is_ok());
+        // 1 2 MARK 3 4 5 ...
+        let data = b"\x80\x02K\x01K\x02(K\x03K\x04K\x05t\x87.";
+        let err = decode_limited(data, limits).unwrap_err();
+        assert!(err.to_string().contains("more than 4 values"), "{err}");
+    }
+
+    #[test]
+    fn test_memo_and_string_limits() {
+        let limits = DecodeLimits {
+            max_memo_entries: 2,
+            max_string_length: 3,
+            ..DecodeLimits::default()
+        };
+        assert!(decode_limited(b"\x80\x02Nq\x01.", limits).is_ok());
+        let err = decode_limited(b"\x80\x02Nq\x02.", limits).unwrap_err();
+        assert!(err.to_string().contains("memo index 2 exceeds maximum 2"));
+        assert!(decode_limited(b"\x80\x03C\x03abc.", limits).is_ok());
+        for data in [
+            &b"\x80\x03C\x04abcd."[..],
+            b"\x80\x03\x8c\x04abcd.",
+            b"\x80\x03X\x04\x00\x00\x00abcd.",
+            b"Vabcd\n.",
+        ] {
+            let err = decode_limited(data, limits).unwrap_err();
+            assert!(matches!(err.root(), CodecError::LimitExceeded(_)), "{err}");
+        }
+    }
 }
@@ -0,0 +1,160 @@
+//! Deduplication of identical leaf values on the PyObject decode path.
+//!
+//! Bucket-heavy records (catalog indexes, BTree buckets) repeat the same
+//! small values thousands of times: `"visible"`, `"Document"`, the same
+//! float score. Inside a [`DedupScope`] entered with dedup enabled (the
+//! `value_dedup` keyword of the Python decode functions) the forward
+//! conversion in `pyconv.rs` hands out one shared Python object per
+//! distinct value within a record instead of allocating a new one for every
+//! occurrence, which cuts allocation churn and the memory of the result.
+//!
+//! Only immutable leaves are shared: `str` values and dict keys up to
+//! [`MAX_STR_LEN`] bytes, `int` values outside CPython's small-int cache
+//! and `float` values. Marker dicts (`@dt`, `@b`, ...) are mutable and are
+//! always created fresh. The cache lives for one call, which decodes one
+//! record, and holds at most [`MAX_ENTRIES`] values.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use pyo3::prelude::*;
+use pyo3::types::{PyFloat, PyInt, PyString};
+
+/// Longest string (in bytes) that is deduplicated.
+pub(crate) const MAX_STR_LEN: usize = 64;
+
+/// Most distinct values cached per record.
+pub(crate) const MAX_ENTRIES: usize = 8192;
+
+#[derive(Default)]
+struct LeafCache {
+    strings: HashMap<Box<str>, Py<PyAny>>,
+    ints: HashMap<i64, Py<PyAny>>,
+    floats: HashMap<u64, Py<PyAny>>,
+    len: usize,
+}
+
+thread_local! {
+    static CACHE: RefCell<Option<LeafCache>> = const { RefCell::new(None) };
+}
+
+/// Keeps a leaf cache active for the current thread while alive, if
+/// entered with dedup enabled.
+///
+/// Nested scopes (a conversion triggered while another one is running)
+/// share the outer cache; only the outermost scope clears it.
+pub(crate) struct DedupScope {
+    owner: bool,
+}
+
+impl DedupScope {
+    pub(crate) fn enter(enabled: bool) -> Self {
+        if !enabled {
+            return DedupScope { owner: false };
+        }
+        let owner = CACHE.with(|c| match c.try_borrow_mut() {
+            Ok(mut cache) if cache.is_none() => {
+                *cache = Some(LeafCache::default());
+                true
+            }
+            _ => false,
+        });
+        DedupScope { owner }
+    }
+}
+
+impl Drop for DedupScope {
+    fn drop(&mut self) {
+        if self.owner {
+            let cache = CACHE.with(|c| c.borrow_mut().take());
+            drop(cache);
+        }
+    }
+}
+
+/// Run `f` on the active cache; `None` when dedup is not active.
+#[inline]
+fn with_cache(f: impl FnOnce(&mut LeafCache) -> Py<PyAny>) -> Option<Py<PyAny>> {
+    CACHE.with(|c| match c.try_borrow_mut() {
+        Ok(mut cache) => cache.as_mut().map(f),
+        Err(_) => None,
+    })
+}
+
+/// Look up `key` in `map`, creating and caching the object on a miss.
+#[inline]
+fn lookup<K: std::hash::Hash + Eq>(
+    py: Python<'_>,
+    map: &mut HashMap<K, Py<PyAny>>,
+    len: &mut usize,
+    key: K,
+    make: impl FnOnce() -> Py<PyAny>,
+) -> Py<PyAny> {
+    if let Some(obj) = map.get(&key) {
+        return obj.clone_ref(py);
+    }
+    let obj = make();
+    if *len < MAX_ENTRIES {
+        map.insert(key, obj.clone_ref(py));
+        *len += 1;
+    }
+    obj
+}
+
+/// CPython preallocates these ints, so sharing them gains nothing.
+#[inline]
+fn is_small_int(i: i64) -> bool {
+    (-5..=256).contains(&i)
+}
+
+/// A Python `str` for `s`, shared with equal strings of the record.
+#[inline]
+pub(crate) fn str_leaf(py: Python<'_>, s: &str) -> Py<PyAny> {
+    let make = || PyString::new(py, s).into_any().unbind();
+    if s.len() > MAX_STR_LEN {
+        return make();
+    }
+    with_cache(|c| {
+        if let Some(obj) = c.strings.get(s) {
+            return obj.clone_ref(py);
+        }
+        let obj = make();
+        if c.len < MAX_ENTRIES {
+            c.strings.insert(s.into(), obj.clone_ref(py));
+            c.len += 1;
+        }
+        obj
+    })
+    .unwrap_or_else(make)
+}
+
+/// A Python `int` for `i`, shared with equal ints of the record.
+#[inline]
+pub(crate) fn int_leaf(py: Python<'_>, i: i64) -> Py<PyAny> {
+    let make = || PyInt::new(py, i).into_any().unbind();
+    if is_small_int(i) {
+        return make();
+    }
+    with_cache(|c| lookup(py, &mut c.ints, &mut c.len, i, make)).unwrap_or_else(make)
+}
+
+/// A Python `float` for `f`, shared with bit-identical floats of the record.
+#[inline]
+pub(crate) fn float_leaf(py: Python<'_>, f: f64) -> Py<PyAny> {
+    let make = || PyFloat::new(py, f).into_any().unbind();
+    with_cache(|c| lookup(py, &mut c.floats, &mut c.len, f.to_bits(), make)).unwrap_or_else(make)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_ints_not_cached() {
+        assert!(is_small_int(-5));
+        assert!(is_small_int(0));
+        assert!(is_small_int(256));
+        assert!(!is_small_int(257));
+        assert!(!is_small_int(-6));
+    }
+}
@@ -0,0 +1,256 @@
+//! Structured diff of two ZODB records.
+//!
+//! Both records are decoded to their JSON form, `{"@cls": ..., "@s": ...}`
+//! as from `decode_zodb_record`, and compared recursively. Paths are JSON
+//! Pointers into that document, e.g. `/@s/title` or `/@s/items/2`, with
+//! `~0` and `~1` escaping `~` and `/` inside a key.
+//!
+//! Objects are compared key by key and arrays index by index; anything
+//! else that differs, including a value whose type changed, is reported
+//! as changed at its path. The `@kv` item list of BTrees and Buckets is
+//! compared as a map: an entry is addressed by its key (`/@s/@kv/<key>`,
+//! string keys as is, other keys as compact JSON), so a key inserted into
+//! a bucket is one added entry instead of every later pair changing.
+
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value;
+
+use crate::error::CodecError;
+use crate::zodb::decode_zodb_record;
+
+/// Differences between two decoded records, in document order.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct RecordDiff {
+    /// Paths only in the second record, with their value.
+    pub added: Vec<(String, Value)>,
+    /// Paths only in the first record, with their value.
+    pub removed: Vec<(String, Value)>,
+    /// Paths in both records with different values: `(path, old, new)`.
+    pub changed: Vec<(String, Value, Value)>,
+}
+
+impl RecordDiff {
+    /// Whether the records decode to the same document.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Decode two ZODB records and list the paths that differ.
+///
+/// ```
+/// use zodb_json_codec::diff_zodb_records;
+///
+/// // Class pickle ("mod", "Cls") followed by {"a": 1} and {"a": 2}
+/// let head = b"\x80\x03X\x03\x00\x00\x00modX\x03\x00\x00\x00Cls\x86N\x86.";
+/// let a = [&head[..], b"\x80\x03}X\x01\x00\x00\x00aK\x01s."].concat();
+/// let b = [&head[..], b"\x80\x03}X\x01\x00\x00\x00aK\x02s."].concat();
+/// let diff = diff_zodb_records(&a, &b)?;
+/// assert_eq!(diff.changed, vec![("/@s/a".to_string(), 1.into(), 2.into())]);
+/// assert!(diff.added.is_empty() && diff.removed.is_empty());
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn diff_zodb_records(a: &[u8], b: &[u8]) -> Result<RecordDiff, CodecError> {
+    let a = decode_zodb_record(a)?;
+    let b = decode_zodb_record(b)?;
+    let mut diff = RecordDiff::default();
+    diff_value(&mut diff, &mut String::new(), &a, &b);
+    Ok(diff)
+}
+
+fn diff_value(diff: &mut RecordDiff, path: &mut String, a: &Value, b: &Value) {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, va) in a {
+                let len = push_segment(path, key);
+                match b.get(key) {
+                    Some(vb) if key == "@kv" => diff_kv(diff, path, va, vb),
+                    Some(vb) => diff_value(diff, path, va, vb),
+                    None => diff.removed.push((path.clone(), va.clone())),
+                }
+                path.truncate(len);
+            }
+            for (key, vb) in b {
+                if !a.contains_key(key) {
+                    let len = push_segment(path, key);
+                    diff.added.push((path.clone(), vb.clone()));
+                    path.truncate(len);
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            for i in 0..a.len().max(b.len()) {
+                let len = push_segment(path, &i.to_string());
+                match (a.get(i), b.get(i)) {
+                    (Some(va), Some(vb)) => diff_value(diff, path, va, vb),
+                    (Some(va), None) => diff.removed.push((path.clone(), va.clone())),
+                    (None, Some(vb)) => diff.added.push((path.clone(), vb.clone())),
+                    (None, None) => {}
+                }
+                path.truncate(len);
+            }
+        }
+        _ if a != b => diff.changed.push((path.clone(), a.clone(), b.clone())),
+        _ => {}
+    }
+}
+
+/// Compare two `@kv` item lists by key, or as plain arrays if either is
+/// not a list of unique `[key, value]` pairs.
+fn diff_kv(diff: &mut RecordDiff, path: &mut String, a: &Value, b: &Value) {
+    let (Some(a), Some(b)) = (kv_entries(a), kv_entries(b)) else {
+        return diff_value(diff, path, a, b);
+    };
+    let b_index: HashMap<&str, &Value> = b.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+    let a_keys: HashSet<&str> = a.iter().map(|(k, _)| k.as_str()).collect();
+    for (key, va) in &a {
+        let len = push_segment(path, key);
+        match b_index.get(key.as_str()) {
+            Some(vb) => diff_value(diff, path, va, vb),
+            None => diff.removed.push((path.clone(), (*va).clone())),
+        }
+        path.truncate(len);
+    }
+    for (key, vb) in &b {
+        if !a_keys.contains(key.as_str()) {
+            let len = push_segment(path, key);
+            diff.added.push((path.clone(), (*vb).clone()));
+            path.truncate(len);
+        }
+    }
+}
+
+/// The `[key, value]` pairs of a `@kv` list with their path segments.
+fn kv_entries(kv: &Value) -> Option<Vec<(String, &Value)>> {
+    let mut seen = HashSet::new();
+    let mut entries = Vec::new();
+    for pair in kv.as_array()? {
+        let [key, value] = pair.as_array()?.as_slice() else {
+            return None;
+        };
+        let segment = match key {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        if !seen.insert(segment.clone()) {
+            return None;
+        }
+        entries.push((segment, value));
+    }
+    Some(entries)
+}
+
+/// Append `/segment` to `path`, escaped as in JSON Pointer. Returns the
+/// length to truncate back to.
+fn push_segment(path: &mut String, segment: &str) -> usize {
+    let len = path.len();
+    path.push('/');
+    path.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::encode_pickle;
+    use crate::types::PickleValue;
+    use serde_json::json;
+
+    fn record(module: &str, name: &str, state: &PickleValue) -> Vec<u8> {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::Tuple(vec![
+                PickleValue::String(module.into()),
+                PickleValue::String(name.into()),
+            ]),
+            PickleValue::None,
+        ]);
+        [
+            encode_pickle(&class).unwrap(),
+            encode_pickle(state).unwrap(),
+        ]
+        .concat()
+    }
+
+    fn state(json: Value) -> PickleValue {
+        crate::json::json_to_pickle_value(&json).unwrap()
+    }
+
+    #[test]
+    fn test_diff_state() {
+        let a = record(
+            "myapp",
+            "Doc",
+            &state(json!({"title": "Old", "tags": ["a", "b"], "gone": 1, "a/b": {"x": 1}})),
+        );
+        let b = record(
+            "myapp",
+            "Doc",
+            &state(json!({"title": "New", "tags": ["a"], "new": null, "a/b": {"x": "1"}})),
+        );
+        let diff = diff_zodb_records(&a, &b).unwrap();
+        assert_eq!(
+            diff.changed,
+            vec![
+                ("/@s/a~1b/x".to_string(), json!(1), json!("1")),
+                ("/@s/title".to_string(), json!("Old"), json!("New")),
+            ]
+        );
+        assert_eq!(
+            diff.removed,
+            vec![
+                ("/@s/gone".to_string(), json!(1)),
+                ("/@s/tags/1".to_string(), json!("b")),
+            ]
+        );
+        assert_eq!(diff.added, vec![("/@s/new".to_string(), json!(null))]);
+        assert!(diff_zodb_records(&a, &a).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff_class() {
+        let st = state(json!({}));
+        let diff = diff_zodb_records(&record("a", "X", &st), &record("b", "X", &st)).unwrap();
+        assert_eq!(
+            diff.changed,
+            vec![("/@cls/0".to_string(), json!("a"), json!("b"))]
+        );
+    }
+
+    #[test]
+    fn test_diff_bucket_by_key() {
+        let bucket = |items: Vec<(i64, &str)>| {
+            let flat = items
+                .into_iter()
+                .flat_map(|(k, v)| [PickleValue::Int(k), PickleValue::String(v.into())])
+                .collect();
+            let st = PickleValue::Tuple(vec![PickleValue::Tuple(flat)]);
+            record("BTrees.IOBTree", "IOBucket", &st)
+        };
+        let a = bucket(vec![(1, "one"), (3, "three"), (5, "five")]);
+        let b = bucket(vec![(1, "one"), (2, "two"), (3, "THREE")]);
+        let diff = diff_zodb_records(&a, &b).unwrap();
+        assert_eq!(
+            diff.changed,
+            vec![("/@s/@kv/3".to_string(), json!("three"), json!("THREE"))]
+        );
+        assert_eq!(diff.removed, vec![("/@s/@kv/5".to_string(), json!("five"))]);
+        assert_eq!(diff.added, vec![("/@s/@kv/2".to_string(), json!("two"))]);
+    }
+
+    #[test]
+    fn test_kv_entries_fallback() {
+        assert!(kv_entries(&json!([["a", 1], ["a", 2]])).is_none());
+        assert!(kv_entries(&json!([["a", 1, 2]])).is_none());
+        assert_eq!(
+            kv_entries(&json!([[{"@t": [1]}, 1]])).unwrap()[0].0,
+            r#"{"@t":[1]}"#
+        );
+    }
+
+    #[test]
+    fn test_invalid_record() {
+        assert!(diff_zodb_records(b"", b"").is_err());
+    }
+}
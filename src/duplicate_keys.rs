@@ -0,0 +1,242 @@
+//! Dicts with duplicate keys.
+//!
+//! `SETITEMS` does not check its keys, so a hand-crafted or corrupted
+//! pickle can build a dict with the same key twice. `PickleValue::Dict`
+//! keeps every item in pickle order, but a JSON object or Python dict
+//! holds each key once. Unpickling keeps the key at its first position
+//! with its last value; [`DuplicateKeys`] decides whether the converters
+//! do the same, fail, or keep every item as an `@d` pair list, for the
+//! conversions run inside [`with_duplicate_keys`].
+//!
+//! All three converters (serde, JSON writer and PyObject) ask
+//! [`object_items`] which items of a dict with string keys to write, so
+//! they always agree. Dicts already written as `@d` (non-string keys) keep
+//! every item in pickle order, and encode back to the same items in the
+//! same order, so unpickling them applies the same last-wins rule.
+
+use std::cell::Cell;
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+
+use crate::bytes_keys::key_text;
+use crate::error::CodecError;
+use crate::types::PickleValue;
+use crate::warnings::{self, WarningCode};
+
+/// What the converters do with a dict whose string keys repeat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateKeys {
+    /// Keep the last value at the key's first position, as unpickling
+    /// does, with a `duplicate-keys` warning.
+    #[default]
+    LastWins,
+    /// Fail with `CodecError::InvalidData`.
+    Error,
+    /// Write the dict as an `@d` pair list, keeping every item.
+    Preserve,
+}
+
+thread_local! {
+    /// The duplicate key mode of this thread's conversions.
+    static MODE: Cell<DuplicateKeys> = const { Cell::new(DuplicateKeys::LastWins) };
+}
+
+/// Run `f` with the conversions it makes on this thread converting dicts
+/// with duplicate keys as `mode` says.
+///
+/// ```
+/// use zodb_json_codec::{pickle_value_to_json, with_duplicate_keys, DuplicateKeys, PickleValue};
+///
+/// let item = |v| (PickleValue::String("a".into()), PickleValue::Int(v));
+/// let dict = PickleValue::Dict(vec![item(1), item(2)]);
+/// assert_eq!(pickle_value_to_json(&dict)?, serde_json::json!({"a": 2}));
+/// assert!(with_duplicate_keys(DuplicateKeys::Error, || pickle_value_to_json(&dict)).is_err());
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn with_duplicate_keys<R>(mode: DuplicateKeys, f: impl FnOnce() -> R) -> R {
+    let _scope = DuplicateKeysScope::enter(mode);
+    f()
+}
+
+/// Sets the duplicate key mode on the current thread while alive.
+pub(crate) struct DuplicateKeysScope {
+    previous: DuplicateKeys,
+}
+
+impl DuplicateKeysScope {
+    pub(crate) fn enter(mode: DuplicateKeys) -> Self {
+        DuplicateKeysScope {
+            previous: MODE.with(|m| m.replace(mode)),
+        }
+    }
+}
+
+impl Drop for DuplicateKeysScope {
+    fn drop(&mut self) {
+        MODE.with(|m| m.set(self.previous));
+    }
+}
+
+fn duplicate_keys() -> DuplicateKeys {
+    MODE.with(Cell::get)
+}
+
+/// Which items of a dict with string keys become object entries.
+pub(crate) enum ObjectItems {
+    /// Every item: the keys are unique.
+    All,
+    /// The items at these indices, in this order.
+    Picked(Vec<usize>),
+    /// None: write the dict as `@d` pairs.
+    Pairs,
+}
+
+impl ObjectItems {
+    /// The items to write, in order.
+    pub(crate) fn iter<'a>(
+        &'a self,
+        pairs: &'a [(PickleValue, PickleValue)],
+    ) -> impl Iterator<Item = &'a (PickleValue, PickleValue)> {
+        let (all, picked) = match self {
+            ObjectItems::All => (Some(pairs.iter()), None),
+            ObjectItems::Picked(picked) => (None, Some(picked.iter().map(|&i| &pairs[i]))),
+            ObjectItems::Pairs => (None, None),
+        };
+        all.into_iter().flatten().chain(picked.into_iter().flatten())
+    }
+
+    /// The same items, ordered by key (`sort_keys`).
+    pub(crate) fn sorted(self, pairs: &[(PickleValue, PickleValue)]) -> ObjectItems {
+        let mut picked = match self {
+            ObjectItems::All => (0..pairs.len()).collect(),
+            ObjectItems::Picked(picked) => picked,
+            ObjectItems::Pairs => return ObjectItems::Pairs,
+        };
+        picked.sort_unstable_by_key(|&i| key_text(&pairs[i].0));
+        ObjectItems::Picked(picked)
+    }
+}
+
+/// Decide how to write a dict whose keys all have a `key_text`.
+pub(crate) fn object_items(
+    pairs: &[(PickleValue, PickleValue)],
+) -> Result<ObjectItems, CodecError> {
+    object_items_for(pairs, duplicate_keys())
+}
+
+fn object_items_for(
+    pairs: &[(PickleValue, PickleValue)],
+    mode: DuplicateKeys,
+) -> Result<ObjectItems, CodecError> {
+    let Some(key) = first_duplicate(pairs) else {
+        return Ok(ObjectItems::All);
+    };
+    match mode {
+        DuplicateKeys::LastWins => {
+            warnings::warn(WarningCode::DuplicateKeys, || {
+                format!("duplicate dict key {key:?}, kept the last value")
+            });
+            Ok(ObjectItems::Picked(last_wins(pairs)))
+        }
+        DuplicateKeys::Error => {
+            Err(CodecError::InvalidData(format!("duplicate dict key {key:?}")))
+        }
+        DuplicateKeys::Preserve => Ok(ObjectItems::Pairs),
+    }
+}
+
+/// The first key that appears twice. Small dicts are compared pairwise.
+fn first_duplicate(pairs: &[(PickleValue, PickleValue)]) -> Option<&str> {
+    if pairs.len() <= 16 {
+        return (1..pairs.len()).find_map(|i| {
+            let key = key_text(&pairs[i].0);
+            pairs[..i].iter().any(|(k, _)| key_text(k) == key).then_some(key).flatten()
+        });
+    }
+    let mut seen = HashSet::with_capacity(pairs.len());
+    pairs.iter().map(|(k, _)| key_text(k)).find(|&key| !seen.insert(key)).flatten()
+}
+
+/// Index of the last item of each key, in the order of first positions.
+fn last_wins(pairs: &[(PickleValue, PickleValue)]) -> Vec<usize> {
+    let mut slots = HashMap::with_capacity(pairs.len());
+    let mut picked = Vec::with_capacity(pairs.len());
+    for (i, (k, _)) in pairs.iter().enumerate() {
+        match slots.entry(key_text(k)) {
+            Entry::Occupied(slot) => picked[*slot.get()] = i,
+            Entry::Vacant(slot) => {
+                slot.insert(picked.len());
+                picked.push(i);
+            }
+        }
+    }
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict(items: &[(&str, i64)]) -> Vec<(PickleValue, PickleValue)> {
+        items
+            .iter()
+            .map(|&(k, v)| (PickleValue::String(k.into()), PickleValue::Int(v)))
+            .collect()
+    }
+
+    fn written(pairs: &[(PickleValue, PickleValue)], mode: DuplicateKeys) -> Vec<(String, i64)> {
+        let items = object_items_for(pairs, mode).unwrap();
+        items
+            .iter(pairs)
+            .map(|(k, v)| match (k, v) {
+                (PickleValue::String(k), PickleValue::Int(v)) => (k.to_string(), *v),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unique_keys() {
+        let pairs = dict(&[("a", 1), ("b", 2)]);
+        assert!(matches!(object_items_for(&pairs, DuplicateKeys::Error), Ok(ObjectItems::All)));
+        assert_eq!(written(&pairs, DuplicateKeys::LastWins), [("a".into(), 1), ("b".into(), 2)]);
+    }
+
+    #[test]
+    fn test_last_wins_keeps_first_position() {
+        let pairs = dict(&[("a", 1), ("b", 2), ("a", 3), ("c", 4), ("b", 5)]);
+        assert_eq!(
+            written(&pairs, DuplicateKeys::LastWins),
+            [("a".into(), 3), ("b".into(), 5), ("c".into(), 4)]
+        );
+    }
+
+    #[test]
+    fn test_large_dicts() {
+        let keys: Vec<String> = (0..40).map(|i| format!("k{i}")).collect();
+        let mut items: Vec<(&str, i64)> = keys.iter().map(|k| (k.as_str(), 0)).collect();
+        assert_eq!(first_duplicate(&dict(&items)), None);
+        items.push(("k7", 1));
+        assert_eq!(first_duplicate(&dict(&items)), Some("k7"));
+    }
+
+    #[test]
+    fn test_sorted() {
+        let pairs = dict(&[("b", 1), ("a", 2), ("b", 3), ("C", 4)]);
+        let items = object_items_for(&pairs, DuplicateKeys::LastWins).unwrap().sorted(&pairs);
+        let keys: Vec<_> = items.iter(&pairs).map(|(k, _)| key_text(k).unwrap()).collect();
+        assert_eq!(keys, ["C", "a", "b"]);
+        assert_eq!(items.iter(&pairs).nth(2).unwrap().1, PickleValue::Int(3));
+    }
+
+    #[test]
+    fn test_error_and_preserve() {
+        let pairs = dict(&[("a", 1), ("a", 2)]);
+        let err = object_items_for(&pairs, DuplicateKeys::Error).err().unwrap();
+        assert!(err.to_string().contains(r#"duplicate dict key "a""#), "{err}");
+        assert!(matches!(
+            object_items_for(&pairs, DuplicateKeys::Preserve),
+            Ok(ObjectItems::Pairs)
+        ));
+    }
+}
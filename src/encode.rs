@@ -1,8 +1,46 @@
 use crate::error::CodecError;
+use crate::framing::{frame_pickle, FramePolicy, PROTOCOL4_FRAME_SIZE};
+use crate::limits::EncodeLimits;
+use crate::memo::{Memo, MemoAction, MemoKey};
 use crate::opcodes::*;
-use crate::types::{InstanceData, PickleValue};
+use crate::rename;
+use crate::shared::{self, SharedIds};
+use crate::types::{AnonymousBuild, InstanceData, PickleValue};
+use std::cell::Cell;
 
-const MAX_DEPTH: usize = 1000;
+pub(crate) const MAX_DEPTH: usize = 1000;
+
+thread_local! {
+    /// Nesting level of the recursive JSON/Python -> PickleValue converters.
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Bounds the recursion of converters that call each other across
+/// modules (`json_to_pickle_value`, `pyobject_to_pickle_value`, the BTree
+/// and known-type helpers), where threading a depth argument through
+/// every call is impractical. Each container level holds one guard; more
+/// than `MAX_DEPTH` of them fail instead of overflowing the stack, more
+/// than the configured `EncodeLimits::max_depth` with a limit error.
+pub(crate) struct NestingGuard;
+
+impl NestingGuard {
+    pub(crate) fn enter() -> Result<Self, CodecError> {
+        NESTING.with(|n| {
+            if n.get() >= MAX_DEPTH {
+                return Err(CodecError::InvalidData("maximum nesting depth exceeded".to_string()));
+            }
+            EncodeLimits::current().check_depth(n.get() + 1)?;
+            n.set(n.get() + 1);
+            Ok(NestingGuard)
+        })
+    }
+}
+
+impl Drop for NestingGuard {
+    fn drop(&mut self) {
+        NESTING.with(|n| n.set(n.get() - 1));
+    }
+}
 
 /// Compute minimal byte length for a signed little-endian integer encoding.
 /// Trims trailing sign-extension bytes (0x00 for positive, 0xFF for negative),
@@ -24,24 +62,73 @@ fn minimal_signed_le_len(bytes: &[u8]) -> usize {
 /// Encode a PickleValue AST into pickle bytes (protocol 3).
 /// We target protocol 3 because ZODB uses zodbpickle which only supports up to protocol 3.
 pub fn encode_pickle(val: &PickleValue) -> Result<Vec<u8>, CodecError> {
-    let mut encoder = Encoder::new();
+    encode_pickle_protocol(val, 3)
+}
+
+/// Encode a PickleValue AST into pickle bytes of the given protocol
+/// (2, 3 or 4).
+///
+/// - Protocol 2 has no bytes opcodes: bytes are written the way Python 3
+///   pickles them for protocol 2, as `_codecs.encode(text, "latin1")`
+///   (`__builtin__.bytes()` when empty), and sets use `__builtin__`.
+/// - Protocol 3 is the ZODB format, as from [`encode_pickle`].
+/// - Protocol 4 uses `SHORT_BINUNICODE`, `STACK_GLOBAL` and the set
+///   opcodes, and wraps the body in `FRAME`s of about 64 KiB, as CPython
+///   does.
+///
+/// Values that occur more than once (strings, bytes, globals and tuples of
+/// those, such as repeated persistent ids) are written once and then
+/// referred to through the memo, see `memo.rs`.
+///
+/// Protocol 0 has its own emitter, see `encode_pickle_protocol0`.
+///
+/// ```
+/// use zodb_json_codec::{decode_pickle, encode_pickle_protocol, PickleValue};
+///
+/// let val = PickleValue::Bytes(b"\x00\xff".to_vec());
+/// for protocol in [2, 3, 4] {
+///     let bytes = encode_pickle_protocol(&val, protocol)?;
+///     assert_eq!(bytes[1], protocol);
+///     assert_eq!(decode_pickle(&bytes)?, val);
+/// }
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn encode_pickle_protocol(val: &PickleValue, protocol: u8) -> Result<Vec<u8>, CodecError> {
+    if !(2..=4).contains(&protocol) {
+        return Err(CodecError::InvalidData(format!(
+            "unsupported pickle protocol {protocol}, expected 2, 3 or 4"
+        )));
+    }
+    let val = &*shared::in_definition_order(val);
+    let mut encoder = Encoder::new(protocol);
+    encoder.memo = Some(Memo::plan(val, protocol));
     encoder.write_u8(PROTO);
-    encoder.write_u8(3); // protocol 3 (required by zodbpickle)
+    encoder.write_u8(protocol);
     encoder.encode_value(val, 0)?;
     encoder.write_u8(STOP);
+    if protocol == 4 {
+        return frame_pickle(&encoder.buf, &FramePolicy::fixed(PROTOCOL4_FRAME_SIZE));
+    }
     Ok(encoder.buf)
 }
 
 /// Encode a PickleValue into an existing buffer (without PROTO/STOP framing).
 /// Used as a fallback when the direct PyObject→pickle encoder encounters
 /// complex types that need the PickleValue intermediate representation.
+///
+/// Within a `SharedIdsScope`, `@shared` ids written by one call can be
+/// referred to by the next.
 pub fn encode_value_into(val: &PickleValue, buf: &mut Vec<u8>) -> Result<(), CodecError> {
-    let mut encoder = Encoder {
-        buf: std::mem::take(buf),
-    };
-    encoder.encode_value(val, 0)?;
-    *buf = encoder.buf;
-    Ok(())
+    let val = &*shared::in_definition_order(val);
+    shared::with_scoped_ids(|ids| {
+        let mut encoder = Encoder::new(3);
+        encoder.buf = std::mem::take(buf);
+        encoder.shared = std::mem::take(ids);
+        let result = encoder.encode_value(val, 0);
+        *ids = encoder.shared;
+        *buf = encoder.buf;
+        result
+    })
 }
 
 // --- Public inline helpers for direct pickle writing ---
@@ -49,7 +136,13 @@ pub fn encode_value_into(val: &PickleValue, buf: &mut Vec<u8>) -> Result<(), Cod
 
 #[inline]
 pub fn write_string(buf: &mut Vec<u8>, s: &str) {
-    let bytes = s.as_bytes();
+    write_text(buf, s.as_bytes());
+}
+
+/// Write a BINUNICODE with a UTF-8 payload, or the `surrogatepass` bytes
+/// of a string with lone surrogates.
+#[inline]
+pub fn write_text(buf: &mut Vec<u8>, bytes: &[u8]) {
     let n = bytes.len();
     // Always use BINUNICODE (protocol 1+), not SHORT_BINUNICODE (protocol 4)
     // because ZODB uses zodbpickle which only supports up to protocol 3.
@@ -59,12 +152,13 @@ pub fn write_string(buf: &mut Vec<u8>, s: &str) {
     buf.extend_from_slice(bytes);
 }
 
+#[cfg(feature = "python")]
 #[inline]
 pub fn write_int(buf: &mut Vec<u8>, val: i64) {
-    if val >= 0 && val < 256 {
+    if (0..256).contains(&val) {
         buf.push(BININT1);
         buf.push(val as u8);
-    } else if val >= 0 && val < 65536 {
+    } else if (0..65536).contains(&val) {
         buf.push(BININT2);
         buf.extend_from_slice(&(val as u16).to_le_bytes());
     } else if val >= i32::MIN as i64 && val <= i32::MAX as i64 {
@@ -81,6 +175,7 @@ pub fn write_int(buf: &mut Vec<u8>, val: i64) {
     }
 }
 
+#[cfg(feature = "python")]
 #[inline]
 pub fn write_bytes_val(buf: &mut Vec<u8>, data: &[u8]) {
     let n = data.len();
@@ -96,8 +191,24 @@ pub fn write_bytes_val(buf: &mut Vec<u8>, data: &[u8]) {
     buf.extend_from_slice(data);
 }
 
+/// Write a GLOBAL, applying the encode class renames.
+#[cfg(any(test, feature = "python"))]
 #[inline]
 pub fn write_global(buf: &mut Vec<u8>, module: &str, name: &str) {
+    match rename::encode_rename(module, name) {
+        Some((module, name)) => write_global_line(buf, &module, &name),
+        None => write_global_line(buf, module, name),
+    }
+}
+
+fn write_global_line(buf: &mut Vec<u8>, module: &str, name: &str) {
+    if module.contains('\n') || name.contains('\n') {
+        // GLOBAL is newline-delimited; keep such names verbatim with STACK_GLOBAL
+        write_string(buf, module);
+        write_string(buf, name);
+        buf.push(STACK_GLOBAL);
+        return;
+    }
     buf.reserve(3 + module.len() + name.len()); // GLOBAL + mod + \n + name + \n
     buf.push(GLOBAL);
     buf.extend_from_slice(module.as_bytes());
@@ -106,14 +217,49 @@ pub fn write_global(buf: &mut Vec<u8>, module: &str, name: &str) {
     buf.push(b'\n');
 }
 
-struct Encoder {
+/// Module of the builtin types in `protocol`: Python 2's name for
+/// protocol 2, as CPython's `fix_imports` writes it.
+pub(crate) fn builtins_module(protocol: u8) -> &'static str {
+    if protocol < 3 {
+        "__builtin__"
+    } else {
+        "builtins"
+    }
+}
+
+/// Module of `copyreg` in `protocol`, named as [`builtins_module`] names
+/// the builtins.
+pub(crate) fn copyreg_module(protocol: u8) -> &'static str {
+    if protocol < 3 {
+        "copy_reg"
+    } else {
+        "copyreg"
+    }
+}
+
+struct Encoder<'a> {
     buf: Vec<u8>,
+    protocol: u8,
+    /// Values written once and then referred to, see `memo.rs`.
+    memo: Option<Memo<'a>>,
+    /// Memo indices of the `Shared` nodes written, see `shared.rs`.
+    shared: SharedIds,
+    /// Id of the `Shared` node whose value is being written, until its
+    /// memo entry is stored.
+    pending_shared: Option<u32>,
+    /// Output, depth and collection limits (see `with_encode_limits`).
+    limits: EncodeLimits,
 }
 
-impl Encoder {
-    fn new() -> Self {
+impl<'a> Encoder<'a> {
+    fn new(protocol: u8) -> Self {
         Self {
             buf: Vec::with_capacity(256),
+            protocol,
+            memo: None,
+            shared: SharedIds::default(),
+            pending_shared: None,
+            limits: EncodeLimits::current(),
         }
     }
 
@@ -127,10 +273,104 @@ impl Encoder {
         self.buf.extend_from_slice(data);
     }
 
-    fn encode_value(&mut self, val: &PickleValue, depth: usize) -> Result<(), CodecError> {
+    fn encode_value(&mut self, val: &'a PickleValue, depth: usize) -> Result<(), CodecError> {
         if depth > MAX_DEPTH {
             return Err(CodecError::InvalidData("maximum nesting depth exceeded".to_string()));
         }
+        self.limits.check_value(val, depth, self.buf.len())?;
+        match val {
+            PickleValue::Shared { id, value } => {
+                let outer = self.pending_shared.replace(*id);
+                self.encode_value(value, depth + 1)?;
+                // Values without a shell to store first, or read from the memo
+                let mut pending = self.pending_shared.take();
+                self.put_shared(&mut pending)?;
+                if let Some(outer) = outer {
+                    // A `Shared` node directly inside another: same object
+                    let index = self.shared.index_of(*id)?;
+                    self.shared.define(outer, index);
+                }
+                return Ok(());
+            }
+            PickleValue::BackRef(id) => {
+                let index = self.shared.index_of(*id)?;
+                self.write_get(index);
+                return Ok(());
+            }
+            _ => {}
+        }
+        let key = self.memo.as_ref().and_then(|_| MemoKey::of(val));
+        if self.memo_get(key) {
+            return Ok(());
+        }
+        self.encode_unmemoized(val, depth)?;
+        self.memo_put(key);
+        Ok(())
+    }
+
+    /// Store the container just created (before its contents are written)
+    /// as the value of the `Shared` node `shared`, if any.
+    fn put_shared(&mut self, shared: &mut Option<u32>) -> Result<(), CodecError> {
+        let Some(id) = shared.take() else {
+            return Ok(());
+        };
+        let index = match &mut self.memo {
+            Some(memo) => memo.reserve().ok_or_else(shared::memo_full)?,
+            None => self.shared.next_index()?,
+        };
+        self.shared.define(id, index);
+        self.write_put(index);
+        Ok(())
+    }
+
+    fn write_get(&mut self, index: u32) {
+        if index < 256 {
+            self.write_u8(BINGET);
+            self.write_u8(index as u8);
+        } else {
+            self.write_u8(LONG_BINGET);
+            self.write_bytes(&index.to_le_bytes());
+        }
+    }
+
+    fn write_put(&mut self, index: u32) {
+        if self.protocol >= 4 {
+            self.write_u8(MEMOIZE);
+        } else if index < 256 {
+            self.write_u8(BINPUT);
+            self.write_u8(index as u8);
+        } else {
+            self.write_u8(LONG_BINPUT);
+            self.write_bytes(&index.to_le_bytes());
+        }
+    }
+
+    /// Write a BINGET if the value with this key is in the memo already.
+    fn memo_get(&mut self, key: Option<MemoKey<'a>>) -> bool {
+        let (Some(memo), Some(key)) = (&self.memo, key) else {
+            return false;
+        };
+        let MemoAction::Get(index) = memo.action(key) else {
+            return false;
+        };
+        self.write_get(index);
+        true
+    }
+
+    /// Store the value just written in the memo if it is used again.
+    fn memo_put(&mut self, key: Option<MemoKey<'a>>) {
+        let (Some(memo), Some(key)) = (&mut self.memo, key) else {
+            return;
+        };
+        if memo.action(key) != MemoAction::WriteAndStore {
+            return;
+        }
+        let index = memo.store(key);
+        self.write_put(index);
+    }
+
+    fn encode_unmemoized(&mut self, val: &'a PickleValue, depth: usize) -> Result<(), CodecError> {
+        let mut shared = self.pending_shared.take();
         match val {
             PickleValue::None => {
                 self.write_u8(NONE);
@@ -161,30 +401,17 @@ impl Encoder {
                 self.write_bytes(&f.to_be_bytes());
             }
             PickleValue::String(s) => {
-                let bytes = s.as_bytes();
-                let n = bytes.len();
-                // Always use BINUNICODE (protocol 1+), not SHORT_BINUNICODE (protocol 4)
-                // because ZODB uses zodbpickle which only supports up to protocol 3.
-                self.buf.reserve(5 + n);
-                self.write_u8(BINUNICODE);
-                self.write_bytes(&(n as u32).to_le_bytes());
-                self.write_bytes(bytes);
+                self.encode_str(s);
+            }
+            PickleValue::SurrogateString(b) => {
+                self.encode_text(b);
             }
             PickleValue::Bytes(b) => {
-                let n = b.len();
-                if n < 256 {
-                    self.buf.reserve(2 + n);
-                    self.write_u8(SHORT_BINBYTES);
-                    self.write_u8(n as u8);
-                } else {
-                    self.buf.reserve(5 + n);
-                    self.write_u8(BINBYTES);
-                    self.write_bytes(&(n as u32).to_le_bytes());
-                }
-                self.write_bytes(b);
+                self.encode_bytes(b);
             }
             PickleValue::List(items) => {
                 self.write_u8(EMPTY_LIST);
+                self.put_shared(&mut shared)?;
                 if !items.is_empty() {
                     self.write_u8(MARK);
                     for item in items {
@@ -222,6 +449,7 @@ impl Encoder {
             }
             PickleValue::Dict(pairs) => {
                 self.write_u8(EMPTY_DICT);
+                self.put_shared(&mut shared)?;
                 if !pairs.is_empty() {
                     self.write_u8(MARK);
                     for (k, v) in pairs {
@@ -231,10 +459,27 @@ impl Encoder {
                     self.write_u8(SETITEMS);
                 }
             }
+            PickleValue::Set(items) if self.protocol >= 4 => {
+                self.write_u8(EMPTY_SET);
+                self.put_shared(&mut shared)?;
+                if !items.is_empty() {
+                    self.write_u8(MARK);
+                    for item in items {
+                        self.encode_value(item, depth + 1)?;
+                    }
+                    self.write_u8(ADDITEMS);
+                }
+            }
+            PickleValue::FrozenSet(items) if self.protocol >= 4 => {
+                self.write_u8(MARK);
+                for item in items {
+                    self.encode_value(item, depth + 1)?;
+                }
+                self.write_u8(FROZENSET);
+            }
             PickleValue::Set(items) => {
                 // Protocol 3: GLOBAL builtins.set, list of items, TUPLE1, REDUCE
-                self.write_u8(GLOBAL);
-                self.write_bytes(b"builtins\nset\n");
+                self.write_builtin("set");
                 self.write_u8(EMPTY_LIST);
                 if !items.is_empty() {
                     self.write_u8(MARK);
@@ -248,8 +493,7 @@ impl Encoder {
             }
             PickleValue::FrozenSet(items) => {
                 // Protocol 3: GLOBAL builtins.frozenset, list of items, TUPLE1, REDUCE
-                self.write_u8(GLOBAL);
-                self.write_bytes(b"builtins\nfrozenset\n");
+                self.write_builtin("frozenset");
                 self.write_u8(EMPTY_LIST);
                 if !items.is_empty() {
                     self.write_u8(MARK);
@@ -261,27 +505,35 @@ impl Encoder {
                 self.write_u8(TUPLE1);
                 self.write_u8(REDUCE);
             }
-            PickleValue::Global { module, name } => {
-                self.buf.reserve(3 + module.len() + name.len());
-                self.write_u8(GLOBAL);
-                self.write_bytes(module.as_bytes());
-                self.write_u8(b'\n');
-                self.write_bytes(name.as_bytes());
-                self.write_u8(b'\n');
+            PickleValue::Global { module, name } | PickleValue::Blocked { module, name } => {
+                self.write_global(module, name);
             }
             PickleValue::Instance(inst) => {
                 let InstanceData { module, name, state, dict_items, list_items } = inst.as_ref();
-                // Emit as: GLOBAL module\nname\n EMPTY_TUPLE NEWOBJ state BUILD
-                // This is the standard ZODB pattern.
-                self.buf.reserve(5 + module.len() + name.len()); // GLOBAL+mod+\n+name+\n+EMPTY_TUPLE+NEWOBJ
-                self.write_u8(GLOBAL);
-                self.write_bytes(module.as_bytes());
-                self.write_u8(b'\n');
-                self.write_bytes(name.as_bytes());
-                self.write_u8(b'\n');
-                self.write_u8(EMPTY_TUPLE);
-                self.write_u8(NEWOBJ);
-                self.encode_value(state, depth + 1)?;
+                match inst.anonymous_build() {
+                    // Regenerate the opcodes of an anonymous instance
+                    Some(AnonymousBuild::Reduce { callable, args, state }) => {
+                        self.encode_value(callable, depth + 1)?;
+                        self.encode_value(args, depth + 1)?;
+                        self.write_u8(REDUCE);
+                        self.put_shared(&mut shared)?;
+                        self.encode_value(state, depth + 1)?;
+                    }
+                    Some(AnonymousBuild::Object { obj, state }) => {
+                        self.encode_value(obj, depth + 1)?;
+                        self.put_shared(&mut shared)?;
+                        self.encode_value(state, depth + 1)?;
+                    }
+                    None => {
+                        // Emit as: GLOBAL module\nname\n EMPTY_TUPLE NEWOBJ state BUILD
+                        // This is the standard ZODB pattern.
+                        self.write_class(module, name);
+                        self.write_u8(EMPTY_TUPLE);
+                        self.write_u8(NEWOBJ);
+                        self.put_shared(&mut shared)?;
+                        self.encode_value(state, depth + 1)?;
+                    }
+                }
                 self.write_u8(BUILD);
                 // Emit post-BUILD dict items (dict subclasses)
                 if let Some(pairs) = dict_items {
@@ -314,10 +566,12 @@ impl Encoder {
                 args,
                 dict_items,
                 list_items,
+                newobj,
             } => {
                 self.encode_value(callable, depth + 1)?;
                 self.encode_value(args, depth + 1)?;
-                self.write_u8(REDUCE);
+                self.write_u8(if *newobj { NEWOBJ } else { REDUCE });
+                self.put_shared(&mut shared)?;
                 // Emit post-REDUCE dict items (dict subclasses)
                 if let Some(pairs) = dict_items {
                     if !pairs.is_empty() {
@@ -340,33 +594,128 @@ impl Encoder {
                     }
                 }
             }
+            PickleValue::NewObjEx { cls, args, kwargs } if self.protocol >= 4 => {
+                self.encode_value(cls, depth + 1)?;
+                self.encode_value(args, depth + 1)?;
+                self.encode_value(kwargs, depth + 1)?;
+                self.write_u8(NEWOBJ_EX);
+                self.put_shared(&mut shared)?;
+            }
+            PickleValue::NewObjEx { cls, args, kwargs } => {
+                // Protocols 2 and 3: copyreg.__newobj_ex__(cls, args, kwargs) REDUCE
+                self.write_class(copyreg_module(self.protocol), "__newobj_ex__");
+                self.encode_value(cls, depth + 1)?;
+                self.encode_value(args, depth + 1)?;
+                self.encode_value(kwargs, depth + 1)?;
+                self.write_u8(TUPLE3);
+                self.write_u8(REDUCE);
+                self.put_shared(&mut shared)?;
+            }
             PickleValue::RawPickle(data) => {
                 // Raw pickle bytes are already valid pickle — but we can't
                 // just splice them in since they include PROTO/STOP.
                 // For now, encode as bytes with a marker.
                 // In practice this should be rare.
-                self.write_u8(SHORT_BINBYTES);
-                let n = data.len();
-                if n < 256 {
-                    self.write_u8(n as u8);
-                    self.write_bytes(data);
-                } else {
-                    // Switch to BINBYTES
-                    *self.buf.last_mut().unwrap() = BINBYTES;
-                    self.write_bytes(&(n as u32).to_le_bytes());
-                    self.write_bytes(data);
-                }
+                self.encode_bytes(data);
+            }
+            PickleValue::Shared { .. } | PickleValue::BackRef(_) => {
+                self.encode_value(val, depth)?;
             }
         }
-        Ok(())
+        // Values stored once complete: scalars, tuples, sets before protocol 4
+        self.put_shared(&mut shared)
+    }
+
+    fn encode_str(&mut self, s: &str) {
+        self.encode_text(s.as_bytes());
+    }
+
+    /// Write a unicode opcode with a UTF-8 payload (or the `surrogatepass`
+    /// bytes of a `SurrogateString`).
+    fn encode_text(&mut self, bytes: &[u8]) {
+        let n = bytes.len();
+        if self.protocol >= 4 && n < 256 {
+            self.buf.reserve(2 + n);
+            self.write_u8(SHORT_BINUNICODE);
+            self.write_u8(n as u8);
+        } else {
+            // BINUNICODE (protocol 1+): zodbpickle only supports up to protocol 3
+            self.buf.reserve(5 + n);
+            self.write_u8(BINUNICODE);
+            self.write_bytes(&(n as u32).to_le_bytes());
+        }
+        self.write_bytes(bytes);
+    }
+
+    fn encode_bytes(&mut self, data: &[u8]) {
+        if self.protocol < 3 {
+            // No bytes opcodes before protocol 3; Python 3 pickles bytes
+            // as a call that also yields `str` on Python 2.
+            if data.is_empty() {
+                self.write_builtin("bytes");
+                self.write_u8(EMPTY_TUPLE);
+            } else {
+                self.write_class("_codecs", "encode");
+                let text: String = data.iter().map(|&b| b as char).collect();
+                self.encode_str(&text);
+                self.encode_str("latin1");
+                self.write_u8(TUPLE2);
+            }
+            self.write_u8(REDUCE);
+            return;
+        }
+        let n = data.len();
+        if n < 256 {
+            self.buf.reserve(2 + n);
+            self.write_u8(SHORT_BINBYTES);
+            self.write_u8(n as u8);
+        } else {
+            self.buf.reserve(5 + n);
+            self.write_u8(BINBYTES);
+            self.write_bytes(&(n as u32).to_le_bytes());
+        }
+        self.write_bytes(data);
+    }
+
+    /// Write a global through the memo: instance classes and the
+    /// builtins of sets and bytes repeat in most pickles.
+    fn write_class(&mut self, module: &'a str, name: &'a str) {
+        let key = self.memo.as_ref().map(|_| MemoKey::Class(module, name));
+        if !self.memo_get(key) {
+            self.write_global(module, name);
+            self.memo_put(key);
+        }
+    }
+
+    fn write_global(&mut self, module: &str, name: &str) {
+        if let Some((module, name)) = rename::encode_rename(module, name) {
+            self.write_global_as(&module, &name);
+        } else {
+            self.write_global_as(module, name);
+        }
+    }
+
+    fn write_global_as(&mut self, module: &str, name: &str) {
+        if self.protocol >= 4 {
+            self.encode_str(module);
+            self.encode_str(name);
+            self.write_u8(STACK_GLOBAL);
+        } else {
+            write_global_line(&mut self.buf, module, name);
+        }
+    }
+
+    /// Global of a builtin type, see [`builtins_module`].
+    fn write_builtin(&mut self, name: &'static str) {
+        self.write_class(builtins_module(self.protocol), name);
     }
 
     #[inline]
     fn encode_int(&mut self, val: i64) {
-        if val >= 0 && val < 256 {
+        if (0..256).contains(&val) {
             self.write_u8(BININT1);
             self.write_u8(val as u8);
-        } else if val >= 0 && val < 65536 {
+        } else if (0..65536).contains(&val) {
             self.write_u8(BININT2);
             self.write_bytes(&(val as u16).to_le_bytes());
         } else if val >= i32::MIN as i64 && val <= i32::MAX as i64 {
@@ -419,6 +768,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_roundtrip_float() {
         let val = PickleValue::Float(3.14);
         let bytes = encode_pickle(&val).unwrap();
@@ -428,7 +778,7 @@ mod tests {
 
     #[test]
     fn test_roundtrip_string() {
-        let val = PickleValue::String("hello world".to_string());
+        let val = PickleValue::String("hello world".into());
         let bytes = encode_pickle(&val).unwrap();
         let decoded = decode_pickle(&bytes).unwrap();
         assert_eq!(val, decoded);
@@ -446,7 +796,7 @@ mod tests {
     fn test_roundtrip_list() {
         let val = PickleValue::List(vec![
             PickleValue::Int(1),
-            PickleValue::String("two".to_string()),
+            PickleValue::String("two".into()),
             PickleValue::None,
         ]);
         let bytes = encode_pickle(&val).unwrap();
@@ -457,8 +807,8 @@ mod tests {
     #[test]
     fn test_roundtrip_dict() {
         let val = PickleValue::Dict(vec![
-            (PickleValue::String("a".to_string()), PickleValue::Int(1)),
-            (PickleValue::String("b".to_string()), PickleValue::Float(2.5)),
+            (PickleValue::String("a".into()), PickleValue::Int(1)),
+            (PickleValue::String("b".into()), PickleValue::Float(2.5)),
         ]);
         let bytes = encode_pickle(&val).unwrap();
         let decoded = decode_pickle(&bytes).unwrap();
@@ -468,7 +818,7 @@ mod tests {
     #[test]
     fn test_roundtrip_nested() {
         let val = PickleValue::Dict(vec![(
-            PickleValue::String("items".to_string()),
+            PickleValue::String("items".into()),
             PickleValue::List(vec![
                 PickleValue::Tuple(vec![PickleValue::Int(1), PickleValue::Int(2)]),
                 PickleValue::Dict(vec![]),
@@ -482,7 +832,7 @@ mod tests {
     #[test]
     fn test_roundtrip_tuple_sizes() {
         for n in 0..=5 {
-            let items: Vec<PickleValue> = (0..n).map(|i| PickleValue::Int(i)).collect();
+            let items: Vec<PickleValue> = (0..n).map(PickleValue::Int).collect();
             let val = PickleValue::Tuple(items);
             let bytes = encode_pickle(&val).unwrap();
             let decoded = decode_pickle(&bytes).unwrap();
@@ -490,6 +840,38 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_nesting_guard() {
+        let guards: Vec<NestingGuard> =
+            (0..MAX_DEPTH).map(|_| NestingGuard::enter().unwrap()).collect();
+        assert!(NestingGuard::enter().is_err());
+        drop(guards);
+        assert!(NestingGuard::enter().is_ok());
+    }
+
+    #[test]
+    fn test_encode_limits() {
+        let encode_with = |val: &PickleValue, limits| {
+            let mut encoder = Encoder::new(3);
+            encoder.limits = limits;
+            encoder.encode_value(val, 0).map(|()| encoder.buf)
+        };
+        let nested = PickleValue::List(vec![PickleValue::Tuple(vec![PickleValue::Int(1); 3])]);
+        assert!(encode_with(&nested, EncodeLimits::default()).is_ok());
+        for limits in [
+            EncodeLimits { max_depth: 1, ..EncodeLimits::default() },
+            EncodeLimits { max_collection_length: 2, ..EncodeLimits::default() },
+            EncodeLimits { max_output_bytes: 3, ..EncodeLimits::default() },
+        ] {
+            let err = encode_with(&nested, limits).unwrap_err();
+            assert!(matches!(err.root(), CodecError::LimitExceeded(_)), "{err}");
+        }
+        // The output limit is checked before each value: one long string passes
+        let long = PickleValue::String("x".repeat(100).into());
+        let limits = EncodeLimits { max_output_bytes: 3, ..EncodeLimits::default() };
+        assert!(encode_with(&long, limits).is_ok());
+    }
+
     #[test]
     fn test_encode_max_depth_exceeded() {
         // Build a deeply nested list that exceeds MAX_DEPTH (1000).
@@ -509,4 +891,247 @@ mod tests {
             .join();
         assert!(result.is_ok(), "test thread panicked");
     }
+
+    /// `getattr(myapp.Point, "restore")(3)` + BUILD, as pickled for a
+    /// `__reduce__` returning a classmethod constructor.
+    const CLASSMETHOD_REDUCE: &[u8] = b"\x80\x03cbuiltins\ngetattr\nq\x00cmyapp\nPoint\nq\x01X\x07\x00\x00\x00restoreq\x02\x86q\x03Rq\x04K\x03\x85q\x05Rq\x06}q\x07X\x05\x00\x00\x00labelq\x08X\x01\x00\x00\x00pq\tsb.";
+
+    #[test]
+    fn test_anonymous_reduce_instance_roundtrip() {
+        let val = decode_pickle(CLASSMETHOD_REDUCE).unwrap();
+        let PickleValue::Instance(inst) = &val else {
+            panic!("expected instance, got {val:?}");
+        };
+        assert!(matches!(inst.anonymous_build(), Some(AnonymousBuild::Reduce { .. })));
+        let bytes = encode_pickle(&val).unwrap();
+        assert_eq!(decode_pickle(&bytes).unwrap(), val);
+        // Same opcodes as the original, minus the memo
+        assert!(bytes.ends_with(b"K\x03\x85R}(X\x05\x00\x00\x00labelX\x01\x00\x00\x00pub."));
+    }
+
+    #[test]
+    fn test_anonymous_object_instance_roundtrip() {
+        // A second BUILD on an anonymous instance, and BUILD on a list
+        let mut data = CLASSMETHOD_REDUCE[..CLASSMETHOD_REDUCE.len() - 1].to_vec();
+        data.extend_from_slice(b"}X\x01\x00\x00\x00yK\x02sb.");
+        for data in [&data[..], b"\x80\x03]K\x01a}b."] {
+            let val = decode_pickle(data).unwrap();
+            let PickleValue::Instance(inst) = &val else {
+                panic!("expected instance, got {val:?}");
+            };
+            assert!(matches!(inst.anonymous_build(), Some(AnonymousBuild::Object { .. })));
+            assert_eq!(decode_pickle(&encode_pickle(&val).unwrap()).unwrap(), val);
+        }
+    }
+
+    #[test]
+    fn test_newobj_reduce_roundtrip() {
+        // A str subclass pickled without state: NEWOBJ and no BUILD
+        let val = decode_pickle(b"\x80\x03cmyapp\nTag\nX\x01\x00\x00\x00x\x85\x81.").unwrap();
+        assert!(matches!(val, PickleValue::Reduce { newobj: true, .. }));
+        for protocol in [2, 3, 4] {
+            let bytes = encode_pickle_protocol(&val, protocol).unwrap();
+            assert!(bytes.contains(&NEWOBJ), "protocol {protocol}");
+            assert_eq!(decode_pickle(&bytes).unwrap(), val);
+        }
+    }
+
+    #[test]
+    fn test_unimportable_globals_verbatim() {
+        for (module, name) in [("", "Orphan"), ("__main__", "Script"), ("my\nmod", "Cls")] {
+            let global = PickleValue::Global {
+                module: module.into(),
+                name: name.into(),
+            };
+            let inst = PickleValue::Instance(Box::new(InstanceData::new(
+                module,
+                name,
+                PickleValue::Dict(vec![]),
+            )));
+            for val in [global, inst] {
+                let bytes = encode_pickle(&val).unwrap();
+                assert_eq!(decode_pickle(&bytes).unwrap(), val, "{module:?}.{name}");
+            }
+        }
+        // A newline cannot be written in a GLOBAL line: STACK_GLOBAL instead
+        let mut buf = Vec::new();
+        write_global(&mut buf, "a\nb", "C");
+        assert_eq!(buf.last(), Some(&STACK_GLOBAL));
+        assert!(!buf.contains(&GLOBAL));
+    }
+
+    fn protocol_sample() -> PickleValue {
+        PickleValue::Dict(vec![
+            (PickleValue::String("s".into()), PickleValue::String("é".repeat(200).into())),
+            (PickleValue::String("b".into()), PickleValue::Bytes(vec![0, 0x80, 0xff])),
+            (PickleValue::String("e".into()), PickleValue::Bytes(vec![])),
+            (
+                PickleValue::String("set".into()),
+                PickleValue::Set(vec![PickleValue::Int(1), PickleValue::Int(2)]),
+            ),
+            (PickleValue::String("fset".into()), PickleValue::FrozenSet(vec![])),
+            (
+                PickleValue::String("obj".into()),
+                PickleValue::Instance(Box::new(InstanceData::new(
+                    "myapp",
+                    "Doc",
+                    PickleValue::Dict(vec![]),
+                ))),
+            ),
+            (
+                PickleValue::String("newobj_ex".into()),
+                PickleValue::NewObjEx {
+                    cls: Box::new(PickleValue::Global {
+                        module: "myapp".into(),
+                        name: "Point".into(),
+                    }),
+                    args: Box::new(PickleValue::Tuple(vec![PickleValue::Int(1)])),
+                    kwargs: Box::new(PickleValue::Dict(vec![(
+                        PickleValue::String("y".into()),
+                        PickleValue::Int(2),
+                    )])),
+                },
+            ),
+        ])
+    }
+
+    fn opcodes(data: &[u8]) -> Vec<u8> {
+        let limits = crate::limits::LineLimits::default();
+        let (mut pos, mut ops) = (0, Vec::new());
+        while pos < data.len() {
+            let (op, next) = crate::zodb::skip_opcode(data, pos, &limits).unwrap();
+            ops.push(op);
+            pos = next;
+        }
+        ops
+    }
+
+    #[test]
+    fn test_protocols_roundtrip() {
+        let val = protocol_sample();
+        for protocol in [2, 3, 4] {
+            let bytes = encode_pickle_protocol(&val, protocol).unwrap();
+            assert_eq!(&bytes[..2], &[PROTO, protocol]);
+            assert_eq!(decode_pickle(&bytes).unwrap(), val, "protocol {protocol}");
+        }
+        assert_eq!(encode_pickle_protocol(&val, 3).unwrap(), encode_pickle(&val).unwrap());
+        for protocol in [0, 1, 5] {
+            assert!(encode_pickle_protocol(&val, protocol).is_err());
+        }
+    }
+
+    #[test]
+    fn test_protocol2_has_no_bytes_opcodes() {
+        let bytes = encode_pickle_protocol(&protocol_sample(), 2).unwrap();
+        let ops = opcodes(&bytes);
+        assert!(!ops.contains(&SHORT_BINBYTES) && !ops.contains(&BINBYTES));
+        let text = String::from_utf8_lossy(&bytes);
+        assert!(text.contains("_codecs\nencode\n"));
+        assert!(text.contains("__builtin__\nbytes\n"));
+        assert!(text.contains("__builtin__\nset\n"));
+        assert!(text.contains("copy_reg\n__newobj_ex__\n"));
+        assert!(!ops.contains(&NEWOBJ_EX));
+    }
+
+    #[test]
+    fn test_protocol4_opcodes_and_frames() {
+        let val = PickleValue::List(
+            (0..2000).map(|i| PickleValue::String(format!("{i:>100}").into())).collect(),
+        );
+        let bytes = encode_pickle_protocol(&val, 4).unwrap();
+        assert_eq!(bytes[2], FRAME);
+        assert_eq!(bytes[11], EMPTY_LIST);
+        assert_eq!(bytes[13], SHORT_BINUNICODE);
+        let first = u64::from_le_bytes(bytes[3..11].try_into().unwrap()) as usize;
+        assert!((PROTOCOL4_FRAME_SIZE..PROTOCOL4_FRAME_SIZE + 200).contains(&first));
+        assert_eq!(decode_pickle(&bytes).unwrap(), val);
+
+        let ops = opcodes(&encode_pickle_protocol(&protocol_sample(), 4).unwrap());
+        assert!(ops.contains(&STACK_GLOBAL) && ops.contains(&EMPTY_SET));
+        assert!(ops.contains(&NEWOBJ_EX));
+        assert!(!ops.contains(&GLOBAL) && !ops.contains(&REDUCE));
+    }
+
+    #[test]
+    fn test_memo_shares_repeated_values() {
+        let row = |i: i64| {
+            PickleValue::Dict(vec![
+                (
+                    PickleValue::String("title".into()),
+                    PickleValue::String(format!("Doc {i}").into()),
+                ),
+                (
+                    PickleValue::String("parent".into()),
+                    PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+                        PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, 1]),
+                        PickleValue::Global {
+                            module: "myapp".into(),
+                            name: "Folder".into(),
+                        },
+                    ]))),
+                ),
+            ])
+        };
+        let val = PickleValue::List((0..500).map(row).collect());
+        let mut plain = vec![PROTO, 3];
+        encode_value_into(&val, &mut plain).unwrap();
+        plain.push(STOP);
+        for protocol in [2, 3, 4] {
+            let bytes = encode_pickle_protocol(&val, protocol).unwrap();
+            assert_eq!(decode_pickle(&bytes).unwrap(), val, "protocol {protocol}");
+            if protocol == 3 {
+                assert!(bytes.len() < plain.len() / 2, "{} vs {}", bytes.len(), plain.len());
+            }
+        }
+        let ops = opcodes(&encode_pickle_protocol(&val, 3).unwrap());
+        assert_eq!(ops.iter().filter(|&&op| op == BINPUT).count(), 3);
+        let ops = opcodes(&encode_pickle_protocol(&val, 4).unwrap());
+        assert_eq!(ops.iter().filter(|&&op| op == MEMOIZE).count(), 3);
+    }
+
+    #[test]
+    fn test_memo_unique_values_unchanged() {
+        let val = PickleValue::List(vec![
+            PickleValue::String("a".into()),
+            PickleValue::List(vec![]),
+            PickleValue::List(vec![]),
+        ]);
+        let mut plain = vec![PROTO, 3];
+        encode_value_into(&val, &mut plain).unwrap();
+        plain.push(STOP);
+        assert_eq!(encode_pickle(&val).unwrap(), plain);
+    }
+
+    #[test]
+    fn test_memo_long_indices() {
+        let keys: Vec<PickleValue> =
+            (0..300).map(|i| PickleValue::String(format!("key{i}").into())).collect();
+        let val = PickleValue::Tuple(vec![
+            PickleValue::List(keys.clone()),
+            PickleValue::List(keys),
+        ]);
+        let bytes = encode_pickle(&val).unwrap();
+        let ops = opcodes(&bytes);
+        assert!(ops.contains(&LONG_BINPUT) && ops.contains(&LONG_BINGET));
+        assert_eq!(decode_pickle(&bytes).unwrap(), val);
+    }
+
+    #[test]
+    fn test_shared_cycle_roundtrip() {
+        // d = {}; d["self"] = d
+        let val = PickleValue::Shared {
+            id: 0,
+            value: Box::new(PickleValue::Dict(vec![(
+                PickleValue::String("self".into()),
+                PickleValue::BackRef(0),
+            )])),
+        };
+        for protocol in [2, 3, 4] {
+            let bytes = encode_pickle_protocol(&val, protocol).unwrap();
+            assert_eq!(decode_pickle(&bytes).unwrap(), val, "protocol {protocol}");
+        }
+        let dangling = PickleValue::List(vec![PickleValue::BackRef(7)]);
+        let err = encode_pickle(&dangling).unwrap_err();
+        assert!(err.to_string().contains("@backref 7"), "{err}");
+    }
 }
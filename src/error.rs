@@ -1,8 +1,8 @@
-use pyo3::exceptions::PyValueError;
-use pyo3::PyErr;
 use std::fmt;
 
+/// Errors produced while decoding or encoding.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum CodecError {
     /// Unexpected end of pickle stream
     UnexpectedEof,
@@ -16,6 +16,175 @@ pub enum CodecError {
     Json(String),
     /// Invalid UTF-8 in pickle string
     InvalidUtf8,
+    /// A configured safety limit was exceeded
+    LimitExceeded(String),
+    /// Reading or writing a file or stream failed
+    Io(String),
+    /// Strict mode met a value that would fall back to `@reduce` or `@pkl`
+    Strict(String),
+    /// One of the errors above, with where in the input it happened.
+    /// Use [`CodecError::root`] to match on the underlying error.
+    Located {
+        error: Box<CodecError>,
+        context: Box<ErrorContext>,
+    },
+}
+
+/// Where in the input an error happened: the opcode being decoded, or
+/// the JSON path of the value being encoded.
+///
+/// ```
+/// use zodb_json_codec::{decode_pickle, CodecError};
+///
+/// // PROTO 3, then BINUNICODE announcing 5 bytes but holding 1
+/// let err = decode_pickle(b"\x80\x03X\x05\x00\x00\x00a").unwrap_err();
+/// assert!(matches!(err.root(), CodecError::UnexpectedEof));
+/// let ctx = err.context().unwrap();
+/// assert_eq!((ctx.offset, ctx.opcode), (Some(2), Some(b'X')));
+/// assert_eq!(
+///     err.to_string(),
+///     "unexpected end of pickle stream at byte 2 (BINUNICODE), near 80 03 [58] 05 00 00 00 61"
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct ErrorContext {
+    /// Byte offset of the opcode that failed
+    pub offset: Option<usize>,
+    /// The opcode at `offset`, unless the data ended before it
+    pub opcode: Option<u8>,
+    /// Up to [`ErrorContext::WINDOW`] bytes either side of `offset`
+    pub window: Vec<u8>,
+    /// Offset of the first byte of `window`
+    pub window_start: usize,
+    /// JSON path of the value being encoded, e.g. `$.items[2]["@t"][0]`
+    pub path: Option<String>,
+}
+
+impl ErrorContext {
+    /// Bytes kept on each side of the failing opcode.
+    pub const WINDOW: usize = 8;
+
+    /// The `pickletools` name of `opcode`.
+    pub fn opcode_name(&self) -> Option<&'static str> {
+        self.opcode.and_then(crate::info::opcode_name)
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {offset}")?;
+            match (self.opcode, self.opcode_name()) {
+                (Some(_), Some(name)) => write!(f, " ({name})")?,
+                (Some(op), None) => write!(f, " (opcode 0x{op:02x})")?,
+                (None, _) => {}
+            }
+            if !self.window.is_empty() {
+                f.write_str(", near")?;
+                for (i, b) in self.window.iter().enumerate() {
+                    if self.window_start + i == offset {
+                        write!(f, " [{b:02x}]")?;
+                    } else {
+                        write!(f, " {b:02x}")?;
+                    }
+                }
+            }
+        }
+        if let Some(path) = &self.path {
+            write!(f, " at {path}")?;
+        }
+        Ok(())
+    }
+}
+
+/// One step of an [`ErrorContext::path`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PathSegment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+impl fmt::Display for PathSegment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathSegment::Key(key)
+                if !key.is_empty()
+                    && !key.starts_with(|c: char| c.is_ascii_digit())
+                    && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                write!(f, ".{key}")
+            }
+            PathSegment::Key(key) => {
+                write!(f, "[{}]", serde_json::Value::String(key.to_string()))
+            }
+            PathSegment::Index(i) => write!(f, "[{i}]"),
+        }
+    }
+}
+
+/// `path` with `segment` put in front, as an error unwinds out of a
+/// container.
+pub(crate) fn prepend_path(path: Option<&str>, segment: PathSegment<'_>) -> String {
+    let rest = path.and_then(|p| p.strip_prefix('$')).unwrap_or("");
+    format!("${segment}{rest}")
+}
+
+impl CodecError {
+    /// The error without its location.
+    pub fn root(&self) -> &CodecError {
+        match self {
+            CodecError::Located { error, .. } => error.root(),
+            err => err,
+        }
+    }
+
+    /// Where the error happened, if known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            CodecError::Located { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    fn with_context(self, update: impl FnOnce(&mut ErrorContext)) -> CodecError {
+        match self {
+            CodecError::Located { error, mut context } => {
+                update(&mut context);
+                CodecError::Located { error, context }
+            }
+            err => {
+                let mut context = ErrorContext::default();
+                update(&mut context);
+                CodecError::Located {
+                    error: Box::new(err),
+                    context: Box::new(context),
+                }
+            }
+        }
+    }
+
+    /// Record that decoding the opcode at `offset` of `data` failed,
+    /// unless an inner decoder already did.
+    pub(crate) fn at_offset(self, data: &[u8], offset: usize) -> CodecError {
+        if self.context().is_some_and(|c| c.offset.is_some()) {
+            return self;
+        }
+        self.with_context(|c| {
+            let start = offset.saturating_sub(ErrorContext::WINDOW);
+            let end = (offset + ErrorContext::WINDOW + 1).min(data.len());
+            c.offset = Some(offset);
+            c.opcode = data.get(offset).copied();
+            c.window = data.get(start..end).unwrap_or_default().to_vec();
+            c.window_start = start;
+        })
+    }
+
+    /// Record that the error happened inside the dict item or list
+    /// element `segment`.
+    pub(crate) fn in_path(self, segment: PathSegment<'_>) -> CodecError {
+        self.with_context(|c| c.path = Some(prepend_path(c.path.as_deref(), segment)))
+    }
 }
 
 impl fmt::Display for CodecError {
@@ -27,15 +196,93 @@ impl fmt::Display for CodecError {
             CodecError::InvalidData(msg) => write!(f, "invalid pickle data: {msg}"),
             CodecError::Json(msg) => write!(f, "JSON error: {msg}"),
             CodecError::InvalidUtf8 => write!(f, "invalid UTF-8 in pickle string"),
+            CodecError::LimitExceeded(msg) => write!(f, "limit exceeded: {msg}"),
+            CodecError::Io(msg) => write!(f, "I/O error: {msg}"),
+            CodecError::Strict(msg) => write!(f, "strict mode: {msg}"),
+            CodecError::Located { error, context } => write!(f, "{error}{context}"),
         }
     }
 }
 
 impl std::error::Error for CodecError {}
 
-impl From<CodecError> for PyErr {
-    fn from(err: CodecError) -> PyErr {
-        PyValueError::new_err(err.to_string())
+#[cfg(feature = "python")]
+pub(crate) mod py {
+    use pyo3::prelude::*;
+    use pyo3::types::PyBytes;
+
+    use super::{prepend_path, ErrorContext, PathSegment};
+
+    pyo3::create_exception!(
+        zodb_json_codec,
+        CodecError,
+        pyo3::exceptions::PyValueError,
+        "Decoding or encoding failed. A ValueError with the location in \
+         `offset`, `opcode`, `opcode_name`, `context`, `context_offset` \
+         and `path` (None where unknown) and the bare message in `reason`."
+    );
+
+    impl From<super::CodecError> for PyErr {
+        fn from(err: super::CodecError) -> PyErr {
+            let reason = err.root().to_string();
+            let context = err.context().cloned().unwrap_or_default();
+            Python::attach(|py| new_err(py, &reason, &context))
+        }
+    }
+
+    fn new_err(py: Python<'_>, reason: &str, ctx: &ErrorContext) -> PyErr {
+        let err = CodecError::new_err(format!("{reason}{ctx}"));
+        let value = err.value(py);
+        let attrs: [(&str, Py<PyAny>); 7] = [
+            ("reason", reason.into_pyobject(py).unwrap().into_any().unbind()),
+            ("offset", ctx.offset.into_pyobject(py).unwrap().into_any().unbind()),
+            ("opcode", ctx.opcode.into_pyobject(py).unwrap().into_any().unbind()),
+            ("opcode_name", ctx.opcode_name().into_pyobject(py).unwrap().into_any().unbind()),
+            (
+                "context",
+                match ctx.offset {
+                    Some(_) => PyBytes::new(py, &ctx.window).into_any().unbind(),
+                    None => py.None(),
+                },
+            ),
+            (
+                "context_offset",
+                ctx.offset.map(|_| ctx.window_start).into_pyobject(py).unwrap().into_any().unbind(),
+            ),
+            ("path", ctx.path.as_deref().into_pyobject(py).unwrap().into_any().unbind()),
+        ];
+        for (name, attr) in attrs {
+            // A fresh exception instance accepts any attribute
+            let _ = value.setattr(name, attr);
+        }
+        err
+    }
+
+    /// Record in a `CodecError` raised while encoding the dict item or
+    /// list element `segment` where it happened; other errors pass as is.
+    pub(crate) fn in_path(py: Python<'_>, err: PyErr, segment: PathSegment<'_>) -> PyErr {
+        if !err.is_instance_of::<CodecError>(py) {
+            return err;
+        }
+        let value = err.value(py);
+        let attr = |name: &str| value.getattr(name).ok();
+        let (Some(reason), Some(offset), Some(window), Some(window_start), Some(path)) = (
+            attr("reason").and_then(|r| r.extract::<String>().ok()),
+            attr("offset").and_then(|o| o.extract::<Option<usize>>().ok()),
+            attr("context").and_then(|w| w.extract::<Option<Vec<u8>>>().ok()),
+            attr("context_offset").and_then(|s| s.extract::<Option<usize>>().ok()),
+            attr("path").and_then(|p| p.extract::<Option<String>>().ok()),
+        ) else {
+            return err;
+        };
+        let context = ErrorContext {
+            offset,
+            opcode: attr("opcode").and_then(|o| o.extract().ok()),
+            window: window.unwrap_or_default(),
+            window_start: window_start.unwrap_or_default(),
+            path: Some(prepend_path(path.as_deref(), segment)),
+        };
+        new_err(py, &reason, &context)
     }
 }
 
@@ -44,3 +291,44 @@ impl From<serde_json::Error> for CodecError {
         CodecError::Json(err.to_string())
     }
 }
+
+impl From<std::io::Error> for CodecError {
+    fn from(err: std::io::Error) -> Self {
+        CodecError::Io(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_segments() {
+        let err = CodecError::InvalidData("bad".into())
+            .in_path(PathSegment::Index(0))
+            .in_path(PathSegment::Key("@t"))
+            .in_path(PathSegment::Index(3))
+            .in_path(PathSegment::Key("items"))
+            .in_path(PathSegment::Key("a b"));
+        assert!(matches!(err.root(), CodecError::InvalidData(_)));
+        assert_eq!(err.context().unwrap().path.as_deref(), Some(r#"$["a b"].items[3]["@t"][0]"#));
+        assert_eq!(err.to_string(), r#"invalid pickle data: bad at $["a b"].items[3]["@t"][0]"#);
+    }
+
+    #[test]
+    fn test_offset_kept_from_inner_decoder() {
+        let data = b"0123456789abcdefghij";
+        let err = CodecError::StackUnderflow.at_offset(data, 15).at_offset(data, 2);
+        let ctx = err.context().unwrap();
+        assert_eq!((ctx.offset, ctx.opcode), (Some(15), Some(b'f')));
+        assert_eq!((ctx.window_start, ctx.window.as_slice()), (7, &data[7..]));
+    }
+
+    #[test]
+    fn test_offset_at_end() {
+        let err = CodecError::UnexpectedEof.at_offset(b"\x80\x03", 2);
+        let ctx = err.context().unwrap();
+        assert_eq!(ctx.opcode, None);
+        assert_eq!(err.to_string(), "unexpected end of pickle stream at byte 2, near 80 03");
+    }
+}
@@ -0,0 +1,788 @@
+//! Decoded size estimates without decoding.
+//!
+//! `estimate_decoded_size` walks the opcode stream like `analyze_pickle`,
+//! but each stack entry carries the size its value would have in the
+//! compact JSON of `decode_zodb_record` instead of the value itself.
+//! Containers are rows of counters, so items added after a `PUT` are seen
+//! by later `GET`s, and the markers the JSON would hold are appended to a
+//! log that is tallied at `STOP`. No string is copied and no tree is built,
+//! so an ingestion pipeline can route very large records to another path
+//! before spending memory on them.
+//!
+//! The estimate follows the codec's JSON for strings, numbers, bytes,
+//! tuples, lists, dicts, sets, persistent references and instances. Typed
+//! markers (`@dt`, `@dec`, ...) count with a typical length, `@reduce`
+//! forms and BTree states with the length of their parts.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::analyze::{length_prefix, memo_index};
+use crate::error::CodecError;
+use crate::floats;
+use crate::limits::LineLimits;
+use crate::opcodes::*;
+use crate::zodb::skip_opcode;
+
+/// Approximate size of the decoded form of a pickle or ZODB record.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SizeEstimate {
+    /// Length of the compact JSON, in bytes.
+    pub json_bytes: usize,
+    /// Number of values in the decoded tree, dict keys included. A marker
+    /// value such as `@ref` or `@dt` counts as one.
+    pub nodes: usize,
+    /// Number of each marker (`@t`, `@b`, `@ref`, ...) in the JSON. A value
+    /// the memo repeats counts once, where it was pickled.
+    pub markers: BTreeMap<&'static str, usize>,
+}
+
+/// Typical JSON length of the typed markers a REDUCE can produce.
+const TYPED: &[(&str, &str, &str, usize)] = &[
+    // {"@dt":"2025-01-01T00:00:00"}
+    ("datetime", "datetime", "@dt", 29),
+    // {"@date":"2025-01-01"}
+    ("datetime", "date", "@date", 22),
+    // {"@time":"12:30:45"}
+    ("datetime", "time", "@time", 19),
+    // {"@td":[1,0,0]}
+    ("datetime", "timedelta", "@td", 15),
+    // {"@dec":"3.14"}
+    ("decimal", "Decimal", "@dec", 15),
+];
+
+/// JSON length and node count of a value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Size {
+    bytes: usize,
+    nodes: usize,
+}
+
+impl Size {
+    fn leaf(bytes: usize) -> Size {
+        Size { bytes, nodes: 1 }
+    }
+
+    fn add(&mut self, other: Size) {
+        self.bytes += other.bytes;
+        self.nodes += other.nodes;
+    }
+}
+
+/// What the walk needs to know about a value besides its size.
+#[derive(Clone, Copy)]
+enum Kind<'a> {
+    /// A text string.
+    Str(&'a str),
+    /// A Python 2 `str`: written as `@b`, but may name a class.
+    Py2Str(&'a str),
+    /// A class, or a `(module, name)` / `(class, args)` tuple.
+    Class(&'a str, &'a str),
+    /// A `(oid, class)` persistent id, with the length of `module.Name`.
+    ClassHint(usize),
+    Other,
+}
+
+impl<'a> Kind<'a> {
+    fn text(self) -> Option<&'a str> {
+        match self {
+            Kind::Str(s) | Kind::Py2Str(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Value<'a> {
+    /// An immutable value of known size.
+    Leaf(Size, Kind<'a>),
+    /// A mutable value, by index into `Walk::containers`.
+    Container(usize),
+}
+
+/// A stack entry.
+#[derive(Clone, Copy)]
+struct Slot<'a> {
+    value: Value<'a>,
+    /// Log position of the first marker of the value and its items.
+    start: usize,
+    /// Log position of the value's own marker.
+    own: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    List,
+    Dict { string_keys: bool },
+    Set,
+    /// `{"@cls": ..., "@s": state}`
+    Instance,
+    /// `{"@reduce": {"callable": ..., "args": ...}}`
+    Reduce,
+}
+
+struct Container {
+    shape: Shape,
+    /// Bytes of the container without its items, e.g. `{"@set":[]}`.
+    overhead: usize,
+    content: Size,
+    items: usize,
+}
+
+impl Container {
+    fn new(shape: Shape, overhead: usize) -> Self {
+        Container {
+            shape,
+            overhead,
+            content: Size::default(),
+            items: 0,
+        }
+    }
+
+    fn size(&self) -> Size {
+        let (overhead, per_item) = match self.shape {
+            Shape::Dict { string_keys: true } => (self.overhead, 1),
+            // {"@d":[[k,v],...]}
+            Shape::Dict { string_keys: false } => (9, 3),
+            _ => (self.overhead, 0),
+        };
+        Size {
+            bytes: overhead
+                + self.content.bytes
+                + self.items.saturating_sub(1)
+                + self.items * per_item,
+            nodes: 1 + self.content.nodes,
+        }
+    }
+}
+
+struct Walk<'a> {
+    stack: Vec<Slot<'a>>,
+    /// Stack length and log length at each MARK.
+    marks: Vec<(usize, usize)>,
+    containers: Vec<Container>,
+    /// Markers of the current pickle; `None` where a value was consumed.
+    log: Vec<Option<&'static str>>,
+}
+
+impl<'a> Walk<'a> {
+    fn pop(&mut self) -> Result<Slot<'a>, CodecError> {
+        if self.marks.last().map(|m| m.0) == Some(self.stack.len()) {
+            return Err(CodecError::StackUnderflow);
+        }
+        self.stack.pop().ok_or(CodecError::StackUnderflow)
+    }
+
+    fn top(&self) -> Result<Slot<'a>, CodecError> {
+        self.stack.last().copied().ok_or(CodecError::StackUnderflow)
+    }
+
+    /// Pop the items above the last MARK, with the log length at the MARK.
+    fn pop_mark(&mut self) -> Result<(Vec<Slot<'a>>, usize), CodecError> {
+        let (mark, start) = self.marks.pop().ok_or(CodecError::StackUnderflow)?;
+        Ok((self.stack.split_off(mark), start))
+    }
+
+    fn log(&mut self, marker: &'static str) -> usize {
+        self.log.push(Some(marker));
+        self.log.len() - 1
+    }
+
+    /// Push a value whose items' markers start at `start`.
+    fn push(&mut self, value: Value<'a>, start: usize, marker: Option<&'static str>) {
+        let own = marker.map(|m| self.log(m));
+        self.stack.push(Slot { value, start, own });
+    }
+
+    fn push_leaf(&mut self, bytes: usize, kind: Kind<'a>, marker: Option<&'static str>) {
+        let start = self.log.len();
+        self.push(Value::Leaf(Size::leaf(bytes), kind), start, marker);
+    }
+
+    fn push_container(&mut self, container: Container, start: usize, marker: Option<&'static str>) {
+        self.containers.push(container);
+        self.push(Value::Container(self.containers.len() - 1), start, marker);
+    }
+
+    /// Push a copy of a value that is already in the log (GET, DUP).
+    fn push_copy(&mut self, value: Value<'a>) {
+        let start = self.log.len();
+        self.push(value, start, None);
+    }
+
+    fn size(&self, slot: &Slot) -> Size {
+        match slot.value {
+            Value::Leaf(size, _) => size,
+            Value::Container(i) => self.containers[i].size(),
+        }
+    }
+
+    fn kind(slot: &Slot<'a>) -> Kind<'a> {
+        match slot.value {
+            Value::Leaf(_, kind) => kind,
+            Value::Container(_) => Kind::Other,
+        }
+    }
+
+    /// Drop the marker of a value that does not appear in the JSON itself.
+    fn unmark(&mut self, slot: &Slot) {
+        if let Some(i) = slot.own {
+            self.log[i] = None;
+        }
+    }
+
+    /// Drop the markers of a value and everything built after it.
+    fn discard(&mut self, slot: &Slot) {
+        self.log[slot.start..].iter_mut().for_each(|m| *m = None);
+    }
+
+    /// Add `items` to the container below them (APPEND(S), SETITEM(S),
+    /// ADDITEMS), or to the object below a BUILD.
+    fn add_items(&mut self, target: Slot<'a>, items: &[Slot<'a>], pairs: bool) {
+        let Value::Container(i) = target.value else {
+            return;
+        };
+        let mut content = Size::default();
+        for item in items {
+            content.add(self.size(item));
+        }
+        if pairs && matches!(self.containers[i].shape, Shape::Dict { string_keys: true }) {
+            let text_keys = items.iter().step_by(2).all(|k| matches!(Self::kind(k), Kind::Str(_)));
+            if !text_keys {
+                self.containers[i].shape = Shape::Dict { string_keys: false };
+                self.log("@d");
+            }
+        }
+        let container = &mut self.containers[i];
+        container.content.add(content);
+        container.items += if pairs { items.len() / 2 } else { items.len() };
+    }
+
+    /// A tuple of `items`, as `{"@t":[...]}`.
+    fn push_tuple(&mut self, items: &[Slot<'a>], start: usize) {
+        let mut size = Size::leaf(9 + items.len().saturating_sub(1));
+        for item in items {
+            size.add(self.size(item));
+        }
+        let first = items.first().map(Self::kind);
+        let second = items.get(1).map(Self::kind);
+        let kind = match (first, second, items.len()) {
+            (Some(Kind::Class(module, name)), _, _) => Kind::Class(module, name),
+            (_, Some(Kind::Class(module, name)), 2) => {
+                Kind::ClassHint(module.len() + name.len() + 1)
+            }
+            (Some(first), Some(second), 2) => match (first.text(), second.text()) {
+                (Some(module), Some(name)) => Kind::Class(module, name),
+                _ => Kind::Other,
+            },
+            _ => Kind::Other,
+        };
+        self.push(Value::Leaf(size, kind), start, Some("@t"));
+    }
+
+    /// The value of `callable(*args)` (REDUCE).
+    fn push_reduce(&mut self, callable: Slot<'a>, args: Slot<'a>) {
+        if let Kind::Class(module, name) = Self::kind(&callable) {
+            if let Some(&(_, _, marker, bytes)) =
+                TYPED.iter().find(|t| t.0 == module && t.1 == name)
+            {
+                self.discard(&callable);
+                self.push_leaf(bytes, Kind::Other, Some(marker));
+                return;
+            }
+            match (module, name) {
+                ("builtins" | "__builtin__", "set" | "frozenset") => {
+                    // {"@set":[...]} from the args tuple ([...],)
+                    self.unmark(&callable);
+                    self.unmark(&args);
+                    let marker = if name == "set" { "@set" } else { "@fset" };
+                    let size = self.size(&args);
+                    let bytes = size.bytes - 9 + marker.len() + 5;
+                    let value = Value::Leaf(Size { bytes, nodes: size.nodes - 1 }, Kind::Other);
+                    self.push(value, callable.start, Some(marker));
+                    return;
+                }
+                ("copy_reg" | "copyreg", "_reconstructor") => {
+                    if let Kind::Class(module, name) = Self::kind(&args) {
+                        self.discard(&callable);
+                        self.push_instance(module, name, callable.start);
+                        return;
+                    }
+                }
+                _ => {}
+            }
+        }
+        // {"@reduce":{"callable":...,"args":...}}
+        let mut container = Container::new(Shape::Reduce, 33);
+        container.content = self.size(&callable);
+        container.content.add(self.size(&args));
+        self.push_container(container, callable.start, Some("@reduce"));
+    }
+
+    /// An instance of `module.name` with no state yet.
+    fn push_instance(&mut self, module: &str, name: &str, start: usize) {
+        // {"@cls":["module","name"],"@s":null}
+        let mut container = Container::new(Shape::Instance, 22 + module.len() + name.len());
+        container.content = Size::leaf(4);
+        self.push_container(container, start, Some("@cls"));
+    }
+
+    /// Apply BUILD's `state` to the object on top of the stack.
+    fn build(&mut self, state: Slot<'a>) -> Result<(), CodecError> {
+        let obj = self.top()?;
+        let state_size = self.size(&state);
+        match (obj.value, Self::kind(&obj)) {
+            (Value::Container(i), _) => {
+                let container = &mut self.containers[i];
+                match container.shape {
+                    Shape::Instance => container.content = state_size,
+                    // ,"state":...
+                    _ => {
+                        container.overhead += 9;
+                        container.content.add(state_size);
+                    }
+                }
+            }
+            (Value::Leaf(..), Kind::Class(module, name)) => {
+                self.stack.pop();
+                self.unmark(&obj);
+                self.push_instance(module, name, obj.start);
+                if let Some(Slot { value: Value::Container(i), .. }) = self.stack.last() {
+                    self.containers[*i].content = state_size;
+                }
+            }
+            (Value::Leaf(mut size, kind), _) => {
+                size.add(state_size);
+                self.stack.pop();
+                self.stack.push(Slot { value: Value::Leaf(size, kind), ..obj });
+            }
+        }
+        Ok(())
+    }
+
+    /// The markers of the pickle just finished.
+    fn take_markers(&mut self) -> BTreeMap<&'static str, usize> {
+        let mut markers = BTreeMap::new();
+        for marker in self.log.drain(..).flatten() {
+            *markers.entry(marker).or_insert(0) += 1;
+        }
+        markers
+    }
+}
+
+/// Estimate the compact JSON size of a pickle or ZODB record without
+/// decoding it.
+///
+/// For a record (a class pickle followed by a state pickle), the estimate
+/// is for the `{"@cls": ..., "@s": ...}` document of `decode_zodb_record`.
+/// Values the memo repeats are counted at each repetition, as the JSON
+/// writes them out each time.
+///
+/// ```
+/// // {"title": "Hello", "tags": ("a", "b")}
+/// let data = b"\x80\x03}q\x00(X\x05\x00\x00\x00titleq\x01X\x05\x00\x00\x00Helloq\x02\
+///              X\x04\x00\x00\x00tagsq\x03X\x01\x00\x00\x00aX\x01\x00\x00\x00b\x86q\x04u.";
+/// let estimate = zodb_json_codec::estimate_decoded_size(data)?;
+/// // {"title":"Hello","tags":{"@t":["a","b"]}}
+/// assert_eq!(estimate.json_bytes, 41);
+/// assert_eq!(estimate.nodes, 7);
+/// assert_eq!(estimate.markers["@t"], 1);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn estimate_decoded_size(data: &[u8]) -> Result<SizeEstimate, CodecError> {
+    let limits = LineLimits::default();
+    let mut w = Walk {
+        stack: Vec::with_capacity(16),
+        marks: Vec::new(),
+        containers: Vec::new(),
+        log: Vec::new(),
+    };
+    let mut memo: HashMap<u32, Value> = HashMap::new();
+    // Size, kind and markers of each pickle
+    let mut pickles = Vec::with_capacity(2);
+    let mut pos = 0;
+    let mut op = STOP;
+    while pos < data.len() {
+        let next;
+        (op, next) = skip_opcode(data, pos, &limits)?;
+        if next > data.len() {
+            return Err(CodecError::UnexpectedEof);
+        }
+        // The argument bytes, after the opcode
+        let arg = &data[pos + 1..next];
+        match op {
+            STOP => {
+                let result = w.pop()?;
+                pickles.push((w.size(&result), Walk::kind(&result), w.take_markers()));
+                w.stack.clear();
+                w.marks.clear();
+            }
+            PROTO | FRAME | READONLY_BUFFER => {}
+            MARK => w.marks.push((w.stack.len(), w.log.len())),
+            POP => {
+                if w.marks.last().map(|m| m.0) == Some(w.stack.len()) {
+                    w.marks.pop();
+                } else {
+                    w.pop()?;
+                }
+            }
+            DUP => {
+                let top = w.top()?;
+                w.push_copy(top.value);
+            }
+
+            NONE | NEWTRUE => w.push_leaf(4, Kind::Other, None),
+            NEWFALSE => w.push_leaf(5, Kind::Other, None),
+            INT => match arg {
+                b"01\n" => w.push_leaf(4, Kind::Other, None),
+                b"00\n" => w.push_leaf(5, Kind::Other, None),
+                _ => w.push_leaf(arg.len() - 1, Kind::Other, None),
+            },
+            BININT => {
+                let n = i32::from_le_bytes(arg.try_into().unwrap());
+                w.push_leaf(digits(n.into()), Kind::Other, None);
+            }
+            BININT1 => w.push_leaf(digits(arg[0].into()), Kind::Other, None),
+            BININT2 => {
+                let n = u16::from_le_bytes(arg.try_into().unwrap());
+                w.push_leaf(digits(n.into()), Kind::Other, None);
+            }
+            LONG => w.push_leaf(arg.len().saturating_sub(2), Kind::Other, None),
+            LONG1 | LONG4 => {
+                let len = arg.len() - if op == LONG1 { 1 } else { 4 };
+                // About 2.41 decimal digits per byte, plus the sign
+                let digits = (len * 241).div_ceil(100) + 1;
+                if len > 8 {
+                    // {"@bi":"..."}
+                    w.push_leaf(9 + digits, Kind::Other, Some("@bi"));
+                } else {
+                    w.push_leaf(digits, Kind::Other, None);
+                }
+            }
+            FLOAT => w.push_leaf(arg.len() - 1, Kind::Other, None),
+            BINFLOAT => {
+                let f = f64::from_be_bytes(arg.try_into().unwrap());
+                match floats::float_marker(f) {
+                    // {"@f":"nan"}
+                    Some(marker) => w.push_leaf(9 + marker.len(), Kind::Other, Some("@f")),
+                    None if !f.is_finite() => w.push_leaf(4, Kind::Other, None),
+                    None => {
+                        let mut buf = ryu::Buffer::new();
+                        w.push_leaf(buf.format_finite(f).len(), Kind::Other, None);
+                    }
+                }
+            }
+
+            UNICODE => {
+                // Raw-unicode-escape line: its length is close enough
+                let line = &arg[..arg.len().saturating_sub(1)];
+                match std::str::from_utf8(line) {
+                    Ok(s) if !s.contains('\\') => {
+                        w.push_leaf(2 + escaped_len(s), Kind::Str(s), None);
+                    }
+                    _ => w.push_leaf(2 + line.len(), Kind::Str(""), None),
+                }
+            }
+            SHORT_BINUNICODE | BINUNICODE | BINUNICODE8 => {
+                let payload = &arg[length_prefix(op)..];
+                let s = std::str::from_utf8(payload).map_err(|_| CodecError::InvalidUtf8)?;
+                w.push_leaf(2 + escaped_len(s), Kind::Str(s), None);
+            }
+            SHORT_BINBYTES | BINBYTES | BINBYTES8 | BYTEARRAY8 | SHORT_BINSTRING | BINSTRING => {
+                let payload = &arg[length_prefix(op)..];
+                let kind = match std::str::from_utf8(payload) {
+                    Ok(s) if op == SHORT_BINSTRING || op == BINSTRING => Kind::Py2Str(s),
+                    _ => Kind::Other,
+                };
+                w.push_leaf(base64_len(payload.len()), kind, Some("@b"));
+            }
+            STRING => {
+                // repr()-quoted text line, without quotes and newline
+                let quoted = arg.get(..arg.len().saturating_sub(1)).unwrap_or_default();
+                let kind = match quoted {
+                    [b'\'', s @ .., b'\''] | [b'"', s @ .., b'"'] if !s.contains(&b'\\') => {
+                        std::str::from_utf8(s).map_or(Kind::Other, Kind::Py2Str)
+                    }
+                    _ => Kind::Other,
+                };
+                w.push_leaf(base64_len(arg.len().saturating_sub(3)), kind, Some("@b"));
+            }
+            NEXT_BUFFER => w.push_leaf(base64_len(0), Kind::Other, Some("@b")),
+
+            EMPTY_LIST => w.push_container(Container::new(Shape::List, 2), w.log.len(), None),
+            EMPTY_DICT => {
+                let dict = Container::new(Shape::Dict { string_keys: true }, 2);
+                w.push_container(dict, w.log.len(), None);
+            }
+            EMPTY_SET => {
+                // {"@set":[]}
+                let set = Container::new(Shape::Set, 11);
+                w.push_container(set, w.log.len(), Some("@set"));
+            }
+            LIST | DICT => {
+                let (items, start) = w.pop_mark()?;
+                let (shape, overhead) = match op {
+                    LIST => (Shape::List, 2),
+                    _ => (Shape::Dict { string_keys: true }, 2),
+                };
+                w.push_container(Container::new(shape, overhead), start, None);
+                let target = w.top()?;
+                w.add_items(target, &items, op == DICT);
+            }
+            FROZENSET => {
+                // {"@fset":[...]}
+                let (items, start) = w.pop_mark()?;
+                let mut size = Size::leaf(12 + items.len().saturating_sub(1));
+                for item in &items {
+                    size.add(w.size(item));
+                }
+                w.push(Value::Leaf(size, Kind::Other), start, Some("@fset"));
+            }
+            EMPTY_TUPLE => w.push_tuple(&[], w.log.len()),
+            TUPLE => {
+                let (items, start) = w.pop_mark()?;
+                w.push_tuple(&items, start);
+            }
+            TUPLE1 | TUPLE2 | TUPLE3 => {
+                let n = (op - TUPLE1 + 1) as usize;
+                let mut items = Vec::with_capacity(n);
+                for _ in 0..n {
+                    items.push(w.pop()?);
+                }
+                items.reverse();
+                w.push_tuple(&items, items[0].start);
+            }
+
+            APPEND | SETITEM => {
+                let n = if op == APPEND { 1 } else { 2 };
+                let mut items = Vec::with_capacity(n);
+                for _ in 0..n {
+                    items.push(w.pop()?);
+                }
+                items.reverse();
+                let target = w.top()?;
+                w.add_items(target, &items, op == SETITEM);
+            }
+            APPENDS | SETITEMS | ADDITEMS => {
+                let (items, _) = w.pop_mark()?;
+                let target = w.top()?;
+                w.add_items(target, &items, op == SETITEMS);
+            }
+            BUILD => {
+                let state = w.pop()?;
+                w.build(state)?;
+            }
+            REDUCE => {
+                let args = w.pop()?;
+                let callable = w.pop()?;
+                w.push_reduce(callable, args);
+            }
+            NEWOBJ | NEWOBJ_EX => {
+                if op == NEWOBJ_EX {
+                    w.pop()?;
+                }
+                let args = w.pop()?;
+                let cls = w.pop()?;
+                match Walk::kind(&cls) {
+                    Kind::Class(module, name) => {
+                        w.discard(&cls);
+                        w.push_instance(module, name, cls.start);
+                    }
+                    _ => w.push_reduce(cls, args),
+                }
+            }
+            GLOBAL | INST => {
+                let text = std::str::from_utf8(arg).map_err(|_| CodecError::InvalidUtf8)?;
+                let mut lines = text.split('\n');
+                let module = lines.next().unwrap_or_default();
+                let name = lines.next().unwrap_or_default();
+                if op == INST {
+                    let (_, start) = w.pop_mark()?;
+                    w.log[start..].iter_mut().for_each(|m| *m = None);
+                    w.push_instance(module, name, start);
+                } else {
+                    // {"@cls":["module","name"]}
+                    let bytes = 16 + module.len() + name.len();
+                    w.push_leaf(bytes, Kind::Class(module, name), Some("@cls"));
+                }
+            }
+            OBJ => {
+                let (items, start) = w.pop_mark()?;
+                match items.first().map(Walk::kind) {
+                    Some(Kind::Class(module, name)) => {
+                        w.log[start..].iter_mut().for_each(|m| *m = None);
+                        w.push_instance(module, name, start);
+                    }
+                    _ => w.push_leaf(4, Kind::Other, None),
+                }
+            }
+            STACK_GLOBAL => {
+                let name = w.pop()?;
+                let module = w.pop()?;
+                match (Walk::kind(&module).text(), Walk::kind(&name).text()) {
+                    (Some(module), Some(name)) => {
+                        let bytes = 16 + module.len() + name.len();
+                        w.push_leaf(bytes, Kind::Class(module, name), Some("@cls"));
+                    }
+                    _ => w.push_leaf(16, Kind::Other, Some("@cls")),
+                }
+            }
+
+            BINPERSID => {
+                // {"@ref":"0000000000000001"} or {"@ref":["...","module.Name"]}
+                let pid = w.pop()?;
+                w.discard(&pid);
+                let bytes = match Walk::kind(&pid) {
+                    Kind::ClassHint(len) => 31 + len,
+                    _ => 26,
+                };
+                w.push(Value::Leaf(Size::leaf(bytes), Kind::Other), pid.start, Some("@ref"));
+            }
+            PERSID => w.push_leaf(26, Kind::Other, Some("@ref")),
+
+            PUT | BINPUT | LONG_BINPUT | MEMOIZE => {
+                let idx = match op {
+                    MEMOIZE => memo.len() as u32,
+                    _ => memo_index(op, arg)?,
+                };
+                memo.insert(idx, w.top()?.value);
+            }
+            GET | BINGET | LONG_BINGET => {
+                let idx = memo_index(op, arg)?;
+                let value = *memo.get(&idx).ok_or_else(|| {
+                    CodecError::InvalidData(format!("memo index {idx} not found"))
+                })?;
+                w.push_copy(value);
+            }
+
+            _ => return Err(CodecError::UnknownOpcode(op)),
+        }
+        pos = next;
+    }
+    if op != STOP {
+        return Err(CodecError::UnexpectedEof);
+    }
+    Ok(match pickles.as_mut_slice() {
+        [(_, Kind::Class(module, name), _), (state, _, markers)] => {
+            // {"@cls":["module","name"],"@s":...}
+            *markers.entry("@cls").or_insert(0) += 1;
+            SizeEstimate {
+                json_bytes: 22 + module.len() + name.len() + state.bytes,
+                nodes: 1 + state.nodes,
+                markers: std::mem::take(markers),
+            }
+        }
+        _ => {
+            let mut estimate = SizeEstimate::default();
+            for (size, _, markers) in pickles {
+                estimate.json_bytes += size.bytes;
+                estimate.nodes += size.nodes;
+                for (marker, n) in markers {
+                    *estimate.markers.entry(marker).or_insert(0) += n;
+                }
+            }
+            estimate
+        }
+    })
+}
+
+/// Number of characters of `n` in decimal.
+fn digits(n: i64) -> usize {
+    let sign = usize::from(n < 0);
+    sign + n.unsigned_abs().checked_ilog10().map_or(1, |d| d as usize + 1)
+}
+
+/// Length of `s` escaped as the JSON writer does, without quotes.
+fn escaped_len(s: &str) -> usize {
+    s.len()
+        + s.bytes()
+            .map(|b| match b {
+                b'"' | b'\\' | b'\n' | b'\r' | b'\t' => 1,
+                0..=0x1f => 5,
+                _ => 0,
+            })
+            .sum::<usize>()
+}
+
+/// Length of `{"@b":"..."}` for `n` bytes.
+fn base64_len(n: usize) -> usize {
+    9 + n.div_ceil(3) * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_pickle, pickle_value_to_json_string};
+
+    /// The estimate and the exact size of the compact JSON.
+    fn compare(data: &[u8]) -> (SizeEstimate, usize) {
+        let json = pickle_value_to_json_string(&decode_pickle(data).unwrap(), None).unwrap();
+        (estimate_decoded_size(data).unwrap(), json.len())
+    }
+
+    #[test]
+    fn test_exact_for_plain_values() {
+        // {"a": [1, -300, 70000, 1.5, None, True, False], "b": b"xyz", "c": 'q"\n'}
+        let data = b"\x80\x03}q\x00(X\x01\x00\x00\x00aq\x01]q\x02(K\x01J\xd4\xfe\xff\xffJp\x11\x01\x00G?\xf8\x00\x00\x00\x00\x00\x00N\x88\x89eX\x01\x00\x00\x00bq\x03C\x03xyzq\x04X\x01\x00\x00\x00cq\x05X\x03\x00\x00\x00q\"\nq\x06u.";
+        let (estimate, exact) = compare(data);
+        assert_eq!(estimate.json_bytes, exact);
+        // dict, 3 keys, list and 7 items, bytes, string
+        assert_eq!(estimate.nodes, 14);
+        assert_eq!(estimate.markers, BTreeMap::from([("@b", 1)]));
+    }
+
+    #[test]
+    fn test_memo_repeats_count_each_time() {
+        // l = [1, 2]; [l, l, l]
+        let data = b"\x80\x03]q\x00(]q\x01(K\x01K\x02eh\x01h\x01e.";
+        let (estimate, exact) = compare(data);
+        assert_eq!(estimate.json_bytes, exact);
+        assert_eq!(estimate.nodes, 10);
+    }
+
+    #[test]
+    fn test_non_string_keys() {
+        // {1: "a", 2: "b"}
+        let data = b"\x80\x03}q\x00(K\x01X\x01\x00\x00\x00aK\x02X\x01\x00\x00\x00bu.";
+        let (estimate, exact) = compare(data);
+        assert_eq!(estimate.json_bytes, exact);
+        assert_eq!(estimate.markers["@d"], 1);
+    }
+
+    #[test]
+    fn test_record() {
+        // (("myapp", "Doc"), None) and {"ref": (b"\0"*7+b"\x03", myapp.Folder),
+        // "when": datetime.datetime(...)}
+        let mut data = b"\x80\x03X\x05\x00\x00\x00myappX\x03\x00\x00\x00Doc\x86N\x86.".to_vec();
+        data.extend_from_slice(
+            b"\x80\x03}(X\x03\x00\x00\x00refC\x08\x00\x00\x00\x00\x00\x00\x00\x03cmyapp\nFolder\n\x86Q\
+              X\x04\x00\x00\x00whencdatetime\ndatetime\nC\n\x07\xe9\x01\x01\x00\x00\x00\x00\x00\x00\x85Ru.",
+        );
+        let estimate = estimate_decoded_size(&data).unwrap();
+        assert_eq!(
+            estimate.markers,
+            BTreeMap::from([("@cls", 1), ("@dt", 1), ("@ref", 1)])
+        );
+        let decoded = crate::zodb::decode_zodb_record(&data).unwrap();
+        let exact = serde_json::to_string(&decoded).unwrap().len();
+        assert!(estimate.json_bytes.abs_diff(exact) <= 2, "{} vs {exact}", estimate.json_bytes);
+        // record, state dict, 2 keys, ref, datetime
+        assert_eq!(estimate.nodes, 6);
+    }
+
+    #[test]
+    fn test_instances_and_sets() {
+        // copyreg._reconstructor instance with state, and a protocol 3 set
+        let data = b"\x80\x02]q\x00(ccopy_reg\n_reconstructor\nq\x01cmyapp\nP\nq\x02c__builtin__\nobject\nq\x03N\x87Rq\x04}q\x05X\x01\x00\x00\x00xK\x01sbc__builtin__\nset\n]q\x06(K\x01K\x02e\x85Re.";
+        let (estimate, exact) = compare(data);
+        assert_eq!(estimate.json_bytes, exact);
+        assert_eq!(estimate.markers, BTreeMap::from([("@cls", 1), ("@set", 1)]));
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(matches!(estimate_decoded_size(b"\x80\x02K\x01"), Err(CodecError::UnexpectedEof)));
+        assert!(matches!(estimate_decoded_size(b"\x80\x02e."), Err(CodecError::StackUnderflow)));
+        assert!(estimate_decoded_size(b"").unwrap().markers.is_empty());
+    }
+}
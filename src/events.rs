@@ -0,0 +1,300 @@
+//! Event stream over decoded pickle contents.
+//!
+//! [`PickleEvents`] walks a decoded value depth-first and yields a flat
+//! sequence of [`PickleEvent`]s, SAX style: containers open and close
+//! around their items, dict items are a `Key` followed by the value's
+//! events. The walk owns the value and takes it apart as it goes, so
+//! memory of finished subtrees is released early and a consumer never
+//! needs the whole document at once in its own representation.
+//!
+//! Scalars, persistent references and globals are single events.
+//! Values without a structural form of their own (REDUCEs, including the
+//! typed markers like `@dt`, shared containers, raw pickles) come whole as
+//! [`PickleEvent::Value`].
+
+use std::vec;
+
+use num_bigint::BigInt;
+
+use crate::error::CodecError;
+use crate::types::PickleValue;
+
+/// One step of the walk over a decoded pickle.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum PickleEvent {
+    /// Class of a ZODB record, before the events of its state.
+    Class { module: String, name: String },
+    StartDict,
+    /// Key of the next dict item; the value's events follow.
+    Key(PickleValue),
+    EndDict,
+    StartList,
+    EndList,
+    StartTuple,
+    EndTuple,
+    StartSet,
+    EndSet,
+    StartFrozenSet,
+    EndFrozenSet,
+    /// An inline instance; its state's events follow.
+    StartInstance { module: String, name: String },
+    EndInstance,
+    None,
+    Bool(bool),
+    Int(i64),
+    BigInt(BigInt),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    /// A persistent reference, with its persistent id.
+    PersistentRef(PickleValue),
+    Global { module: String, name: String },
+    /// Any other value, whole.
+    Value(PickleValue),
+}
+
+enum Work {
+    Value(PickleValue),
+    Event(PickleEvent),
+    Items(vec::IntoIter<PickleValue>, PickleEvent),
+    Pairs(vec::IntoIter<(PickleValue, PickleValue)>),
+}
+
+/// Iterator of the [`PickleEvent`]s of a decoded value.
+pub struct PickleEvents {
+    stack: Vec<Work>,
+    record: bool,
+}
+
+impl PickleEvents {
+    /// Events of `val`.
+    pub fn new(val: PickleValue) -> Self {
+        PickleEvents {
+            stack: vec![Work::Value(val)],
+            record: false,
+        }
+    }
+
+    /// Events of a ZODB record: [`PickleEvent::Class`], then the state.
+    pub fn for_record(module: String, name: String, state: PickleValue) -> Self {
+        PickleEvents {
+            stack: vec![
+                Work::Value(state),
+                Work::Event(PickleEvent::Class { module, name }),
+            ],
+            record: true,
+        }
+    }
+
+    /// Whether these are the events of a ZODB record.
+    pub fn is_record(&self) -> bool {
+        self.record
+    }
+
+    /// The event for `val`, scheduling its contents.
+    fn open(&mut self, val: PickleValue) -> PickleEvent {
+        let (items, start, end) = match val {
+            PickleValue::Dict(pairs) => {
+                self.stack.push(Work::Pairs(pairs.into_iter()));
+                return PickleEvent::StartDict;
+            }
+            PickleValue::List(items) => (items, PickleEvent::StartList, PickleEvent::EndList),
+            PickleValue::Tuple(items) => (items, PickleEvent::StartTuple, PickleEvent::EndTuple),
+            PickleValue::Set(items) => (items, PickleEvent::StartSet, PickleEvent::EndSet),
+            PickleValue::FrozenSet(items) => {
+                (items, PickleEvent::StartFrozenSet, PickleEvent::EndFrozenSet)
+            }
+            PickleValue::Instance(inst) if inst.dict_items.is_none() && inst.list_items.is_none() => {
+                let inst = *inst;
+                self.stack.push(Work::Event(PickleEvent::EndInstance));
+                self.stack.push(Work::Value(*inst.state));
+                return PickleEvent::StartInstance {
+                    module: inst.module,
+                    name: inst.name,
+                };
+            }
+            PickleValue::None => return PickleEvent::None,
+            PickleValue::Bool(b) => return PickleEvent::Bool(b),
+            PickleValue::Int(i) => return PickleEvent::Int(i),
+            PickleValue::BigInt(i) => return PickleEvent::BigInt(i),
+            PickleValue::Float(f) => return PickleEvent::Float(f),
+            PickleValue::String(s) => return PickleEvent::Str(s.to_string()),
+            PickleValue::Bytes(b) => return PickleEvent::Bytes(b),
+            PickleValue::PersistentRef(pid) => return PickleEvent::PersistentRef(*pid),
+            PickleValue::Global { module, name } => return PickleEvent::Global { module, name },
+            other => return PickleEvent::Value(other),
+        };
+        self.stack.push(Work::Items(items.into_iter(), end));
+        start
+    }
+}
+
+impl Iterator for PickleEvents {
+    type Item = PickleEvent;
+
+    fn next(&mut self) -> Option<PickleEvent> {
+        match self.stack.pop()? {
+            Work::Value(val) => Some(self.open(val)),
+            Work::Event(event) => Some(event),
+            Work::Items(mut items, end) => match items.next() {
+                Some(val) => {
+                    self.stack.push(Work::Items(items, end));
+                    Some(self.open(val))
+                }
+                None => Some(end),
+            },
+            Work::Pairs(mut pairs) => match pairs.next() {
+                Some((key, val)) => {
+                    self.stack.push(Work::Pairs(pairs));
+                    self.stack.push(Work::Value(val));
+                    Some(PickleEvent::Key(key))
+                }
+                None => Some(PickleEvent::EndDict),
+            },
+        }
+    }
+}
+
+/// Decode `data`, a single pickle or a ZODB record, into its events.
+///
+/// ```
+/// use zodb_json_codec::{pickle_events, PickleEvent, PickleValue};
+///
+/// // pickle.dumps({"a": [1]}, protocol=3)
+/// let data = b"\x80\x03}q\x00X\x01\x00\x00\x00aq\x01]q\x02K\x01as.";
+/// let events: Vec<_> = pickle_events(data)?.collect();
+/// assert_eq!(
+///     events,
+///     vec![
+///         PickleEvent::StartDict,
+///         PickleEvent::Key(PickleValue::String("a".into())),
+///         PickleEvent::StartList,
+///         PickleEvent::Int(1),
+///         PickleEvent::EndList,
+///         PickleEvent::EndDict,
+///     ]
+/// );
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn pickle_events(data: &[u8]) -> Result<PickleEvents, CodecError> {
+    if crate::zodb::find_pickle_end(data)? == data.len() {
+        return Ok(PickleEvents::new(crate::decode::decode_pickle(data)?));
+    }
+    let (class_val, state) = crate::decode::decode_zodb_pickles(data)?;
+    let (module, name) = crate::zodb::extract_class_info(&class_val);
+    Ok(PickleEvents::for_record(module, name, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::encode_pickle;
+    use crate::types::InstanceData;
+
+    fn s(v: &str) -> PickleValue {
+        PickleValue::String(v.into())
+    }
+
+    #[test]
+    fn test_nested_events() {
+        let val = PickleValue::Dict(vec![
+            (
+                s("items"),
+                PickleValue::List(vec![
+                    PickleValue::Tuple(vec![PickleValue::Int(1), PickleValue::None]),
+                    PickleValue::FrozenSet(vec![]),
+                ]),
+            ),
+            (
+                s("ref"),
+                PickleValue::PersistentRef(Box::new(PickleValue::Bytes(vec![0; 8]))),
+            ),
+            (
+                s("obj"),
+                PickleValue::Instance(Box::new(InstanceData::new(
+                    "myapp",
+                    "Point",
+                    PickleValue::Dict(vec![(s("x"), PickleValue::Float(1.5))]),
+                ))),
+            ),
+        ]);
+        let events: Vec<_> = PickleEvents::new(val).collect();
+        assert_eq!(
+            events,
+            vec![
+                PickleEvent::StartDict,
+                PickleEvent::Key(s("items")),
+                PickleEvent::StartList,
+                PickleEvent::StartTuple,
+                PickleEvent::Int(1),
+                PickleEvent::None,
+                PickleEvent::EndTuple,
+                PickleEvent::StartFrozenSet,
+                PickleEvent::EndFrozenSet,
+                PickleEvent::EndList,
+                PickleEvent::Key(s("ref")),
+                PickleEvent::PersistentRef(PickleValue::Bytes(vec![0; 8])),
+                PickleEvent::Key(s("obj")),
+                PickleEvent::StartInstance {
+                    module: "myapp".into(),
+                    name: "Point".into(),
+                },
+                PickleEvent::StartDict,
+                PickleEvent::Key(s("x")),
+                PickleEvent::Float(1.5),
+                PickleEvent::EndDict,
+                PickleEvent::EndInstance,
+                PickleEvent::EndDict,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reduce_is_one_value() {
+        let reduce = PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global {
+                module: "decimal".into(),
+                name: "Decimal".into(),
+            }),
+            args: Box::new(PickleValue::Tuple(vec![s("1.5")])),
+            dict_items: None,
+            list_items: None,
+            newobj: false,
+        };
+        let events: Vec<_> = PickleEvents::new(PickleValue::List(vec![reduce.clone()])).collect();
+        assert_eq!(
+            events,
+            vec![
+                PickleEvent::StartList,
+                PickleEvent::Value(reduce),
+                PickleEvent::EndList
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_events() {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::Tuple(vec![s("myapp"), s("Doc")]),
+            PickleValue::None,
+        ]);
+        let state = PickleValue::Dict(vec![(s("title"), s("Hi"))]);
+        let data = [encode_pickle(&class).unwrap(), encode_pickle(&state).unwrap()].concat();
+        let events: Vec<_> = pickle_events(&data).unwrap().collect();
+        assert_eq!(
+            events,
+            vec![
+                PickleEvent::Class {
+                    module: "myapp".into(),
+                    name: "Doc".into()
+                },
+                PickleEvent::StartDict,
+                PickleEvent::Key(s("title")),
+                PickleEvent::Str("Hi".into()),
+                PickleEvent::EndDict,
+            ]
+        );
+        assert!(pickle_events(b"\x80\x03").is_err());
+    }
+}
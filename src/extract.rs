@@ -0,0 +1,209 @@
+//! Selective field extraction from ZODB records.
+//!
+//! Indexers often need two or three fields of a record, but converting the
+//! whole state to JSON or Python objects dominates their cost. Here the
+//! record is decoded to the `PickleValue` AST once and the requested paths
+//! are looked up in it; only the values found are converted.
+//!
+//! Paths address the `decode_zodb_record` form: `@cls`, `@s`, then keys
+//! and indices below, e.g. `@s/title` or `@s/@kv/0/1`. A leading `/` is
+//! optional and `~1`/`~0` escape `/` and `~` inside a key, as in JSON
+//! Pointer. Plain dicts with string keys and lists are walked on the AST;
+//! at any other value (a tuple, a marker, a BTree or `@pmap` state) the
+//! walk continues on that value's JSON form, so paths through markers
+//! such as `@s/point/@t/0` work as well.
+
+use serde_json::{json, Value};
+
+use crate::decode::decode_zodb_pickles;
+use crate::error::CodecError;
+use crate::types::PickleValue;
+use crate::zodb::{extract_class_info, typed_state_to_json, value_to_record_json};
+
+/// Look up `paths` in a ZODB record, in the same order. A path that does
+/// not exist gives `None`.
+///
+/// ```
+/// use serde_json::json;
+/// use zodb_json_codec::extract_paths;
+///
+/// // Class pickle ("mod", "Cls") followed by {"a": (1, 2)}
+/// let record = b"\x80\x03X\x03\x00\x00\x00modX\x03\x00\x00\x00Cls\x86N\x86.\
+///                \x80\x03}X\x01\x00\x00\x00aK\x01K\x02\x86s.";
+/// let found = extract_paths(record, &["@cls/1", "@s/a/@t/1", "@s/b"])?;
+/// assert_eq!(found, vec![Some(json!("Cls")), Some(json!(2)), None]);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn extract_paths<S: AsRef<str>>(
+    record: &[u8],
+    paths: &[S],
+) -> Result<Vec<Option<Value>>, CodecError> {
+    let (class_val, state) = decode_zodb_pickles(record)?;
+    let (module, name) = extract_class_info(&class_val);
+    let typed_state = typed_state_to_json(&module, &name, &state)?;
+
+    let mut found = Vec::with_capacity(paths.len());
+    for path in paths {
+        let segments = parse_path(path.as_ref());
+        let value = match segments.split_first() {
+            None => {
+                let state_json = match &typed_state {
+                    Some(typed) => typed.clone(),
+                    None => value_to_record_json(&state)?,
+                };
+                Some(json!({"@cls": [&module, &name], "@s": state_json}))
+            }
+            Some((first, rest)) if first == "@cls" => {
+                lookup_json(&json!([&module, &name]), rest).cloned()
+            }
+            Some((first, rest)) if first == "@s" => match &typed_state {
+                Some(typed) => lookup_json(typed, rest).cloned(),
+                None => lookup_value(&state, rest)?,
+            },
+            Some(_) => None,
+        };
+        found.push(value);
+    }
+    Ok(found)
+}
+
+fn parse_path(path: &str) -> Vec<String> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    if path.is_empty() {
+        return Vec::new();
+    }
+    path.split('/')
+        .map(|s| s.replace("~1", "/").replace("~0", "~"))
+        .collect()
+}
+
+/// Whether a dict converts to a JSON object with the same keys.
+fn is_plain_dict(pairs: &[(PickleValue, PickleValue)]) -> bool {
+    pairs
+        .iter()
+        .all(|(k, _)| matches!(k, PickleValue::String(s) if !s.starts_with('@')))
+}
+
+fn lookup_value(node: &PickleValue, segments: &[String]) -> Result<Option<Value>, CodecError> {
+    let Some((seg, rest)) = segments.split_first() else {
+        return value_to_record_json(node).map(Some);
+    };
+    let child = match node {
+        PickleValue::Dict(pairs) if is_plain_dict(pairs) => pairs
+            .iter()
+            .find(|(k, _)| matches!(k, PickleValue::String(s) if **s == **seg))
+            .map(|(_, v)| v),
+        PickleValue::List(items) => seg.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => {
+            let json = value_to_record_json(node)?;
+            return Ok(lookup_json(&json, segments).cloned());
+        }
+    };
+    match child {
+        Some(child) => lookup_value(child, rest),
+        None => Ok(None),
+    }
+}
+
+fn lookup_json<'a>(mut node: &'a Value, segments: &[String]) -> Option<&'a Value> {
+    for seg in segments {
+        node = match node {
+            Value::Object(map) => map.get(seg)?,
+            Value::Array(items) => items.get(seg.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::encode_pickle;
+    use crate::json::json_to_pickle_value;
+    use crate::zodb::decode_zodb_record;
+
+    fn record(module: &str, name: &str, state: &PickleValue) -> Vec<u8> {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::Tuple(vec![
+                PickleValue::String(module.into()),
+                PickleValue::String(name.into()),
+            ]),
+            PickleValue::None,
+        ]);
+        [
+            encode_pickle(&class).unwrap(),
+            encode_pickle(state).unwrap(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_paths_match_decoded_record() {
+        let state = json_to_pickle_value(&json!({
+            "title": "Hello",
+            "items": [{"name": "a"}, {"@t": [1, {"@ref": "0000000000000003"}]}],
+            "a/b": {"@d": [[1, "one"]]},
+            "when": {"@dt": "2025-01-01T00:00:00"},
+        }))
+        .unwrap();
+        let data = record("myapp", "Doc", &state);
+        let doc = decode_zodb_record(&data).unwrap();
+        let paths = [
+            "@s/title",
+            "/@s/items/0/name",
+            "@s/items/1/@t/1",
+            "@s/a~1b/@d/0/1",
+            "@s/when",
+            "@s",
+            "@cls",
+            "",
+        ];
+        let found = extract_paths(&data, &paths).unwrap();
+        for (path, value) in paths.iter().zip(found) {
+            let pointer = format!("/{}", path.trim_start_matches('/'));
+            let pointer = if path.is_empty() {
+                ""
+            } else {
+                pointer.as_str()
+            };
+            assert_eq!(value.as_ref(), doc.pointer(pointer), "{path}");
+        }
+        assert_eq!(
+            extract_paths(&data, &["@s/items/1/@t/1/@ref"]).unwrap(),
+            vec![Some(json!("0000000000000003"))]
+        );
+    }
+
+    #[test]
+    fn test_missing_paths() {
+        let state = json_to_pickle_value(&json!({"title": "Hello", "tags": ["a"]})).unwrap();
+        let data = record("myapp", "Doc", &state);
+        let found = extract_paths(
+            &data,
+            &["@s/nope", "@s/tags/1", "@s/tags/x", "@x", "@s/title/0"],
+        )
+        .unwrap();
+        assert!(found.iter().all(Option::is_none), "{found:?}");
+    }
+
+    #[test]
+    fn test_btree_state() {
+        let state = PickleValue::Tuple(vec![PickleValue::Tuple(vec![
+            PickleValue::Int(1),
+            PickleValue::String("one".into()),
+            PickleValue::Int(2),
+            PickleValue::String("two".into()),
+        ])]);
+        let data = record("BTrees.IOBTree", "IOBucket", &state);
+        assert_eq!(
+            extract_paths(&data, &["@s/@kv/1/1", "@s/@kv/0"]).unwrap(),
+            vec![Some(json!("two")), Some(json!([1, "one"]))]
+        );
+    }
+
+    #[test]
+    fn test_invalid_record() {
+        assert!(extract_paths(b"\x80\x03N.", &["@s"]).is_err());
+    }
+}
@@ -0,0 +1,628 @@
+//! FileStorage files and transaction records.
+//!
+//! A `Data.fs` file is a 4-byte magic (`FS21`, or `FS30` from ZODB 5 on
+//! Python 3) followed by transaction records. A transaction record is a
+//! 23-byte header (tid, length, status, and the lengths of the user name,
+//! description and extension), those three fields, the data records of
+//! the objects it stored, and the transaction length again:
+//!
+//! ```text
+//! tid:8 tlen:8 status:1 ulen:2 dlen:2 elen:2 user description extension
+//! data records...
+//! tlen:8
+//! ```
+//!
+//! `tlen` covers everything up to, not including, the trailing copy.
+//! Each data record is a 42-byte header followed by the ZODB record, or
+//! by an 8-byte back pointer to an earlier data record when the pickle
+//! length is 0 (undo, copies):
+//!
+//! ```text
+//! oid:8 tid:8 prev:8 tloc:8 vlen:2 plen:8 record[plen] | backpointer:8
+//! ```
+//!
+//! All integers are big-endian. Versions (`vlen` > 0) were removed in
+//! ZODB 3.9 and are rejected. File positions (`prev`, `tloc`, back
+//! pointers) are offsets from the start of the file.
+
+use crate::error::CodecError;
+
+/// Length of a transaction record header.
+const TRANS_HDR_LEN: usize = 8 + 8 + 1 + 2 + 2 + 2;
+/// Length of a data record header.
+const DATA_HDR_LEN: usize = 8 + 8 + 8 + 8 + 2 + 8;
+/// Magic bytes a FileStorage file may start with.
+const FILE_MAGICS: [&[u8; 4]; 2] = [b"FS21", b"FS30"];
+/// Length of the file header (the magic).
+const FILE_HDR_LEN: usize = 4;
+/// Status of a transaction whose commit was still in progress.
+const STATUS_CHECKPOINT: u8 = b'c';
+
+/// One transaction record of a FileStorage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Transaction<'a> {
+    pub tid: [u8; 8],
+    /// `b' '` for committed, `b'p'` once packed, `b'c'` while the commit
+    /// is in progress.
+    pub status: u8,
+    pub user: &'a [u8],
+    pub description: &'a [u8],
+    /// The pickled extension dict, empty if there is none.
+    pub extension: &'a [u8],
+    /// Bytes of the whole transaction record, trailing length included.
+    pub size: usize,
+    /// The data records, starting at offset `data_offset` of the record.
+    records: &'a [u8],
+    data_offset: usize,
+}
+
+/// One object revision stored by a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DataRecord<'a> {
+    pub oid: [u8; 8],
+    pub tid: [u8; 8],
+    /// File position of the object's previous revision, 0 if none.
+    pub prev: u64,
+    /// File position of the transaction record.
+    pub tloc: u64,
+    /// The ZODB record (class pickle + state pickle); empty when the
+    /// revision refers to earlier data through `backpointer`.
+    pub data: &'a [u8],
+    /// File position of the data record holding this revision's data, for
+    /// revisions written by undo or copied; `Some(0)` means the object's
+    /// creation was undone.
+    pub backpointer: Option<u64>,
+}
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes(data[at..at + 2].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn bad_record(offset: usize, msg: &str) -> CodecError {
+    CodecError::InvalidData(format!("FileStorage record at offset {offset}: {msg}"))
+}
+
+impl<'a> Transaction<'a> {
+    /// Parse the transaction record at the start of `data`. Bytes after
+    /// it (`size` onwards) are ignored.
+    ///
+    /// ```
+    /// use zodb_json_codec::Transaction;
+    ///
+    /// let mut data = vec![0, 0, 0, 0, 0, 0, 0, 1]; // tid
+    /// data.extend_from_slice(&26u64.to_be_bytes()); // tlen
+    /// data.extend_from_slice(b" \x00\x03\x00\x00\x00\x00bob");
+    /// data.extend_from_slice(&26u64.to_be_bytes());
+    /// let txn = Transaction::parse(&data)?;
+    /// assert_eq!(txn.user, b"bob");
+    /// assert_eq!(txn.records().count(), 0);
+    /// # Ok::<(), zodb_json_codec::CodecError>(())
+    /// ```
+    pub fn parse(data: &'a [u8]) -> Result<Self, CodecError> {
+        if data.len() < TRANS_HDR_LEN {
+            return Err(bad_record(0, "truncated transaction header"));
+        }
+        let tid: [u8; 8] = data[..8].try_into().unwrap();
+        let tlen = read_u64(data, 8);
+        let status = data[16];
+        let ulen = read_u16(data, 17) as usize;
+        let dlen = read_u16(data, 19) as usize;
+        let elen = read_u16(data, 21) as usize;
+        let data_offset = TRANS_HDR_LEN + ulen + dlen + elen;
+        let tlen = usize::try_from(tlen)
+            .ok()
+            .filter(|&n| n >= data_offset)
+            .ok_or_else(|| bad_record(0, "invalid transaction length"))?;
+        let trailer = tlen
+            .checked_add(8)
+            .and_then(|end| data.get(tlen..end))
+            .ok_or_else(|| bad_record(0, "truncated transaction"))?;
+        if read_u64(trailer, 0) != tlen as u64 {
+            return Err(bad_record(0, "redundant transaction length does not match"));
+        }
+        let field = |start: usize, len: usize| &data[start..start + len];
+        Ok(Transaction {
+            tid,
+            status,
+            user: field(TRANS_HDR_LEN, ulen),
+            description: field(TRANS_HDR_LEN + ulen, dlen),
+            extension: field(TRANS_HDR_LEN + ulen + dlen, elen),
+            size: tlen + 8,
+            records: &data[..tlen],
+            data_offset,
+        })
+    }
+
+    /// Iterate over the data records, in file order.
+    pub fn records(&self) -> DataRecords<'a> {
+        DataRecords {
+            data: self.records,
+            tid: self.tid,
+            pos: self.data_offset,
+        }
+    }
+}
+
+/// Iterator over the data records of a [`Transaction`]. Stops after the
+/// first error.
+pub struct DataRecords<'a> {
+    data: &'a [u8],
+    tid: [u8; 8],
+    pos: usize,
+}
+
+impl<'a> DataRecords<'a> {
+    fn next_record(&mut self) -> Result<DataRecord<'a>, CodecError> {
+        let start = self.pos;
+        let (record, end) = parse_data_record(self.data, start)?;
+        if record.tid != self.tid {
+            return Err(bad_record(
+                start,
+                "data record tid differs from its transaction",
+            ));
+        }
+        self.pos = end;
+        Ok(record)
+    }
+}
+
+/// Parse the data record at `start`, returning it and its end.
+fn parse_data_record(data: &[u8], start: usize) -> Result<(DataRecord<'_>, usize), CodecError> {
+    let header = start
+        .checked_add(DATA_HDR_LEN)
+        .and_then(|end| data.get(start..end))
+        .ok_or_else(|| bad_record(start, "truncated data record header"))?;
+    if read_u16(header, 32) != 0 {
+        return Err(bad_record(start, "versioned records are not supported"));
+    }
+    let plen = usize::try_from(read_u64(header, 34))
+        .map_err(|_| bad_record(start, "invalid pickle length"))?;
+    let body = start + DATA_HDR_LEN;
+    let (record, backpointer, end) = if plen == 0 {
+        let back = data
+            .get(body..body + 8)
+            .ok_or_else(|| bad_record(start, "truncated back pointer"))?;
+        (&[][..], Some(read_u64(back, 0)), body + 8)
+    } else {
+        let record = body
+            .checked_add(plen)
+            .and_then(|end| data.get(body..end))
+            .ok_or_else(|| bad_record(start, "truncated data record"))?;
+        (record, None, body + plen)
+    };
+    let record = DataRecord {
+        oid: header[..8].try_into().unwrap(),
+        tid: header[8..16].try_into().unwrap(),
+        prev: read_u64(header, 16),
+        tloc: read_u64(header, 24),
+        data: record,
+        backpointer,
+    };
+    Ok((record, end))
+}
+
+impl<'a> Iterator for DataRecords<'a> {
+    type Item = Result<DataRecord<'a>, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+        let result = self.next_record();
+        if result.is_err() {
+            self.pos = self.data.len();
+        }
+        Some(result)
+    }
+}
+
+/// A FileStorage file's contents.
+///
+/// ```
+/// use zodb_json_codec::FileStorage;
+///
+/// let storage = FileStorage::parse(b"FS21")?;
+/// assert_eq!(storage.records().count(), 0);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct FileStorage<'a> {
+    data: &'a [u8],
+}
+
+/// One object revision of a [`FileStorage`], with back pointers resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StorageRecord<'a> {
+    pub oid: [u8; 8],
+    pub tid: [u8; 8],
+    /// The ZODB record, or `None` if this revision undid the object's
+    /// creation.
+    pub data: Option<&'a [u8]>,
+}
+
+impl<'a> FileStorage<'a> {
+    /// Check the magic at the start of a FileStorage file's contents.
+    pub fn parse(data: &'a [u8]) -> Result<Self, CodecError> {
+        if !FILE_MAGICS.iter().any(|magic| data.starts_with(*magic)) {
+            return Err(CodecError::InvalidData(
+                "not a FileStorage file (expected FS21 or FS30 header)".to_string(),
+            ));
+        }
+        Ok(FileStorage { data })
+    }
+
+    /// Iterate over the transactions with their file positions, in file
+    /// order. A transaction whose commit was still in progress ends the
+    /// iteration.
+    pub fn transactions(&self) -> Transactions<'a> {
+        self.transactions_from(FILE_HDR_LEN)
+    }
+
+    /// Iterate over the transactions from file position `pos`, which must
+    /// be the start of one.
+    pub(crate) fn transactions_from(&self, pos: usize) -> Transactions<'a> {
+        Transactions {
+            data: self.data,
+            pos,
+        }
+    }
+
+    /// Iterate over all object revisions, in file order.
+    pub fn records(&self) -> StorageRecords<'a> {
+        StorageRecords {
+            storage: *self,
+            transactions: self.transactions(),
+            current: None,
+        }
+    }
+
+    /// Resolve a data record of the transaction at `txn_pos`: check that
+    /// it points back at its transaction and follow its back pointer.
+    pub(crate) fn resolve(
+        &self,
+        txn_pos: u64,
+        record: Result<DataRecord<'a>, CodecError>,
+    ) -> Result<StorageRecord<'a>, CodecError> {
+        let record = record.map_err(|e| at_offset(e, txn_pos as usize))?;
+        if record.tloc != txn_pos {
+            return Err(bad_record(
+                txn_pos as usize,
+                &format!("data record points back to offset {}", record.tloc),
+            ));
+        }
+        let data = match record.backpointer {
+            None => Some(record.data),
+            Some(pos) => self.load_backpointer(&record.oid, pos)?,
+        };
+        Ok(StorageRecord {
+            oid: record.oid,
+            tid: record.tid,
+            data,
+        })
+    }
+
+    /// The ZODB record a back pointer refers to, following further back
+    /// pointers; `None` for a null back pointer (creation undone).
+    pub fn load_backpointer(
+        &self,
+        oid: &[u8; 8],
+        mut pos: u64,
+    ) -> Result<Option<&'a [u8]>, CodecError> {
+        loop {
+            if pos == 0 {
+                return Ok(None);
+            }
+            let start = usize::try_from(pos)
+                .ok()
+                .filter(|&start| start >= FILE_HDR_LEN)
+                .ok_or_else(|| {
+                    CodecError::InvalidData(format!("invalid FileStorage back pointer {pos}"))
+                })?;
+            let (record, _) = parse_data_record(self.data, start)?;
+            if record.oid != *oid {
+                return Err(bad_record(start, "back pointer to another object's data"));
+            }
+            match record.backpointer {
+                None => return Ok(Some(record.data)),
+                // Back pointers point to earlier records, so this ends
+                Some(back) if back < pos => pos = back,
+                Some(_) => return Err(bad_record(start, "back pointer does not point backwards")),
+            }
+        }
+    }
+}
+
+/// Iterator over the transactions of a [`FileStorage`], yielding each
+/// with its file position. Stops after the first error.
+pub struct Transactions<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Transactions<'_> {
+    /// File position of the next transaction.
+    #[cfg(feature = "python")]
+    pub(crate) fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl<'a> Iterator for Transactions<'a> {
+    type Item = Result<(u64, Transaction<'a>), CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self.pos;
+        if pos >= self.data.len() {
+            return None;
+        }
+        match Transaction::parse(&self.data[pos..]) {
+            Ok(txn) if txn.status == STATUS_CHECKPOINT => {
+                self.pos = self.data.len();
+                None
+            }
+            Ok(txn) => {
+                self.pos = pos + txn.size;
+                Some(Ok((pos as u64, txn)))
+            }
+            Err(e) => {
+                self.pos = self.data.len();
+                Some(Err(at_offset(e, pos)))
+            }
+        }
+    }
+}
+
+/// Add the transaction's file offset to an error from parsing it, whose
+/// offsets are relative to the transaction.
+fn at_offset(err: CodecError, txn_pos: usize) -> CodecError {
+    match err {
+        CodecError::InvalidData(msg) => {
+            CodecError::InvalidData(format!("{msg} (transaction at file offset {txn_pos})"))
+        }
+        err => err,
+    }
+}
+
+/// Iterator over the object revisions of a [`FileStorage`]. Stops after
+/// the first error.
+pub struct StorageRecords<'a> {
+    storage: FileStorage<'a>,
+    transactions: Transactions<'a>,
+    current: Option<(u64, DataRecords<'a>)>,
+}
+
+impl<'a> Iterator for StorageRecords<'a> {
+    type Item = Result<StorageRecord<'a>, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((txn_pos, records)) = &mut self.current {
+                let txn_pos = *txn_pos;
+                match records.next() {
+                    Some(record) => {
+                        let result = self.storage.resolve(txn_pos, record);
+                        if result.is_err() {
+                            self.current = None;
+                            self.transactions.pos = self.transactions.data.len();
+                        }
+                        return Some(result);
+                    }
+                    None => self.current = None,
+                }
+            }
+            match self.transactions.next()? {
+                Ok((pos, txn)) => self.current = Some((pos, txn.records())),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TID: [u8; 8] = [0x03, 0xe0, 0, 0, 0, 0, 0, 0x42];
+
+    fn data_record(oid: u8, data: &[u8], backpointer: Option<u64>) -> Vec<u8> {
+        let mut rec = vec![0, 0, 0, 0, 0, 0, 0, oid];
+        rec.extend_from_slice(&TID);
+        rec.extend_from_slice(&100u64.to_be_bytes()); // prev
+        rec.extend_from_slice(&4u64.to_be_bytes()); // tloc
+        rec.extend_from_slice(&0u16.to_be_bytes());
+        rec.extend_from_slice(&(data.len() as u64).to_be_bytes());
+        rec.extend_from_slice(data);
+        if let Some(back) = backpointer {
+            rec.extend_from_slice(&back.to_be_bytes());
+        }
+        rec
+    }
+
+    fn transaction(records: &[Vec<u8>]) -> Vec<u8> {
+        let (user, desc, ext) = (&b"/ alice"[..], &b"Edit page"[..], &b""[..]);
+        let body: Vec<u8> = records.concat();
+        let tlen = (TRANS_HDR_LEN + user.len() + desc.len() + ext.len() + body.len()) as u64;
+        let mut txn = TID.to_vec();
+        txn.extend_from_slice(&tlen.to_be_bytes());
+        txn.push(b' ');
+        for field in [user, desc, ext] {
+            txn.extend_from_slice(&(field.len() as u16).to_be_bytes());
+        }
+        for field in [user, desc, ext] {
+            txn.extend_from_slice(field);
+        }
+        txn.extend_from_slice(&body);
+        txn.extend_from_slice(&tlen.to_be_bytes());
+        txn
+    }
+
+    #[test]
+    fn test_parse_transaction() {
+        let txn_bytes = transaction(&[
+            data_record(1, b"\x80\x03N.\x80\x03N.", None),
+            data_record(2, b"", Some(1234)),
+        ]);
+        let mut with_next = txn_bytes.clone();
+        with_next.extend_from_slice(b"next transaction");
+        let txn = Transaction::parse(&with_next).unwrap();
+        assert_eq!(txn.tid, TID);
+        assert_eq!(txn.status, b' ');
+        assert_eq!(txn.user, b"/ alice");
+        assert_eq!(txn.description, b"Edit page");
+        assert_eq!(txn.extension, b"");
+        assert_eq!(txn.size, txn_bytes.len());
+        let records: Vec<DataRecord> = txn.records().collect::<Result<_, _>>().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].oid, [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!((records[0].prev, records[0].tloc), (100, 4));
+        assert_eq!(records[0].data, b"\x80\x03N.\x80\x03N.");
+        assert_eq!(records[0].backpointer, None);
+        assert_eq!(records[1].data, b"");
+        assert_eq!(records[1].backpointer, Some(1234));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let good = transaction(&[data_record(1, b"\x80\x03N.", None)]);
+        let err = |data: &[u8]| match Transaction::parse(data) {
+            Ok(txn) => txn.records().find_map(Result::err).unwrap().to_string(),
+            Err(e) => e.to_string(),
+        };
+        assert!(err(&good[..10]).contains("truncated transaction header"));
+        assert!(err(&good[..good.len() - 1]).contains("truncated transaction"));
+        let mut bad = good.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        assert!(err(&bad).contains("does not match"));
+
+        // A data record claiming more bytes than the transaction holds
+        let mut rec = data_record(1, b"\x80\x03N.", None);
+        rec[41] = 99;
+        assert!(err(&transaction(&[rec])).contains("truncated data record"));
+        let mut rec = data_record(1, b"\x80\x03N.", None);
+        rec[33] = 1;
+        assert!(err(&transaction(&[rec])).contains("versioned"));
+        let mut rec = data_record(1, b"\x80\x03N.", None);
+        rec[8] = 0;
+        let msg = err(&transaction(&[rec]));
+        assert!(
+            msg.contains("tid differs") && msg.contains("offset 39"),
+            "{msg}"
+        );
+    }
+
+    /// A FileStorage file of transactions, fixing up each record's tloc.
+    fn storage(transactions: &[Vec<Vec<u8>>]) -> Vec<u8> {
+        let mut file = b"FS21".to_vec();
+        for records in transactions {
+            let tloc = (file.len() as u64).to_be_bytes();
+            let records: Vec<Vec<u8>> = records
+                .iter()
+                .map(|rec| {
+                    let mut rec = rec.clone();
+                    rec[24..32].copy_from_slice(&tloc);
+                    rec
+                })
+                .collect();
+            file.extend_from_slice(&transaction(&records));
+        }
+        file
+    }
+
+    #[test]
+    fn test_storage_records() {
+        // The first record's data starts after the header and `/ alice`,
+        // `Edit page`
+        let first = (FILE_HDR_LEN + TRANS_HDR_LEN + 7 + 9) as u64;
+        let file = storage(&[
+            vec![
+                data_record(1, b"\x80\x03N.", None),
+                data_record(2, b"\x80\x03K\x01.", None),
+            ],
+            vec![
+                data_record(1, b"", Some(first)),
+                data_record(2, b"", Some(0)),
+            ],
+        ]);
+        let fs = FileStorage::parse(&file).unwrap();
+        let positions: Vec<u64> = fs.transactions().map(|t| t.unwrap().0).collect();
+        assert_eq!(positions.len(), 2);
+        assert_eq!(positions[0], FILE_HDR_LEN as u64);
+
+        let records: Vec<StorageRecord> = fs.records().collect::<Result<_, _>>().unwrap();
+        let summary: Vec<(u8, Option<&[u8]>)> =
+            records.iter().map(|r| (r.oid[7], r.data)).collect();
+        assert_eq!(
+            summary,
+            [
+                (1, Some(&b"\x80\x03N."[..])),
+                (2, Some(&b"\x80\x03K\x01."[..])),
+                (1, Some(&b"\x80\x03N."[..])),
+                (2, None),
+            ]
+        );
+
+        // A back pointer chain resolves to the first real data
+        let second = positions[1] + first - FILE_HDR_LEN as u64;
+        assert_eq!(
+            fs.load_backpointer(&records[0].oid, second).unwrap(),
+            Some(&b"\x80\x03N."[..])
+        );
+        let err = fs.load_backpointer(&records[1].oid, first).unwrap_err();
+        assert!(err.to_string().contains("another object"), "{err}");
+    }
+
+    #[test]
+    fn test_storage_errors() {
+        assert!(FileStorage::parse(b"FS99").is_err());
+        assert_eq!(FileStorage::parse(b"FS30").unwrap().records().count(), 0);
+
+        // An in-progress commit at the end is not an error
+        let mut file = storage(&[vec![data_record(1, b"\x80\x03N.", None)]]);
+        let size = file.len() - FILE_HDR_LEN;
+        file.extend_from_within(FILE_HDR_LEN..);
+        file[FILE_HDR_LEN + size + 16] = b'c';
+        let fs = FileStorage::parse(&file).unwrap();
+        assert_eq!(fs.records().count(), 1);
+
+        // A record whose tloc is wrong, and a truncated file
+        let file = storage(&[vec![data_record(1, b"\x80\x03N.", None)]]);
+        let mut bad = file.clone();
+        bad[FILE_HDR_LEN + TRANS_HDR_LEN + 16 + 31] ^= 1;
+        let errors: Vec<_> = FileStorage::parse(&bad).unwrap().records().collect();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("points back"));
+        let mut records = FileStorage::parse(&file[..file.len() - 1])
+            .unwrap()
+            .records();
+        let err = records.next().unwrap().unwrap_err().to_string();
+        assert!(err.contains("transaction at file offset 4"), "{err}");
+        assert!(records.next().is_none());
+
+        // Back pointers must point backwards
+        let mut file = storage(&[vec![data_record(1, b"", Some(0))]]);
+        let start = FILE_HDR_LEN + TRANS_HDR_LEN + 16;
+        let pos = (start as u64).to_be_bytes();
+        file[start + DATA_HDR_LEN..start + DATA_HDR_LEN + 8].copy_from_slice(&pos);
+        let err = FileStorage::parse(&file)
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("does not point backwards"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! JSON representation of non-finite floats.
+//!
+//! JSON has no NaN or infinities. The codec writes them as
+//! `{"@f": "nan"}`, `{"@f": "inf"}` and `{"@f": "-inf"}` by default, so
+//! they survive a round trip. Strict-JSON consumers that cannot handle the
+//! marker can restore the old lossy behavior, `null`, for the conversions
+//! run inside [`with_nonfinite_floats`].
+//!
+//! Parsing is independent of the setting: an `@f` marker always decodes
+//! to the float it names.
+
+use std::cell::Cell;
+
+use crate::error::CodecError;
+
+/// How NaN and the infinities are written to JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NonFiniteFloats {
+    /// `{"@f": "nan" | "inf" | "-inf"}`, lossless.
+    #[default]
+    Marker,
+    /// `null`, which decodes back to `None`.
+    Null,
+}
+
+thread_local! {
+    /// The non-finite float mode of this thread's conversions.
+    static MODE: Cell<NonFiniteFloats> = const { Cell::new(NonFiniteFloats::Marker) };
+}
+
+/// Run `f` with the conversions it makes on this thread writing NaN and
+/// the infinities as `mode` says.
+///
+/// ```
+/// use zodb_json_codec::{
+///     pickle_value_to_json, with_nonfinite_floats, NonFiniteFloats, PickleValue,
+/// };
+///
+/// let nan = PickleValue::Float(f64::NAN);
+/// assert_eq!(pickle_value_to_json(&nan)?, serde_json::json!({"@f": "nan"}));
+/// let json = with_nonfinite_floats(NonFiniteFloats::Null, || pickle_value_to_json(&nan))?;
+/// assert_eq!(json, serde_json::Value::Null);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn with_nonfinite_floats<R>(mode: NonFiniteFloats, f: impl FnOnce() -> R) -> R {
+    let _scope = NonFiniteScope::enter(mode);
+    f()
+}
+
+/// Sets the non-finite float mode on the current thread while alive.
+pub(crate) struct NonFiniteScope {
+    previous: NonFiniteFloats,
+}
+
+impl NonFiniteScope {
+    pub(crate) fn enter(mode: NonFiniteFloats) -> Self {
+        NonFiniteScope {
+            previous: MODE.with(|m| m.replace(mode)),
+        }
+    }
+}
+
+impl Drop for NonFiniteScope {
+    fn drop(&mut self) {
+        MODE.with(|m| m.set(self.previous));
+    }
+}
+
+fn nonfinite_floats() -> NonFiniteFloats {
+    MODE.with(Cell::get)
+}
+
+/// The `@f` text for `f`, or `None` if `f` is written as a plain number
+/// (or as `null` when it is non-finite and the mode is `Null`).
+#[inline]
+pub(crate) fn float_marker(f: f64) -> Option<&'static str> {
+    if f.is_finite() {
+        return None;
+    }
+    marker_for(f, nonfinite_floats())
+}
+
+fn marker_for(f: f64, mode: NonFiniteFloats) -> Option<&'static str> {
+    match mode {
+        NonFiniteFloats::Null => None,
+        NonFiniteFloats::Marker if f.is_nan() => Some("nan"),
+        NonFiniteFloats::Marker if f > 0.0 => Some("inf"),
+        NonFiniteFloats::Marker => Some("-inf"),
+    }
+}
+
+/// Parse the text of an `@f` marker.
+pub(crate) fn parse_float_marker(text: &str) -> Result<f64, CodecError> {
+    match text {
+        "nan" => Ok(f64::NAN),
+        "inf" => Ok(f64::INFINITY),
+        "-inf" => Ok(f64::NEG_INFINITY),
+        _ => Err(CodecError::Json(format!(
+            "invalid @f value: {text:?} (expected \"nan\", \"inf\" or \"-inf\")"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markers() {
+        let marker = NonFiniteFloats::Marker;
+        assert_eq!(marker_for(f64::NAN, marker), Some("nan"));
+        assert_eq!(marker_for(f64::INFINITY, marker), Some("inf"));
+        assert_eq!(marker_for(f64::NEG_INFINITY, marker), Some("-inf"));
+        assert_eq!(marker_for(f64::NAN, NonFiniteFloats::Null), None);
+        assert_eq!(float_marker(1.5), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(parse_float_marker("nan").unwrap().is_nan());
+        assert_eq!(parse_float_marker("inf").unwrap(), f64::INFINITY);
+        assert_eq!(parse_float_marker("-inf").unwrap(), f64::NEG_INFINITY);
+        assert!(parse_float_marker("NaN").is_err());
+        assert!(parse_float_marker("1.5").is_err());
+    }
+}
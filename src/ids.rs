@@ -0,0 +1,183 @@
+//! OID and TID conversions.
+//!
+//! OIDs and TIDs are 8-byte big-endian values. In JSON they are 16
+//! lowercase hex digits (`@ref`, `@tid`, `@serial`). A TID is also a
+//! timestamp (`persistent.TimeStamp`): the first 4 bytes count minutes
+//! since 1900 with 31-day months, the last 4 bytes are the seconds within
+//! the minute scaled to `2**32 / 60`.
+
+use crate::binenc::{hex_decode, hex_encode};
+use crate::error::CodecError;
+use crate::known_types::tid_parts;
+
+/// Seconds per unit of a TID's low 4 bytes, as in `persistent.TimeStamp`.
+const SECONDS_PER_UNIT: f64 = 60.0 / 4_294_967_296.0;
+
+/// An OID as 16 lowercase hex digits, the `@ref` form.
+///
+/// ```
+/// use zodb_json_codec::oid_to_hex;
+///
+/// assert_eq!(oid_to_hex(&[0, 0, 0, 0, 0, 0, 0, 0x2a]), "000000000000002a");
+/// ```
+pub fn oid_to_hex(oid: &[u8; 8]) -> String {
+    hex_encode(oid)
+}
+
+/// Parse an OID from hex digits (either case), with an optional `0x`
+/// prefix. Fewer than 16 digits are zero-padded, so `"0x2a"` reads as
+/// OID 42.
+///
+/// ```
+/// use zodb_json_codec::hex_to_oid;
+///
+/// assert_eq!(hex_to_oid("0x2a")?, [0, 0, 0, 0, 0, 0, 0, 0x2a]);
+/// assert_eq!(hex_to_oid("000000000000002A")?, [0, 0, 0, 0, 0, 0, 0, 0x2a]);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn hex_to_oid(hex: &str) -> Result<[u8; 8], CodecError> {
+    let digits = hex.strip_prefix("0x").unwrap_or(hex);
+    let invalid = || CodecError::InvalidData(format!("invalid oid hex: {hex:?}"));
+    if digits.is_empty() || digits.len() > 16 {
+        return Err(invalid());
+    }
+    let padded = format!("{digits:0>16}");
+    let bytes = hex_decode(&padded).map_err(|_| invalid())?;
+    Ok(bytes.try_into().unwrap())
+}
+
+/// The POSIX timestamp (seconds since 1970, UTC) of a TID, as
+/// `TimeStamp.timeTime()` computes it.
+///
+/// ```
+/// use zodb_json_codec::tid_to_timestamp;
+///
+/// // 2024-05-01T12:30:15.249999
+/// let tid = [0x03, 0xf8, 0x4a, 0x6e, 0x41, 0x11, 0x11, 0x11];
+/// assert!((tid_to_timestamp(&tid) - 1_714_566_615.25).abs() < 1e-5);
+/// ```
+pub fn tid_to_timestamp(tid: &[u8; 8]) -> f64 {
+    let (year, month, day, hour, minute, _) = tid_parts(tid);
+    let days = days_from_civil(i64::from(year), month, day);
+    let minutes = (days * 24 + i64::from(hour)) * 60 + i64::from(minute);
+    let units = u32::from_be_bytes(tid[4..].try_into().unwrap());
+    minutes as f64 * 60.0 + f64::from(units) * SECONDS_PER_UNIT
+}
+
+/// The TID of a POSIX timestamp, as `TimeStamp(*time.gmtime(t)[:5] + (t % 60,))`
+/// builds it. Fails for timestamps before 1900 or too far in the future
+/// to fit.
+///
+/// ```
+/// use zodb_json_codec::{timestamp_to_tid, tid_to_timestamp};
+///
+/// let tid = timestamp_to_tid(1_714_566_615.25)?;
+/// assert_eq!(&tid[..4], &[0x03, 0xf8, 0x4a, 0x6e]);
+/// assert!((tid_to_timestamp(&tid) - 1_714_566_615.25).abs() < 1e-6);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn timestamp_to_tid(timestamp: f64) -> Result<[u8; 8], CodecError> {
+    let out_of_range =
+        || CodecError::InvalidData(format!("timestamp out of TID range: {timestamp}"));
+    if !timestamp.is_finite() {
+        return Err(out_of_range());
+    }
+    let minutes = (timestamp / 60.0).floor();
+    if minutes.abs() > 1e12 {
+        return Err(out_of_range());
+    }
+    let minutes = minutes as i64;
+    let (year, month, day) = civil_from_days(minutes.div_euclid(24 * 60));
+    let minute_of_day = minutes.rem_euclid(24 * 60);
+    if year < 1900 {
+        return Err(out_of_range());
+    }
+    let high = ((((year - 1900) * 12 + i64::from(month) - 1) * 31 + i64::from(day) - 1) * 24 * 60)
+        + minute_of_day;
+    let high = u32::try_from(high).map_err(|_| out_of_range())?;
+    // Truncates, like the TimeStamp constructor
+    let units = (timestamp.rem_euclid(60.0) / SECONDS_PER_UNIT) as u32;
+    let mut tid = [0u8; 8];
+    tid[..4].copy_from_slice(&high.to_be_bytes());
+    tid[4..].copy_from_slice(&units.to_be_bytes());
+    Ok(tid)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = i64::from((month + 9) % 12);
+    let day_of_year = (153 * month_index + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The proleptic Gregorian date of a day count since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = (month_index + if month_index < 10 { 3 } else { -9 }) as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_to_oid() {
+        assert_eq!(
+            hex_to_oid("0000000000000001").unwrap(),
+            [0, 0, 0, 0, 0, 0, 0, 1]
+        );
+        assert_eq!(hex_to_oid("FFFFFFFFFFFFFFFF").unwrap(), [0xff; 8]);
+        assert_eq!(oid_to_hex(&hex_to_oid("1").unwrap()), "0000000000000001");
+        for bad in ["", "0x", "00000000000000001", "xyz", "0x-1"] {
+            assert!(hex_to_oid(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_civil_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(1900, 1, 1), -25_567);
+        for days in [-25_567, -1, 0, 59, 11_016, 11_017, 19_844, 100_000] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn test_timestamp_roundtrip() {
+        for ts in [
+            0.0,
+            59.999,
+            951_782_400.5,
+            1_714_566_615.25,
+            -2_208_988_800.0,
+        ] {
+            let tid = timestamp_to_tid(ts).unwrap();
+            assert!((tid_to_timestamp(&tid) - ts).abs() < 1e-6, "{ts}");
+        }
+        // 1900-01-01T00:00:00 is the zero TID
+        assert_eq!(timestamp_to_tid(-2_208_988_800.0).unwrap(), [0; 8]);
+        assert_eq!(tid_to_timestamp(&[0; 8]), -2_208_988_800.0);
+    }
+
+    #[test]
+    fn test_timestamp_out_of_range() {
+        for ts in [-2_208_988_800.5, f64::NAN, f64::INFINITY, 1e15] {
+            assert!(timestamp_to_tid(ts).is_err(), "{ts}");
+        }
+    }
+}
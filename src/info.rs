@@ -0,0 +1,217 @@
+//! Capability and version introspection.
+//!
+//! Deployment tooling checks at startup that the installed codec supports
+//! what a storage schema relies on (a marker, a protocol, a build
+//! feature). [`codec_info`] collects those facts in one place; the Python
+//! `codec_info()` returns the same data as a dict.
+//!
+//! The tables here must follow the code they describe: the opcode list is
+//! checked against the decoder by a unit test.
+
+use crate::opcodes::*;
+
+/// Version of the JSON marker format. Markers are only added, never
+/// changed, within a major crate version, so this follows the crate's
+/// major version.
+pub const MARKER_FORMAT_VERSION: u32 = 1;
+
+/// Every marker key the codec emits or accepts at the top of a JSON object.
+const MARKERS: &[&str] = &[
+    "@t", "@b", "@bi", "@f", "@d", "@bk", "@set", "@fset", "@ns", "@su", "@dt", "@zdt", "@zdt_raw",
+    "@date", "@time", "@td", "@dec", "@complex", "@frac", "@uuid", "@provides", "@tid", "@odict",
+    "@ddict", "@pmap", "@plist", "@rel", "@len", "@cls", "@s", "@ref", "@reduce", "@newobj",
+    "@newobj_ex", "@inst", "@pkl", "@dangling", "@blocked", "@shared", "@backref", "@kv", "@ks",
+    "@children", "@first", "@next", "@blob", "@serial", "@cls_raw",
+];
+
+/// Whether `marker` is one of the codec's own marker keys (`@tz` included,
+/// which only appears next to `@dt` / `@time`).
+pub(crate) fn is_builtin_marker(marker: &str) -> bool {
+    marker == "@tz" || MARKERS.contains(&marker)
+}
+
+/// Opcodes the decoder understands, by `pickletools` name.
+const DECODED_OPCODES: &[(&str, u8)] = &[
+    ("MARK", MARK),
+    ("STOP", STOP),
+    ("POP", POP),
+    ("DUP", DUP),
+    ("FLOAT", FLOAT),
+    ("INT", INT),
+    ("LONG", LONG),
+    ("NONE", NONE),
+    ("REDUCE", REDUCE),
+    ("STRING", STRING),
+    ("UNICODE", UNICODE),
+    ("APPEND", APPEND),
+    ("BUILD", BUILD),
+    ("GLOBAL", GLOBAL),
+    ("DICT", DICT),
+    ("EMPTY_DICT", EMPTY_DICT),
+    ("APPENDS", APPENDS),
+    ("GET", GET),
+    ("LIST", LIST),
+    ("EMPTY_LIST", EMPTY_LIST),
+    ("PUT", PUT),
+    ("SETITEM", SETITEM),
+    ("TUPLE", TUPLE),
+    ("EMPTY_TUPLE", EMPTY_TUPLE),
+    ("SETITEMS", SETITEMS),
+    ("PERSID", PERSID),
+    ("BINPERSID", BINPERSID),
+    ("INST", INST),
+    ("BININT", BININT),
+    ("BININT1", BININT1),
+    ("BININT2", BININT2),
+    ("BINSTRING", BINSTRING),
+    ("SHORT_BINSTRING", SHORT_BINSTRING),
+    ("BINUNICODE", BINUNICODE),
+    ("BINGET", BINGET),
+    ("LONG_BINGET", LONG_BINGET),
+    ("BINPUT", BINPUT),
+    ("LONG_BINPUT", LONG_BINPUT),
+    ("BINFLOAT", BINFLOAT),
+    ("BINBYTES", BINBYTES),
+    ("SHORT_BINBYTES", SHORT_BINBYTES),
+    ("OBJ", OBJ),
+    ("PROTO", PROTO),
+    ("NEWOBJ", NEWOBJ),
+    ("TUPLE1", TUPLE1),
+    ("TUPLE2", TUPLE2),
+    ("TUPLE3", TUPLE3),
+    ("NEWTRUE", NEWTRUE),
+    ("NEWFALSE", NEWFALSE),
+    ("LONG1", LONG1),
+    ("LONG4", LONG4),
+    ("SHORT_BINUNICODE", SHORT_BINUNICODE),
+    ("BINUNICODE8", BINUNICODE8),
+    ("BINBYTES8", BINBYTES8),
+    ("EMPTY_SET", EMPTY_SET),
+    ("ADDITEMS", ADDITEMS),
+    ("FROZENSET", FROZENSET),
+    ("NEWOBJ_EX", NEWOBJ_EX),
+    ("STACK_GLOBAL", STACK_GLOBAL),
+    ("MEMOIZE", MEMOIZE),
+    ("FRAME", FRAME),
+    ("BYTEARRAY8", BYTEARRAY8),
+    ("NEXT_BUFFER", NEXT_BUFFER),
+    ("READONLY_BUFFER", READONLY_BUFFER),
+];
+
+/// The `pickletools` name of a decoded opcode.
+pub(crate) fn opcode_name(op: u8) -> Option<&'static str> {
+    DECODED_OPCODES
+        .iter()
+        .find(|&&(_, code)| code == op)
+        .map(|&(name, _)| name)
+}
+
+/// Classes converted to typed markers, as `(marker, "module.Name")`.
+const KNOWN_TYPES: &[(&str, &str)] = &[
+    ("@dt", "datetime.datetime"),
+    ("@zdt", "DateTime.DateTime"),
+    ("@date", "datetime.date"),
+    ("@time", "datetime.time"),
+    ("@td", "datetime.timedelta"),
+    ("@dec", "decimal.Decimal"),
+    ("@complex", "builtins.complex"),
+    ("@frac", "fractions.Fraction"),
+    ("@uuid", "uuid.UUID"),
+    ("@provides", "zope.interface.declarations.Provides"),
+    ("@set", "builtins.set"),
+    ("@fset", "builtins.frozenset"),
+    ("@tid", "persistent.TimeStamp"),
+    ("@odict", "collections.OrderedDict"),
+    ("@ddict", "collections.defaultdict"),
+    ("@pmap", "persistent.mapping.PersistentMapping"),
+    ("@plist", "persistent.list.PersistentList"),
+    ("@rel", "z3c.relationfield.relation.RelationValue"),
+    ("@len", "BTrees.Length.Length"),
+    ("@blob", "ZODB.blob.Blob"),
+];
+
+/// What this build of the codec supports.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CodecInfo {
+    /// Crate version (`CARGO_PKG_VERSION`).
+    pub version: &'static str,
+    /// See [`MARKER_FORMAT_VERSION`].
+    pub marker_format: u32,
+    /// Marker keys of the JSON format.
+    pub markers: &'static [&'static str],
+    /// Opcode names the decoder understands.
+    pub opcodes: Vec<&'static str>,
+    /// Pickle protocols that can be decoded.
+    pub decode_protocols: &'static [u8],
+    /// Pickle protocols that can be produced (see `encode_pickle_protocol`
+    /// and `encode_pickle_protocol0`).
+    pub encode_protocols: &'static [u8],
+    /// Typed markers and the classes they stand for.
+    pub known_types: &'static [(&'static str, &'static str)],
+    /// Optional capabilities and whether this build has them.
+    pub features: Vec<(&'static str, bool)>,
+}
+
+/// Describe the capabilities of this build.
+///
+/// ```
+/// let info = zodb_json_codec::codec_info();
+/// assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+/// assert!(info.markers.contains(&"@ref"));
+/// ```
+pub fn codec_info() -> CodecInfo {
+    CodecInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        marker_format: MARKER_FORMAT_VERSION,
+        markers: MARKERS,
+        opcodes: DECODED_OPCODES.iter().map(|(name, _)| *name).collect(),
+        decode_protocols: &[0, 1, 2, 3, 4, 5],
+        encode_protocols: &[0, 2, 3, 4],
+        known_types: KNOWN_TYPES,
+        features: vec![
+            ("pg_sanitization", true),
+            ("pg_json", true),
+            ("framed_output", true),
+            ("protocol0_output", true),
+            ("msgpack", false),
+            ("cbor", true),
+            ("capi", cfg!(feature = "capi")),
+            ("python", cfg!(feature = "python")),
+            ("abi3", cfg!(feature = "abi3")),
+            ("cpython_interop", cfg!(feature = "cpython-interop")),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::decode_pickle;
+    use crate::error::CodecError;
+
+    #[test]
+    fn test_opcodes_match_decoder() {
+        for op in 0..=255u8 {
+            let listed = DECODED_OPCODES.iter().any(|&(_, code)| code == op);
+            let unknown = matches!(decode_pickle(&[op]).unwrap_err().root(), CodecError::UnknownOpcode(_));
+            assert_eq!(listed, !unknown, "opcode 0x{op:02x}");
+        }
+    }
+
+    #[test]
+    fn test_codec_info() {
+        let info = codec_info();
+        assert_eq!(
+            info.marker_format.to_string(),
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            "bump MARKER_FORMAT_VERSION with the major version"
+        );
+        assert!(info.opcodes.contains(&"STACK_GLOBAL"));
+        assert!(info.opcodes.contains(&"NEXT_BUFFER"));
+        assert!(info.decode_protocols.contains(&5));
+        for (marker, _) in info.known_types {
+            assert!(info.markers.contains(marker), "{marker}");
+        }
+    }
+}
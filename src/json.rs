@@ -1,15 +1,36 @@
 use std::cell::RefCell;
 
-use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde_json::{json, Map, Value};
 
+use crate::bigint;
+use crate::binenc::{b64_decode, b64_encode, hex_encode};
 use crate::btrees;
-use crate::error::CodecError;
+use crate::bytes_keys::{self, BYTES_KEYS_MARKER};
+use crate::dangling;
+use crate::duplicate_keys::{self, ObjectItems};
+use crate::encode::NestingGuard;
+use crate::error::{CodecError, PathSegment};
+use crate::floats;
 use crate::json_writer::JsonWriter;
 use crate::known_types;
+use crate::null_strings;
+use crate::raw_pickle;
+use crate::surrogates;
 use crate::types::{InstanceData, PickleValue};
+use crate::warnings::{self, WarningCode};
+use crate::zodb::{compact_class_path, int_ref_oid, ref_oid_json, ExtendedRef};
 
 /// Convert a PickleValue AST to a serde_json Value.
+///
+/// ```
+/// use zodb_json_codec::{json_to_pickle_value, pickle_value_to_json, PickleValue};
+///
+/// let val = PickleValue::Tuple(vec![PickleValue::Int(1), PickleValue::Bytes(b"x".to_vec())]);
+/// let json = pickle_value_to_json(&val)?;
+/// assert_eq!(json, serde_json::json!({"@t": [1, {"@b": "eA=="}]}));
+/// assert_eq!(json_to_pickle_value(&json)?, val);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
 pub fn pickle_value_to_json(val: &PickleValue) -> Result<Value, CodecError> {
     pickle_value_to_json_impl(val, false, false, 0)
 }
@@ -46,10 +67,16 @@ fn pickle_value_to_json_impl(
         PickleValue::Bool(b) => Ok(Value::Bool(*b)),
         PickleValue::Int(i) => Ok(json!(*i)),
         PickleValue::BigInt(bi) => {
+            if let Some(n) = bigint::bigint_as_number(bi).and_then(serde_json::Number::from_i128) {
+                return Ok(Value::Number(n));
+            }
             // Store as string to avoid precision loss
             Ok(json!({"@bi": bi.to_string()}))
         }
         PickleValue::Float(f) => {
+            if let Some(marker) = floats::float_marker(*f) {
+                return Ok(json!({"@f": marker}));
+            }
             Ok(serde_json::Number::from_f64(*f)
                 .map(Value::Number)
                 .unwrap_or(Value::Null))
@@ -57,13 +84,18 @@ fn pickle_value_to_json_impl(
         PickleValue::String(s) => {
             if sanitize_nulls && s.contains('\0') {
                 // PG JSONB cannot store \u0000 — base64-encode with @ns marker
-                Ok(json!({"@ns": BASE64.encode(s.as_bytes())}))
+                null_bytes_fallback(false);
+                Ok(json!({"@ns": b64_encode(s.as_bytes())}))
             } else {
-                Ok(Value::String(s.clone()))
+                Ok(Value::String(s.to_string()))
             }
         }
+        PickleValue::SurrogateString(b) => Ok(json!({"@su": surrogates::escape(b)?})),
         PickleValue::Bytes(b) => {
-            Ok(json!({"@b": BASE64.encode(b)}))
+            if let Some(raw) = known_types::detect_raw_tid(b) {
+                return Ok(known_types::tid_json(raw, None));
+            }
+            Ok(json!({"@b": b64_encode(b)}))
         }
         PickleValue::List(items) => {
             let arr: Result<Vec<Value>, _> = items.iter().map(&to_json).collect();
@@ -75,15 +107,25 @@ fn pickle_value_to_json_impl(
         }
         PickleValue::Dict(pairs) => {
             let all_string_keys = pairs.iter().all(|(k, _)| matches!(k, PickleValue::String(_)));
-            if all_string_keys {
+            let bytes_keys = !all_string_keys && bytes_keys::promote_keys(pairs);
+            let items = if all_string_keys || bytes_keys {
+                duplicate_keys::object_items(pairs)?
+            } else {
+                ObjectItems::Pairs
+            };
+            if !matches!(items, ObjectItems::Pairs) {
                 let mut map = Map::new();
-                for (k, v) in pairs {
-                    if let PickleValue::String(key) = k {
+                if bytes_keys {
+                    map.insert(BYTES_KEYS_MARKER.to_string(), Value::Bool(true));
+                }
+                for (k, v) in items.iter(pairs) {
+                    if let Some(key) = bytes_keys::key_text(k) {
                         let json_key = if sanitize_nulls && key.contains('\0') {
                             // Null-byte in dict key — use @ns: prefix for JSON key
-                            format!("@ns:{}", BASE64.encode(key.as_bytes()))
+                            null_bytes_fallback(true);
+                            null_strings::escape_key(key)
                         } else {
-                            key.clone()
+                            key.to_string()
                         };
                         map.insert(json_key, to_json(v)?);
                     }
@@ -108,6 +150,9 @@ fn pickle_value_to_json_impl(
         PickleValue::Global { module, name } => {
             Ok(json!({"@cls": [module, name]}))
         }
+        PickleValue::Blocked { module, name } => {
+            Ok(json!({"@blocked": [module, name]}))
+        }
         PickleValue::Instance(inst) => {
             let InstanceData { module, name, state, dict_items, list_items } = inst.as_ref();
             if let Some(typed) =
@@ -120,26 +165,26 @@ fn pickle_value_to_json_impl(
             } else {
                 to_json(state)?
             };
-            if module.is_empty() && name.is_empty() {
-                Ok(json!({"@inst": state_json}))
+            let mut obj = if inst.is_anonymous() {
+                json!({"@inst": state_json})
             } else {
-                let mut obj = json!({
+                json!({
                     "@cls": [module, name],
                     "@s": state_json,
-                });
-                if let Some(pairs) = dict_items {
-                    let items_json: Result<Vec<Value>, CodecError> = pairs
-                        .iter()
-                        .map(|(k, v)| Ok(json!([to_json(k)?, to_json(v)?])))
-                        .collect();
-                    obj.as_object_mut().unwrap().insert("@items".to_string(), json!(items_json?));
-                }
-                if let Some(items) = list_items {
-                    let appends_json: Result<Vec<Value>, _> = items.iter().map(&to_json).collect();
-                    obj.as_object_mut().unwrap().insert("@appends".to_string(), json!(appends_json?));
-                }
-                Ok(obj)
+                })
+            };
+            if let Some(pairs) = dict_items {
+                let items_json: Result<Vec<Value>, CodecError> = pairs
+                    .iter()
+                    .map(|(k, v)| Ok(json!([to_json(k)?, to_json(v)?])))
+                    .collect();
+                obj.as_object_mut().unwrap().insert("@items".to_string(), json!(items_json?));
             }
+            if let Some(items) = list_items {
+                let appends_json: Result<Vec<Value>, _> = items.iter().map(&to_json).collect();
+                obj.as_object_mut().unwrap().insert("@appends".to_string(), json!(appends_json?));
+            }
+            Ok(obj)
         }
         PickleValue::PersistentRef(inner) => {
             if compact_refs {
@@ -148,16 +193,19 @@ fn pickle_value_to_json_impl(
             let inner_json = to_json(inner)?;
             Ok(json!({"@ref": inner_json}))
         }
-        PickleValue::Reduce { callable, args, dict_items, list_items } => {
+        PickleValue::Reduce { callable, args, dict_items, list_items, newobj } => {
+            let items = dict_items.as_deref().map(Vec::as_slice);
             if let Some(typed) =
-                known_types::try_reduce_to_typed_json(callable, args, &to_json)?
+                known_types::try_reduce_to_typed_json(callable, args, items, &to_json)?
             {
                 return Ok(typed);
             }
+            reduce_fallback(callable);
+            let (marker, callable_key) = reduce_keys(*newobj);
             let callable_json = to_json(callable)?;
             let args_json = to_json(args)?;
             let mut reduce_obj = json!({
-                "callable": callable_json,
+                callable_key: callable_json,
                 "args": args_json,
             });
             if let Some(pairs) = dict_items {
@@ -171,14 +219,96 @@ fn pickle_value_to_json_impl(
                 let appends_json: Result<Vec<Value>, _> = items.iter().map(&to_json).collect();
                 reduce_obj.as_object_mut().unwrap().insert("appends".to_string(), json!(appends_json?));
             }
-            Ok(json!({"@reduce": reduce_obj}))
+            Ok(json!({marker: reduce_obj}))
         }
+        PickleValue::NewObjEx { cls, args, kwargs } => Ok(json!({"@newobj_ex": {
+            "cls": to_json(cls)?,
+            "args": to_json(args)?,
+            "kwargs": to_json(kwargs)?,
+        }})),
         PickleValue::RawPickle(data) => {
-            Ok(json!({"@pkl": BASE64.encode(data)}))
+            Ok(json!({"@pkl": b64_encode(data)}))
         }
+        PickleValue::Shared { id, value } => {
+            Ok(json!({"@shared": [id, to_json(value)?]}))
+        }
+        PickleValue::BackRef(id) => {
+            Ok(json!({"@backref": id}))
+        }
+    }
+}
+
+/// Note that a REDUCE had no typed marker and is stored as `@reduce`.
+pub(crate) fn reduce_fallback(callable: &PickleValue) {
+    if let PickleValue::Global { module, name } = callable {
+        tracing::debug!(module = module.as_str(), name = name.as_str(), "no typed marker, using @reduce");
+        warnings::warn(WarningCode::Reduce, || {
+            format!("no typed marker for {module}.{name}, kept as @reduce")
+        });
+    } else {
+        tracing::debug!("REDUCE with a non-global callable, using @reduce");
+        warnings::warn(WarningCode::Reduce, || {
+            "REDUCE with a non-global callable, kept as @reduce".to_string()
+        });
+    }
+}
+
+/// Note that a string (or dict key) with null bytes is stored as `@ns`.
+pub(crate) fn null_bytes_fallback(key: bool) {
+    warnings::warn(WarningCode::NullBytes, || match key {
+        true => "dict key with null bytes stored as @ns:".to_string(),
+        false => "string with null bytes stored as @ns".to_string(),
+    });
+}
+
+/// The marker and callable key of a `Reduce`'s JSON form.
+pub(crate) fn reduce_keys(newobj: bool) -> (&'static str, &'static str) {
+    if newobj {
+        ("@newobj", "cls")
+    } else {
+        ("@reduce", "callable")
     }
 }
 
+/// The `Reduce` of a `@reduce` or `@newobj` value.
+fn json_to_reduce(
+    reduce_map: &Map<String, Value>,
+    callable_key: &str,
+    newobj: bool,
+) -> Result<PickleValue, CodecError> {
+    let callable = json_to_pickle_value(reduce_map.get(callable_key).unwrap_or(&Value::Null))?;
+    let args = json_to_pickle_value(reduce_map.get("args").unwrap_or(&Value::Null))?;
+    let dict_items = if let Some(Value::Array(items_arr)) = reduce_map.get("items") {
+        let mut pairs = Vec::new();
+        for pair in items_arr {
+            if let Value::Array(kv) = pair {
+                if kv.len() == 2 {
+                    let k = json_to_pickle_value(&kv[0])?;
+                    let v = json_to_pickle_value(&kv[1])?;
+                    pairs.push((k, v));
+                }
+            }
+        }
+        Some(Box::new(pairs))
+    } else {
+        None
+    };
+    let list_items = if let Some(Value::Array(appends_arr)) = reduce_map.get("appends") {
+        let items: Result<Vec<PickleValue>, _> =
+            appends_arr.iter().map(json_to_pickle_value).collect();
+        Some(Box::new(items?))
+    } else {
+        None
+    };
+    Ok(PickleValue::Reduce {
+        callable: Box::new(callable),
+        args: Box::new(args),
+        dict_items,
+        list_items,
+        newobj,
+    })
+}
+
 /// Compact a ZODB persistent ref to JSON.
 /// inner is typically Tuple([Bytes(oid), None_or_Global])
 fn compact_ref_to_json(
@@ -188,24 +318,24 @@ fn compact_ref_to_json(
     if let PickleValue::Tuple(items) = inner {
         if items.len() == 2 {
             if let PickleValue::Bytes(oid) = &items[0] {
-                let hex = hex::encode(oid);
+                let oid = ref_oid_json(oid);
                 match &items[1] {
                     PickleValue::None => {
-                        return Ok(json!({"@ref": hex}));
+                        return Ok(json!({"@ref": oid}));
                     }
                     PickleValue::Global { module, name } => {
-                        let class_path = if module.is_empty() {
-                            name.clone()
-                        } else {
-                            format!("{module}.{name}")
-                        };
-                        return Ok(json!({"@ref": [hex, class_path]}));
+                        if let Some(class_path) = compact_class_path(module, name) {
+                            return Ok(json!({"@ref": [oid, class_path]}));
+                        }
                     }
                     _ => {}
                 }
             }
         }
     }
+    if let Some(ext) = ExtendedRef::parse(inner) {
+        return Ok(json!({"@ref": ext.to_json()}));
+    }
     // Fallback: generic ref
     let inner_json = to_json(inner)?;
     Ok(json!({"@ref": inner_json}))
@@ -219,10 +349,96 @@ thread_local! {
     static JSON_BUF: RefCell<JsonWriter> = RefCell::new(JsonWriter::with_capacity(4096));
 }
 
+/// Convert a PickleValue AST straight to a JSON string, without building
+/// a `serde_json::Value` tree.
+///
+/// The document is the one [`pickle_value_to_json`] gives, with object
+/// keys in pickle order instead of sorted. `indent` is the number of
+/// spaces per nesting level, laid out like `serde_json::to_string_pretty`
+/// (0 puts items on their own lines without indenting them); `None` gives
+/// compact output.
+///
+/// ```
+/// use zodb_json_codec::{pickle_value_to_json_string, PickleValue};
+///
+/// let val = PickleValue::List(vec![PickleValue::Int(1), PickleValue::Bytes(b"x".to_vec())]);
+/// assert_eq!(pickle_value_to_json_string(&val, None)?, r#"[1,{"@b":"eA=="}]"#);
+/// assert_eq!(
+///     pickle_value_to_json_string(&val, Some(2))?,
+///     "[\n  1,\n  {\n    \"@b\": \"eA==\"\n  }\n]"
+/// );
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn pickle_value_to_json_string(
+    val: &PickleValue,
+    indent: Option<usize>,
+) -> Result<String, CodecError> {
+    let mut w = JsonWriter::with_indent(4096, indent);
+    write_value_depth(&mut w, val, WriteStyle::PLAIN, 0)?;
+    Ok(w.take())
+}
+
+/// As `pickle_value_to_json_string`, with the members of every dict object
+/// sorted by key.
+///
+/// Two pickles of equal dicts built in different insertion orders give the
+/// same string. `@d` pair lists, sets and the codec's own marker objects
+/// keep their order, so the output still encodes back to the same pickle
+/// items.
+///
+/// ```
+/// use zodb_json_codec::{pickle_value_to_json_string_sorted, PickleValue};
+///
+/// let val = PickleValue::Dict(vec![
+///     (PickleValue::String("b".into()), PickleValue::Int(1)),
+///     (PickleValue::String("a".into()), PickleValue::Int(2)),
+/// ]);
+/// assert_eq!(pickle_value_to_json_string_sorted(&val, None)?, r#"{"a":2,"b":1}"#);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn pickle_value_to_json_string_sorted(
+    val: &PickleValue,
+    indent: Option<usize>,
+) -> Result<String, CodecError> {
+    let mut w = JsonWriter::with_indent(4096, indent);
+    let style = WriteStyle {
+        sort_keys: true,
+        ..WriteStyle::PLAIN
+    };
+    write_value_depth(&mut w, val, style, 0)?;
+    Ok(w.take())
+}
+
+/// Which transformations the JSON writer applies.
+#[derive(Clone, Copy)]
+struct WriteStyle {
+    /// Strings and keys containing `\0` become `@ns` markers.
+    sanitize_nulls: bool,
+    /// Persistent references use the compact `@ref` forms.
+    compact_refs: bool,
+    /// Dict objects are written with their members sorted by key.
+    sort_keys: bool,
+}
+
+impl WriteStyle {
+    /// As `pickle_value_to_json`.
+    const PLAIN: WriteStyle = WriteStyle {
+        sanitize_nulls: false,
+        compact_refs: false,
+        sort_keys: false,
+    };
+    /// As `pickle_value_to_json_pg`.
+    const PG: WriteStyle = WriteStyle {
+        sanitize_nulls: true,
+        compact_refs: true,
+        sort_keys: false,
+    };
+}
+
 /// Convert a PickleValue AST directly to a JSON string for PostgreSQL JSONB.
 ///
 /// This is the fast path that eliminates all serde_json::Value allocations.
-/// It handles BTree dispatch internally.
+/// It handles BTree and persistent container dispatch internally.
 pub fn pickle_value_to_json_string_pg(
     val: &PickleValue,
     module: &str,
@@ -234,23 +450,35 @@ pub fn pickle_value_to_json_string_pg(
 
         if let Some(info) = btrees::classify_btree(module, name) {
             btrees::btree_state_to_json_writer(&info, val, &write_value_pg_flat, &mut w)?;
-        } else {
-            write_value_pg_depth(&mut w, val, 0)?;
+        } else if !known_types::try_write_container_state(
+            &mut w,
+            module,
+            name,
+            val,
+            &write_value_pg_flat,
+        )? {
+            write_value_depth(&mut w, val, WriteStyle::PG, 0)?;
         }
 
         Ok(w.take())
     })
 }
 
-/// Recursive walker: write a PickleValue as PG-compatible JSON to a JsonWriter.
-fn write_value_pg_depth(w: &mut JsonWriter, val: &PickleValue, depth: usize) -> Result<(), CodecError> {
+/// Recursive walker: write a PickleValue as JSON to a JsonWriter.
+fn write_value_depth(
+    w: &mut JsonWriter,
+    val: &PickleValue,
+    style: WriteStyle,
+    depth: usize,
+) -> Result<(), CodecError> {
     if depth > MAX_DEPTH {
         return Err(CodecError::InvalidData(
             "maximum nesting depth exceeded in JSON conversion".to_string(),
         ));
     }
-    let recurse =
-        |w: &mut JsonWriter, v: &PickleValue| -> Result<(), CodecError> { write_value_pg_depth(w, v, depth + 1) };
+    let recurse = |w: &mut JsonWriter, v: &PickleValue| -> Result<(), CodecError> {
+        write_value_depth(w, v, style, depth + 1)
+    };
 
     match val {
         PickleValue::None => {
@@ -263,6 +491,10 @@ fn write_value_pg_depth(w: &mut JsonWriter, val: &PickleValue, depth: usize) ->
             w.write_i64(*i);
         }
         PickleValue::BigInt(bi) => {
+            if let Some(n) = bigint::bigint_as_number(bi) {
+                w.write_i128(n);
+                return Ok(());
+            }
             // {"@bi": "..."}
             w.begin_object();
             w.write_key_literal("@bi");
@@ -270,24 +502,44 @@ fn write_value_pg_depth(w: &mut JsonWriter, val: &PickleValue, depth: usize) ->
             w.end_object();
         }
         PickleValue::Float(f) => {
+            if let Some(marker) = floats::float_marker(*f) {
+                // {"@f": "nan"}
+                w.begin_object();
+                w.write_key_literal("@f");
+                w.write_string_literal(marker);
+                w.end_object();
+                return Ok(());
+            }
             w.write_f64(*f);
         }
         PickleValue::String(s) => {
-            if s.contains('\0') {
+            if style.sanitize_nulls && s.contains('\0') {
                 // PG JSONB cannot store \u0000 — base64-encode with @ns marker
+                null_bytes_fallback(false);
                 w.begin_object();
                 w.write_key_literal("@ns");
-                w.write_string_literal(&BASE64.encode(s.as_bytes()));
+                w.write_base64(s.as_bytes());
                 w.end_object();
             } else {
                 w.write_string(s);
             }
         }
+        PickleValue::SurrogateString(b) => {
+            // {"@su": escaped text}
+            w.begin_object();
+            w.write_key_literal("@su");
+            w.write_string(&surrogates::escape(b)?);
+            w.end_object();
+        }
         PickleValue::Bytes(b) => {
+            if let Some(raw) = known_types::detect_raw_tid(b) {
+                known_types::write_tid(w, raw, None);
+                return Ok(());
+            }
             // {"@b": base64}
             w.begin_object();
             w.write_key_literal("@b");
-            w.write_string_literal(&BASE64.encode(b));
+            w.write_base64(b);
             w.end_object();
         }
         PickleValue::List(items) => {
@@ -318,16 +570,26 @@ fn write_value_pg_depth(w: &mut JsonWriter, val: &PickleValue, depth: usize) ->
             let all_string_keys = pairs
                 .iter()
                 .all(|(k, _)| matches!(k, PickleValue::String(_)));
-            if all_string_keys {
+            let bytes_keys = !all_string_keys && bytes_keys::promote_keys(pairs);
+            let items = match (all_string_keys || bytes_keys, style.sort_keys) {
+                (true, false) => duplicate_keys::object_items(pairs)?,
+                (true, true) => duplicate_keys::object_items(pairs)?.sorted(pairs),
+                (false, _) => ObjectItems::Pairs,
+            };
+            if !matches!(items, ObjectItems::Pairs) {
                 w.begin_object();
-                for (i, (k, v)) in pairs.iter().enumerate() {
-                    if i > 0 {
+                if bytes_keys {
+                    w.write_key_literal(BYTES_KEYS_MARKER);
+                    w.write_bool(true);
+                }
+                for (i, (k, v)) in items.iter(pairs).enumerate() {
+                    if i > 0 || bytes_keys {
                         w.write_comma();
                     }
-                    if let PickleValue::String(key) = k {
-                        if key.contains('\0') {
-                            let encoded = format!("@ns:{}", BASE64.encode(key.as_bytes()));
-                            w.write_key(&encoded);
+                    if let Some(key) = bytes_keys::key_text(k) {
+                        if style.sanitize_nulls && key.contains('\0') {
+                            null_bytes_fallback(true);
+                            w.write_key(&null_strings::escape_key(key));
                         } else {
                             w.write_key(key);
                         }
@@ -393,6 +655,17 @@ fn write_value_pg_depth(w: &mut JsonWriter, val: &PickleValue, depth: usize) ->
             w.end_array();
             w.end_object();
         }
+        PickleValue::Blocked { module, name } => {
+            // {"@blocked": ["module", "name"]}
+            w.begin_object();
+            w.write_key_literal("@blocked");
+            w.begin_array();
+            w.write_string(module);
+            w.write_comma();
+            w.write_string(name);
+            w.end_array();
+            w.end_object();
+        }
         PickleValue::Instance(inst) => {
             let InstanceData {
                 module,
@@ -403,26 +676,19 @@ fn write_value_pg_depth(w: &mut JsonWriter, val: &PickleValue, depth: usize) ->
             } = inst.as_ref();
 
             // Try known type handlers first
-            if known_types::try_write_instance_typed(w, module, name, state)? {
+            if known_types::try_write_instance_typed(w, module, name, state, &recurse)? {
                 return Ok(());
             }
 
             // BTree handling
             let has_btree = btrees::classify_btree(module, name);
 
-            if module.is_empty() && name.is_empty() {
-                // {"@inst": state}
-                w.begin_object();
+            w.begin_object();
+            if inst.is_anonymous() {
+                // {"@inst": state, ...}
                 w.write_key_literal("@inst");
-                if let Some(info) = &has_btree {
-                    btrees::btree_state_to_json_writer(info, state, &recurse, w)?;
-                } else {
-                    recurse(w, state)?;
-                }
-                w.end_object();
             } else {
                 // {"@cls": [mod, name], "@s": state, ...}
-                w.begin_object();
                 w.write_key_literal("@cls");
                 w.begin_array();
                 w.write_string(module);
@@ -431,61 +697,73 @@ fn write_value_pg_depth(w: &mut JsonWriter, val: &PickleValue, depth: usize) ->
                 w.end_array();
                 w.write_comma();
                 w.write_key_literal("@s");
-                if let Some(info) = &has_btree {
-                    btrees::btree_state_to_json_writer(info, state, &recurse, w)?;
-                } else {
-                    recurse(w, state)?;
-                }
-                if let Some(pairs) = dict_items {
-                    w.write_comma();
-                    w.write_key_literal("@items");
-                    w.begin_array();
-                    for (i, (k, v)) in pairs.iter().enumerate() {
-                        if i > 0 {
-                            w.write_comma();
-                        }
-                        w.begin_array();
-                        recurse(w, k)?;
+            }
+            if let Some(info) = &has_btree {
+                btrees::btree_state_to_json_writer(info, state, &recurse, w)?;
+            } else {
+                recurse(w, state)?;
+            }
+            if let Some(pairs) = dict_items {
+                w.write_comma();
+                w.write_key_literal("@items");
+                w.begin_array();
+                for (i, (k, v)) in pairs.iter().enumerate() {
+                    if i > 0 {
                         w.write_comma();
-                        recurse(w, v)?;
-                        w.end_array();
                     }
+                    w.begin_array();
+                    recurse(w, k)?;
+                    w.write_comma();
+                    recurse(w, v)?;
                     w.end_array();
                 }
-                if let Some(items) = list_items {
-                    w.write_comma();
-                    w.write_key_literal("@appends");
-                    w.begin_array();
-                    for (i, item) in items.iter().enumerate() {
-                        if i > 0 {
-                            w.write_comma();
-                        }
-                        recurse(w, item)?;
+                w.end_array();
+            }
+            if let Some(items) = list_items {
+                w.write_comma();
+                w.write_key_literal("@appends");
+                w.begin_array();
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        w.write_comma();
                     }
-                    w.end_array();
+                    recurse(w, item)?;
                 }
-                w.end_object();
+                w.end_array();
             }
+            w.end_object();
         }
         PickleValue::PersistentRef(inner) => {
-            // Compact ref: always use compact mode for PG path
-            write_compact_ref_pg(w, inner, &recurse)?;
+            if style.compact_refs {
+                write_compact_ref_pg(w, inner, &recurse)?;
+            } else {
+                // {"@ref": inner}
+                w.begin_object();
+                w.write_key_literal("@ref");
+                recurse(w, inner)?;
+                w.end_object();
+            }
         }
         PickleValue::Reduce {
             callable,
             args,
             dict_items,
             list_items,
+            newobj,
         } => {
             // Try known types first
-            if known_types::try_write_reduce_typed(w, callable, args, &recurse)? {
+            let items = dict_items.as_deref().map(Vec::as_slice);
+            if known_types::try_write_reduce_typed(w, callable, args, items, &recurse)? {
                 return Ok(());
             }
             // Fallback: {"@reduce": {"callable": ..., "args": ..., ...}}
+            // or {"@newobj": {"cls": ..., "args": ..., ...}}
+            reduce_fallback(callable);
+            let (marker, callable_key) = reduce_keys(*newobj);
             w.begin_object();
-            w.write_key_literal("@reduce");
+            w.write_key_literal(marker);
             w.begin_object();
-            w.write_key_literal("callable");
+            w.write_key_literal(callable_key);
             recurse(w, callable)?;
             w.write_comma();
             w.write_key_literal("args");
@@ -521,11 +799,45 @@ fn write_value_pg_depth(w: &mut JsonWriter, val: &PickleValue, depth: usize) ->
             w.end_object();
             w.end_object();
         }
+        PickleValue::NewObjEx { cls, args, kwargs } => {
+            // {"@newobj_ex": {"cls": ..., "args": ..., "kwargs": ...}}
+            w.begin_object();
+            w.write_key_literal("@newobj_ex");
+            w.begin_object();
+            w.write_key_literal("cls");
+            recurse(w, cls)?;
+            w.write_comma();
+            w.write_key_literal("args");
+            recurse(w, args)?;
+            w.write_comma();
+            w.write_key_literal("kwargs");
+            recurse(w, kwargs)?;
+            w.end_object();
+            w.end_object();
+        }
         PickleValue::RawPickle(data) => {
             // {"@pkl": base64}
             w.begin_object();
             w.write_key_literal("@pkl");
-            w.write_string_literal(&BASE64.encode(data));
+            w.write_base64(data);
+            w.end_object();
+        }
+        PickleValue::Shared { id, value } => {
+            // {"@shared": [id, value]}
+            w.begin_object();
+            w.write_key_literal("@shared");
+            w.begin_array();
+            w.write_i64(*id as i64);
+            w.write_comma();
+            recurse(w, value)?;
+            w.end_array();
+            w.end_object();
+        }
+        PickleValue::BackRef(id) => {
+            // {"@backref": id}
+            w.begin_object();
+            w.write_key_literal("@backref");
+            w.write_i64(*id as i64);
             w.end_object();
         }
     }
@@ -534,7 +846,7 @@ fn write_value_pg_depth(w: &mut JsonWriter, val: &PickleValue, depth: usize) ->
 
 /// Wrapper for BTree callbacks — they take (w, val) not (w, val, depth).
 fn write_value_pg_flat(w: &mut JsonWriter, val: &PickleValue) -> Result<(), CodecError> {
-    write_value_pg_depth(w, val, 0)
+    write_value_depth(w, val, WriteStyle::PG, 0)
 }
 
 /// Write a compact persistent ref for PG path.
@@ -546,38 +858,41 @@ fn write_compact_ref_pg(
     if let PickleValue::Tuple(items) = inner {
         if items.len() == 2 {
             if let PickleValue::Bytes(oid) = &items[0] {
-                let hex = hex::encode(oid);
                 match &items[1] {
                     PickleValue::None => {
                         // {"@ref": "hex_oid"}
                         w.begin_object();
                         w.write_key_literal("@ref");
-                        w.write_string_literal(&hex);
+                        write_ref_oid_pg(w, oid);
                         w.end_object();
                         return Ok(());
                     }
                     PickleValue::Global { module, name } => {
-                        let class_path = if module.is_empty() {
-                            name.clone()
-                        } else {
-                            format!("{module}.{name}")
-                        };
-                        // {"@ref": ["hex_oid", "class_path"]}
-                        w.begin_object();
-                        w.write_key_literal("@ref");
-                        w.begin_array();
-                        w.write_string_literal(&hex);
-                        w.write_comma();
-                        w.write_string(&class_path);
-                        w.end_array();
-                        w.end_object();
-                        return Ok(());
+                        if let Some(class_path) = compact_class_path(module, name) {
+                            // {"@ref": ["hex_oid", "class_path"]}
+                            w.begin_object();
+                            w.write_key_literal("@ref");
+                            w.begin_array();
+                            write_ref_oid_pg(w, oid);
+                            w.write_comma();
+                            w.write_string(&class_path);
+                            w.end_array();
+                            w.end_object();
+                            return Ok(());
+                        }
                     }
                     _ => {}
                 }
             }
         }
     }
+    if let Some(ext) = ExtendedRef::parse(inner) {
+        w.begin_object();
+        w.write_key_literal("@ref");
+        write_extended_ref_pg(w, &ext);
+        w.end_object();
+        return Ok(());
+    }
     // Fallback: generic ref
     w.begin_object();
     w.write_key_literal("@ref");
@@ -586,6 +901,53 @@ fn write_compact_ref_pg(
     Ok(())
 }
 
+/// Write the OID of a compact `@ref` for PG path.
+fn write_ref_oid_pg(w: &mut JsonWriter, oid: &[u8]) {
+    match int_ref_oid(oid) {
+        Some(n) => w.write_i64(n),
+        None => w.write_string_literal(&hex_encode(oid)),
+    }
+}
+
+/// Write the compact `@ref` value of an extended ref for PG path.
+fn write_extended_ref_pg(w: &mut JsonWriter, ext: &ExtendedRef<'_>) {
+    match ext {
+        ExtendedRef::Legacy(oid) => {
+            // ["hex_oid"]
+            w.begin_array();
+            write_ref_oid_pg(w, oid);
+            w.end_array();
+        }
+        ExtendedRef::Extended { oid, weak, db, class } => {
+            // {"oid": "hex_oid", "weak": true, "db": "name", "cls": "class_path"}
+            w.begin_object();
+            w.write_key_literal("oid");
+            write_ref_oid_pg(w, oid);
+            if *weak {
+                w.write_comma();
+                w.write_key_literal("weak");
+                w.write_bool(true);
+            }
+            if let Some(db) = db {
+                w.write_comma();
+                w.write_key_literal("db");
+                w.write_string(db);
+            }
+            if let Some(class) = class {
+                w.write_comma();
+                w.write_key_literal("cls");
+                w.write_string(class);
+            }
+            w.end_object();
+        }
+    }
+}
+
+/// Id of an `@shared` or `@backref` marker.
+fn shared_id(val: &Value) -> Option<u32> {
+    val.as_u64().and_then(|id| u32::try_from(id).ok())
+}
+
 /// Convert a serde_json Value back to a PickleValue AST.
 pub fn json_to_pickle_value(val: &Value) -> Result<PickleValue, CodecError> {
     match val {
@@ -594,87 +956,84 @@ pub fn json_to_pickle_value(val: &Value) -> Result<PickleValue, CodecError> {
         Value::Number(n) => {
             if let Some(i) = n.as_i64() {
                 Ok(PickleValue::Int(i))
+            } else if let Some(bi) = bigint::parse_big_json_integer(&n.to_string()) {
+                Ok(bi)
             } else if let Some(f) = n.as_f64() {
                 Ok(PickleValue::Float(f))
             } else {
                 Err(CodecError::Json(format!("unsupported number: {n}")))
             }
         }
-        Value::String(s) => Ok(PickleValue::String(s.clone())),
+        Value::String(s) => Ok(PickleValue::String(s.as_str().into())),
         Value::Array(arr) => {
-            let items: Result<Vec<PickleValue>, _> =
-                arr.iter().map(json_to_pickle_value).collect();
-            Ok(PickleValue::List(items?))
+            let _nesting = NestingGuard::enter()?;
+            Ok(PickleValue::List(json_items_to_pickle_values(arr)?))
         }
         Value::Object(map) => {
+            let _nesting = NestingGuard::enter()?;
             // Check for our special type markers
-            if let Some(v) = map.get("@t") {
+            if let Some(Value::Array(arr)) = map.get("@t") {
                 // Tuple
-                if let Value::Array(arr) = v {
-                    let items: Result<Vec<PickleValue>, _> =
-                        arr.iter().map(json_to_pickle_value).collect();
-                    return Ok(PickleValue::Tuple(items?));
-                }
+                let items = json_items_to_pickle_values(arr).map_err(in_key("@t"))?;
+                return Ok(PickleValue::Tuple(items));
             }
-            if let Some(v) = map.get("@b") {
+            if let Some(Value::String(s)) = map.get("@b") {
                 // Bytes
-                if let Value::String(s) = v {
-                    let bytes = BASE64
-                        .decode(s)
-                        .map_err(|e| CodecError::Json(format!("base64 decode: {e}")))?;
-                    return Ok(PickleValue::Bytes(bytes));
-                }
+                let bytes = b64_decode(s)?;
+                return Ok(PickleValue::Bytes(bytes));
+            }
+            if let Some(Value::String(s)) = map.get("@su") {
+                // String with lone surrogates
+                return surrogates::unescape(s);
             }
-            if let Some(v) = map.get("@bi") {
+            if let (true, Some(Value::String(s))) = (null_strings::restoring(), map.get("@ns")) {
+                // String with null bytes, sanitized for PostgreSQL
+                return null_strings::unescape(s);
+            }
+            if let Some(Value::String(s)) = map.get("@f") {
+                // NaN or infinity
+                return Ok(PickleValue::Float(floats::parse_float_marker(s)?));
+            }
+            if let Some(Value::String(s)) = map.get("@bi") {
                 // BigInt
-                if let Value::String(s) = v {
-                    let bi: num_bigint::BigInt = s
-                        .parse()
-                        .map_err(|e| CodecError::Json(format!("bigint parse: {e}")))?;
-                    return Ok(PickleValue::BigInt(bi));
-                }
+                let bi: num_bigint::BigInt = s
+                    .parse()
+                    .map_err(|e| CodecError::Json(format!("bigint parse: {e}")))?;
+                return Ok(PickleValue::BigInt(bi));
             }
-            if let Some(v) = map.get("@d") {
+            if let Some(Value::Array(arr)) = map.get("@d") {
                 // Dict with non-string keys
-                if let Value::Array(arr) = v {
-                    let mut pairs = Vec::new();
-                    for pair in arr {
-                        if let Value::Array(kv) = pair {
-                            if kv.len() == 2 {
-                                let k = json_to_pickle_value(&kv[0])?;
-                                let v = json_to_pickle_value(&kv[1])?;
-                                pairs.push((k, v));
-                            }
+                let mut pairs = Vec::new();
+                for (i, pair) in arr.iter().enumerate() {
+                    if let Value::Array(kv) = pair {
+                        if kv.len() == 2 {
+                            let at = |e: CodecError, j| {
+                                e.in_path(PathSegment::Index(j))
+                                    .in_path(PathSegment::Index(i))
+                                    .in_path(PathSegment::Key("@d"))
+                            };
+                            let k = json_to_pickle_value(&kv[0]).map_err(|e| at(e, 0))?;
+                            let v = json_to_pickle_value(&kv[1]).map_err(|e| at(e, 1))?;
+                            pairs.push((k, v));
                         }
                     }
-                    return Ok(PickleValue::Dict(pairs));
                 }
+                return Ok(PickleValue::Dict(pairs));
             }
-            if let Some(v) = map.get("@set") {
-                if let Value::Array(arr) = v {
-                    let items: Result<Vec<PickleValue>, _> =
-                        arr.iter().map(json_to_pickle_value).collect();
-                    return Ok(PickleValue::Set(items?));
-                }
+            if let Some(Value::Array(arr)) = map.get("@set") {
+                let items = json_items_to_pickle_values(arr).map_err(in_key("@set"))?;
+                return Ok(PickleValue::Set(items));
             }
-            if let Some(v) = map.get("@fset") {
-                if let Value::Array(arr) = v {
-                    let items: Result<Vec<PickleValue>, _> =
-                        arr.iter().map(json_to_pickle_value).collect();
-                    return Ok(PickleValue::FrozenSet(items?));
-                }
+            if let Some(Value::Array(arr)) = map.get("@fset") {
+                let items = json_items_to_pickle_values(arr).map_err(in_key("@fset"))?;
+                return Ok(PickleValue::FrozenSet(items));
             }
             if let Some(v) = map.get("@ref") {
-                let inner = json_to_pickle_value(v)?;
+                let inner = json_to_pickle_value(v).map_err(in_key("@ref"))?;
                 return Ok(PickleValue::PersistentRef(Box::new(inner)));
             }
-            if let Some(v) = map.get("@pkl") {
-                if let Value::String(s) = v {
-                    let bytes = BASE64
-                        .decode(s)
-                        .map_err(|e| CodecError::Json(format!("base64 decode: {e}")))?;
-                    return Ok(PickleValue::RawPickle(bytes));
-                }
+            if let Some(Value::String(s)) = map.get("@pkl") {
+                return raw_pickle::decode_raw_pickle_marker(s);
             }
             // Check for known typed markers (@dt, @date, @time, @td, @dec, @uuid)
             if let Some(pv) =
@@ -696,32 +1055,12 @@ pub fn json_to_pickle_value(val: &Value) -> Result<PickleValue, CodecError> {
                                     &info,
                                     state_json,
                                     &json_to_pickle_value,
-                                )?
+                                )
                             } else {
-                                json_to_pickle_value(state_json)?
+                                json_to_pickle_value(state_json)
                             };
-                        let dict_items = if let Some(Value::Array(items_arr)) = map.get("@items") {
-                            let mut pairs = Vec::new();
-                            for pair in items_arr {
-                                if let Value::Array(kv) = pair {
-                                    if kv.len() == 2 {
-                                        let k = json_to_pickle_value(&kv[0])?;
-                                        let v = json_to_pickle_value(&kv[1])?;
-                                        pairs.push((k, v));
-                                    }
-                                }
-                            }
-                            Some(Box::new(pairs))
-                        } else {
-                            None
-                        };
-                        let list_items = if let Some(Value::Array(appends_arr)) = map.get("@appends") {
-                            let items: Result<Vec<PickleValue>, _> =
-                                appends_arr.iter().map(json_to_pickle_value).collect();
-                            Some(Box::new(items?))
-                        } else {
-                            None
-                        };
+                        let state = state.map_err(in_key("@s"))?;
+                        let (dict_items, list_items) = json_to_instance_items(map)?;
                         return Ok(PickleValue::Instance(Box::new(InstanceData {
                             module,
                             name,
@@ -732,6 +1071,13 @@ pub fn json_to_pickle_value(val: &Value) -> Result<PickleValue, CodecError> {
                     }
                 }
             }
+            // Anonymous instance: {"@inst": state}
+            if let Some(state_json) = map.get("@inst") {
+                let state = json_to_pickle_value(state_json).map_err(in_key("@inst"))?;
+                let mut inst = InstanceData::new("", "", state);
+                (inst.dict_items, inst.list_items) = json_to_instance_items(map)?;
+                return Ok(PickleValue::Instance(Box::new(inst)));
+            }
             // Check for standalone @cls (Global reference)
             if let Some(Value::Array(cls)) = map.get("@cls") {
                 if cls.len() == 2 && !map.contains_key("@s") {
@@ -740,60 +1086,207 @@ pub fn json_to_pickle_value(val: &Value) -> Result<PickleValue, CodecError> {
                     return Ok(PickleValue::Global { module, name });
                 }
             }
-            if let Some(v) = map.get("@reduce") {
-                if let Value::Object(reduce_map) = v {
-                    let callable =
-                        json_to_pickle_value(reduce_map.get("callable").unwrap_or(&Value::Null))?;
-                    let args =
-                        json_to_pickle_value(reduce_map.get("args").unwrap_or(&Value::Null))?;
-                    let dict_items = if let Some(Value::Array(items_arr)) = reduce_map.get("items") {
-                        let mut pairs = Vec::new();
-                        for pair in items_arr {
-                            if let Value::Array(kv) = pair {
-                                if kv.len() == 2 {
-                                    let k = json_to_pickle_value(&kv[0])?;
-                                    let v = json_to_pickle_value(&kv[1])?;
-                                    pairs.push((k, v));
-                                }
-                            }
-                        }
-                        Some(Box::new(pairs))
-                    } else {
-                        None
-                    };
-                    let list_items = if let Some(Value::Array(appends_arr)) = reduce_map.get("appends") {
-                        let items: Result<Vec<PickleValue>, _> =
-                            appends_arr.iter().map(json_to_pickle_value).collect();
-                        Some(Box::new(items?))
-                    } else {
-                        None
-                    };
-                    return Ok(PickleValue::Reduce {
-                        callable: Box::new(callable),
-                        args: Box::new(args),
-                        dict_items,
-                        list_items,
+            if let Some(Value::Array(cls)) = map.get("@blocked") {
+                if let [Value::String(module), Value::String(name)] = cls.as_slice() {
+                    return Ok(PickleValue::Blocked {
+                        module: module.clone(),
+                        name: name.clone(),
                     });
                 }
             }
+            if let Some(Value::Array(arr)) = map.get("@shared") {
+                if let [id, value] = arr.as_slice() {
+                    if let Some(id) = shared_id(id) {
+                        let value = Box::new(json_to_pickle_value(value)?);
+                        return Ok(PickleValue::Shared { id, value });
+                    }
+                }
+            }
+            if let Some(id) = map.get("@backref").and_then(shared_id) {
+                return Ok(PickleValue::BackRef(id));
+            }
+            for newobj in [false, true] {
+                let (marker, callable_key) = reduce_keys(newobj);
+                if let Some(Value::Object(reduce_map)) = map.get(marker) {
+                    return json_to_reduce(reduce_map, callable_key, newobj);
+                }
+            }
+            if let Some(Value::Object(newobj_map)) = map.get("@newobj_ex") {
+                let part =
+                    |key: &str| json_to_pickle_value(newobj_map.get(key).unwrap_or(&Value::Null));
+                return Ok(PickleValue::NewObjEx {
+                    cls: Box::new(part("cls")?),
+                    args: Box::new(part("args")?),
+                    kwargs: Box::new(part("kwargs")?),
+                });
+            }
             // Regular dict with string keys
+            let bytes_keys = map.get(BYTES_KEYS_MARKER) == Some(&Value::Bool(true));
             let mut pairs = Vec::new();
             for (k, v) in map {
+                if (bytes_keys && k == BYTES_KEYS_MARKER) || dangling::skip_key(k)? {
+                    continue;
+                }
                 pairs.push((
-                    PickleValue::String(k.clone()),
-                    json_to_pickle_value(v)?,
+                    null_strings::key_value(k),
+                    json_to_pickle_value(v).map_err(in_key(k))?,
                 ));
             }
+            if bytes_keys {
+                bytes_keys::restore_bytes_keys(&mut pairs);
+            }
             Ok(PickleValue::Dict(pairs))
         }
     }
 }
 
+/// The elements of a JSON array, with the index of a failing one in the
+/// error path.
+fn json_items_to_pickle_values(arr: &[Value]) -> Result<Vec<PickleValue>, CodecError> {
+    arr.iter()
+        .enumerate()
+        .map(|(i, item)| json_to_pickle_value(item).map_err(|e| e.in_path(PathSegment::Index(i))))
+        .collect()
+}
+
+/// Error mapper adding the object key `key` to the error path.
+fn in_key(key: &str) -> impl Fn(CodecError) -> CodecError + '_ {
+    move |e| e.in_path(PathSegment::Key(key))
+}
+
+/// Subclass items of an instance marker: `@items` pairs and `@appends`.
+#[allow(clippy::type_complexity, clippy::box_collection)]
+fn json_to_instance_items(
+    map: &serde_json::Map<String, Value>,
+) -> Result<
+    (
+        Option<Box<Vec<(PickleValue, PickleValue)>>>,
+        Option<Box<Vec<PickleValue>>>,
+    ),
+    CodecError,
+> {
+    let dict_items = if let Some(Value::Array(items_arr)) = map.get("@items") {
+        let mut pairs = Vec::new();
+        for pair in items_arr {
+            if let Value::Array(kv) = pair {
+                if kv.len() == 2 {
+                    let k = json_to_pickle_value(&kv[0])?;
+                    let v = json_to_pickle_value(&kv[1])?;
+                    pairs.push((k, v));
+                }
+            }
+        }
+        Some(Box::new(pairs))
+    } else {
+        None
+    };
+    let list_items = if let Some(Value::Array(appends_arr)) = map.get("@appends") {
+        let items: Result<Vec<PickleValue>, _> =
+            appends_arr.iter().map(json_to_pickle_value).collect();
+        Some(Box::new(items?))
+    } else {
+        None
+    };
+    Ok((dict_items, list_items))
+}
+
+/// Re-emit a marker-bearing JSON document in the codec's canonical form.
+///
+/// The document is converted to a `PickleValue` and back, so every marker
+/// comes out exactly as the decoder would produce it: object keys sorted,
+/// no insignificant whitespace, persistent refs in the compact
+/// `"oid"` / `["oid", "module.Class"]` form with lowercase hex, and typed
+/// markers (`@dt`, `@time`, `@dec`, ...) in their normalized string form.
+/// A top-level `{"@cls": ..., "@s": ...}` document is treated as a ZODB
+/// record, including BTree state flattening, and a document with `@blob`
+/// as a blob stub (without its `@serial`). Two documents that encode to
+/// the same pickle canonicalize to the same string.
+///
+/// ```
+/// use zodb_json_codec::canonicalize_json;
+///
+/// let doc = r#"{"b": {"@ref": "00000000000000AB"}, "a": {"@dt": "2025-01-01T00:00:00.000000"}}"#;
+/// assert_eq!(
+///     canonicalize_json(doc)?,
+///     r#"{"a":{"@dt":"2025-01-01T00:00:00"},"b":{"@ref":"00000000000000ab"}}"#
+/// );
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn canonicalize_json(json_str: &str) -> Result<String, CodecError> {
+    let doc: Value =
+        serde_json::from_str(json_str).map_err(|e| CodecError::Json(e.to_string()))?;
+    let doc = crate::zodb::restore_persistent_refs(doc);
+    let to_json = |v: &PickleValue| pickle_value_to_json_impl(v, false, true, 0);
+
+    let canonical = match doc.as_object() {
+        Some(map) if map.contains_key("@blob") => {
+            // Blob stubs all encode to the same record
+            let record = crate::zodb::encode_zodb_record(doc)?;
+            crate::zodb::decode_zodb_record(&record)?
+        }
+        Some(map) if map.len() == 2 && map.contains_key("@cls") && map.contains_key("@s") => {
+            let (module, name) = match map["@cls"].as_array().map(Vec::as_slice) {
+                Some([Value::String(m), Value::String(n)]) => (m.as_str(), n.as_str()),
+                _ => {
+                    return Err(CodecError::InvalidData(
+                        "@cls must be [module, name]".to_string(),
+                    ))
+                }
+            };
+            let state_json = if let Some(info) = btrees::classify_btree(module, name) {
+                let state = btrees::json_to_btree_state(&info, &map["@s"], &json_to_pickle_value)?;
+                btrees::btree_state_to_json(&info, &state, &to_json)?
+            } else {
+                let state = match known_types::try_typed_json_to_container_state(
+                    module,
+                    name,
+                    &map["@s"],
+                    &json_to_pickle_value,
+                )? {
+                    Some(state) => state,
+                    None => json_to_pickle_value(&map["@s"])?,
+                };
+                match known_types::try_container_state_to_typed_json(module, name, &state, &to_json)? {
+                    Some(typed) => typed,
+                    None => to_json(&state)?,
+                }
+            };
+            json!({"@cls": [module, name], "@s": state_json})
+        }
+        _ => to_json(&json_to_pickle_value(&doc)?)?,
+    };
+    serde_json::to_string(&canonical).map_err(|e| CodecError::Json(e.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::null_strings::with_pg_safe_input;
     use crate::types::InstanceData;
 
+    #[test]
+    fn test_json_to_pickle_value_max_depth() {
+        // Build and drop the deep Value on a thread with a large stack.
+        let result = std::thread::Builder::new()
+            .stack_size(64 * 1024 * 1024)
+            .spawn(|| {
+                let mut val = json!(1);
+                for i in 0..2000 {
+                    val = Value::Array(vec![val]);
+                    if i % 2 == 0 {
+                        val = Value::Object(Map::from_iter([("@t".to_string(), val)]));
+                    }
+                }
+                let err = json_to_pickle_value(&val).unwrap_err();
+                assert!(err.to_string().contains("nesting depth"), "{err}");
+                // The guards are released on the error path
+                assert!(json_to_pickle_value(&json!([[1]])).is_ok());
+            })
+            .unwrap()
+            .join();
+        assert!(result.is_ok(), "test thread panicked");
+    }
+
     #[test]
     fn test_roundtrip_none() {
         let val = PickleValue::None;
@@ -804,13 +1297,26 @@ mod tests {
 
     #[test]
     fn test_roundtrip_string() {
-        let val = PickleValue::String("hello".to_string());
+        let val = PickleValue::String("hello".into());
         let json = pickle_value_to_json(&val).unwrap();
         assert_eq!(json, Value::String("hello".to_string()));
         let back = json_to_pickle_value(&json).unwrap();
         assert_eq!(val, back);
     }
 
+    #[test]
+    fn test_big_json_numbers_parse_exactly() {
+        let json: Value = serde_json::from_str("[18446744073709551617, -1e3, 2.5]").unwrap();
+        assert_eq!(
+            json_to_pickle_value(&json).unwrap(),
+            PickleValue::List(vec![
+                PickleValue::BigInt("18446744073709551617".parse().unwrap()),
+                PickleValue::Float(-1000.0),
+                PickleValue::Float(2.5),
+            ])
+        );
+    }
+
     #[test]
     fn test_roundtrip_bytes() {
         let val = PickleValue::Bytes(vec![1, 2, 3]);
@@ -830,8 +1336,8 @@ mod tests {
     #[test]
     fn test_roundtrip_dict_string_keys() {
         let val = PickleValue::Dict(vec![
-            (PickleValue::String("a".to_string()), PickleValue::Int(1)),
-            (PickleValue::String("b".to_string()), PickleValue::Int(2)),
+            (PickleValue::String("a".into()), PickleValue::Int(1)),
+            (PickleValue::String("b".into()), PickleValue::Int(2)),
         ]);
         let json = pickle_value_to_json(&val).unwrap();
         // Should be a plain JSON object
@@ -846,8 +1352,8 @@ mod tests {
     #[test]
     fn test_roundtrip_dict_nonstring_keys() {
         let val = PickleValue::Dict(vec![
-            (PickleValue::Int(1), PickleValue::String("a".to_string())),
-            (PickleValue::Int(2), PickleValue::String("b".to_string())),
+            (PickleValue::Int(1), PickleValue::String("a".into())),
+            (PickleValue::Int(2), PickleValue::String("b".into())),
         ]);
         let json = pickle_value_to_json(&val).unwrap();
         // Should use @d encoding
@@ -862,7 +1368,7 @@ mod tests {
             module: "myapp".to_string(),
             name: "MyClass".to_string(),
             state: Box::new(PickleValue::Dict(vec![(
-                PickleValue::String("x".to_string()),
+                PickleValue::String("x".into()),
                 PickleValue::Int(42),
             )])),
             dict_items: None,
@@ -883,8 +1389,8 @@ mod tests {
             name: "OrderedDict".to_string(),
             state: Box::new(PickleValue::None),
             dict_items: Some(Box::new(vec![
-                (PickleValue::String("a".to_string()), PickleValue::Int(1)),
-                (PickleValue::String("b".to_string()), PickleValue::Int(2)),
+                (PickleValue::String("a".into()), PickleValue::Int(1)),
+                (PickleValue::String("b".into()), PickleValue::Int(2)),
             ])),
             list_items: None,
         }));
@@ -923,14 +1429,15 @@ mod tests {
     fn test_reduce_with_dict_items() {
         let val = PickleValue::Reduce {
             callable: Box::new(PickleValue::Global {
-                module: "collections".to_string(),
-                name: "OrderedDict".to_string(),
+                module: "myapp.registry".to_string(),
+                name: "Registry".to_string(),
             }),
             args: Box::new(PickleValue::Tuple(vec![])),
             dict_items: Some(Box::new(vec![
-                (PickleValue::String("x".to_string()), PickleValue::Int(1)),
+                (PickleValue::String("x".into()), PickleValue::Int(1)),
             ])),
             list_items: None,
+            newobj: false,
         };
         let json = pickle_value_to_json(&val).unwrap();
         let reduce = json.get("@reduce").unwrap();
@@ -953,6 +1460,7 @@ mod tests {
             args: Box::new(PickleValue::Tuple(vec![])),
             dict_items: None,
             list_items: Some(Box::new(vec![PickleValue::Int(5), PickleValue::Int(6)])),
+            newobj: false,
         };
         let json = pickle_value_to_json(&val).unwrap();
         let reduce = json.get("@reduce").unwrap();
@@ -963,11 +1471,67 @@ mod tests {
         assert_eq!(val, back);
     }
 
+    #[test]
+    fn test_newobj() {
+        let val = PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global {
+                module: "mymod".to_string(),
+                name: "Tag".to_string(),
+            }),
+            args: Box::new(PickleValue::Tuple(vec![PickleValue::String("x".into())])),
+            dict_items: None,
+            list_items: None,
+            newobj: true,
+        };
+        let json = pickle_value_to_json(&val).unwrap();
+        assert_eq!(
+            json,
+            json!({"@newobj": {"cls": {"@cls": ["mymod", "Tag"]}, "args": {"@t": ["x"]}}})
+        );
+        assert_eq!(json_to_pickle_value(&json).unwrap(), val);
+        let direct = pickle_value_to_json_string(&val, None).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&direct).unwrap(), json);
+    }
+
+    #[test]
+    fn test_json_string_matches_value_path() {
+        let val = json_to_pickle_value(&json!({
+            "title": "a\0b",
+            "ref": {"@ref": {"@t": [{"@b": "AAAAAAAAAAE="}, null]}},
+            "items": [{"@t": [1, 2.5]}, {"@set": [1]}, {"@d": [[1, "one"]]}],
+            "when": {"@dt": "2025-01-01T00:00:00+00:00"},
+            "big": {"@bi": "123456789012345678901234567890"},
+            "empty": {"list": [], "dict": {}},
+        }))
+        .unwrap();
+        let expected = pickle_value_to_json(&val).unwrap();
+        for indent in [None, Some(0), Some(2), Some(4)] {
+            let text = pickle_value_to_json_string(&val, indent).unwrap();
+            assert_eq!(serde_json::from_str::<Value>(&text).unwrap(), expected);
+        }
+        // Same layout as serde_json for sorted input
+        let sorted = PickleValue::Dict(vec![
+            (PickleValue::String("a".into()), PickleValue::List(vec![])),
+            (
+                PickleValue::String("b".into()),
+                PickleValue::Tuple(vec![PickleValue::None]),
+            ),
+        ]);
+        assert_eq!(
+            pickle_value_to_json_string(&sorted, Some(2)).unwrap(),
+            serde_json::to_string_pretty(&pickle_value_to_json(&sorted).unwrap()).unwrap()
+        );
+        assert_eq!(
+            pickle_value_to_json_string(&sorted, None).unwrap(),
+            r#"{"a":[],"b":{"@t":[null]}}"#
+        );
+    }
+
     // ── PG-specific tests ──────────────────────────────────────────
 
     #[test]
     fn test_pg_null_byte_sanitization() {
-        let val = PickleValue::String("hello\0world".to_string());
+        let val = PickleValue::String("hello\0world".into());
         // Standard path: no sanitization
         let json = pickle_value_to_json(&val).unwrap();
         assert_eq!(json, Value::String("hello\0world".to_string()));
@@ -975,14 +1539,39 @@ mod tests {
         let pg_json = pickle_value_to_json_pg(&val).unwrap();
         assert!(pg_json.get("@ns").is_some());
         let encoded = pg_json["@ns"].as_str().unwrap();
-        let decoded = BASE64.decode(encoded).unwrap();
+        let decoded = b64_decode(encoded).unwrap();
         assert_eq!(decoded, b"hello\0world");
     }
 
+    #[test]
+    fn test_pg_null_byte_warnings() {
+        use crate::warnings::collect_warnings;
+        let val = PickleValue::Dict(vec![
+            (PickleValue::String("a\0".into()), PickleValue::String("b\0".into())),
+            (PickleValue::String("c".into()), PickleValue::String("d\0".into())),
+        ]);
+        let ((), plain) = collect_warnings(|| {
+            pickle_value_to_json(&val).unwrap();
+        });
+        assert!(plain.is_empty());
+        let ((), value_path) = collect_warnings(|| {
+            pickle_value_to_json_pg(&val).unwrap();
+        });
+        let ((), string_path) = collect_warnings(|| {
+            pickle_value_to_json_string_pg(&val, "myapp", "Doc").unwrap();
+        });
+        assert_eq!(value_path, string_path);
+        let counts: Vec<_> = value_path.iter().map(|w| (w.message.as_str(), w.count)).collect();
+        assert_eq!(
+            counts,
+            [("dict key with null bytes stored as @ns:", 1), ("string with null bytes stored as @ns", 2)]
+        );
+    }
+
     #[test]
     fn test_pg_null_byte_in_dict_key() {
         let val = PickleValue::Dict(vec![(
-            PickleValue::String("key\0null".to_string()),
+            PickleValue::String("key\0null".into()),
             PickleValue::Int(42),
         )]);
         let pg_json = pickle_value_to_json_pg(&val).unwrap();
@@ -992,9 +1581,45 @@ mod tests {
         assert_eq!(*map.values().next().unwrap(), json!(42));
     }
 
+    #[test]
+    fn test_pg_null_bytes_roundtrip() {
+        // "@ns:YWJj" has no null bytes and stays a plain key
+        let val = PickleValue::Dict(vec![
+            (PickleValue::String("@ns:YWJj".into()), PickleValue::Int(1)),
+            (
+                PickleValue::String("key\0null".into()),
+                PickleValue::List(vec![PickleValue::String("a\0b".into())]),
+            ),
+        ]);
+        let pg_json = pickle_value_to_json_pg(&val).unwrap();
+        let restore = |json: &Value| with_pg_safe_input(|| json_to_pickle_value(json)).unwrap();
+        assert_eq!(restore(&pg_json), val);
+        let text = pickle_value_to_json_string_pg(&val, "myapp", "Doc").unwrap();
+        let parsed: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(restore(&parsed), val);
+
+        let err =
+            with_pg_safe_input(|| json_to_pickle_value(&json!({"@ns": "/wA="}))).unwrap_err();
+        assert!(err.to_string().contains("not UTF-8"), "{err}");
+    }
+
+    #[test]
+    fn test_ns_data_kept_without_pg_safe_input() {
+        // A genuine "@ns:" key and "@ns" dict are ordinary data
+        let val = PickleValue::Dict(vec![(
+            PickleValue::String("@ns:YQBi".into()),
+            PickleValue::Dict(vec![(
+                PickleValue::String("@ns".into()),
+                PickleValue::String("YQBi".into()),
+            )]),
+        )]);
+        let json = pickle_value_to_json(&val).unwrap();
+        assert_eq!(json_to_pickle_value(&json).unwrap(), val);
+    }
+
     #[test]
     fn test_pg_string_without_null_unchanged() {
-        let val = PickleValue::String("normal".to_string());
+        let val = PickleValue::String("normal".into());
         let pg_json = pickle_value_to_json_pg(&val).unwrap();
         assert_eq!(pg_json, Value::String("normal".to_string()));
     }
@@ -1046,8 +1671,8 @@ mod tests {
     #[test]
     fn test_pg_null_sanitization_in_list() {
         let val = PickleValue::List(vec![
-            PickleValue::String("ok".to_string()),
-            PickleValue::String("has\0null".to_string()),
+            PickleValue::String("ok".into()),
+            PickleValue::String("has\0null".into()),
         ]);
         let pg_json = pickle_value_to_json_pg(&val).unwrap();
         let arr = pg_json.as_array().unwrap();
@@ -1073,6 +1698,91 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pg_ref_qualified_name_not_compacted() {
+        // "myapp.Outer.Inner" would split into ("myapp.Outer", "Inner")
+        let val = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, 1]),
+            PickleValue::Global {
+                module: "myapp".to_string(),
+                name: "Outer.Inner".to_string(),
+            },
+        ])));
+        let pg_json = pickle_value_to_json_pg(&val).unwrap();
+        assert_eq!(
+            pg_json,
+            json!({"@ref": {"@t": [{"@b": "AAAAAAAAAAE="}, {"@cls": ["myapp", "Outer.Inner"]}]}})
+        );
+        assert_eq!(json_to_pickle_value(&pg_json).unwrap(), val);
+        assert_pg_paths_match(&val, "", "");
+    }
+
+    #[test]
+    fn test_pg_compact_extended_refs() {
+        let oid = || PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, 7]);
+        let s = |v: &str| PickleValue::String(v.into());
+        let extended = |tag: &str, args: Vec<PickleValue>| {
+            PickleValue::PersistentRef(Box::new(PickleValue::List(vec![
+                s(tag),
+                PickleValue::Tuple(args),
+            ])))
+        };
+        let cases = vec![
+            (
+                PickleValue::PersistentRef(Box::new(PickleValue::List(vec![oid()]))),
+                json!({"@ref": ["0000000000000007"]}),
+            ),
+            (
+                extended("w", vec![oid()]),
+                json!({"@ref": {"oid": "0000000000000007", "weak": true}}),
+            ),
+            (
+                extended("w", vec![oid(), s("catalog")]),
+                json!({"@ref": {"oid": "0000000000000007", "weak": true, "db": "catalog"}}),
+            ),
+            (
+                extended("n", vec![s("catalog"), oid()]),
+                json!({"@ref": {"oid": "0000000000000007", "db": "catalog"}}),
+            ),
+            (
+                extended(
+                    "m",
+                    vec![
+                        s("catalog"),
+                        oid(),
+                        PickleValue::Global {
+                            module: "myapp.models".to_string(),
+                            name: "Index".to_string(),
+                        },
+                    ],
+                ),
+                json!({"@ref": {"oid": "0000000000000007", "db": "catalog", "cls": "myapp.models.Index"}}),
+            ),
+        ];
+        for (val, expected) in cases {
+            let pg_json = pickle_value_to_json_pg(&val).unwrap();
+            assert_eq!(pg_json, expected);
+            assert_pg_paths_match(&val, "", "");
+            let restored = crate::zodb::restore_persistent_refs(pg_json);
+            assert_eq!(json_to_pickle_value(&restored).unwrap(), val);
+        }
+    }
+
+    #[test]
+    fn test_pg_extended_ref_python2_tag_not_compacted() {
+        // Python 2 ZODB writes the tag as a byte string: keep the generic form
+        let val = PickleValue::PersistentRef(Box::new(PickleValue::List(vec![
+            PickleValue::Bytes(b"w".to_vec()),
+            PickleValue::Tuple(vec![PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, 7])]),
+        ])));
+        let pg_json = pickle_value_to_json_pg(&val).unwrap();
+        assert_eq!(
+            pg_json,
+            json!({"@ref": [{"@b": "dw=="}, {"@t": [{"@b": "AAAAAAAAAAc="}]}]})
+        );
+        assert_pg_paths_match(&val, "", "");
+    }
+
     // ── Direct JSON writer path tests ────────────────────────────────
 
     /// Helper: compare old path (serde_json::Value → to_string) vs new path (direct writer).
@@ -1082,6 +1792,11 @@ mod tests {
         // Old path
         let state_json = if let Some(info) = crate::btrees::classify_btree(module, name) {
             crate::btrees::btree_state_to_json(&info, val, &pickle_value_to_json_pg).unwrap()
+        } else if let Some(typed) =
+            known_types::try_container_state_to_typed_json(module, name, val, &pickle_value_to_json_pg)
+                .unwrap()
+        {
+            typed
         } else {
             pickle_value_to_json_pg(val).unwrap()
         };
@@ -1126,6 +1841,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_direct_float() {
         assert_pg_paths_match(&PickleValue::Float(3.14), "", "");
         assert_pg_paths_match(&PickleValue::Float(0.0), "", "");
@@ -1135,6 +1851,37 @@ mod tests {
         assert_pg_paths_match(&PickleValue::Float(f64::NEG_INFINITY), "", "");
     }
 
+    #[test]
+    fn test_nonfinite_float_markers() {
+        for (f, marker) in [(f64::INFINITY, "inf"), (f64::NEG_INFINITY, "-inf")] {
+            let json = pickle_value_to_json(&PickleValue::Float(f)).unwrap();
+            assert_eq!(json, json!({"@f": marker}));
+            assert_eq!(json_to_pickle_value(&json).unwrap(), PickleValue::Float(f));
+        }
+        let back = json_to_pickle_value(&json!({"@f": "nan"})).unwrap();
+        assert!(matches!(back, PickleValue::Float(f) if f.is_nan()));
+        assert!(json_to_pickle_value(&json!({"@f": "NaN"})).is_err());
+    }
+
+    #[test]
+    fn test_sorted_json_string() {
+        let s = |v: &str| PickleValue::String(v.into());
+        let i = PickleValue::Int;
+        let val = PickleValue::Dict(vec![
+            (s("z"), PickleValue::Dict(vec![(s("y"), i(1)), (s("x"), s("b"))])),
+            (s("a"), PickleValue::Dict(vec![(i(2), s("c")), (i(1), s("d"))])),
+        ]);
+        assert_eq!(
+            pickle_value_to_json_string_sorted(&val, None).unwrap(),
+            r#"{"a":{"@d":[[2,"c"],[1,"d"]]},"z":{"x":"b","y":1}}"#
+        );
+        // The serde path sorts too: both spellings parse to the same value
+        let sorted: Value =
+            serde_json::from_str(&pickle_value_to_json_string_sorted(&val, Some(2)).unwrap())
+                .unwrap();
+        assert_eq!(sorted, pickle_value_to_json(&val).unwrap());
+    }
+
     #[test]
     fn test_direct_string() {
         assert_pg_paths_match(&PickleValue::String("hello".into()), "", "");
@@ -1258,6 +2005,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_direct_blocked() {
+        let val = PickleValue::Blocked {
+            module: "os".into(),
+            name: "system".into(),
+        };
+        assert_pg_paths_match(&val, "", "");
+        let json = pickle_value_to_json(&val).unwrap();
+        assert_eq!(json, json!({"@blocked": ["os", "system"]}));
+        assert_eq!(json_to_pickle_value(&json).unwrap(), val);
+    }
+
     #[test]
     fn test_direct_instance() {
         let inst = PickleValue::Instance(Box::new(InstanceData {
@@ -1338,6 +2097,7 @@ mod tests {
             args: Box::new(args),
             dict_items: None,
             list_items: None,
+            newobj: false,
         }
     }
 
@@ -1587,6 +2347,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unusual_byte_groupings)]
     fn test_direct_uuid() {
         let int_val: u128 = 0x12345678_1234_5678_1234_5678_1234_5678;
         let bi = num_bigint::BigInt::from(int_val);
@@ -1643,6 +2404,7 @@ mod tests {
                 (PickleValue::String("x".into()), PickleValue::Int(1)),
             ])),
             list_items: None,
+            newobj: false,
         };
         assert_pg_paths_match(&val, "", "");
     }
@@ -1657,6 +2419,7 @@ mod tests {
             args: Box::new(PickleValue::Tuple(vec![])),
             dict_items: None,
             list_items: Some(Box::new(vec![PickleValue::Int(5)])),
+            newobj: false,
         };
         assert_pg_paths_match(&val, "", "");
     }
@@ -1772,6 +2535,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_direct_mixed_types_in_list() {
         let val = PickleValue::List(vec![
             PickleValue::None,
@@ -1791,7 +2555,7 @@ mod tests {
         let mut val = PickleValue::Int(42);
         for i in 0..10 {
             val = PickleValue::Dict(vec![(
-                PickleValue::String(format!("level_{i}")),
+                PickleValue::String(format!("level_{i}").into()),
                 val,
             )]);
         }
@@ -1817,6 +2581,8 @@ mod tests {
             ))),
         ]);
         assert_pg_paths_match(&state, "persistent.mapping", "PersistentMapping");
+        let wrapped = PickleValue::Dict(vec![(PickleValue::String("data".into()), state)]);
+        assert_pg_paths_match(&wrapped, "persistent.mapping", "PersistentMapping");
     }
 
     #[test]
@@ -1864,4 +2630,128 @@ mod tests {
         }));
         assert_pg_paths_match(&inst, "", "");
     }
+
+    #[test]
+    fn test_canonicalize_json_normalizes_markers() {
+        let doc = r#"{
+            "z": {"@ref": {"@t": [{"@b": "AAAAAAAAAAM="}, {"@cls": ["myapp", "Doc"]}]}},
+            "a": [{"@t": [1, 2]}, {"@dt": "2025-06-15T12:30:45.000000"}]
+        }"#;
+        let out = canonicalize_json(doc).unwrap();
+        assert_eq!(
+            out,
+            r#"{"a":[{"@t":[1,2]},{"@dt":"2025-06-15T12:30:45"}],"z":{"@ref":["0000000000000003","myapp.Doc"]}}"#
+        );
+        assert_eq!(canonicalize_json(&out).unwrap(), out);
+    }
+
+    #[test]
+    fn test_canonicalize_json_btree_record() {
+        let doc = r#"{"@s": {"@next": {"@ref": "000000000000000A"}, "@kv": [["a", 1]]},
+                      "@cls": ["BTrees.OOBTree", "OOBucket"]}"#;
+        assert_eq!(
+            canonicalize_json(doc).unwrap(),
+            r#"{"@cls":["BTrees.OOBTree","OOBucket"],"@s":{"@kv":[["a",1]],"@next":{"@ref":"000000000000000a"}}}"#
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_json_errors() {
+        assert!(matches!(canonicalize_json("{"), Err(CodecError::Json(_))));
+        assert!(matches!(
+            canonicalize_json(r#"{"@cls": "x", "@s": null}"#),
+            Err(CodecError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn test_bytes_keys_marker_restores_bytes() {
+        let json = json!({"@bk": true, "title": "x", "_objects": []});
+        let val = json_to_pickle_value(&json).unwrap();
+        let PickleValue::Dict(pairs) = val else { panic!("expected dict") };
+        assert_eq!(pairs.len(), 2);
+        assert!(pairs.contains(&(PickleValue::Bytes(b"_objects".to_vec()), PickleValue::List(vec![]))));
+        assert!(pairs.contains(&(
+            PickleValue::Bytes(b"title".to_vec()),
+            PickleValue::String("x".into())
+        )));
+        // Only `true` annotates; other values are a plain key
+        let val = json_to_pickle_value(&json!({"@bk": 1, "a": 2})).unwrap();
+        let PickleValue::Dict(pairs) = val else { panic!("expected dict") };
+        assert!(pairs.iter().all(|(k, _)| matches!(k, PickleValue::String(_))));
+    }
+
+    #[test]
+    fn test_anonymous_instance_json_roundtrip() {
+        // getattr(myapp.Point, "restore")(3) with state and SETITEMS
+        let callable = PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global {
+                module: "builtins".into(),
+                name: "getattr".into(),
+            }),
+            args: Box::new(PickleValue::Tuple(vec![
+                PickleValue::Global {
+                    module: "myapp".into(),
+                    name: "Point".into(),
+                },
+                PickleValue::String("restore".into()),
+            ])),
+            dict_items: None,
+            list_items: None,
+            newobj: false,
+        };
+        let mut inst = InstanceData::anonymous_reduce(
+            callable,
+            PickleValue::Tuple(vec![PickleValue::Int(3)]),
+            PickleValue::Dict(vec![(PickleValue::String("label".into()), PickleValue::String("p".into()))]),
+        );
+        inst.dict_items = Some(Box::new(vec![(PickleValue::Int(1), PickleValue::None)]));
+        let val = PickleValue::Instance(Box::new(inst));
+        let json = pickle_value_to_json(&val).unwrap();
+        assert_eq!(json["@inst"]["@args"], serde_json::json!({"@t": [3]}));
+        assert_eq!(json["@items"], serde_json::json!([[1, null]]));
+        // Key order of the state dict may differ; the opcodes may not
+        assert_eq!(
+            crate::encode::encode_pickle(&json_to_pickle_value(&json).unwrap()).unwrap(),
+            crate::encode::encode_pickle(&val).unwrap()
+        );
+        let pg = pickle_value_to_json_string_pg(&val, "", "").unwrap();
+        let pg: Value = serde_json::from_str(&pg).unwrap();
+        assert_eq!(pg["@inst"]["@state"], serde_json::json!({"label": "p"}));
+        assert_eq!(pg["@items"], serde_json::json!([[1, null]]));
+    }
+
+    #[test]
+    fn test_shared_markers_roundtrip() {
+        let val = PickleValue::Shared {
+            id: 0,
+            value: Box::new(PickleValue::List(vec![PickleValue::BackRef(0)])),
+        };
+        let json = pickle_value_to_json(&val).unwrap();
+        assert_eq!(json, serde_json::json!({"@shared": [0, [{"@backref": 0}]]}));
+        assert_eq!(json_to_pickle_value(&json).unwrap(), val);
+        let pg = pickle_value_to_json_string_pg(&val, "", "").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&pg).unwrap(), json);
+        // Malformed markers stay plain dicts
+        for bad in [serde_json::json!({"@backref": -1}), serde_json::json!({"@shared": [0]})] {
+            assert!(matches!(json_to_pickle_value(&bad).unwrap(), PickleValue::Dict(_)));
+        }
+    }
+
+    #[test]
+    fn test_backref_key_sorted_before_shared() {
+        // {'b': d, 'a': d}: the object keys come back sorted
+        let json = serde_json::json!({"b": {"@shared": [0, [1]]}, "a": {"@backref": 0}});
+        let val = json_to_pickle_value(&json).unwrap();
+        for bytes in [
+            crate::encode::encode_pickle(&val).unwrap(),
+            crate::protocol0::encode_pickle_protocol0(&val).unwrap(),
+        ] {
+            let decoded = crate::decode::decode_pickle(&bytes).unwrap();
+            let list = PickleValue::List(vec![PickleValue::Int(1)]);
+            let pairs = [("a", &list), ("b", &list)];
+            let expected = pairs.map(|(k, v)| (PickleValue::String(k.into()), v.clone()));
+            assert_eq!(decoded, PickleValue::Dict(expected.to_vec()));
+        }
+    }
 }
@@ -4,21 +4,38 @@
 use std::fmt::Write;
 
 /// A low-level JSON token writer that appends directly to a String buffer.
+///
+/// Output is compact unless an indent is set with
+/// [`with_indent`](Self::with_indent), in which case it is laid out like
+/// `serde_json::to_string_pretty`: one item per line and `": "` after keys.
 pub struct JsonWriter {
     buf: String,
+    /// Spaces per nesting level; `None` for compact output.
+    indent: Option<usize>,
+    level: usize,
+    /// A container was just opened and nothing written into it yet.
+    open: bool,
 }
 
 impl JsonWriter {
     #[cfg(test)]
     pub fn new() -> Self {
-        Self {
-            buf: String::new(),
-        }
+        Self::with_capacity(0)
     }
 
     pub fn with_capacity(cap: usize) -> Self {
+        Self::with_indent(cap, None)
+    }
+
+    /// A writer for pretty output with `indent` spaces per level, or
+    /// compact output for `None`. As in Python's `json.dumps`, an indent of
+    /// 0 still puts every item on its own line.
+    pub fn with_indent(cap: usize, indent: Option<usize>) -> Self {
         Self {
             buf: String::with_capacity(cap),
+            indent,
+            level: 0,
+            open: false,
         }
     }
 
@@ -43,27 +60,81 @@ impl JsonWriter {
     /// Clear the buffer while retaining capacity.
     pub fn clear(&mut self) {
         self.buf.clear();
+        self.level = 0;
+        self.open = false;
+    }
+
+    // -- Layout --
+
+    /// Start the first item of a just opened container on its own line.
+    #[inline]
+    fn before_value(&mut self) {
+        if self.open {
+            self.open = false;
+            self.newline();
+        }
+    }
+
+    fn newline(&mut self) {
+        self.buf.push('\n');
+        for _ in 0..self.level * self.indent.unwrap_or(0) {
+            self.buf.push(' ');
+        }
+    }
+
+    #[inline]
+    fn open_container(&mut self, bracket: char) {
+        self.before_value();
+        self.buf.push(bracket);
+        if self.indent.is_some() {
+            self.level += 1;
+            self.open = true;
+        }
+    }
+
+    #[inline]
+    fn close_container(&mut self, bracket: char) {
+        if self.indent.is_some() {
+            self.level -= 1;
+            if self.open {
+                // Empty container stays on one line
+                self.open = false;
+            } else {
+                self.newline();
+            }
+        }
+        self.buf.push(bracket);
     }
 
     // -- Primitives --
 
     #[inline]
     pub fn write_null(&mut self) {
+        self.before_value();
         self.buf.push_str("null");
     }
 
     #[inline]
     pub fn write_bool(&mut self, b: bool) {
+        self.before_value();
         self.buf.push_str(if b { "true" } else { "false" });
     }
 
     #[inline]
     pub fn write_i64(&mut self, n: i64) {
+        self.before_value();
+        let _ = write!(self.buf, "{n}");
+    }
+
+    #[inline]
+    pub fn write_i128(&mut self, n: i128) {
+        self.before_value();
         let _ = write!(self.buf, "{n}");
     }
 
     #[inline]
     pub fn write_f64(&mut self, f: f64) {
+        self.before_value();
         if f.is_nan() || f.is_infinite() {
             // Match serde_json behavior: NaN/Infinity → null
             self.buf.push_str("null");
@@ -77,6 +148,7 @@ impl JsonWriter {
     /// Write a JSON-escaped string (with surrounding quotes).
     #[inline]
     pub fn write_string(&mut self, s: &str) {
+        self.before_value();
         self.buf.push('"');
         write_escaped(&mut self.buf, s);
         self.buf.push('"');
@@ -86,56 +158,78 @@ impl JsonWriter {
     /// SAFETY: caller must guarantee `s` contains no characters that need JSON escaping.
     #[inline]
     pub fn write_string_literal(&mut self, s: &str) {
+        self.before_value();
         self.buf.push('"');
         self.buf.push_str(s);
         self.buf.push('"');
     }
 
+    /// Write `data` as a quoted base64 string, encoding straight into the
+    /// output buffer.
+    #[inline]
+    pub fn write_base64(&mut self, data: &[u8]) {
+        self.before_value();
+        self.buf.push('"');
+        crate::binenc::b64_encode_into(data, &mut self.buf);
+        self.buf.push('"');
+    }
+
     // -- Containers --
 
     #[inline]
     pub fn begin_object(&mut self) {
-        self.buf.push('{');
+        self.open_container('{');
     }
 
     #[inline]
     pub fn end_object(&mut self) {
-        self.buf.push('}');
+        self.close_container('}');
     }
 
     #[inline]
     pub fn begin_array(&mut self) {
-        self.buf.push('[');
+        self.open_container('[');
     }
 
     #[inline]
     pub fn end_array(&mut self) {
-        self.buf.push(']');
+        self.close_container(']');
     }
 
     /// Write `"key":` — a JSON object key followed by colon.
     #[inline]
     pub fn write_key(&mut self, key: &str) {
         self.write_string(key);
-        self.buf.push(':');
+        self.key_separator();
     }
 
     /// Write a key that is known to need no escaping.
     #[inline]
     pub fn write_key_literal(&mut self, key: &str) {
+        self.before_value();
         self.buf.push('"');
         self.buf.push_str(key);
-        self.buf.push_str("\":");
+        self.buf.push('"');
+        self.key_separator();
+    }
+
+    #[inline]
+    fn key_separator(&mut self) {
+        self.buf.push_str(if self.indent.is_some() { ": " } else { ":" });
     }
 
     #[inline]
     pub fn write_comma(&mut self) {
         self.buf.push(',');
+        if self.indent.is_some() {
+            self.newline();
+        }
     }
 
     /// Write a raw string directly to the buffer (for pre-formatted content).
     #[inline]
     pub fn write_raw(&mut self, s: &str) {
+        self.before_value();
         self.buf.push_str(s);
     }
 }
@@ -230,6 +324,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_f64() {
         let mut w = JsonWriter::new();
         w.write_f64(3.14);
@@ -387,4 +482,26 @@ mod tests {
         w.end_object();
         assert_eq!(w.into_string(), r#"{"@dt":"2025-01-01"}"#);
     }
+
+    #[test]
+    fn test_indent_matches_serde_pretty() {
+        let mut w = JsonWriter::with_indent(0, Some(2));
+        w.begin_object();
+        w.write_key("a");
+        w.begin_array();
+        w.write_i64(1);
+        w.write_comma();
+        w.begin_object();
+        w.end_object();
+        w.write_comma();
+        w.begin_array();
+        w.end_array();
+        w.end_array();
+        w.write_comma();
+        w.write_key_literal("b");
+        w.write_null();
+        w.end_object();
+        let expected = serde_json::to_string_pretty(&serde_json::json!({"a": [1, {}, []], "b": null}));
+        assert_eq!(w.into_string(), expected.unwrap());
+    }
 }
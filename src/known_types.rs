@@ -4,21 +4,28 @@
 //! `@reduce` JSON, we use compact typed markers (`@dt`, `@date`, `@dec`, etc.)
 //! that are human-readable and queryable in PostgreSQL JSONB.
 
+use std::cell::Cell;
+
 use serde_json::{json, Map, Value};
 
+use crate::binenc::{hex_decode, hex_encode};
 use crate::error::CodecError;
 use crate::json_writer::JsonWriter;
+use crate::registry::{self, Payload};
 use crate::types::{InstanceData, PickleValue};
+use crate::zodb::{compact_class_path, split_class_path};
 
 // ---------------------------------------------------------------------------
 // Forward direction: PickleValue → typed JSON
 // ---------------------------------------------------------------------------
 
 /// Try to convert a known REDUCE pattern to compact typed JSON.
+/// `dict_items` are the SETITEMS applied after the REDUCE.
 /// Returns Ok(None) if the callable is not recognized.
 pub fn try_reduce_to_typed_json(
     callable: &PickleValue,
     args: &PickleValue,
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
     to_json: &dyn Fn(&PickleValue) -> Result<Value, CodecError>,
 ) -> Result<Option<Value>, CodecError> {
     let (module, name) = match callable {
@@ -32,9 +39,28 @@ pub fn try_reduce_to_typed_json(
         ("datetime", "time") => try_encode_time(args, to_json),
         ("datetime", "timedelta") => try_encode_timedelta(args),
         ("decimal", "Decimal") => try_encode_decimal(args),
+        (m, "complex") if is_builtins_module(m) => {
+            Ok(complex_parts(args).map(|(re, im)| json!({"@complex": [re, im]})))
+        }
+        ("fractions", "Fraction") => Ok(fraction_string(args).map(|s| json!({"@frac": s}))),
         ("builtins", "set") => try_encode_set(args, to_json),
         ("builtins", "frozenset") => try_encode_frozenset(args, to_json),
-        _ => Ok(None),
+        ("collections", "OrderedDict") => try_encode_odict(args, dict_items, to_json),
+        ("collections", "defaultdict") => try_encode_ddict(args, dict_items, to_json),
+        (PROVIDES_MODULE, "Provides") => {
+            Ok(provides_names(args).map(|names| json!({"@provides": names})))
+        }
+        (m, "TimeStamp") if is_timestamp_module(m) => {
+            Ok(timestamp_raw(args).map(|raw| tid_json(raw, Some(m))))
+        }
+        _ => match registry::reduce_payload(module, name, args, dict_items) {
+            Some((handler, Payload::One(arg))) => Ok(Some(json!({ &handler.marker: to_json(arg)? }))),
+            Some((handler, Payload::Many(args))) => {
+                let args: Vec<Value> = args.iter().map(to_json).collect::<Result<_, _>>()?;
+                Ok(Some(json!({ &handler.marker: args })))
+            }
+            None => Ok(None),
+        },
     }
 }
 
@@ -44,11 +70,18 @@ pub fn try_instance_to_typed_json(
     module: &str,
     name: &str,
     state: &PickleValue,
-    _to_json: &dyn Fn(&PickleValue) -> Result<Value, CodecError>,
+    to_json: &dyn Fn(&PickleValue) -> Result<Value, CodecError>,
 ) -> Result<Option<Value>, CodecError> {
     match (module, name) {
         ("uuid", "UUID") => try_encode_uuid(state),
-        _ => Ok(None),
+        (ZDT_MODULE, "DateTime") => try_encode_zdt(state, to_json),
+        _ => match try_container_state_to_typed_json(module, name, state, to_json)? {
+            Some(typed) => Ok(Some(typed)),
+            None => match registry::instance_handler(module, name) {
+                Some(handler) => Ok(Some(json!({ &handler.marker: to_json(state)? }))),
+                None => Ok(None),
+            },
+        },
     }
 }
 
@@ -62,6 +95,7 @@ pub fn try_write_reduce_typed(
     w: &mut JsonWriter,
     callable: &PickleValue,
     args: &PickleValue,
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
     write_val: &dyn Fn(&mut JsonWriter, &PickleValue) -> Result<(), CodecError>,
 ) -> Result<bool, CodecError> {
     let (module, name) = match callable {
@@ -75,9 +109,43 @@ pub fn try_write_reduce_typed(
         ("datetime", "time") => write_time(w, args, write_val),
         ("datetime", "timedelta") => write_timedelta(w, args),
         ("decimal", "Decimal") => write_decimal(w, args),
+        (m, "complex") if is_builtins_module(m) => Ok(write_complex(w, args)),
+        ("fractions", "Fraction") => Ok(write_fraction(w, args)),
         ("builtins", "set") => write_set(w, args, write_val),
         ("builtins", "frozenset") => write_frozenset(w, args, write_val),
-        _ => Ok(false),
+        ("collections", "OrderedDict") => write_odict(w, args, dict_items, write_val),
+        ("collections", "defaultdict") => write_ddict(w, args, dict_items, write_val),
+        (PROVIDES_MODULE, "Provides") => Ok(write_provides(w, args)),
+        (m, "TimeStamp") if is_timestamp_module(m) => match timestamp_raw(args) {
+            Some(raw) => {
+                write_tid(w, raw, Some(m));
+                Ok(true)
+            }
+            None => Ok(false),
+        },
+        _ => {
+            let Some((handler, payload)) = registry::reduce_payload(module, name, args, dict_items)
+            else {
+                return Ok(false);
+            };
+            w.begin_object();
+            w.write_key(&handler.marker);
+            match payload {
+                Payload::One(arg) => write_val(w, arg)?,
+                Payload::Many(args) => {
+                    w.begin_array();
+                    for (i, arg) in args.iter().enumerate() {
+                        if i > 0 {
+                            w.write_comma();
+                        }
+                        write_val(w, arg)?;
+                    }
+                    w.end_array();
+                }
+            }
+            w.end_object();
+            Ok(true)
+        }
     }
 }
 
@@ -88,10 +156,24 @@ pub fn try_write_instance_typed(
     module: &str,
     name: &str,
     state: &PickleValue,
+    write_val: &dyn Fn(&mut JsonWriter, &PickleValue) -> Result<(), CodecError>,
 ) -> Result<bool, CodecError> {
     match (module, name) {
         ("uuid", "UUID") => write_uuid(w, state),
-        _ => Ok(false),
+        (ZDT_MODULE, "DateTime") => write_zdt(w, state, write_val),
+        _ => {
+            if try_write_container_state(w, module, name, state, write_val)? {
+                return Ok(true);
+            }
+            let Some(handler) = registry::instance_handler(module, name) else {
+                return Ok(false);
+            };
+            w.begin_object();
+            w.write_key(&handler.marker);
+            write_val(w, state)?;
+            w.end_object();
+            Ok(true)
+        }
     }
 }
 
@@ -126,7 +208,7 @@ fn write_datetime(
         let to_json_for_tz = |v: &PickleValue| -> Result<Value, CodecError> {
             // For pytz args, we need to produce Values
             match v {
-                PickleValue::String(s) => Ok(Value::String(s.clone())),
+                PickleValue::String(s) => Ok(Value::String(s.to_string())),
                 PickleValue::Int(i) => Ok(serde_json::json!(*i)),
                 _ => Ok(Value::Null),
             }
@@ -252,7 +334,7 @@ fn write_time(
     } else if tuple_items.len() == 2 {
         let to_json_for_tz = |v: &PickleValue| -> Result<Value, CodecError> {
             match v {
-                PickleValue::String(s) => Ok(Value::String(s.clone())),
+                PickleValue::String(s) => Ok(Value::String(s.to_string())),
                 PickleValue::Int(i) => Ok(serde_json::json!(*i)),
                 _ => Ok(Value::Null),
             }
@@ -371,6 +453,34 @@ fn write_decimal(w: &mut JsonWriter, args: &PickleValue) -> Result<bool, CodecEr
     Ok(true)
 }
 
+fn write_complex(w: &mut JsonWriter, args: &PickleValue) -> bool {
+    let Some((re, im)) = complex_parts(args) else {
+        return false;
+    };
+    // {"@complex": [re, im]}
+    w.begin_object();
+    w.write_key_literal("@complex");
+    w.begin_array();
+    w.write_f64(re);
+    w.write_comma();
+    w.write_f64(im);
+    w.end_array();
+    w.end_object();
+    true
+}
+
+fn write_fraction(w: &mut JsonWriter, args: &PickleValue) -> bool {
+    let Some(s) = fraction_string(args) else {
+        return false;
+    };
+    // {"@frac": "n/d"}
+    w.begin_object();
+    w.write_key_literal("@frac");
+    w.write_string(&s);
+    w.end_object();
+    true
+}
+
 fn write_set(
     w: &mut JsonWriter,
     args: &PickleValue,
@@ -437,7 +547,7 @@ fn write_uuid(w: &mut JsonWriter, state: &PickleValue) -> Result<bool, CodecErro
 
     for (k, v) in pairs {
         if let PickleValue::String(key) = k {
-            if key == "int" {
+            if &**key == "int" {
                 let int_val = match v {
                     PickleValue::Int(i) => *i as u128,
                     PickleValue::BigInt(bi) => {
@@ -472,6 +582,143 @@ fn write_uuid(w: &mut JsonWriter, state: &PickleValue) -> Result<bool, CodecErro
     Ok(false)
 }
 
+/// Write the state of a PersistentMapping/PersistentList as its marker.
+/// Returns Ok(false) if the class or the state shape doesn't match.
+pub fn try_write_container_state(
+    w: &mut JsonWriter,
+    module: &str,
+    name: &str,
+    state: &PickleValue,
+    write_val: &dyn Fn(&mut JsonWriter, &PickleValue) -> Result<(), CodecError>,
+) -> Result<bool, CodecError> {
+    let Some((marker, data)) = container_data(module, name, state) else {
+        return Ok(false);
+    };
+    // {"@pmap": {...}} / {"@plist": [...]} / {"@rel": {...}} / {"@len": n}
+    w.begin_object();
+    w.write_key_literal(marker);
+    write_val(w, data)?;
+    w.end_object();
+    Ok(true)
+}
+
+fn write_odict(
+    w: &mut JsonWriter,
+    args: &PickleValue,
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
+    write_val: &dyn Fn(&mut JsonWriter, &PickleValue) -> Result<(), CodecError>,
+) -> Result<bool, CodecError> {
+    let Some(pairs) = odict_pairs(args, dict_items) else {
+        return Ok(false);
+    };
+    // {"@odict": [[k, v], ...]}
+    w.begin_object();
+    w.write_key_literal("@odict");
+    w.begin_array();
+    for (i, (k, v)) in pairs.into_iter().enumerate() {
+        if i > 0 {
+            w.write_comma();
+        }
+        w.begin_array();
+        write_val(w, k)?;
+        w.write_comma();
+        write_val(w, v)?;
+        w.end_array();
+    }
+    w.end_array();
+    w.end_object();
+    Ok(true)
+}
+
+fn write_ddict(
+    w: &mut JsonWriter,
+    args: &PickleValue,
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
+    write_val: &dyn Fn(&mut JsonWriter, &PickleValue) -> Result<(), CodecError>,
+) -> Result<bool, CodecError> {
+    let Some(factory) = ddict_factory(args) else {
+        return Ok(false);
+    };
+    // {"@ddict": {"factory": ..., "items": {...}}}
+    w.begin_object();
+    w.write_key_literal("@ddict");
+    w.begin_object();
+    w.write_key_literal("factory");
+    write_val(w, factory)?;
+    w.write_comma();
+    w.write_key_literal("items");
+    write_val(w, &PickleValue::Dict(dict_items.unwrap_or_default().to_vec()))?;
+    w.end_object();
+    w.end_object();
+    Ok(true)
+}
+
+fn write_provides(w: &mut JsonWriter, args: &PickleValue) -> bool {
+    let Some(names) = provides_names(args) else {
+        return false;
+    };
+    // {"@provides": ["mod.Class", "mod.IFoo", ...]}
+    w.begin_object();
+    w.write_key_literal("@provides");
+    w.begin_array();
+    for (i, name) in names.iter().enumerate() {
+        if i > 0 {
+            w.write_comma();
+        }
+        w.write_string(name);
+    }
+    w.end_array();
+    w.end_object();
+    true
+}
+
+fn write_zdt(
+    w: &mut JsonWriter,
+    state: &PickleValue,
+    write_val: &dyn Fn(&mut JsonWriter, &PickleValue) -> Result<(), CodecError>,
+) -> Result<bool, CodecError> {
+    let Some(iso) = zdt_iso(state) else {
+        return Ok(false);
+    };
+    // {"@zdt": iso, "@zdt_raw": [micros, naive, tz]}
+    w.begin_object();
+    w.write_key_literal("@zdt");
+    w.write_string(&iso);
+    w.write_comma();
+    w.write_key_literal("@zdt_raw");
+    match state {
+        PickleValue::Tuple(items) => {
+            w.begin_array();
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    w.write_comma();
+                }
+                write_val(w, item)?;
+            }
+            w.end_array();
+        }
+        _ => write_val(w, state)?,
+    }
+    w.end_object();
+    Ok(true)
+}
+
+/// Write a `@tid` marker: `{"@tid": [hex, iso]}` (+ module for TimeStamp).
+pub fn write_tid(w: &mut JsonWriter, raw: &[u8; 8], module: Option<&str>) {
+    w.begin_object();
+    w.write_key_literal("@tid");
+    w.begin_array();
+    w.write_string_literal(&hex_encode(raw));
+    w.write_comma();
+    w.write_string_literal(&format_tid_iso(raw));
+    if let Some(m) = module {
+        w.write_comma();
+        w.write_string(m);
+    }
+    w.end_array();
+    w.end_object();
+}
+
 /// Write a serde_json::Value to the JsonWriter (bridge for tz args).
 fn write_serde_value(w: &mut JsonWriter, val: &Value) {
     match val {
@@ -536,9 +783,56 @@ pub fn try_typed_json_to_pickle_value(
     if let Some(v) = map.get("@dec") {
         return try_decode_decimal(v).map(Some);
     }
+    if let Some(v) = map.get("@complex") {
+        return try_decode_complex(v).map(Some);
+    }
+    if let Some(v) = map.get("@frac") {
+        let s = v
+            .as_str()
+            .ok_or_else(|| CodecError::InvalidData("@frac must be a string".into()))?;
+        return fraction_reduce(s).map(Some);
+    }
     if let Some(v) = map.get("@uuid") {
         return try_decode_uuid(v).map(Some);
     }
+    if let Some(v) = map.get("@zdt") {
+        let state = match map.get("@zdt_raw") {
+            Some(Value::Array(items)) => {
+                PickleValue::Tuple(items.iter().map(from_json).collect::<Result<_, _>>()?)
+            }
+            Some(raw) => from_json(raw)?,
+            None => zdt_state_from_iso(v.as_str().ok_or_else(|| {
+                CodecError::InvalidData("@zdt must be a string".into())
+            })?)?,
+        };
+        return Ok(Some(zdt_instance(state)));
+    }
+    if let Some(v) = map.get("@provides") {
+        let names: Option<Vec<&str>> = v
+            .as_array()
+            .and_then(|arr| arr.iter().map(Value::as_str).collect());
+        return provides_reduce(&names.unwrap_or_default()).map(Some);
+    }
+    if let Some(v) = map.get("@tid") {
+        return try_decode_tid(v).map(Some);
+    }
+    if let Some(v) = map.get("@odict") {
+        return try_decode_odict(v, from_json).map(Some);
+    }
+    if let Some(v) = map.get("@ddict") {
+        return try_decode_ddict(v, from_json).map(Some);
+    }
+    for (marker, _, _) in CONTAINER_CLASSES {
+        if let Some(v) = map.get(*marker) {
+            return container_instance(marker, from_json(v)?).map(Some);
+        }
+    }
+    if map.len() == 1 {
+        let (key, v) = map.iter().next().unwrap();
+        if let Some(handler) = registry::for_marker(key) {
+            return handler.build(from_json(v)?).map(Some);
+        }
+    }
     Ok(None)
 }
 
@@ -610,13 +904,13 @@ pub fn extract_tz_info(
                     // pytz._p('US/Eastern', -18000, 0, 'EST')
                     ("pytz", "_p") => {
                         if let PickleValue::Tuple(items) = args.as_ref() {
-                            if items.len() >= 1 {
+                            if !items.is_empty() {
                                 if let PickleValue::String(tz_name) = &items[0] {
                                     // Collect all args as JSON for roundtrip
                                     let args_json: Result<Vec<Value>, _> =
-                                        items.iter().map(|i| to_json(i)).collect();
+                                        items.iter().map(to_json).collect();
                                     return Ok(Some(TzInfo::Pytz {
-                                        name: tz_name.clone(),
+                                        name: tz_name.to_string(),
                                         args: args_json?,
                                     }));
                                 }
@@ -638,9 +932,9 @@ pub fn extract_tz_info(
                 if let PickleValue::Global { module, name } = inner_callable.as_ref() {
                     if module == "builtins" && name == "getattr" {
                         if let PickleValue::Tuple(outer_args) = args.as_ref() {
-                            if outer_args.len() >= 1 {
+                            if !outer_args.is_empty() {
                                 if let PickleValue::String(tz_key) = &outer_args[0] {
-                                    return Ok(Some(TzInfo::ZoneInfo(tz_key.clone())));
+                                    return Ok(Some(TzInfo::ZoneInfo(tz_key.to_string())));
                                 }
                             }
                         }
@@ -876,7 +1170,116 @@ fn try_encode_decimal(args: &PickleValue) -> Result<Option<Value>, CodecError> {
         _ => return Ok(None),
     };
 
-    Ok(Some(json!({"@dec": s})))
+    Ok(Some(json!({"@dec": &**s})))
+}
+
+// ===========================================================================
+// builtins.complex / fractions.Fraction
+// ===========================================================================
+
+/// Whether `module` holds the builtin types (`__builtin__` in Python 2
+/// pickles, which protocols 0-2 keep writing).
+pub fn is_builtins_module(module: &str) -> bool {
+    module == "builtins" || module == "__builtin__"
+}
+
+/// Real and imaginary part of a `complex(re, im)` REDUCE. Non-finite
+/// parts have no JSON number and keep the generic form.
+pub fn complex_parts(args: &PickleValue) -> Option<(f64, f64)> {
+    match args {
+        PickleValue::Tuple(items) => match items.as_slice() {
+            [PickleValue::Float(re), PickleValue::Float(im)] if re.is_finite() && im.is_finite() => {
+                Some((*re, *im))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// `"n/d"` text of a `Fraction` REDUCE, like `str(Fraction)` (just `"n"`
+/// for a denominator of 1). Recent Pythons pickle the numerator and
+/// denominator; older ones pickle `str(self)`.
+pub fn fraction_string(args: &PickleValue) -> Option<String> {
+    let PickleValue::Tuple(items) = args else {
+        return None;
+    };
+    match items.as_slice() {
+        [num, den] => {
+            let (num, den) = (integer_text(num)?, integer_text(den)?);
+            if den.starts_with('-') || den == "0" {
+                return None;
+            }
+            Some(if den == "1" { num } else { format!("{num}/{den}") })
+        }
+        [PickleValue::String(s)] => fraction_parts(s).map(|_| s.to_string()),
+        _ => None,
+    }
+}
+
+fn integer_text(val: &PickleValue) -> Option<String> {
+    match val {
+        PickleValue::Int(i) => Some(i.to_string()),
+        PickleValue::BigInt(bi) => Some(bi.to_string()),
+        _ => None,
+    }
+}
+
+/// Numerator and denominator of an `"n/d"` or `"n"` string.
+fn fraction_parts(s: &str) -> Option<(PickleValue, PickleValue)> {
+    let (num, den) = s.split_once('/').unwrap_or((s, "1"));
+    let den = parse_integer(den).filter(|d| !den.starts_with('-') && *d != PickleValue::Int(0))?;
+    Some((parse_integer(num)?, den))
+}
+
+fn parse_integer(text: &str) -> Option<PickleValue> {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match text.parse::<i64>() {
+        Ok(i) => Some(PickleValue::Int(i)),
+        Err(_) => text.parse().ok().map(PickleValue::BigInt),
+    }
+}
+
+/// `complex(re, im)`.
+pub fn complex_reduce(re: f64, im: f64) -> PickleValue {
+    PickleValue::Reduce {
+        callable: Box::new(PickleValue::Global {
+            module: "builtins".into(),
+            name: "complex".into(),
+        }),
+        args: Box::new(PickleValue::Tuple(vec![PickleValue::Float(re), PickleValue::Float(im)])),
+        dict_items: None,
+        list_items: None,
+        newobj: false,
+    }
+}
+
+/// `Fraction(numerator, denominator)` for an `"n/d"` string.
+pub fn fraction_reduce(s: &str) -> Result<PickleValue, CodecError> {
+    let (num, den) = fraction_parts(s)
+        .ok_or_else(|| CodecError::InvalidData(format!("invalid @frac value: {s:?}")))?;
+    Ok(PickleValue::Reduce {
+        callable: Box::new(PickleValue::Global {
+            module: "fractions".into(),
+            name: "Fraction".into(),
+        }),
+        args: Box::new(PickleValue::Tuple(vec![num, den])),
+        dict_items: None,
+        list_items: None,
+        newobj: false,
+    })
+}
+
+fn try_decode_complex(val: &Value) -> Result<PickleValue, CodecError> {
+    let parts = val
+        .as_array()
+        .filter(|parts| parts.len() == 2)
+        .and_then(|parts| Some((parts[0].as_f64()?, parts[1].as_f64()?)))
+        .ok_or_else(|| CodecError::InvalidData("@complex must be [real, imag]".into()))?;
+    Ok(complex_reduce(parts.0, parts.1))
 }
 
 // ===========================================================================
@@ -898,7 +1301,7 @@ fn try_encode_set(
         _ => return Ok(None),
     };
 
-    let arr: Result<Vec<Value>, _> = list_items.iter().map(|i| to_json(i)).collect();
+    let arr: Result<Vec<Value>, _> = list_items.iter().map(to_json).collect();
     Ok(Some(json!({"@set": arr?})))
 }
 
@@ -916,10 +1319,326 @@ fn try_encode_frozenset(
         _ => return Ok(None),
     };
 
-    let arr: Result<Vec<Value>, _> = list_items.iter().map(|i| to_json(i)).collect();
+    let arr: Result<Vec<Value>, _> = list_items.iter().map(to_json).collect();
     Ok(Some(json!({"@fset": arr?})))
 }
 
+// ===========================================================================
+// collections.OrderedDict / collections.defaultdict (REDUCE + SETITEMS)
+// ===========================================================================
+
+/// Items of an `OrderedDict` REDUCE: `OrderedDict()` followed by SETITEMS
+/// (Python 3), or Python 2's `OrderedDict([[k, v], ...])`.
+pub fn odict_pairs<'a>(
+    args: &'a PickleValue,
+    dict_items: Option<&'a [(PickleValue, PickleValue)]>,
+) -> Option<Vec<(&'a PickleValue, &'a PickleValue)>> {
+    match (args, dict_items) {
+        (PickleValue::Tuple(items), _) if items.is_empty() => {
+            Some(dict_items.unwrap_or_default().iter().map(|(k, v)| (k, v)).collect())
+        }
+        (PickleValue::Tuple(items), None) => match items.as_slice() {
+            [PickleValue::List(pairs)] => pairs
+                .iter()
+                .map(|pair| match pair {
+                    PickleValue::List(kv) | PickleValue::Tuple(kv) if kv.len() == 2 => {
+                        Some((&kv[0], &kv[1]))
+                    }
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Default factory of a `defaultdict(factory)` REDUCE. A `defaultdict`
+/// without a factory is pickled as `defaultdict()`.
+pub fn ddict_factory(args: &PickleValue) -> Option<&PickleValue> {
+    static NO_FACTORY: PickleValue = PickleValue::None;
+    match args {
+        PickleValue::Tuple(items) => match items.as_slice() {
+            [] => Some(&NO_FACTORY),
+            [factory] => Some(factory),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn try_encode_odict(
+    args: &PickleValue,
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
+    to_json: &dyn Fn(&PickleValue) -> Result<Value, CodecError>,
+) -> Result<Option<Value>, CodecError> {
+    let Some(pairs) = odict_pairs(args, dict_items) else {
+        return Ok(None);
+    };
+    let arr: Result<Vec<Value>, CodecError> = pairs
+        .into_iter()
+        .map(|(k, v)| Ok(json!([to_json(k)?, to_json(v)?])))
+        .collect();
+    Ok(Some(json!({"@odict": arr?})))
+}
+
+fn try_encode_ddict(
+    args: &PickleValue,
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
+    to_json: &dyn Fn(&PickleValue) -> Result<Value, CodecError>,
+) -> Result<Option<Value>, CodecError> {
+    let Some(factory) = ddict_factory(args) else {
+        return Ok(None);
+    };
+    // The items go through the regular dict conversion (string keys,
+    // @d pairs, @bk), so they are queryable like any other dict
+    let items = PickleValue::Dict(dict_items.unwrap_or_default().to_vec());
+    Ok(Some(json!({"@ddict": {"factory": to_json(factory)?, "items": to_json(&items)?}})))
+}
+
+/// `OrderedDict()` followed by SETITEMS of `pairs`.
+pub fn odict_reduce(pairs: Vec<(PickleValue, PickleValue)>) -> PickleValue {
+    dict_reduce("OrderedDict", PickleValue::Tuple(vec![]), pairs)
+}
+
+/// `defaultdict(factory)` followed by SETITEMS of `pairs`.
+pub fn ddict_reduce(factory: PickleValue, pairs: Vec<(PickleValue, PickleValue)>) -> PickleValue {
+    let args = match factory {
+        PickleValue::None => vec![],
+        factory => vec![factory],
+    };
+    dict_reduce("defaultdict", PickleValue::Tuple(args), pairs)
+}
+
+fn dict_reduce(name: &str, args: PickleValue, pairs: Vec<(PickleValue, PickleValue)>) -> PickleValue {
+    PickleValue::Reduce {
+        callable: Box::new(PickleValue::Global {
+            module: "collections".into(),
+            name: name.into(),
+        }),
+        args: Box::new(args),
+        dict_items: (!pairs.is_empty()).then(|| Box::new(pairs)),
+        list_items: None,
+        newobj: false,
+    }
+}
+
+// ===========================================================================
+// zope.interface.declarations.Provides (directlyProvides / alsoProvides)
+// ===========================================================================
+
+const PROVIDES_MODULE: &str = "zope.interface.declarations";
+
+/// Dotted names of a `Provides(cls, *interfaces)` REDUCE: the class the
+/// declaration was made for, then the interfaces. Arguments that are not
+/// classes, or whose names do not split back unambiguously, keep the
+/// generic form.
+pub fn provides_names(args: &PickleValue) -> Option<Vec<String>> {
+    match args {
+        PickleValue::Tuple(items) if !items.is_empty() => items
+            .iter()
+            .map(|item| match item {
+                PickleValue::Global { module, name } => compact_class_path(module, name),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+/// `Provides(cls, *interfaces)` from the names of [`provides_names`].
+pub fn provides_reduce<S: AsRef<str>>(names: &[S]) -> Result<PickleValue, CodecError> {
+    if names.is_empty() {
+        return Err(CodecError::InvalidData(
+            "@provides must be a non-empty list of dotted names".into(),
+        ));
+    }
+    let args = names
+        .iter()
+        .map(|path| {
+            let (module, name) = split_class_path(path.as_ref());
+            PickleValue::Global {
+                module: module.into(),
+                name: name.into(),
+            }
+        })
+        .collect();
+    Ok(PickleValue::Reduce {
+        callable: Box::new(PickleValue::Global {
+            module: PROVIDES_MODULE.into(),
+            name: "Provides".into(),
+        }),
+        args: Box::new(PickleValue::Tuple(args)),
+        dict_items: None,
+        list_items: None,
+        newobj: false,
+    })
+}
+
+// ===========================================================================
+// persistent.TimeStamp and raw 8-byte transaction ids
+// ===========================================================================
+
+/// Modules that `persistent.TimeStamp.TimeStamp` is pickled under (C
+/// implementation, pure-Python implementation, older releases).
+const TIMESTAMP_MODULES: &[&str] = &[
+    "persistent.TimeStamp",
+    "persistent.timestamp",
+    "persistent._timestamp",
+];
+
+thread_local! {
+    /// Whether plain 8-byte bytes values that decode to a plausible
+    /// transaction timestamp are rendered as `@tid` (off by default: any
+    /// 8-byte value could be a tid, an oid or arbitrary data).
+    static RAW_TID_DETECTION: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f` with the conversions it makes on this thread rendering raw
+/// 8-byte tid values as `@tid`.
+///
+/// ```
+/// use zodb_json_codec::{pickle_value_to_json, with_raw_tid_detection, PickleValue};
+///
+/// let tid = PickleValue::Bytes(b"\x03\xf8\x4a\x6e\x41\x11\x11\x11".to_vec());
+/// assert!(pickle_value_to_json(&tid)?.get("@b").is_some());
+/// let json = with_raw_tid_detection(|| pickle_value_to_json(&tid))?;
+/// assert_eq!(json["@tid"][1], "2024-05-01T12:30:15.249999");
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn with_raw_tid_detection<R>(f: impl FnOnce() -> R) -> R {
+    let _scope = RawTidScope::enter(true);
+    f()
+}
+
+/// Sets raw tid detection on the current thread while alive.
+pub(crate) struct RawTidScope {
+    previous: bool,
+}
+
+impl RawTidScope {
+    pub(crate) fn enter(enabled: bool) -> Self {
+        RawTidScope {
+            previous: RAW_TID_DETECTION.with(|d| d.replace(enabled)),
+        }
+    }
+}
+
+impl Drop for RawTidScope {
+    fn drop(&mut self) {
+        RAW_TID_DETECTION.with(|d| d.set(self.previous));
+    }
+}
+
+pub fn is_timestamp_module(module: &str) -> bool {
+    TIMESTAMP_MODULES.contains(&module)
+}
+
+/// The raw bytes of a `TimeStamp(raw)` REDUCE argument tuple.
+pub fn timestamp_raw(args: &PickleValue) -> Option<&[u8; 8]> {
+    match args {
+        PickleValue::Tuple(items) if items.len() == 1 => match &items[0] {
+            PickleValue::Bytes(b) => b.as_slice().try_into().ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Split a tid into `(year, month, day, hour, minute, microsecond-of-minute)`.
+///
+/// The first 4 bytes count minutes since 1900 with 31-day months, the last
+/// 4 bytes are the seconds within the minute scaled to `2**32 / 60`.
+pub(crate) fn tid_parts(raw: &[u8; 8]) -> (u32, u32, u32, u32, u32, u64) {
+    let mut a = u32::from_be_bytes(raw[..4].try_into().unwrap());
+    let b = u32::from_be_bytes(raw[4..].try_into().unwrap());
+    let minute = a % 60;
+    a /= 60;
+    let hour = a % 24;
+    a /= 24;
+    let day = a % 31 + 1;
+    a /= 31;
+    let month = a % 12 + 1;
+    let year = a / 12 + 1900;
+    let us = (b as u64 * 60_000_000) >> 32;
+    (year, month, day, hour, minute, us)
+}
+
+/// ISO 8601 rendering (UTC, microsecond precision) of a tid.
+pub fn format_tid_iso(raw: &[u8; 8]) -> String {
+    let (year, month, day, hour, minute, us) = tid_parts(raw);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{:02}.{:06}",
+        us / 1_000_000,
+        us % 1_000_000
+    )
+}
+
+/// If raw tid detection is enabled, return `b` as a tid when it is 8 bytes
+/// long and decodes to a valid date between 1990 and 2100.
+#[inline]
+pub fn detect_raw_tid(b: &[u8]) -> Option<&[u8; 8]> {
+    if b.len() != 8 || !RAW_TID_DETECTION.with(Cell::get) {
+        return None;
+    }
+    plausible_tid(b)
+}
+
+fn plausible_tid(b: &[u8]) -> Option<&[u8; 8]> {
+    let raw: &[u8; 8] = b.try_into().ok()?;
+    let (year, month, day, ..) = tid_parts(raw);
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    ((1990..=2100).contains(&year) && day <= days_in_month).then_some(raw)
+}
+
+/// `{"@tid": [hex, iso]}` for a raw tid, `{"@tid": [hex, iso, module]}` for
+/// a `TimeStamp` object.
+pub fn tid_json(raw: &[u8; 8], module: Option<&str>) -> Value {
+    match module {
+        Some(m) => json!({"@tid": [hex_encode(raw), format_tid_iso(raw), m]}),
+        None => json!({"@tid": [hex_encode(raw), format_tid_iso(raw)]}),
+    }
+}
+
+/// Rebuild the PickleValue for a `@tid` marker from its hex and optional
+/// TimeStamp module. Only the hex is used; the ISO form is informational.
+pub fn tid_to_pickle_value(hex_str: &str, module: Option<&str>) -> Result<PickleValue, CodecError> {
+    let raw = hex_decode(hex_str)
+        .ok()
+        .filter(|b| b.len() == 8)
+        .ok_or_else(|| CodecError::InvalidData(format!("@tid must be 16 hex digits: {hex_str}")))?;
+    Ok(match module {
+        None => PickleValue::Bytes(raw),
+        Some(m) => PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global {
+                module: m.to_string(),
+                name: "TimeStamp".into(),
+            }),
+            args: Box::new(PickleValue::Tuple(vec![PickleValue::Bytes(raw)])),
+            dict_items: None,
+            list_items: None,
+            newobj: false,
+        },
+    })
+}
+
+fn try_decode_tid(val: &Value) -> Result<PickleValue, CodecError> {
+    let err = || CodecError::InvalidData("@tid must be [hex, iso] or [hex, iso, module]".into());
+    let arr = val.as_array().ok_or_else(err)?;
+    let hex_str = arr.first().and_then(Value::as_str).ok_or_else(err)?;
+    let module = match arr.len() {
+        2 => None,
+        3 => Some(arr[2].as_str().ok_or_else(err)?),
+        _ => return Err(err()),
+    };
+    tid_to_pickle_value(hex_str, module)
+}
+
 // ===========================================================================
 // uuid.UUID (Instance from NEWOBJ+BUILD, state = {'int': N})
 // ===========================================================================
@@ -933,7 +1652,7 @@ fn try_encode_uuid(state: &PickleValue) -> Result<Option<Value>, CodecError> {
     // Look for the 'int' key
     for (k, v) in pairs {
         if let PickleValue::String(key) = k {
-            if key == "int" {
+            if &**key == "int" {
                 let int_val = match v {
                     PickleValue::Int(i) => *i as u128,
                     PickleValue::BigInt(bi) => {
@@ -964,6 +1683,304 @@ fn try_encode_uuid(state: &PickleValue) -> Result<Option<Value>, CodecError> {
     Ok(None)
 }
 
+// ===========================================================================
+// DateTime.DateTime (Zope; Instance, state = (micros, tz_naive, tz))
+// ===========================================================================
+
+const ZDT_MODULE: &str = "DateTime.DateTime";
+
+/// ISO 8601 reading of a Zope `DateTime` state: the `(micros,
+/// timezone_naive, tz)` tuple of current versions, or the attribute dict
+/// (`_micros` or `_t`, `_tz`) of old pickles. The time is shown in its
+/// zone when that is `UTC`/`GMT` or a `GMT+h` offset, otherwise in UTC;
+/// naive values carry no offset.
+pub fn zdt_iso(state: &PickleValue) -> Option<String> {
+    let (micros, naive, tz) = match state {
+        PickleValue::Tuple(items) => {
+            let micros = match items.first()? {
+                PickleValue::Int(us) => *us,
+                _ => return None,
+            };
+            let naive = matches!(items.get(1), Some(PickleValue::Bool(true)));
+            let tz = match items.get(2) {
+                Some(PickleValue::String(tz)) => Some(&**tz),
+                _ => None,
+            };
+            (micros, naive, tz)
+        }
+        PickleValue::Dict(pairs) => {
+            let get = |key: &str| {
+                pairs
+                    .iter()
+                    .find(|(k, _)| matches!(k, PickleValue::String(s) if **s == *key))
+                    .map(|(_, v)| v)
+            };
+            let micros = match (get("_micros"), get("_t")) {
+                (Some(PickleValue::Int(us)), _) => *us,
+                (_, Some(PickleValue::Float(t))) if t.is_finite() => (t * 1e6).round() as i64,
+                _ => return None,
+            };
+            let naive = matches!(get("_timezone_naive"), Some(PickleValue::Bool(true)));
+            let tz = match get("_tz") {
+                Some(PickleValue::String(tz)) => Some(&**tz),
+                _ => None,
+            };
+            (micros, naive, tz)
+        }
+        _ => return None,
+    };
+    let offset = tz.and_then(zdt_tz_offset).unwrap_or(0);
+    let local = micros.checked_add(offset * 1_000_000)?;
+    let days = local.div_euclid(86_400_000_000);
+    let us_of_day = local.rem_euclid(86_400_000_000);
+    let (year, month, day) = civil_from_days(days);
+    let year = u16::try_from(year).ok().filter(|y| (1..=9999).contains(y))?;
+    let secs = us_of_day / 1_000_000;
+    let mut iso = format_datetime_iso(
+        year,
+        month,
+        day,
+        (secs / 3600) as u8,
+        (secs / 60 % 60) as u8,
+        (secs % 60) as u8,
+        (us_of_day % 1_000_000) as u32,
+    );
+    if !naive {
+        let sign = if offset < 0 { '-' } else { '+' };
+        let abs = offset.abs();
+        iso.push_str(&format!("{sign}{:02}:{:02}", abs / 3600, abs / 60 % 60));
+    }
+    Some(iso)
+}
+
+/// UTC offset in seconds of the zone names `DateTime` uses for fixed
+/// offsets: `UTC`, `GMT`, `GMT+1`, `GMT-0530`.
+fn zdt_tz_offset(tz: &str) -> Option<i64> {
+    if tz.eq_ignore_ascii_case("UTC") || tz.eq_ignore_ascii_case("GMT") {
+        return Some(0);
+    }
+    let rest = tz.strip_prefix("GMT").or_else(|| tz.strip_prefix("gmt"))?;
+    let (sign, digits) = match rest.as_bytes().first()? {
+        b'+' => (1, &rest[1..]),
+        b'-' => (-1, &rest[1..]),
+        _ => return None,
+    };
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes): (i64, i64) = match digits.len() {
+        1 | 2 => (digits.parse().ok()?, 0),
+        4 => (digits[..2].parse().ok()?, digits[2..].parse().ok()?),
+        _ => return None,
+    };
+    (hours <= 14 && minutes < 60).then_some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Proleptic Gregorian (year, month, day) of a day count since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Inverse of [`civil_from_days`].
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn try_encode_zdt(
+    state: &PickleValue,
+    to_json: &dyn Fn(&PickleValue) -> Result<Value, CodecError>,
+) -> Result<Option<Value>, CodecError> {
+    let Some(iso) = zdt_iso(state) else {
+        return Ok(None);
+    };
+    // The raw state is kept verbatim; the ISO string is only for reading
+    let raw = match state {
+        PickleValue::Tuple(items) => {
+            Value::Array(items.iter().map(to_json).collect::<Result<_, _>>()?)
+        }
+        _ => to_json(state)?,
+    };
+    Ok(Some(json!({"@zdt": iso, "@zdt_raw": raw})))
+}
+
+/// `(micros, timezone_naive, tz)` state for an `@zdt` string without
+/// `@zdt_raw`. Offsets become `GMT+h` zones like `DateTime` names them.
+pub fn zdt_state_from_iso(iso: &str) -> Result<PickleValue, CodecError> {
+    let ((year, month, day, hour, min, sec, us), offset) = parse_iso_datetime(iso)?;
+    let days = days_from_civil(i64::from(year), month, day);
+    let local = ((days * 24 + i64::from(hour)) * 60 + i64::from(min)) * 60 + i64::from(sec);
+    let micros = (local - offset.unwrap_or(0)) * 1_000_000 + i64::from(us);
+    let tz = match offset.unwrap_or(0) {
+        0 => "UTC".to_string(),
+        off => {
+            let sign = if off < 0 { '-' } else { '+' };
+            let (h, m) = (off.abs() / 3600, off.abs() / 60 % 60);
+            if m == 0 {
+                format!("GMT{sign}{h}")
+            } else {
+                format!("GMT{sign}{h:02}{m:02}")
+            }
+        }
+    };
+    Ok(PickleValue::Tuple(vec![
+        PickleValue::Int(micros),
+        PickleValue::Bool(offset.is_none()),
+        PickleValue::String(tz.into()),
+    ]))
+}
+
+/// A `DateTime` instance with the given state.
+pub fn zdt_instance(state: PickleValue) -> PickleValue {
+    PickleValue::Instance(Box::new(InstanceData::new(ZDT_MODULE, "DateTime", state)))
+}
+
+// ===========================================================================
+// persistent.mapping.PersistentMapping / persistent.list.PersistentList
+// (state = {'data': dict} / {'data': list}),
+// z3c.relationfield.relation.RelationValue (state = {'to_id': int, ...}),
+// BTrees.Length.Length (state = int)
+// ===========================================================================
+
+/// Marker, module and name of the persistent classes whose state is
+/// flattened into a marker: the containers to their `data`, relations to
+/// their attribute dict, counters to their value.
+///
+/// `BTrees.Length.Length` is the conflict-resolving counter used for the
+/// object and word counts of `Products.ZCatalog` catalogs, indexes and
+/// lexicons, so those become `@len` too.
+pub const CONTAINER_CLASSES: &[(&str, &str, &str)] = &[
+    ("@pmap", "persistent.mapping", "PersistentMapping"),
+    ("@plist", "persistent.list", "PersistentList"),
+    ("@rel", "z3c.relationfield.relation", "RelationValue"),
+    ("@len", "BTrees.Length", "Length"),
+];
+
+/// The `@pmap`/`@plist` marker for a persistent container class.
+pub fn container_marker(module: &str, name: &str) -> Option<&'static str> {
+    CONTAINER_CLASSES
+        .iter()
+        .find(|(_, m, n)| *m == module && *n == name)
+        .map(|(marker, _, _)| *marker)
+}
+
+/// Marker and `data` of a container state shaped exactly like
+/// `{'data': dict}` (`@pmap`) or `{'data': list}` (`@plist`). States with
+/// other attributes stay unflattened so nothing is lost.
+///
+/// For `@rel` the data is the whole state (`to_id`, `from_attribute`,
+/// `from_object`, `__parent__`), which must have string keys and an
+/// integer `to_id`.
+///
+/// For `@len` the data is the counter value, which must be an integer.
+pub fn container_data<'a>(
+    module: &str,
+    name: &str,
+    state: &'a PickleValue,
+) -> Option<(&'static str, &'a PickleValue)> {
+    let marker = container_marker(module, name)?;
+    if marker == "@len" {
+        return matches!(state, PickleValue::Int(_)).then_some((marker, state));
+    }
+    let PickleValue::Dict(pairs) = state else {
+        return None;
+    };
+    if marker == "@rel" {
+        return is_relation_state(pairs).then_some((marker, state));
+    }
+    let [(PickleValue::String(key), data)] = pairs.as_slice() else {
+        return None;
+    };
+    let shaped = match data {
+        PickleValue::Dict(_) => marker == "@pmap",
+        PickleValue::List(_) => marker == "@plist",
+        _ => false,
+    };
+    (&**key == "data" && shaped).then_some((marker, data))
+}
+
+/// Convert a PersistentMapping/PersistentList state to its marker form.
+/// Returns Ok(None) if the class or the state shape doesn't match.
+pub fn try_container_state_to_typed_json(
+    module: &str,
+    name: &str,
+    state: &PickleValue,
+    to_json: &dyn Fn(&PickleValue) -> Result<Value, CodecError>,
+) -> Result<Option<Value>, CodecError> {
+    match container_data(module, name, state) {
+        Some((marker, data)) => Ok(Some(json!({ marker: to_json(data)? }))),
+        None => Ok(None),
+    }
+}
+
+fn is_relation_state(pairs: &[(PickleValue, PickleValue)]) -> bool {
+    pairs.iter().all(|(k, _)| matches!(k, PickleValue::String(_)))
+        && pairs.iter().any(|(k, v)| {
+            matches!((k, v), (PickleValue::String(key), PickleValue::Int(_)) if &**key == "to_id")
+        })
+}
+
+/// Rebuild `{'data': ...}` from the value of a `@pmap`/`@plist` marker
+/// (the state itself for `@rel` and `@len`).
+pub fn container_state(marker: &str, data: PickleValue) -> Result<PickleValue, CodecError> {
+    match (marker, &data) {
+        ("@pmap", PickleValue::Dict(_)) | ("@plist", PickleValue::List(_)) => {
+            Ok(PickleValue::Dict(vec![(PickleValue::String("data".into()), data)]))
+        }
+        ("@rel", PickleValue::Dict(_)) | ("@len", PickleValue::Int(_)) => Ok(data),
+        ("@pmap", _) => Err(CodecError::InvalidData("@pmap must hold a dict".into())),
+        ("@rel", _) => Err(CodecError::InvalidData("@rel must hold a dict".into())),
+        ("@len", _) => Err(CodecError::InvalidData("@len must hold an int".into())),
+        _ => Err(CodecError::InvalidData("@plist must hold a list".into())),
+    }
+}
+
+/// An inline PersistentMapping/PersistentList/RelationValue/Length instance
+/// from the value of its `@pmap`/`@plist`/`@rel`/`@len` marker.
+pub fn container_instance(marker: &str, data: PickleValue) -> Result<PickleValue, CodecError> {
+    let (_, module, name) = CONTAINER_CLASSES
+        .iter()
+        .find(|(m, _, _)| *m == marker)
+        .ok_or_else(|| CodecError::InvalidData(format!("unknown container marker {marker}")))?;
+    let state = container_state(marker, data)?;
+    Ok(PickleValue::Instance(Box::new(InstanceData::new(*module, *name, state))))
+}
+
+/// Convert the `{"@pmap": ...}`/`{"@plist": ...}` state of a ZODB record
+/// of that class back to its `{'data': ...}` state.
+/// Returns Ok(None) if the class or the state doesn't match.
+pub fn try_typed_json_to_container_state(
+    module: &str,
+    name: &str,
+    state: &Value,
+    from_json: &dyn Fn(&Value) -> Result<PickleValue, CodecError>,
+) -> Result<Option<PickleValue>, CodecError> {
+    let Some(marker) = container_marker(module, name) else {
+        return Ok(None);
+    };
+    match state.as_object() {
+        Some(map) if map.len() == 1 => match map.get(marker) {
+            Some(data) => container_state(marker, from_json(data)?).map(Some),
+            None => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
 // ===========================================================================
 // Reverse: typed JSON → PickleValue
 // ===========================================================================
@@ -985,12 +2002,7 @@ fn try_decode_datetime(
     let tz_pickle = if let Some(tz_json) = tz_val {
         // Explicit @tz field: pytz or zoneinfo
         Some(decode_tz_json(tz_json)?)
-    } else if let Some(offset_secs) = offset_part {
-        // Offset in the ISO string
-        Some(make_stdlib_timezone(offset_secs))
-    } else {
-        None
-    };
+    } else { offset_part.map(make_stdlib_timezone) };
 
     let args = if let Some(tz) = tz_pickle {
         PickleValue::Tuple(vec![dt_bytes, tz])
@@ -1006,6 +2018,7 @@ fn try_decode_datetime(
         args: Box::new(args),
         dict_items: None,
         list_items: None,
+        newobj: false,
     })
 }
 
@@ -1038,6 +2051,7 @@ fn try_decode_date(val: &Value) -> Result<PickleValue, CodecError> {
         args: Box::new(PickleValue::Tuple(vec![PickleValue::Bytes(bytes)])),
         dict_items: None,
         list_items: None,
+        newobj: false,
     })
 }
 
@@ -1060,11 +2074,7 @@ fn try_decode_time(val: &Value, tz_val: Option<&Value>) -> Result<PickleValue, C
 
     let tz_pickle = if let Some(tz_json) = tz_val {
         Some(decode_tz_json(tz_json)?)
-    } else if let Some(offset_secs) = offset_part {
-        Some(make_stdlib_timezone(offset_secs))
-    } else {
-        None
-    };
+    } else { offset_part.map(make_stdlib_timezone) };
 
     let args = if let Some(tz) = tz_pickle {
         PickleValue::Tuple(vec![PickleValue::Bytes(bytes), tz])
@@ -1080,6 +2090,7 @@ fn try_decode_time(val: &Value, tz_val: Option<&Value>) -> Result<PickleValue, C
         args: Box::new(args),
         dict_items: None,
         list_items: None,
+        newobj: false,
     })
 }
 
@@ -1113,6 +2124,7 @@ fn try_decode_timedelta(val: &Value) -> Result<PickleValue, CodecError> {
         ])),
         dict_items: None,
         list_items: None,
+        newobj: false,
     })
 }
 
@@ -1126,12 +2138,45 @@ fn try_decode_decimal(val: &Value) -> Result<PickleValue, CodecError> {
             module: "decimal".into(),
             name: "Decimal".into(),
         }),
-        args: Box::new(PickleValue::Tuple(vec![PickleValue::String(s.to_string())])),
+        args: Box::new(PickleValue::Tuple(vec![PickleValue::String(s.into())])),
         dict_items: None,
         list_items: None,
+        newobj: false,
     })
 }
 
+fn try_decode_odict(
+    val: &Value,
+    from_json: &dyn Fn(&Value) -> Result<PickleValue, CodecError>,
+) -> Result<PickleValue, CodecError> {
+    let invalid = || CodecError::InvalidData("@odict must be a list of [key, value] pairs".into());
+    let arr = val.as_array().ok_or_else(invalid)?;
+    let pairs = arr
+        .iter()
+        .map(|pair| match pair.as_array().map(Vec::as_slice) {
+            Some([k, v]) => Ok((from_json(k)?, from_json(v)?)),
+            _ => Err(invalid()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(odict_reduce(pairs))
+}
+
+fn try_decode_ddict(
+    val: &Value,
+    from_json: &dyn Fn(&Value) -> Result<PickleValue, CodecError>,
+) -> Result<PickleValue, CodecError> {
+    let map = val
+        .as_object()
+        .ok_or_else(|| CodecError::InvalidData("@ddict must be an object".into()))?;
+    let factory = from_json(map.get("factory").unwrap_or(&Value::Null))?;
+    let pairs = match map.get("items").map(from_json).transpose()? {
+        Some(PickleValue::Dict(pairs)) => pairs,
+        None => vec![],
+        Some(_) => return Err(CodecError::InvalidData("@ddict items must be a dict".into())),
+    };
+    Ok(ddict_reduce(factory, pairs))
+}
+
 fn try_decode_uuid(val: &Value) -> Result<PickleValue, CodecError> {
     let s = val
         .as_str()
@@ -1170,10 +2215,14 @@ fn try_decode_uuid(val: &Value) -> Result<PickleValue, CodecError> {
 // ISO 8601 parsing helpers
 // ===========================================================================
 
+/// Datetime components: (year, month, day, hour, minute, second, microsecond).
+pub type DateTimeParts = (u16, u8, u8, u8, u8, u8, u32);
+
+/// Time components: (hour, minute, second, microsecond).
+pub type TimeParts = (u8, u8, u8, u32);
+
 /// Parse ISO datetime string, returning components and optional offset in seconds.
-pub fn parse_iso_datetime(
-    s: &str,
-) -> Result<((u16, u8, u8, u8, u8, u8, u32), Option<i64>), CodecError> {
+pub fn parse_iso_datetime(s: &str) -> Result<(DateTimeParts, Option<i64>), CodecError> {
     // Format: YYYY-MM-DDTHH:MM:SS[.ffffff][+HH:MM]
     if s.len() < 19 {
         return Err(CodecError::InvalidData(format!(
@@ -1203,18 +2252,17 @@ pub fn parse_iso_datetime(
     let rest = &s[19..];
 
     // Parse optional microseconds
-    let (us, rest) = if rest.starts_with('.') {
-        let frac_end = rest[1..]
+    let (us, rest) = if let Some(frac) = rest.strip_prefix('.') {
+        let frac_end = frac
             .find(|c: char| !c.is_ascii_digit())
-            .map(|i| i + 1)
-            .unwrap_or(rest.len());
-        let frac_str = &rest[1..frac_end];
+            .unwrap_or(frac.len());
+        let frac_str = &frac[..frac_end];
         // Pad or truncate to 6 digits
         let padded = format!("{frac_str:0<6}");
         let us: u32 = padded[..6]
             .parse()
             .map_err(|_| CodecError::InvalidData(format!("bad microseconds: {s}")))?;
-        (us, &rest[frac_end..])
+        (us, &frac[frac_end..])
     } else {
         (0u32, rest)
     };
@@ -1226,7 +2274,7 @@ pub fn parse_iso_datetime(
 }
 
 /// Parse ISO time string.
-pub fn parse_iso_time(s: &str) -> Result<((u8, u8, u8, u32), Option<i64>), CodecError> {
+pub fn parse_iso_time(s: &str) -> Result<(TimeParts, Option<i64>), CodecError> {
     if s.len() < 8 {
         return Err(CodecError::InvalidData(format!("time too short: {s}")));
     }
@@ -1243,17 +2291,16 @@ pub fn parse_iso_time(s: &str) -> Result<((u8, u8, u8, u32), Option<i64>), Codec
 
     let rest = &s[8..];
 
-    let (us, rest) = if rest.starts_with('.') {
-        let frac_end = rest[1..]
+    let (us, rest) = if let Some(frac) = rest.strip_prefix('.') {
+        let frac_end = frac
             .find(|c: char| !c.is_ascii_digit())
-            .map(|i| i + 1)
-            .unwrap_or(rest.len());
-        let frac_str = &rest[1..frac_end];
+            .unwrap_or(frac.len());
+        let frac_str = &frac[..frac_end];
         let padded = format!("{frac_str:0<6}");
         let us: u32 = padded[..6]
             .parse()
             .map_err(|_| CodecError::InvalidData(format!("bad microseconds: {s}")))?;
-        (us, &rest[frac_end..])
+        (us, &frac[frac_end..])
     } else {
         (0u32, rest)
     };
@@ -1310,6 +2357,7 @@ pub fn make_stdlib_timezone(offset_seconds: i64) -> PickleValue {
         ])),
         dict_items: None,
         list_items: None,
+        newobj: false,
     };
     PickleValue::Reduce {
         callable: Box::new(PickleValue::Global {
@@ -1319,6 +2367,7 @@ pub fn make_stdlib_timezone(offset_seconds: i64) -> PickleValue {
         args: Box::new(PickleValue::Tuple(vec![td])),
         dict_items: None,
         list_items: None,
+        newobj: false,
     }
 }
 
@@ -1334,7 +2383,7 @@ fn decode_tz_json(tz_json: &Value) -> Result<PickleValue, CodecError> {
             let pickle_args: Vec<PickleValue> = args
                 .iter()
                 .map(|a| match a {
-                    Value::String(s) => Ok(PickleValue::String(s.clone())),
+                    Value::String(s) => Ok(PickleValue::String(s.as_str().into())),
                     Value::Number(n) => {
                         if let Some(i) = n.as_i64() {
                             Ok(PickleValue::Int(i))
@@ -1354,6 +2403,7 @@ fn decode_tz_json(tz_json: &Value) -> Result<PickleValue, CodecError> {
                 args: Box::new(PickleValue::Tuple(pickle_args)),
                 dict_items: None,
                 list_items: None,
+                newobj: false,
             });
         }
 
@@ -1374,15 +2424,17 @@ fn decode_tz_json(tz_json: &Value) -> Result<PickleValue, CodecError> {
                 ])),
                 dict_items: None,
                 list_items: None,
+                newobj: false,
             };
             return Ok(PickleValue::Reduce {
                 callable: Box::new(inner_reduce),
                 args: Box::new(PickleValue::Tuple(vec![
-                    PickleValue::String(key.clone()),
+                    PickleValue::String(key.as_str().into()),
                     PickleValue::Int(1),
                 ])),
                 dict_items: None,
                 list_items: None,
+                newobj: false,
             });
         }
     }
@@ -1410,6 +2462,7 @@ mod tests {
             args: Box::new(args),
             dict_items: None,
             list_items: None,
+            newobj: false,
         }
     }
 
@@ -1611,6 +2664,188 @@ mod tests {
         assert_eq!(json, json!({"@dec": "3.14159"}));
     }
 
+    // -- complex / Fraction --
+
+    #[test]
+    fn test_complex() {
+        let args = PickleValue::Tuple(vec![PickleValue::Float(1.5), PickleValue::Float(-2.0)]);
+        let reduce = make_reduce("builtins", "complex", args.clone());
+        let json = pickle_value_to_json(&reduce).unwrap();
+        assert_eq!(json, json!({"@complex": [1.5, -2.0]}));
+        assert_eq!(crate::json::json_to_pickle_value(&json).unwrap(), reduce);
+        let pg = crate::json::pickle_value_to_json_string_pg(&reduce, "", "").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&pg).unwrap(), json);
+        // Python 2 module name, integer parts from other JSON producers
+        let py2 = make_reduce("__builtin__", "complex", args);
+        assert_eq!(pickle_value_to_json(&py2).unwrap(), json);
+        let back = crate::json::json_to_pickle_value(&json!({"@complex": [1, 0]})).unwrap();
+        assert_eq!(back, complex_reduce(1.0, 0.0));
+        // No JSON number for inf: generic form
+        let inf = make_reduce(
+            "builtins",
+            "complex",
+            PickleValue::Tuple(vec![PickleValue::Float(f64::INFINITY), PickleValue::Float(0.0)]),
+        );
+        assert!(pickle_value_to_json(&inf).unwrap().get("@reduce").is_some());
+        assert!(crate::json::json_to_pickle_value(&json!({"@complex": [1.0]})).is_err());
+    }
+
+    #[test]
+    fn test_fraction() {
+        let reduce = make_reduce(
+            "fractions",
+            "Fraction",
+            PickleValue::Tuple(vec![PickleValue::Int(-3), PickleValue::Int(7)]),
+        );
+        let json = pickle_value_to_json(&reduce).unwrap();
+        assert_eq!(json, json!({"@frac": "-3/7"}));
+        assert_eq!(crate::json::json_to_pickle_value(&json).unwrap(), reduce);
+        let pg = crate::json::pickle_value_to_json_string_pg(&reduce, "", "").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&pg).unwrap(), json);
+
+        // Whole numbers read like str(Fraction)
+        let whole = make_reduce(
+            "fractions",
+            "Fraction",
+            PickleValue::Tuple(vec![PickleValue::Int(5), PickleValue::Int(1)]),
+        );
+        assert_eq!(pickle_value_to_json(&whole).unwrap(), json!({"@frac": "5"}));
+        assert_eq!(crate::json::json_to_pickle_value(&json!({"@frac": "5"})).unwrap(), whole);
+
+        // Older Pythons pickle str(self); big numerators stay exact
+        let big = "123456789012345678901234567890/7";
+        let old = make_reduce(
+            "fractions",
+            "Fraction",
+            PickleValue::Tuple(vec![PickleValue::String(big.into())]),
+        );
+        let json = pickle_value_to_json(&old).unwrap();
+        assert_eq!(json, json!({"@frac": big}));
+        let back = crate::json::json_to_pickle_value(&json).unwrap();
+        assert_eq!(
+            back,
+            make_reduce(
+                "fractions",
+                "Fraction",
+                PickleValue::Tuple(vec![
+                    PickleValue::BigInt("123456789012345678901234567890".parse().unwrap()),
+                    PickleValue::Int(7),
+                ]),
+            )
+        );
+
+        for bad in ["1/0", "1/-2", "x", "1.5", ""] {
+            assert!(crate::json::json_to_pickle_value(&json!({"@frac": bad})).is_err(), "{bad}");
+        }
+    }
+
+    // -- Zope DateTime --
+
+    #[test]
+    fn test_zope_datetime() {
+        let state = PickleValue::Tuple(vec![
+            PickleValue::Int(1_709_284_830_000_000),
+            PickleValue::Bool(false),
+            PickleValue::String("GMT+1".into()),
+        ]);
+        let zdt = zdt_instance(state.clone());
+        let json = pickle_value_to_json(&zdt).unwrap();
+        assert_eq!(
+            json,
+            json!({"@zdt": "2024-03-01T10:20:30+01:00", "@zdt_raw": [1_709_284_830_000_000_i64, false, "GMT+1"]})
+        );
+        assert_eq!(crate::json::json_to_pickle_value(&json).unwrap(), zdt);
+        let pg = crate::json::pickle_value_to_json_string_pg(&zdt, "", "").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&pg).unwrap(), json);
+
+        // Named zones read in UTC, naive values without offset
+        let named = PickleValue::Tuple(vec![
+            PickleValue::Int(1_709_284_830_000_123),
+            PickleValue::Bool(false),
+            PickleValue::String("US/Eastern".into()),
+        ]);
+        assert_eq!(zdt_iso(&named).unwrap(), "2024-03-01T09:20:30.000123+00:00");
+        let naive = PickleValue::Tuple(vec![
+            PickleValue::Int(-1_000_000),
+            PickleValue::Bool(true),
+            PickleValue::String("GMT-0530".into()),
+        ]);
+        assert_eq!(zdt_iso(&naive).unwrap(), "1969-12-31T18:29:59");
+
+        // Old attribute-dict state is kept verbatim
+        let old = PickleValue::Dict(vec![
+            (PickleValue::String("_t".into()), PickleValue::Float(1_709_284_830.5)),
+            (PickleValue::String("_tz".into()), PickleValue::String("UTC".into())),
+        ]);
+        let json = pickle_value_to_json(&zdt_instance(old.clone())).unwrap();
+        assert_eq!(json["@zdt"], "2024-03-01T09:20:30.500000+00:00");
+        assert_eq!(crate::json::json_to_pickle_value(&json).unwrap(), zdt_instance(old));
+
+        // @zdt alone is parsed
+        let back = crate::json::json_to_pickle_value(&json!({"@zdt": "2024-03-01T10:20:30+01:00"}));
+        assert_eq!(back.unwrap(), zdt);
+        let back = crate::json::json_to_pickle_value(&json!({"@zdt": "2024-03-01T10:20:30+05:30"}));
+        let expected = PickleValue::Tuple(vec![
+            PickleValue::Int(1_709_284_830_000_000 - 4 * 3600 * 1_000_000 - 1800 * 1_000_000),
+            PickleValue::Bool(false),
+            PickleValue::String("GMT+0530".into()),
+        ]);
+        assert_eq!(back.unwrap(), zdt_instance(expected));
+    }
+
+    #[test]
+    fn test_civil_days() {
+        for days in [-719_468, -1, 0, 19_783, 2_932_896] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+    }
+
+    // -- zope.interface Provides --
+
+    #[test]
+    fn test_provides() {
+        let global = |module: &str, name: &str| PickleValue::Global {
+            module: module.into(),
+            name: name.into(),
+        };
+        let reduce = make_reduce(
+            "zope.interface.declarations",
+            "Provides",
+            PickleValue::Tuple(vec![
+                global("plone.app.contenttypes.content", "Document"),
+                global("plone.dexterity.interfaces", "IDexterityContent"),
+                global("myapp.interfaces", "IFeatured"),
+            ]),
+        );
+        let json = pickle_value_to_json(&reduce).unwrap();
+        assert_eq!(
+            json,
+            json!({"@provides": [
+                "plone.app.contenttypes.content.Document",
+                "plone.dexterity.interfaces.IDexterityContent",
+                "myapp.interfaces.IFeatured"
+            ]})
+        );
+        assert_eq!(crate::json::json_to_pickle_value(&json).unwrap(), reduce);
+        let pg = crate::json::pickle_value_to_json_string_pg(&reduce, "", "").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&pg).unwrap(), json);
+
+        // Qualified names and non-class arguments keep the generic form
+        for arg in [global("myapp", "Outer.IInner"), PickleValue::None] {
+            let reduce = make_reduce(
+                "zope.interface.declarations",
+                "Provides",
+                PickleValue::Tuple(vec![global("myapp", "Doc"), arg]),
+            );
+            assert!(pickle_value_to_json(&reduce).unwrap().get("@reduce").is_some());
+        }
+        for bad in [json!([]), json!("myapp.IFoo"), json!([1])] {
+            assert!(crate::json::json_to_pickle_value(&json!({"@provides": bad})).is_err());
+        }
+    }
+
     // -- set --
 
     #[test]
@@ -1647,6 +2882,7 @@ mod tests {
     // -- UUID --
 
     #[test]
+    #[allow(clippy::unusual_byte_groupings)]
     fn test_uuid() {
         // UUID: 12345678-1234-5678-1234-567812345678
         // Integer value: 0x12345678123456781234567812345678
@@ -1731,4 +2967,209 @@ mod tests {
         let json2 = pickle_value_to_json(&pv).unwrap();
         assert_eq!(json, json2);
     }
+
+    // -- persistent.TimeStamp / raw tids --
+
+    const TID: [u8; 8] = [0x03, 0xf8, 0x4a, 0x6e, 0x41, 0x11, 0x11, 0x11];
+
+    #[test]
+    fn test_format_tid_iso() {
+        assert_eq!(format_tid_iso(&TID), "2024-05-01T12:30:15.249999");
+        assert_eq!(format_tid_iso(&[0; 8]), "1900-01-01T00:00:00.000000");
+    }
+
+    #[test]
+    fn test_timestamp_reduce_to_tid() {
+        let reduce = make_reduce(
+            "persistent.TimeStamp",
+            "TimeStamp",
+            PickleValue::Tuple(vec![PickleValue::Bytes(TID.to_vec())]),
+        );
+        let json = pickle_value_to_json(&reduce).unwrap();
+        assert_eq!(
+            json,
+            json!({"@tid": ["03f84a6e41111111", "2024-05-01T12:30:15.249999", "persistent.TimeStamp"]})
+        );
+        let pv = crate::json::json_to_pickle_value(&json).unwrap();
+        assert_eq!(pv, reduce);
+    }
+
+    #[test]
+    fn test_timestamp_direct_writer() {
+        let reduce = make_reduce(
+            "persistent.timestamp",
+            "TimeStamp",
+            PickleValue::Tuple(vec![PickleValue::Bytes(TID.to_vec())]),
+        );
+        let out = crate::json::pickle_value_to_json_string_pg(&reduce, "myapp", "Doc").unwrap();
+        let out: Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(out, pickle_value_to_json(&reduce).unwrap());
+    }
+
+    #[test]
+    fn test_raw_tid_marker_roundtrip() {
+        let json = json!({"@tid": ["03f84a6e41111111", "ignored on decode"]});
+        let pv = crate::json::json_to_pickle_value(&json).unwrap();
+        assert_eq!(pv, PickleValue::Bytes(TID.to_vec()));
+        assert!(crate::json::json_to_pickle_value(&json!({"@tid": ["03f8", "x"]})).is_err());
+    }
+
+    #[test]
+    fn test_plausible_tid() {
+        assert!(plausible_tid(&TID).is_some());
+        // Small oids decode to 1900
+        assert!(plausible_tid(&[0, 0, 0, 0, 0, 0, 0, 1]).is_none());
+        // 2024-02-31 is not a date
+        assert!(plausible_tid(&[0x03, 0xf6, 0xe5, 0x20, 0, 0, 0, 0]).is_none());
+        assert!(plausible_tid(&TID[..7]).is_none());
+    }
+
+    // -- PersistentMapping / PersistentList --
+
+    fn container(module: &str, name: &str, state: PickleValue) -> PickleValue {
+        PickleValue::Instance(Box::new(InstanceData::new(module, name, state)))
+    }
+
+    fn data_state(data: PickleValue) -> PickleValue {
+        PickleValue::Dict(vec![(PickleValue::String("data".into()), data)])
+    }
+
+    #[test]
+    fn test_persistent_containers() {
+        let pmap = container(
+            "persistent.mapping",
+            "PersistentMapping",
+            data_state(PickleValue::Dict(vec![(
+                PickleValue::String("a".into()),
+                PickleValue::Int(1),
+            )])),
+        );
+        let plist = container(
+            "persistent.list",
+            "PersistentList",
+            data_state(PickleValue::List(vec![PickleValue::Int(1), PickleValue::Int(2)])),
+        );
+        for (val, expected) in [
+            (&pmap, json!({"@pmap": {"a": 1}})),
+            (&plist, json!({"@plist": [1, 2]})),
+        ] {
+            let json = pickle_value_to_json(val).unwrap();
+            assert_eq!(json, expected);
+            assert_eq!(&crate::json::json_to_pickle_value(&json).unwrap(), val);
+            let pg = crate::json::pickle_value_to_json_string_pg(val, "", "").unwrap();
+            assert_eq!(serde_json::from_str::<Value>(&pg).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_persistent_container_shapes() {
+        // Extra attributes or a mismatched data type keep the generic form
+        let extra = PickleValue::Dict(vec![
+            (PickleValue::String("data".into()), PickleValue::Dict(vec![])),
+            (PickleValue::String("extra".into()), PickleValue::None),
+        ]);
+        assert!(container_data("persistent.mapping", "PersistentMapping", &extra).is_none());
+        let list_in_map = data_state(PickleValue::List(vec![]));
+        assert!(container_data("persistent.mapping", "PersistentMapping", &list_in_map).is_none());
+        assert!(container_data("persistent.list", "PersistentList", &list_in_map).is_some());
+        assert!(container_data("myapp", "PersistentList", &list_in_map).is_none());
+
+        let err = crate::json::json_to_pickle_value(&json!({"@pmap": [1]})).unwrap_err();
+        assert!(err.to_string().contains("@pmap must hold a dict"), "{err}");
+        let err = crate::json::json_to_pickle_value(&json!({"@plist": {}})).unwrap_err();
+        assert!(err.to_string().contains("@plist must hold a list"), "{err}");
+    }
+
+    #[test]
+    fn test_relation_value() {
+        let state = PickleValue::Dict(vec![
+            (PickleValue::String("__parent__".into()), PickleValue::None),
+            (PickleValue::String("from_attribute".into()), PickleValue::String("relatedItems".into())),
+            (PickleValue::String("to_id".into()), PickleValue::Int(1_234_567)),
+        ]);
+        let rel = container("z3c.relationfield.relation", "RelationValue", state.clone());
+        let expected =
+            json!({"@rel": {"__parent__": null, "from_attribute": "relatedItems", "to_id": 1_234_567}});
+        let json = pickle_value_to_json(&rel).unwrap();
+        assert_eq!(json, expected);
+        assert_eq!(crate::json::json_to_pickle_value(&json).unwrap(), rel);
+        let pg = crate::json::pickle_value_to_json_string_pg(&rel, "", "").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&pg).unwrap(), expected);
+
+        // Without an integer to_id the generic form is kept
+        let broken = PickleValue::Dict(vec![(PickleValue::String("to_id".into()), PickleValue::None)]);
+        let module = "z3c.relationfield.relation";
+        assert!(container_data(module, "RelationValue", &broken).is_none());
+        let err = crate::json::json_to_pickle_value(&json!({"@rel": [1]})).unwrap_err();
+        assert!(err.to_string().contains("@rel must hold a dict"), "{err}");
+    }
+
+    // -- OrderedDict / defaultdict --
+
+    fn s(text: &str) -> PickleValue {
+        PickleValue::String(text.into())
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let length = container("BTrees.Length", "Length", PickleValue::Int(42));
+        let json = pickle_value_to_json(&length).unwrap();
+        assert_eq!(json, json!({"@len": 42}));
+        assert_eq!(crate::json::json_to_pickle_value(&json).unwrap(), length);
+        let pg = crate::json::pickle_value_to_json_string_pg(&length, "", "").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&pg).unwrap(), json!({"@len": 42}));
+
+        // Any other state keeps the generic form
+        assert!(container_data("BTrees.Length", "Length", &PickleValue::None).is_none());
+        let err = crate::json::json_to_pickle_value(&json!({"@len": "42"})).unwrap_err();
+        assert!(err.to_string().contains("@len must hold an int"), "{err}");
+    }
+
+    #[test]
+    fn test_ordered_dict() {
+        let pairs = vec![(s("b"), PickleValue::Int(2)), (s("a"), PickleValue::Int(1))];
+        let reduce = odict_reduce(pairs.clone());
+        let json = pickle_value_to_json(&reduce).unwrap();
+        assert_eq!(json, json!({"@odict": [["b", 2], ["a", 1]]}));
+        assert_eq!(crate::json::json_to_pickle_value(&json).unwrap(), reduce);
+        let pg = crate::json::pickle_value_to_json_string_pg(&reduce, "", "").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&pg).unwrap(), json);
+        // Empty: no SETITEMS
+        assert_eq!(
+            odict_reduce(vec![]),
+            make_reduce("collections", "OrderedDict", PickleValue::Tuple(vec![]))
+        );
+        // Python 2: OrderedDict([[k, v], ...])
+        let py2 = make_reduce(
+            "collections",
+            "OrderedDict",
+            PickleValue::Tuple(vec![PickleValue::List(
+                pairs.iter().map(|(k, v)| PickleValue::List(vec![k.clone(), v.clone()])).collect(),
+            )]),
+        );
+        assert_eq!(pickle_value_to_json(&py2).unwrap(), json);
+        assert!(crate::json::json_to_pickle_value(&json!({"@odict": [[1]]})).is_err());
+    }
+
+    #[test]
+    fn test_default_dict() {
+        let factory = PickleValue::Global {
+            module: "builtins".into(),
+            name: "list".into(),
+        };
+        let reduce = ddict_reduce(factory, vec![(s("tags"), PickleValue::List(vec![s("x")]))]);
+        let json = pickle_value_to_json(&reduce).unwrap();
+        assert_eq!(
+            json,
+            json!({"@ddict": {"factory": {"@cls": ["builtins", "list"]}, "items": {"tags": ["x"]}}})
+        );
+        assert_eq!(crate::json::json_to_pickle_value(&json).unwrap(), reduce);
+        let pg = crate::json::pickle_value_to_json_string_pg(&reduce, "", "").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&pg).unwrap(), json);
+        // No factory, non-string keys
+        let reduce = ddict_reduce(PickleValue::None, vec![(PickleValue::Int(1), s("one"))]);
+        let json = pickle_value_to_json(&reduce).unwrap();
+        assert_eq!(json, json!({"@ddict": {"factory": null, "items": {"@d": [[1, "one"]]}}}));
+        assert_eq!(crate::json::json_to_pickle_value(&json).unwrap(), reduce);
+    }
 }
@@ -1,199 +1,171 @@
+//! Fast pickle ↔ JSON transcoder for ZODB.
+//!
+//! The crate is primarily built as the `zodb_json_codec._rust` Python
+//! extension, but the codec core is also usable directly from Rust. The
+//! extension module is behind the default `python` feature; with
+//! `default-features = false` the crate depends on neither PyO3 nor
+//! libpython:
+//!
+//! ```
+//! use zodb_json_codec::{decode_pickle, encode_pickle, PickleValue};
+//!
+//! // Python: pickle.dumps({"title": "Hello"}, protocol=3)
+//! let data = b"\x80\x03}q\x00X\x05\x00\x00\x00titleq\x01X\x05\x00\x00\x00Helloq\x02s.";
+//! let val = decode_pickle(data)?;
+//! assert_eq!(
+//!     val,
+//!     PickleValue::Dict(vec![(
+//!         PickleValue::String("title".into()),
+//!         PickleValue::String("Hello".into()),
+//!     )])
+//! );
+//!
+//! // Re-encode (always protocol 3, as required by zodbpickle)
+//! let bytes = encode_pickle(&val)?;
+//! assert_eq!(decode_pickle(&bytes)?, val);
+//! # Ok::<(), zodb_json_codec::CodecError>(())
+//! ```
+//!
+//! # Stability
+//!
+//! Only the items re-exported from the crate root are public API and
+//! follow semantic versioning; the modules themselves are private.
+//! `PickleValue`, `CodecError` and the option/info structs are
+//! `#[non_exhaustive]` so that new pickle constructs, error cases and
+//! options can be added in minor releases. The JSON marker format is
+//! versioned together with the crate: markers are only added, never
+//! changed, within a major version.
+
+mod analyze;
+#[cfg(feature = "python")]
+mod batch;
+mod bigint;
+mod binenc;
 mod btrees;
+mod bytes_keys;
+mod canonical;
+#[cfg(any(test, feature = "capi"))]
+mod capi;
+mod cbor;
+mod dangling;
 mod decode;
+#[cfg(feature = "python")]
+mod dedup;
+mod diff;
+mod duplicate_keys;
 mod encode;
 mod error;
+mod estimate;
+mod events;
+mod extract;
+mod filestorage;
+mod floats;
+mod framing;
+mod ids;
+mod info;
 mod json;
 mod json_writer;
 mod known_types;
+mod limits;
+mod lint;
+#[cfg(feature = "python")]
+mod logbridge;
+mod materialize;
+mod memo;
+mod null_strings;
 mod opcodes;
+mod patch;
+mod policy;
+mod protocol0;
+#[cfg(feature = "python")]
+mod pyast;
+#[cfg(feature = "python")]
+mod pybuffer;
+#[cfg(feature = "python")]
 mod pyconv;
+#[cfg(feature = "python")]
+mod python;
+mod quotas;
+mod raw_pickle;
+mod refgraph;
+mod refscan;
+mod registry;
+mod remap;
+mod rename;
+mod shared;
+mod strict;
+mod subtree;
+mod surrogates;
 mod types;
+mod verify;
+mod warnings;
 mod zodb;
 
-use pyo3::prelude::*;
-use pyo3::intern;
-use pyo3::types::{PyBytes, PyDict, PyList, PyString};
-
-use crate::decode::{decode_pickle, decode_zodb_pickles};
-use crate::encode::encode_pickle;
-use crate::error::CodecError;
-use crate::json::{json_to_pickle_value, pickle_value_to_json};
-
-/// Convert pickle bytes to a JSON string.
-#[pyfunction]
-fn pickle_to_json(py: Python<'_>, data: &[u8]) -> PyResult<String> {
-    // Entire function is pure Rust — release GIL for the full duration
-    py.detach(|| {
-        let val = decode_pickle(data).map_err(CodecError::from)?;
-        let json_val = pickle_value_to_json(&val)?;
-        let json_str = serde_json::to_string_pretty(&json_val)
-            .map_err(|e| CodecError::Json(e.to_string()))?;
-        Ok(json_str)
-    })
-}
-
-/// Convert a JSON string to pickle bytes.
-#[pyfunction]
-fn json_to_pickle(py: Python<'_>, json_str: &str) -> PyResult<Py<PyBytes>> {
-    let json_val: serde_json::Value =
-        serde_json::from_str(json_str).map_err(|e| CodecError::Json(e.to_string()))?;
-    let pickle_val = json_to_pickle_value(&json_val)?;
-    let bytes = encode_pickle(&pickle_val)?;
-    Ok(PyBytes::new(py, &bytes).into())
-}
-
-/// Convert pickle bytes to a Python dict (direct PickleValue → Py<PyAny>).
-#[pyfunction]
-fn pickle_to_dict(py: Python<'_>, data: &[u8]) -> PyResult<Py<PyAny>> {
-    let val = py.detach(|| decode_pickle(data).map_err(CodecError::from))?;
-    pyconv::pickle_value_to_pyobject(py, &val, false)
-}
-
-/// Convert a Python dict to pickle bytes (direct Py<PyAny> → pickle bytes).
-#[pyfunction]
-fn dict_to_pickle(py: Python<'_>, obj: &Bound<'_, PyDict>) -> PyResult<Py<PyBytes>> {
-    let bytes = pyconv::encode_pyobject_as_pickle(obj.as_any(), false)?;
-    Ok(PyBytes::new(py, &bytes).into())
-}
-
-/// Decode a ZODB record (two concatenated pickles) into a Python dict.
-/// Returns: `{"@cls": ["module", "name"], "@s": { ... state ... }}`
-#[pyfunction]
-fn decode_zodb_record(py: Python<'_>, data: &[u8]) -> PyResult<Py<PyAny>> {
-    // Release GIL during pure-Rust pickle parsing
-    let (_class_val, state_val, module, name) = py.detach(|| {
-        let (class_val, state_val) = decode_zodb_pickles(data).map_err(CodecError::from)?;
-        let (module, name) = zodb::extract_class_info(&class_val);
-        Ok::<_, PyErr>((class_val, state_val, module, name))
-    })?;
-
-    // BTree-aware state conversion with inline persistent ref compaction
-    let state_obj = if let Some(info) = btrees::classify_btree(&module, &name) {
-        pyconv::btree_state_to_pyobject(py, &info, &state_val, true)?
-    } else {
-        pyconv::pickle_value_to_pyobject(py, &state_val, true)?
-    };
-
-    // Build result dict directly
-    let dict = PyDict::new(py);
-    let cls_list = PyList::new(py, [module.as_str(), name.as_str()])?;
-    dict.set_item(intern!(py, "@cls"), cls_list)?;
-    dict.set_item(intern!(py, "@s"), state_obj)?;
-    Ok(dict.into_any().unbind())
-}
-
-/// Decode a ZODB record for PostgreSQL JSONB storage.
-///
-/// Combines decode + ref extraction + null-byte sanitization in a single pass.
-/// Returns: `(class_mod: str, class_name: str, state: dict, refs: list[int])`
-///
-/// - `state` has null-byte strings replaced with `{"@ns": base64}` markers
-///   (PostgreSQL JSONB cannot store `\u0000`)
-/// - `refs` contains all persistent reference OIDs as integers (for the
-///   `refs` column used by pure-SQL pack)
-#[pyfunction]
-fn decode_zodb_record_for_pg(py: Python<'_>, data: &[u8]) -> PyResult<Py<PyAny>> {
-    // Release GIL during pure-Rust pickle parsing + ref extraction.
-    // This allows other Python threads to run during the CPU-bound phase.
-    let (_class_val, state_val, module, name, refs) = py.detach(|| {
-        let (class_val, state_val) = decode_zodb_pickles(data).map_err(CodecError::from)?;
-        let (module, name) = zodb::extract_class_info(&class_val);
-        let mut refs = Vec::new();
-        pyconv::collect_refs_from_pickle_value(&state_val, &mut refs);
-        Ok::<_, PyErr>((class_val, state_val, module, name, refs))
-    })?;
-
-    // BTree-aware state conversion with null-byte sanitization + ref compaction
-    let state_obj = if let Some(info) = btrees::classify_btree(&module, &name) {
-        pyconv::btree_state_to_pyobject_pg(py, &info, &state_val, true)?
-    } else {
-        pyconv::pickle_value_to_pyobject_pg(py, &state_val, true)?
-    };
-
-    // Build result tuple: (class_mod, class_name, state, refs)
-    let refs_list = PyList::new(py, &refs)?;
-    let result = (
-        module.into_pyobject(py)?,
-        name.into_pyobject(py)?,
-        state_obj.into_bound(py),
-        refs_list.into_any(),
-    );
-    Ok(result.into_pyobject(py)?.into_any().unbind())
-}
-
-/// Decode a ZODB record for PostgreSQL JSONB storage, returning a JSON string.
-///
-/// Like `decode_zodb_record_for_pg` but the entire pipeline runs in Rust with
-/// the GIL released — no intermediate Python dicts are created.
-/// Returns: `(class_mod: str, class_name: str, state_json: str, refs: list[int])`
-#[pyfunction]
-fn decode_zodb_record_for_pg_json(py: Python<'_>, data: &[u8]) -> PyResult<Py<PyAny>> {
-    // ENTIRE pipeline runs with GIL released: pickle decode + JSON conversion
-    let (module, name, json_str, refs) = py.detach(|| {
-        let (class_val, state_val) = decode_zodb_pickles(data).map_err(CodecError::from)?;
-        let (module, name) = zodb::extract_class_info(&class_val);
-        let mut refs = Vec::new();
-        pyconv::collect_refs_from_pickle_value(&state_val, &mut refs);
-
-        let json_str = json::pickle_value_to_json_string_pg(&state_val, &module, &name)?;
-        Ok::<_, PyErr>((module, name, json_str, refs))
-    })?;
-
-    // Only GIL-held work: build the 4-element return tuple
-    let refs_list = PyList::new(py, &refs)?;
-    let result = (
-        module.into_pyobject(py)?,
-        name.into_pyobject(py)?,
-        json_str.into_pyobject(py)?,
-        refs_list.into_any(),
-    );
-    Ok(result.into_pyobject(py)?.into_any().unbind())
-}
-
-/// Encode a ZODB JSON record back into two concatenated pickles.
-/// Uses the direct Py<PyAny> → pickle encoder, bypassing PickleValue allocations.
-#[pyfunction]
-fn encode_zodb_record(py: Python<'_>, obj: &Bound<'_, PyDict>) -> PyResult<Py<PyBytes>> {
-    let cls_val = obj
-        .get_item(intern!(py, "@cls"))?
-        .ok_or_else(|| CodecError::InvalidData("missing @cls in ZODB record".to_string()))?;
-    let cls_list = cls_val.cast::<PyList>().map_err(|_| {
-        CodecError::InvalidData("@cls must be a list".to_string())
-    })?;
-    if cls_list.len() != 2 {
-        return Err(CodecError::InvalidData("@cls must be [module, name]".to_string()).into());
-    }
-
-    // Borrow module/name as &str from Python (zero-copy)
-    let item0 = cls_list.get_item(0)?;
-    let item1 = cls_list.get_item(1)?;
-    let module = item0.cast::<PyString>()
-        .map_err(|_| CodecError::InvalidData("@cls[0] must be a string".to_string()))?
-        .to_str()?;
-    let name = item1.cast::<PyString>()
-        .map_err(|_| CodecError::InvalidData("@cls[1] must be a string".to_string()))?
-        .to_str()?;
-
-    // Get state
-    let state_obj = obj
-        .get_item(intern!(py, "@s"))?
-        .unwrap_or_else(|| py.None().into_bound(py));
-
-    // Direct encode: class pickle + state pickle, no PickleValue intermediates
-    let result = pyconv::encode_zodb_record_direct(module, name, &state_obj)?;
-    Ok(PyBytes::new(py, &result).into())
-}
-
-/// Python module definition
-#[pymodule]
-fn _rust(m: &Bound<'_, PyModule>) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(pickle_to_json, m)?)?;
-    m.add_function(wrap_pyfunction!(json_to_pickle, m)?)?;
-    m.add_function(wrap_pyfunction!(pickle_to_dict, m)?)?;
-    m.add_function(wrap_pyfunction!(dict_to_pickle, m)?)?;
-    m.add_function(wrap_pyfunction!(decode_zodb_record, m)?)?;
-    m.add_function(wrap_pyfunction!(decode_zodb_record_for_pg, m)?)?;
-    m.add_function(wrap_pyfunction!(decode_zodb_record_for_pg_json, m)?)?;
-    m.add_function(wrap_pyfunction!(encode_zodb_record, m)?)?;
-    Ok(())
-}
+pub use crate::analyze::{analyze_pickle, find_class_references, PickleStats};
+pub use crate::bigint::{with_bigint_policy, MAX_BIGINT_NUMBER_BITS};
+pub use crate::btrees::{
+    classify_btree, clear_btree_registrations, register_btree_class, register_btree_module_prefix,
+    BTreeClassInfo, BTreeNodeKind, BTreeValueType,
+};
+pub use crate::bytes_keys::{with_bytes_key_promotion, BYTES_KEYS_MARKER};
+pub use crate::canonical::{canonicalize_pickle, state_fingerprint};
+pub use crate::cbor::{cbor_to_pickle, cbor_to_pickle_value, pickle_to_cbor, pickle_value_to_cbor};
+pub use crate::dangling::with_dangling_dropped;
+pub use crate::decode::{
+    decode_pickle, decode_pickle_with_buffers, decode_pickle_with_options, decode_zodb_pickles,
+    decode_zodb_pickles_with_options, DecodeOptions, Py2Strings, DANGLING_KEY,
+};
+pub use crate::diff::{diff_zodb_records, RecordDiff};
+pub use crate::duplicate_keys::{with_duplicate_keys, DuplicateKeys};
+pub use crate::encode::{encode_pickle, encode_pickle_protocol};
+pub use crate::error::{CodecError, ErrorContext};
+pub use crate::estimate::{estimate_decoded_size, SizeEstimate};
+pub use crate::events::{pickle_events, PickleEvent, PickleEvents};
+pub use crate::extract::extract_paths;
+pub use crate::filestorage::{
+    DataRecord, DataRecords, FileStorage, StorageRecord, StorageRecords, Transaction, Transactions,
+};
+pub use crate::floats::{with_nonfinite_floats, NonFiniteFloats};
+pub use crate::framing::{encode_pickle_framed, frame_pickle, FramePolicy, DEFAULT_FRAME_AVG_SIZE};
+pub use crate::ids::{hex_to_oid, oid_to_hex, tid_to_timestamp, timestamp_to_tid};
+pub use crate::info::{codec_info, CodecInfo, MARKER_FORMAT_VERSION};
+pub use crate::json::{
+    canonicalize_json, json_to_pickle_value, pickle_value_to_json, pickle_value_to_json_string,
+    pickle_value_to_json_string_sorted,
+};
+pub use crate::known_types::with_raw_tid_detection;
+pub use crate::limits::{
+    with_encode_limits, DecodeLimits, EncodeLimits, LineLimits, DEFAULT_MAX_MEMO_ENTRIES,
+    DEFAULT_MAX_NAME_LINE, DEFAULT_MAX_NUMBER_LINE, DEFAULT_MAX_STRING_LENGTH,
+    DEFAULT_MAX_STRING_LINE,
+};
+pub use crate::lint::{
+    lint_record, LintCode, LintOptions, LintWarning, DEFAULT_LINT_MAX_DEPTH,
+    DEFAULT_LINT_MAX_STRING,
+};
+pub use crate::materialize::{materialize_btree, split_btree, SplitBTree};
+pub use crate::null_strings::with_pg_safe_input;
+pub use crate::patch::apply_patch_to_record;
+pub use crate::policy::{DecodePolicy, PolicyViolation};
+pub use crate::protocol0::encode_pickle_protocol0;
+pub use crate::quotas::{ClassQuota, ClassQuotas};
+pub use crate::raw_pickle::{with_raw_pickle_policy, RawPicklePolicy, DEFAULT_MAX_RAW_PICKLE_SIZE};
+pub use crate::refgraph::{
+    reachable_oids, record_refs_to_edges, record_refs_to_edges_batch, write_edges_csv,
+    write_edges_dot, ReachableOids, RefEdge,
+};
+pub use crate::refscan::{collect_refs_ex, count_refs, has_ref_to, PersistentRefInfo};
+pub use crate::registry::{register_type_handler, unregister_type_handler, TypeSpec};
+pub use crate::remap::{remap_record, remap_storage, OidMapping, OID_MAPPING_ENTRY_SIZE};
+pub use crate::rename::{with_encode_renames, ClassRenames};
+pub use crate::strict::check_strict;
+pub use crate::subtree::{extract_subtree, graft_subtree};
+pub use crate::surrogates::SurrogatePolicy;
+pub use crate::types::{InstanceData, PickleValue};
+pub use crate::verify::{verify_roundtrip, RoundtripMismatch};
+pub use crate::warnings::{collect_warnings, ConversionWarning, WarningCode};
+pub use crate::zodb::{
+    decode_persistent_id, decode_zodb_record, encode_persistent_id, encode_zodb_record,
+    extract_class_info, find_pickle_end, split_zodb_record, with_ref_format, RefFormat, ZeoCache,
+    ZeoCacheRecord, ZeoCacheRecords,
+};
@@ -0,0 +1,361 @@
+//! Safety limits for decoding and encoding.
+//!
+//! Text-mode (protocol 0) opcodes carry newline-terminated arguments, so a
+//! malicious pickle can make the decoder scan and copy an arbitrarily long
+//! line. Every line is therefore bounded by a per-category limit, checked
+//! while scanning for the newline: we never look further than the limit.
+//! A decoding call takes its [`LineLimits`] from
+//! [`DecodeOptions::with_line_limits`](crate::DecodeOptions::with_line_limits);
+//! the opcode walks (`count_refs`, `analyze_pickle`, ...) use the defaults.
+//!
+//! [`DecodeLimits`] bound the resources the decoder itself may use: the
+//! memory of the values it builds (memo GETs copy values, so a small
+//! pickle can expand a lot), the pickle VM stack, the memo, the length of
+//! a single string and the number of containers.
+//!
+//! [`EncodeLimits`] bound the encoders the same way: the size of the
+//! pickle written, the nesting depth and the length of a single
+//! collection, so a hostile JSON document cannot make encoding exhaust
+//! memory. The encoders have no options argument, so the limits of the
+//! encodings run inside [`with_encode_limits`] are kept per thread;
+//! outside it, encoding is unlimited.
+
+use std::cell::Cell;
+
+use crate::error::CodecError;
+use crate::opcodes::*;
+use crate::types::PickleValue;
+
+/// Default limit for GLOBAL module/name lines, PUT/GET memo keys and
+/// PERSID ids (4 KB).
+pub const DEFAULT_MAX_NAME_LINE: usize = 4 * 1024;
+/// Default limit for INT/LONG/FLOAT lines (32 KB).
+pub const DEFAULT_MAX_NUMBER_LINE: usize = 32 * 1024;
+/// Default limit for STRING/UNICODE lines (16 MB).
+pub const DEFAULT_MAX_STRING_LINE: usize = 16 * 1024 * 1024;
+
+/// Maximum line lengths (excluding the newline) for text-mode opcodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LineLimits {
+    /// GLOBAL module and name, PUT/GET memo keys, PERSID.
+    pub max_name: usize,
+    /// INT, LONG and FLOAT.
+    pub max_number: usize,
+    /// STRING and UNICODE.
+    pub max_string: usize,
+}
+
+impl LineLimits {
+    pub(crate) const fn new() -> Self {
+        LineLimits {
+            max_name: DEFAULT_MAX_NAME_LINE,
+            max_number: DEFAULT_MAX_NUMBER_LINE,
+            max_string: DEFAULT_MAX_STRING_LINE,
+        }
+    }
+
+    /// The limit applying to the line argument(s) of `op`.
+    #[inline]
+    pub(crate) fn for_opcode(&self, op: u8) -> usize {
+        match op {
+            INT | LONG | FLOAT => self.max_number,
+            STRING | UNICODE => self.max_string,
+            _ => self.max_name,
+        }
+    }
+}
+
+impl Default for LineLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Default maximum number of memo entries (100,000).
+pub const DEFAULT_MAX_MEMO_ENTRIES: usize = 100_000;
+/// Default maximum length of a single string, bytes value or
+/// out-of-band buffer (256 MB).
+pub const DEFAULT_MAX_STRING_LENGTH: usize = 256 * 1024 * 1024;
+
+/// Resource limits enforced by the decoder. `usize::MAX` means unlimited,
+/// the default for all but the memo and string limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DecodeLimits {
+    /// Approximate bytes allocated for decoded values, counting a node
+    /// per value plus string, bytes and integer payloads, and the copies
+    /// made for memo PUTs and GETs.
+    pub max_allocation: usize,
+    /// Values on the pickle VM stack, including those saved by MARKs.
+    pub max_stack_items: usize,
+    /// Memo entries; a PUT at or beyond this index fails.
+    pub max_memo_entries: usize,
+    /// Bytes in a single string, bytes value or out-of-band buffer.
+    pub max_string_length: usize,
+    /// Lists, tuples, dicts, sets and frozensets created, copies included.
+    pub max_containers: usize,
+}
+
+impl DecodeLimits {
+    pub(crate) const fn new() -> Self {
+        DecodeLimits {
+            max_allocation: usize::MAX,
+            max_stack_items: usize::MAX,
+            max_memo_entries: DEFAULT_MAX_MEMO_ENTRIES,
+            max_string_length: DEFAULT_MAX_STRING_LENGTH,
+            max_containers: usize::MAX,
+        }
+    }
+
+    /// Whether copies of memoized values must be measured, which takes a
+    /// walk over each copy.
+    #[inline]
+    pub(crate) fn counts_copies(&self) -> bool {
+        self.max_allocation != usize::MAX || self.max_containers != usize::MAX
+    }
+
+    /// Fail if a string, bytes value or buffer of `len` bytes is too long.
+    #[inline]
+    pub(crate) fn check_string(&self, len: u64, what: &str) -> Result<(), CodecError> {
+        if len > self.max_string_length as u64 {
+            return Err(CodecError::LimitExceeded(format!(
+                "{what} data too large: {len} bytes, maximum {}",
+                self.max_string_length
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Resource limits enforced by the encoders. `usize::MAX` means
+/// unlimited, the default; nesting deeper than 1000 levels always fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EncodeLimits {
+    /// Bytes of pickle written for one value or record, checked before
+    /// each value is written.
+    pub max_output_bytes: usize,
+    /// Nesting depth of containers and wrapped values.
+    pub max_depth: usize,
+    /// Items in a single list, tuple, dict or set.
+    pub max_collection_length: usize,
+}
+
+impl EncodeLimits {
+    const fn new() -> Self {
+        EncodeLimits {
+            max_output_bytes: usize::MAX,
+            max_depth: usize::MAX,
+            max_collection_length: usize::MAX,
+        }
+    }
+
+    /// The limits of this thread's encodings.
+    pub(crate) fn current() -> Self {
+        ENCODE_LIMITS.with(Cell::get)
+    }
+
+    /// Fail if `written` bytes of output already exceed the limit.
+    #[inline]
+    pub(crate) fn check_output(&self, written: usize) -> Result<(), CodecError> {
+        if written > self.max_output_bytes {
+            return Err(CodecError::LimitExceeded(format!(
+                "encoded pickle exceeds {} bytes",
+                self.max_output_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fail if a value at nesting `depth` is too deep.
+    #[inline]
+    pub(crate) fn check_depth(&self, depth: usize) -> Result<(), CodecError> {
+        if depth > self.max_depth {
+            return Err(CodecError::LimitExceeded(format!(
+                "encode nesting depth exceeds {}",
+                self.max_depth
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fail if a collection of `len` items is too long.
+    #[inline]
+    pub(crate) fn check_collection(&self, len: usize) -> Result<(), CodecError> {
+        if len > self.max_collection_length {
+            return Err(CodecError::LimitExceeded(format!(
+                "collection of {len} items to encode, maximum {}",
+                self.max_collection_length
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check a `PickleValue` about to be written at nesting `depth`, after
+    /// `written` bytes of output.
+    #[inline]
+    pub(crate) fn check_value(
+        &self,
+        val: &PickleValue,
+        depth: usize,
+        written: usize,
+    ) -> Result<(), CodecError> {
+        self.check_output(written)?;
+        self.check_depth(depth)?;
+        let len = match val {
+            PickleValue::List(items)
+            | PickleValue::Tuple(items)
+            | PickleValue::Set(items)
+            | PickleValue::FrozenSet(items) => items.len(),
+            PickleValue::Dict(pairs) => pairs.len(),
+            _ => return Ok(()),
+        };
+        self.check_collection(len)
+    }
+}
+
+impl Default for EncodeLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+thread_local! {
+    /// The encoder limits of this thread's encodings.
+    static ENCODE_LIMITS: Cell<EncodeLimits> = const { Cell::new(EncodeLimits::new()) };
+}
+
+/// Run `f` with the encodings it makes on this thread bounded by `limits`.
+///
+/// ```
+/// use zodb_json_codec::{encode_pickle, with_encode_limits, EncodeLimits, PickleValue};
+///
+/// let list = PickleValue::List(vec![PickleValue::Int(1); 3]);
+/// let mut limits = EncodeLimits::default();
+/// limits.max_collection_length = 2;
+/// assert!(encode_pickle(&list).is_ok());
+/// assert!(with_encode_limits(limits, || encode_pickle(&list)).is_err());
+/// ```
+pub fn with_encode_limits<R>(limits: EncodeLimits, f: impl FnOnce() -> R) -> R {
+    let _scope = EncodeLimitsScope::enter(limits);
+    f()
+}
+
+/// Sets the encoder limits of the current thread while alive.
+pub(crate) struct EncodeLimitsScope {
+    previous: EncodeLimits,
+}
+
+impl EncodeLimitsScope {
+    pub(crate) fn enter(limits: EncodeLimits) -> Self {
+        EncodeLimitsScope {
+            previous: ENCODE_LIMITS.with(|l| l.replace(limits)),
+        }
+    }
+}
+
+impl Drop for EncodeLimitsScope {
+    fn drop(&mut self) {
+        ENCODE_LIMITS.with(|l| l.set(self.previous));
+    }
+}
+
+/// Find the newline ending the line that starts at `start`, looking at no
+/// more than `max` bytes. Returns the newline's position.
+#[inline]
+pub(crate) fn find_line_end(
+    data: &[u8],
+    start: usize,
+    max: usize,
+    op: u8,
+) -> Result<usize, CodecError> {
+    let window_end = data.len().min(start.saturating_add(max).saturating_add(1));
+    let window = data.get(start..window_end).ok_or(CodecError::UnexpectedEof)?;
+    match window.iter().position(|&b| b == b'\n') {
+        Some(i) => Ok(start + i),
+        None if window.len() > max => {
+            Err(CodecError::LimitExceeded(format!(
+                "line argument of opcode 0x{op:02x} exceeds {max} bytes"
+            )))
+        }
+        None => Err(CodecError::UnexpectedEof),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_line_end() {
+        assert_eq!(find_line_end(b"Iabc\n.", 1, 3, INT).unwrap(), 4);
+        assert!(matches!(
+            find_line_end(b"Iabcd\n.", 1, 3, INT),
+            Err(CodecError::LimitExceeded(_))
+        ));
+        assert!(matches!(
+            find_line_end(b"Iab", 1, 3, INT),
+            Err(CodecError::UnexpectedEof)
+        ));
+        // Overlong and unterminated: the limit is reported, not EOF
+        assert!(matches!(
+            find_line_end(b"Iabcd", 1, 3, INT),
+            Err(CodecError::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_string() {
+        let limits = DecodeLimits {
+            max_string_length: 3,
+            ..DecodeLimits::default()
+        };
+        assert!(limits.check_string(3, "BINBYTES").is_ok());
+        let err = limits.check_string(4, "BINBYTES").unwrap_err();
+        assert!(matches!(err.root(), CodecError::LimitExceeded(_)));
+        assert!(err.to_string().contains("BINBYTES data too large"));
+        assert!(!DecodeLimits::default().counts_copies());
+    }
+
+    #[test]
+    fn test_encode_limits() {
+        let limits = EncodeLimits {
+            max_output_bytes: 10,
+            max_depth: 2,
+            max_collection_length: 2,
+        };
+        let list = |n| PickleValue::List(vec![PickleValue::None; n]);
+        assert!(limits.check_value(&list(2), 2, 10).is_ok());
+        for (val, depth, written, msg) in [
+            (list(3), 0, 0, "collection of 3 items"),
+            (list(0), 3, 0, "nesting depth exceeds 2"),
+            (PickleValue::None, 0, 11, "exceeds 10 bytes"),
+        ] {
+            let err = limits.check_value(&val, depth, written).unwrap_err();
+            assert!(matches!(err.root(), CodecError::LimitExceeded(_)));
+            assert!(err.to_string().contains(msg), "{err}");
+        }
+        let dict = PickleValue::Dict(vec![(PickleValue::None, PickleValue::None); 3]);
+        assert!(limits.check_value(&dict, 0, 0).is_err());
+        assert!(EncodeLimits::default().check_value(&list(1000), 999, 1 << 40).is_ok());
+    }
+
+    #[test]
+    fn test_for_opcode() {
+        let limits = LineLimits {
+            max_name: 1,
+            max_number: 2,
+            max_string: 3,
+        };
+        assert_eq!(limits.for_opcode(GLOBAL), 1);
+        assert_eq!(limits.for_opcode(LONG), 2);
+        assert_eq!(limits.for_opcode(UNICODE), 3);
+    }
+}
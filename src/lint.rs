@@ -0,0 +1,516 @@
+//! Record linting: detect pickle patterns that cause trouble downstream.
+//!
+//! Migration readiness reports need to know, across a whole storage, which
+//! records will not map cleanly to queryable JSON. `lint_record` walks a
+//! record once and reports:
+//!
+//! - `inline-persistent`: a persistent class (`PersistentMapping`,
+//!   `PersistentList`, BTrees) pickled inline instead of as a reference,
+//!   so it is not a separate object and is copied with its parent.
+//! - `huge-string`: a string or bytes value above `max_string_len`.
+//! - `deep-nesting`: containers nested deeper than `max_depth`.
+//! - `non-string-key`: a dict with keys that are not strings, which JSON
+//!   can only represent through `@d`.
+//! - `legacy-opcode`: protocol 0 text opcodes, Python 2 `str` opcodes or
+//!   Python 2 class instances (INST, OBJ).
+//! - `unknown-class`: a REDUCE with no typed marker, stored as `@reduce`.
+//! - `unresolvable-class`: a class in `__main__` or with an empty module
+//!   name, which no other process can import. The codec keeps such names
+//!   verbatim; the finding only flags them.
+//!
+//! Paths use the `extract_subtree` syntax relative to the state.
+
+use crate::btrees::classify_btree;
+use crate::decode::decode_zodb_pickles;
+use crate::error::CodecError;
+use crate::json::pickle_value_to_json;
+use crate::known_types::try_reduce_to_typed_json;
+use crate::limits::LineLimits;
+use crate::opcodes::*;
+use crate::types::PickleValue;
+use crate::zodb::{extract_class_info, skip_opcode};
+
+/// Default `max_string_len` (1 MB).
+pub const DEFAULT_LINT_MAX_STRING: usize = 1024 * 1024;
+/// Default `max_depth`.
+pub const DEFAULT_LINT_MAX_DEPTH: usize = 64;
+
+/// Thresholds for [`lint_record`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LintOptions {
+    /// Strings and bytes longer than this are reported.
+    pub max_string_len: usize,
+    /// Container nesting deeper than this is reported.
+    pub max_depth: usize,
+}
+
+impl Default for LintOptions {
+    fn default() -> Self {
+        LintOptions {
+            max_string_len: DEFAULT_LINT_MAX_STRING,
+            max_depth: DEFAULT_LINT_MAX_DEPTH,
+        }
+    }
+}
+
+/// The kind of a lint finding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LintCode {
+    InlinePersistent,
+    HugeString,
+    DeepNesting,
+    NonStringKey,
+    LegacyOpcode,
+    UnknownClass,
+    UnresolvableClass,
+}
+
+impl LintCode {
+    /// The kebab-case name used in reports (`"huge-string"`, ...).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LintCode::InlinePersistent => "inline-persistent",
+            LintCode::HugeString => "huge-string",
+            LintCode::DeepNesting => "deep-nesting",
+            LintCode::NonStringKey => "non-string-key",
+            LintCode::LegacyOpcode => "legacy-opcode",
+            LintCode::UnknownClass => "unknown-class",
+            LintCode::UnresolvableClass => "unresolvable-class",
+        }
+    }
+}
+
+/// One lint finding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LintWarning {
+    pub code: LintCode,
+    /// Location in the state (empty for the state itself or for
+    /// record-wide findings such as legacy opcodes).
+    pub path: String,
+    pub message: String,
+}
+
+/// Lint a ZODB record (class pickle + state pickle).
+pub fn lint_record(data: &[u8], options: &LintOptions) -> Result<Vec<LintWarning>, CodecError> {
+    let mut warnings = lint_opcodes(data)?;
+    let (class_val, state) = decode_zodb_pickles(data)?;
+    let (module, name) = extract_class_info(&class_val);
+    let mut linter = Linter {
+        options,
+        warnings: Vec::new(),
+        path: Vec::new(),
+    };
+    linter.check_class(&module, &name);
+    linter.visit(&state, 0);
+    warnings.append(&mut linter.warnings);
+    Ok(warnings)
+}
+
+/// Report legacy opcodes, one warning per opcode with its count.
+fn lint_opcodes(data: &[u8]) -> Result<Vec<LintWarning>, CodecError> {
+    const LEGACY: [(u8, &str); 12] = [
+        (INT, "INT"),
+        (LONG, "LONG"),
+        (FLOAT, "FLOAT"),
+        (STRING, "STRING"),
+        (UNICODE, "UNICODE"),
+        (PUT, "PUT"),
+        (GET, "GET"),
+        (PERSID, "PERSID"),
+        (BINSTRING, "BINSTRING"),
+        (SHORT_BINSTRING, "SHORT_BINSTRING"),
+        (INST, "INST"),
+        (OBJ, "OBJ"),
+    ];
+    let mut counts = [0usize; LEGACY.len()];
+    let limits = LineLimits::default();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (op, next) = skip_opcode(data, pos, &limits)?;
+        if let Some(i) = LEGACY.iter().position(|(o, _)| *o == op) {
+            counts[i] += 1;
+        }
+        pos = next;
+    }
+    Ok(LEGACY
+        .iter()
+        .zip(counts)
+        .filter(|(_, n)| *n > 0)
+        .map(|((op, name), n)| {
+            let why = match *op {
+                BINSTRING | SHORT_BINSTRING => "Python 2 str",
+                INST | OBJ => "Python 2 class instance",
+                _ => "protocol 0 text opcode",
+            };
+            LintWarning {
+                code: LintCode::LegacyOpcode,
+                path: String::new(),
+                message: format!("{n} x {name} ({why})"),
+            }
+        })
+        .collect())
+}
+
+/// Whether instances of `module.name` are persistent objects that should
+/// be stored as references rather than inline.
+fn is_persistent_class(module: &str, name: &str) -> bool {
+    matches!(
+        (module, name),
+        ("persistent", "Persistent")
+            | ("persistent.mapping", "PersistentMapping")
+            | ("persistent.list", "PersistentList")
+            | ("persistent.dict", "PersistentDict")
+            | ("BTrees.Length", "Length")
+    ) || classify_btree(module, name).is_some()
+}
+
+struct Linter<'a> {
+    options: &'a LintOptions,
+    warnings: Vec<LintWarning>,
+    path: Vec<String>,
+}
+
+impl Linter<'_> {
+    fn warn(&mut self, code: LintCode, message: String) {
+        self.warnings.push(LintWarning {
+            code,
+            path: self
+                .path
+                .iter()
+                .map(|s| s.replace('~', "~0").replace('/', "~1"))
+                .collect::<Vec<_>>()
+                .join("/"),
+            message,
+        });
+    }
+
+    fn child(&mut self, seg: String, val: &PickleValue, depth: usize) {
+        self.path.push(seg);
+        self.visit(val, depth);
+        self.path.pop();
+    }
+
+    fn visit(&mut self, val: &PickleValue, depth: usize) {
+        if depth > self.options.max_depth {
+            self.warn(
+                LintCode::DeepNesting,
+                format!("nesting deeper than {} levels", self.options.max_depth),
+            );
+            return;
+        }
+        match val {
+            PickleValue::String(s) => self.check_len(s.len(), "string"),
+            PickleValue::SurrogateString(b) => self.check_len(b.len(), "string"),
+            PickleValue::Bytes(b) => self.check_len(b.len(), "bytes"),
+            PickleValue::List(items)
+            | PickleValue::Tuple(items)
+            | PickleValue::Set(items)
+            | PickleValue::FrozenSet(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    self.child(i.to_string(), item, depth + 1);
+                }
+            }
+            PickleValue::Dict(pairs) => self.visit_pairs(pairs, depth),
+            PickleValue::Global { module, name } => self.check_class(module, name),
+            PickleValue::PersistentRef(inner) => {
+                if let PickleValue::Tuple(items) = inner.as_ref() {
+                    if let Some(PickleValue::Global { module, name }) = items.get(1) {
+                        self.check_class(module, name);
+                    }
+                }
+            }
+            PickleValue::Instance(inst) => {
+                if !inst.is_anonymous() {
+                    self.check_class(&inst.module, &inst.name);
+                }
+                if is_persistent_class(&inst.module, &inst.name) {
+                    self.warn(
+                        LintCode::InlinePersistent,
+                        format!("{}.{} pickled inline", inst.module, inst.name),
+                    );
+                }
+                self.visit(&inst.state, depth + 1);
+                if let Some(pairs) = &inst.dict_items {
+                    self.visit_pairs(pairs, depth);
+                }
+                if let Some(items) = &inst.list_items {
+                    for (i, item) in items.iter().enumerate() {
+                        self.child(i.to_string(), item, depth + 1);
+                    }
+                }
+            }
+            PickleValue::Reduce {
+                callable,
+                args,
+                dict_items,
+                list_items,
+                ..
+            } => {
+                self.check_reduce(callable, args);
+                self.visit(args, depth + 1);
+                if let Some(pairs) = dict_items {
+                    self.visit_pairs(pairs, depth);
+                }
+                if let Some(items) = list_items {
+                    for (i, item) in items.iter().enumerate() {
+                        self.child(i.to_string(), item, depth + 1);
+                    }
+                }
+            }
+            PickleValue::NewObjEx { cls, args, kwargs } => {
+                if let PickleValue::Global { module, name } = cls.as_ref() {
+                    self.check_class(module, name);
+                    if is_persistent_class(module, name) {
+                        self.warn(
+                            LintCode::InlinePersistent,
+                            format!("{module}.{name} pickled inline"),
+                        );
+                    }
+                }
+                self.visit(args, depth + 1);
+                self.visit(kwargs, depth + 1);
+            }
+            PickleValue::Shared { value, .. } => self.visit(value, depth),
+            _ => {}
+        }
+    }
+
+    fn visit_pairs(&mut self, pairs: &[(PickleValue, PickleValue)], depth: usize) {
+        let non_string = pairs
+            .iter()
+            .filter(|(k, _)| !matches!(k, PickleValue::String(_)))
+            .count();
+        if non_string > 0 {
+            self.warn(
+                LintCode::NonStringKey,
+                format!("{non_string} of {} dict keys are not strings", pairs.len()),
+            );
+        }
+        for (k, v) in pairs {
+            let seg = match k {
+                PickleValue::String(s) => s.to_string(),
+                PickleValue::Int(i) => i.to_string(),
+                PickleValue::Bytes(b) => String::from_utf8_lossy(b).into_owned(),
+                _ => "?".to_string(),
+            };
+            self.child(seg, v, depth + 1);
+        }
+    }
+
+    fn check_len(&mut self, len: usize, what: &str) {
+        if len > self.options.max_string_len {
+            self.warn(LintCode::HugeString, format!("{what} of {len} bytes"));
+        }
+    }
+
+    fn check_class(&mut self, module: &str, name: &str) {
+        if module.is_empty() {
+            self.warn(
+                LintCode::UnresolvableClass,
+                format!("class {name:?} has an empty module name"),
+            );
+        } else if module == "__main__" {
+            self.warn(
+                LintCode::UnresolvableClass,
+                format!("class __main__.{name} is only importable by the script that wrote it"),
+            );
+        }
+    }
+
+    fn check_reduce(&mut self, callable: &PickleValue, args: &PickleValue) {
+        let PickleValue::Global { module, name } = callable else {
+            return;
+        };
+        // copy_reg._reconstructor(cls, base, state): the class is the first
+        // argument
+        let class = match (module.as_str(), name.as_str(), args) {
+            ("copy_reg" | "copyreg", "_reconstructor", PickleValue::Tuple(items)) => {
+                match items.first() {
+                    Some(PickleValue::Global { module, name }) => Some((module, name)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let (cls_module, cls_name) = class.unwrap_or((module, name));
+        self.check_class(cls_module, cls_name);
+        if is_persistent_class(cls_module, cls_name) {
+            self.warn(
+                LintCode::InlinePersistent,
+                format!("{cls_module}.{cls_name} pickled inline"),
+            );
+        }
+        if matches!(try_reduce_to_typed_json(callable, args, None, &pickle_value_to_json), Ok(None)) {
+            self.warn(
+                LintCode::UnknownClass,
+                format!("no typed marker for {cls_module}.{cls_name}, stored as @reduce"),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::encode_pickle;
+    use crate::types::InstanceData;
+
+    fn record(state: PickleValue) -> Vec<u8> {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::String("myapp".into()),
+            PickleValue::String("Doc".into()),
+        ]);
+        let mut out = encode_pickle(&class).unwrap();
+        out.extend(encode_pickle(&state).unwrap());
+        out
+    }
+
+    fn codes(warnings: &[LintWarning]) -> Vec<&'static str> {
+        warnings.iter().map(|w| w.code.as_str()).collect()
+    }
+
+    fn s(v: &str) -> PickleValue {
+        PickleValue::String(v.into())
+    }
+
+    #[test]
+    fn test_clean_record() {
+        let data = record(PickleValue::Dict(vec![(s("title"), s("Hello"))]));
+        assert!(lint_record(&data, &LintOptions::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_huge_string_and_non_string_keys() {
+        let options = LintOptions {
+            max_string_len: 4,
+            ..LintOptions::default()
+        };
+        let data = record(PickleValue::Dict(vec![
+            (s("body"), s("too long")),
+            (PickleValue::Int(1), PickleValue::None),
+        ]));
+        let warnings = lint_record(&data, &options).unwrap();
+        assert_eq!(codes(&warnings), ["non-string-key", "huge-string"]);
+        assert_eq!(warnings[0].path, "");
+        assert_eq!(warnings[1].path, "body");
+    }
+
+    #[test]
+    fn test_deep_nesting_reported_once() {
+        let mut val = PickleValue::None;
+        for _ in 0..10 {
+            val = PickleValue::List(vec![val]);
+        }
+        let options = LintOptions {
+            max_depth: 5,
+            ..LintOptions::default()
+        };
+        let warnings = lint_record(&record(val), &options).unwrap();
+        assert_eq!(codes(&warnings), ["deep-nesting"]);
+        assert_eq!(warnings[0].path, "0/0/0/0/0/0");
+    }
+
+    #[test]
+    fn test_inline_persistent_and_unknown_class() {
+        let inline = PickleValue::Instance(Box::new(InstanceData {
+            module: "persistent.mapping".into(),
+            name: "PersistentMapping".into(),
+            state: Box::new(PickleValue::Dict(vec![])),
+            dict_items: None,
+            list_items: None,
+        }));
+        let reduce = PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global {
+                module: "myapp".into(),
+                name: "Money".into(),
+            }),
+            args: Box::new(PickleValue::Tuple(vec![s("1.00")])),
+            dict_items: None,
+            list_items: None,
+            newobj: false,
+        };
+        let set = PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global {
+                module: "builtins".into(),
+                name: "set".into(),
+            }),
+            args: Box::new(PickleValue::Tuple(vec![PickleValue::List(vec![])])),
+            dict_items: None,
+            list_items: None,
+            newobj: false,
+        };
+        let data = record(PickleValue::Dict(vec![
+            (s("a/b"), inline),
+            (s("price"), reduce),
+            (s("tags"), set),
+        ]));
+        let warnings = lint_record(&data, &LintOptions::default()).unwrap();
+        assert_eq!(codes(&warnings), ["inline-persistent", "unknown-class"]);
+        assert_eq!(warnings[0].path, "a~1b");
+        assert!(warnings[1].message.contains("myapp.Money"), "{}", warnings[1].message);
+    }
+
+    #[test]
+    fn test_shared_memo_record() {
+        // Written by one CPython pickler, as ZODB does: the class of the
+        // inline PersistentMapping is a BINGET of the class pickle's memo.
+        let data = b"\x80\x03cpersistent.mapping\nPersistentMapping\nq\x00N\x86q\x01.\
+            \x80\x03}q\x02X\x04\x00\x00\x00dataq\x03}q\x04X\x05\x00\x00\x00innerq\x05\
+            h\x00)\x81q\x06}q\x07h\x03}q\x08sbss.";
+        let warnings = lint_record(data, &LintOptions::default()).unwrap();
+        assert_eq!(codes(&warnings), ["inline-persistent"]);
+        assert_eq!(warnings[0].path, "data/inner");
+    }
+
+    #[test]
+    fn test_legacy_opcodes() {
+        // Python 2 protocol 0 state {'a': 1}: STRING key, INT value
+        let mut data = encode_pickle(&PickleValue::Tuple(vec![s("m"), s("C")])).unwrap();
+        data.extend_from_slice(b"(dS'a'\nI1\ns.");
+        let warnings = lint_record(&data, &LintOptions::default()).unwrap();
+        // Python 2 str keys decode as bytes
+        assert_eq!(codes(&warnings), ["legacy-opcode", "legacy-opcode", "non-string-key"]);
+        assert_eq!(warnings[0].message, "1 x INT (protocol 0 text opcode)");
+        assert_eq!(warnings[1].message, "1 x STRING (protocol 0 text opcode)");
+        // A classic class instance as the state's only value
+        let mut data = encode_pickle(&PickleValue::Tuple(vec![s("m"), s("C")])).unwrap();
+        data.extend_from_slice(b"\x80\x02}X\x01\x00\x00\x00a(cm\nD\nos.");
+        let warnings = lint_record(&data, &LintOptions::default()).unwrap();
+        assert_eq!(warnings[0].message, "1 x OBJ (Python 2 class instance)");
+    }
+
+    #[test]
+    fn test_unresolvable_class() {
+        let script_obj = PickleValue::Instance(Box::new(InstanceData::new(
+            "__main__",
+            "Note",
+            PickleValue::Dict(vec![]),
+        )));
+        let ref_to_orphan = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(vec![0; 8]),
+            PickleValue::Global {
+                module: "".into(),
+                name: "Orphan".into(),
+            },
+        ])));
+        let data = record(PickleValue::Dict(vec![
+            (s("note"), script_obj),
+            (s("other"), ref_to_orphan),
+        ]));
+        let warnings = lint_record(&data, &LintOptions::default()).unwrap();
+        assert_eq!(codes(&warnings), ["unresolvable-class", "unresolvable-class"]);
+        assert_eq!(warnings[0].path, "note");
+        assert!(warnings[0].message.contains("__main__.Note"), "{}", warnings[0].message);
+        assert_eq!(warnings[1].path, "other");
+
+        // The record's own class
+        let mut data = encode_pickle(&PickleValue::Tuple(vec![s("__main__"), s("Doc")])).unwrap();
+        data.extend(encode_pickle(&PickleValue::Dict(vec![])).unwrap());
+        let warnings = lint_record(&data, &LintOptions::default()).unwrap();
+        assert_eq!(codes(&warnings), ["unresolvable-class"]);
+        assert_eq!(warnings[0].path, "");
+    }
+}
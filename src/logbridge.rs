@@ -0,0 +1,263 @@
+//! `tracing` instrumentation and its bridge to Python's `logging`.
+//!
+//! The record entry points open a span per record (`size`, `module`,
+//! `name`) and generic fallbacks such as `@reduce` emit events. Without a
+//! subscriber this costs one relaxed atomic load per callsite. Rust users
+//! attach any `tracing` subscriber; Python users call `configure_logging`,
+//! which installs `PyLoggingLayer` as the global subscriber. The layer
+//! times each span and forwards span closes and events to a `logging`
+//! logger, with the fields in the record's `tracing_fields` attribute.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::Instant;
+
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::error::CodecError;
+
+/// Most verbose level forwarded to Python; 0 disables forwarding.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(0);
+static LOGGER: RwLock<Option<Py<PyAny>>> = RwLock::new(None);
+static INSTALLED: OnceLock<Result<(), String>> = OnceLock::new();
+
+fn level_rank(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 1,
+        Level::WARN => 2,
+        Level::INFO => 3,
+        Level::DEBUG => 4,
+        Level::TRACE => 5,
+    }
+}
+
+/// The Python `logging` level number for a `tracing` level.
+fn python_level(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 40,
+        Level::WARN => 30,
+        Level::INFO => 20,
+        Level::DEBUG => 10,
+        Level::TRACE => 5,
+    }
+}
+
+/// Parse a level name as accepted by `configure_logging`.
+pub(crate) fn parse_level(name: &str) -> Result<Level, CodecError> {
+    match name.to_ascii_uppercase().as_str() {
+        "ERROR" => Ok(Level::ERROR),
+        "WARN" | "WARNING" => Ok(Level::WARN),
+        "INFO" => Ok(Level::INFO),
+        "DEBUG" => Ok(Level::DEBUG),
+        "TRACE" => Ok(Level::TRACE),
+        _ => Err(CodecError::InvalidData(format!("unknown log level: {name}"))),
+    }
+}
+
+/// Forward codec events at `level` and above to `logger`, or stop
+/// forwarding with `None`. Installs the global subscriber on first use.
+pub(crate) fn configure(logger: Py<PyAny>, level: Option<Level>) -> Result<(), CodecError> {
+    let installed = INSTALLED.get_or_init(|| {
+        let subscriber = tracing_subscriber::registry().with(PyLoggingLayer);
+        tracing::subscriber::set_global_default(subscriber).map_err(|e| e.to_string())
+    });
+    if let Err(e) = installed {
+        return Err(CodecError::InvalidData(format!(
+            "cannot install tracing subscriber: {e}"
+        )));
+    }
+    *LOGGER.write().unwrap_or_else(|e| e.into_inner()) = level.map(|_| logger);
+    MAX_LEVEL.store(level.as_ref().map_or(0, level_rank), Ordering::Relaxed);
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FieldValue {
+    Int(i64),
+    UInt(u64),
+    Str(String),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Int(v) => write!(f, "{v}"),
+            FieldValue::UInt(v) => write!(f, "{v}"),
+            FieldValue::Str(v) => f.write_str(v),
+        }
+    }
+}
+
+/// Field values of a span or event, with the `message` kept apart.
+#[derive(Debug, Default)]
+struct Fields {
+    message: Option<String>,
+    values: Vec<(&'static str, FieldValue)>,
+}
+
+impl Fields {
+    fn set(&mut self, field: &Field, value: FieldValue) {
+        if field.name() == "message" {
+            self.message = Some(value.to_string());
+        } else if let Some(slot) = self.values.iter_mut().find(|(k, _)| *k == field.name()) {
+            slot.1 = value;
+        } else {
+            self.values.push((field.name(), value));
+        }
+    }
+
+    /// `message k=v k=v`, the text passed to `logging`.
+    fn render(&self, default_message: &str) -> String {
+        let mut text = self.message.as_deref().unwrap_or(default_message).to_string();
+        for (k, v) in &self.values {
+            text.push_str(&format!(" {k}={v}"));
+        }
+        text
+    }
+}
+
+impl Visit for Fields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, FieldValue::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, FieldValue::UInt(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, FieldValue::Str(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, FieldValue::Str(format!("{value:?}")));
+    }
+}
+
+struct SpanTiming {
+    start: Instant,
+    fields: Fields,
+}
+
+/// Subscriber layer forwarding to the configured Python logger.
+struct PyLoggingLayer;
+
+impl<S> Layer<S> for PyLoggingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        // The level can change at any time, so ask on every call
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        level_rank(metadata.level()) <= MAX_LEVEL.load(Ordering::Relaxed)
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanTiming {
+                start: Instant::now(),
+                fields,
+            });
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<SpanTiming>() {
+                values.record(&mut timing.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        emit(event.metadata().level(), event.metadata().name(), fields);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(timing) = span.extensions_mut().remove::<SpanTiming>() else {
+            return;
+        };
+        let mut fields = timing.fields;
+        let elapsed = u64::try_from(timing.start.elapsed().as_micros()).unwrap_or(u64::MAX);
+        fields.values.push(("elapsed_us", FieldValue::UInt(elapsed)));
+        emit(span.metadata().level(), span.name(), fields);
+    }
+}
+
+fn emit(level: &Level, default_message: &str, fields: Fields) {
+    Python::attach(|py| {
+        // Not holding the lock while logging: a handler may reconfigure
+        let logger = LOGGER
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|l| l.clone_ref(py));
+        let Some(logger) = logger else {
+            return;
+        };
+        let result = (|| {
+            let values = PyDict::new(py);
+            for (k, v) in &fields.values {
+                match v {
+                    FieldValue::Int(i) => values.set_item(k, i)?,
+                    FieldValue::UInt(u) => values.set_item(k, u)?,
+                    FieldValue::Str(s) => values.set_item(k, s)?,
+                }
+            }
+            let extra = PyDict::new(py);
+            extra.set_item("tracing_fields", values)?;
+            let kwargs = PyDict::new(py);
+            kwargs.set_item("extra", extra)?;
+            logger.bind(py).call_method(
+                "log",
+                (python_level(level), fields.render(default_message)),
+                Some(&kwargs),
+            )?;
+            Ok::<_, PyErr>(())
+        })();
+        if let Err(e) = result {
+            e.write_unraisable(py, None);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("debug").unwrap(), Level::DEBUG);
+        assert_eq!(parse_level("WARNING").unwrap(), Level::WARN);
+        assert!(parse_level("verbose").is_err());
+    }
+
+    #[test]
+    fn test_fields_render() {
+        let mut fields = Fields::default();
+        fields.values.push(("size", FieldValue::UInt(12)));
+        fields.values.push(("module", FieldValue::Str("myapp".into())));
+        assert_eq!(fields.render("decode_zodb_record"), "decode_zodb_record size=12 module=myapp");
+        fields.message = Some("fallback".into());
+        assert_eq!(fields.render("ignored"), "fallback size=12 module=myapp");
+    }
+}
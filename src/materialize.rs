@@ -0,0 +1,409 @@
+//! Materializing large BTrees by following their bucket chain, and
+//! splitting large inline BTrees into buckets.
+//!
+//! A BTree too large for one inline bucket stores only persistent
+//! references to its children (`@children`/`@first` in JSON). Consumers
+//! that want the logical mapping rather than the storage topology can
+//! supply a loader: starting at the first bucket, each bucket record is
+//! loaded, its items collected, and its `next` reference followed until
+//! the chain ends. The leaf chain holds every item in key order, so the
+//! interior BTree nodes never need to be loaded.
+//!
+//! The result is the state of the equivalent small BTree, so the usual
+//! flattening turns it into a single `@kv` (or `@ks`) for the whole tree.
+//!
+//! [`split_btree`] goes the other way on encode: a tree whose inline
+//! bucket holds more items than a BTrees bucket would is written as a
+//! chain of bucket records under new oids, with the tree record holding
+//! only the references.
+
+use std::collections::HashSet;
+
+use crate::binenc::hex_encode;
+use crate::btrees::{self, BTreeClassInfo, BTreeNodeKind};
+use crate::decode::decode_zodb_pickles;
+use crate::encode::encode_value_into;
+use crate::error::CodecError;
+use crate::types::PickleValue;
+use crate::opcodes::{PROTO, STOP};
+use crate::zodb::{build_class_pickle, extract_class_info};
+
+/// Return the inline state of a large BTree or TreeSet with all items of
+/// its buckets, loading each bucket record through `load(oid)`.
+///
+/// Returns `Ok(None)` if `state` is not the state of a large BTree (not a
+/// tree class, inline or empty), which then needs no loading. Errors from
+/// `load` are passed through; a bucket that is not a bucket of the tree's
+/// kind, or a chain that loops, is an error.
+///
+/// ```
+/// use zodb_json_codec::{classify_btree, encode_pickle, materialize_btree, PickleValue};
+///
+/// let oid = vec![0, 0, 0, 0, 0, 0, 0, 2];
+/// let bucket_ref = || {
+///     PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+///         PickleValue::Bytes(oid.clone()),
+///         PickleValue::None,
+///     ])))
+/// };
+/// // A bucket record: class, then ((1, 10, 2, 20),)
+/// let mut bucket = encode_pickle(&PickleValue::Tuple(vec![
+///     PickleValue::String("BTrees.IIBTree".into()),
+///     PickleValue::String("IIBucket".into()),
+/// ]))?;
+/// bucket.extend(encode_pickle(&PickleValue::Tuple(vec![PickleValue::Tuple(
+///     [1, 10, 2, 20].map(PickleValue::Int).to_vec(),
+/// )]))?);
+///
+/// let info = classify_btree("BTrees.IIBTree", "IIBTree").unwrap();
+/// let state = PickleValue::Tuple(vec![PickleValue::Tuple(vec![bucket_ref()]), bucket_ref()]);
+/// let inline = materialize_btree(&info, &state, |_oid: &[u8]| {
+///     Ok::<_, zodb_json_codec::CodecError>(bucket.clone())
+/// })?
+/// .unwrap();
+/// let flat = PickleValue::Tuple([1, 10, 2, 20].map(PickleValue::Int).to_vec());
+/// let nest = |v| PickleValue::Tuple(vec![v]);
+/// assert_eq!(inline, nest(nest(nest(flat))));
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn materialize_btree<E, F>(
+    info: &BTreeClassInfo,
+    state: &PickleValue,
+    mut load: F,
+) -> Result<Option<PickleValue>, E>
+where
+    E: From<CodecError>,
+    F: FnMut(&[u8]) -> Result<Vec<u8>, E>,
+{
+    let leaf_kind = match info.kind {
+        BTreeNodeKind::BTree => BTreeNodeKind::Bucket,
+        BTreeNodeKind::TreeSet => BTreeNodeKind::Set,
+        BTreeNodeKind::Bucket | BTreeNodeKind::Set => return Ok(None),
+    };
+    let PickleValue::Tuple(outer) = state else {
+        return Ok(None);
+    };
+    let [PickleValue::Tuple(children), first] = outer.as_slice() else {
+        return Ok(None);
+    };
+    if !btrees::children_has_refs(children) {
+        return Ok(None);
+    }
+
+    let mut items = Vec::new();
+    let mut seen = HashSet::new();
+    let mut next = Some(first.clone());
+    while let Some(bucket_ref) = next.take() {
+        let oid = ref_oid(&bucket_ref)
+            .ok_or_else(|| {
+                CodecError::InvalidData("BTree bucket link is not a persistent reference".into())
+            })?
+            .to_vec();
+        if !seen.insert(oid.clone()) {
+            return Err(CodecError::InvalidData(format!(
+                "BTree bucket chain loops at oid {}",
+                hex_encode(&oid)
+            ))
+            .into());
+        }
+        let record = load(&oid)?;
+        let (class_val, bucket_state) = decode_zodb_pickles(&record)?;
+        let (module, name) = extract_class_info(&class_val);
+        if btrees::classify_btree(&module, &name).map(|b| b.kind) != Some(leaf_kind) {
+            return Err(CodecError::InvalidData(format!(
+                "BTree bucket {} is a {module}.{name}, not a {leaf_kind:?}",
+                hex_encode(&oid)
+            ))
+            .into());
+        }
+        // ((data,),) or ((data,), next_ref)
+        let PickleValue::Tuple(mut parts) = bucket_state else {
+            return Err(bad_bucket(&oid).into());
+        };
+        if parts.len() == 2 {
+            next = parts.pop();
+        }
+        match (parts.pop(), parts.is_empty()) {
+            (Some(PickleValue::Tuple(data)), true) => items.extend(data),
+            _ => return Err(bad_bucket(&oid).into()),
+        }
+    }
+    Ok(Some(btrees::inline_btree_state(items)))
+}
+
+/// A large BTree split by [`split_btree`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct SplitBTree {
+    /// The tree's new state, referencing the buckets.
+    pub state: PickleValue,
+    /// `(oid, record)` of each bucket, in key order.
+    pub buckets: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Split the inline state of a BTree or TreeSet into bucket records of at
+/// most `bucket_size` entries, taking their oids from `new_oid()`.
+///
+/// `bucket_size` defaults to [`BTreeClassInfo::max_bucket_size`]. Returns
+/// `Ok(None)` if `state` is not an inline tree or fits in one bucket. The
+/// items must be in key order, as they are in a decoded tree; the split
+/// tree has a single level of buckets under the root. New oids must be 8
+/// bytes; errors from `new_oid` are passed through.
+///
+/// ```
+/// use zodb_json_codec::{classify_btree, split_btree, PickleValue};
+///
+/// let info = classify_btree("BTrees.IIBTree", "IIBTree").unwrap();
+/// // Five items, 0 -> 1, 2 -> 3, ..., in a small tree's inline bucket
+/// let nest = |v| PickleValue::Tuple(vec![v]);
+/// let state = nest(nest(nest(PickleValue::Tuple((0..10).map(PickleValue::Int).collect()))));
+/// let mut next = 0u64;
+/// let split = split_btree("BTrees.IIBTree", "IIBTree", &info, &state, Some(2), || {
+///     next += 1;
+///     Ok::<_, zodb_json_codec::CodecError>(next.to_be_bytes().to_vec())
+/// })?
+/// .unwrap();
+/// assert_eq!(split.buckets.len(), 3);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn split_btree<E, F>(
+    module: &str,
+    name: &str,
+    info: &BTreeClassInfo,
+    state: &PickleValue,
+    bucket_size: Option<usize>,
+    mut new_oid: F,
+) -> Result<Option<SplitBTree>, E>
+where
+    E: From<CodecError>,
+    F: FnMut() -> Result<Vec<u8>, E>,
+{
+    let bucket_name = match info.kind {
+        BTreeNodeKind::BTree => name.strip_suffix("BTree").map(|n| format!("{n}Bucket")),
+        BTreeNodeKind::TreeSet => name.strip_suffix("TreeSet").map(|n| format!("{n}Set")),
+        BTreeNodeKind::Bucket | BTreeNodeKind::Set => return Ok(None),
+    };
+    let items = match state {
+        PickleValue::Tuple(outer) if outer.len() == 1 => {
+            match btrees::unwrap_inline_btree(&outer[0]) {
+                Some(items) => items,
+                None => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    let bucket_size = bucket_size.unwrap_or_else(|| info.max_bucket_size());
+    if bucket_size == 0 {
+        return Err(CodecError::InvalidData("bucket_size must be positive".into()).into());
+    }
+    let step = if info.is_map { 2 } else { 1 };
+    if items.len() <= bucket_size * step {
+        return Ok(None);
+    }
+    let bucket_name = bucket_name.ok_or_else(|| {
+        CodecError::InvalidData(format!("cannot derive the bucket class of {module}.{name}"))
+    })?;
+
+    let chunks: Vec<&[PickleValue]> = items.chunks(bucket_size * step).collect();
+    let refs = chunks
+        .iter()
+        .map(|_| {
+            let oid = new_oid()?;
+            if oid.len() != 8 {
+                return Err(CodecError::InvalidData(format!(
+                    "new_oid must return 8-byte oids, got {} bytes",
+                    oid.len()
+                ))
+                .into());
+            }
+            Ok(PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+                PickleValue::Bytes(oid),
+                PickleValue::Global {
+                    module: module.to_string(),
+                    name: bucket_name.clone(),
+                },
+            ]))))
+        })
+        .collect::<Result<Vec<_>, E>>()?;
+
+    let mut buckets = Vec::with_capacity(chunks.len());
+    let mut children = Vec::with_capacity(chunks.len() * 2);
+    for (i, chunk) in chunks.iter().enumerate() {
+        if i > 0 {
+            children.push(chunk[0].clone());
+        }
+        children.push(refs[i].clone());
+        // ((data,),) or ((data,), next_ref)
+        let mut bucket_state = vec![PickleValue::Tuple(chunk.to_vec())];
+        bucket_state.extend(refs.get(i + 1).cloned());
+        let mut record = build_class_pickle(module, &bucket_name);
+        record.extend_from_slice(&[PROTO, 2]);
+        encode_value_into(&PickleValue::Tuple(bucket_state), &mut record)?;
+        record.push(STOP);
+        let oid = ref_oid(&refs[i]).unwrap_or_default().to_vec();
+        buckets.push((oid, record));
+    }
+    let state = PickleValue::Tuple(vec![PickleValue::Tuple(children), refs[0].clone()]);
+    Ok(Some(SplitBTree { state, buckets }))
+}
+
+fn bad_bucket(oid: &[u8]) -> CodecError {
+    CodecError::InvalidData(format!(
+        "BTree bucket {} has no bucket state",
+        hex_encode(oid)
+    ))
+}
+
+/// The oid of a persistent reference pickled as `(oid, klass)` or `oid`.
+fn ref_oid(val: &PickleValue) -> Option<&[u8]> {
+    let PickleValue::PersistentRef(inner) = val else {
+        return None;
+    };
+    match inner.as_ref() {
+        PickleValue::Bytes(oid) => Some(oid),
+        PickleValue::Tuple(items) => match items.first() {
+            Some(PickleValue::Bytes(oid)) => Some(oid),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btrees::classify_btree;
+    use crate::encode::encode_pickle;
+    use std::collections::HashMap;
+
+    fn pref(oid: u8) -> PickleValue {
+        PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, oid]),
+            PickleValue::None,
+        ])))
+    }
+
+    fn record(module: &str, name: &str, state: PickleValue) -> Vec<u8> {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::String(module.into()),
+            PickleValue::String(name.into()),
+        ]);
+        let mut out = encode_pickle(&class).unwrap();
+        out.extend(encode_pickle(&state).unwrap());
+        out
+    }
+
+    fn bucket(items: &[i64], next: Option<u8>) -> Vec<u8> {
+        let data = PickleValue::Tuple(items.iter().map(|&i| PickleValue::Int(i)).collect());
+        let mut state = vec![data];
+        state.extend(next.map(pref));
+        record("BTrees.IIBTree", "IIBucket", PickleValue::Tuple(state))
+    }
+
+    fn large_state() -> PickleValue {
+        PickleValue::Tuple(vec![
+            PickleValue::Tuple(vec![pref(2), PickleValue::Int(3), pref(3)]),
+            pref(2),
+        ])
+    }
+
+    fn loader(
+        records: HashMap<u8, Vec<u8>>,
+    ) -> impl FnMut(&[u8]) -> Result<Vec<u8>, CodecError> {
+        move |oid| {
+            records
+                .get(&oid[7])
+                .cloned()
+                .ok_or_else(|| CodecError::InvalidData(format!("no oid {}", oid[7])))
+        }
+    }
+
+    #[test]
+    fn test_follows_bucket_chain() {
+        let info = classify_btree("BTrees.IIBTree", "IIBTree").unwrap();
+        let mut load = loader(HashMap::from([
+            (2, bucket(&[1, 10, 2, 20], Some(3))),
+            (3, bucket(&[3, 30], None)),
+        ]));
+        let inline = materialize_btree(&info, &large_state(), &mut load).unwrap().unwrap();
+        let flat = [1, 10, 2, 20, 3, 30].map(PickleValue::Int).to_vec();
+        assert_eq!(inline, btrees::inline_btree_state(flat));
+    }
+
+    #[test]
+    fn test_small_tree_needs_no_loading() {
+        let info = classify_btree("BTrees.IIBTree", "IIBTree").unwrap();
+        let mut load = |_: &[u8]| -> Result<Vec<u8>, CodecError> { panic!("loaded") };
+        let inline = btrees::inline_btree_state(vec![PickleValue::Int(1), PickleValue::Int(2)]);
+        assert_eq!(materialize_btree(&info, &inline, &mut load).unwrap(), None);
+        assert_eq!(materialize_btree(&info, &PickleValue::None, &mut load).unwrap(), None);
+        let bucket_info = classify_btree("BTrees.IIBTree", "IIBucket").unwrap();
+        assert_eq!(materialize_btree(&bucket_info, &large_state(), &mut load).unwrap(), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let info = classify_btree("BTrees.IIBTree", "IIBTree").unwrap();
+        // A chain that loops back
+        let mut load = loader(HashMap::from([
+            (2, bucket(&[1, 10], Some(3))),
+            (3, bucket(&[3, 30], Some(2))),
+        ]));
+        let err = materialize_btree(&info, &large_state(), &mut load).unwrap_err();
+        assert!(err.to_string().contains("loops at oid 0000000000000002"), "{err}");
+
+        // Not a bucket
+        let mut load = loader(HashMap::from([(2, record("myapp", "Doc", PickleValue::None))]));
+        let err = materialize_btree(&info, &large_state(), &mut load).unwrap_err();
+        assert!(err.to_string().contains("is a myapp.Doc, not a Bucket"), "{err}");
+
+        // Loader errors pass through
+        let mut load = loader(HashMap::new());
+        let err = materialize_btree(&info, &large_state(), &mut load).unwrap_err();
+        assert!(err.to_string().contains("no oid 2"), "{err}");
+    }
+
+    #[test]
+    fn test_split_roundtrips_through_materialize() {
+        let info = classify_btree("BTrees.IIBTree", "IIBTree").unwrap();
+        let flat: Vec<PickleValue> = (0..14).map(PickleValue::Int).collect();
+        let state = btrees::inline_btree_state(flat.clone());
+        let mut next = 10u8;
+        let split = split_btree("BTrees.IIBTree", "IIBTree", &info, &state, Some(3), || {
+            next += 1;
+            Ok::<_, CodecError>(vec![0, 0, 0, 0, 0, 0, 0, next])
+        })
+        .unwrap()
+        .unwrap();
+        // 7 entries in buckets of 3: keys 0, 6 and 12 start the buckets
+        assert_eq!(split.buckets.len(), 3);
+        let PickleValue::Tuple(outer) = &split.state else { panic!() };
+        let PickleValue::Tuple(children) = &outer[0] else { panic!() };
+        assert_eq!(children[1], PickleValue::Int(6));
+        assert_eq!(children[3], PickleValue::Int(12));
+        let (class_val, _) = decode_zodb_pickles(&split.buckets[0].1).unwrap();
+        assert_eq!(
+            extract_class_info(&class_val),
+            ("BTrees.IIBTree".into(), "IIBucket".into())
+        );
+
+        let records = split.buckets.iter().map(|(oid, r)| (oid[7], r.clone())).collect();
+        let inline = materialize_btree(&info, &split.state, loader(records)).unwrap();
+        assert_eq!(inline, Some(state));
+    }
+
+    #[test]
+    fn test_split_not_needed() {
+        let info = classify_btree("BTrees.OOBTree", "OOTreeSet").unwrap();
+        let state = btrees::inline_btree_state((0..30).map(PickleValue::Int).collect());
+        let new_oid = || -> Result<Vec<u8>, CodecError> { panic!("allocated") };
+        let split = split_btree("BTrees.OOBTree", "OOTreeSet", &info, &state, None, new_oid);
+        assert_eq!(split.unwrap(), None);
+        let split = split_btree("BTrees.OOBTree", "OOTreeSet", &info, &PickleValue::None, Some(1), new_oid);
+        assert_eq!(split.unwrap(), None);
+        let split = split_btree("BTrees.OOBTree", "OOTreeSet", &info, &state, Some(29), || {
+            Ok::<_, CodecError>(vec![1])
+        });
+        assert!(split.unwrap_err().to_string().contains("8-byte oids"));
+    }
+}
@@ -0,0 +1,412 @@
+//! Memo planning for the pickle encoder.
+//!
+//! CPython's pickler writes every string, global and tuple once and refers
+//! back to it with `BINGET`, which keeps pickles with many repeated dict
+//! keys or persistent references small. The encoder does the same, but
+//! only for values that actually repeat: [`Memo::plan`] counts occurrences
+//! over the whole tree first, so a memo entry is written only when a later
+//! `BINGET` uses it, and the output never grows.
+//!
+//! Only immutable values are shared: strings, bytes, globals, and tuples
+//! made of those and of ints, bools and `None` (ZODB's `(oid, class)`
+//! persistent ids are such tuples). Sharing a list or dict would turn equal
+//! but distinct containers into one object after unpickling.
+//!
+//! The encoder writes no more memo entries than the decoder accepts
+//! (100,000); further repeats are written in full.
+
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::decode::MAX_MEMO_SIZE;
+use crate::encode::{builtins_module, copyreg_module, MAX_DEPTH};
+use crate::types::{AnonymousBuild, PickleValue};
+
+/// What the encoder should do with a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MemoAction {
+    /// Write the value; it is not memoized.
+    Write,
+    /// Write the value, then store it in the memo (see [`Memo::store`]).
+    WriteAndStore,
+    /// Refer to the memo entry with this index.
+    Get(u32),
+}
+
+/// A memoizable value, compared and hashed by content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum MemoKey<'a> {
+    /// A class or function global, from a `Global` value or an instance.
+    Class(&'a str, &'a str),
+    /// A string, bytes or tuple value.
+    Value(ValueKey<'a>),
+}
+
+impl<'a> MemoKey<'a> {
+    /// Key of `val`, if it may be shared.
+    pub(crate) fn of(val: &'a PickleValue) -> Option<Self> {
+        match val {
+            PickleValue::Global { module, name } => Some(MemoKey::Class(module, name)),
+            _ if is_memoizable(val) => Some(MemoKey::Value(ValueKey(val))),
+            _ => None,
+        }
+    }
+}
+
+/// Occurrence counts and assigned memo indices of the values of one pickle.
+pub(crate) struct Memo<'a> {
+    slots: HashMap<MemoKey<'a>, Slot>,
+    protocol: u8,
+    len: u32,
+}
+
+#[derive(Default)]
+struct Slot {
+    count: u32,
+    index: Option<u32>,
+}
+
+impl<'a> Memo<'a> {
+    /// Count the memoizable values of `root` as the encoder writes them
+    /// in `protocol`.
+    pub(crate) fn plan(root: &'a PickleValue, protocol: u8) -> Self {
+        let mut memo = Memo {
+            slots: HashMap::new(),
+            protocol,
+            len: 0,
+        };
+        memo.scan(root, 0);
+        memo.slots.retain(|_, slot| slot.count > 1);
+        memo
+    }
+
+    /// Count one occurrence; true if it is the first.
+    fn count(&mut self, key: MemoKey<'a>) -> bool {
+        let slot = self.slots.entry(key).or_default();
+        slot.count += 1;
+        slot.count == 1
+    }
+
+    fn scan(&mut self, val: &'a PickleValue, depth: usize) {
+        if depth > MAX_DEPTH {
+            // The encoder rejects the value anyway
+            return;
+        }
+        if let Some(key) = MemoKey::of(val) {
+            if !self.count(key) {
+                // Written as a BINGET: its contents are not written again
+                return;
+            }
+        }
+        match val {
+            PickleValue::Bytes(b) if self.protocol < 3 => {
+                let (module, name) = match b.is_empty() {
+                    true => (builtins_module(self.protocol), "bytes"),
+                    false => ("_codecs", "encode"),
+                };
+                self.count(MemoKey::Class(module, name));
+            }
+            PickleValue::Set(items) | PickleValue::FrozenSet(items) => {
+                if self.protocol < 4 {
+                    let name = match val {
+                        PickleValue::Set(_) => "set",
+                        _ => "frozenset",
+                    };
+                    self.count(MemoKey::Class(builtins_module(self.protocol), name));
+                }
+                self.scan_all(items, depth);
+            }
+            PickleValue::List(items) | PickleValue::Tuple(items) => self.scan_all(items, depth),
+            PickleValue::Dict(pairs) => self.scan_pairs(pairs, depth),
+            PickleValue::Instance(inst) => {
+                match inst.anonymous_build() {
+                    Some(AnonymousBuild::Reduce {
+                        callable,
+                        args,
+                        state,
+                    }) => {
+                        self.scan(callable, depth + 1);
+                        self.scan(args, depth + 1);
+                        self.scan(state, depth + 1);
+                    }
+                    Some(AnonymousBuild::Object { obj, state }) => {
+                        self.scan(obj, depth + 1);
+                        self.scan(state, depth + 1);
+                    }
+                    None => {
+                        self.count(MemoKey::Class(&inst.module, &inst.name));
+                        self.scan(&inst.state, depth + 1);
+                    }
+                }
+                self.scan_items(
+                    inst.dict_items.as_deref(),
+                    inst.list_items.as_deref(),
+                    depth,
+                );
+            }
+            PickleValue::PersistentRef(inner) | PickleValue::Shared { value: inner, .. } => {
+                self.scan(inner, depth + 1)
+            }
+            PickleValue::Reduce {
+                callable,
+                args,
+                dict_items,
+                list_items,
+                ..
+            } => {
+                self.scan(callable, depth + 1);
+                self.scan(args, depth + 1);
+                self.scan_items(dict_items.as_deref(), list_items.as_deref(), depth);
+            }
+            PickleValue::NewObjEx { cls, args, kwargs } => {
+                if self.protocol < 4 {
+                    self.count(MemoKey::Class(copyreg_module(self.protocol), "__newobj_ex__"));
+                }
+                self.scan(cls, depth + 1);
+                self.scan(args, depth + 1);
+                self.scan(kwargs, depth + 1);
+            }
+            _ => {}
+        }
+    }
+
+    fn scan_all(&mut self, items: &'a [PickleValue], depth: usize) {
+        for item in items {
+            self.scan(item, depth + 1);
+        }
+    }
+
+    fn scan_pairs(&mut self, pairs: &'a [(PickleValue, PickleValue)], depth: usize) {
+        for (k, v) in pairs {
+            self.scan(k, depth + 1);
+            self.scan(v, depth + 1);
+        }
+    }
+
+    fn scan_items(
+        &mut self,
+        dict_items: Option<&'a Vec<(PickleValue, PickleValue)>>,
+        list_items: Option<&'a Vec<PickleValue>>,
+        depth: usize,
+    ) {
+        if let Some(pairs) = dict_items {
+            self.scan_pairs(pairs, depth);
+        }
+        if let Some(items) = list_items {
+            self.scan_all(items, depth);
+        }
+    }
+
+    /// Decide how to write the value with this key.
+    pub(crate) fn action(&self, key: MemoKey<'a>) -> MemoAction {
+        match self.slots.get(&key) {
+            Some(Slot {
+                index: Some(index), ..
+            }) => MemoAction::Get(*index),
+            // Stay within what the decoder accepts
+            Some(_) if (self.len as usize) < MAX_MEMO_SIZE => MemoAction::WriteAndStore,
+            _ => MemoAction::Write,
+        }
+    }
+
+    /// Take the next memo index for a value that is not planned, such as
+    /// a `Shared` container; `None` once the memo is full.
+    pub(crate) fn reserve(&mut self) -> Option<u32> {
+        if self.len as usize >= MAX_MEMO_SIZE {
+            return None;
+        }
+        self.len += 1;
+        Some(self.len - 1)
+    }
+
+    /// Assign the next memo index to the value just written.
+    pub(crate) fn store(&mut self, key: MemoKey<'a>) -> u32 {
+        let index = self.len;
+        self.len += 1;
+        if let Some(slot) = self.slots.get_mut(&key) {
+            slot.index = Some(index);
+        }
+        index
+    }
+}
+
+fn is_memoizable(val: &PickleValue) -> bool {
+    match val {
+        PickleValue::String(_) | PickleValue::Bytes(_) | PickleValue::Global { .. } => true,
+        PickleValue::Tuple(items) => !items.is_empty() && items.iter().all(is_tuple_item),
+        _ => false,
+    }
+}
+
+fn is_tuple_item(val: &PickleValue) -> bool {
+    matches!(
+        val,
+        PickleValue::None | PickleValue::Bool(_) | PickleValue::Int(_)
+    ) || is_memoizable(val)
+}
+
+/// A string, bytes or tuple value as a memo key.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ValueKey<'a>(&'a PickleValue);
+
+impl PartialEq for ValueKey<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        // Memoizable values contain no floats, so this is an equivalence
+        self.0 == other.0
+    }
+}
+
+impl Eq for ValueKey<'_> {}
+
+impl Hash for ValueKey<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_value(self.0, state);
+    }
+}
+
+fn hash_value<H: Hasher>(val: &PickleValue, state: &mut H) {
+    std::mem::discriminant(val).hash(state);
+    match val {
+        PickleValue::Bool(b) => b.hash(state),
+        PickleValue::Int(i) => i.hash(state),
+        PickleValue::String(s) => s.hash(state),
+        PickleValue::Bytes(b) => b.hash(state),
+        PickleValue::Global { module, name } => (module, name).hash(state),
+        PickleValue::Tuple(items) => {
+            items.len().hash(state);
+            for item in items {
+                hash_value(item, state);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(v: &str) -> PickleValue {
+        PickleValue::String(v.into())
+    }
+
+    fn key(val: &PickleValue) -> MemoKey<'_> {
+        MemoKey::of(val).unwrap()
+    }
+
+    #[test]
+    fn test_only_repeated_values_are_stored() {
+        let val = PickleValue::List(vec![s("a"), s("b"), s("a"), PickleValue::Int(1)]);
+        let PickleValue::List(items) = &val else {
+            unreachable!()
+        };
+        let mut memo = Memo::plan(&val, 3);
+        assert_eq!(memo.action(key(&items[1])), MemoAction::Write);
+        assert!(MemoKey::of(&items[3]).is_none());
+        assert_eq!(memo.action(key(&items[0])), MemoAction::WriteAndStore);
+        assert_eq!(memo.store(key(&items[0])), 0);
+        assert_eq!(memo.action(key(&items[2])), MemoAction::Get(0));
+    }
+
+    #[test]
+    fn test_containers_are_not_shared() {
+        let list = PickleValue::List(vec![s("x")]);
+        let val = PickleValue::Tuple(vec![list.clone(), list]);
+        let PickleValue::Tuple(items) = &val else {
+            unreachable!()
+        };
+        assert!(MemoKey::of(&items[0]).is_none());
+        // The strings inside are shared
+        let PickleValue::List(inner) = &items[0] else {
+            unreachable!()
+        };
+        assert_eq!(
+            Memo::plan(&val, 3).action(key(&inner[0])),
+            MemoAction::WriteAndStore
+        );
+    }
+
+    #[test]
+    fn test_repeated_tuple_contents_are_not_counted_twice() {
+        let global = PickleValue::Global {
+            module: "myapp".into(),
+            name: "Doc".into(),
+        };
+        let pid = PickleValue::Tuple(vec![PickleValue::Bytes(vec![0; 8]), global]);
+        let val = PickleValue::List(vec![pid.clone(), pid]);
+        let PickleValue::List(items) = &val else {
+            unreachable!()
+        };
+        let memo = Memo::plan(&val, 3);
+        assert_eq!(memo.action(key(&items[0])), MemoAction::WriteAndStore);
+        assert_eq!(
+            memo.action(MemoKey::Class("myapp", "Doc")),
+            MemoAction::Write
+        );
+    }
+
+    #[test]
+    fn test_instance_classes_and_globals_share_keys() {
+        let inst = PickleValue::Instance(Box::new(crate::types::InstanceData::new(
+            "myapp",
+            "Doc",
+            PickleValue::Dict(vec![]),
+        )));
+        let global = PickleValue::Global {
+            module: "myapp".into(),
+            name: "Doc".into(),
+        };
+        let val = PickleValue::List(vec![inst, global]);
+        let memo = Memo::plan(&val, 3);
+        assert_eq!(
+            memo.action(MemoKey::Class("myapp", "Doc")),
+            MemoAction::WriteAndStore
+        );
+    }
+
+    #[test]
+    fn test_builtin_globals_depend_on_protocol() {
+        let val = PickleValue::List(vec![PickleValue::Set(vec![]), PickleValue::Set(vec![])]);
+        let set_class = MemoKey::Class("builtins", "set");
+        assert_eq!(
+            Memo::plan(&val, 3).action(set_class),
+            MemoAction::WriteAndStore
+        );
+        assert_eq!(Memo::plan(&val, 4).action(set_class), MemoAction::Write);
+        let py2_set = MemoKey::Class("__builtin__", "set");
+        assert_eq!(
+            Memo::plan(&val, 2).action(py2_set),
+            MemoAction::WriteAndStore
+        );
+    }
+
+    #[test]
+    fn test_int_and_bool_differ() {
+        let a = PickleValue::Tuple(vec![PickleValue::Int(1)]);
+        let b = PickleValue::Tuple(vec![PickleValue::Bool(true)]);
+        assert_ne!(key(&a), key(&b));
+        let val = PickleValue::List(vec![a.clone(), b]);
+        assert_eq!(Memo::plan(&val, 3).action(key(&a)), MemoAction::Write);
+    }
+
+    #[test]
+    fn test_memo_size_is_capped() {
+        let strings: Vec<PickleValue> = (0..=MAX_MEMO_SIZE).map(|i| s(&i.to_string())).collect();
+        let val = PickleValue::Tuple(vec![
+            PickleValue::List(strings.clone()),
+            PickleValue::List(strings),
+        ]);
+        let PickleValue::Tuple(lists) = &val else {
+            unreachable!()
+        };
+        let PickleValue::List(first) = &lists[0] else {
+            unreachable!()
+        };
+        let mut memo = Memo::plan(&val, 3);
+        for item in &first[..MAX_MEMO_SIZE] {
+            assert_eq!(memo.action(key(item)), MemoAction::WriteAndStore);
+            memo.store(key(item));
+        }
+        assert_eq!(memo.action(key(&first[MAX_MEMO_SIZE])), MemoAction::Write);
+    }
+}
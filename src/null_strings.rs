@@ -0,0 +1,144 @@
+//! Strings with null bytes in the PostgreSQL JSON form.
+//!
+//! PostgreSQL JSONB cannot store `\u0000`, so the `_pg` conversions write
+//! a string holding a NUL as `{"@ns": base64}` of its UTF-8 bytes, and a
+//! dict key holding one as the object key `"@ns:" + base64`:
+//!
+//! ```text
+//! {"a\0b": "c\0d"}  ->  {"@ns:YQBi": {"@ns": "YwBk"}}
+//! ```
+//!
+//! Encoding turns both back into the original strings when the input is
+//! declared PostgreSQL-safe (see [`with_pg_safe_input`]); otherwise an
+//! `@ns` dict or `@ns:` key is ordinary data and is kept as it is. Even
+//! then, a key is only restored when its base64 decodes to text with a
+//! NUL.
+
+use std::cell::Cell;
+
+use crate::binenc::{b64_decode, b64_encode};
+use crate::error::CodecError;
+use crate::types::PickleValue;
+
+/// Prefix of an object key with null bytes.
+const NULL_KEY_PREFIX: &str = "@ns:";
+
+thread_local! {
+    /// Whether encoding restores `@ns` markers and keys (off by default).
+    static RESTORE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f` with the encodings it makes on this thread reading
+/// `{"@ns": base64}` markers and `"@ns:"` keys, as the `_pg` conversions
+/// write them, back into strings with null bytes.
+///
+/// ```
+/// use zodb_json_codec::{json_to_pickle_value, with_pg_safe_input, PickleValue};
+///
+/// let json = serde_json::json!({"@ns": "YQBi"});
+/// let val = with_pg_safe_input(|| json_to_pickle_value(&json))?;
+/// assert_eq!(val, PickleValue::String("a\0b".into()));
+/// // Without it, the marker is an ordinary dict
+/// assert!(matches!(json_to_pickle_value(&json)?, PickleValue::Dict(_)));
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn with_pg_safe_input<R>(f: impl FnOnce() -> R) -> R {
+    let _scope = PgSafeScope::enter(true);
+    f()
+}
+
+/// Sets `@ns` restoration on the current thread while alive.
+pub(crate) struct PgSafeScope {
+    previous: bool,
+}
+
+impl PgSafeScope {
+    pub(crate) fn enter(enabled: bool) -> Self {
+        PgSafeScope {
+            previous: RESTORE.with(|r| r.replace(enabled)),
+        }
+    }
+}
+
+impl Drop for PgSafeScope {
+    fn drop(&mut self) {
+        RESTORE.with(|r| r.set(self.previous));
+    }
+}
+
+/// Whether `@ns` markers and keys are read back as strings.
+#[inline]
+pub(crate) fn restoring() -> bool {
+    RESTORE.with(Cell::get)
+}
+
+/// The object key written for a dict key with null bytes.
+pub(crate) fn escape_key(key: &str) -> String {
+    format!("{NULL_KEY_PREFIX}{}", b64_encode(key))
+}
+
+/// The string of an `@ns` marker.
+pub(crate) fn unescape(b64: &str) -> Result<PickleValue, CodecError> {
+    match String::from_utf8(b64_decode(b64)?) {
+        Ok(text) => Ok(PickleValue::String(text.into())),
+        Err(_) => Err(CodecError::Json(format!("@ns string {b64:?} is not UTF-8"))),
+    }
+}
+
+/// The original text of a key written by [`escape_key`], or `None` for
+/// any other key or without [`with_pg_safe_input`].
+#[inline]
+pub(crate) fn unescape_key(key: &str) -> Option<String> {
+    if !restoring() {
+        return None;
+    }
+    let b64 = key.strip_prefix(NULL_KEY_PREFIX)?;
+    let text = String::from_utf8(b64_decode(b64).ok()?).ok()?;
+    text.contains('\0').then_some(text)
+}
+
+/// A string dict key as a `PickleValue`, restoring null bytes.
+#[inline]
+pub(crate) fn key_value(key: &str) -> PickleValue {
+    match unescape_key(key) {
+        Some(text) => PickleValue::String(text.into()),
+        None => PickleValue::String(key.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_roundtrip() {
+        let key = escape_key("a\0b");
+        assert_eq!(key, "@ns:YQBi");
+        with_pg_safe_input(|| {
+            assert_eq!(unescape_key(&key).as_deref(), Some("a\0b"));
+            assert_eq!(key_value(&key), PickleValue::String("a\0b".into()));
+        });
+    }
+
+    #[test]
+    fn test_other_keys_kept() {
+        // Not base64, no NUL, or not UTF-8
+        for key in ["title", "@ns:", "@ns:not base64", "@ns:YWJj", "@ns:/wA="] {
+            with_pg_safe_input(|| assert_eq!(unescape_key(key), None, "{key}"));
+            assert_eq!(key_value(key), PickleValue::String(key.into()));
+        }
+    }
+
+    #[test]
+    fn test_keys_kept_without_pg_safe_input() {
+        assert_eq!(unescape_key("@ns:YQBi"), None);
+        assert_eq!(key_value("@ns:YQBi"), PickleValue::String("@ns:YQBi".into()));
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("YQBi").unwrap(), PickleValue::String("a\0b".into()));
+        assert!(unescape("/wA=").is_err());
+        assert!(unescape("not base64").is_err());
+    }
+}
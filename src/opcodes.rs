@@ -1,8 +1,8 @@
-/// Pickle protocol opcodes (protocol 0-5).
-/// We focus on protocol 2-3 which ZODB typically uses,
-/// but include protocol 4-5 opcodes for future support.
-///
-/// Reference: Python pickletools.py and PEP 3154 (protocol 4), PEP 574 (protocol 5)
+//! Pickle protocol opcodes (protocol 0-5).
+//! We focus on protocol 2-3 which ZODB typically uses,
+//! but include protocol 4-5 opcodes for future support.
+//!
+//! Reference: Python pickletools.py and PEP 3154 (protocol 4), PEP 574 (protocol 5)
 
 // -- Protocol 0/1 (text-based, legacy) --
 pub const MARK: u8 = b'('; // push special markobject on stack
@@ -15,7 +15,7 @@ pub const LONG: u8 = b'L'; // push long; decimal string argument
 pub const NONE: u8 = b'N'; // push None
 pub const REDUCE: u8 = b'R'; // apply callable to argtuple, both on stack
 pub const STRING: u8 = b'S'; // push string; NL-terminated string argument
-pub const UNICODE: u8 = b'V'; // push Unicode string; NL-terminated UTF-8
+pub const UNICODE: u8 = b'V'; // push Unicode string; raw-unicode-escaped, NL-terminated
 pub const APPEND: u8 = b'a'; // append stack top to list below it
 pub const BUILD: u8 = b'b'; // call __setstate__ or update __dict__
 pub const GLOBAL: u8 = b'c'; // push class/callable by module\nname\n
@@ -32,6 +32,7 @@ pub const EMPTY_TUPLE: u8 = b')'; // push empty tuple
 pub const SETITEMS: u8 = b'u'; // modify dict by adding topmost key+value pairs
 pub const PERSID: u8 = b'P'; // push persistent id (string arg)
 pub const BINPERSID: u8 = b'Q'; // push persistent id from stack
+pub const INST: u8 = b'i'; // build class instance; module\nname\n, args above MARK
 
 // -- Protocol 1 (binary) --
 pub const BININT: u8 = b'J'; // push 4-byte signed int
@@ -48,6 +49,7 @@ pub const LONG_BINPUT: u8 = b'r'; // store stack top in memo by 4-byte index
 pub const BINFLOAT: u8 = b'G'; // push float; binary 8-byte IEEE
 pub const BINBYTES: u8 = b'B'; // push bytes; counted binary
 pub const SHORT_BINBYTES: u8 = b'C'; // push bytes; counted <= 255
+pub const OBJ: u8 = b'o'; // build class instance; class and args above MARK
 
 // -- Protocol 2 --
 pub const PROTO: u8 = 0x80; // identify pickle protocol
@@ -76,9 +78,6 @@ pub const MEMOIZE: u8 = 0x94; // store top in memo (auto-incrementing key)
 pub const FRAME: u8 = 0x95; // framing for protocol 4+
 
 // -- Protocol 5 --
-#[allow(dead_code)]
 pub const BYTEARRAY8: u8 = 0x96; // push bytearray
-#[allow(dead_code)]
 pub const NEXT_BUFFER: u8 = 0x97; // push next out-of-band buffer
-#[allow(dead_code)]
 pub const READONLY_BUFFER: u8 = 0x98; // make top-of-stack read-only
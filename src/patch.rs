@@ -0,0 +1,287 @@
+//! JSON Patch (RFC 6902) applied to ZODB records.
+//!
+//! The record is decoded to its JSON form, `{"@cls": ..., "@s": ...}` as
+//! from `decode_zodb_record`, the operations are applied in order, and the
+//! document is encoded back to a record. Paths are JSON Pointers into that
+//! document, the same paths `diff_zodb_records` reports (`/@s/title`), so
+//! values are written in marker form: `{"@dt": ...}`, `{"@ref": ...}`.
+//!
+//! All six operations are supported: `add`, `remove`, `replace`, `move`,
+//! `copy` and `test`. Unlike in `diff_zodb_records`, a `@kv` list is an
+//! ordinary array here: `/@s/@kv/0/1` is the value of the first pair.
+//! A failing operation fails the whole patch, so either every operation
+//! applies or no record is produced.
+
+use serde_json::Value;
+
+use crate::error::CodecError;
+use crate::zodb::{decode_zodb_record, encode_zodb_record};
+
+/// Apply a JSON Patch to a ZODB record and return the re-encoded record.
+///
+/// `patch` is an array of operation objects with `op`, `path` and, by
+/// operation, `value` or `from`.
+///
+/// ```
+/// use serde_json::json;
+/// use zodb_json_codec::{apply_patch_to_record, decode_zodb_pickles, PickleValue};
+///
+/// // Class pickle ("mod", "Cls") followed by {"a": 1}
+/// let record = b"\x80\x03X\x03\x00\x00\x00modX\x03\x00\x00\x00Cls\x86N\x86.\
+///                \x80\x03}X\x01\x00\x00\x00aK\x01s.";
+/// let patch = json!([{"op": "remove", "path": "/@s/a"}]);
+/// let patched = apply_patch_to_record(record, &patch)?;
+/// assert_eq!(decode_zodb_pickles(&patched)?.1, PickleValue::Dict(vec![]));
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn apply_patch_to_record(record: &[u8], patch: &Value) -> Result<Vec<u8>, CodecError> {
+    let ops = patch
+        .as_array()
+        .ok_or_else(|| CodecError::InvalidData("patch must be an array".to_string()))?;
+    let mut doc = decode_zodb_record(record)?;
+    for (i, op) in ops.iter().enumerate() {
+        apply_op(&mut doc, op)
+            .map_err(|msg| CodecError::InvalidData(format!("patch operation {i}: {msg}")))?;
+    }
+    encode_zodb_record(doc)
+}
+
+fn apply_op(doc: &mut Value, op: &Value) -> Result<(), String> {
+    let field = |name: &str| op.get(name).ok_or_else(|| format!("missing {name:?}"));
+    let pointer = |name: &str| -> Result<Vec<String>, String> {
+        match field(name)? {
+            Value::String(s) => parse_pointer(s),
+            _ => Err(format!("{name:?} must be a string")),
+        }
+    };
+    let path = pointer("path")?;
+    match field("op")?.as_str() {
+        Some("add") => add(doc, &path, field("value")?.clone()),
+        Some("remove") => remove(doc, &path).map(drop),
+        Some("replace") => {
+            let target = get_mut(doc, &path)?;
+            *target = field("value")?.clone();
+            Ok(())
+        }
+        Some("move") => {
+            let from = pointer("from")?;
+            if path.len() > from.len() && path.starts_with(&from) {
+                return Err("cannot move a value into itself".to_string());
+            }
+            let value = remove(doc, &from)?;
+            add(doc, &path, value)
+        }
+        Some("copy") => {
+            let value = get_mut(doc, &pointer("from")?)?.clone();
+            add(doc, &path, value)
+        }
+        Some("test") => {
+            if get_mut(doc, &path)? == field("value")? {
+                Ok(())
+            } else {
+                Err(format!("test failed at {}", join(&path)))
+            }
+        }
+        Some(other) => Err(format!("unknown op {other:?}")),
+        None => Err("\"op\" must be a string".to_string()),
+    }
+}
+
+/// Split a JSON Pointer into unescaped reference tokens.
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, String> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = pointer.strip_prefix('/') else {
+        return Err(format!("path {pointer:?} must start with '/'"));
+    };
+    Ok(rest
+        .split('/')
+        .map(|s| s.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+fn join(tokens: &[String]) -> String {
+    tokens
+        .iter()
+        .map(|t| format!("/{}", t.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+fn array_index(token: &str, len: usize) -> Option<usize> {
+    // RFC 6901: no leading zeros, no sign
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) {
+        return None;
+    }
+    if !token.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok().filter(|&i| i < len)
+}
+
+fn get_mut<'a>(doc: &'a mut Value, path: &[String]) -> Result<&'a mut Value, String> {
+    let mut node = doc;
+    for (depth, token) in path.iter().enumerate() {
+        node = match node {
+            Value::Object(map) => map.get_mut(token),
+            Value::Array(items) => {
+                let len = items.len();
+                array_index(token, len).map(move |i| &mut items[i])
+            }
+            _ => None,
+        }
+        .ok_or_else(|| format!("path {} not found", join(&path[..=depth])))?;
+    }
+    Ok(node)
+}
+
+/// The container holding the target of `path`, and the last token.
+fn parent_mut<'a, 'p>(
+    doc: &'a mut Value,
+    path: &'p [String],
+) -> Result<(&'a mut Value, &'p str), String> {
+    let (last, parents) = path
+        .split_last()
+        .ok_or_else(|| "the record itself cannot be removed".to_string())?;
+    Ok((get_mut(doc, parents)?, last))
+}
+
+fn add(doc: &mut Value, path: &[String], value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, last) = parent_mut(doc, path)?;
+    match parent {
+        Value::Object(map) => {
+            map.insert(last.to_string(), value);
+        }
+        Value::Array(items) if last == "-" => items.push(value),
+        Value::Array(items) => {
+            let len = items.len();
+            let i = array_index(last, len + 1)
+                .ok_or_else(|| format!("index {} out of range", join(path)))?;
+            items.insert(i, value);
+        }
+        _ => return Err(format!("{} is not in an object or array", join(path))),
+    }
+    Ok(())
+}
+
+fn remove(doc: &mut Value, path: &[String]) -> Result<Value, String> {
+    let (parent, last) = parent_mut(doc, path)?;
+    let removed = match parent {
+        Value::Object(map) => map.remove(last),
+        Value::Array(items) => {
+            let len = items.len();
+            array_index(last, len).map(|i| items.remove(i))
+        }
+        _ => None,
+    };
+    removed.ok_or_else(|| format!("path {} not found", join(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::encode_pickle;
+    use crate::json::json_to_pickle_value;
+    use crate::types::PickleValue;
+    use serde_json::json;
+
+    fn record(state: Value) -> Vec<u8> {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::Tuple(vec![
+                PickleValue::String("myapp".into()),
+                PickleValue::String("Doc".into()),
+            ]),
+            PickleValue::None,
+        ]);
+        let state = json_to_pickle_value(&state).unwrap();
+        [
+            encode_pickle(&class).unwrap(),
+            encode_pickle(&state).unwrap(),
+        ]
+        .concat()
+    }
+
+    fn patched(state: Value, patch: Value) -> Result<Value, CodecError> {
+        let out = apply_patch_to_record(&record(state), &patch)?;
+        Ok(decode_zodb_record(&out)?["@s"].clone())
+    }
+
+    #[test]
+    fn test_operations() {
+        let state = json!({"title": "Hi", "tags": ["a", "c"], "broken": {"@b": "AA=="}});
+        let patch = json!([
+            {"op": "test", "path": "/@s/title", "value": "Hi"},
+            {"op": "remove", "path": "/@s/broken"},
+            {"op": "replace", "path": "/@s/title", "value": "Hello"},
+            {"op": "add", "path": "/@s/tags/1", "value": "b"},
+            {"op": "add", "path": "/@s/tags/-", "value": "d"},
+            {"op": "copy", "from": "/@s/tags/0", "path": "/@s/first"},
+            {"op": "move", "from": "/@s/first", "path": "/@s/when"},
+            {"op": "replace", "path": "/@s/when", "value": {"@dt": "2025-01-01T00:00:00"}},
+        ]);
+        assert_eq!(
+            patched(state, patch).unwrap(),
+            json!({
+                "title": "Hello",
+                "tags": ["a", "b", "c", "d"],
+                "when": {"@dt": "2025-01-01T00:00:00"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_escaped_keys() {
+        let patch = json!([{"op": "remove", "path": "/@s/a~1b"}, {"op": "add", "path": "/@s/c~0", "value": 1}]);
+        assert_eq!(patched(json!({"a/b": 0}), patch).unwrap(), json!({"c~": 1}));
+    }
+
+    #[test]
+    fn test_errors() {
+        let state = json!({"title": "Hi", "tags": ["a"]});
+        for (patch, msg) in [
+            (json!({}), "must be an array"),
+            (
+                json!([{"op": "remove", "path": "/@s/nope"}]),
+                "/@s/nope not found",
+            ),
+            (
+                json!([{"op": "add", "path": "/@s/tags/5", "value": 1}]),
+                "out of range",
+            ),
+            (
+                json!([{"op": "remove", "path": "/@s/tags/01"}]),
+                "not found",
+            ),
+            (
+                json!([{"op": "test", "path": "/@s/title", "value": "x"}]),
+                "test failed",
+            ),
+            (
+                json!([{"op": "move", "from": "/@s", "path": "/@s/x"}]),
+                "into itself",
+            ),
+            (json!([{"op": "frob", "path": ""}]), "unknown op"),
+            (
+                json!([{"op": "add", "path": "@s/x", "value": 1}]),
+                "must start with '/'",
+            ),
+            (
+                json!([{"op": "replace", "path": "/@s/title"}]),
+                "missing \"value\"",
+            ),
+            (json!([{}]), "missing \"path\""),
+            (json!([{"op": "remove", "path": ""}]), "cannot be removed"),
+            (json!([{"op": "remove", "path": "/@cls"}]), "missing @cls"),
+        ] {
+            let err = patched(state.clone(), patch).unwrap_err().to_string();
+            assert!(err.contains(msg), "{err} does not contain {msg}");
+        }
+        let err = patched(state, json!([{"op": "test", "path": "/@s/title", "value": "Hi"}, {"op": "remove", "path": "/x"}]))
+            .unwrap_err();
+        assert!(err.to_string().contains("patch operation 1"), "{err}");
+    }
+}
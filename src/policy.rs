@@ -0,0 +1,131 @@
+//! Class allowlist/denylist policy for decoding.
+//!
+//! Converting records from an untrusted FileStorage should not silently
+//! carry references to removed or dangerous callables into the JSON.
+//! A decode policy is consulted for every GLOBAL / STACK_GLOBAL opcode:
+//!
+//! - a class on the denylist is always a violation;
+//! - with an allowlist configured, any class not on it is a violation.
+//!
+//! Entries are `(module, name)` pairs; the name `"*"` matches every name
+//! in the module. A violation either fails decoding or, in block mode,
+//! replaces the reference with a `PickleValue::Blocked` that is written as
+//! `{"@blocked": ["module", "name"]}`. Blocked references encode back to
+//! the original GLOBAL, so block mode stays lossless.
+//!
+//! A policy applies to the decoding calls it is passed to (see
+//! `DecodeOptions::with_policy`); no policy is checked by default.
+
+use std::collections::HashSet;
+
+use crate::error::CodecError;
+
+/// What decoding does with a class outside the policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PolicyViolation {
+    /// Fail decoding the pickle.
+    #[default]
+    Error,
+    /// Replace the class reference with an `@blocked` marker.
+    Block,
+}
+
+/// Allowed and denied classes for decoding.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DecodePolicy {
+    /// Classes that may be referenced (`None` = every class not denied).
+    pub allowed: Option<HashSet<(String, String)>>,
+    /// Classes that may never be referenced.
+    pub denied: HashSet<(String, String)>,
+    /// Action taken on a violation.
+    pub on_violation: PolicyViolation,
+}
+
+impl DecodePolicy {
+    /// A policy denying nothing, allowing everything.
+    pub fn new(on_violation: PolicyViolation) -> Self {
+        DecodePolicy {
+            on_violation,
+            ..DecodePolicy::default()
+        }
+    }
+
+    /// Add `module.name` to the allowlist (`name` may be `"*"`).
+    pub fn allow(mut self, module: impl Into<String>, name: impl Into<String>) -> Self {
+        self.allowed
+            .get_or_insert_with(HashSet::new)
+            .insert((module.into(), name.into()));
+        self
+    }
+
+    /// Add `module.name` to the denylist (`name` may be `"*"`).
+    pub fn deny(mut self, module: impl Into<String>, name: impl Into<String>) -> Self {
+        self.denied.insert((module.into(), name.into()));
+        self
+    }
+
+    /// Whether the class `module.name` may be referenced.
+    pub fn permits(&self, module: &str, name: &str) -> bool {
+        if contains(&self.denied, module, name) {
+            return false;
+        }
+        match &self.allowed {
+            Some(allowed) => contains(allowed, module, name),
+            None => true,
+        }
+    }
+
+    /// Check a class reference: `Ok(true)` if it is permitted, `Ok(false)`
+    /// if it must be blocked, or an error in error mode.
+    pub(crate) fn check(&self, module: &str, name: &str) -> Result<bool, CodecError> {
+        if self.permits(module, name) {
+            return Ok(true);
+        }
+        match self.on_violation {
+            PolicyViolation::Block => Ok(false),
+            PolicyViolation::Error => Err(CodecError::InvalidData(format!(
+                "class {module}.{name} is not permitted by the decode policy"
+            ))),
+        }
+    }
+}
+
+fn contains(set: &HashSet<(String, String)>, module: &str, name: &str) -> bool {
+    set.iter()
+        .any(|(m, n)| m == module && (n == name || n == "*"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits() {
+        let p = DecodePolicy::new(PolicyViolation::Error)
+            .allow("myapp.models", "*")
+            .allow("builtins", "set")
+            .deny("myapp.models", "Legacy");
+        assert!(p.permits("myapp.models", "Folder"));
+        assert!(p.permits("builtins", "set"));
+        assert!(!p.permits("myapp.models", "Legacy"));
+        assert!(!p.permits("os", "system"));
+        assert!(!p.permits("myapp.models.sub", "Folder"));
+    }
+
+    #[test]
+    fn test_denylist_only() {
+        let p = DecodePolicy::new(PolicyViolation::Block).deny("os", "*");
+        assert!(p.permits("myapp", "Folder"));
+        assert!(!p.permits("os", "system"));
+        assert!(!p.check("os", "system").unwrap());
+        assert!(p.check("myapp", "Folder").unwrap());
+    }
+
+    #[test]
+    fn test_check_error_names_class() {
+        let p = DecodePolicy::new(PolicyViolation::Error).allow("myapp", "Folder");
+        let err = p.check("os", "system").unwrap_err();
+        assert!(err.to_string().contains("os.system"), "{err}");
+    }
+}
@@ -0,0 +1,474 @@
+//! Protocol 0 (text) pickle emitter.
+//!
+//! Protocol 0 is the original, line-oriented pickle format. Every
+//! unpickler back to Python 2 reads it, which makes it the format of
+//! choice for tooling that exchanges data with very old Zope installations
+//! or that wants diffable, printable output.
+//!
+//! The output is pure ASCII and uses only text opcodes:
+//!
+//! - ints and floats in decimal (`INT`, `LONG`, `FLOAT`),
+//! - text as `UNICODE` with everything outside printable ASCII written as
+//!   `\uXXXX` escapes (raw-unicode-escape),
+//! - byte strings as quoted `STRING`s, i.e. Python 2 `str`,
+//! - containers built from MARK with `DICT`/`LIST`/`TUPLE`,
+//! - sets via `__builtin__.set` and instances via
+//!   `copy_reg._reconstructor`, as Python 2 pickles them.
+//!
+//! Memo entries are written only for `Shared` containers, whose
+//! back-references need them (see `shared.rs`). Persistent ids that are not strings, such
+//! as ZODB's `(oid, class)` tuples, have no text form and are written with
+//! `BINPERSID`, which every unpickler accepts regardless of protocol.
+
+use std::fmt::Write as _;
+
+use crate::encode::MAX_DEPTH;
+use crate::error::CodecError;
+use crate::limits::EncodeLimits;
+use crate::opcodes::*;
+use crate::rename;
+use crate::shared::{self, SharedIds};
+use crate::surrogates::{for_each_piece, Piece};
+use crate::types::{AnonymousBuild, InstanceData, PickleValue};
+
+/// Encode a PickleValue AST as a protocol 0 (text) pickle.
+///
+/// ```
+/// use zodb_json_codec::{decode_pickle, encode_pickle_protocol0, PickleValue};
+///
+/// let val = PickleValue::List(vec![PickleValue::Int(1), PickleValue::String("é".into())]);
+/// let bytes = encode_pickle_protocol0(&val)?;
+/// assert_eq!(bytes, b"(I1\nV\\u00e9\nl.");
+/// assert_eq!(decode_pickle(&bytes)?, val);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn encode_pickle_protocol0(val: &PickleValue) -> Result<Vec<u8>, CodecError> {
+    let val = &*shared::in_definition_order(val);
+    let mut encoder = TextEncoder {
+        buf: Vec::with_capacity(256),
+        shared: SharedIds::default(),
+        pending_shared: None,
+        limits: EncodeLimits::current(),
+    };
+    encoder.encode_value(val, 0)?;
+    encoder.buf.push(STOP);
+    Ok(encoder.buf)
+}
+
+struct TextEncoder {
+    buf: Vec<u8>,
+    /// Memo indices of the `Shared` nodes written.
+    shared: SharedIds,
+    /// Id of the `Shared` node whose value is being written.
+    pending_shared: Option<u32>,
+    /// Output, depth and collection limits.
+    limits: EncodeLimits,
+}
+
+impl TextEncoder {
+    /// Write `op` followed by `arg` and the terminating newline.
+    fn write_line(&mut self, op: u8, arg: &str) {
+        self.buf.push(op);
+        self.buf.extend_from_slice(arg.as_bytes());
+        self.buf.push(b'\n');
+    }
+
+    fn write_global(&mut self, module: &str, name: &str) -> Result<(), CodecError> {
+        if let Some((module, name)) = rename::encode_rename(module, name) {
+            return self.write_global_line(&module, &name);
+        }
+        self.write_global_line(module, name)
+    }
+
+    fn write_global_line(&mut self, module: &str, name: &str) -> Result<(), CodecError> {
+        if module.contains('\n') || name.contains('\n') {
+            return Err(CodecError::InvalidData(format!(
+                "global {module:?}.{name:?} contains a newline"
+            )));
+        }
+        self.buf.push(GLOBAL);
+        self.buf.extend_from_slice(module.as_bytes());
+        self.buf.push(b'\n');
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.push(b'\n');
+        Ok(())
+    }
+
+    fn write_int(&mut self, val: i64) {
+        // Like CPython: INT within 32 bits, LONG beyond, so that 32-bit
+        // Python 2 builds read the value back as well.
+        if i32::try_from(val).is_ok() {
+            self.write_line(INT, &val.to_string());
+        } else {
+            self.write_line(LONG, &format!("{val}L"));
+        }
+    }
+
+    fn write_unicode(&mut self, s: &str) {
+        let mut escaped = String::with_capacity(s.len());
+        escape_raw_unicode(&mut escaped, s);
+        self.write_escaped_unicode(&escaped);
+    }
+
+    /// A `SurrogateString`: surrogates are `\uXXXX` escapes like any
+    /// other non-ASCII code unit.
+    fn write_surrogate_unicode(&mut self, bytes: &[u8]) -> Result<(), CodecError> {
+        let mut escaped = String::with_capacity(bytes.len());
+        for_each_piece(bytes, |piece| match piece {
+            Piece::Text(s) => escape_raw_unicode(&mut escaped, s),
+            Piece::Surrogate(unit) => {
+                let _ = write!(escaped, "\\u{unit:04x}");
+            }
+        })?;
+        self.write_escaped_unicode(&escaped);
+        Ok(())
+    }
+
+    fn write_escaped_unicode(&mut self, escaped: &str) {
+        self.buf.push(UNICODE);
+        self.buf.extend_from_slice(escaped.as_bytes());
+        self.buf.push(b'\n');
+    }
+
+    fn write_string(&mut self, data: &[u8]) {
+        self.buf.reserve(data.len() + 4);
+        self.buf.push(STRING);
+        self.buf.push(b'\'');
+        for &b in data {
+            match b {
+                b'\\' => self.buf.extend_from_slice(b"\\\\"),
+                b'\'' => self.buf.extend_from_slice(b"\\'"),
+                b'\t' => self.buf.extend_from_slice(b"\\t"),
+                b'\n' => self.buf.extend_from_slice(b"\\n"),
+                b'\r' => self.buf.extend_from_slice(b"\\r"),
+                b' '..=b'~' => self.buf.push(b),
+                _ => self.buf.extend_from_slice(format!("\\x{b:02x}").as_bytes()),
+            }
+        }
+        self.buf.extend_from_slice(b"'\n");
+    }
+
+    /// Store the container just created as the value of the `Shared` node
+    /// `shared`, if any.
+    fn put_shared(&mut self, shared: &mut Option<u32>) -> Result<(), CodecError> {
+        if let Some(id) = shared.take() {
+            let index = self.shared.next_index()?;
+            self.shared.define(id, index);
+            self.write_line(PUT, &index.to_string());
+        }
+        Ok(())
+    }
+
+    fn encode_items(&mut self, items: &[PickleValue], depth: usize) -> Result<(), CodecError> {
+        for item in items {
+            self.encode_value(item, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Dict items and list items added after an object is built.
+    fn encode_extra_items(
+        &mut self,
+        dict_items: Option<&[(PickleValue, PickleValue)]>,
+        list_items: Option<&[PickleValue]>,
+        depth: usize,
+    ) -> Result<(), CodecError> {
+        for (k, v) in dict_items.unwrap_or_default() {
+            self.encode_value(k, depth + 1)?;
+            self.encode_value(v, depth + 1)?;
+            self.buf.push(SETITEM);
+        }
+        for item in list_items.unwrap_or_default() {
+            self.encode_value(item, depth + 1)?;
+            self.buf.push(APPEND);
+        }
+        Ok(())
+    }
+
+    fn encode_value(&mut self, val: &PickleValue, depth: usize) -> Result<(), CodecError> {
+        if depth > MAX_DEPTH {
+            return Err(CodecError::InvalidData("maximum nesting depth exceeded".to_string()));
+        }
+        self.limits.check_value(val, depth, self.buf.len())?;
+        let mut shared = self.pending_shared.take();
+        match val {
+            PickleValue::None => self.buf.push(NONE),
+            PickleValue::Bool(b) => self.write_line(INT, if *b { "01" } else { "00" }),
+            PickleValue::Int(i) => self.write_int(*i),
+            PickleValue::BigInt(bi) => self.write_line(LONG, &format!("{bi}L")),
+            PickleValue::Float(f) => self.write_line(FLOAT, &format!("{f:?}")),
+            PickleValue::String(s) => self.write_unicode(s),
+            PickleValue::SurrogateString(b) => self.write_surrogate_unicode(b)?,
+            PickleValue::Bytes(b) | PickleValue::RawPickle(b) => self.write_string(b),
+            PickleValue::List(items) if shared.is_some() => {
+                // Created empty and stored first: the items may refer to it
+                self.buf.extend_from_slice(&[MARK, LIST]);
+                self.put_shared(&mut shared)?;
+                self.encode_extra_items(None, Some(items), depth)?;
+            }
+            PickleValue::List(items) => {
+                self.buf.push(MARK);
+                self.encode_items(items, depth)?;
+                self.buf.push(LIST);
+            }
+            PickleValue::Tuple(items) => {
+                self.buf.push(MARK);
+                self.encode_items(items, depth)?;
+                self.buf.push(TUPLE);
+            }
+            PickleValue::Dict(pairs) if shared.is_some() => {
+                self.buf.extend_from_slice(&[MARK, DICT]);
+                self.put_shared(&mut shared)?;
+                self.encode_extra_items(Some(pairs), None, depth)?;
+            }
+            PickleValue::Dict(pairs) => {
+                self.buf.push(MARK);
+                for (k, v) in pairs {
+                    self.encode_value(k, depth + 1)?;
+                    self.encode_value(v, depth + 1)?;
+                }
+                self.buf.push(DICT);
+            }
+            PickleValue::Set(items) | PickleValue::FrozenSet(items) => {
+                let name = if matches!(val, PickleValue::Set(_)) { "set" } else { "frozenset" };
+                self.write_global("__builtin__", name)?;
+                self.buf.extend_from_slice(&[MARK, MARK]);
+                self.encode_items(items, depth)?;
+                self.buf.extend_from_slice(&[LIST, TUPLE, REDUCE]);
+            }
+            PickleValue::Global { module, name } | PickleValue::Blocked { module, name } => {
+                self.write_global(module, name)?
+            }
+            PickleValue::Instance(inst) => {
+                let InstanceData { module, name, state, dict_items, list_items } = inst.as_ref();
+                match inst.anonymous_build() {
+                    Some(AnonymousBuild::Reduce { callable, args, state }) => {
+                        self.encode_value(callable, depth + 1)?;
+                        self.encode_value(args, depth + 1)?;
+                        self.buf.push(REDUCE);
+                        self.put_shared(&mut shared)?;
+                        self.encode_value(state, depth + 1)?;
+                    }
+                    Some(AnonymousBuild::Object { obj, state }) => {
+                        self.encode_value(obj, depth + 1)?;
+                        self.put_shared(&mut shared)?;
+                        self.encode_value(state, depth + 1)?;
+                    }
+                    None => {
+                        // copy_reg._reconstructor(cls, object, None) is object.__new__(cls)
+                        self.write_global("copy_reg", "_reconstructor")?;
+                        self.buf.push(MARK);
+                        self.write_global(module, name)?;
+                        self.write_global("__builtin__", "object")?;
+                        self.buf.extend_from_slice(&[NONE, TUPLE, REDUCE]);
+                        self.put_shared(&mut shared)?;
+                        self.encode_value(state, depth + 1)?;
+                    }
+                }
+                self.buf.push(BUILD);
+                self.encode_extra_items(
+                    dict_items.as_deref().map(Vec::as_slice),
+                    list_items.as_deref().map(Vec::as_slice),
+                    depth,
+                )?;
+            }
+            PickleValue::PersistentRef(inner) => match inner.as_ref() {
+                PickleValue::String(pid) if !pid.contains('\n') && pid.is_ascii() => {
+                    self.write_line(PERSID, pid);
+                }
+                _ => {
+                    self.encode_value(inner, depth + 1)?;
+                    self.buf.push(BINPERSID);
+                }
+            },
+            PickleValue::Reduce {
+                callable,
+                args,
+                dict_items,
+                list_items,
+                newobj: true,
+            } => {
+                // No NEWOBJ before protocol 2: copy_reg.__newobj__(cls, *args)
+                let PickleValue::Tuple(args) = args.as_ref() else {
+                    return Err(CodecError::InvalidData(
+                        "NEWOBJ arguments must be a tuple".to_string(),
+                    ));
+                };
+                self.write_global("copy_reg", "__newobj__")?;
+                self.buf.push(MARK);
+                self.encode_value(callable, depth + 1)?;
+                self.encode_items(args, depth)?;
+                self.buf.extend_from_slice(&[TUPLE, REDUCE]);
+                self.put_shared(&mut shared)?;
+                self.encode_extra_items(
+                    dict_items.as_deref().map(Vec::as_slice),
+                    list_items.as_deref().map(Vec::as_slice),
+                    depth,
+                )?;
+            }
+            PickleValue::Reduce {
+                callable,
+                args,
+                dict_items,
+                list_items,
+                newobj: false,
+            } => {
+                self.encode_value(callable, depth + 1)?;
+                self.encode_value(args, depth + 1)?;
+                self.buf.push(REDUCE);
+                self.put_shared(&mut shared)?;
+                self.encode_extra_items(
+                    dict_items.as_deref().map(Vec::as_slice),
+                    list_items.as_deref().map(Vec::as_slice),
+                    depth,
+                )?;
+            }
+            PickleValue::NewObjEx { cls, args, kwargs } => {
+                self.write_global("copy_reg", "__newobj_ex__")?;
+                self.buf.push(MARK);
+                self.encode_value(cls, depth + 1)?;
+                self.encode_value(args, depth + 1)?;
+                self.encode_value(kwargs, depth + 1)?;
+                self.buf.extend_from_slice(&[TUPLE, REDUCE]);
+                self.put_shared(&mut shared)?;
+            }
+            PickleValue::Shared { id, value } => {
+                self.pending_shared = Some(*id);
+                self.encode_value(value, depth + 1)?;
+            }
+            PickleValue::BackRef(id) => {
+                let index = self.shared.index_of(*id)?;
+                self.write_line(GET, &index.to_string());
+            }
+        }
+        // Values stored once complete
+        self.put_shared(&mut shared)
+    }
+}
+
+/// Append `s` in raw-unicode-escape form, escaping everything but
+/// printable ASCII so the line stays ASCII.
+fn escape_raw_unicode(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            ' '..='~' if c != '\\' => out.push(c),
+            '\0'..='\u{ffff}' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            _ => {
+                let _ = write!(out, "\\U{:08x}", c as u32);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::decode_pickle;
+    use num_bigint::BigInt;
+
+    fn roundtrip(val: PickleValue) {
+        let bytes = encode_pickle_protocol0(&val).unwrap();
+        assert!(bytes.is_ascii(), "{:?}", String::from_utf8_lossy(&bytes));
+        assert_eq!(decode_pickle(&bytes).unwrap(), val, "{}", String::from_utf8_lossy(&bytes));
+    }
+
+    #[test]
+    fn test_scalars() {
+        assert_eq!(encode_pickle_protocol0(&PickleValue::Int(-5)).unwrap(), b"I-5\n.");
+        assert_eq!(
+            encode_pickle_protocol0(&PickleValue::Int(1 << 40)).unwrap(),
+            b"L1099511627776L\n."
+        );
+        assert_eq!(encode_pickle_protocol0(&PickleValue::Bool(true)).unwrap(), b"I01\n.");
+        roundtrip(PickleValue::None);
+        roundtrip(PickleValue::Bool(false));
+        roundtrip(PickleValue::Int(i64::MIN));
+        roundtrip(PickleValue::BigInt(BigInt::from(1u128 << 100)));
+        roundtrip(PickleValue::Float(0.1));
+        roundtrip(PickleValue::Float(-1e300));
+        roundtrip(PickleValue::Float(f64::INFINITY));
+    }
+
+    #[test]
+    fn test_strings() {
+        roundtrip(PickleValue::String("plain text".into()));
+        roundtrip(PickleValue::String("back\\slash\nnew line\r\0 caf\u{e9} \u{20ac} \u{1f600}".into()));
+        roundtrip(PickleValue::String("".into()));
+        roundtrip(PickleValue::Bytes(b"it's \"quoted\"\t\\ \x00\xff".to_vec()));
+        roundtrip(PickleValue::Bytes(Vec::new()));
+    }
+
+    #[test]
+    fn test_containers() {
+        roundtrip(PickleValue::List(vec![]));
+        roundtrip(PickleValue::Tuple(vec![]));
+        roundtrip(PickleValue::Tuple(vec![PickleValue::Int(1); 5]));
+        roundtrip(PickleValue::Dict(vec![
+            (PickleValue::String("a".into()), PickleValue::List(vec![PickleValue::None])),
+            (PickleValue::Int(2), PickleValue::Dict(vec![])),
+        ]));
+        roundtrip(PickleValue::Set(vec![PickleValue::Int(1), PickleValue::Int(2)]));
+        roundtrip(PickleValue::FrozenSet(vec![]));
+    }
+
+    #[test]
+    fn test_instance_and_refs() {
+        roundtrip(PickleValue::Instance(Box::new(InstanceData {
+            module: "myapp.models".into(),
+            name: "Doc".into(),
+            state: Box::new(PickleValue::Dict(vec![(
+                PickleValue::String("title".into()),
+                PickleValue::String("Hello".into()),
+            )])),
+            dict_items: None,
+            list_items: None,
+        })));
+        roundtrip(PickleValue::PersistentRef(Box::new(PickleValue::String("42".into()))));
+        roundtrip(PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, 3]),
+            PickleValue::None,
+        ]))));
+        let bytes = encode_pickle_protocol0(&PickleValue::PersistentRef(Box::new(
+            PickleValue::String("42".into()),
+        )))
+        .unwrap();
+        assert_eq!(bytes, b"P42\n.");
+        roundtrip(PickleValue::NewObjEx {
+            cls: Box::new(PickleValue::Global { module: "myapp".into(), name: "P".into() }),
+            args: Box::new(PickleValue::Tuple(vec![PickleValue::Int(1)])),
+            kwargs: Box::new(PickleValue::Dict(vec![])),
+        });
+        roundtrip(PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global { module: "myapp".into(), name: "P".into() }),
+            args: Box::new(PickleValue::Tuple(vec![PickleValue::Int(1)])),
+            dict_items: None,
+            list_items: None,
+            newobj: true,
+        });
+    }
+
+    #[test]
+    fn test_newline_in_global_rejected() {
+        let val = PickleValue::Global {
+            module: "a\nb".into(),
+            name: "C".into(),
+        };
+        assert!(encode_pickle_protocol0(&val).is_err());
+    }
+
+    #[test]
+    fn test_shared_cycle() {
+        let val = PickleValue::Shared {
+            id: 0,
+            value: Box::new(PickleValue::List(vec![
+                PickleValue::Int(1),
+                PickleValue::BackRef(0),
+            ])),
+        };
+        roundtrip(val.clone());
+        let bytes = encode_pickle_protocol0(&val).unwrap();
+        assert_eq!(bytes, b"(lp0\nI1\nag0\na.");
+    }
+}
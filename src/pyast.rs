@@ -0,0 +1,811 @@
+//! The pickle AST as Python objects (`zodb_json_codec.nodes`).
+//!
+//! `decode_pickle_ast()` returns the decoded `PickleValue` tree as
+//! instances of node classes, one per variant, all subclasses of
+//! `PickleNode`. Unlike the dict form there are no markers: a tuple is a
+//! `Tuple`, a REDUCE a `Reduce` with its callable and args, a persistent
+//! reference a `PersistentRef` around the exact persistent id. Known
+//! types (datetime, Decimal, ...) are not recognized, so every value keeps
+//! the pickle's own representation.
+//!
+//! The nodes are mutable: attributes can be reassigned and the `items`
+//! lists edited in place. `encode_pickle_ast()` converts the tree back,
+//! checking each child is a node.
+
+use pyo3::exceptions::PyTypeError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyInt, PyList, PyString, PyTuple};
+
+use crate::encode::NestingGuard;
+use crate::types::{InstanceData, PickleValue};
+
+/// Base class of the AST nodes. Nodes compare equal when they encode the
+/// same pickle value.
+#[pyclass(subclass, name = "PickleNode", module = "zodb_json_codec.nodes")]
+pub(crate) struct PickleNode {}
+
+#[pymethods]
+impl PickleNode {
+    fn __repr__(slf: &Bound<'_, Self>) -> PyResult<String> {
+        let cls = slf.get_type();
+        let mut fields = Vec::new();
+        for field in cls.getattr("__match_args__")?.cast::<PyTuple>()? {
+            let field: String = field.extract()?;
+            let value = slf.getattr(field.as_str())?;
+            fields.push(format!("{field}={}", value.repr()?));
+        }
+        Ok(format!("{}({})", cls.name()?, fields.join(", ")))
+    }
+
+    fn __eq__(slf: &Bound<'_, Self>, other: &Bound<'_, Self>) -> PyResult<bool> {
+        Ok(node_to_value(slf)? == node_to_value(other)?)
+    }
+}
+
+/// `None`
+#[pyclass(extends = PickleNode, name = "Null", module = "zodb_json_codec.nodes")]
+pub(crate) struct Null {}
+
+#[pymethods]
+impl Null {
+    #[new]
+    fn new() -> (Self, PickleNode) {
+        (Null {}, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        Ok(PyTuple::empty(py))
+    }
+}
+
+/// `True` or `False`
+#[pyclass(extends = PickleNode, name = "Bool", module = "zodb_json_codec.nodes", get_all, set_all)]
+pub(crate) struct Bool {
+    value: bool,
+}
+
+#[pymethods]
+impl Bool {
+    #[new]
+    fn new(value: bool) -> (Self, PickleNode) {
+        (Bool { value }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["value"])
+    }
+}
+
+/// An integer of any size
+#[pyclass(extends = PickleNode, name = "Int", module = "zodb_json_codec.nodes", get_all, set_all)]
+pub(crate) struct Int {
+    value: Py<PyInt>,
+}
+
+#[pymethods]
+impl Int {
+    #[new]
+    fn new(value: Py<PyInt>) -> (Self, PickleNode) {
+        (Int { value }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["value"])
+    }
+}
+
+/// A float
+#[pyclass(extends = PickleNode, name = "Float", module = "zodb_json_codec.nodes", get_all, set_all)]
+pub(crate) struct Float {
+    value: f64,
+}
+
+#[pymethods]
+impl Float {
+    #[new]
+    fn new(value: f64) -> (Self, PickleNode) {
+        (Float { value }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["value"])
+    }
+}
+
+/// A text string
+#[pyclass(extends = PickleNode, name = "Str", module = "zodb_json_codec.nodes", get_all, set_all)]
+pub(crate) struct Str {
+    value: Py<PyString>,
+}
+
+#[pymethods]
+impl Str {
+    #[new]
+    fn new(value: Py<PyString>) -> (Self, PickleNode) {
+        (Str { value }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["value"])
+    }
+}
+
+/// A byte string
+#[pyclass(extends = PickleNode, name = "Bytes", module = "zodb_json_codec.nodes", get_all, set_all)]
+pub(crate) struct Bytes {
+    value: Py<PyBytes>,
+}
+
+#[pymethods]
+impl Bytes {
+    #[new]
+    fn new(value: Py<PyBytes>) -> (Self, PickleNode) {
+        (Bytes { value }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["value"])
+    }
+}
+
+/// A list; `items` is a list of nodes
+#[pyclass(extends = PickleNode, name = "List", module = "zodb_json_codec.nodes", get_all, set_all)]
+pub(crate) struct List {
+    items: Py<PyList>,
+}
+
+#[pymethods]
+impl List {
+    #[new]
+    fn new(items: Py<PyList>) -> (Self, PickleNode) {
+        (List { items }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["items"])
+    }
+}
+
+/// A tuple; `items` is a list of nodes
+#[pyclass(extends = PickleNode, name = "Tuple", module = "zodb_json_codec.nodes", get_all, set_all)]
+pub(crate) struct Tuple {
+    items: Py<PyList>,
+}
+
+#[pymethods]
+impl Tuple {
+    #[new]
+    fn new(items: Py<PyList>) -> (Self, PickleNode) {
+        (Tuple { items }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["items"])
+    }
+}
+
+/// A dict; `items` is a list of `(key, value)` node pairs, in pickle order
+#[pyclass(extends = PickleNode, name = "Dict", module = "zodb_json_codec.nodes", get_all, set_all)]
+pub(crate) struct Dict {
+    items: Py<PyList>,
+}
+
+#[pymethods]
+impl Dict {
+    #[new]
+    fn new(items: Py<PyList>) -> (Self, PickleNode) {
+        (Dict { items }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["items"])
+    }
+}
+
+/// A set; `items` is a list of nodes
+#[pyclass(extends = PickleNode, name = "Set", module = "zodb_json_codec.nodes", get_all, set_all)]
+pub(crate) struct Set {
+    items: Py<PyList>,
+}
+
+#[pymethods]
+impl Set {
+    #[new]
+    fn new(items: Py<PyList>) -> (Self, PickleNode) {
+        (Set { items }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["items"])
+    }
+}
+
+/// A frozenset; `items` is a list of nodes
+#[pyclass(
+    extends = PickleNode,
+    name = "FrozenSet",
+    module = "zodb_json_codec.nodes",
+    get_all,
+    set_all
+)]
+pub(crate) struct FrozenSet {
+    items: Py<PyList>,
+}
+
+#[pymethods]
+impl FrozenSet {
+    #[new]
+    fn new(items: Py<PyList>) -> (Self, PickleNode) {
+        (FrozenSet { items }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["items"])
+    }
+}
+
+/// A class or function reference (GLOBAL / STACK_GLOBAL)
+#[pyclass(
+    extends = PickleNode,
+    name = "Global",
+    module = "zodb_json_codec.nodes",
+    get_all,
+    set_all
+)]
+pub(crate) struct Global {
+    module: String,
+    name: String,
+}
+
+#[pymethods]
+impl Global {
+    #[new]
+    fn new(module: String, name: String) -> (Self, PickleNode) {
+        (Global { module, name }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["module", "name"])
+    }
+}
+
+/// A class reference rejected by the decode policy in block mode
+#[pyclass(
+    extends = PickleNode,
+    name = "Blocked",
+    module = "zodb_json_codec.nodes",
+    get_all,
+    set_all
+)]
+pub(crate) struct Blocked {
+    module: String,
+    name: String,
+}
+
+#[pymethods]
+impl Blocked {
+    #[new]
+    fn new(module: String, name: String) -> (Self, PickleNode) {
+        (Blocked { module, name }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["module", "name"])
+    }
+}
+
+/// An instance of `module.name` built from `state`; `dict_items` and
+/// `list_items` hold the items of dict and list subclasses, or `None`
+#[pyclass(
+    extends = PickleNode,
+    name = "Instance",
+    module = "zodb_json_codec.nodes",
+    get_all,
+    set_all
+)]
+pub(crate) struct Instance {
+    module: String,
+    name: String,
+    state: Py<PickleNode>,
+    dict_items: Option<Py<PyList>>,
+    list_items: Option<Py<PyList>>,
+}
+
+#[pymethods]
+impl Instance {
+    #[new]
+    #[pyo3(signature = (module, name, state, dict_items=None, list_items=None))]
+    fn new(
+        module: String,
+        name: String,
+        state: Py<PickleNode>,
+        dict_items: Option<Py<PyList>>,
+        list_items: Option<Py<PyList>>,
+    ) -> (Self, PickleNode) {
+        let instance = Instance {
+            module,
+            name,
+            state,
+            dict_items,
+            list_items,
+        };
+        (instance, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        let fields = ["module", "name", "state", "dict_items", "list_items"];
+        PyTuple::new(py, fields)
+    }
+}
+
+/// A persistent reference; `pid` is the persistent id
+#[pyclass(
+    extends = PickleNode,
+    name = "PersistentRef",
+    module = "zodb_json_codec.nodes",
+    get_all,
+    set_all
+)]
+pub(crate) struct PersistentRef {
+    pid: Py<PickleNode>,
+}
+
+#[pymethods]
+impl PersistentRef {
+    #[new]
+    fn new(pid: Py<PickleNode>) -> (Self, PickleNode) {
+        (PersistentRef { pid }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["pid"])
+    }
+}
+
+/// `callable(*args)` (REDUCE), or `callable.__new__(callable, *args)`
+/// (NEWOBJ) if `newobj` is true
+#[pyclass(
+    extends = PickleNode,
+    name = "Reduce",
+    module = "zodb_json_codec.nodes",
+    get_all,
+    set_all
+)]
+pub(crate) struct Reduce {
+    callable: Py<PickleNode>,
+    args: Py<PickleNode>,
+    dict_items: Option<Py<PyList>>,
+    list_items: Option<Py<PyList>>,
+    newobj: bool,
+}
+
+#[pymethods]
+impl Reduce {
+    #[new]
+    #[pyo3(signature = (callable, args, dict_items=None, list_items=None, newobj=false))]
+    fn new(
+        callable: Py<PickleNode>,
+        args: Py<PickleNode>,
+        dict_items: Option<Py<PyList>>,
+        list_items: Option<Py<PyList>>,
+        newobj: bool,
+    ) -> (Self, PickleNode) {
+        let reduce = Reduce {
+            callable,
+            args,
+            dict_items,
+            list_items,
+            newobj,
+        };
+        (reduce, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        let fields = ["callable", "args", "dict_items", "list_items", "newobj"];
+        PyTuple::new(py, fields)
+    }
+}
+
+/// `cls.__new__(cls, *args, **kwargs)` (NEWOBJ_EX)
+#[pyclass(
+    extends = PickleNode,
+    name = "NewObjEx",
+    module = "zodb_json_codec.nodes",
+    get_all,
+    set_all
+)]
+pub(crate) struct NewObjEx {
+    cls: Py<PickleNode>,
+    args: Py<PickleNode>,
+    kwargs: Py<PickleNode>,
+}
+
+#[pymethods]
+impl NewObjEx {
+    #[new]
+    fn new(
+        cls: Py<PickleNode>,
+        args: Py<PickleNode>,
+        kwargs: Py<PickleNode>,
+    ) -> (Self, PickleNode) {
+        (NewObjEx { cls, args, kwargs }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["cls", "args", "kwargs"])
+    }
+}
+
+/// Pickle bytes the decoder kept as-is
+#[pyclass(
+    extends = PickleNode,
+    name = "RawPickle",
+    module = "zodb_json_codec.nodes",
+    get_all,
+    set_all
+)]
+pub(crate) struct RawPickle {
+    data: Py<PyBytes>,
+}
+
+#[pymethods]
+impl RawPickle {
+    #[new]
+    fn new(data: Py<PyBytes>) -> (Self, PickleNode) {
+        (RawPickle { data }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["data"])
+    }
+}
+
+/// A container referred to elsewhere by `BackRef(id)`
+#[pyclass(
+    extends = PickleNode,
+    name = "Shared",
+    module = "zodb_json_codec.nodes",
+    get_all,
+    set_all
+)]
+pub(crate) struct Shared {
+    id: u32,
+    value: Py<PickleNode>,
+}
+
+#[pymethods]
+impl Shared {
+    #[new]
+    fn new(id: u32, value: Py<PickleNode>) -> (Self, PickleNode) {
+        (Shared { id, value }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["id", "value"])
+    }
+}
+
+/// A reference to the `Shared` node with this id
+#[pyclass(
+    extends = PickleNode,
+    name = "BackRef",
+    module = "zodb_json_codec.nodes",
+    get_all,
+    set_all
+)]
+pub(crate) struct BackRef {
+    id: u32,
+}
+
+#[pymethods]
+impl BackRef {
+    #[new]
+    fn new(id: u32) -> (Self, PickleNode) {
+        (BackRef { id }, PickleNode {})
+    }
+
+    #[classattr]
+    fn __match_args__(py: Python<'_>) -> PyResult<Bound<'_, PyTuple>> {
+        PyTuple::new(py, ["id"])
+    }
+}
+
+/// Register the node classes in the extension module.
+pub(crate) fn add_classes(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PickleNode>()?;
+    m.add_class::<Null>()?;
+    m.add_class::<Bool>()?;
+    m.add_class::<Int>()?;
+    m.add_class::<Float>()?;
+    m.add_class::<Str>()?;
+    m.add_class::<Bytes>()?;
+    m.add_class::<List>()?;
+    m.add_class::<Tuple>()?;
+    m.add_class::<Dict>()?;
+    m.add_class::<Set>()?;
+    m.add_class::<FrozenSet>()?;
+    m.add_class::<Global>()?;
+    m.add_class::<Blocked>()?;
+    m.add_class::<Instance>()?;
+    m.add_class::<PersistentRef>()?;
+    m.add_class::<Reduce>()?;
+    m.add_class::<NewObjEx>()?;
+    m.add_class::<RawPickle>()?;
+    m.add_class::<Shared>()?;
+    m.add_class::<BackRef>()?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// PickleValue → nodes
+// ---------------------------------------------------------------------------
+
+/// Wrap a node class instance as its `PickleNode` base.
+fn node<T>(py: Python<'_>, init: (T, PickleNode)) -> PyResult<Py<PickleNode>>
+where
+    T: pyo3::PyClass<BaseType = PickleNode>,
+{
+    Ok(Bound::new(py, init)?.into_super().unbind())
+}
+
+fn node_list(py: Python<'_>, items: &[PickleValue]) -> PyResult<Py<PyList>> {
+    let nodes = items
+        .iter()
+        .map(|item| value_to_node(py, item))
+        .collect::<PyResult<Vec<_>>>()?;
+    Ok(PyList::new(py, nodes)?.unbind())
+}
+
+fn pair_list(py: Python<'_>, pairs: &[(PickleValue, PickleValue)]) -> PyResult<Py<PyList>> {
+    let list = PyList::empty(py);
+    for (k, v) in pairs {
+        list.append((value_to_node(py, k)?, value_to_node(py, v)?))?;
+    }
+    Ok(list.unbind())
+}
+
+/// Convert a decoded value to its node tree.
+pub(crate) fn value_to_node(py: Python<'_>, val: &PickleValue) -> PyResult<Py<PickleNode>> {
+    match val {
+        PickleValue::None => node(py, Null::new()),
+        PickleValue::Bool(value) => node(py, Bool::new(*value)),
+        PickleValue::Int(i) => node(py, Int::new(i.into_pyobject(py)?.unbind())),
+        PickleValue::BigInt(bi) => {
+            let int = py.get_type::<PyInt>().call1((bi.to_string(),))?;
+            node(py, Int::new(int.cast_into::<PyInt>()?.unbind()))
+        }
+        PickleValue::Float(value) => node(py, Float::new(*value)),
+        PickleValue::String(s) => node(py, Str::new(PyString::new(py, s).unbind())),
+        PickleValue::SurrogateString(b) => {
+            node(py, Str::new(crate::pyconv::surrogate_pystring(py, b)?.unbind()))
+        }
+        PickleValue::Bytes(b) => node(py, Bytes::new(PyBytes::new(py, b).unbind())),
+        PickleValue::List(items) => node(py, List::new(node_list(py, items)?)),
+        PickleValue::Tuple(items) => node(py, Tuple::new(node_list(py, items)?)),
+        PickleValue::Dict(pairs) => node(py, Dict::new(pair_list(py, pairs)?)),
+        PickleValue::Set(items) => node(py, Set::new(node_list(py, items)?)),
+        PickleValue::FrozenSet(items) => node(py, FrozenSet::new(node_list(py, items)?)),
+        PickleValue::Global { module, name } => node(py, Global::new(module.clone(), name.clone())),
+        PickleValue::Blocked { module, name } => {
+            node(py, Blocked::new(module.clone(), name.clone()))
+        }
+        PickleValue::Instance(inst) => node(
+            py,
+            Instance::new(
+                inst.module.clone(),
+                inst.name.clone(),
+                value_to_node(py, &inst.state)?,
+                inst.dict_items
+                    .as_deref()
+                    .map(|d| pair_list(py, d))
+                    .transpose()?,
+                inst.list_items
+                    .as_deref()
+                    .map(|l| node_list(py, l))
+                    .transpose()?,
+            ),
+        ),
+        PickleValue::PersistentRef(pid) => node(py, PersistentRef::new(value_to_node(py, pid)?)),
+        PickleValue::Reduce {
+            callable,
+            args,
+            dict_items,
+            list_items,
+            newobj,
+        } => node(
+            py,
+            Reduce::new(
+                value_to_node(py, callable)?,
+                value_to_node(py, args)?,
+                dict_items
+                    .as_deref()
+                    .map(|d| pair_list(py, d))
+                    .transpose()?,
+                list_items
+                    .as_deref()
+                    .map(|l| node_list(py, l))
+                    .transpose()?,
+                *newobj,
+            ),
+        ),
+        PickleValue::NewObjEx { cls, args, kwargs } => node(
+            py,
+            NewObjEx::new(
+                value_to_node(py, cls)?,
+                value_to_node(py, args)?,
+                value_to_node(py, kwargs)?,
+            ),
+        ),
+        PickleValue::RawPickle(data) => node(py, RawPickle::new(PyBytes::new(py, data).unbind())),
+        PickleValue::Shared { id, value } => node(py, Shared::new(*id, value_to_node(py, value)?)),
+        PickleValue::BackRef(id) => node(py, BackRef::new(*id)),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// nodes → PickleValue
+// ---------------------------------------------------------------------------
+
+fn child(py: Python<'_>, node: &Py<PickleNode>) -> PyResult<PickleValue> {
+    node_to_value(node.bind(py))
+}
+
+fn list_values(list: &Bound<'_, PyList>) -> PyResult<Vec<PickleValue>> {
+    list.iter()
+        .map(|item| node_to_value(item.cast::<PickleNode>()?))
+        .collect()
+}
+
+fn pair_values(list: &Bound<'_, PyList>) -> PyResult<Vec<(PickleValue, PickleValue)>> {
+    list.iter()
+        .map(|item| {
+            let (k, v): (Bound<'_, PickleNode>, Bound<'_, PickleNode>) = item.extract()?;
+            Ok((node_to_value(&k)?, node_to_value(&v)?))
+        })
+        .collect()
+}
+
+/// The `dict_items` field of `Instance` and `Reduce` values.
+#[allow(clippy::box_collection)]
+type DictItems = Option<Box<Vec<(PickleValue, PickleValue)>>>;
+
+fn optional_pairs(py: Python<'_>, list: &Option<Py<PyList>>) -> PyResult<DictItems> {
+    list.as_ref()
+        .map(|l| pair_values(l.bind(py)).map(Box::new))
+        .transpose()
+}
+
+#[allow(clippy::box_collection)]
+fn optional_items(
+    py: Python<'_>,
+    list: &Option<Py<PyList>>,
+) -> PyResult<Option<Box<Vec<PickleValue>>>> {
+    list.as_ref()
+        .map(|l| list_values(l.bind(py)).map(Box::new))
+        .transpose()
+}
+
+/// Convert a node tree back to a `PickleValue`. Fails with a `TypeError`
+/// on children that are not nodes, and with a depth error on cycles.
+pub(crate) fn node_to_value(node: &Bound<'_, PickleNode>) -> PyResult<PickleValue> {
+    let _guard = NestingGuard::enter()?;
+    let py = node.py();
+    if let Ok(n) = node.cast::<Str>() {
+        return crate::pyconv::pystring_to_pickle_value(n.borrow().value.bind(py));
+    }
+    if let Ok(n) = node.cast::<Int>() {
+        return crate::pyconv::pyint_to_pickle_value(n.borrow().value.bind(py));
+    }
+    if let Ok(n) = node.cast::<Dict>() {
+        return Ok(PickleValue::Dict(pair_values(n.borrow().items.bind(py))?));
+    }
+    if let Ok(n) = node.cast::<List>() {
+        return Ok(PickleValue::List(list_values(n.borrow().items.bind(py))?));
+    }
+    if let Ok(n) = node.cast::<Tuple>() {
+        return Ok(PickleValue::Tuple(list_values(n.borrow().items.bind(py))?));
+    }
+    if node.cast::<Null>().is_ok() {
+        return Ok(PickleValue::None);
+    }
+    if let Ok(n) = node.cast::<Bool>() {
+        return Ok(PickleValue::Bool(n.borrow().value));
+    }
+    if let Ok(n) = node.cast::<Float>() {
+        return Ok(PickleValue::Float(n.borrow().value));
+    }
+    if let Ok(n) = node.cast::<Bytes>() {
+        return Ok(PickleValue::Bytes(n.borrow().value.as_bytes(py).to_vec()));
+    }
+    if let Ok(n) = node.cast::<PersistentRef>() {
+        return Ok(PickleValue::PersistentRef(Box::new(child(
+            py,
+            &n.borrow().pid,
+        )?)));
+    }
+    if let Ok(n) = node.cast::<Instance>() {
+        let n = n.borrow();
+        let mut inst = InstanceData::new(n.module.clone(), n.name.clone(), child(py, &n.state)?);
+        inst.dict_items = optional_pairs(py, &n.dict_items)?;
+        inst.list_items = optional_items(py, &n.list_items)?;
+        return Ok(PickleValue::Instance(Box::new(inst)));
+    }
+    if let Ok(n) = node.cast::<Global>() {
+        let n = n.borrow();
+        return Ok(PickleValue::Global {
+            module: n.module.clone(),
+            name: n.name.clone(),
+        });
+    }
+    if let Ok(n) = node.cast::<Reduce>() {
+        let n = n.borrow();
+        return Ok(PickleValue::Reduce {
+            callable: Box::new(child(py, &n.callable)?),
+            args: Box::new(child(py, &n.args)?),
+            dict_items: optional_pairs(py, &n.dict_items)?,
+            list_items: optional_items(py, &n.list_items)?,
+            newobj: n.newobj,
+        });
+    }
+    if let Ok(n) = node.cast::<Set>() {
+        return Ok(PickleValue::Set(list_values(n.borrow().items.bind(py))?));
+    }
+    if let Ok(n) = node.cast::<FrozenSet>() {
+        return Ok(PickleValue::FrozenSet(list_values(
+            n.borrow().items.bind(py),
+        )?));
+    }
+    if let Ok(n) = node.cast::<NewObjEx>() {
+        let n = n.borrow();
+        return Ok(PickleValue::NewObjEx {
+            cls: Box::new(child(py, &n.cls)?),
+            args: Box::new(child(py, &n.args)?),
+            kwargs: Box::new(child(py, &n.kwargs)?),
+        });
+    }
+    if let Ok(n) = node.cast::<Blocked>() {
+        let n = n.borrow();
+        return Ok(PickleValue::Blocked {
+            module: n.module.clone(),
+            name: n.name.clone(),
+        });
+    }
+    if let Ok(n) = node.cast::<RawPickle>() {
+        return Ok(PickleValue::RawPickle(
+            n.borrow().data.as_bytes(py).to_vec(),
+        ));
+    }
+    if let Ok(n) = node.cast::<Shared>() {
+        let n = n.borrow();
+        return Ok(PickleValue::Shared {
+            id: n.id,
+            value: Box::new(child(py, &n.value)?),
+        });
+    }
+    if let Ok(n) = node.cast::<BackRef>() {
+        return Ok(PickleValue::BackRef(n.borrow().id));
+    }
+    Err(PyTypeError::new_err(format!(
+        "{} is not a pickle AST node",
+        node.get_type().name()?
+    )))
+}
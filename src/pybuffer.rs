@@ -0,0 +1,82 @@
+//! Zero-copy pickle input from Python.
+//!
+//! The decoding functions accept any bytes-like object, not just `bytes`:
+//! `bytearray`, `memoryview`, `mmap.mmap`, `pickle.PickleBuffer`, ...
+//! A `bytes` argument is borrowed directly; anything else is read through
+//! the buffer protocol, which pins the exporter's memory (a `bytearray`
+//! cannot be resized) until the argument is dropped. Only C-contiguous
+//! buffers are accepted, so a strided `memoryview` must be copied by the
+//! caller first.
+//!
+//! The decoders read the memory with the GIL released. Callers must not
+//! write to a mutable buffer from another thread while it is decoded.
+//!
+//! The buffer protocol is not part of the stable ABI before Python 3.11,
+//! so `abi3` builds copy non-`bytes` arguments through a `memoryview`.
+
+#[cfg(not(feature = "abi3"))]
+use pyo3::buffer::PyUntypedBuffer;
+use pyo3::exceptions::PyBufferError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+/// A bytes-like function argument, borrowed without copying.
+pub(crate) enum BytesLike<'py> {
+    Bytes(Bound<'py, PyBytes>),
+    #[cfg(not(feature = "abi3"))]
+    Buffer(PyUntypedBuffer),
+}
+
+impl BytesLike<'_> {
+    /// The argument's contents.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match self {
+            BytesLike::Bytes(b) => b.as_bytes(),
+            #[cfg(not(feature = "abi3"))]
+            BytesLike::Buffer(buf) => {
+                let len = buf.len_bytes();
+                if len == 0 {
+                    return &[];
+                }
+                // SAFETY: the buffer is C-contiguous (checked on
+                // extraction) and its memory stays exported while `buf`
+                // is alive.
+                unsafe { std::slice::from_raw_parts(buf.buf_ptr() as *const u8, len) }
+            }
+        }
+    }
+}
+
+impl<'a, 'py> FromPyObject<'a, 'py> for BytesLike<'py> {
+    type Error = PyErr;
+
+    fn extract(obj: Borrowed<'a, 'py, PyAny>) -> PyResult<Self> {
+        if let Ok(b) = obj.cast::<PyBytes>() {
+            return Ok(BytesLike::Bytes(b.to_owned()));
+        }
+        extract_buffer(obj)
+    }
+}
+
+#[cfg(not(feature = "abi3"))]
+fn extract_buffer<'py>(obj: Borrowed<'_, 'py, PyAny>) -> PyResult<BytesLike<'py>> {
+    let buf = PyUntypedBuffer::get(&obj)?;
+    if !buf.is_c_contiguous() {
+        return Err(PyBufferError::new_err("buffer is not C-contiguous"));
+    }
+    Ok(BytesLike::Buffer(buf))
+}
+
+#[cfg(feature = "abi3")]
+fn extract_buffer<'py>(obj: Borrowed<'_, 'py, PyAny>) -> PyResult<BytesLike<'py>> {
+    use pyo3::intern;
+    use pyo3::types::PyMemoryView;
+
+    let py = obj.py();
+    let view = PyMemoryView::from(&obj)?;
+    if !view.getattr(intern!(py, "c_contiguous"))?.is_truthy()? {
+        return Err(PyBufferError::new_err("buffer is not C-contiguous"));
+    }
+    let data = view.call_method0(intern!(py, "tobytes"))?;
+    Ok(BytesLike::Bytes(data.cast_into::<PyBytes>()?))
+}
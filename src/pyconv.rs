@@ -8,17 +8,39 @@
 //! The JSON string API (`pickle_to_json`, `json_to_pickle`) still uses
 //! json.rs + serde_json::Value.
 
-use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use pyo3::prelude::*;
 use pyo3::intern;
-use pyo3::types::{PyBool, PyDict, PyFloat, PyInt, PyList, PyString};
+use pyo3::types::{PyBool, PyBytes, PyDict, PyFloat, PyInt, PyList, PyString, PyTuple};
+use std::cell::Cell;
 
+use crate::batch;
+use crate::bigint;
+use crate::binenc::{b64_decode, b64_encode, hex_decode, hex_encode};
 use crate::btrees;
-use crate::encode::{encode_value_into, write_bytes_val, write_global, write_int, write_string};
-use crate::error::CodecError;
+use crate::bytes_keys::{self, BYTES_KEYS_MARKER};
+use crate::dangling;
+use crate::dedup;
+use crate::duplicate_keys::{self, ObjectItems};
+use crate::encode::{
+    encode_pickle, encode_value_into, write_bytes_val, write_global, write_int, write_string,
+    write_text, NestingGuard,
+};
+use crate::error::{self, CodecError, PathSegment};
+use crate::floats;
+use crate::json::{null_bytes_fallback, reduce_fallback, reduce_keys};
 use crate::known_types;
+use crate::limits::EncodeLimits;
+use crate::null_strings;
 use crate::opcodes::*;
+use crate::raw_pickle;
+use crate::registry::{self, Payload};
+use crate::rename;
+use crate::shared::SharedIdsScope;
+use crate::surrogates;
 use crate::types::{InstanceData, PickleValue};
+use crate::zodb::{
+    build_class_pickle, compact_class_path, expand_extended_ref, int_ref_oid, split_class_path, ExtendedRef,
+};
 
 const MAX_DEPTH: usize = 1000;
 
@@ -48,67 +70,78 @@ pub fn pickle_value_to_pyobject_pg(
     pickle_value_to_pyobject_impl(py, val, compact_refs, true, 0)
 }
 
-/// Collect all persistent reference OIDs from a PickleValue tree.
-///
-/// OIDs are returned as i64 (big-endian interpretation of 8-byte ZODB OID).
-/// Cross-database refs (non-8-byte OIDs) are skipped.
-pub fn collect_refs_from_pickle_value(val: &PickleValue, refs: &mut Vec<i64>) {
-    match val {
-        PickleValue::PersistentRef(inner) => {
-            // Extract OID from Tuple([Bytes(oid), ...])
-            if let PickleValue::Tuple(items) = inner.as_ref() {
-                if let Some(PickleValue::Bytes(oid)) = items.first() {
-                    if oid.len() == 8 {
-                        if let Ok(arr) = <[u8; 8]>::try_from(oid.as_slice()) {
-                            refs.push(i64::from_be_bytes(arr));
-                        }
-                    }
-                }
-            }
-        }
-        PickleValue::List(items)
-        | PickleValue::Tuple(items)
-        | PickleValue::Set(items)
-        | PickleValue::FrozenSet(items) => {
-            for item in items {
-                collect_refs_from_pickle_value(item, refs);
-            }
-        }
-        PickleValue::Dict(pairs) => {
-            for (k, v) in pairs {
-                collect_refs_from_pickle_value(k, refs);
-                collect_refs_from_pickle_value(v, refs);
-            }
+/// How the forward conversion writes `PickleValue::Bytes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BytesMode {
+    /// `{"@b": "base64"}`, the JSON-compatible default.
+    Base64,
+    /// `{"@b": b"..."}` (the `binary_mode` argument).
+    Binary,
+    /// A plain `bytes` object (the `raw_bytes` argument).
+    Raw,
+}
+
+impl BytesMode {
+    /// The mode selected by the `binary_mode` and `raw_bytes` arguments;
+    /// `raw_bytes` wins.
+    pub(crate) fn from_flags(binary_mode: bool, raw_bytes: bool) -> Self {
+        match (binary_mode, raw_bytes) {
+            (_, true) => BytesMode::Raw,
+            (true, false) => BytesMode::Binary,
+            (false, false) => BytesMode::Base64,
         }
-        PickleValue::Instance(inst) => {
-            collect_refs_from_pickle_value(&inst.state, refs);
-            if let Some(pairs) = &inst.dict_items {
-                for (k, v) in pairs.iter() {
-                    collect_refs_from_pickle_value(k, refs);
-                    collect_refs_from_pickle_value(v, refs);
-                }
-            }
-            if let Some(items) = &inst.list_items {
-                for item in items.iter() {
-                    collect_refs_from_pickle_value(item, refs);
-                }
-            }
+    }
+}
+
+thread_local! {
+    static BYTES_MODE: Cell<BytesMode> = const { Cell::new(BytesMode::Base64) };
+}
+
+/// Sets the `BytesMode` of the forward conversion on the current thread
+/// while alive.
+///
+/// The non-base64 modes create the bytes object straight from the decoded
+/// slice, which skips base64 encoding and its 4/3 size overhead. They are
+/// not for output that is serialized to JSON afterwards.
+pub(crate) struct BytesModeScope {
+    previous: BytesMode,
+}
+
+impl BytesModeScope {
+    pub(crate) fn enter(mode: BytesMode) -> Self {
+        BytesModeScope {
+            previous: BYTES_MODE.with(|m| m.replace(mode)),
         }
-        PickleValue::Reduce { args, dict_items, list_items, .. } => {
-            collect_refs_from_pickle_value(args, refs);
-            if let Some(pairs) = dict_items {
-                for (k, v) in pairs.iter() {
-                    collect_refs_from_pickle_value(k, refs);
-                    collect_refs_from_pickle_value(v, refs);
-                }
-            }
-            if let Some(items) = list_items {
-                for item in items.iter() {
-                    collect_refs_from_pickle_value(item, refs);
-                }
-            }
+    }
+}
+
+impl Drop for BytesModeScope {
+    fn drop(&mut self) {
+        BYTES_MODE.with(|m| m.set(self.previous));
+    }
+}
+
+thread_local! {
+    static SORT_KEYS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Makes the forward conversion insert dict items sorted by key on the
+/// current thread while alive (`sort_keys=True`).
+pub(crate) struct SortKeysScope {
+    previous: bool,
+}
+
+impl SortKeysScope {
+    pub(crate) fn enter(sort_keys: bool) -> Self {
+        SortKeysScope {
+            previous: SORT_KEYS.with(|s| s.replace(sort_keys)),
         }
-        _ => {}
+    }
+}
+
+impl Drop for SortKeysScope {
+    fn drop(&mut self) {
+        SORT_KEYS.with(|s| s.set(self.previous));
     }
 }
 
@@ -126,26 +159,50 @@ fn pickle_value_to_pyobject_impl(
     match val {
         PickleValue::None => Ok(py.None()),
         PickleValue::Bool(b) => Ok(b.into_pyobject(py)?.to_owned().into_any().unbind()),
-        PickleValue::Int(i) => Ok(i.into_pyobject(py)?.into_any().unbind()),
+        PickleValue::Int(i) => Ok(dedup::int_leaf(py, *i)),
         PickleValue::BigInt(bi) => {
+            if let Some(n) = bigint::bigint_as_number(bi) {
+                return Ok(n.into_pyobject(py)?.into_any().unbind());
+            }
             let dict = PyDict::new(py);
             dict.set_item(intern!(py, "@bi"), bi.to_string())?;
             Ok(dict.into_any().unbind())
         }
-        PickleValue::Float(f) => Ok(f.into_pyobject(py)?.into_any().unbind()),
+        PickleValue::Float(f) if !f.is_finite() => match floats::float_marker(*f) {
+            Some(marker) => {
+                let dict = PyDict::new(py);
+                dict.set_item(intern!(py, "@f"), marker)?;
+                Ok(dict.into_any().unbind())
+            }
+            None => Ok(py.None()),
+        },
+        PickleValue::Float(f) => Ok(dedup::float_leaf(py, *f)),
         PickleValue::String(s) => {
             if sanitize_nulls && s.contains('\0') {
                 // PG JSONB cannot store \u0000 — base64-encode with @ns marker
+                null_bytes_fallback(false);
                 let dict = PyDict::new(py);
-                dict.set_item(intern!(py, "@ns"), BASE64.encode(s.as_bytes()))?;
+                dict.set_item(intern!(py, "@ns"), b64_encode(s.as_bytes()))?;
                 Ok(dict.into_any().unbind())
             } else {
-                Ok(s.into_pyobject(py)?.into_any().unbind())
+                Ok(dedup::str_leaf(py, s))
             }
         }
+        PickleValue::SurrogateString(b) => {
+            let dict = PyDict::new(py);
+            dict.set_item(intern!(py, "@su"), surrogates::escape(b)?)?;
+            Ok(dict.into_any().unbind())
+        }
         PickleValue::Bytes(b) => {
+            if let Some(raw) = known_types::detect_raw_tid(b) {
+                return tid_pyobject(py, raw, None);
+            }
             let dict = PyDict::new(py);
-            dict.set_item(intern!(py, "@b"), BASE64.encode(b))?;
+            match BYTES_MODE.with(Cell::get) {
+                BytesMode::Base64 => dict.set_item(intern!(py, "@b"), b64_encode(b))?,
+                BytesMode::Binary => dict.set_item(intern!(py, "@b"), PyBytes::new(py, b))?,
+                BytesMode::Raw => return Ok(PyBytes::new(py, b).into_any().unbind()),
+            }
             Ok(dict.into_any().unbind())
         }
         PickleValue::List(items) => {
@@ -169,23 +226,31 @@ fn pickle_value_to_pyobject_impl(
         PickleValue::Dict(pairs) => {
             // Pre-scan: check if all keys are strings to avoid double processing
             let all_string_keys = pairs.iter().all(|(k, _)| matches!(k, PickleValue::String(_)));
-            if all_string_keys {
+            let bytes_keys = !all_string_keys && bytes_keys::promote_keys(pairs);
+            let items = match (all_string_keys || bytes_keys, SORT_KEYS.with(Cell::get)) {
+                (true, false) => duplicate_keys::object_items(pairs)?,
+                (true, true) => duplicate_keys::object_items(pairs)?.sorted(pairs),
+                (false, _) => ObjectItems::Pairs,
+            };
+            if !matches!(items, ObjectItems::Pairs) {
                 let dict = PyDict::new(py);
-                for (k, v) in pairs {
-                    if let PickleValue::String(key) = k {
+                if bytes_keys {
+                    dict.set_item(intern!(py, "@bk"), true)?;
+                }
+                for (k, v) in items.iter(pairs) {
+                    if let Some(key) = bytes_keys::key_text(k) {
                         let py_key = if sanitize_nulls && key.contains('\0') {
-                            let marker = PyDict::new(py);
-                            marker.set_item(intern!(py, "@ns"), BASE64.encode(key.as_bytes()))?;
-                            marker.into_any().unbind()
+                            null_bytes_fallback(true);
+                            PyString::new(py, &null_strings::escape_key(key)).into_any().unbind()
                         } else {
-                            key.into_pyobject(py)?.into_any().unbind()
+                            dedup::str_leaf(py, key)
                         };
                         dict.set_item(py_key, pickle_value_to_pyobject_impl(py, v, compact_refs, sanitize_nulls, depth + 1)?)?;
                     }
                 }
                 Ok(dict.into_any().unbind())
             } else {
-                // Non-string keys: use @d format
+                // Non-string or duplicate keys: use @d format
                 let py_pairs: PyResult<Vec<Py<PyAny>>> = pairs
                     .iter()
                     .map(|(k, v)| {
@@ -227,12 +292,24 @@ fn pickle_value_to_pyobject_impl(
             dict.set_item(intern!(py, "@cls"), cls_list)?;
             Ok(dict.into_any().unbind())
         }
+        PickleValue::Blocked { module, name } => {
+            let cls_list = PyList::new(py, [module.as_str(), name.as_str()])?;
+            let dict = PyDict::new(py);
+            dict.set_item(intern!(py, "@blocked"), cls_list)?;
+            Ok(dict.into_any().unbind())
+        }
         PickleValue::Instance(inst) => {
-            let InstanceData { module, name, state, .. } = inst.as_ref();
+            let InstanceData { module, name, state, dict_items, list_items } = inst.as_ref();
             // Try known type handlers first (e.g., uuid.UUID)
-            if let Some(obj) =
-                try_instance_to_pyobject(py, module, name, state, compact_refs)?
-            {
+            if let Some(obj) = try_instance_to_pyobject(
+                py,
+                module,
+                name,
+                state,
+                compact_refs,
+                sanitize_nulls,
+                depth,
+            )? {
                 return Ok(obj);
             }
             // Try BTree state flattening
@@ -241,18 +318,19 @@ fn pickle_value_to_pyobject_impl(
             } else {
                 pickle_value_to_pyobject_impl(py, state, compact_refs, sanitize_nulls, depth + 1)?
             };
+            let dict = PyDict::new(py);
             if module.is_empty() && name.is_empty() {
                 // Anonymous instance
-                let dict = PyDict::new(py);
                 dict.set_item(intern!(py, "@inst"), state_obj)?;
-                Ok(dict.into_any().unbind())
             } else {
                 let cls_list = PyList::new(py, [module.as_str(), name.as_str()])?;
-                let dict = PyDict::new(py);
                 dict.set_item(intern!(py, "@cls"), cls_list)?;
                 dict.set_item(intern!(py, "@s"), state_obj)?;
-                Ok(dict.into_any().unbind())
             }
+            let keys = (intern!(py, "@items"), intern!(py, "@appends"));
+            let items = (dict_items.as_deref().map(Vec::as_slice), list_items.as_deref().map(Vec::as_slice));
+            set_items_pyobjects(&dict, keys, items.0, items.1, compact_refs, sanitize_nulls, depth)?;
+            Ok(dict.into_any().unbind())
         }
         PickleValue::PersistentRef(inner) => {
             if compact_refs {
@@ -264,26 +342,60 @@ fn pickle_value_to_pyobject_impl(
                 Ok(dict.into_any().unbind())
             }
         }
-        PickleValue::Reduce { callable, args, .. } => {
+        PickleValue::Reduce { callable, args, dict_items, list_items, newobj } => {
             // Try known type handlers first (datetime, Decimal, set, etc.)
-            if let Some(obj) =
-                try_reduce_to_pyobject_impl(py, callable, args, compact_refs, sanitize_nulls, depth)?
-            {
+            let items = dict_items.as_deref().map(Vec::as_slice);
+            if let Some(obj) = try_reduce_to_pyobject_impl(
+                py,
+                callable,
+                args,
+                items,
+                compact_refs,
+                sanitize_nulls,
+                depth,
+            )? {
                 return Ok(obj);
             }
-            // Fall back to generic @reduce
+            // Fall back to generic @reduce / @newobj
+            reduce_fallback(callable);
+            let (marker, callable_key) = reduce_keys(*newobj);
             let callable_obj = pickle_value_to_pyobject_impl(py, callable, compact_refs, sanitize_nulls, depth + 1)?;
             let args_obj = pickle_value_to_pyobject_impl(py, args, compact_refs, sanitize_nulls, depth + 1)?;
             let inner_dict = PyDict::new(py);
-            inner_dict.set_item(intern!(py, "callable"), callable_obj)?;
+            inner_dict.set_item(callable_key, callable_obj)?;
             inner_dict.set_item(intern!(py, "args"), args_obj)?;
+            let keys = (intern!(py, "items"), intern!(py, "appends"));
+            let appends = list_items.as_deref().map(Vec::as_slice);
+            set_items_pyobjects(&inner_dict, keys, items, appends, compact_refs, sanitize_nulls, depth)?;
             let dict = PyDict::new(py);
-            dict.set_item(intern!(py, "@reduce"), inner_dict)?;
+            dict.set_item(marker, inner_dict)?;
+            Ok(dict.into_any().unbind())
+        }
+        PickleValue::NewObjEx { cls, args, kwargs } => {
+            let inner_dict = PyDict::new(py);
+            for (key, part) in [("cls", cls), ("args", args), ("kwargs", kwargs)] {
+                let obj = pickle_value_to_pyobject_impl(py, part, compact_refs, sanitize_nulls, depth + 1)?;
+                inner_dict.set_item(key, obj)?;
+            }
+            let dict = PyDict::new(py);
+            dict.set_item(intern!(py, "@newobj_ex"), inner_dict)?;
             Ok(dict.into_any().unbind())
         }
         PickleValue::RawPickle(data) => {
             let dict = PyDict::new(py);
-            dict.set_item(intern!(py, "@pkl"), BASE64.encode(data))?;
+            dict.set_item(intern!(py, "@pkl"), b64_encode(data))?;
+            Ok(dict.into_any().unbind())
+        }
+        PickleValue::Shared { id, value } => {
+            let value_obj = pickle_value_to_pyobject_impl(py, value, compact_refs, sanitize_nulls, depth + 1)?;
+            let pair = PyList::new(py, [id.into_pyobject(py)?.into_any().unbind(), value_obj])?;
+            let dict = PyDict::new(py);
+            dict.set_item(intern!(py, "@shared"), pair)?;
+            Ok(dict.into_any().unbind())
+        }
+        PickleValue::BackRef(id) => {
+            let dict = PyDict::new(py);
+            dict.set_item(intern!(py, "@backref"), id)?;
             Ok(dict.into_any().unbind())
         }
     }
@@ -305,28 +417,32 @@ fn compact_ref_to_pyobject_impl(
     if let PickleValue::Tuple(items) = inner {
         if items.len() == 2 {
             if let PickleValue::Bytes(oid) = &items[0] {
-                let hex = hex::encode(oid);
+                let oid = ref_oid_to_pyobject(py, oid)?;
                 let dict = PyDict::new(py);
                 match &items[1] {
                     PickleValue::None => {
-                        dict.set_item(intern!(py, "@ref"), &hex)?;
+                        dict.set_item(intern!(py, "@ref"), oid)?;
                         return Ok(dict.into_any().unbind());
                     }
                     PickleValue::Global { module, name } => {
-                        let class_path = if module.is_empty() {
-                            name.clone()
-                        } else {
-                            format!("{module}.{name}")
-                        };
-                        let ref_list = PyList::new(py, [hex.as_str(), class_path.as_str()])?;
-                        dict.set_item(intern!(py, "@ref"), ref_list)?;
-                        return Ok(dict.into_any().unbind());
+                        if let Some(class_path) = compact_class_path(module, name) {
+                            let class_path = PyString::new(py, &class_path).into_any();
+                            let ref_list = PyList::new(py, [oid, class_path])?;
+                            dict.set_item(intern!(py, "@ref"), ref_list)?;
+                            return Ok(dict.into_any().unbind());
+                        }
                     }
                     _ => {}
                 }
             }
         }
     }
+    if let Some(ext) = ExtendedRef::parse(inner) {
+        let ref_obj = extended_ref_to_pyobject(py, &ext)?;
+        let dict = PyDict::new(py);
+        dict.set_item(intern!(py, "@ref"), ref_obj)?;
+        return Ok(dict.into_any().unbind());
+    }
     // Fallback: generic ref
     let inner_obj = pickle_value_to_pyobject_impl(py, inner, compact_refs, sanitize_nulls, depth)?;
     let dict = PyDict::new(py);
@@ -334,6 +450,92 @@ fn compact_ref_to_pyobject_impl(
     Ok(dict.into_any().unbind())
 }
 
+/// An OID in a compact `@ref`: hex digits, or an int with the int ref
+/// format.
+fn ref_oid_to_pyobject<'py>(py: Python<'py>, oid: &[u8]) -> PyResult<Bound<'py, PyAny>> {
+    Ok(match int_ref_oid(oid) {
+        Some(n) => n.into_pyobject(py)?.into_any(),
+        None => PyString::new(py, &hex_encode(oid)).into_any(),
+    })
+}
+
+/// The OID of a compact `@ref` value given as hex digits or an int, or
+/// `None` for other objects.
+fn ref_oid_from_pyobject(obj: &Bound<'_, PyAny>) -> PyResult<Option<Vec<u8>>> {
+    if let Ok(s) = obj.cast::<PyString>() {
+        return Ok(Some(hex_decode(s.to_str()?)?));
+    }
+    if obj.is_instance_of::<PyInt>() && !obj.is_instance_of::<PyBool>() {
+        let oid = match obj.extract::<i64>() {
+            Ok(n) => n.to_be_bytes(),
+            Err(_) => obj
+                .extract::<u64>()
+                .map_err(|_| CodecError::InvalidData("@ref oid out of 64-bit range".to_string()))?
+                .to_be_bytes(),
+        };
+        return Ok(Some(oid.to_vec()));
+    }
+    Ok(None)
+}
+
+/// The compact `@ref` value of a legacy weak or extended ref:
+/// `["hex"]` or `{"oid": "hex", "weak": True, "db": ..., "cls": ...}`.
+fn extended_ref_to_pyobject(py: Python<'_>, ext: &ExtendedRef<'_>) -> PyResult<Py<PyAny>> {
+    match ext {
+        ExtendedRef::Legacy(oid) => {
+            Ok(PyList::new(py, [ref_oid_to_pyobject(py, oid)?])?.into_any().unbind())
+        }
+        ExtendedRef::Extended { oid, weak, db, class } => {
+            let dict = PyDict::new(py);
+            dict.set_item(intern!(py, "oid"), ref_oid_to_pyobject(py, oid)?)?;
+            if *weak {
+                dict.set_item(intern!(py, "weak"), true)?;
+            }
+            if let Some(db) = db {
+                dict.set_item(intern!(py, "db"), *db)?;
+            }
+            if let Some(class) = class {
+                dict.set_item(intern!(py, "cls"), class)?;
+            }
+            Ok(dict.into_any().unbind())
+        }
+    }
+}
+
+/// Set the SETITEMS/APPENDS items of an instance (`@items`/`@appends`) or
+/// reduce (`items`/`appends`) on its output dict, as `[key, value]` pairs
+/// and a list.
+fn set_items_pyobjects(
+    dict: &Bound<'_, PyDict>,
+    (items_key, appends_key): (&Bound<'_, PyString>, &Bound<'_, PyString>),
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
+    list_items: Option<&[PickleValue]>,
+    compact_refs: bool,
+    sanitize_nulls: bool,
+    depth: usize,
+) -> PyResult<()> {
+    let py = dict.py();
+    if let Some(pairs) = dict_items {
+        let py_pairs: PyResult<Vec<Py<PyAny>>> = pairs
+            .iter()
+            .map(|(k, v)| {
+                let k = pickle_value_to_pyobject_impl(py, k, compact_refs, sanitize_nulls, depth + 1)?;
+                let v = pickle_value_to_pyobject_impl(py, v, compact_refs, sanitize_nulls, depth + 1)?;
+                Ok(PyList::new(py, [k, v])?.into_any().unbind())
+            })
+            .collect();
+        dict.set_item(items_key, PyList::new(py, py_pairs?)?)?;
+    }
+    if let Some(items) = list_items {
+        let py_items: PyResult<Vec<Py<PyAny>>> = items
+            .iter()
+            .map(|item| pickle_value_to_pyobject_impl(py, item, compact_refs, sanitize_nulls, depth + 1))
+            .collect();
+        dict.set_item(appends_key, PyList::new(py, py_items?)?)?;
+    }
+    Ok(())
+}
+
 // ---------------------------------------------------------------------------
 // Forward: known type handlers (PickleValue → Py<PyAny>)
 // ---------------------------------------------------------------------------
@@ -343,6 +545,7 @@ fn try_reduce_to_pyobject_impl(
     py: Python<'_>,
     callable: &PickleValue,
     args: &PickleValue,
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
     compact_refs: bool,
     sanitize_nulls: bool,
     depth: usize,
@@ -358,9 +561,50 @@ fn try_reduce_to_pyobject_impl(
         ("datetime", "time") => encode_time_pyobject(py, args, compact_refs),
         ("datetime", "timedelta") => encode_timedelta_pyobject(py, args),
         ("decimal", "Decimal") => encode_decimal_pyobject(py, args),
+        (m, "complex") if known_types::is_builtins_module(m) => encode_complex_pyobject(py, args),
+        ("fractions", "Fraction") => encode_fraction_pyobject(py, args),
         ("builtins", "set") => encode_set_pyobject_impl(py, args, compact_refs, sanitize_nulls, depth + 1),
         ("builtins", "frozenset") => encode_frozenset_pyobject_impl(py, args, compact_refs, sanitize_nulls, depth + 1),
-        _ => Ok(None),
+        ("collections", "OrderedDict") => {
+            encode_odict_pyobject_impl(py, args, dict_items, compact_refs, sanitize_nulls, depth + 1)
+        }
+        ("collections", "defaultdict") => {
+            encode_ddict_pyobject_impl(py, args, dict_items, compact_refs, sanitize_nulls, depth + 1)
+        }
+        ("zope.interface.declarations", "Provides") => encode_provides_pyobject(py, args),
+        (m, "TimeStamp") if known_types::is_timestamp_module(m) => {
+            match known_types::timestamp_raw(args) {
+                Some(raw) => tid_pyobject(py, raw, Some(m)).map(Some),
+                None => Ok(None),
+            }
+        }
+        _ => {
+            let Some((handler, payload)) = registry::reduce_payload(module, name, args, dict_items)
+            else {
+                return Ok(None);
+            };
+            let value = match payload {
+                Payload::One(arg) => {
+                    pickle_value_to_pyobject_impl(py, arg, compact_refs, sanitize_nulls, depth + 1)?
+                }
+                Payload::Many(args) => {
+                    let list = PyList::empty(py);
+                    for arg in args {
+                        list.append(pickle_value_to_pyobject_impl(
+                            py,
+                            arg,
+                            compact_refs,
+                            sanitize_nulls,
+                            depth + 1,
+                        )?)?;
+                    }
+                    list.into_any().unbind()
+                }
+            };
+            let dict = PyDict::new(py);
+            dict.set_item(handler.marker.as_str(), value)?;
+            Ok(Some(dict.into_any().unbind()))
+        }
     }
 }
 
@@ -395,7 +639,7 @@ fn encode_datetime_pyobject(
         let to_json_dummy =
             |pv: &PickleValue| -> Result<serde_json::Value, CodecError> {
                 match pv {
-                    PickleValue::String(s) => Ok(serde_json::Value::String(s.clone())),
+                    PickleValue::String(s) => Ok(serde_json::Value::String(s.to_string())),
                     PickleValue::Int(i) => Ok(serde_json::json!(*i)),
                     PickleValue::Float(f) => Ok(serde_json::json!(*f)),
                     PickleValue::None => Ok(serde_json::Value::Null),
@@ -464,6 +708,88 @@ fn json_value_to_simple_pyobject(
     }
 }
 
+/// Convert any serde_json::Value to the Python object `json.loads` gives.
+pub(crate) fn json_value_to_pyobject(py: Python<'_>, val: &serde_json::Value) -> PyResult<Py<PyAny>> {
+    match val {
+        serde_json::Value::Bool(b) => Ok(b.into_pyobject(py)?.to_owned().into_any().unbind()),
+        serde_json::Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Ok(i.into_pyobject(py)?.into_any().unbind())
+            } else if let Some(u) = n.as_u64() {
+                Ok(u.into_pyobject(py)?.into_any().unbind())
+            } else {
+                Ok(n.as_f64().unwrap_or(f64::NAN).into_pyobject(py)?.into_any().unbind())
+            }
+        }
+        serde_json::Value::Array(items) => {
+            let list = PyList::empty(py);
+            for item in items {
+                list.append(json_value_to_pyobject(py, item)?)?;
+            }
+            Ok(list.into_any().unbind())
+        }
+        serde_json::Value::Object(map) => {
+            let dict = PyDict::new(py);
+            for (key, item) in map {
+                dict.set_item(key, json_value_to_pyobject(py, item)?)?;
+            }
+            Ok(dict.into_any().unbind())
+        }
+        other => json_value_to_simple_pyobject(py, other),
+    }
+}
+
+/// Convert a JSON-compatible Python object (as from `json.loads`) to a
+/// serde_json::Value. Tuples are accepted as lists.
+pub(crate) fn pyobject_to_json_value(obj: &Bound<'_, PyAny>) -> PyResult<serde_json::Value> {
+    if obj.is_none() {
+        return Ok(serde_json::Value::Null);
+    }
+    if let Ok(s) = obj.cast::<PyString>() {
+        return Ok(serde_json::Value::String(s.to_str()?.to_string()));
+    }
+    if obj.is_instance_of::<PyBool>() {
+        return Ok(serde_json::Value::Bool(obj.extract()?));
+    }
+    if obj.is_instance_of::<PyInt>() {
+        if let Ok(i) = obj.extract::<i64>() {
+            return Ok(i.into());
+        }
+        let u: u64 = obj
+            .extract()
+            .map_err(|_| CodecError::InvalidData("integer out of JSON range".to_string()))?;
+        return Ok(u.into());
+    }
+    if obj.is_instance_of::<PyFloat>() {
+        let f: f64 = obj.extract()?;
+        return serde_json::Number::from_f64(f)
+            .map(serde_json::Value::Number)
+            .ok_or_else(|| CodecError::InvalidData(format!("{f} is not valid JSON")).into());
+    }
+    if let Ok(dict) = obj.cast::<PyDict>() {
+        let _nesting = NestingGuard::enter()?;
+        let mut map = serde_json::Map::with_capacity(dict.len());
+        for (key, value) in dict.iter() {
+            let key = key
+                .cast::<PyString>()
+                .map_err(|_| CodecError::InvalidData("JSON object keys must be strings".to_string()))?;
+            map.insert(key.to_str()?.to_string(), pyobject_to_json_value(&value)?);
+        }
+        return Ok(serde_json::Value::Object(map));
+    }
+    if obj.is_instance_of::<PyList>() || obj.is_instance_of::<PyTuple>() {
+        let _nesting = NestingGuard::enter()?;
+        let items: PyResult<Vec<serde_json::Value>> =
+            obj.try_iter()?.map(|item| pyobject_to_json_value(&item?)).collect();
+        return Ok(serde_json::Value::Array(items?));
+    }
+    Err(CodecError::InvalidData(format!(
+        "{} is not JSON-compatible",
+        obj.get_type().name()?
+    ))
+    .into())
+}
+
 fn encode_date_pyobject(
     py: Python<'_>,
     args: &PickleValue,
@@ -515,7 +841,7 @@ fn encode_time_pyobject(
         let to_json_dummy =
             |pv: &PickleValue| -> Result<serde_json::Value, CodecError> {
                 match pv {
-                    PickleValue::String(s) => Ok(serde_json::Value::String(s.clone())),
+                    PickleValue::String(s) => Ok(serde_json::Value::String(s.to_string())),
                     PickleValue::Int(i) => Ok(serde_json::json!(*i)),
                     _ => Ok(serde_json::Value::Null),
                 }
@@ -599,10 +925,59 @@ fn encode_decimal_pyobject(
         _ => return Ok(None),
     };
     let dict = PyDict::new(py);
-    dict.set_item(intern!(py, "@dec"), s.as_str())?;
+    dict.set_item(intern!(py, "@dec"), &**s)?;
     Ok(Some(dict.into_any().unbind()))
 }
 
+fn encode_complex_pyobject(
+    py: Python<'_>,
+    args: &PickleValue,
+) -> PyResult<Option<Py<PyAny>>> {
+    let Some((re, im)) = known_types::complex_parts(args) else {
+        return Ok(None);
+    };
+    let dict = PyDict::new(py);
+    dict.set_item(intern!(py, "@complex"), PyList::new(py, [re, im])?)?;
+    Ok(Some(dict.into_any().unbind()))
+}
+
+fn encode_fraction_pyobject(
+    py: Python<'_>,
+    args: &PickleValue,
+) -> PyResult<Option<Py<PyAny>>> {
+    let Some(s) = known_types::fraction_string(args) else {
+        return Ok(None);
+    };
+    let dict = PyDict::new(py);
+    dict.set_item(intern!(py, "@frac"), s)?;
+    Ok(Some(dict.into_any().unbind()))
+}
+
+fn encode_provides_pyobject(
+    py: Python<'_>,
+    args: &PickleValue,
+) -> PyResult<Option<Py<PyAny>>> {
+    let Some(names) = known_types::provides_names(args) else {
+        return Ok(None);
+    };
+    let dict = PyDict::new(py);
+    dict.set_item(intern!(py, "@provides"), PyList::new(py, names)?)?;
+    Ok(Some(dict.into_any().unbind()))
+}
+
+/// `{"@tid": [hex, iso]}` (+ module for TimeStamp objects).
+fn tid_pyobject(py: Python<'_>, raw: &[u8; 8], module: Option<&str>) -> PyResult<Py<PyAny>> {
+    let hex_str = hex_encode(raw);
+    let iso = known_types::format_tid_iso(raw);
+    let list = match module {
+        Some(m) => PyList::new(py, [hex_str.as_str(), iso.as_str(), m])?,
+        None => PyList::new(py, [hex_str.as_str(), iso.as_str()])?,
+    };
+    let dict = PyDict::new(py);
+    dict.set_item(intern!(py, "@tid"), list)?;
+    Ok(dict.into_any().unbind())
+}
+
 fn encode_set_pyobject_impl(
     py: Python<'_>,
     args: &PickleValue,
@@ -653,20 +1028,186 @@ fn encode_frozenset_pyobject_impl(
     Ok(Some(dict.into_any().unbind()))
 }
 
+fn encode_odict_pyobject_impl(
+    py: Python<'_>,
+    args: &PickleValue,
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
+    compact_refs: bool,
+    sanitize_nulls: bool,
+    depth: usize,
+) -> PyResult<Option<Py<PyAny>>> {
+    let Some(pairs) = known_types::odict_pairs(args, dict_items) else {
+        return Ok(None);
+    };
+    let list = PyList::empty(py);
+    for (k, v) in pairs {
+        let k = pickle_value_to_pyobject_impl(py, k, compact_refs, sanitize_nulls, depth)?;
+        let v = pickle_value_to_pyobject_impl(py, v, compact_refs, sanitize_nulls, depth)?;
+        list.append(PyList::new(py, [k, v])?)?;
+    }
+    let dict = PyDict::new(py);
+    dict.set_item(intern!(py, "@odict"), list)?;
+    Ok(Some(dict.into_any().unbind()))
+}
+
+fn encode_ddict_pyobject_impl(
+    py: Python<'_>,
+    args: &PickleValue,
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
+    compact_refs: bool,
+    sanitize_nulls: bool,
+    depth: usize,
+) -> PyResult<Option<Py<PyAny>>> {
+    let Some(factory) = known_types::ddict_factory(args) else {
+        return Ok(None);
+    };
+    let items = PickleValue::Dict(dict_items.unwrap_or_default().to_vec());
+    let inner = PyDict::new(py);
+    inner.set_item(
+        intern!(py, "factory"),
+        pickle_value_to_pyobject_impl(py, factory, compact_refs, sanitize_nulls, depth)?,
+    )?;
+    inner.set_item(
+        intern!(py, "items"),
+        pickle_value_to_pyobject_impl(py, &items, compact_refs, sanitize_nulls, depth)?,
+    )?;
+    let dict = PyDict::new(py);
+    dict.set_item(intern!(py, "@ddict"), inner)?;
+    Ok(Some(dict.into_any().unbind()))
+}
+
 /// Try to convert a known Instance to a compact typed Py<PyAny>.
 fn try_instance_to_pyobject(
     py: Python<'_>,
     module: &str,
     name: &str,
     state: &PickleValue,
-    _compact_refs: bool,
+    compact_refs: bool,
+    sanitize_nulls: bool,
+    depth: usize,
 ) -> PyResult<Option<Py<PyAny>>> {
     match (module, name) {
         ("uuid", "UUID") => encode_uuid_pyobject(py, state),
-        _ => Ok(None),
+        ("DateTime.DateTime", "DateTime") => {
+            encode_zdt_pyobject(py, state, compact_refs, sanitize_nulls, depth + 1)
+        }
+        _ => {
+            if let Some(obj) = container_state_to_pyobject_impl(
+                py,
+                module,
+                name,
+                state,
+                compact_refs,
+                sanitize_nulls,
+                depth,
+            )? {
+                return Ok(Some(obj));
+            }
+            let Some(handler) = registry::instance_handler(module, name) else {
+                return Ok(None);
+            };
+            let dict = PyDict::new(py);
+            dict.set_item(
+                handler.marker.as_str(),
+                pickle_value_to_pyobject_impl(py, state, compact_refs, sanitize_nulls, depth + 1)?,
+            )?;
+            Ok(Some(dict.into_any().unbind()))
+        }
     }
 }
 
+/// Convert a PersistentMapping/PersistentList/RelationValue/Length state to
+/// its `@pmap`/`@plist`/`@rel`/`@len` marker dict, or `None` if the class or
+/// the state shape doesn't match.
+pub fn container_state_to_pyobject(
+    py: Python<'_>,
+    module: &str,
+    name: &str,
+    state: &PickleValue,
+    compact_refs: bool,
+) -> PyResult<Option<Py<PyAny>>> {
+    container_state_to_pyobject_impl(py, module, name, state, compact_refs, false, 0)
+}
+
+/// Like `container_state_to_pyobject` but with null-byte sanitization for PG JSONB.
+pub fn container_state_to_pyobject_pg(
+    py: Python<'_>,
+    module: &str,
+    name: &str,
+    state: &PickleValue,
+    compact_refs: bool,
+) -> PyResult<Option<Py<PyAny>>> {
+    container_state_to_pyobject_impl(py, module, name, state, compact_refs, true, 0)
+}
+
+fn container_state_to_pyobject_impl(
+    py: Python<'_>,
+    module: &str,
+    name: &str,
+    state: &PickleValue,
+    compact_refs: bool,
+    sanitize_nulls: bool,
+    depth: usize,
+) -> PyResult<Option<Py<PyAny>>> {
+    let Some((marker, data)) = known_types::container_data(module, name, state) else {
+        return Ok(None);
+    };
+    let data_obj = pickle_value_to_pyobject_impl(py, data, compact_refs, sanitize_nulls, depth + 1)?;
+    let dict = PyDict::new(py);
+    dict.set_item(marker, data_obj)?;
+    Ok(Some(dict.into_any().unbind()))
+}
+
+/// `{"@zdt": iso, "@zdt_raw": state}` for a Zope `DateTime`.
+fn encode_zdt_pyobject(
+    py: Python<'_>,
+    state: &PickleValue,
+    compact_refs: bool,
+    sanitize_nulls: bool,
+    depth: usize,
+) -> PyResult<Option<Py<PyAny>>> {
+    let Some(iso) = known_types::zdt_iso(state) else {
+        return Ok(None);
+    };
+    let raw = match state {
+        PickleValue::Tuple(items) => {
+            let list = PyList::empty(py);
+            for item in items {
+                list.append(pickle_value_to_pyobject_impl(py, item, compact_refs, sanitize_nulls, depth)?)?;
+            }
+            list.into_any().unbind()
+        }
+        _ => pickle_value_to_pyobject_impl(py, state, compact_refs, sanitize_nulls, depth)?,
+    };
+    let dict = PyDict::new(py);
+    dict.set_item(intern!(py, "@zdt"), iso)?;
+    dict.set_item(intern!(py, "@zdt_raw"), raw)?;
+    Ok(Some(dict.into_any().unbind()))
+}
+
+/// Zope `DateTime` from `@zdt` (+ `@zdt_raw`, which wins when present).
+fn decode_zdt_from_pyobject(
+    iso: &Bound<'_, PyAny>,
+    raw: Option<&Bound<'_, PyAny>>,
+    expand_refs: bool,
+) -> PyResult<Option<PickleValue>> {
+    let state = match raw {
+        Some(raw) => match raw.cast::<PyList>() {
+            Ok(list) => PickleValue::Tuple(
+                list.iter()
+                    .map(|item| pyobject_to_pickle_value(&item, expand_refs))
+                    .collect::<PyResult<_>>()?,
+            ),
+            Err(_) => pyobject_to_pickle_value(raw, expand_refs)?,
+        },
+        None => match iso.extract::<String>() {
+            Ok(iso) => known_types::zdt_state_from_iso(&iso)?,
+            Err(_) => return Ok(None),
+        },
+    };
+    Ok(Some(known_types::zdt_instance(state)))
+}
+
 fn encode_uuid_pyobject(
     py: Python<'_>,
     state: &PickleValue,
@@ -677,7 +1218,7 @@ fn encode_uuid_pyobject(
     };
     for (k, v) in pairs {
         if let PickleValue::String(key) = k {
-            if key == "int" {
+            if &**key == "int" {
                 let int_val = match v {
                     PickleValue::Int(i) => *i as u128,
                     PickleValue::BigInt(bi) => {
@@ -890,6 +1431,42 @@ fn format_flat_data_pyobject_impl(
 // Reverse direction: Py<PyAny> → PickleValue
 // ---------------------------------------------------------------------------
 
+/// Convert a Python int, falling back to `BigInt` outside the i64 range.
+pub(crate) fn pyint_to_pickle_value(obj: &Bound<'_, PyAny>) -> PyResult<PickleValue> {
+    if let Ok(i) = obj.extract::<i64>() {
+        return Ok(PickleValue::Int(i));
+    }
+    // int() first: str() of an int subclass (IntEnum) is not its digits
+    let text = obj.py().get_type::<PyInt>().call1((obj,))?.str()?;
+    let bi: num_bigint::BigInt = text
+        .to_str()?
+        .parse()
+        .map_err(|e| CodecError::InvalidData(format!("int conversion: {e}")))?;
+    Ok(PickleValue::BigInt(bi))
+}
+
+/// A Python `str` from the `surrogatepass` bytes of a `SurrogateString`.
+pub(crate) fn surrogate_pystring<'py>(py: Python<'py>, bytes: &[u8]) -> PyResult<Bound<'py, PyString>> {
+    let s = PyBytes::new(py, bytes).call_method1(intern!(py, "decode"), ("utf-8", "surrogatepass"))?;
+    Ok(s.cast_into::<PyString>()?)
+}
+
+/// The UTF-8 bytes of a `str` with lone surrogates, as pickle writes them.
+fn surrogatepass_bytes(s: &Bound<'_, PyString>) -> PyResult<Vec<u8>> {
+    let py = s.py();
+    let b = s.call_method1(intern!(py, "encode"), ("utf-8", "surrogatepass"))?;
+    Ok(b.cast::<PyBytes>()?.as_bytes().to_vec())
+}
+
+/// The value of a Python `str`: a `SurrogateString` when it holds lone
+/// surrogates, which `to_str` rejects.
+pub(crate) fn pystring_to_pickle_value(s: &Bound<'_, PyString>) -> PyResult<PickleValue> {
+    match s.to_str() {
+        Ok(s) => Ok(PickleValue::String(s.into())),
+        Err(_) => Ok(PickleValue::SurrogateString(surrogatepass_bytes(s)?)),
+    }
+}
+
 /// Convert a Python object to a PickleValue AST with marker detection.
 ///
 /// When `expand_refs` is true, compact ZODB persistent refs are expanded inline.
@@ -898,11 +1475,11 @@ pub fn pyobject_to_pickle_value(
     expand_refs: bool,
 ) -> PyResult<PickleValue> {
     // Ordered by frequency in ZODB data: string > dict > int > none > float > list > bool
-    if obj.is_instance_of::<PyString>() {
-        let s: String = obj.extract()?;
-        return Ok(PickleValue::String(s));
+    if let Ok(s) = obj.cast::<PyString>() {
+        return pystring_to_pickle_value(s);
     }
     if obj.is_instance_of::<PyDict>() {
+        let _nesting = NestingGuard::enter()?;
         let dict = obj.cast::<PyDict>()?;
         return pydict_to_pickle_value(dict, expand_refs);
     }
@@ -915,24 +1492,30 @@ pub fn pyobject_to_pickle_value(
         return Ok(PickleValue::Bool(b));
     }
     if obj.is_instance_of::<PyInt>() {
-        let i: i64 = obj.extract()?;
-        return Ok(PickleValue::Int(i));
+        return pyint_to_pickle_value(obj);
     }
     if obj.is_instance_of::<PyFloat>() {
         let f: f64 = obj.extract()?;
         return Ok(PickleValue::Float(f));
     }
     if obj.is_instance_of::<PyList>() {
+        let _nesting = NestingGuard::enter()?;
         let list = obj.cast::<PyList>()?;
         let items: PyResult<Vec<PickleValue>> = list
             .iter()
-            .map(|item| pyobject_to_pickle_value(&item, expand_refs))
+            .enumerate()
+            .map(|(i, item)| {
+                pyobject_to_pickle_value(&item, expand_refs).map_err(at_index(obj.py(), i))
+            })
             .collect();
         return Ok(PickleValue::List(items?));
     }
+    if let Ok(b) = obj.cast::<PyBytes>() {
+        return Ok(PickleValue::Bytes(b.as_bytes().to_vec()));
+    }
     // Fallback: try str() representation
     let s = obj.str()?.to_string();
-    Ok(PickleValue::String(s))
+    Ok(PickleValue::String(s.into()))
 }
 
 /// Convert a PyDict to PickleValue, checking for marker keys.
@@ -968,17 +1551,20 @@ fn pydict_to_pickle_value(
                     if let Some(pv) = try_decode_single_key_marker(py, key, &v, expand_refs)? {
                         return Ok(pv);
                     }
+                    if dangling::skip_key(key)? {
+                        return Ok(PickleValue::Dict(Vec::new()));
+                    }
                 }
                 // Non-marker key, or marker with unrecognized value type
                 return Ok(PickleValue::Dict(vec![(
-                    PickleValue::String(key.to_owned()),
+                    null_strings::key_value(key),
                     pyobject_to_pickle_value(&v, expand_refs)?,
                 )]));
             }
         }
         let k_str: String = k.extract()?;
         return Ok(PickleValue::Dict(vec![(
-            PickleValue::String(k_str),
+            PickleValue::String(k_str.into()),
             pyobject_to_pickle_value(&v, expand_refs)?,
         )]));
     }
@@ -996,17 +1582,15 @@ fn pydict_to_pickle_value(
                     break;
                 }
                 pairs.push((
-                    PickleValue::String(key_str.to_owned()),
+                    PickleValue::String(key_str.into()),
                     pyobject_to_pickle_value(&v, expand_refs)?,
                 ));
                 continue;
             }
         }
         let key: String = k.extract()?;
-        pairs.push((
-            PickleValue::String(key),
-            pyobject_to_pickle_value(&v, expand_refs)?,
-        ));
+        let value = pyobject_to_pickle_value(&v, expand_refs).map_err(at_key(dict.py(), &key))?;
+        pairs.push((PickleValue::String(key.into()), value));
     }
     if !found_marker {
         return Ok(PickleValue::Dict(pairs));
@@ -1027,12 +1611,14 @@ fn pydict_to_pickle_value(
                     } else {
                         pyobject_to_pickle_value(&state_val, expand_refs)?
                     };
+                    let keys = (intern!(py, "@items"), intern!(py, "@appends"));
+                    let (dict_items, list_items) = items_from_pydict(dict, keys, expand_refs)?;
                     return Ok(PickleValue::Instance(Box::new(InstanceData {
                         module,
                         name,
                         state: Box::new(state),
-                        dict_items: None,
-                        list_items: None,
+                        dict_items,
+                        list_items,
                     })));
                 }
                 return Ok(PickleValue::Global { module, name });
@@ -1040,6 +1626,15 @@ fn pydict_to_pickle_value(
         }
     }
 
+    // @inst with @items / @appends — anonymous instance of a dict or list subclass
+    if let Some(state_val) = dict.get_item(intern!(py, "@inst"))? {
+        let state = pyobject_to_pickle_value(&state_val, expand_refs)?;
+        let mut inst = InstanceData::new("", "", state);
+        let keys = (intern!(py, "@items"), intern!(py, "@appends"));
+        (inst.dict_items, inst.list_items) = items_from_pydict(dict, keys, expand_refs)?;
+        return Ok(PickleValue::Instance(Box::new(inst)));
+    }
+
     // Known type markers: @dt (+@tz), @date, @time (+@tz), @td, @dec, @uuid
     if let Some(pv) = try_typed_pydict_to_pickle_value(dict, expand_refs)? {
         return Ok(pv);
@@ -1061,11 +1656,12 @@ fn pydict_to_pickle_value(
     // @b — Bytes
     if let Some(v) = dict.get_item(intern!(py, "@b"))? {
         if let Ok(s) = v.extract::<String>() {
-            let bytes = BASE64
-                .decode(&s)
-                .map_err(|e| CodecError::Json(format!("base64 decode: {e}")))?;
+            let bytes = b64_decode(s)?;
             return Ok(PickleValue::Bytes(bytes));
         }
+        if let Ok(b) = v.cast::<PyBytes>() {
+            return Ok(PickleValue::Bytes(b.as_bytes().to_vec()));
+        }
     }
 
     // @bi — BigInt
@@ -1130,35 +1726,110 @@ fn pydict_to_pickle_value(
     // @pkl — Raw pickle
     if let Some(v) = dict.get_item(intern!(py, "@pkl"))? {
         if let Ok(s) = v.extract::<String>() {
-            let bytes = BASE64
-                .decode(&s)
-                .map_err(|e| CodecError::Json(format!("base64 decode: {e}")))?;
-            return Ok(PickleValue::RawPickle(bytes));
-        }
-    }
-
-    // @reduce — Generic reduce
-    if let Some(v) = dict.get_item(intern!(py, "@reduce"))? {
-        if let Ok(reduce_dict) = v.cast::<PyDict>() {
-            let callable_obj = reduce_dict
-                .get_item(intern!(py, "callable"))?
-                .unwrap_or_else(|| py.None().into_bound(py));
-            let args_obj = reduce_dict
-                .get_item(intern!(py, "args"))?
-                .unwrap_or_else(|| py.None().into_bound(py));
-            let callable = pyobject_to_pickle_value(&callable_obj, expand_refs)?;
-            let args = pyobject_to_pickle_value(&args_obj, expand_refs)?;
-            return Ok(PickleValue::Reduce {
-                callable: Box::new(callable),
-                args: Box::new(args),
-                dict_items: None,
-                list_items: None,
-            });
+            return Ok(raw_pickle::decode_raw_pickle_marker(&s)?);
+        }
+    }
+
+    // @reduce / @newobj — Generic REDUCE or NEWOBJ
+    for newobj in [false, true] {
+        let (marker, _) = reduce_keys(newobj);
+        if let Some(v) = dict.get_item(marker)? {
+            if let Ok(reduce_dict) = v.cast::<PyDict>() {
+                return reduce_from_pydict(reduce_dict, newobj, expand_refs);
+            }
+        }
+    }
+
+    // @newobj_ex — NEWOBJ_EX with keyword arguments
+    if let Some(v) = dict.get_item(intern!(py, "@newobj_ex"))? {
+        if let Ok(newobj_dict) = v.cast::<PyDict>() {
+            return newobj_ex_from_pydict(newobj_dict, expand_refs);
+        }
+    }
+
+    // Fallback: regular dict with string keys
+    plain_dict_to_pickle_value(dict, expand_refs)
+}
+
+/// The `Reduce` of a `@reduce` or `@newobj` value (see `reduce_keys`).
+fn reduce_from_pydict(
+    reduce_dict: &Bound<'_, PyDict>,
+    newobj: bool,
+    expand_refs: bool,
+) -> PyResult<PickleValue> {
+    let py = reduce_dict.py();
+    let (_, callable_key) = reduce_keys(newobj);
+    let part = |key: &str| -> PyResult<Box<PickleValue>> {
+        match reduce_dict.get_item(key)? {
+            Some(obj) => Ok(Box::new(pyobject_to_pickle_value(&obj, expand_refs)?)),
+            None => Ok(Box::new(PickleValue::None)),
+        }
+    };
+    let keys = (intern!(py, "items"), intern!(py, "appends"));
+    let (dict_items, list_items) = items_from_pydict(reduce_dict, keys, expand_refs)?;
+    Ok(PickleValue::Reduce {
+        callable: part(callable_key)?,
+        args: part("args")?,
+        dict_items,
+        list_items,
+        newobj,
+    })
+}
+
+/// The SETITEMS/APPENDS items stored under `keys` (see `set_items_pyobjects`).
+#[allow(clippy::type_complexity)]
+fn items_from_pydict(
+    dict: &Bound<'_, PyDict>,
+    (items_key, appends_key): (&Bound<'_, PyString>, &Bound<'_, PyString>),
+    expand_refs: bool,
+) -> PyResult<(Option<Box<Vec<(PickleValue, PickleValue)>>>, Option<Box<Vec<PickleValue>>>)> {
+    let dict_items = match dict.get_item(items_key)? {
+        Some(v) if v.is_instance_of::<PyList>() => {
+            let list = v.cast::<PyList>()?;
+            let mut pairs = Vec::with_capacity(list.len());
+            for pair_obj in list.iter() {
+                if let Ok(pair_list) = pair_obj.cast::<PyList>() {
+                    if pair_list.len() == 2 {
+                        let k = pyobject_to_pickle_value(&pair_list.get_item(0)?, expand_refs)?;
+                        let v = pyobject_to_pickle_value(&pair_list.get_item(1)?, expand_refs)?;
+                        pairs.push((k, v));
+                    }
+                }
+            }
+            Some(Box::new(pairs))
+        }
+        _ => None,
+    };
+    let list_items = match dict.get_item(appends_key)? {
+        Some(v) if v.is_instance_of::<PyList>() => {
+            let items: PyResult<Vec<PickleValue>> = v
+                .cast::<PyList>()?
+                .iter()
+                .map(|item| pyobject_to_pickle_value(&item, expand_refs))
+                .collect();
+            Some(Box::new(items?))
         }
-    }
+        _ => None,
+    };
+    Ok((dict_items, list_items))
+}
 
-    // Fallback: regular dict with string keys
-    plain_dict_to_pickle_value(dict, expand_refs)
+/// The `NewObjEx` of a `@newobj_ex` value: `{"cls": .., "args": .., "kwargs": ..}`.
+fn newobj_ex_from_pydict(
+    newobj_dict: &Bound<'_, PyDict>,
+    expand_refs: bool,
+) -> PyResult<PickleValue> {
+    let part = |key: &str| -> PyResult<Box<PickleValue>> {
+        match newobj_dict.get_item(key)? {
+            Some(obj) => Ok(Box::new(pyobject_to_pickle_value(&obj, expand_refs)?)),
+            None => Ok(Box::new(PickleValue::None)),
+        }
+    };
+    Ok(PickleValue::NewObjEx {
+        cls: part("cls")?,
+        args: part("args")?,
+        kwargs: part("kwargs")?,
+    })
 }
 
 /// Build a plain dict PickleValue from a PyDict (no marker checking).
@@ -1168,12 +1839,20 @@ fn plain_dict_to_pickle_value(
     expand_refs: bool,
 ) -> PyResult<PickleValue> {
     let mut pairs = Vec::with_capacity(dict.len());
+    let mut bytes_keys = false;
     for (k, v) in dict {
         let key: String = k.extract()?;
-        pairs.push((
-            PickleValue::String(key),
-            pyobject_to_pickle_value(&v, expand_refs)?,
-        ));
+        if key == BYTES_KEYS_MARKER && v.is(PyBool::new(dict.py(), true)) {
+            bytes_keys = true;
+            continue;
+        }
+        if dangling::skip_key(&key)? {
+            continue;
+        }
+        pairs.push((null_strings::key_value(&key), pyobject_to_pickle_value(&v, expand_refs)?));
+    }
+    if bytes_keys {
+        bytes_keys::restore_bytes_keys(&mut pairs);
     }
     Ok(PickleValue::Dict(pairs))
 }
@@ -1198,11 +1877,12 @@ fn try_decode_single_key_marker(
         }
         "@b" => {
             if let Ok(s) = v.extract::<String>() {
-                let bytes = BASE64
-                    .decode(&s)
-                    .map_err(|e| CodecError::Json(format!("base64 decode: {e}")))?;
+                let bytes = b64_decode(s)?;
                 return Ok(Some(PickleValue::Bytes(bytes)));
             }
+            if let Ok(b) = v.cast::<PyBytes>() {
+                return Ok(Some(PickleValue::Bytes(b.as_bytes().to_vec())));
+            }
         }
         "@bi" => {
             if let Ok(s) = v.extract::<String>() {
@@ -1257,10 +1937,22 @@ fn try_decode_single_key_marker(
         }
         "@pkl" => {
             if let Ok(s) = v.extract::<String>() {
-                let bytes = BASE64
-                    .decode(&s)
-                    .map_err(|e| CodecError::Json(format!("base64 decode: {e}")))?;
-                return Ok(Some(PickleValue::RawPickle(bytes)));
+                return Ok(Some(raw_pickle::decode_raw_pickle_marker(&s)?));
+            }
+        }
+        "@su" => {
+            if let Ok(s) = v.extract::<String>() {
+                return Ok(Some(surrogates::unescape(&s)?));
+            }
+        }
+        "@ns" if null_strings::restoring() => {
+            if let Ok(s) = v.extract::<String>() {
+                return Ok(Some(null_strings::unescape(&s)?));
+            }
+        }
+        "@f" => {
+            if let Ok(s) = v.extract::<String>() {
+                return Ok(Some(PickleValue::Float(floats::parse_float_marker(&s)?)));
             }
         }
         "@dt" => {
@@ -1296,6 +1988,7 @@ fn try_decode_single_key_marker(
                         ])),
                         dict_items: None,
                         list_items: None,
+                        newobj: false,
                     }));
                 }
             }
@@ -1307,9 +2000,10 @@ fn try_decode_single_key_marker(
                         module: "decimal".into(),
                         name: "Decimal".into(),
                     }),
-                    args: Box::new(PickleValue::Tuple(vec![PickleValue::String(s)])),
+                    args: Box::new(PickleValue::Tuple(vec![PickleValue::String(s.into())])),
                     dict_items: None,
                     list_items: None,
+                    newobj: false,
                 }));
             }
         }
@@ -1318,6 +2012,80 @@ fn try_decode_single_key_marker(
                 return Ok(Some(decode_uuid_from_str(&s)?));
             }
         }
+        "@complex" => {
+            if let Ok(parts) = v.extract::<Vec<f64>>() {
+                if let [re, im] = parts[..] {
+                    return Ok(Some(known_types::complex_reduce(re, im)));
+                }
+            }
+        }
+        "@frac" => {
+            if let Ok(s) = v.extract::<String>() {
+                return Ok(Some(known_types::fraction_reduce(&s)?));
+            }
+        }
+        "@zdt" => return decode_zdt_from_pyobject(v, None, expand_refs),
+        "@provides" => {
+            if let Ok(names) = v.extract::<Vec<String>>() {
+                return Ok(Some(known_types::provides_reduce(&names)?));
+            }
+        }
+        "@odict" => {
+            if let Ok(list) = v.cast::<PyList>() {
+                let mut pairs = Vec::with_capacity(list.len());
+                for pair in list.iter() {
+                    let pair = pair.cast::<PyList>().ok().filter(|p| p.len() == 2).ok_or_else(|| {
+                        CodecError::InvalidData("@odict must be a list of [key, value] pairs".into())
+                    })?;
+                    pairs.push((
+                        pyobject_to_pickle_value(&pair.get_item(0)?, expand_refs)?,
+                        pyobject_to_pickle_value(&pair.get_item(1)?, expand_refs)?,
+                    ));
+                }
+                return Ok(Some(known_types::odict_reduce(pairs)));
+            }
+        }
+        "@ddict" => {
+            if let Ok(inner) = v.cast::<PyDict>() {
+                let factory = match inner.get_item(intern!(py, "factory"))? {
+                    Some(f) => pyobject_to_pickle_value(&f, expand_refs)?,
+                    None => PickleValue::None,
+                };
+                let pairs = match inner.get_item(intern!(py, "items"))? {
+                    Some(items) => match pyobject_to_pickle_value(&items, expand_refs)? {
+                        PickleValue::Dict(pairs) => pairs,
+                        _ => {
+                            return Err(CodecError::InvalidData(
+                                "@ddict items must be a dict".into(),
+                            )
+                            .into())
+                        }
+                    },
+                    None => vec![],
+                };
+                return Ok(Some(known_types::ddict_reduce(factory, pairs)));
+            }
+        }
+        "@pmap" | "@plist" | "@rel" | "@len" => {
+            let data = pyobject_to_pickle_value(v, expand_refs)?;
+            return Ok(Some(known_types::container_instance(key, data)?));
+        }
+        "@tid" => {
+            if let Ok(list) = v.cast::<PyList>() {
+                if list.len() == 2 || list.len() == 3 {
+                    let hex_str: String = list.get_item(0)?.extract()?;
+                    let module: Option<String> = if list.len() == 3 {
+                        Some(list.get_item(2)?.extract()?)
+                    } else {
+                        None
+                    };
+                    return Ok(Some(known_types::tid_to_pickle_value(
+                        &hex_str,
+                        module.as_deref(),
+                    )?));
+                }
+            }
+        }
         "@cls" => {
             if let Ok(cls_list) = v.cast::<PyList>() {
                 if cls_list.len() == 2 {
@@ -1327,25 +2095,52 @@ fn try_decode_single_key_marker(
                 }
             }
         }
-        "@reduce" => {
+        "@blocked" => {
+            if let Ok(cls_list) = v.cast::<PyList>() {
+                if cls_list.len() == 2 {
+                    let module: String = cls_list.get_item(0)?.extract()?;
+                    let name: String = cls_list.get_item(1)?.extract()?;
+                    return Ok(Some(PickleValue::Blocked { module, name }));
+                }
+            }
+        }
+        "@shared" => {
+            if let Ok(pair) = v.cast::<PyList>() {
+                if pair.len() == 2 {
+                    if let Ok(id) = pair.get_item(0)?.extract::<u32>() {
+                        let value = pyobject_to_pickle_value(&pair.get_item(1)?, expand_refs)?;
+                        return Ok(Some(PickleValue::Shared {
+                            id,
+                            value: Box::new(value),
+                        }));
+                    }
+                }
+            }
+        }
+        "@backref" => {
+            if let Ok(id) = v.extract::<u32>() {
+                return Ok(Some(PickleValue::BackRef(id)));
+            }
+        }
+        "@inst" => {
+            let state = pyobject_to_pickle_value(v, expand_refs)?;
+            return Ok(Some(PickleValue::Instance(Box::new(InstanceData::new("", "", state)))));
+        }
+        "@reduce" | "@newobj" => {
             if let Ok(reduce_dict) = v.cast::<PyDict>() {
-                let callable_obj = reduce_dict
-                    .get_item(intern!(py, "callable"))?
-                    .unwrap_or_else(|| py.None().into_bound(py));
-                let args_obj = reduce_dict
-                    .get_item(intern!(py, "args"))?
-                    .unwrap_or_else(|| py.None().into_bound(py));
-                let callable = pyobject_to_pickle_value(&callable_obj, expand_refs)?;
-                let args = pyobject_to_pickle_value(&args_obj, expand_refs)?;
-                return Ok(Some(PickleValue::Reduce {
-                    callable: Box::new(callable),
-                    args: Box::new(args),
-                    dict_items: None,
-                    list_items: None,
-                }));
+                return reduce_from_pydict(reduce_dict, key == "@newobj", expand_refs).map(Some);
+            }
+        }
+        "@newobj_ex" => {
+            if let Ok(newobj_dict) = v.cast::<PyDict>() {
+                return newobj_ex_from_pydict(newobj_dict, expand_refs).map(Some);
+            }
+        }
+        _ => {
+            if let Some(handler) = registry::for_marker(key) {
+                return Ok(Some(handler.build(pyobject_to_pickle_value(v, expand_refs)?)?));
             }
         }
-        _ => {}
     }
     Ok(None)
 }
@@ -1356,39 +2151,47 @@ fn try_decode_single_key_marker(
 
 /// Expand a compact ZODB persistent ref from Py<PyAny>.
 fn expand_compact_ref(ref_val: &Bound<'_, pyo3::PyAny>) -> PyResult<PickleValue> {
-    // Simple string oid: "0000000000000003"
-    if let Ok(hex_str) = ref_val.extract::<String>() {
-        let oid_bytes = hex::decode(&hex_str)
-            .map_err(|e| CodecError::Json(format!("hex decode: {e}")))?;
+    // Simple oid: "0000000000000003" or 3
+    if let Some(oid_bytes) = ref_oid_from_pyobject(ref_val)? {
         return Ok(PickleValue::PersistentRef(Box::new(PickleValue::Tuple(
             vec![PickleValue::Bytes(oid_bytes), PickleValue::None],
         ))));
     }
 
-    // Array [oid_hex, class_path]
     if let Ok(list) = ref_val.cast::<PyList>() {
+        // Legacy weak ref ["oid_hex"]
+        if list.len() == 1 {
+            if let Some(oid_bytes) = ref_oid_from_pyobject(&list.get_item(0)?)? {
+                return Ok(PickleValue::PersistentRef(Box::new(PickleValue::List(
+                    vec![PickleValue::Bytes(oid_bytes)],
+                ))));
+            }
+        }
+        // Array [oid_hex, class_path]
         if list.len() == 2 {
-            let oid_hex: String = list.get_item(0)?.extract()?;
-            let class_path: String = list.get_item(1)?.extract()?;
-            let oid_bytes = hex::decode(&oid_hex)
-                .map_err(|e| CodecError::Json(format!("hex decode: {e}")))?;
-
-            // Split "module.ClassName" back into module + name
-            let (module, name) = if let Some(dot_pos) = class_path.rfind('.') {
-                (
-                    class_path[..dot_pos].to_string(),
-                    class_path[dot_pos + 1..].to_string(),
-                )
-            } else {
-                (String::new(), class_path)
-            };
+            if let (Some(oid_bytes), Ok(class_path)) = (
+                ref_oid_from_pyobject(&list.get_item(0)?)?,
+                list.get_item(1)?.extract::<String>(),
+            ) {
+                let (module, name) = split_class_path(&class_path);
+
+                return Ok(PickleValue::PersistentRef(Box::new(PickleValue::Tuple(
+                    vec![
+                        PickleValue::Bytes(oid_bytes),
+                        PickleValue::Global {
+                            module: module.to_string(),
+                            name: name.to_string(),
+                        },
+                    ],
+                ))));
+            }
+        }
+    }
 
-            return Ok(PickleValue::PersistentRef(Box::new(PickleValue::Tuple(
-                vec![
-                    PickleValue::Bytes(oid_bytes),
-                    PickleValue::Global { module, name },
-                ],
-            ))));
+    // Extended ref {"oid": oid_hex, "weak": True, "db": name, "cls": class_path}
+    if let Ok(dict) = ref_val.cast::<PyDict>() {
+        if let Some(inner) = expand_extended_ref_dict(dict)? {
+            return Ok(PickleValue::PersistentRef(Box::new(inner)));
         }
     }
 
@@ -1397,6 +2200,43 @@ fn expand_compact_ref(ref_val: &Bound<'_, pyo3::PyAny>) -> PyResult<PickleValue>
     Ok(PickleValue::PersistentRef(Box::new(inner)))
 }
 
+/// The persistent id of an object-form `@ref` value, or `None` when the
+/// dict has other keys or names no ZODB reference form.
+fn expand_extended_ref_dict(dict: &Bound<'_, PyDict>) -> PyResult<Option<PickleValue>> {
+    let py = dict.py();
+    let Some(oid) = dict.get_item(intern!(py, "oid"))? else {
+        return Ok(None);
+    };
+    let Some(oid) = ref_oid_from_pyobject(&oid)? else {
+        return Ok(None);
+    };
+    let mut fields = 1;
+    let weak = match dict.get_item(intern!(py, "weak"))? {
+        None => false,
+        Some(v) if v.is(PyBool::new(py, true)) => {
+            fields += 1;
+            true
+        }
+        Some(_) => return Ok(None),
+    };
+    let mut text = |key: &Bound<'_, PyString>| -> PyResult<Result<Option<String>, ()>> {
+        match dict.get_item(key)? {
+            None => Ok(Ok(None)),
+            Some(v) => {
+                fields += 1;
+                Ok(v.extract::<String>().map(Some).map_err(|_| ()))
+            }
+        }
+    };
+    let (Ok(db), Ok(class_path)) = (text(intern!(py, "db"))?, text(intern!(py, "cls"))?) else {
+        return Ok(None);
+    };
+    if fields != dict.len() {
+        return Ok(None);
+    }
+    Ok(expand_extended_ref(oid, weak, db.as_deref(), class_path.as_deref())?)
+}
+
 // ---------------------------------------------------------------------------
 // Reverse: known type markers → PickleValue
 // ---------------------------------------------------------------------------
@@ -1449,11 +2289,20 @@ fn try_typed_pydict_to_pickle_value(
                     ])),
                     dict_items: None,
                     list_items: None,
+                    newobj: false,
                 }));
             }
         }
     }
 
+    // @zdt (+@zdt_raw) — Zope DateTime
+    if let Some(v) = dict.get_item(intern!(py, "@zdt"))? {
+        let raw = dict.get_item(intern!(py, "@zdt_raw"))?;
+        if let Some(pv) = decode_zdt_from_pyobject(&v, raw.as_ref(), expand_refs)? {
+            return Ok(Some(pv));
+        }
+    }
+
     // @dec — Decimal
     if let Some(v) = dict.get_item(intern!(py, "@dec"))? {
         if let Ok(s) = v.extract::<String>() {
@@ -1462,9 +2311,10 @@ fn try_typed_pydict_to_pickle_value(
                     module: "decimal".into(),
                     name: "Decimal".into(),
                 }),
-                args: Box::new(PickleValue::Tuple(vec![PickleValue::String(s)])),
+                args: Box::new(PickleValue::Tuple(vec![PickleValue::String(s.into())])),
                 dict_items: None,
                 list_items: None,
+                newobj: false,
             }));
         }
     }
@@ -1492,11 +2342,7 @@ fn decode_datetime_from_pyobject(
 
     let tz_pickle = if let Some(tz_val) = tz_obj {
         Some(decode_tz_from_pyobject(tz_val)?)
-    } else if let Some(offset_secs) = offset_part {
-        Some(known_types::make_stdlib_timezone(offset_secs))
-    } else {
-        None
-    };
+    } else { offset_part.map(known_types::make_stdlib_timezone) };
 
     let args = if let Some(tz) = tz_pickle {
         PickleValue::Tuple(vec![dt_bytes, tz])
@@ -1512,6 +2358,7 @@ fn decode_datetime_from_pyobject(
         args: Box::new(args),
         dict_items: None,
         list_items: None,
+        newobj: false,
     })
 }
 
@@ -1537,6 +2384,7 @@ fn decode_date_from_str(s: &str) -> PyResult<PickleValue> {
         args: Box::new(PickleValue::Tuple(vec![PickleValue::Bytes(bytes)])),
         dict_items: None,
         list_items: None,
+        newobj: false,
     })
 }
 
@@ -1558,11 +2406,7 @@ fn decode_time_from_pyobject(
 
     let tz_pickle = if let Some(tz_val) = tz_obj {
         Some(decode_tz_from_pyobject(tz_val)?)
-    } else if let Some(offset_secs) = offset_part {
-        Some(known_types::make_stdlib_timezone(offset_secs))
-    } else {
-        None
-    };
+    } else { offset_part.map(known_types::make_stdlib_timezone) };
 
     let args = if let Some(tz) = tz_pickle {
         PickleValue::Tuple(vec![PickleValue::Bytes(bytes), tz])
@@ -1578,6 +2422,7 @@ fn decode_time_from_pyobject(
         args: Box::new(args),
         dict_items: None,
         list_items: None,
+        newobj: false,
     })
 }
 
@@ -1616,7 +2461,7 @@ fn decode_tz_from_pyobject(tz_val: &Bound<'_, pyo3::PyAny>) -> PyResult<PickleVa
                     .iter()
                     .map(|a| {
                         if let Ok(s) = a.extract::<String>() {
-                            Ok(PickleValue::String(s))
+                            Ok(PickleValue::String(s.into()))
                         } else if let Ok(i) = a.extract::<i64>() {
                             Ok(PickleValue::Int(i))
                         } else {
@@ -1632,6 +2477,7 @@ fn decode_tz_from_pyobject(tz_val: &Bound<'_, pyo3::PyAny>) -> PyResult<PickleVa
                     args: Box::new(PickleValue::Tuple(pickle_args?)),
                     dict_items: None,
                     list_items: None,
+                    newobj: false,
                 });
             }
         }
@@ -1653,15 +2499,17 @@ fn decode_tz_from_pyobject(tz_val: &Bound<'_, pyo3::PyAny>) -> PyResult<PickleVa
                     ])),
                     dict_items: None,
                     list_items: None,
+                    newobj: false,
                 };
                 return Ok(PickleValue::Reduce {
                     callable: Box::new(inner_reduce),
                     args: Box::new(PickleValue::Tuple(vec![
-                        PickleValue::String(key),
+                        PickleValue::String(key.into()),
                         PickleValue::Int(1),
                     ])),
                     dict_items: None,
                     list_items: None,
+                    newobj: false,
                 });
             }
         }
@@ -1725,7 +2573,9 @@ pub fn btree_state_from_pyobject(
                 .iter()
                 .map(|item| pyobject_to_pickle_value(&item, expand_refs))
                 .collect();
-            let children_tuple = PickleValue::Tuple(children?);
+            let children = children?;
+            btrees::validate_children(info, &children)?;
+            let children_tuple = PickleValue::Tuple(children);
             let firstbucket = pyobject_to_pickle_value(&first_val, expand_refs)?;
             return Ok(PickleValue::Tuple(vec![children_tuple, firstbucket]));
         }
@@ -1766,16 +2616,26 @@ fn decode_keys_from_pyobject(
 // Direct encoder: Py<PyAny> → pickle bytes (bypasses PickleValue allocation)
 // ---------------------------------------------------------------------------
 
-/// Encode a Py<PyAny> directly to pickle bytes with PROTO 2 framing.
-/// Used by `dict_to_pickle`.
+/// Encode a Py<PyAny> directly to pickle bytes with PROTO 3 framing,
+/// the protocol of the bytes opcodes it writes. Used by `dict_to_pickle`.
 pub fn encode_pyobject_as_pickle(
     obj: &Bound<'_, pyo3::PyAny>,
     expand_refs: bool,
 ) -> PyResult<Vec<u8>> {
+    let shared = SharedIdsScope::enter();
     let mut buf = Vec::with_capacity(256);
     buf.push(PROTO);
-    buf.push(2);
-    encode_pyobject_to_pickle(obj, &mut buf, expand_refs)?;
+    buf.push(3);
+    match encode_pyobject_to_pickle(obj, &mut buf, expand_refs) {
+        // A @backref before its @shared node, as in a dict from json.loads
+        // with sorted keys: encode the whole tree, which reorders them
+        Err(_) if shared.unresolved_backref() => {
+            drop(shared);
+            let val = pyobject_to_pickle_value(obj, expand_refs)?;
+            return Ok(encode_pickle(&val)?);
+        }
+        result => result?,
+    }
     buf.push(STOP);
     Ok(buf)
 }
@@ -1795,56 +2655,187 @@ thread_local! {
         const { std::cell::RefCell::new(Vec::new()) };
 }
 
-/// Build the class pickle bytes for a ZODB record: PROTO 2 + ((module, name), None) + STOP.
-/// This is the format produced by ZODB's PersistentPickler and expected
-/// by ZODB's standard unpickling (ObjectReader and zodb_unpickle).
-pub(crate) fn build_class_pickle(module: &str, name: &str) -> Vec<u8> {
-    let cap = 8 + (5 + module.len()) + (5 + name.len());
-    let mut buf = Vec::with_capacity(cap);
-    buf.extend_from_slice(&[PROTO, 2]);
-    write_string(&mut buf, module);
-    write_string(&mut buf, name);
-    buf.push(TUPLE2); // inner tuple: (module, name)
-    buf.push(NONE);
-    buf.push(TUPLE2); // outer tuple: ((module, name), None)
-    buf.push(STOP);
-    buf
-}
-
+/// Encode a ZODB record directly from the Python state. `class_pickle`
+/// is copied as-is; without it the class pickle is built from `module`
+/// and `name`, which also select the BTree and container state forms.
 pub fn encode_zodb_record_direct(
     module: &str,
     name: &str,
     state_obj: &Bound<'_, pyo3::PyAny>,
+    class_pickle: Option<&[u8]>,
 ) -> PyResult<Vec<u8>> {
-    ENCODE_BUF.with(|cell| {
+    let shared = SharedIdsScope::enter();
+    let result = ENCODE_BUF.with(|cell| {
         let mut buf = cell.borrow_mut();
         buf.clear(); // keep capacity from previous calls
 
         let btree_info = btrees::classify_btree(module, name);
 
-        // Class pickle: use cached bytes (identical for all records of same class)
-        CLASS_PICKLE_CACHE.with(|cache_cell| {
-            let mut cache = cache_cell.borrow_mut();
-            if let Some((_, _, bytes)) = cache.iter().find(|(m, n, _)| m == module && n == name) {
-                buf.extend_from_slice(bytes);
-            } else {
-                let bytes = build_class_pickle(module, name);
-                buf.extend_from_slice(&bytes);
-                cache.push((module.to_string(), name.to_string(), bytes));
-            }
-        });
+        // Class pickle: use cached bytes (identical for all records of same class),
+        // unless renames, which may change between calls, apply
+        if let Some(class_pickle) = class_pickle {
+            buf.extend_from_slice(class_pickle);
+        } else if rename::encoding() {
+            buf.extend_from_slice(&build_class_pickle(module, name));
+        } else {
+            CLASS_PICKLE_CACHE.with(|cache_cell| {
+                let mut cache = cache_cell.borrow_mut();
+                let cached = cache.iter().find(|(m, n, _)| m == module && n == name);
+                if let Some((_, _, bytes)) = cached {
+                    buf.extend_from_slice(bytes);
+                } else {
+                    let bytes = build_class_pickle(module, name);
+                    buf.extend_from_slice(&bytes);
+                    cache.push((module.to_string(), name.to_string(), bytes));
+                }
+            });
+        }
+
+        // A state kept as @pkl by lenient decoding is the state pickle itself
+        let raw_state = raw_state_from_pyobject(state_obj);
+        if let Some(data) = raw_state.map_err(at_key(state_obj.py(), "@s"))? {
+            buf.extend_from_slice(&data);
+            return Ok(buf.to_vec());
+        }
 
         // State pickle: PROTO 2 + state opcodes + STOP
         buf.extend_from_slice(&[PROTO, 2]);
-        if let Some(info) = btree_info {
-            encode_btree_state_to_pickle(&info, state_obj, &mut buf, true)?;
-        } else {
-            encode_pyobject_to_pickle(state_obj, &mut buf, true)?;
-        }
+        let encode_state = || -> PyResult<()> {
+            if let Some(info) = btree_info {
+                encode_btree_state_to_pickle(&info, state_obj, &mut buf, true)?;
+            } else if let Some(data) = container_data_from_pyobject(module, name, state_obj)? {
+                if matches!(known_types::container_marker(module, name), Some("@rel" | "@len")) {
+                    // The relation's attribute dict or the counter value is the state
+                    encode_pyobject_to_pickle(&data, &mut buf, true)?;
+                } else {
+                    // {'data': ...}
+                    buf.push(EMPTY_DICT);
+                    write_string(&mut buf, "data");
+                    encode_pyobject_to_pickle(&data, &mut buf, true)?;
+                    buf.push(SETITEM);
+                }
+            } else {
+                encode_pyobject_to_pickle(state_obj, &mut buf, true)?;
+            }
+            Ok(())
+        };
+        encode_state().map_err(at_key(state_obj.py(), "@s"))?;
         buf.push(STOP);
 
         Ok(buf.to_vec())
-    })
+    });
+    match result {
+        // A @backref before its @shared node: encode the state tree instead
+        Err(_) if shared.unresolved_backref() => {
+            drop(shared);
+            let state = record_state_from_pyobject(module, name, state_obj)
+                .map_err(at_key(state_obj.py(), "@s"))?;
+            Ok(batch::encode_record(&batch::RecordToEncode {
+                module: module.to_string(),
+                name: name.to_string(),
+                state,
+                class_pickle: class_pickle.map(<[u8]>::to_vec),
+            })?)
+        }
+        result => result,
+    }
+}
+
+/// Convert the `@s` value of a ZODB JSON record back to a state tree,
+/// with the BTree and container state forms of `module`.`name`.
+pub fn record_state_from_pyobject(
+    module: &str,
+    name: &str,
+    state_obj: &Bound<'_, pyo3::PyAny>,
+) -> PyResult<PickleValue> {
+    if let Some(data) = raw_state_from_pyobject(state_obj)? {
+        return Ok(PickleValue::RawPickle(data));
+    }
+    match btrees::classify_btree(module, name) {
+        Some(info) => btree_state_from_pyobject(&info, state_obj, true),
+        None => match container_state_from_pyobject(module, name, state_obj, true)? {
+            Some(state) => Ok(state),
+            None => pyobject_to_pickle_value(state_obj, true),
+        },
+    }
+}
+
+/// The pickle of a record state that is a single `@pkl` marker, as
+/// lenient decoding writes for a state pickle it cannot decode.
+fn raw_state_from_pyobject(state_obj: &Bound<'_, pyo3::PyAny>) -> PyResult<Option<Vec<u8>>> {
+    let Ok(dict) = state_obj.cast::<PyDict>() else {
+        return Ok(None);
+    };
+    if dict.len() != 1 {
+        return Ok(None);
+    }
+    match dict.get_item(intern!(state_obj.py(), "@pkl"))? {
+        Some(v) => match v.extract::<&str>() {
+            Ok(b64) => Ok(Some(raw_pickle::decode_raw_pickle(b64)?)),
+            Err(_) => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The value of the `@pmap`/`@plist`/`@rel`/`@len` marker of a
+/// PersistentMapping, PersistentList, RelationValue or Length record state,
+/// or `None` if the class or the state doesn't match.
+fn container_data_from_pyobject<'py>(
+    module: &str,
+    name: &str,
+    state_obj: &Bound<'py, pyo3::PyAny>,
+) -> PyResult<Option<Bound<'py, pyo3::PyAny>>> {
+    let Some(marker) = known_types::container_marker(module, name) else {
+        return Ok(None);
+    };
+    let Ok(dict) = state_obj.cast::<PyDict>() else {
+        return Ok(None);
+    };
+    if dict.len() != 1 {
+        return Ok(None);
+    }
+    let Some(data) = dict.get_item(marker)? else {
+        return Ok(None);
+    };
+    let (shaped, kind) = match marker {
+        "@plist" => (data.is_instance_of::<PyList>(), "a list"),
+        "@len" => (
+            data.is_instance_of::<PyInt>() && !data.is_instance_of::<PyBool>(),
+            "an int",
+        ),
+        _ => (data.is_instance_of::<PyDict>(), "a dict"),
+    };
+    if !shaped {
+        return Err(CodecError::InvalidData(format!("{marker} must hold {kind}")).into());
+    }
+    Ok(Some(data))
+}
+
+/// Convert the `@pmap`/`@plist`/`@rel`/`@len` state of a PersistentMapping,
+/// PersistentList, RelationValue or Length record back to its state.
+pub fn container_state_from_pyobject(
+    module: &str,
+    name: &str,
+    state_obj: &Bound<'_, pyo3::PyAny>,
+    expand_refs: bool,
+) -> PyResult<Option<PickleValue>> {
+    let Some(data) = container_data_from_pyobject(module, name, state_obj)? else {
+        return Ok(None);
+    };
+    let marker = known_types::container_marker(module, name).unwrap_or_default();
+    let data = pyobject_to_pickle_value(&data, expand_refs)?;
+    Ok(Some(known_types::container_state(marker, data)?))
+}
+
+/// Error mapper adding the dict key `key` to the path of a `CodecError`.
+fn at_key<'a>(py: Python<'a>, key: &'a str) -> impl FnOnce(PyErr) -> PyErr + 'a {
+    move |e| error::py::in_path(py, e, PathSegment::Key(key))
+}
+
+/// Error mapper adding the list index `i` to the path of a `CodecError`.
+fn at_index(py: Python<'_>, i: usize) -> impl FnOnce(PyErr) -> PyErr + '_ {
+    move |e| error::py::in_path(py, e, PathSegment::Index(i))
 }
 
 /// Write a Py<PyAny> as pickle opcodes into the buffer (no PROTO/STOP framing).
@@ -1854,15 +2845,19 @@ pub fn encode_pyobject_to_pickle(
     buf: &mut Vec<u8>,
     expand_refs: bool,
 ) -> PyResult<()> {
+    EncodeLimits::current().check_output(buf.len())?;
     // String: borrow &str from Python, write directly (zero-copy)
-    if obj.is_instance_of::<PyString>() {
-        let s = obj.cast::<PyString>()?.to_str()?;
-        write_string(buf, s);
+    if let Ok(s) = obj.cast::<PyString>() {
+        match s.to_str() {
+            Ok(s) => write_string(buf, s),
+            Err(_) => write_text(buf, &surrogatepass_bytes(s)?),
+        }
         return Ok(());
     }
 
     // Dict: handle markers or write plain dict
     if obj.is_instance_of::<PyDict>() {
+        let _nesting = NestingGuard::enter()?;
         let dict = obj.cast::<PyDict>()?;
         return encode_pydict_to_pickle(dict, buf, expand_refs);
     }
@@ -1885,8 +2880,11 @@ pub fn encode_pyobject_to_pickle(
 
     // Int
     if obj.is_instance_of::<PyInt>() {
-        let i: i64 = obj.extract()?;
-        write_int(buf, i);
+        if let Ok(i) = obj.extract::<i64>() {
+            write_int(buf, i);
+        } else {
+            encode_value_into(&pyint_to_pickle_value(obj)?, buf)?;
+        }
         return Ok(());
     }
 
@@ -1900,17 +2898,23 @@ pub fn encode_pyobject_to_pickle(
 
     // List
     if obj.is_instance_of::<PyList>() {
+        let _nesting = NestingGuard::enter()?;
         let list = obj.cast::<PyList>()?;
+        EncodeLimits::current().check_collection(list.len())?;
         buf.push(EMPTY_LIST);
         if !list.is_empty() {
             buf.push(MARK);
-            for item in list.iter() {
-                encode_pyobject_to_pickle(&item, buf, expand_refs)?;
+            for (i, item) in list.iter().enumerate() {
+                encode_pyobject_to_pickle(&item, buf, expand_refs).map_err(at_index(obj.py(), i))?;
             }
             buf.push(APPENDS);
         }
         return Ok(());
     }
+    if let Ok(b) = obj.cast::<PyBytes>() {
+        write_bytes_val(buf, b.as_bytes());
+        return Ok(());
+    }
 
     // Fallback: convert to PickleValue first, then encode
     let pv = pyobject_to_pickle_value(obj, expand_refs)?;
@@ -1925,6 +2929,7 @@ fn encode_pydict_to_pickle(
     expand_refs: bool,
 ) -> PyResult<()> {
     let len = dict.len();
+    EncodeLimits::current().check_collection(len)?;
 
     // Fast path: no marker dict has more than 4 keys
     if len > 4 {
@@ -1941,16 +2946,19 @@ fn encode_pydict_to_pickle(
         let (k, v) = dict.iter().next().unwrap();
         if let Ok(s) = k.cast::<PyString>() {
             if let Ok(key) = s.to_str() {
-                if key.starts_with('@') {
-                    if try_encode_marker_to_pickle(key, &v, buf, expand_refs)? {
+                if dangling::skip_key(key)? {
+                    buf.push(EMPTY_DICT);
+                    return Ok(());
+                }
+                if key.starts_with('@')
+                    && try_encode_marker_to_pickle(key, &v, buf, expand_refs)? {
                         return Ok(());
                     }
-                }
                 // Non-marker single key or unrecognized marker value
                 buf.push(EMPTY_DICT);
                 buf.push(MARK);
                 write_string(buf, key);
-                encode_pyobject_to_pickle(&v, buf, expand_refs)?;
+                encode_pyobject_to_pickle(&v, buf, expand_refs).map_err(at_key(dict.py(), key))?;
                 buf.push(SETITEMS);
                 return Ok(());
             }
@@ -1978,16 +2986,26 @@ fn encode_pydict_to_pickle(
                     let name = name_py.to_str()?;
 
                     if let Some(state_val) = dict.get_item(intern!(py, "@s"))? {
+                        if len > 2
+                            && (dict.contains(intern!(py, "@items"))?
+                                || dict.contains(intern!(py, "@appends"))?)
+                        {
+                            // Dict/list subclass: SETITEMS/APPENDS after BUILD
+                            let pv = pydict_to_pickle_value(dict, expand_refs)?;
+                            encode_value_into(&pv, buf)?;
+                            return Ok(());
+                        }
                         // Instance: GLOBAL module\nname\n EMPTY_TUPLE NEWOBJ state BUILD
                         write_global(buf, module, name);
                         buf.push(EMPTY_TUPLE);
                         buf.push(NEWOBJ);
 
                         if let Some(info) = btrees::classify_btree(module, name) {
-                            encode_btree_state_to_pickle(&info, &state_val, buf, expand_refs)?;
+                            encode_btree_state_to_pickle(&info, &state_val, buf, expand_refs)
                         } else {
-                            encode_pyobject_to_pickle(&state_val, buf, expand_refs)?;
+                            encode_pyobject_to_pickle(&state_val, buf, expand_refs)
                         }
+                        .map_err(at_key(py, "@s"))?;
                         buf.push(BUILD);
                         return Ok(());
                     }
@@ -2003,10 +3021,18 @@ fn encode_pydict_to_pickle(
         return Ok(());
     }
 
-    // Check for 2-key @dt+@tz (datetime with named timezone).
-    // Only @dt — the most common multi-key typed marker — to minimize overhead
-    // on the hot path for plain 2-key dicts. Other multi-key typed markers
-    // (@time+@tz) are extremely rare in ZODB data.
+    // @inst with @items / @appends: anonymous instance of a dict or list subclass
+    if dict.contains(intern!(py, "@inst"))? {
+        let pv = pydict_to_pickle_value(dict, expand_refs)?;
+        encode_value_into(&pv, buf)?;
+        return Ok(());
+    }
+
+    // Check for 2-key @dt+@tz (datetime with named timezone) and
+    // @zdt+@zdt_raw (Zope DateTime, on nearly every Plone object).
+    // Only these two to minimize overhead on the hot path for plain 2-key
+    // dicts. Other multi-key typed markers (@time+@tz) are extremely rare
+    // in ZODB data.
     if len == 2 {
         if let Some(dt_val) = dict.get_item(intern!(py, "@dt"))? {
             if let Ok(iso) = dt_val.extract::<String>() {
@@ -2016,6 +3042,14 @@ fn encode_pydict_to_pickle(
                 return Ok(());
             }
         }
+        if let Some(raw) = dict.get_item(intern!(py, "@zdt_raw"))? {
+            if let Some(iso) = dict.get_item(intern!(py, "@zdt"))? {
+                if let Some(pv) = decode_zdt_from_pyobject(&iso, Some(&raw), expand_refs)? {
+                    encode_value_into(&pv, buf)?;
+                    return Ok(());
+                }
+            }
+        }
     }
 
     // No @cls, no typed marker → plain dict (most common case for nested non-marker dicts)
@@ -2029,6 +3063,7 @@ fn encode_plain_dict_to_pickle(
     buf: &mut Vec<u8>,
     expand_refs: bool,
 ) -> PyResult<()> {
+    let start = buf.len();
     buf.push(EMPTY_DICT);
     if !dict.is_empty() {
         buf.push(MARK);
@@ -2036,8 +3071,18 @@ fn encode_plain_dict_to_pickle(
             // Optimistically assume string keys (>99% in ZODB)
             if let Ok(s) = k.cast::<PyString>() {
                 if let Ok(key_str) = s.to_str() {
-                    write_string(buf, key_str);
-                    encode_pyobject_to_pickle(&v, buf, expand_refs)?;
+                    if key_str == BYTES_KEYS_MARKER {
+                        // Promoted byte keys: redo this dict via PickleValue
+                        buf.truncate(start);
+                        let pv = plain_dict_to_pickle_value(dict, expand_refs)?;
+                        return encode_value_into(&pv, buf).map_err(Into::into);
+                    }
+                    if dangling::skip_key(key_str)? {
+                        continue;
+                    }
+                    write_dict_key(buf, key_str);
+                    encode_pyobject_to_pickle(&v, buf, expand_refs)
+                        .map_err(at_key(dict.py(), key_str))?;
                     continue;
                 }
             }
@@ -2051,6 +3096,15 @@ fn encode_plain_dict_to_pickle(
     Ok(())
 }
 
+/// Write a string dict key, restoring null bytes (see `null_strings.rs`).
+#[inline]
+fn write_dict_key(buf: &mut Vec<u8>, key: &str) {
+    match null_strings::unescape_key(key) {
+        Some(text) => write_string(buf, &text),
+        None => write_string(buf, key),
+    }
+}
+
 /// Write a fixed-offset timezone as inline REDUCE opcodes.
 /// After this, the timezone object is on the pickle stack.
 /// Pattern: GLOBAL datetime.timezone( GLOBAL datetime.timedelta(0, offset, 0) )
@@ -2078,36 +3132,24 @@ fn try_encode_marker_to_pickle(
     match key {
         "@ref" => {
             if expand_refs {
-                // Expand compact hex ref → PersistentRef(Tuple([Bytes(oid), None/Global]))
-                if let Ok(s) = v.cast::<PyString>() {
-                    if let Ok(hex_str) = s.to_str() {
-                        let oid = hex::decode(hex_str)
-                            .map_err(|e| CodecError::Json(format!("hex decode: {e}")))?;
-                        write_bytes_val(buf, &oid);
-                        buf.push(NONE);
-                        buf.push(TUPLE2);
-                        buf.push(BINPERSID);
-                        return Ok(true);
-                    }
+                // Expand compact ref → PersistentRef(Tuple([Bytes(oid), None/Global]))
+                if let Some(oid) = ref_oid_from_pyobject(v)? {
+                    write_bytes_val(buf, &oid);
+                    buf.push(NONE);
+                    buf.push(TUPLE2);
+                    buf.push(BINPERSID);
+                    return Ok(true);
                 }
                 if let Ok(list) = v.cast::<PyList>() {
                     if list.len() == 2 {
                         let item0 = list.get_item(0)?;
                         let item1 = list.get_item(1)?;
-                        if let (Ok(oid_py), Ok(cls_py)) = (
-                            item0.cast::<PyString>(),
-                            item1.cast::<PyString>(),
-                        ) {
-                            let oid_str = oid_py.to_str()?;
+                        if let (Ok(cls_py), Some(oid)) =
+                            (item1.cast::<PyString>(), ref_oid_from_pyobject(&item0)?)
+                        {
                             let cls_str = cls_py.to_str()?;
-                            let oid = hex::decode(oid_str)
-                                .map_err(|e| CodecError::Json(format!("hex decode: {e}")))?;
                             write_bytes_val(buf, &oid);
-                            let (module, name) = if let Some(dot) = cls_str.rfind('.') {
-                                (&cls_str[..dot], &cls_str[dot + 1..])
-                            } else {
-                                ("", cls_str)
-                            };
+                            let (module, name) = split_class_path(cls_str);
                             write_global(buf, module, name);
                             buf.push(TUPLE2);
                             buf.push(BINPERSID);
@@ -2129,31 +3171,22 @@ fn try_encode_marker_to_pickle(
         "@t" => {
             if let Ok(list) = v.cast::<PyList>() {
                 let n = list.len();
-                match n {
-                    0 => buf.push(EMPTY_TUPLE),
-                    1 => {
-                        encode_pyobject_to_pickle(&list.get_item(0)?, buf, expand_refs)?;
-                        buf.push(TUPLE1);
-                    }
-                    2 => {
-                        encode_pyobject_to_pickle(&list.get_item(0)?, buf, expand_refs)?;
-                        encode_pyobject_to_pickle(&list.get_item(1)?, buf, expand_refs)?;
-                        buf.push(TUPLE2);
-                    }
-                    3 => {
-                        encode_pyobject_to_pickle(&list.get_item(0)?, buf, expand_refs)?;
-                        encode_pyobject_to_pickle(&list.get_item(1)?, buf, expand_refs)?;
-                        encode_pyobject_to_pickle(&list.get_item(2)?, buf, expand_refs)?;
-                        buf.push(TUPLE3);
-                    }
-                    _ => {
-                        buf.push(MARK);
-                        for item in list.iter() {
-                            encode_pyobject_to_pickle(&item, buf, expand_refs)?;
-                        }
-                        buf.push(TUPLE);
-                    }
+                EncodeLimits::current().check_collection(n)?;
+                if n > 3 {
+                    buf.push(MARK);
+                }
+                for (i, item) in list.iter().enumerate() {
+                    encode_pyobject_to_pickle(&item, buf, expand_refs)
+                        .map_err(at_index(v.py(), i))
+                        .map_err(at_key(v.py(), "@t"))?;
                 }
+                buf.push(match n {
+                    0 => EMPTY_TUPLE,
+                    1 => TUPLE1,
+                    2 => TUPLE2,
+                    3 => TUPLE3,
+                    _ => TUPLE,
+                });
                 return Ok(true);
             }
             Ok(false)
@@ -2161,16 +3194,27 @@ fn try_encode_marker_to_pickle(
         "@b" => {
             if let Ok(s) = v.cast::<PyString>() {
                 if let Ok(b64_str) = s.to_str() {
-                    let bytes = BASE64
-                        .decode(b64_str)
-                        .map_err(|e| CodecError::Json(format!("base64 decode: {e}")))?;
+                    let bytes = b64_decode(b64_str)?;
                     write_bytes_val(buf, &bytes);
                     return Ok(true);
                 }
             }
+            if let Ok(b) = v.cast::<PyBytes>() {
+                write_bytes_val(buf, b.as_bytes());
+                return Ok(true);
+            }
             Ok(false)
         }
-        "@cls" => {
+        "@shared" | "@backref" => {
+            // Through PickleValue: memo indices are shared across the
+            // fallback calls of one SharedIdsScope
+            let Some(pv) = try_decode_single_key_marker(v.py(), key, v, expand_refs)? else {
+                return Ok(false);
+            };
+            encode_value_into(&pv, buf)?;
+            Ok(true)
+        }
+        "@cls" | "@blocked" => {
             if let Ok(cls_list) = v.cast::<PyList>() {
                 if cls_list.len() == 2 {
                     let item0 = cls_list.get_item(0)?;
@@ -2299,8 +3343,9 @@ fn try_encode_marker_to_pickle(
             Ok(false)
         }
         _ => {
-            // Remaining single-key markers (@uuid, @pkl, @reduce, @bi, @d,
-            // @set, @fset, @inst): fall back to PickleValue conversion + encode
+            // Remaining single-key markers (@uuid, @pkl, @su, @f, @reduce, @bi,
+            // @d, @set, @fset, @inst): fall back to PickleValue conversion +
+            // encode
             let py = v.py();
             let pv =
                 if let Some(pv) = try_decode_single_key_marker(py, key, v, expand_refs)? {
@@ -2308,7 +3353,7 @@ fn try_encode_marker_to_pickle(
                 } else {
                     // Unrecognized marker: encode as plain dict
                     let val_pv = pyobject_to_pickle_value(v, expand_refs)?;
-                    PickleValue::Dict(vec![(PickleValue::String(key.to_owned()), val_pv)])
+                    PickleValue::Dict(vec![(null_strings::key_value(key), val_pv)])
                 };
             encode_value_into(&pv, buf)?;
             Ok(true)
@@ -2332,7 +3377,7 @@ pub fn encode_btree_state_to_pickle(
     let dict = match state_obj.cast::<PyDict>() {
         Ok(d) => d,
         Err(_) => {
-            // Not a dict — generic encode (e.g., scalar for BTrees.Length)
+            // Not a dict — generic encode
             encode_pyobject_to_pickle(state_obj, buf, expand_refs)?;
             return Ok(());
         }
@@ -2345,6 +3390,7 @@ pub fn encode_btree_state_to_pickle(
         if let Ok(kv_list) = kv_val.cast::<PyList>() {
             let next_val = dict.get_item(intern!(py, "@next"))?;
 
+            check_btree_kv_types(info, kv_list)?;
             // Write flat data tuple: MARK k1 v1 k2 v2 ... TUPLE (or TUPLE2 etc.)
             encode_flat_kv_tuple(kv_list, buf, expand_refs)?;
 
@@ -2375,6 +3421,7 @@ pub fn encode_btree_state_to_pickle(
         if let Ok(ks_list) = ks_val.cast::<PyList>() {
             let next_val = dict.get_item(intern!(py, "@next"))?;
 
+            check_btree_key_types(info, ks_list, "@ks", 0, 1)?;
             encode_flat_keys_tuple(ks_list, buf, expand_refs)?;
 
             match info.kind {
@@ -2403,6 +3450,8 @@ pub fn encode_btree_state_to_pickle(
             .ok_or_else(|| CodecError::InvalidData("@children without @first".into()))?;
         if let Ok(children_list) = children_val.cast::<PyList>() {
             let n = children_list.len();
+            EncodeLimits::current().check_collection(n)?;
+            check_btree_key_types(info, children_list, "@children", 1, 2)?;
             match n {
                 0 => buf.push(EMPTY_TUPLE),
                 1 => {
@@ -2441,12 +3490,61 @@ pub fn encode_btree_state_to_pickle(
 }
 
 /// Write @kv pairs as a flat tuple: MARK k1 v1 k2 v2 ... TUPLE
+/// Check typed BTree `@kv` pairs (e.g. `IIBucket`) before encoding them
+/// directly; see [`btrees::validate_flat_data`].
+fn check_btree_kv_types(info: &btrees::BTreeClassInfo, kv_list: &Bound<'_, PyList>) -> PyResult<()> {
+    let check_keys = btrees::is_typed(info.key_type);
+    let check_values = btrees::is_typed(info.value_type);
+    if !check_keys && !check_values {
+        return Ok(());
+    }
+    for (i, pair_obj) in kv_list.iter().enumerate() {
+        let Ok(pair) = pair_obj.cast::<PyList>() else {
+            continue;
+        };
+        if pair.len() != 2 {
+            continue;
+        }
+        if check_keys {
+            let key = pyobject_to_pickle_value(&pair.get_item(0)?, false)?;
+            btrees::check_typed_item(info.key_type, "key", &key, &|| format!("@kv[{i}][0]"))?;
+        }
+        if check_values {
+            let value = pyobject_to_pickle_value(&pair.get_item(1)?, false)?;
+            btrees::check_typed_item(info.value_type, "value", &value, &|| {
+                format!("@kv[{i}][1]")
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Check typed BTree keys in `list[start::step]` before encoding them
+/// directly: set keys (`@ks`) or the separator keys of `@children`.
+fn check_btree_key_types(
+    info: &btrees::BTreeClassInfo,
+    list: &Bound<'_, PyList>,
+    marker: &str,
+    start: usize,
+    step: usize,
+) -> PyResult<()> {
+    if !btrees::is_typed(info.key_type) {
+        return Ok(());
+    }
+    for (i, item) in list.iter().enumerate().skip(start).step_by(step) {
+        let key = pyobject_to_pickle_value(&item, false)?;
+        btrees::check_typed_item(info.key_type, "key", &key, &|| format!("{marker}[{i}]"))?;
+    }
+    Ok(())
+}
+
 fn encode_flat_kv_tuple(
     kv_list: &Bound<'_, PyList>,
     buf: &mut Vec<u8>,
     expand_refs: bool,
 ) -> PyResult<()> {
     let n_pairs = kv_list.len();
+    EncodeLimits::current().check_collection(n_pairs)?;
     let n_items = n_pairs * 2;
 
     if n_items == 0 {
@@ -2489,6 +3587,7 @@ fn encode_flat_keys_tuple(
     expand_refs: bool,
 ) -> PyResult<()> {
     let n = ks_list.len();
+    EncodeLimits::current().check_collection(n)?;
 
     if n == 0 {
         buf.push(EMPTY_TUPLE);
@@ -2518,181 +3617,25 @@ fn encode_flat_keys_tuple(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::encode::encode_pickle;
-    use crate::types::PickleValue;
-
-    #[test]
-    fn test_collect_refs_empty() {
-        let val = PickleValue::Dict(vec![]);
-        let mut refs = Vec::new();
-        collect_refs_from_pickle_value(&val, &mut refs);
-        assert!(refs.is_empty());
-    }
-
-    #[test]
-    fn test_collect_refs_persistent_ref() {
-        // OID = 8 bytes big-endian for value 42
-        let oid = vec![0, 0, 0, 0, 0, 0, 0, 42];
-        let val = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
-            PickleValue::Bytes(oid),
-            PickleValue::None,
-        ])));
-        let mut refs = Vec::new();
-        collect_refs_from_pickle_value(&val, &mut refs);
-        assert_eq!(refs, vec![42]);
-    }
-
-    #[test]
-    fn test_collect_refs_nested_in_dict() {
-        let oid1 = vec![0, 0, 0, 0, 0, 0, 0, 1];
-        let oid2 = vec![0, 0, 0, 0, 0, 0, 0, 2];
-        let ref1 = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
-            PickleValue::Bytes(oid1),
-            PickleValue::None,
-        ])));
-        let ref2 = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
-            PickleValue::Bytes(oid2),
-            PickleValue::None,
-        ])));
-        let val = PickleValue::Dict(vec![
-            (PickleValue::String("a".to_string()), ref1),
-            (PickleValue::String("b".to_string()), ref2),
-        ]);
-        let mut refs = Vec::new();
-        collect_refs_from_pickle_value(&val, &mut refs);
-        assert_eq!(refs, vec![1, 2]);
-    }
-
-    #[test]
-    fn test_collect_refs_in_list() {
-        let oid = vec![0, 0, 0, 0, 0, 0, 0, 99];
-        let pref = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
-            PickleValue::Bytes(oid),
-            PickleValue::None,
-        ])));
-        let val = PickleValue::List(vec![
-            PickleValue::String("hello".to_string()),
-            pref,
-        ]);
-        let mut refs = Vec::new();
-        collect_refs_from_pickle_value(&val, &mut refs);
-        assert_eq!(refs, vec![99]);
-    }
-
-    #[test]
-    fn test_collect_refs_skips_short_oid() {
-        // Cross-database refs may have different OID lengths
-        let oid = vec![1, 2, 3];  // Not 8 bytes → skip
-        let val = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
-            PickleValue::Bytes(oid),
-            PickleValue::None,
-        ])));
-        let mut refs = Vec::new();
-        collect_refs_from_pickle_value(&val, &mut refs);
-        assert!(refs.is_empty());
-    }
-
-    #[test]
-    fn test_collect_refs_in_instance() {
-        let oid = vec![0, 0, 0, 0, 0, 0, 0, 7];
-        let pref = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
-            PickleValue::Bytes(oid),
-            PickleValue::None,
-        ])));
-        let val = PickleValue::Instance(Box::new(InstanceData {
-            module: "myapp".to_string(),
-            name: "Obj".to_string(),
-            state: Box::new(PickleValue::Dict(vec![
-                (PickleValue::String("ref".to_string()), pref),
-            ])),
-            dict_items: None,
-            list_items: None,
-        }));
-        let mut refs = Vec::new();
-        collect_refs_from_pickle_value(&val, &mut refs);
-        assert_eq!(refs, vec![7]);
-    }
-
-    #[test]
-    fn test_collect_refs_in_reduce() {
-        let oid = vec![0, 0, 0, 0, 0, 0, 0, 5];
-        let pref = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
-            PickleValue::Bytes(oid),
-            PickleValue::None,
-        ])));
-        let val = PickleValue::Reduce {
-            callable: Box::new(PickleValue::Global {
-                module: "builtins".to_string(),
-                name: "set".to_string(),
-            }),
-            args: Box::new(PickleValue::Tuple(vec![
-                PickleValue::List(vec![pref]),
-            ])),
-            dict_items: None,
-            list_items: None,
-        };
-        let mut refs = Vec::new();
-        collect_refs_from_pickle_value(&val, &mut refs);
-        assert_eq!(refs, vec![5]);
-    }
 
     #[test]
-    fn test_collect_refs_no_refs() {
-        let val = PickleValue::Dict(vec![
-            (PickleValue::String("title".to_string()), PickleValue::String("Hello".to_string())),
-            (PickleValue::String("count".to_string()), PickleValue::Int(42)),
-        ]);
-        let mut refs = Vec::new();
-        collect_refs_from_pickle_value(&val, &mut refs);
-        assert!(refs.is_empty());
+    fn test_bytes_mode_from_flags() {
+        assert_eq!(BytesMode::from_flags(false, false), BytesMode::Base64);
+        assert_eq!(BytesMode::from_flags(true, false), BytesMode::Binary);
+        assert_eq!(BytesMode::from_flags(false, true), BytesMode::Raw);
+        assert_eq!(BytesMode::from_flags(true, true), BytesMode::Raw);
     }
 
     #[test]
-    fn test_build_class_pickle_matches_pickle_value_encode() {
-        // Verify that build_class_pickle produces identical bytes to the
-        // PickleValue-based approach for various class names.
-        // Note: build_class_pickle uses PROTO 2 (matching production encode),
-        // encode_pickle uses PROTO 3. Both are valid; we compare after byte 1.
-        let cases = vec![
-            ("persistent.mapping", "PersistentMapping"),
-            ("BTrees.OOBTree", "OOBTree"),
-            ("BTrees.OOBTree", "OOBucket"),
-            ("BTrees.Length", "Length"),
-            ("myapp.models", "Article"),
-            ("a", "B"),  // short names
-            ("", ""),    // empty (edge case)
-        ];
-
-        for (module, name) in cases {
-            let cached = build_class_pickle(module, name);
-
-            // Build the same bytes via PickleValue + encode_pickle
-            let class_val = PickleValue::Tuple(vec![
-                PickleValue::Tuple(vec![
-                    PickleValue::String(module.to_string()),
-                    PickleValue::String(name.to_string()),
-                ]),
-                PickleValue::None,
-            ]);
-            let reference = encode_pickle(&class_val).unwrap();
-
-            // Protocol byte differs (2 vs 3), rest must be identical
-            assert_eq!(cached[0], PROTO);
-            assert_eq!(cached[1], 2);
-            assert_eq!(reference[1], 3);
-            assert_eq!(
-                &cached[2..], &reference[2..],
-                "class pickle body mismatch for ({}, {})",
-                module, name
-            );
+    fn test_bytes_mode_scope_restores() {
+        {
+            let _outer = BytesModeScope::enter(BytesMode::Raw);
+            {
+                let _inner = BytesModeScope::enter(BytesMode::Binary);
+                assert_eq!(BYTES_MODE.with(Cell::get), BytesMode::Binary);
+            }
+            assert_eq!(BYTES_MODE.with(Cell::get), BytesMode::Raw);
         }
-    }
-
-    #[test]
-    fn test_build_class_pickle_starts_with_proto_ends_with_stop() {
-        let bytes = build_class_pickle("mod", "Cls");
-        assert_eq!(bytes[0], PROTO);
-        assert_eq!(bytes[1], 2);
-        assert_eq!(*bytes.last().unwrap(), STOP);
+        assert_eq!(BYTES_MODE.with(Cell::get), BytesMode::Base64);
     }
 }
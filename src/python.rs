@@ -0,0 +1,2760 @@
+//! The `zodb_json_codec._rust` Python extension module.
+//!
+//! Thin `#[pyfunction]` wrappers over the codec core: arguments are
+//! borrowed from Python where possible, the GIL is released around pure
+//! Rust work, and `CodecError` surfaces as the `CodecError` exception, a
+//! `ValueError` subclass. Compiled with the `python` feature (on by
+//! default).
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use pyo3::prelude::*;
+use pyo3::intern;
+use pyo3::types::{PyBool, PyBytes, PyDict, PyInt, PyList, PySet, PyString, PyTuple};
+
+use crate::pybuffer::BytesLike;
+use crate::pyconv::BytesMode;
+use crate::{
+    batch, bigint, binenc, btrees, bytes_keys, dangling, dedup, duplicate_keys, error, floats,
+    known_types, limits, logbridge, null_strings, pyast, pyconv, raw_pickle, refscan, remap, rename,
+    zodb,
+};
+use crate::decode::decode_pickle_with;
+use crate::error::PathSegment;
+use crate::{
+    DEFAULT_LINT_MAX_DEPTH, DEFAULT_LINT_MAX_STRING, DEFAULT_MAX_MEMO_ENTRIES,
+    DEFAULT_MAX_NAME_LINE, DEFAULT_MAX_NUMBER_LINE, DEFAULT_MAX_STRING_LENGTH,
+    DEFAULT_MAX_STRING_LINE, BTreeNodeKind, ClassQuota, ClassQuotas, ClassRenames, CodecError,
+    DecodeLimits, DecodeOptions, DecodePolicy, DuplicateKeys, EncodeLimits, FileStorage,
+    FramePolicy, LineLimits, LintOptions, NonFiniteFloats, OidMapping, PickleEvent, PickleEvents,
+    PickleValue, PolicyViolation, Py2Strings, RefFormat, SurrogatePolicy, Transaction, TypeSpec,
+    ZeoCache, analyze_pickle, apply_patch_to_record, canonicalize_json, canonicalize_pickle,
+    cbor_to_pickle_value, classify_btree, clear_btree_registrations, codec_info, check_strict,
+    collect_refs_ex, collect_warnings, count_refs, decode_pickle, decode_pickle_with_options,
+    decode_zodb_pickles, decode_zodb_pickles_with_options, diff_zodb_records, encode_pickle,
+    encode_pickle_framed, encode_pickle_protocol, encode_pickle_protocol0, estimate_decoded_size,
+    extract_paths, extract_subtree, find_class_references, frame_pickle, graft_subtree, has_ref_to,
+    hex_to_oid, json_to_pickle_value, lint_record, materialize_btree, oid_to_hex, pickle_events,
+    pickle_to_cbor, pickle_value_to_json_string, pickle_value_to_json_string_sorted, reachable_oids,
+    record_refs_to_edges, record_refs_to_edges_batch, register_btree_class,
+    register_btree_module_prefix, register_type_handler, remap_record,
+    split_btree, split_zodb_record, state_fingerprint,
+    tid_to_timestamp, timestamp_to_tid, unregister_type_handler, verify_roundtrip, write_edges_csv,
+    write_edges_dot,
+};
+
+/// Run `f`, appending the conversion warnings it gives to `warnings` as
+/// `{"code", "message", "count"}` dicts, also when it fails.
+fn with_warnings<R, E: Into<PyErr>>(
+    py: Python<'_>,
+    warnings: Option<&Bound<'_, PyList>>,
+    f: impl FnOnce() -> Result<R, E>,
+) -> PyResult<R> {
+    let Some(list) = warnings else {
+        return f().map_err(Into::into);
+    };
+    let (result, collected) = collect_warnings(f);
+    for w in collected {
+        let dict = PyDict::new(py);
+        dict.set_item(intern!(py, "code"), w.code.as_str())?;
+        dict.set_item(intern!(py, "message"), w.message)?;
+        dict.set_item(intern!(py, "count"), w.count)?;
+        list.append(dict)?;
+    }
+    result.map_err(Into::into)
+}
+
+/// Borrow the contents of the `buffers` argument of the decoding functions.
+fn buffer_slices<'a>(buffers: &'a Option<Vec<BytesLike<'_>>>) -> Vec<&'a [u8]> {
+    buffers.iter().flatten().map(|b| b.as_bytes()).collect()
+}
+
+/// Convert pickle bytes to a JSON string.
+///
+/// `data` may be any bytes-like object; it is read without copying.
+/// `buffers` holds the out-of-band buffers of a protocol 5 pickle, in the
+/// order `buffer_callback` received them. `indent` is the number of
+/// spaces per nesting level; `None` gives compact output. Conversion
+/// warnings (`@reduce`, `@pkl`, ... fallbacks) are appended to the list
+/// `warnings` as `{"code", "message", "count"}` dicts. With
+/// `strict=True`, such fallbacks to `@reduce` or `@pkl` raise instead.
+/// With `sort_keys=True`, dict members are written sorted by key; `@d`
+/// and `@kv` pair lists keep their order. With `lenient=True`, a dict
+/// with an odd item count keeps its last item under `@dangling` and an
+/// undecodable pickle is kept as `@pkl`, instead of failing.
+/// `py2_strings` chooses how Python 2 `str` values decode: `"bytes"`
+/// (`@b`), `"latin1"` or `"utf8"` (text, keeping values that are not
+/// valid UTF-8 as `@b`). OIDs and the packed `datetime`/`TimeStamp`
+/// arguments stay bytes. With `shared_references=True`, a container the
+/// pickle refers to more than once decodes to one `@shared` node and
+/// `@backref`s instead of one copy per reference, so that encoding
+/// restores the aliasing; cycles are kept either way. `policy` is a
+/// `DecodePolicy` checked at every class reference, after the
+/// `ClassRenames` of `renames` are applied to it. `line_limits` is a
+/// `LineLimits` bounding the lines of text-mode (protocol 0) opcodes, and
+/// `limits` a `DecodeLimits` bounding the decoder's resources.
+/// `surrogates` chooses how strings with lone surrogates decode: `"error"`
+/// (the default, raise `CodecError`), `"replace"` (U+FFFD, with a
+/// `surrogates` warning) or `"preserve"` (an `@su` marker that encodes back
+/// to the same string). `nonfinite_floats` chooses how NaN and the
+/// infinities are written: `"marker"` (the default, `{"@f": "nan" | "inf" |
+/// "-inf"}`) or `"null"` (lossy, for strict-JSON consumers).
+/// `duplicate_keys` chooses how dicts whose pickle repeats a key are
+/// converted: `"last-wins"` (the default, as unpickling does, with a
+/// `duplicate-keys` warning), `"error"` (raise `CodecError`) or
+/// `"preserve"` (an `@d` pair list with every item). With `bigint_max_bits`
+/// (64 to 127), integers outside the i64 range whose magnitude is below
+/// `2**bigint_max_bits` are written as plain numbers instead of `@bi`
+/// strings. With `detect_raw_tids=True`, plain 8-byte values that decode to
+/// a plausible transaction timestamp (1990-2100) are written as `@tid`
+/// markers instead of `@b`; `persistent.TimeStamp` objects always are. With
+/// `promote_bytes_keys=True`, dicts whose keys are all ASCII-clean byte
+/// strings (Python 2 `str` keys) are written as plain objects annotated
+/// with `"@bk": true` instead of `@d` pair lists; encoding restores the
+/// keys to bytes.
+#[pyfunction]
+#[pyo3(signature = (
+    data, *, buffers=None, indent=Some(2), strict=false, warnings=None, sort_keys=false,
+    lenient=false, py2_strings="bytes", shared_references=false, policy=None, renames=None,
+    line_limits=None, limits=None, surrogates="error", nonfinite_floats="marker",
+    duplicate_keys="last-wins", bigint_max_bits=None, detect_raw_tids=false,
+    promote_bytes_keys=false
+))]
+#[allow(clippy::too_many_arguments)]
+fn pickle_to_json(
+    py: Python<'_>,
+    data: BytesLike<'_>,
+    buffers: Option<Vec<BytesLike<'_>>>,
+    indent: Option<usize>,
+    strict: bool,
+    warnings: Option<&Bound<'_, PyList>>,
+    sort_keys: bool,
+    lenient: bool,
+    py2_strings: &str,
+    shared_references: bool,
+    policy: Option<&Bound<'_, PyDecodePolicy>>,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    line_limits: Option<&Bound<'_, PyLineLimits>>,
+    limits: Option<&Bound<'_, PyDecodeLimits>>,
+    surrogates: &str,
+    nonfinite_floats: &str,
+    duplicate_keys: &str,
+    bigint_max_bits: Option<u32>,
+    detect_raw_tids: bool,
+    promote_bytes_keys: bool,
+) -> PyResult<String> {
+    let data = data.as_bytes();
+    let buffers = buffer_slices(&buffers);
+    let options = decode_options(
+        None,
+        lenient,
+        py2_strings,
+        shared_references,
+        policy,
+        renames,
+        line_limits,
+        limits,
+        surrogates,
+    )?;
+    let _bytes_keys = bytes_keys::PromotionScope::enter(promote_bytes_keys);
+    let _nonfinite = floats::NonFiniteScope::enter(parse_nonfinite_floats(nonfinite_floats)?);
+    let _duplicates =
+        duplicate_keys::DuplicateKeysScope::enter(parse_duplicate_keys(duplicate_keys)?);
+    let _bigint = bigint::BigIntScope::enter(bigint::number_bits(bigint_max_bits)?);
+    let _raw_tids = known_types::RawTidScope::enter(detect_raw_tids);
+    // Entire function is pure Rust — release GIL for the full duration
+    with_warnings(py, warnings, || {
+        py.detach(|| {
+            let val = decode_pickle_with(data, &buffers, &options)?;
+            if strict {
+                check_strict(&val)?;
+            }
+            if sort_keys {
+                pickle_value_to_json_string_sorted(&val, indent)
+            } else {
+                pickle_value_to_json_string(&val, indent)
+            }
+        })
+    })
+}
+
+/// Check the `protocol` argument of the encoding functions: 3 (default),
+/// 0 for text pickles, which cannot be framed, 2 or 4.
+fn check_protocol(protocol: u8, chunk_size: Option<usize>) -> Result<(), CodecError> {
+    match (protocol, chunk_size) {
+        (3 | 4, _) | (0 | 2, None) => Ok(()),
+        (0 | 2, Some(_)) => Err(CodecError::InvalidData(
+            "chunk_size requires protocol 3 or 4".to_string(),
+        )),
+        _ => Err(CodecError::InvalidData(format!(
+            "unsupported pickle protocol {protocol}, expected 0, 2, 3 or 4"
+        ))),
+    }
+}
+
+/// Encode with the checked `protocol` and `chunk_size` arguments.
+fn encode_with_options(
+    val: &PickleValue,
+    chunk_size: Option<usize>,
+    protocol: u8,
+) -> Result<Vec<u8>, CodecError> {
+    match (chunk_size, protocol) {
+        (Some(avg), 4) => frame_pickle(
+            &encode_pickle_protocol(val, 4)?,
+            &FramePolicy::with_avg_size(avg),
+        ),
+        (Some(avg), _) => encode_pickle_framed(val, &FramePolicy::with_avg_size(avg)),
+        (None, 0) => encode_pickle_protocol0(val),
+        (None, _) => encode_pickle_protocol(val, protocol),
+    }
+}
+
+/// Convert a JSON string to pickle bytes.
+///
+/// With `chunk_size`, the output is a protocol 4 pickle split into
+/// content-defined frames of about that many bytes (see `frame_pickle`).
+/// With `protocol=0`, the output is a text pickle readable by any
+/// unpickler (see `encode_pickle_protocol0`); 2 and 4 select those
+/// protocols (see `encode_pickle_protocol`). With `pg_safe=True`, the
+/// `{"@ns": base64}` markers and `"@ns:"` keys of the PostgreSQL form
+/// are read back as strings with null bytes; otherwise they are ordinary
+/// data. A dict holding the `@dangling` key of lenient decoding raises
+/// `ValueError`, unless `drop_dangling=True` drops the key and its item.
+/// `renames` is a `ClassRenames` applied to every class written,
+/// `raw_pickle_policy` a `RawPicklePolicy` validating `@pkl` payloads
+/// instead of the default one, and `limits` an `EncodeLimits` bounding
+/// the pickle written.
+#[pyfunction]
+#[pyo3(signature = (
+    json_str, *, chunk_size=None, protocol=3, pg_safe=false, drop_dangling=false, renames=None,
+    raw_pickle_policy=None, limits=None
+))]
+#[allow(clippy::too_many_arguments)]
+fn json_to_pickle(
+    py: Python<'_>,
+    json_str: &str,
+    chunk_size: Option<usize>,
+    protocol: u8,
+    pg_safe: bool,
+    drop_dangling: bool,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    raw_pickle_policy: Option<&Bound<'_, PyRawPicklePolicy>>,
+    limits: Option<&Bound<'_, PyEncodeLimits>>,
+) -> PyResult<Py<PyBytes>> {
+    check_protocol(protocol, chunk_size)?;
+    let _pg_safe = null_strings::PgSafeScope::enter(pg_safe);
+    let _dangling = dangling::DropDanglingScope::enter(drop_dangling);
+    let _renames = rename::EncodeRenamesScope::enter(encode_renames(renames));
+    let _raw_pickle =
+        raw_pickle::RawPicklePolicyScope::enter(raw_pickle_policy.map(|p| Arc::clone(&p.get().0)));
+    let _limits = limits::EncodeLimitsScope::enter(encode_limits(limits));
+    let json_val: serde_json::Value =
+        serde_json::from_str(json_str).map_err(|e| CodecError::Json(e.to_string()))?;
+    let pickle_val = json_to_pickle_value(&json_val)?;
+    let bytes = encode_with_options(&pickle_val, chunk_size, protocol)?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// Convert pickle bytes to CBOR, with semantic tags in place of the JSON
+/// markers.
+#[pyfunction(name = "pickle_to_cbor")]
+fn py_pickle_to_cbor(py: Python<'_>, data: BytesLike<'_>) -> PyResult<Py<PyBytes>> {
+    let data = data.as_bytes();
+    let bytes = py.detach(|| pickle_to_cbor(data))?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// Convert CBOR from `pickle_to_cbor` back to pickle bytes.
+///
+/// `chunk_size`, `protocol` and `limits` work as for `json_to_pickle`.
+#[pyfunction(name = "cbor_to_pickle")]
+#[pyo3(signature = (data, *, chunk_size=None, protocol=3, limits=None))]
+fn py_cbor_to_pickle(
+    py: Python<'_>,
+    data: BytesLike<'_>,
+    chunk_size: Option<usize>,
+    protocol: u8,
+    limits: Option<&Bound<'_, PyEncodeLimits>>,
+) -> PyResult<Py<PyBytes>> {
+    check_protocol(protocol, chunk_size)?;
+    let _limits = limits::EncodeLimitsScope::enter(encode_limits(limits));
+    let data = data.as_bytes();
+    let bytes = py.detach(|| {
+        let val = cbor_to_pickle_value(data)?;
+        encode_with_options(&val, chunk_size, protocol)
+    })?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// Re-emit a marker-bearing JSON string in canonical form (sorted keys,
+/// compact refs, normalized typed markers).
+#[pyfunction(name = "canonicalize_json")]
+fn py_canonicalize_json(py: Python<'_>, json_str: &str) -> PyResult<String> {
+    py.detach(|| Ok(canonicalize_json(json_str)?))
+}
+
+/// Re-encode a pickle or ZODB record so that equal states give identical
+/// bytes (protocol 3, content-planned memo, dict order kept).
+#[pyfunction(name = "canonicalize_pickle")]
+fn py_canonicalize_pickle(py: Python<'_>, data: BytesLike<'_>) -> PyResult<Py<PyBytes>> {
+    let data = data.as_bytes();
+    let bytes = py.detach(|| canonicalize_pickle(data))?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// Hex SHA-256 of a record's logical state, independent of how it was
+/// pickled.
+#[pyfunction(name = "state_fingerprint")]
+fn py_state_fingerprint(py: Python<'_>, record: BytesLike<'_>) -> PyResult<String> {
+    let record = record.as_bytes();
+    Ok(py.detach(|| state_fingerprint(record))?)
+}
+
+/// Convert pickle bytes to a Python dict (direct PickleValue → Py<PyAny>).
+///
+/// `data` and `buffers` work as for `pickle_to_json`. With
+/// `binary_mode=True`, `@b` markers hold `bytes` instead of base64; with
+/// `raw_bytes=True`, byte strings decode to plain `bytes` objects.
+/// `strict`, `warnings`, `lenient`, `py2_strings`, `shared_references`,
+/// `policy`, `renames`, `line_limits`, `limits`, `surrogates`,
+/// `nonfinite_floats`, `duplicate_keys`, `bigint_max_bits`,
+/// `detect_raw_tids` and `promote_bytes_keys` work as for `pickle_to_json`,
+/// and `compact_refs`, `pg_safe` and `value_dedup` as for
+/// `decode_zodb_record`, except that `compact_refs` defaults to `False`:
+/// `pickle_to_dict` has always returned the generic `@ref` form, and
+/// existing callers keep it.
+#[pyfunction]
+#[pyo3(signature = (
+    data, *, buffers=None, binary_mode=false, raw_bytes=false, strict=false, warnings=None,
+    compact_refs=false, pg_safe=false, lenient=false, py2_strings="bytes", shared_references=false,
+    policy=None, renames=None, line_limits=None, limits=None, surrogates="error",
+    nonfinite_floats="marker", duplicate_keys="last-wins", bigint_max_bits=None,
+    detect_raw_tids=false, value_dedup=false, promote_bytes_keys=false
+))]
+#[allow(clippy::too_many_arguments)]
+fn pickle_to_dict(
+    py: Python<'_>,
+    data: BytesLike<'_>,
+    buffers: Option<Vec<BytesLike<'_>>>,
+    binary_mode: bool,
+    raw_bytes: bool,
+    strict: bool,
+    warnings: Option<&Bound<'_, PyList>>,
+    compact_refs: bool,
+    pg_safe: bool,
+    lenient: bool,
+    py2_strings: &str,
+    shared_references: bool,
+    policy: Option<&Bound<'_, PyDecodePolicy>>,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    line_limits: Option<&Bound<'_, PyLineLimits>>,
+    limits: Option<&Bound<'_, PyDecodeLimits>>,
+    surrogates: &str,
+    nonfinite_floats: &str,
+    duplicate_keys: &str,
+    bigint_max_bits: Option<u32>,
+    detect_raw_tids: bool,
+    value_dedup: bool,
+    promote_bytes_keys: bool,
+) -> PyResult<Py<PyAny>> {
+    let data = data.as_bytes();
+    let buffers = buffer_slices(&buffers);
+    let options = decode_options(
+        None,
+        lenient,
+        py2_strings,
+        shared_references,
+        policy,
+        renames,
+        line_limits,
+        limits,
+        surrogates,
+    )?;
+    let _bytes_keys = bytes_keys::PromotionScope::enter(promote_bytes_keys);
+    let _nonfinite = floats::NonFiniteScope::enter(parse_nonfinite_floats(nonfinite_floats)?);
+    let _duplicates =
+        duplicate_keys::DuplicateKeysScope::enter(parse_duplicate_keys(duplicate_keys)?);
+    let _bigint = bigint::BigIntScope::enter(bigint::number_bits(bigint_max_bits)?);
+    let _raw_tids = known_types::RawTidScope::enter(detect_raw_tids);
+    let _dedup = dedup::DedupScope::enter(value_dedup);
+    let _bytes_mode = pyconv::BytesModeScope::enter(BytesMode::from_flags(binary_mode, raw_bytes));
+    with_warnings(py, warnings, || {
+        let val = py.detach(|| {
+            let val = decode_pickle_with(data, &buffers, &options)?;
+            if strict {
+                check_strict(&val)?;
+            }
+            Ok::<_, CodecError>(val)
+        })?;
+        if pg_safe {
+            pyconv::pickle_value_to_pyobject_pg(py, &val, compact_refs)
+        } else {
+            pyconv::pickle_value_to_pyobject(py, &val, compact_refs)
+        }
+    })
+}
+
+/// Convert a Python dict to pickle bytes (direct Py<PyAny> → pickle bytes).
+///
+/// `chunk_size`, `protocol`, `pg_safe`, `drop_dangling`, `renames`,
+/// `raw_pickle_policy` and `limits` work as for `json_to_pickle`.
+#[pyfunction]
+#[pyo3(signature = (
+    obj, *, chunk_size=None, protocol=3, pg_safe=false, drop_dangling=false, renames=None,
+    raw_pickle_policy=None, limits=None
+))]
+#[allow(clippy::too_many_arguments)]
+fn dict_to_pickle(
+    py: Python<'_>,
+    obj: &Bound<'_, PyDict>,
+    chunk_size: Option<usize>,
+    protocol: u8,
+    pg_safe: bool,
+    drop_dangling: bool,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    raw_pickle_policy: Option<&Bound<'_, PyRawPicklePolicy>>,
+    limits: Option<&Bound<'_, PyEncodeLimits>>,
+) -> PyResult<Py<PyBytes>> {
+    check_protocol(protocol, chunk_size)?;
+    let _pg_safe = null_strings::PgSafeScope::enter(pg_safe);
+    let _dangling = dangling::DropDanglingScope::enter(drop_dangling);
+    let _renames = rename::EncodeRenamesScope::enter(encode_renames(renames));
+    let _raw_pickle =
+        raw_pickle::RawPicklePolicyScope::enter(raw_pickle_policy.map(|p| Arc::clone(&p.get().0)));
+    let _limits = limits::EncodeLimitsScope::enter(encode_limits(limits));
+    if protocol != 3 {
+        // The direct encoder only writes protocol 3 opcodes
+        let val = pyconv::pyobject_to_pickle_value(obj.as_any(), false)?;
+        let bytes = py.detach(|| encode_with_options(&val, chunk_size, protocol))?;
+        return Ok(PyBytes::new(py, &bytes).into());
+    }
+    let mut bytes = pyconv::encode_pyobject_as_pickle(obj.as_any(), false)?;
+    if let Some(avg) = chunk_size {
+        bytes = py.detach(|| frame_pickle(&bytes, &FramePolicy::with_avg_size(avg)))?;
+    }
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// Decode a pickle into its AST: a tree of `zodb_json_codec.nodes`
+/// objects mirroring `PickleValue`, without the markers of the dict form.
+/// `surrogates` works as for `pickle_to_json`.
+#[pyfunction(name = "decode_pickle_ast")]
+#[pyo3(signature = (data, *, surrogates="error"))]
+fn py_decode_pickle_ast(
+    py: Python<'_>,
+    data: BytesLike<'_>,
+    surrogates: &str,
+) -> PyResult<Py<pyast::PickleNode>> {
+    let data = data.as_bytes();
+    let options = DecodeOptions::new().with_surrogates(parse_surrogates(surrogates)?);
+    let val = py.detach(|| decode_pickle_with_options(data, &options))?;
+    pyast::value_to_node(py, &val)
+}
+
+/// Encode a pickle AST (as returned by `decode_pickle_ast`) to pickle
+/// bytes. `chunk_size`, `protocol` and `limits` work as for
+/// `json_to_pickle`.
+#[pyfunction(name = "encode_pickle_ast")]
+#[pyo3(signature = (node, *, chunk_size=None, protocol=3, limits=None))]
+fn py_encode_pickle_ast(
+    py: Python<'_>,
+    node: &Bound<'_, pyast::PickleNode>,
+    chunk_size: Option<usize>,
+    protocol: u8,
+    limits: Option<&Bound<'_, PyEncodeLimits>>,
+) -> PyResult<Py<PyBytes>> {
+    check_protocol(protocol, chunk_size)?;
+    let _limits = limits::EncodeLimitsScope::enter(encode_limits(limits));
+    let val = pyast::node_to_value(node)?;
+    let bytes = py.detach(|| encode_with_options(&val, chunk_size, protocol))?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// Decode a ZODB record (two concatenated pickles) into a Python dict.
+/// Returns: `{"@cls": ["module", "name"], "@s": { ... state ... }}`
+///
+/// `data` may be any bytes-like object. `binary_mode` and `raw_bytes`
+/// work as for `pickle_to_dict`. Blob records are marked `"@blob": True`,
+/// with `"@serial"` (hex) when the record's 8-byte tid `serial` is given.
+/// With `keep_class_pickle=True`, the class pickle is kept as `"@cls_raw"`
+/// for `encode_zodb_record` to write back unchanged. With a `load(oid) ->
+/// bytes` callable, a large BTree's buckets are loaded and inlined into a
+/// single `@kv`/`@ks`. `strict`, `warnings` and `sort_keys` work as for
+/// `pickle_to_json`. With `compact_refs=False`, persistent references keep
+/// the generic `@ref` form of `pickle_to_dict`; with `pg_safe=True`,
+/// strings with null bytes become `@ns` markers as in
+/// `decode_zodb_record_for_pg`. `quotas` is a `ClassQuotas` to enforce;
+/// `lenient`, `py2_strings`, `shared_references`, `policy`, `renames`,
+/// `line_limits`, `limits`, `surrogates`, `nonfinite_floats`,
+/// `duplicate_keys`, `bigint_max_bits`, `detect_raw_tids` and
+/// `promote_bytes_keys` work as for `pickle_to_json`. `ref_format` chooses
+/// how compact refs write their OID: `"hex"` (`{"@ref":
+/// "000000000000002a"}`) or `"int"` (`{"@ref": 42}`, the signed 64-bit form
+/// of the `refs` list); encoding accepts both. With `value_dedup=True`,
+/// identical `str`, `int` and `float` leaves of the record share one Python
+/// object instead of one per occurrence, which reduces allocations for
+/// bucket-heavy records.
+#[pyfunction]
+#[pyo3(signature = (
+    data, *, binary_mode=false, raw_bytes=false, serial=None, keep_class_pickle=false, load=None,
+    strict=false, warnings=None, sort_keys=false, compact_refs=true, pg_safe=false, quotas=None,
+    lenient=false, py2_strings="bytes", shared_references=false, policy=None, renames=None,
+    line_limits=None, limits=None, surrogates="error", nonfinite_floats="marker",
+    duplicate_keys="last-wins", bigint_max_bits=None, detect_raw_tids=false, value_dedup=false,
+    promote_bytes_keys=false, ref_format="hex"
+))]
+#[allow(clippy::too_many_arguments)]
+fn decode_zodb_record(
+    py: Python<'_>,
+    data: BytesLike<'_>,
+    binary_mode: bool,
+    raw_bytes: bool,
+    serial: Option<&[u8]>,
+    keep_class_pickle: bool,
+    load: Option<&Bound<'_, PyAny>>,
+    strict: bool,
+    warnings: Option<&Bound<'_, PyList>>,
+    sort_keys: bool,
+    compact_refs: bool,
+    pg_safe: bool,
+    quotas: Option<&Bound<'_, PyClassQuotas>>,
+    lenient: bool,
+    py2_strings: &str,
+    shared_references: bool,
+    policy: Option<&Bound<'_, PyDecodePolicy>>,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    line_limits: Option<&Bound<'_, PyLineLimits>>,
+    limits: Option<&Bound<'_, PyDecodeLimits>>,
+    surrogates: &str,
+    nonfinite_floats: &str,
+    duplicate_keys: &str,
+    bigint_max_bits: Option<u32>,
+    detect_raw_tids: bool,
+    value_dedup: bool,
+    promote_bytes_keys: bool,
+    ref_format: &str,
+) -> PyResult<Py<PyAny>> {
+    let serial: Option<[u8; 8]> = serial
+        .map(|s| s.try_into())
+        .transpose()
+        .map_err(|_| CodecError::InvalidData("serial must be 8 bytes".to_string()))?;
+    let _bytes_mode = pyconv::BytesModeScope::enter(BytesMode::from_flags(binary_mode, raw_bytes));
+    let _sort_keys = pyconv::SortKeysScope::enter(sort_keys);
+    let _bytes_keys = bytes_keys::PromotionScope::enter(promote_bytes_keys);
+    let _nonfinite = floats::NonFiniteScope::enter(parse_nonfinite_floats(nonfinite_floats)?);
+    let _duplicates =
+        duplicate_keys::DuplicateKeysScope::enter(parse_duplicate_keys(duplicate_keys)?);
+    let _bigint = bigint::BigIntScope::enter(bigint::number_bits(bigint_max_bits)?);
+    let _raw_tids = known_types::RawTidScope::enter(detect_raw_tids);
+    let _dedup = dedup::DedupScope::enter(value_dedup);
+    let _ref_format = zodb::RefFormatScope::enter(parse_ref_format(ref_format)?);
+    let data = data.as_bytes();
+    let options = RecordOptions {
+        load,
+        strict,
+        compact_refs,
+        pg_safe,
+        decode: decode_options(
+            quotas,
+            lenient,
+            py2_strings,
+            shared_references,
+            policy,
+            renames,
+            line_limits,
+            limits,
+            surrogates,
+        )?,
+    };
+    let record = with_warnings(py, warnings, || {
+        decode_zodb_record_impl(py, data, serial.as_ref(), &options)
+    })?;
+    if keep_class_pickle {
+        let dict = record.bind(py).cast::<PyDict>()?;
+        let cls: Vec<String> = dict.as_any().get_item(intern!(py, "@cls"))?.extract()?;
+        if let (false, [module, name]) = (dict.contains(intern!(py, "@blob"))?, cls.as_slice()) {
+            if let Some(class_pickle) = zodb::class_pickle_to_keep(data, module, name)? {
+                let raw: Py<PyAny> = if binary_mode || raw_bytes {
+                    PyBytes::new(py, class_pickle).into_any().unbind()
+                } else {
+                    binenc::b64_encode(class_pickle).into_pyobject(py)?.into_any().unbind()
+                };
+                dict.set_item(intern!(py, "@cls_raw"), raw)?;
+            }
+        }
+    }
+    Ok(record)
+}
+
+/// How a record's state becomes Python objects.
+struct RecordOptions<'a, 'py> {
+    /// Loads the buckets of a large BTree to inline them.
+    load: Option<&'a Bound<'py, PyAny>>,
+    /// Fail on `@reduce` and `@pkl` fallbacks.
+    strict: bool,
+    /// Write persistent references in the compact `@ref` form.
+    compact_refs: bool,
+    /// Write strings with null bytes as `@ns` markers.
+    pg_safe: bool,
+    /// Options for decoding the record's pickles.
+    decode: DecodeOptions,
+}
+
+impl RecordOptions<'_, '_> {
+    /// The `decode_zodb_record()` defaults.
+    const DEFAULT: Self = RecordOptions {
+        load: None,
+        strict: false,
+        compact_refs: true,
+        pg_safe: false,
+        decode: DecodeOptions::new(),
+    };
+}
+
+fn decode_zodb_record_impl(
+    py: Python<'_>,
+    data: &[u8],
+    serial: Option<&[u8; 8]>,
+    options: &RecordOptions<'_, '_>,
+) -> PyResult<Py<PyAny>> {
+    let span = tracing::debug_span!(
+        "decode_zodb_record",
+        size = data.len(),
+        module = tracing::field::Empty,
+        name = tracing::field::Empty,
+    );
+    let _entered = span.enter();
+    let (strict, decode_options) = (options.strict, &options.decode);
+    // Release GIL during pure-Rust pickle parsing
+    let (_class_val, state_val, module, name) = py.detach(|| {
+        let (class_val, state_val) = decode_zodb_pickles_with_options(data, decode_options)?;
+        if strict {
+            check_strict(&state_val).map_err(|e| e.in_path(PathSegment::Key("@s")))?;
+        }
+        let (module, name) = zodb::extract_class_info(&class_val);
+        Ok::<_, PyErr>((class_val, state_val, module, name))
+    })?;
+    span.record("module", module.as_str());
+    span.record("name", name.as_str());
+    let state_obj = record_state_to_pyobject(py, &module, &name, &state_val, options)?;
+
+    // Build result dict directly
+    let dict = PyDict::new(py);
+    let cls_list = PyList::new(py, [module.as_str(), name.as_str()])?;
+    dict.set_item(intern!(py, "@cls"), cls_list)?;
+    dict.set_item(intern!(py, "@s"), state_obj)?;
+    if zodb::is_blob(&module, &name, state_val == PickleValue::None) {
+        dict.set_item(intern!(py, "@blob"), true)?;
+        if let Some(serial) = serial {
+            dict.set_item(intern!(py, "@serial"), binenc::hex_encode(serial))?;
+        }
+    }
+    Ok(dict.into_any().unbind())
+}
+
+/// The `@s` of a decoded record of class `module.name`. `options.strict`
+/// is left to the caller.
+fn record_state_to_pyobject(
+    py: Python<'_>,
+    module: &str,
+    name: &str,
+    state_val: &PickleValue,
+    options: &RecordOptions<'_, '_>,
+) -> PyResult<Py<PyAny>> {
+    let RecordOptions {
+        load,
+        compact_refs,
+        pg_safe,
+        ..
+    } = *options;
+    // BTree-aware state conversion with inline persistent ref compaction
+    if let Some(info) = btrees::classify_btree(module, name) {
+        let mut inline = None;
+        if let Some(load) = load {
+            let load_bucket = |oid: &[u8]| -> PyResult<Vec<u8>> {
+                let record = load.call1((PyBytes::new(py, oid),))?;
+                Ok(record.extract::<BytesLike<'_>>()?.as_bytes().to_vec())
+            };
+            inline = materialize_btree(&info, state_val, load_bucket)?;
+        }
+        let state_val = inline.as_ref().unwrap_or(state_val);
+        return if pg_safe {
+            pyconv::btree_state_to_pyobject_pg(py, &info, state_val, compact_refs)
+        } else {
+            pyconv::btree_state_to_pyobject(py, &info, state_val, compact_refs)
+        };
+    }
+    let container = if pg_safe {
+        pyconv::container_state_to_pyobject_pg(py, module, name, state_val, compact_refs)?
+    } else {
+        pyconv::container_state_to_pyobject(py, module, name, state_val, compact_refs)?
+    };
+    match container {
+        Some(obj) => Ok(obj),
+        None if pg_safe => pyconv::pickle_value_to_pyobject_pg(py, state_val, compact_refs),
+        None => pyconv::pickle_value_to_pyobject(py, state_val, compact_refs),
+    }
+}
+
+/// Decode only the state of a ZODB record: the `@s` of
+/// `decode_zodb_record`, without building the `@cls` wrapper.
+///
+/// The class pickle is only read for the class name, which selects the
+/// BTree and container state forms. `binary_mode`, `raw_bytes`, `load`,
+/// `strict`, `warnings`, `quotas`, `lenient`, `py2_strings`,
+/// `shared_references`, `policy`, `renames`, `line_limits`, `limits`,
+/// `surrogates`, `nonfinite_floats`, `duplicate_keys`, `bigint_max_bits`,
+/// `detect_raw_tids`, `value_dedup`, `promote_bytes_keys` and `ref_format`
+/// work as for `decode_zodb_record`.
+#[pyfunction(name = "decode_zodb_state")]
+#[pyo3(signature = (
+    data, *, binary_mode=false, raw_bytes=false, load=None, strict=false, warnings=None,
+    quotas=None, lenient=false, py2_strings="bytes", shared_references=false, policy=None,
+    renames=None, line_limits=None, limits=None, surrogates="error", nonfinite_floats="marker",
+    duplicate_keys="last-wins", bigint_max_bits=None, detect_raw_tids=false, value_dedup=false,
+    promote_bytes_keys=false, ref_format="hex"
+))]
+#[allow(clippy::too_many_arguments)]
+fn py_decode_zodb_state(
+    py: Python<'_>,
+    data: BytesLike<'_>,
+    binary_mode: bool,
+    raw_bytes: bool,
+    load: Option<&Bound<'_, PyAny>>,
+    strict: bool,
+    warnings: Option<&Bound<'_, PyList>>,
+    quotas: Option<&Bound<'_, PyClassQuotas>>,
+    lenient: bool,
+    py2_strings: &str,
+    shared_references: bool,
+    policy: Option<&Bound<'_, PyDecodePolicy>>,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    line_limits: Option<&Bound<'_, PyLineLimits>>,
+    limits: Option<&Bound<'_, PyDecodeLimits>>,
+    surrogates: &str,
+    nonfinite_floats: &str,
+    duplicate_keys: &str,
+    bigint_max_bits: Option<u32>,
+    detect_raw_tids: bool,
+    value_dedup: bool,
+    promote_bytes_keys: bool,
+    ref_format: &str,
+) -> PyResult<Py<PyAny>> {
+    let data = data.as_bytes();
+    let decode_options = decode_options(
+        quotas,
+        lenient,
+        py2_strings,
+        shared_references,
+        policy,
+        renames,
+        line_limits,
+        limits,
+        surrogates,
+    )?;
+    let _bytes_keys = bytes_keys::PromotionScope::enter(promote_bytes_keys);
+    let _nonfinite = floats::NonFiniteScope::enter(parse_nonfinite_floats(nonfinite_floats)?);
+    let _duplicates =
+        duplicate_keys::DuplicateKeysScope::enter(parse_duplicate_keys(duplicate_keys)?);
+    let _bigint = bigint::BigIntScope::enter(bigint::number_bits(bigint_max_bits)?);
+    let _raw_tids = known_types::RawTidScope::enter(detect_raw_tids);
+    let _dedup = dedup::DedupScope::enter(value_dedup);
+    let _ref_format = zodb::RefFormatScope::enter(parse_ref_format(ref_format)?);
+    with_warnings(py, warnings, || {
+        let (module, name, state_val) = py.detach(|| {
+            let (class_val, state_val) = decode_zodb_pickles_with_options(data, &decode_options)?;
+            if strict {
+                check_strict(&state_val)?;
+            }
+            let (module, name) = zodb::extract_class_info(&class_val);
+            Ok::<_, PyErr>((module, name, state_val))
+        })?;
+        let _bytes_mode =
+            pyconv::BytesModeScope::enter(BytesMode::from_flags(binary_mode, raw_bytes));
+        let options = RecordOptions {
+            load,
+            ..RecordOptions::DEFAULT
+        };
+        record_state_to_pyobject(py, &module, &name, &state_val, &options)
+    })
+}
+
+/// Encode a ZODB record from its class and state, as `encode_zodb_record`
+/// does for `{"@cls": [class_module, class_name], "@s": state}`.
+///
+/// With `record`, the class pickle is copied byte for byte from that
+/// record (typically the one the state was decoded from) instead of
+/// being re-encoded; the class name still selects the state form.
+/// `pg_safe`, `drop_dangling`, `renames`, `raw_pickle_policy` and `limits`
+/// work as for `encode_zodb_record`.
+#[pyfunction(name = "encode_zodb_state")]
+#[pyo3(signature = (
+    class_module, class_name, state, *, record=None, pg_safe=false, drop_dangling=false,
+    renames=None, raw_pickle_policy=None, limits=None
+))]
+#[allow(clippy::too_many_arguments)]
+fn py_encode_zodb_state(
+    py: Python<'_>,
+    class_module: &str,
+    class_name: &str,
+    state: &Bound<'_, PyAny>,
+    record: Option<BytesLike<'_>>,
+    pg_safe: bool,
+    drop_dangling: bool,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    raw_pickle_policy: Option<&Bound<'_, PyRawPicklePolicy>>,
+    limits: Option<&Bound<'_, PyEncodeLimits>>,
+) -> PyResult<Py<PyBytes>> {
+    let _pg_safe = null_strings::PgSafeScope::enter(pg_safe);
+    let _dangling = dangling::DropDanglingScope::enter(drop_dangling);
+    let _renames = rename::EncodeRenamesScope::enter(encode_renames(renames));
+    let _raw_pickle =
+        raw_pickle::RawPicklePolicyScope::enter(raw_pickle_policy.map(|p| Arc::clone(&p.get().0)));
+    let _limits = limits::EncodeLimitsScope::enter(encode_limits(limits));
+    let class_pickle = match &record {
+        Some(record) => Some(split_zodb_record(record.as_bytes())?.0),
+        None if zodb::is_blob(class_module, class_name, state.is_none()) => {
+            return Ok(PyBytes::new(py, zodb::BLOB_RECORD).into());
+        }
+        None => None,
+    };
+    let result = pyconv::encode_zodb_record_direct(class_module, class_name, state, class_pickle)?;
+    Ok(PyBytes::new(py, &result).into())
+}
+
+/// Decode a standalone persistent id pickle, as `referencesf` and undo
+/// logs handle them, into its `{"@ref": ...}` dict, in the compact form
+/// `decode_zodb_record` writes. `ref_format` works as for
+/// `decode_zodb_record`.
+#[pyfunction(name = "decode_persistent_id")]
+#[pyo3(signature = (data, *, ref_format="hex"))]
+fn py_decode_persistent_id(
+    py: Python<'_>,
+    data: BytesLike<'_>,
+    ref_format: &str,
+) -> PyResult<Py<PyAny>> {
+    let _ref_format = zodb::RefFormatScope::enter(parse_ref_format(ref_format)?);
+    let data = data.as_bytes();
+    let pid = py.detach(|| decode_pickle(data))?;
+    pyconv::pickle_value_to_pyobject(py, &PickleValue::PersistentRef(Box::new(pid)), true)
+}
+
+/// Encode an `{"@ref": ...}` dict, compact or generic, as a standalone
+/// persistent id pickle (protocol 3). The inverse of
+/// `decode_persistent_id`.
+#[pyfunction(name = "encode_persistent_id")]
+fn py_encode_persistent_id(py: Python<'_>, r#ref: &Bound<'_, PyDict>) -> PyResult<Py<PyBytes>> {
+    let PickleValue::PersistentRef(pid) = pyconv::pyobject_to_pickle_value(r#ref.as_any(), true)?
+    else {
+        return Err(
+            CodecError::InvalidData("a persistent id must be an @ref marker".to_string()).into()
+        );
+    };
+    let bytes = py.detach(|| encode_pickle(&pid))?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// Decode a ZODB record for PostgreSQL JSONB storage.
+///
+/// Combines decode + ref extraction + null-byte sanitization in a single pass.
+/// Returns: `(class_mod: str, class_name: str, state: dict, refs: list[int])`
+///
+/// - `state` has null-byte strings replaced with `{"@ns": base64}` markers
+///   (PostgreSQL JSONB cannot store `\u0000`)
+/// - `refs` contains all persistent reference OIDs as integers (for the
+///   `refs` column used by pure-SQL pack)
+///
+/// `strict`, `warnings`, `lenient`, `py2_strings`, `shared_references`,
+/// `policy`, `renames`, `line_limits`, `limits`, `surrogates`,
+/// `nonfinite_floats`, `duplicate_keys`, `bigint_max_bits`,
+/// `detect_raw_tids` and `promote_bytes_keys` work as for `pickle_to_json`,
+/// `quotas`, `value_dedup` and `ref_format` as for `decode_zodb_record`.
+#[pyfunction]
+#[pyo3(signature = (
+    data, *, strict=false, warnings=None, quotas=None, lenient=false, py2_strings="bytes",
+    shared_references=false, policy=None, renames=None, line_limits=None, limits=None,
+    surrogates="error", nonfinite_floats="marker", duplicate_keys="last-wins", bigint_max_bits=None,
+    detect_raw_tids=false, value_dedup=false, promote_bytes_keys=false, ref_format="hex"
+))]
+#[allow(clippy::too_many_arguments)]
+fn decode_zodb_record_for_pg(
+    py: Python<'_>,
+    data: BytesLike<'_>,
+    strict: bool,
+    warnings: Option<&Bound<'_, PyList>>,
+    quotas: Option<&Bound<'_, PyClassQuotas>>,
+    lenient: bool,
+    py2_strings: &str,
+    shared_references: bool,
+    policy: Option<&Bound<'_, PyDecodePolicy>>,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    line_limits: Option<&Bound<'_, PyLineLimits>>,
+    limits: Option<&Bound<'_, PyDecodeLimits>>,
+    surrogates: &str,
+    nonfinite_floats: &str,
+    duplicate_keys: &str,
+    bigint_max_bits: Option<u32>,
+    detect_raw_tids: bool,
+    value_dedup: bool,
+    promote_bytes_keys: bool,
+    ref_format: &str,
+) -> PyResult<Py<PyAny>> {
+    let data = data.as_bytes();
+    let decode_options = decode_options(
+        quotas,
+        lenient,
+        py2_strings,
+        shared_references,
+        policy,
+        renames,
+        line_limits,
+        limits,
+        surrogates,
+    )?;
+    let _bytes_keys = bytes_keys::PromotionScope::enter(promote_bytes_keys);
+    let _nonfinite = floats::NonFiniteScope::enter(parse_nonfinite_floats(nonfinite_floats)?);
+    let _duplicates =
+        duplicate_keys::DuplicateKeysScope::enter(parse_duplicate_keys(duplicate_keys)?);
+    let _bigint = bigint::BigIntScope::enter(bigint::number_bits(bigint_max_bits)?);
+    let _raw_tids = known_types::RawTidScope::enter(detect_raw_tids);
+    let _dedup = dedup::DedupScope::enter(value_dedup);
+    let _ref_format = zodb::RefFormatScope::enter(parse_ref_format(ref_format)?);
+    let span = tracing::debug_span!(
+        "decode_zodb_record_for_pg",
+        size = data.len(),
+        module = tracing::field::Empty,
+        name = tracing::field::Empty,
+    );
+    let _entered = span.enter();
+    // Release GIL during pure-Rust pickle parsing + ref extraction.
+    // This allows other Python threads to run during the CPU-bound phase.
+    let (module, name, state_obj, refs) = with_warnings(py, warnings, || {
+        let (_class_val, state_val, module, name, refs) = py.detach(|| {
+            let (class_val, state_val) = decode_zodb_pickles_with_options(data, &decode_options)?;
+            if strict {
+                check_strict(&state_val)?;
+            }
+            let (module, name) = zodb::extract_class_info(&class_val);
+            let mut refs = Vec::new();
+            refscan::collect_refs_from_pickle_value(&state_val, &mut refs);
+            Ok::<_, PyErr>((class_val, state_val, module, name, refs))
+        })?;
+        span.record("module", module.as_str());
+        span.record("name", name.as_str());
+
+        // BTree-aware state conversion with null-byte sanitization + ref compaction
+        let options = RecordOptions {
+            pg_safe: true,
+            ..RecordOptions::DEFAULT
+        };
+        let state_obj = record_state_to_pyobject(py, &module, &name, &state_val, &options)?;
+        Ok::<_, PyErr>((module, name, state_obj, refs))
+    })?;
+
+    // Build result tuple: (class_mod, class_name, state, refs)
+    let refs_list = PyList::new(py, &refs)?;
+    let result = (
+        module.into_pyobject(py)?,
+        name.into_pyobject(py)?,
+        state_obj.into_bound(py),
+        refs_list.into_any(),
+    );
+    Ok(result.into_pyobject(py)?.into_any().unbind())
+}
+
+/// Decode a ZODB record for PostgreSQL JSONB storage, returning a JSON string.
+///
+/// Like `decode_zodb_record_for_pg` but the entire pipeline runs in Rust with
+/// the GIL released — no intermediate Python dicts are created.
+/// Returns: `(class_mod: str, class_name: str, state_json: str, refs: list[int])`
+/// `strict`, `warnings`, `lenient`, `py2_strings`, `shared_references`,
+/// `policy`, `renames`, `line_limits`, `limits`, `surrogates`,
+/// `nonfinite_floats`, `duplicate_keys`, `bigint_max_bits`,
+/// `detect_raw_tids` and `promote_bytes_keys` work as for `pickle_to_json`,
+/// `quotas` and `ref_format` as for `decode_zodb_record`.
+#[pyfunction]
+#[pyo3(signature = (
+    data, *, strict=false, warnings=None, quotas=None, lenient=false, py2_strings="bytes",
+    shared_references=false, policy=None, renames=None, line_limits=None, limits=None,
+    surrogates="error", nonfinite_floats="marker", duplicate_keys="last-wins", bigint_max_bits=None,
+    detect_raw_tids=false, promote_bytes_keys=false, ref_format="hex"
+))]
+#[allow(clippy::too_many_arguments)]
+fn decode_zodb_record_for_pg_json(
+    py: Python<'_>,
+    data: BytesLike<'_>,
+    strict: bool,
+    warnings: Option<&Bound<'_, PyList>>,
+    quotas: Option<&Bound<'_, PyClassQuotas>>,
+    lenient: bool,
+    py2_strings: &str,
+    shared_references: bool,
+    policy: Option<&Bound<'_, PyDecodePolicy>>,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    line_limits: Option<&Bound<'_, PyLineLimits>>,
+    limits: Option<&Bound<'_, PyDecodeLimits>>,
+    surrogates: &str,
+    nonfinite_floats: &str,
+    duplicate_keys: &str,
+    bigint_max_bits: Option<u32>,
+    detect_raw_tids: bool,
+    promote_bytes_keys: bool,
+    ref_format: &str,
+) -> PyResult<Py<PyAny>> {
+    let data = data.as_bytes();
+    let options = decode_options(
+        quotas,
+        lenient,
+        py2_strings,
+        shared_references,
+        policy,
+        renames,
+        line_limits,
+        limits,
+        surrogates,
+    )?;
+    let _bytes_keys = bytes_keys::PromotionScope::enter(promote_bytes_keys);
+    let _nonfinite = floats::NonFiniteScope::enter(parse_nonfinite_floats(nonfinite_floats)?);
+    let _duplicates =
+        duplicate_keys::DuplicateKeysScope::enter(parse_duplicate_keys(duplicate_keys)?);
+    let _bigint = bigint::BigIntScope::enter(bigint::number_bits(bigint_max_bits)?);
+    let _raw_tids = known_types::RawTidScope::enter(detect_raw_tids);
+    let _ref_format = zodb::RefFormatScope::enter(parse_ref_format(ref_format)?);
+    // ENTIRE pipeline runs with GIL released: pickle decode + JSON conversion
+    let record = with_warnings(py, warnings, || {
+        py.detach(|| batch::decode_for_pg_json(data, strict, &options))
+    })?;
+
+    // Only GIL-held work: build the 4-element return tuple
+    pg_json_tuple(py, record)
+}
+
+fn pg_json_tuple(py: Python<'_>, record: batch::PgJsonRecord) -> PyResult<Py<PyAny>> {
+    let refs_list = PyList::new(py, &record.refs)?;
+    let result = (
+        record.module.into_pyobject(py)?,
+        record.name.into_pyobject(py)?,
+        record.state_json.into_pyobject(py)?,
+        refs_list.into_any(),
+    );
+    Ok(result.into_pyobject(py)?.into_any().unbind())
+}
+
+/// Decode a batch of ZODB records for PostgreSQL JSONB storage without
+/// blocking the event loop.
+///
+/// Must be called from a coroutine. The records are decoded in parallel
+/// on a Rust thread pool with the GIL released; the returned asyncio
+/// future resolves to a list with one
+/// `(class_mod, class_name, state_json, refs)` tuple per record, as from
+/// `decode_zodb_record_for_pg_json`. If any record fails, the future
+/// raises `ValueError` naming the record's index. `quotas`, `lenient`,
+/// `py2_strings`, `shared_references`, `policy`, `renames`, `line_limits`,
+/// `limits`, `surrogates`, `nonfinite_floats`, `duplicate_keys`,
+/// `bigint_max_bits`, `detect_raw_tids` and `ref_format` work as for
+/// `decode_zodb_record`.
+#[pyfunction]
+#[pyo3(signature = (
+    records, *, quotas=None, lenient=false, py2_strings="bytes", shared_references=false,
+    policy=None, renames=None, line_limits=None, limits=None, surrogates="error",
+    nonfinite_floats="marker", duplicate_keys="last-wins", bigint_max_bits=None,
+    detect_raw_tids=false, ref_format="hex"
+))]
+#[allow(clippy::too_many_arguments)]
+fn decode_batch_async<'py>(
+    py: Python<'py>,
+    records: Vec<BytesLike<'py>>,
+    quotas: Option<&Bound<'py, PyClassQuotas>>,
+    lenient: bool,
+    py2_strings: &str,
+    shared_references: bool,
+    policy: Option<&Bound<'_, PyDecodePolicy>>,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    line_limits: Option<&Bound<'_, PyLineLimits>>,
+    limits: Option<&Bound<'_, PyDecodeLimits>>,
+    surrogates: &str,
+    nonfinite_floats: &str,
+    duplicate_keys: &str,
+    bigint_max_bits: Option<u32>,
+    detect_raw_tids: bool,
+    ref_format: &str,
+) -> PyResult<Bound<'py, PyAny>> {
+    let options = decode_options(
+        quotas,
+        lenient,
+        py2_strings,
+        shared_references,
+        policy,
+        renames,
+        line_limits,
+        limits,
+        surrogates,
+    )?;
+    let ref_format = parse_ref_format(ref_format)?;
+    let nonfinite_floats = parse_nonfinite_floats(nonfinite_floats)?;
+    let duplicate_keys = parse_duplicate_keys(duplicate_keys)?;
+    let bigint_bits = bigint::number_bits(bigint_max_bits)?;
+    let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+    let future = event_loop.call_method0("create_future")?;
+    // The worker outlives this call, so the batch is copied out of Python
+    let records: Vec<Vec<u8>> = records.iter().map(|b| b.as_bytes().to_vec()).collect();
+    let (event_loop, fut) = (event_loop.unbind(), future.clone().unbind());
+    batch::pool().spawn(move || {
+        let scopes = || {
+            (
+                zodb::RefFormatScope::enter(ref_format),
+                floats::NonFiniteScope::enter(nonfinite_floats),
+                duplicate_keys::DuplicateKeysScope::enter(duplicate_keys),
+                bigint::BigIntScope::enter(bigint_bits),
+                known_types::RawTidScope::enter(detect_raw_tids),
+            )
+        };
+        let outcome = batch::decode_batch_for_pg_json(&records, &options, scopes);
+        Python::attach(|py| {
+            let result = match outcome {
+                Ok(decoded) => decoded
+                    .into_iter()
+                    .map(|record| pg_json_tuple(py, record))
+                    .collect::<PyResult<Vec<_>>>()
+                    .and_then(|items| Ok(PyList::new(py, items)?.into_any().unbind())),
+                Err((index, e)) => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "record {index}: {e}"
+                ))),
+            };
+            let (value, error) = match result {
+                Ok(value) => (value, py.None()),
+                Err(e) => (py.None(), e.into_value(py).into_any()),
+            };
+            let scheduled = wrap_pyfunction!(resolve_future, py).and_then(|resolve| {
+                event_loop.call_method1(py, "call_soon_threadsafe", (resolve, fut, value, error))
+            });
+            // The loop was closed before the batch finished: nobody is
+            // waiting for the result any more
+            if let Err(e) = scheduled {
+                e.write_unraisable(py, None);
+            }
+        });
+    });
+    Ok(future)
+}
+
+/// Complete a `decode_batch_async` future on its event loop, unless it was
+/// cancelled meanwhile.
+#[pyfunction]
+fn resolve_future(future: &Bound<'_, PyAny>, value: &Bound<'_, PyAny>, error: &Bound<'_, PyAny>) -> PyResult<()> {
+    if future.call_method0("done")?.is_truthy()? {
+        return Ok(());
+    }
+    if error.is_none() {
+        future.call_method1("set_result", (value,))?;
+    } else {
+        future.call_method1("set_exception", (error,))?;
+    }
+    Ok(())
+}
+
+/// The `@cls_raw` class pickle of a record dict, if it is to be written.
+fn kept_class_pickle(
+    obj: &Bound<'_, PyDict>,
+    module: &str,
+    name: &str,
+) -> PyResult<Option<Vec<u8>>> {
+    let Some(raw) = obj.get_item(intern!(obj.py(), "@cls_raw"))? else {
+        return Ok(None);
+    };
+    let raw = if let Ok(b) = raw.cast::<PyBytes>() {
+        b.as_bytes().to_vec()
+    } else if let Ok(s) = raw.cast::<PyString>() {
+        binenc::b64_decode(s.to_str()?)?
+    } else {
+        let msg = "@cls_raw must be base64 text or bytes";
+        return Err(CodecError::InvalidData(msg.to_string()).into());
+    };
+    Ok(zodb::keeps_class_pickle(&raw, module, name)?.then_some(raw))
+}
+
+/// Split a ZODB JSON record into its `@cls` strings and `@s` state.
+/// A record marked `@blob` may leave both out.
+fn record_parts<'py>(
+    obj: &Bound<'py, PyDict>,
+) -> PyResult<(Bound<'py, PyString>, Bound<'py, PyString>, Bound<'py, PyAny>)> {
+    let py = obj.py();
+    let blob = match obj.get_item(intern!(py, "@blob"))? {
+        None => false,
+        Some(marker) if marker.cast::<PyBool>().is_ok_and(|b| b.is_true()) => true,
+        Some(_) => return Err(CodecError::InvalidData("@blob must be true".to_string()).into()),
+    };
+    if blob && !obj.contains(intern!(py, "@cls"))? {
+        let state_is_none = obj.get_item(intern!(py, "@s"))?.is_none_or(|s| s.is_none());
+        zodb::check_blob_record(None, state_is_none)?;
+        return Ok((
+            intern!(py, "ZODB.blob").clone(),
+            intern!(py, "Blob").clone(),
+            py.None().into_bound(py),
+        ));
+    }
+    let cls_val = obj
+        .get_item(intern!(py, "@cls"))?
+        .ok_or_else(|| CodecError::InvalidData("missing @cls in ZODB record".to_string()))?;
+    let cls_list = cls_val.cast::<PyList>().map_err(|_| {
+        CodecError::InvalidData("@cls must be a list".to_string())
+    })?;
+    if cls_list.len() != 2 {
+        return Err(CodecError::InvalidData("@cls must be [module, name]".to_string()).into());
+    }
+
+    let module = cls_list.get_item(0)?.cast_into::<PyString>()
+        .map_err(|_| CodecError::InvalidData("@cls[0] must be a string".to_string()))?;
+    let name = cls_list.get_item(1)?.cast_into::<PyString>()
+        .map_err(|_| CodecError::InvalidData("@cls[1] must be a string".to_string()))?;
+
+    // Get state
+    let state_obj = obj
+        .get_item(intern!(py, "@s"))?
+        .unwrap_or_else(|| py.None().into_bound(py));
+    if blob {
+        zodb::check_blob_record(Some((module.to_str()?, name.to_str()?)), state_obj.is_none())?;
+    }
+    Ok((module, name, state_obj))
+}
+
+/// Encode a ZODB JSON record back into two concatenated pickles.
+/// Uses the direct Py<PyAny> → pickle encoder, bypassing PickleValue allocations.
+///
+/// With a `new_oid() -> bytes` callable, returns `(record, buckets)`: a
+/// BTree or TreeSet with more items than fit in one bucket (or than
+/// `bucket_size`) is split into bucket records, listed as `(oid, record)`
+/// pairs under oids from `new_oid`. `buckets` is empty for other records.
+///
+/// With `pg_safe=True`, the state is read as the PostgreSQL form of
+/// `decode_zodb_record_for_pg`: `{"@ns": base64}` markers and `"@ns:"`
+/// keys become strings with null bytes again. Otherwise they are
+/// ordinary data. `drop_dangling`, `renames`, `raw_pickle_policy` and
+/// `limits` work as for `json_to_pickle`.
+#[pyfunction]
+#[pyo3(signature = (
+    obj, *, new_oid=None, bucket_size=None, pg_safe=false, drop_dangling=false, renames=None,
+    raw_pickle_policy=None, limits=None
+))]
+#[allow(clippy::too_many_arguments)]
+fn encode_zodb_record(
+    py: Python<'_>,
+    obj: &Bound<'_, PyDict>,
+    new_oid: Option<&Bound<'_, PyAny>>,
+    bucket_size: Option<usize>,
+    pg_safe: bool,
+    drop_dangling: bool,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    raw_pickle_policy: Option<&Bound<'_, PyRawPicklePolicy>>,
+    limits: Option<&Bound<'_, PyEncodeLimits>>,
+) -> PyResult<Py<PyAny>> {
+    let _pg_safe = null_strings::PgSafeScope::enter(pg_safe);
+    let _dangling = dangling::DropDanglingScope::enter(drop_dangling);
+    let _renames = rename::EncodeRenamesScope::enter(encode_renames(renames));
+    let _raw_pickle =
+        raw_pickle::RawPicklePolicyScope::enter(raw_pickle_policy.map(|p| Arc::clone(&p.get().0)));
+    let _limits = limits::EncodeLimitsScope::enter(encode_limits(limits));
+    let (module, name, state_obj) = record_parts(obj)?;
+    // Borrow module/name as &str from Python (zero-copy)
+    let (module, name) = (module.to_str()?, name.to_str()?);
+    let Some(new_oid) = new_oid else {
+        if bucket_size.is_some() {
+            return Err(CodecError::InvalidData("bucket_size needs new_oid".to_string()).into());
+        }
+        return Ok(encode_zodb_record_impl(py, obj, module, name, &state_obj)?.into_any());
+    };
+    if let Some(info) = btrees::classify_btree(module, name) {
+        let state = pyconv::btree_state_from_pyobject(&info, &state_obj, true)?;
+        let next_oid = || -> PyResult<Vec<u8>> {
+            Ok(new_oid.call0()?.extract::<BytesLike<'_>>()?.as_bytes().to_vec())
+        };
+        if let Some(split) = split_btree(module, name, &info, &state, bucket_size, next_oid)? {
+            let record = batch::encode_record(&batch::RecordToEncode {
+                module: module.to_string(),
+                name: name.to_string(),
+                state: split.state,
+                class_pickle: kept_class_pickle(obj, module, name)?,
+            })?;
+            let buckets = split
+                .buckets
+                .iter()
+                .map(|(oid, bucket)| (PyBytes::new(py, oid), PyBytes::new(py, bucket)))
+                .collect::<Vec<_>>();
+            return Ok((PyBytes::new(py, &record), buckets).into_pyobject(py)?.into_any().unbind());
+        }
+    }
+    let record = encode_zodb_record_impl(py, obj, module, name, &state_obj)?;
+    let buckets = PyList::empty(py);
+    Ok((record, buckets).into_pyobject(py)?.into_any().unbind())
+}
+
+fn encode_zodb_record_impl(
+    py: Python<'_>,
+    obj: &Bound<'_, PyDict>,
+    module: &str,
+    name: &str,
+    state_obj: &Bound<'_, PyAny>,
+) -> PyResult<Py<PyBytes>> {
+
+    let span = tracing::debug_span!(
+        "encode_zodb_record",
+        module,
+        name,
+        size = tracing::field::Empty,
+    );
+    let _entered = span.enter();
+    if zodb::is_blob(module, name, state_obj.is_none()) {
+        return Ok(PyBytes::new(py, zodb::BLOB_RECORD).into());
+    }
+    // Direct encode: class pickle + state pickle, no PickleValue intermediates
+    let class_pickle = kept_class_pickle(obj, module, name)?;
+    let class_pickle = class_pickle.as_deref();
+    let result = pyconv::encode_zodb_record_direct(module, name, state_obj, class_pickle)?;
+    span.record("size", result.len());
+    Ok(PyBytes::new(py, &result).into())
+}
+
+/// Encode a list of ZODB JSON records, as from `encode_zodb_record`.
+///
+/// The dicts are converted to pickle trees first; then all records are
+/// encoded in parallel with the GIL released. Returns one `bytes` per
+/// record, in order. A failing record raises `ValueError` naming its
+/// index. `pg_safe`, `drop_dangling`, `renames`, `raw_pickle_policy` and
+/// `limits` work as for `encode_zodb_record`.
+#[pyfunction]
+#[pyo3(signature = (
+    records, *, pg_safe=false, drop_dangling=false, renames=None, raw_pickle_policy=None,
+    limits=None
+))]
+fn encode_zodb_records_batch(
+    py: Python<'_>,
+    records: Vec<Bound<'_, PyDict>>,
+    pg_safe: bool,
+    drop_dangling: bool,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    raw_pickle_policy: Option<&Bound<'_, PyRawPicklePolicy>>,
+    limits: Option<&Bound<'_, PyEncodeLimits>>,
+) -> PyResult<Vec<Py<PyBytes>>> {
+    // The dicts are converted on this thread, before the parallel encoding
+    let _pg_safe = null_strings::PgSafeScope::enter(pg_safe);
+    let _dangling = dangling::DropDanglingScope::enter(drop_dangling);
+    let renames = encode_renames(renames);
+    let _renames = rename::EncodeRenamesScope::enter(renames.clone());
+    let _raw_pickle =
+        raw_pickle::RawPicklePolicyScope::enter(raw_pickle_policy.map(|p| Arc::clone(&p.get().0)));
+    let limits = encode_limits(limits);
+    let _limits = limits::EncodeLimitsScope::enter(limits);
+    let in_record = |index: usize, e: PyErr| {
+        pyo3::exceptions::PyValueError::new_err(format!("record {index}: {}", e.value(py)))
+    };
+    let prepare = |obj: &Bound<'_, PyDict>| -> PyResult<batch::RecordToEncode> {
+        let (module, name, state_obj) = record_parts(obj)?;
+        let (module, name) = (module.to_str()?, name.to_str()?);
+        let state = pyconv::record_state_from_pyobject(module, name, &state_obj)?;
+        Ok(batch::RecordToEncode {
+            module: module.to_string(),
+            name: name.to_string(),
+            state,
+            class_pickle: kept_class_pickle(obj, module, name)?,
+        })
+    };
+    let prepared = records
+        .iter()
+        .enumerate()
+        .map(|(i, obj)| prepare(obj).map_err(|e| in_record(i, e)))
+        .collect::<PyResult<Vec<_>>>()?;
+    let _span = tracing::debug_span!("encode_zodb_records_batch", count = prepared.len()).entered();
+    let encoded = py
+        .detach(|| {
+            batch::encode_batch(&prepared, || {
+                (
+                    rename::EncodeRenamesScope::enter(renames.clone()),
+                    limits::EncodeLimitsScope::enter(limits),
+                )
+            })
+        })
+        .map_err(|(index, e)| in_record(index, e.into()))?;
+    Ok(encoded.iter().map(|data| PyBytes::new(py, data).unbind()).collect())
+}
+
+/// Count the persistent references in a pickle or ZODB record without
+/// decoding it.
+#[pyfunction(name = "count_refs")]
+fn py_count_refs(py: Python<'_>, data: BytesLike<'_>) -> PyResult<usize> {
+    let data = data.as_bytes();
+    Ok(py.detach(|| count_refs(data))?)
+}
+
+/// Return whether a pickle or ZODB record references `oid` (8 bytes or
+/// an int), without decoding it.
+#[pyfunction(name = "has_ref_to")]
+fn py_has_ref_to(py: Python<'_>, data: BytesLike<'_>, oid: &Bound<'_, PyAny>) -> PyResult<bool> {
+    let data = data.as_bytes();
+    let oid = oid_arg(oid)?;
+    Ok(py.detach(|| has_ref_to(data, &oid))?)
+}
+
+/// An OID argument given as 8 bytes or an int.
+fn oid_arg(oid: &Bound<'_, PyAny>) -> PyResult<[u8; 8]> {
+    if let Ok(b) = oid.cast::<PyBytes>() {
+        Ok(b.as_bytes()
+            .try_into()
+            .map_err(|_| CodecError::InvalidData("oid must be 8 bytes".to_string()))?)
+    } else {
+        Ok(oid.extract::<u64>()?.to_be_bytes())
+    }
+}
+
+/// Format an OID (8 bytes or an int) as 16 lowercase hex digits, the
+/// `@ref` form.
+#[pyfunction(name = "oid_to_hex")]
+fn py_oid_to_hex(oid: &Bound<'_, PyAny>) -> PyResult<String> {
+    Ok(oid_to_hex(&oid_arg(oid)?))
+}
+
+/// Parse hex digits (optionally `0x`-prefixed, zero-padded to 16) into
+/// an 8-byte OID.
+#[pyfunction(name = "hex_to_oid")]
+fn py_hex_to_oid(py: Python<'_>, hex: &str) -> PyResult<Py<PyBytes>> {
+    Ok(PyBytes::new(py, &hex_to_oid(hex)?).into())
+}
+
+/// The POSIX timestamp of an 8-byte TID, as `TimeStamp.timeTime()`.
+#[pyfunction(name = "tid_to_timestamp")]
+fn py_tid_to_timestamp(tid: &[u8]) -> PyResult<f64> {
+    let tid: &[u8; 8] = tid
+        .try_into()
+        .map_err(|_| CodecError::InvalidData("tid must be 8 bytes".to_string()))?;
+    Ok(tid_to_timestamp(tid))
+}
+
+/// The 8-byte TID of a POSIX timestamp, as ZODB builds it from
+/// `time.time()`.
+#[pyfunction(name = "timestamp_to_tid")]
+fn py_timestamp_to_tid(py: Python<'_>, timestamp: f64) -> PyResult<Py<PyBytes>> {
+    Ok(PyBytes::new(py, &timestamp_to_tid(timestamp)?).into())
+}
+
+/// List every persistent reference in a ZODB record's state as
+/// `(oid, cls, db)` tuples: `oid` is bytes of any width, `cls` a
+/// `(module, name)` tuple or `None`, and `db` the database name of a
+/// cross-database reference or `None`. Weak references are included.
+#[pyfunction(name = "collect_refs_ex")]
+fn py_collect_refs_ex<'py>(py: Python<'py>, data: BytesLike<'_>) -> PyResult<Bound<'py, PyList>> {
+    let data = data.as_bytes();
+    let refs = py.detach(|| {
+        let (_class_val, state_val) = decode_zodb_pickles(data)?;
+        Ok::<_, CodecError>(collect_refs_ex(&state_val))
+    })?;
+    let items = refs
+        .into_iter()
+        .map(|r| (PyBytes::new(py, &r.oid), r.class, r.database))
+        .collect::<Vec<_>>();
+    PyList::new(py, items)
+}
+
+/// The outgoing reference edges of the record `record` stored under
+/// `oid` (8 bytes or an int), as `(source, target, class)` tuples: oids
+/// as ints and the dotted target class, or `""` when the reference
+/// carries none. Cross-database references are skipped.
+#[pyfunction(name = "record_refs_to_edges")]
+fn py_record_refs_to_edges<'py>(
+    py: Python<'py>,
+    oid: &Bound<'_, PyAny>,
+    record: BytesLike<'_>,
+) -> PyResult<Bound<'py, PyList>> {
+    let oid = u64::from_be_bytes(oid_arg(oid)?);
+    let record = record.as_bytes();
+    let edges = py.detach(|| record_refs_to_edges(oid, record))?;
+    PyList::new(py, edges.into_iter().map(|e| (e.source, e.target, e.class)))
+}
+
+/// The reference edges of an iterable of `(oid, record)` pairs, like
+/// `record_refs_to_edges`, with unknown target classes filled in from the
+/// records. When given, `dot` and `csv` are text files the edges are also
+/// written to as a Graphviz digraph and as CSV.
+#[pyfunction(name = "record_refs_to_edges_batch")]
+#[pyo3(signature = (records, *, dot=None, csv=None))]
+fn py_record_refs_to_edges_batch<'py>(
+    py: Python<'py>,
+    records: &Bound<'_, PyAny>,
+    dot: Option<&Bound<'_, PyAny>>,
+    csv: Option<&Bound<'_, PyAny>>,
+) -> PyResult<Bound<'py, PyList>> {
+    let mut items = Vec::new();
+    for item in records.try_iter()? {
+        let (oid, data): (Bound<'_, PyAny>, Bound<'_, PyBytes>) = item?.extract()?;
+        items.push((u64::from_be_bytes(oid_arg(&oid)?), data));
+    }
+    let records: Vec<(u64, &[u8])> = items.iter().map(|(oid, data)| (*oid, data.as_bytes())).collect();
+    let edges = py.detach(|| record_refs_to_edges_batch(&records)).map_err(|(index, e)| {
+        pyo3::exceptions::PyValueError::new_err(format!("record {index}: {e}"))
+    })?;
+    if let Some(dot) = dot {
+        let mut buf = Vec::new();
+        write_edges_dot(&edges, &mut buf).map_err(CodecError::from)?;
+        dot.call_method1(intern!(py, "write"), (String::from_utf8_lossy(&buf),))?;
+    }
+    if let Some(csv) = csv {
+        let mut buf = Vec::new();
+        write_edges_csv(&edges, &mut buf).map_err(CodecError::from)?;
+        csv.call_method1(intern!(py, "write"), (String::from_utf8_lossy(&buf),))?;
+    }
+    PyList::new(py, edges.into_iter().map(|e| (e.source, e.target, e.class)))
+}
+
+/// The oids reachable from `root_oid` (8 bytes or an int), loading each
+/// record once with `load(oid)`, which gets an int and returns the record
+/// bytes, or `None` when there is no record. Such dangling oids are
+/// appended to `missing` when given. Decoding runs without the GIL.
+#[pyfunction(name = "reachable_oids")]
+#[pyo3(signature = (root_oid, load, *, missing=None))]
+fn py_reachable_oids<'py>(
+    py: Python<'py>,
+    root_oid: &Bound<'_, PyAny>,
+    load: Py<PyAny>,
+    missing: Option<&Bound<'_, PyList>>,
+) -> PyResult<Bound<'py, PySet>> {
+    /// A loader failure, or a record that failed to decode.
+    enum Error {
+        Load(PyErr),
+        Decode(CodecError),
+    }
+    impl From<CodecError> for Error {
+        fn from(e: CodecError) -> Self {
+            Error::Decode(e)
+        }
+    }
+    let root = u64::from_be_bytes(oid_arg(root_oid)?);
+    let mut current = root;
+    let found = py
+        .detach(|| {
+            reachable_oids(root, |oid| {
+                current = oid;
+                Python::attach(|py| {
+                    let data = load.call1(py, (oid,)).map_err(Error::Load)?;
+                    let data: Option<BytesLike<'_>> = data.extract(py).map_err(Error::Load)?;
+                    Ok(data.map(|d| d.as_bytes().to_vec()))
+                })
+            })
+        })
+        .map_err(|e| match e {
+            Error::Load(e) => e,
+            Error::Decode(e) => pyo3::exceptions::PyValueError::new_err(format!("oid {current}: {e}")),
+        })?;
+    if let Some(missing) = missing {
+        for oid in found.missing {
+            missing.append(oid)?;
+        }
+    }
+    PySet::new(py, found.oids)
+}
+
+/// Collect statistics about a pickle or ZODB record without decoding it.
+///
+/// Returns a dict with `size`, `pickles`, `opcodes` (count per opcode
+/// name), `max_stack_depth`, `memo_size`, `persistent_refs`,
+/// `max_string`, `max_bytes` and `classes` (a list of `(module, name)`
+/// tuples in order of first appearance).
+#[pyfunction(name = "analyze_pickle")]
+fn py_analyze_pickle(py: Python<'_>, data: BytesLike<'_>) -> PyResult<Py<PyAny>> {
+    let data = data.as_bytes();
+    let stats = py.detach(|| analyze_pickle(data))?;
+    let dict = PyDict::new(py);
+    dict.set_item("size", stats.size)?;
+    dict.set_item("pickles", stats.pickles)?;
+    let opcodes = PyDict::new(py);
+    for (name, count) in &stats.opcodes {
+        opcodes.set_item(name, count)?;
+    }
+    dict.set_item("opcodes", opcodes)?;
+    dict.set_item("max_stack_depth", stats.max_stack_depth)?;
+    dict.set_item("memo_size", stats.memo_size)?;
+    dict.set_item("persistent_refs", stats.persistent_refs)?;
+    dict.set_item("max_string", stats.max_string)?;
+    dict.set_item("max_bytes", stats.max_bytes)?;
+    dict.set_item("classes", PyList::new(py, stats.classes)?)?;
+    Ok(dict.into_any().unbind())
+}
+
+/// Estimate the decoded size of a pickle or ZODB record without decoding it.
+///
+/// Returns a dict with `json_bytes` (approximate length of the compact
+/// JSON), `nodes` (values in the decoded tree) and `markers` (count per
+/// marker, e.g. `{"@ref": 12, "@dt": 3}`).
+#[pyfunction(name = "estimate_decoded_size")]
+fn py_estimate_decoded_size(py: Python<'_>, record: BytesLike<'_>) -> PyResult<Py<PyAny>> {
+    let record = record.as_bytes();
+    let estimate = py.detach(|| estimate_decoded_size(record))?;
+    let dict = PyDict::new(py);
+    dict.set_item("json_bytes", estimate.json_bytes)?;
+    dict.set_item("nodes", estimate.nodes)?;
+    let markers = PyDict::new(py);
+    for (marker, count) in &estimate.markers {
+        markers.set_item(marker, count)?;
+    }
+    dict.set_item("markers", markers)?;
+    Ok(dict.into_any().unbind())
+}
+
+/// List the classes a pickle or ZODB record references without decoding it.
+///
+/// Returns `(module, name)` tuples in order of first appearance: the class
+/// of the record, GLOBAL/STACK_GLOBAL/INST references and the class hints
+/// of persistent references.
+#[pyfunction(name = "find_class_references")]
+fn py_find_class_references<'py>(
+    py: Python<'py>,
+    data: BytesLike<'_>,
+) -> PyResult<Bound<'py, PyList>> {
+    let data = data.as_bytes();
+    let classes = py.detach(|| find_class_references(data))?;
+    PyList::new(py, classes)
+}
+
+/// Lint a ZODB record for patterns that cause trouble downstream.
+///
+/// Returns a list of `{"code", "path", "message"}` dicts; see the
+/// `lint` module for the codes.
+#[pyfunction(name = "lint_record")]
+#[pyo3(signature = (data, *, max_string_len=DEFAULT_LINT_MAX_STRING, max_depth=DEFAULT_LINT_MAX_DEPTH))]
+fn py_lint_record<'py>(
+    py: Python<'py>,
+    data: BytesLike<'_>,
+    max_string_len: usize,
+    max_depth: usize,
+) -> PyResult<Bound<'py, PyList>> {
+    let data = data.as_bytes();
+    let options = LintOptions {
+        max_string_len,
+        max_depth,
+    };
+    let warnings = py.detach(|| lint_record(data, &options))?;
+    let list = PyList::empty(py);
+    for w in warnings {
+        let dict = PyDict::new(py);
+        dict.set_item("code", w.code.as_str())?;
+        dict.set_item("path", w.path)?;
+        dict.set_item("message", w.message)?;
+        list.append(dict)?;
+    }
+    Ok(list)
+}
+
+/// Extract the value at `path` in a ZODB record's state as a standalone
+/// pickle. Persistent references are preserved as-is.
+#[pyfunction(name = "extract_subtree")]
+fn py_extract_subtree(py: Python<'_>, data: BytesLike<'_>, path: &str) -> PyResult<Py<PyBytes>> {
+    let data = data.as_bytes();
+    let bytes = py.detach(|| extract_subtree(data, path))?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// Iterator returned by `iter_pickle_events()`.
+#[pyclass(name = "PickleEventIterator", module = "zodb_json_codec")]
+struct PyPickleEvents {
+    events: PickleEvents,
+    /// Whether persistent references use the compact record form.
+    compact_refs: bool,
+}
+
+#[pymethods]
+impl PyPickleEvents {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyTuple>>> {
+        let Some(event) = self.events.next() else {
+            return Ok(None);
+        };
+        let convert = |val: &PickleValue| pyconv::pickle_value_to_pyobject(py, val, self.compact_refs);
+        let (kind, value): (&str, Py<PyAny>) = match event {
+            PickleEvent::Class { module, name } => ("class", (module, name).into_pyobject(py)?.into_any().unbind()),
+            PickleEvent::StartDict => ("start_dict", py.None()),
+            PickleEvent::Key(key) => ("key", convert(&key)?),
+            PickleEvent::EndDict => ("end_dict", py.None()),
+            PickleEvent::StartList => ("start_list", py.None()),
+            PickleEvent::EndList => ("end_list", py.None()),
+            PickleEvent::StartTuple => ("start_tuple", py.None()),
+            PickleEvent::EndTuple => ("end_tuple", py.None()),
+            PickleEvent::StartSet => ("start_set", py.None()),
+            PickleEvent::EndSet => ("end_set", py.None()),
+            PickleEvent::StartFrozenSet => ("start_frozenset", py.None()),
+            PickleEvent::EndFrozenSet => ("end_frozenset", py.None()),
+            PickleEvent::StartInstance { module, name } => {
+                ("start_instance", (module, name).into_pyobject(py)?.into_any().unbind())
+            }
+            PickleEvent::EndInstance => ("end_instance", py.None()),
+            PickleEvent::None => ("none", py.None()),
+            PickleEvent::Bool(b) => ("bool", convert(&PickleValue::Bool(b))?),
+            PickleEvent::Int(i) => ("int", i.into_pyobject(py)?.into_any().unbind()),
+            PickleEvent::BigInt(i) => {
+                // A real int, not the `@bi` marker of the dict form
+                let int = py.get_type::<PyInt>().call1((i.to_string(),))?;
+                ("int", int.unbind())
+            }
+            PickleEvent::Float(f) => ("float", f.into_pyobject(py)?.into_any().unbind()),
+            PickleEvent::Str(s) => ("str", s.into_pyobject(py)?.into_any().unbind()),
+            PickleEvent::Bytes(b) => ("bytes", PyBytes::new(py, &b).into_any().unbind()),
+            PickleEvent::PersistentRef(pid) => {
+                let val = convert(&PickleValue::PersistentRef(Box::new(pid)))?;
+                let inner = val.bind(py).get_item("@ref")?.unbind();
+                ("ref", inner)
+            }
+            PickleEvent::Global { module, name } => {
+                ("global", (module, name).into_pyobject(py)?.into_any().unbind())
+            }
+            PickleEvent::Value(val) => ("value", convert(&val)?),
+        };
+        Ok(Some(PyTuple::new(py, [kind.into_pyobject(py)?.into_any().unbind(), value])?))
+    }
+}
+
+/// Iterate over the contents of a pickle or ZODB record as
+/// `(kind, value)` event tuples, without building the dict.
+#[pyfunction(name = "iter_pickle_events")]
+fn py_iter_pickle_events(py: Python<'_>, data: BytesLike<'_>) -> PyResult<PyPickleEvents> {
+    let data = data.as_bytes();
+    let events = py.detach(|| pickle_events(data))?;
+    let compact_refs = events.is_record();
+    Ok(PyPickleEvents {
+        events,
+        compact_refs,
+    })
+}
+
+/// Look up a few paths (`"@s/title"`, `"@s/@kv/0/1"`) in a ZODB record
+/// without converting the rest of it. Returns a dict of the paths found.
+#[pyfunction(name = "extract_paths")]
+fn py_extract_paths<'py>(
+    py: Python<'py>,
+    record: BytesLike<'_>,
+    paths: Vec<String>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let record = record.as_bytes();
+    let found = py.detach(|| extract_paths(record, &paths))?;
+    let result = PyDict::new(py);
+    for (path, value) in paths.iter().zip(found) {
+        if let Some(value) = value {
+            result.set_item(path, pyconv::json_value_to_pyobject(py, &value)?)?;
+        }
+    }
+    Ok(result)
+}
+
+/// Return a copy of the ZODB record `dst` with the value at `path` replaced
+/// by the standalone pickle `src` (e.g. from `extract_subtree()`).
+#[pyfunction(name = "graft_subtree")]
+fn py_graft_subtree(
+    py: Python<'_>,
+    dst: BytesLike<'_>,
+    path: &str,
+    src: BytesLike<'_>,
+) -> PyResult<Py<PyBytes>> {
+    let (dst, src) = (dst.as_bytes(), src.as_bytes());
+    let bytes = py.detach(|| graft_subtree(dst, path, src))?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// Compare two ZODB records by their decoded JSON form.
+///
+/// Returns `{"added": [...], "removed": [...], "changed": [...]}` with
+/// `{"path", "value"}` and `{"path", "old", "new"}` entries; BTree `@kv`
+/// items are compared by key.
+#[pyfunction(name = "diff_zodb_records")]
+fn py_diff_zodb_records<'py>(
+    py: Python<'py>,
+    a: BytesLike<'_>,
+    b: BytesLike<'_>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let diff = py.detach(|| diff_zodb_records(a, b))?;
+    let entries = |items: Vec<(String, serde_json::Value)>| -> PyResult<Bound<'py, PyList>> {
+        let list = PyList::empty(py);
+        for (path, value) in items {
+            let entry = PyDict::new(py);
+            entry.set_item("path", path)?;
+            entry.set_item("value", pyconv::json_value_to_pyobject(py, &value)?)?;
+            list.append(entry)?;
+        }
+        Ok(list)
+    };
+    let changed = PyList::empty(py);
+    for (path, old, new) in diff.changed {
+        let entry = PyDict::new(py);
+        entry.set_item("path", path)?;
+        entry.set_item("old", pyconv::json_value_to_pyobject(py, &old)?)?;
+        entry.set_item("new", pyconv::json_value_to_pyobject(py, &new)?)?;
+        changed.append(entry)?;
+    }
+    let result = PyDict::new(py);
+    result.set_item("added", entries(diff.added)?)?;
+    result.set_item("removed", entries(diff.removed)?)?;
+    result.set_item("changed", changed)?;
+    Ok(result)
+}
+
+/// Check that a ZODB record survives the JSONB round trip: decode, encode
+/// the JSON back to a record, decode again and compare the two trees.
+///
+/// Returns `{"equal": bool, "path": str | None, "reason": str | None}`
+/// with the path and description of the first difference.
+/// `nonfinite_floats` works as for `pickle_to_json`.
+#[pyfunction(name = "verify_roundtrip")]
+#[pyo3(signature = (record, *, nonfinite_floats="marker"))]
+fn py_verify_roundtrip<'py>(
+    py: Python<'py>,
+    record: BytesLike<'_>,
+    nonfinite_floats: &str,
+) -> PyResult<Bound<'py, PyDict>> {
+    let record = record.as_bytes();
+    let _nonfinite = floats::NonFiniteScope::enter(parse_nonfinite_floats(nonfinite_floats)?);
+    let mismatch = py.detach(|| verify_roundtrip(record))?;
+    let result = PyDict::new(py);
+    result.set_item("equal", mismatch.is_none())?;
+    result.set_item("path", mismatch.as_ref().map(|m| m.path.as_str()))?;
+    result.set_item("reason", mismatch.as_ref().map(|m| m.reason.as_str()))?;
+    Ok(result)
+}
+
+/// Apply a JSON Patch (list of RFC 6902 operation dicts) to a ZODB record
+/// and return the re-encoded record. Paths point into the
+/// `decode_zodb_record()` form, e.g. `/@s/title`.
+#[pyfunction(name = "apply_patch_to_record")]
+fn py_apply_patch_to_record(
+    py: Python<'_>,
+    record: BytesLike<'_>,
+    patch: &Bound<'_, PyList>,
+) -> PyResult<Py<PyBytes>> {
+    let patch = pyconv::pyobject_to_json_value(patch.as_any())?;
+    let record = record.as_bytes();
+    let bytes = py.detach(|| apply_patch_to_record(record, &patch))?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// Read the records of a ZEO client cache file.
+///
+/// Returns a list of dicts with `oid`, `start_tid`, `end_tid` (`None` for
+/// current revisions) and `data` (the raw record). With `decode=True`,
+/// `data` is replaced by `record`, the `decode_zodb_record()` dict.
+#[pyfunction]
+#[pyo3(signature = (path, *, decode=false))]
+fn read_zeo_cache<'py>(
+    py: Python<'py>,
+    path: std::path::PathBuf,
+    decode: bool,
+) -> PyResult<Bound<'py, PyList>> {
+    let file = std::fs::File::open(&path).map_err(CodecError::from)?;
+    // SAFETY: read-only mapping; ZEO must not be writing the cache file
+    // while it is analyzed.
+    let mmap = unsafe { memmap2::Mmap::map(&file) }.map_err(CodecError::from)?;
+    let cache = ZeoCache::parse(&mmap)?;
+    let list = PyList::empty(py);
+    for record in cache.records() {
+        let record = record?;
+        let dict = PyDict::new(py);
+        dict.set_item("oid", PyBytes::new(py, &record.oid))?;
+        dict.set_item("start_tid", PyBytes::new(py, &record.start_tid))?;
+        dict.set_item("end_tid", record.end_tid.map(|t| PyBytes::new(py, &t)))?;
+        if decode {
+            let decoded = decode_zodb_record_impl(
+                py,
+                record.data,
+                Some(&record.start_tid),
+                &RecordOptions::DEFAULT,
+            )?;
+            dict.set_item("record", decoded)?;
+        } else {
+            dict.set_item("data", PyBytes::new(py, record.data))?;
+        }
+        list.append(dict)?;
+    }
+    Ok(list)
+}
+
+/// Decode the objects stored by one FileStorage transaction record.
+///
+/// Returns a list of dicts, one per data record, with `oid` and `tid`
+/// (8-byte `bytes`), the file positions `prev` and `tloc`, `backpointer`
+/// (a file position, or `None` when the record holds its own data) and
+/// `record`, the `decode_zodb_record()` dict or `None` for back pointers.
+#[pyfunction(name = "decode_transaction")]
+fn py_decode_transaction<'py>(py: Python<'py>, data: BytesLike<'_>) -> PyResult<Bound<'py, PyList>> {
+    let txn = Transaction::parse(data.as_bytes())?;
+    let list = PyList::empty(py);
+    for record in txn.records() {
+        let record = record?;
+        let decoded = match record.backpointer {
+            Some(_) => None,
+            None => {
+                let options = &RecordOptions::DEFAULT;
+                let tid = Some(&record.tid);
+                let decoded = decode_zodb_record_impl(py, record.data, tid, options).map_err(|e| {
+                    pyo3::exceptions::PyValueError::new_err(format!(
+                        "oid 0x{}: {}",
+                        binenc::hex_encode(record.oid),
+                        e.value(py)
+                    ))
+                })?;
+                Some(decoded)
+            }
+        };
+        let dict = PyDict::new(py);
+        dict.set_item("oid", PyBytes::new(py, &record.oid))?;
+        dict.set_item("tid", PyBytes::new(py, &record.tid))?;
+        dict.set_item("prev", record.prev)?;
+        dict.set_item("tloc", record.tloc)?;
+        dict.set_item("backpointer", record.backpointer)?;
+        dict.set_item("record", decoded)?;
+        list.append(dict)?;
+    }
+    Ok(list)
+}
+
+/// Iterate over the object revisions of a FileStorage (`Data.fs`) file.
+///
+/// The file is memory-mapped and read one transaction at a time. Yields
+/// `(oid, tid, data)` tuples in file order, with back pointers resolved;
+/// `data` is the raw record, or with `decode=True` the
+/// `decode_zodb_record()` dict, and `None` for revisions that undid an
+/// object's creation. `lenient`, `py2_strings`, `shared_references`,
+/// `policy`, `renames`, `line_limits`, `limits`, `surrogates`,
+/// `nonfinite_floats`, `duplicate_keys`, `bigint_max_bits`,
+/// `detect_raw_tids`, `value_dedup` and `ref_format` apply to the decoding
+/// as for `decode_zodb_record`.
+#[pyfunction(name = "open_filestorage")]
+#[pyo3(signature = (
+    path, *, decode=false, lenient=false, py2_strings="bytes", shared_references=false, policy=None,
+    renames=None, line_limits=None, limits=None, surrogates="error", nonfinite_floats="marker",
+    duplicate_keys="last-wins", bigint_max_bits=None, detect_raw_tids=false, value_dedup=false,
+    ref_format="hex"
+))]
+#[allow(clippy::too_many_arguments)]
+fn py_open_filestorage(
+    path: std::path::PathBuf,
+    decode: bool,
+    lenient: bool,
+    py2_strings: &str,
+    shared_references: bool,
+    policy: Option<&Bound<'_, PyDecodePolicy>>,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    line_limits: Option<&Bound<'_, PyLineLimits>>,
+    limits: Option<&Bound<'_, PyDecodeLimits>>,
+    surrogates: &str,
+    nonfinite_floats: &str,
+    duplicate_keys: &str,
+    bigint_max_bits: Option<u32>,
+    detect_raw_tids: bool,
+    value_dedup: bool,
+    ref_format: &str,
+) -> PyResult<PyFileStorageIterator> {
+    let options = decode_options(
+        None,
+        lenient,
+        py2_strings,
+        shared_references,
+        policy,
+        renames,
+        line_limits,
+        limits,
+        surrogates,
+    )?;
+    let ref_format = parse_ref_format(ref_format)?;
+    let nonfinite_floats = parse_nonfinite_floats(nonfinite_floats)?;
+    let duplicate_keys = parse_duplicate_keys(duplicate_keys)?;
+    let bigint_bits = bigint::number_bits(bigint_max_bits)?;
+    let file = std::fs::File::open(&path).map_err(CodecError::from)?;
+    // SAFETY: read-only mapping; FileStorage only appends to Data.fs, and
+    // the file must not be packed while it is read.
+    let mmap = unsafe { memmap2::Mmap::map(&file) }.map_err(CodecError::from)?;
+    let pos = FileStorage::parse(&mmap)?.transactions().position();
+    Ok(PyFileStorageIterator {
+        mmap,
+        pos,
+        pending: VecDeque::new(),
+        decode,
+        options,
+        ref_format,
+        nonfinite_floats,
+        duplicate_keys,
+        bigint_bits,
+        detect_raw_tids,
+        value_dedup,
+    })
+}
+
+/// A FileStorage revision: oid, tid and the record's byte range in the
+/// mapped file.
+type PendingRecord = ([u8; 8], [u8; 8], Option<std::ops::Range<usize>>);
+
+/// Iterator returned by `open_filestorage()`.
+#[pyclass(name = "FileStorageIterator", module = "zodb_json_codec")]
+struct PyFileStorageIterator {
+    mmap: memmap2::Mmap,
+    /// File position of the next transaction to read.
+    pos: usize,
+    /// The rest of the current transaction's records, ending with its
+    /// error if one was found.
+    pending: VecDeque<Result<PendingRecord, CodecError>>,
+    decode: bool,
+    /// Options for decoding the records, with `decode`.
+    options: DecodeOptions,
+    /// OID format of the compact refs, with `decode`.
+    ref_format: RefFormat,
+    /// How NaN and the infinities are written, with `decode`.
+    nonfinite_floats: NonFiniteFloats,
+    /// How dicts with duplicate keys are converted, with `decode`.
+    duplicate_keys: DuplicateKeys,
+    /// Magnitude bound for big integers written as numbers, with `decode`.
+    bigint_bits: u32,
+    /// Whether raw 8-byte tids are rendered as `@tid`, with `decode`.
+    detect_raw_tids: bool,
+    /// Whether identical leaves share one Python object, with `decode`.
+    value_dedup: bool,
+}
+
+impl PyFileStorageIterator {
+    /// Queue the records of the next transaction. Returns false at the end
+    /// of the file.
+    fn read_transaction(&mut self) -> bool {
+        let storage = FileStorage::parse(&self.mmap).expect("header checked on open");
+        let mut transactions = storage.transactions_from(self.pos);
+        let next = transactions.next();
+        self.pos = transactions.position();
+        let (txn_pos, txn) = match next {
+            None => return false,
+            Some(Ok(next)) => next,
+            Some(Err(e)) => {
+                self.pending.push_back(Err(e));
+                return true;
+            }
+        };
+        let base = self.mmap.as_ptr() as usize;
+        for record in txn.records() {
+            let record = storage.resolve(txn_pos, record).map(|r| {
+                let range = r.data.map(|d| {
+                    let start = d.as_ptr() as usize - base;
+                    start..start + d.len()
+                });
+                (r.oid, r.tid, range)
+            });
+            let failed = record.is_err();
+            self.pending.push_back(record);
+            if failed {
+                // Stop after the first error
+                self.pos = self.mmap.len();
+                break;
+            }
+        }
+        true
+    }
+}
+
+#[pymethods]
+impl PyFileStorageIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyTuple>>> {
+        while self.pending.is_empty() {
+            if !self.read_transaction() {
+                return Ok(None);
+            }
+        }
+        let (oid, tid, range) = self.pending.pop_front().expect("queued above")?;
+        let data: Py<PyAny> = match range {
+            None => py.None(),
+            Some(range) if self.decode => {
+                let _ref_format = zodb::RefFormatScope::enter(self.ref_format);
+                let _nonfinite = floats::NonFiniteScope::enter(self.nonfinite_floats);
+                let _duplicates = duplicate_keys::DuplicateKeysScope::enter(self.duplicate_keys);
+                let _bigint = bigint::BigIntScope::enter(self.bigint_bits);
+                let _raw_tids = known_types::RawTidScope::enter(self.detect_raw_tids);
+                let _dedup = dedup::DedupScope::enter(self.value_dedup);
+                let options = &RecordOptions {
+                    decode: self.options.clone(),
+                    ..RecordOptions::DEFAULT
+                };
+                decode_zodb_record_impl(py, &self.mmap[range], Some(&tid), options).map_err(|e| {
+                    pyo3::exceptions::PyValueError::new_err(format!(
+                        "oid 0x{}: {}",
+                        binenc::hex_encode(oid),
+                        e.value(py)
+                    ))
+                })?
+            }
+            Some(range) => PyBytes::new(py, &self.mmap[range]).into_any().unbind(),
+        };
+        Ok(Some(PyTuple::new(
+            py,
+            [PyBytes::new(py, &oid).into_any().unbind(), PyBytes::new(py, &tid).into_any().unbind(), data],
+        )?))
+    }
+}
+
+/// Stream `(oid, data)` records through an OID mapping file into `out`.
+///
+/// `mapping_path` is a file of 16-byte entries (old OID, new OID), sorted
+/// by old OID; it is memory-mapped, not loaded. Each record's OID and the
+/// persistent references in its state are remapped, and the result is
+/// written to `out.write()` as 8-byte OID, 4-byte big-endian length and
+/// record bytes. Returns the number of records written.
+#[pyfunction(name = "remap_storage")]
+fn py_remap_storage(
+    py: Python<'_>,
+    records: &Bound<'_, PyAny>,
+    mapping_path: std::path::PathBuf,
+    out: &Bound<'_, PyAny>,
+) -> PyResult<usize> {
+    const FLUSH_SIZE: usize = 1 << 20;
+    let mapping = py.detach(|| OidMapping::open(&mapping_path))?;
+    let write = out.getattr(intern!(py, "write"))?;
+    let mut buf = Vec::new();
+    let mut count = 0;
+    for item in records.try_iter()? {
+        let (oid, data): (Bound<'_, PyBytes>, Bound<'_, PyBytes>) = item?.extract()?;
+        let oid: [u8; 8] = oid
+            .as_bytes()
+            .try_into()
+            .map_err(|_| CodecError::InvalidData("oid must be 8 bytes".to_string()))?;
+        let data = data.as_bytes();
+        py.detach(|| remap::write_remapped(&mut buf, &oid, data, &mapping))?;
+        count += 1;
+        if buf.len() >= FLUSH_SIZE {
+            write.call1((PyBytes::new(py, &buf),))?;
+            buf.clear();
+        }
+    }
+    if !buf.is_empty() {
+        write.call1((PyBytes::new(py, &buf),))?;
+    }
+    Ok(count)
+}
+
+/// Rewrite the persistent references of one ZODB record through `mapping`.
+///
+/// `mapping` is a dict of 8-byte old OID to 8-byte new OID. Every
+/// same-database reference in the state is rewritten, including BTree
+/// children and bucket `next` links; unmapped OIDs are kept. A record
+/// without a rewritten reference is returned unchanged.
+#[pyfunction(name = "remap_oids")]
+fn py_remap_oids(
+    py: Python<'_>,
+    data: BytesLike<'_>,
+    mapping: &Bound<'_, PyDict>,
+) -> PyResult<Py<PyBytes>> {
+    let to_oid = |value: &Bound<'_, PyAny>| -> PyResult<[u8; 8]> {
+        let bytes = value.cast::<PyBytes>()?.as_bytes();
+        bytes.try_into().map_err(|_| {
+            CodecError::InvalidData(format!("mapping OIDs must be 8 bytes, got {}", bytes.len()))
+                .into()
+        })
+    };
+    let mut oids = HashMap::with_capacity(mapping.len());
+    for (old, new) in mapping.iter() {
+        oids.insert(to_oid(&old)?, to_oid(&new)?);
+    }
+    let data = data.as_bytes();
+    let bytes = py.detach(|| remap_record(data, |oid| oids.get(oid).copied()))?;
+    Ok(PyBytes::new(py, &bytes).into())
+}
+
+/// A validation policy for `@pkl` raw pickle payloads, passed as
+/// `raw_pickle_policy=` to the encoding functions.
+///
+/// Every `@pkl` payload must be a single complete pickle of at most
+/// `max_size` bytes. If `allowed_sha256` is given (an iterable of hex
+/// digests), payloads with any other digest are rejected; `None`
+/// disables the allowlist.
+#[pyclass(name = "RawPicklePolicy", module = "zodb_json_codec", frozen)]
+struct PyRawPicklePolicy(Arc<raw_pickle::RawPicklePolicy>);
+
+#[pymethods]
+impl PyRawPicklePolicy {
+    #[new]
+    #[pyo3(signature = (max_size=raw_pickle::DEFAULT_MAX_RAW_PICKLE_SIZE, allowed_sha256=None))]
+    fn new(max_size: usize, allowed_sha256: Option<Vec<String>>) -> PyResult<Self> {
+        let allowed_digests = match allowed_sha256 {
+            Some(hexes) => {
+                let mut set = std::collections::HashSet::with_capacity(hexes.len());
+                for h in &hexes {
+                    let digest: [u8; 32] = binenc::hex_decode(h)
+                        .ok()
+                        .and_then(|b| b.try_into().ok())
+                        .ok_or_else(|| {
+                            CodecError::InvalidData(format!("invalid sha256 hex digest: {h}"))
+                        })?;
+                    set.insert(digest);
+                }
+                Some(set)
+            }
+            None => None,
+        };
+        Ok(PyRawPicklePolicy(Arc::new(raw_pickle::RawPicklePolicy {
+            max_size,
+            allowed_digests,
+        })))
+    }
+}
+
+/// Per-class quotas for record decoding, passed as `quotas=` to the
+/// record decoding functions.
+///
+/// `max_size` (bytes) and `max_time` (seconds) are the default quota;
+/// `classes` maps dotted class names to dicts with their own `max_size`
+/// and/or `max_time` keys, replacing the default for that class. Quotas
+/// are checked once the class pickle of a record is decoded; a record
+/// over quota fails with a "limit exceeded" `ValueError`.
+#[pyclass(name = "ClassQuotas", module = "zodb_json_codec", frozen)]
+struct PyClassQuotas(Arc<ClassQuotas>);
+
+#[pymethods]
+impl PyClassQuotas {
+    #[new]
+    #[pyo3(signature = (classes=None, *, max_size=None, max_time=None))]
+    fn new(
+        classes: Option<&Bound<'_, PyDict>>,
+        max_size: Option<usize>,
+        max_time: Option<f64>,
+    ) -> PyResult<Self> {
+        let mut quotas = ClassQuotas::new(ClassQuota::new(max_size, quota_duration(max_time)?));
+        for (key, spec) in classes.into_iter().flatten() {
+            let class_name: String = key.extract()?;
+            let spec = spec.cast_into::<PyDict>().map_err(|_| {
+                CodecError::InvalidData(format!("quota for {class_name} must be a dict"))
+            })?;
+            let mut quota = ClassQuota::default();
+            for (k, v) in spec.iter() {
+                match k.extract::<String>()?.as_str() {
+                    "max_size" => quota.max_size = v.extract()?,
+                    "max_time" => quota.max_time = quota_duration(v.extract()?)?,
+                    other => {
+                        return Err(CodecError::InvalidData(format!(
+                            "unknown quota key {other:?} for {class_name}"
+                        ))
+                        .into())
+                    }
+                }
+            }
+            quotas = quotas.with_class(class_name, quota);
+        }
+        Ok(PyClassQuotas(Arc::new(quotas)))
+    }
+}
+
+/// A class allowlist/denylist for decoding, passed as `policy=` to the
+/// decoding functions.
+///
+/// `allowed` and `denied` are iterables of `(module, name)` pairs; a name
+/// of `"*"` covers the whole module. A class on the denylist, or missing
+/// from the allowlist when one is given, is a violation: with
+/// `on_violation="error"` decoding fails with a `ValueError`, with
+/// `"block"` the reference is replaced by `{"@blocked": [module, name]}`
+/// (which encodes back to the original class).
+#[pyclass(name = "DecodePolicy", module = "zodb_json_codec", frozen)]
+struct PyDecodePolicy(Arc<DecodePolicy>);
+
+#[pymethods]
+impl PyDecodePolicy {
+    #[new]
+    #[pyo3(signature = (allowed=None, denied=None, *, on_violation="error"))]
+    fn new(
+        allowed: Option<Vec<(String, String)>>,
+        denied: Option<Vec<(String, String)>>,
+        on_violation: &str,
+    ) -> PyResult<Self> {
+        let on_violation = match on_violation {
+            "error" => PolicyViolation::Error,
+            "block" => PolicyViolation::Block,
+            other => {
+                return Err(CodecError::InvalidData(format!(
+                    "on_violation must be 'error' or 'block', not {other:?}"
+                ))
+                .into())
+            }
+        };
+        let mut policy = DecodePolicy::new(on_violation);
+        if let Some(allowed) = allowed {
+            policy.allowed = Some(allowed.into_iter().collect());
+        }
+        policy.denied = denied.into_iter().flatten().collect();
+        Ok(PyDecodePolicy(Arc::new(policy)))
+    }
+}
+
+/// A class rename table, passed as `renames=` to the decoding functions
+/// (renaming the classes read) or to the encoding functions (renaming
+/// the classes written).
+///
+/// `classes` maps dotted class paths, `{"Products.Archetypes.Foo":
+/// "plone.app.Foo"}`, split at the last dot. `modules` maps module names
+/// and moves every class of the old module. Renames apply to instance
+/// classes, reduce callables and the classes of persistent references.
+#[pyclass(name = "ClassRenames", module = "zodb_json_codec", frozen)]
+struct PyClassRenames(Arc<ClassRenames>);
+
+#[pymethods]
+impl PyClassRenames {
+    #[new]
+    #[pyo3(signature = (classes=None, modules=None))]
+    fn new(
+        classes: Option<HashMap<String, String>>,
+        modules: Option<HashMap<String, String>>,
+    ) -> PyResult<Self> {
+        let dotted = |path: &str| match path.rfind('.') {
+            Some(dot) if dot > 0 && dot + 1 < path.len() => {
+                Ok((path[..dot].to_string(), path[dot + 1..].to_string()))
+            }
+            _ => Err(CodecError::InvalidData(format!(
+                "class path must be 'module.Name', not {path:?}"
+            ))),
+        };
+        let mut renames = ClassRenames::new();
+        for (from, to) in classes.into_iter().flatten() {
+            renames = renames.rename_class(dotted(&from)?, dotted(&to)?);
+        }
+        renames.modules = modules.unwrap_or_default();
+        Ok(PyClassRenames(Arc::new(renames)))
+    }
+}
+
+/// Maximum line lengths for text-mode (protocol 0) opcodes, passed as
+/// `line_limits=` to the decoding functions.
+///
+/// `max_name` bounds GLOBAL module/name lines, PUT/GET memo keys and
+/// PERSID ids, `max_number` INT/LONG/FLOAT and `max_string` STRING/UNICODE
+/// lines. Longer lines fail with a "limit exceeded" `ValueError` before
+/// any allocation.
+#[pyclass(name = "LineLimits", module = "zodb_json_codec", frozen)]
+struct PyLineLimits(LineLimits);
+
+#[pymethods]
+impl PyLineLimits {
+    #[new]
+    #[pyo3(signature = (
+        max_name=DEFAULT_MAX_NAME_LINE,
+        max_number=DEFAULT_MAX_NUMBER_LINE,
+        max_string=DEFAULT_MAX_STRING_LINE,
+    ))]
+    fn new(max_name: usize, max_number: usize, max_string: usize) -> Self {
+        PyLineLimits(LineLimits {
+            max_name,
+            max_number,
+            max_string,
+        })
+    }
+}
+
+/// The decoder's resource limits, passed as `limits=` to the decoding
+/// functions, e.g. for storages that accept untrusted pickles.
+///
+/// `max_allocation` bounds the approximate bytes of decoded values,
+/// including the copies memo GETs make; `max_stack_items` the values on
+/// the pickle stack; `max_memo_entries` the memo; `max_string_length` a
+/// single string or bytes value; `max_containers` the lists, tuples,
+/// dicts and sets created. `None` means unlimited. Exceeding a limit fails
+/// with a "limit exceeded" `ValueError`.
+#[pyclass(name = "DecodeLimits", module = "zodb_json_codec", frozen)]
+struct PyDecodeLimits(DecodeLimits);
+
+#[pymethods]
+impl PyDecodeLimits {
+    #[new]
+    #[pyo3(signature = (
+        max_allocation=None,
+        max_stack_items=None,
+        max_memo_entries=Some(DEFAULT_MAX_MEMO_ENTRIES),
+        max_string_length=Some(DEFAULT_MAX_STRING_LENGTH),
+        max_containers=None,
+    ))]
+    fn new(
+        max_allocation: Option<usize>,
+        max_stack_items: Option<usize>,
+        max_memo_entries: Option<usize>,
+        max_string_length: Option<usize>,
+        max_containers: Option<usize>,
+    ) -> Self {
+        PyDecodeLimits(DecodeLimits {
+            max_allocation: max_allocation.unwrap_or(usize::MAX),
+            max_stack_items: max_stack_items.unwrap_or(usize::MAX),
+            max_memo_entries: max_memo_entries.unwrap_or(usize::MAX),
+            max_string_length: max_string_length.unwrap_or(usize::MAX),
+            max_containers: max_containers.unwrap_or(usize::MAX),
+        })
+    }
+}
+
+/// The encoders' resource limits, passed as `limits=` to the encoding
+/// functions, e.g. for services that encode JSON from untrusted clients.
+///
+/// `max_output_bytes` bounds the pickle written for one value or record,
+/// `max_depth` the nesting of containers and `max_collection_length` the
+/// items of a single list, tuple, dict or set. `None` means unlimited (the
+/// default). Exceeding a limit fails with a "limit exceeded" `ValueError`.
+#[pyclass(name = "EncodeLimits", module = "zodb_json_codec", frozen)]
+struct PyEncodeLimits(EncodeLimits);
+
+#[pymethods]
+impl PyEncodeLimits {
+    #[new]
+    #[pyo3(signature = (max_output_bytes=None, max_depth=None, max_collection_length=None))]
+    fn new(
+        max_output_bytes: Option<usize>,
+        max_depth: Option<usize>,
+        max_collection_length: Option<usize>,
+    ) -> Self {
+        PyEncodeLimits(EncodeLimits {
+            max_output_bytes: max_output_bytes.unwrap_or(usize::MAX),
+            max_depth: max_depth.unwrap_or(usize::MAX),
+            max_collection_length: max_collection_length.unwrap_or(usize::MAX),
+        })
+    }
+}
+
+/// The renames of an encoding call given `renames=`.
+fn encode_renames(renames: Option<&Bound<'_, PyClassRenames>>) -> Option<Arc<ClassRenames>> {
+    renames.map(|r| Arc::clone(&r.get().0))
+}
+
+/// The encoder limits of an encoding call given `limits=`.
+fn encode_limits(limits: Option<&Bound<'_, PyEncodeLimits>>) -> EncodeLimits {
+    limits.map_or_else(EncodeLimits::default, |l| l.get().0)
+}
+
+/// The `DecodeOptions` of a decoding call given `quotas=`, `lenient=`,
+/// `py2_strings=`, `shared_references=`, `policy=`, `renames=`,
+/// `line_limits=`, `limits=` and `surrogates=`.
+#[allow(clippy::too_many_arguments)]
+fn decode_options(
+    quotas: Option<&Bound<'_, PyClassQuotas>>,
+    lenient: bool,
+    py2_strings: &str,
+    shared_references: bool,
+    policy: Option<&Bound<'_, PyDecodePolicy>>,
+    renames: Option<&Bound<'_, PyClassRenames>>,
+    line_limits: Option<&Bound<'_, PyLineLimits>>,
+    limits: Option<&Bound<'_, PyDecodeLimits>>,
+    surrogates: &str,
+) -> PyResult<DecodeOptions> {
+    let py2_strings = match py2_strings {
+        "bytes" => Py2Strings::Bytes,
+        "latin1" => Py2Strings::Latin1,
+        "utf8" => Py2Strings::Utf8,
+        mode => {
+            return Err(CodecError::InvalidData(format!(
+                "unknown py2_strings mode: {mode} (expected 'bytes', 'latin1' or 'utf8')"
+            ))
+            .into())
+        }
+    };
+    let mut options = DecodeOptions::new()
+        .with_lenient(lenient)
+        .with_py2_strings(py2_strings)
+        .with_shared_references(shared_references)
+        .with_surrogates(parse_surrogates(surrogates)?);
+    if let Some(quotas) = quotas {
+        options = options.with_quotas(Arc::clone(&quotas.get().0));
+    }
+    if let Some(policy) = policy {
+        options = options.with_policy(Arc::clone(&policy.get().0));
+    }
+    if let Some(renames) = renames {
+        options = options.with_renames(Arc::clone(&renames.get().0));
+    }
+    if let Some(line_limits) = line_limits {
+        options = options.with_line_limits(line_limits.get().0);
+    }
+    if let Some(limits) = limits {
+        options = options.with_limits(limits.get().0);
+    }
+    Ok(options)
+}
+
+/// The lone surrogate handling named `policy`: `"error"`, `"replace"` or
+/// `"preserve"`.
+fn parse_surrogates(policy: &str) -> PyResult<SurrogatePolicy> {
+    Ok(match policy {
+        "error" => SurrogatePolicy::Error,
+        "replace" => SurrogatePolicy::Replace,
+        "preserve" => SurrogatePolicy::Preserve,
+        _ => {
+            return Err(CodecError::InvalidData(format!(
+                "unknown surrogate policy: {policy} (expected 'error', 'replace' or 'preserve')"
+            ))
+            .into())
+        }
+    })
+}
+
+/// The non-finite float mode named `mode`: `"marker"` or `"null"`.
+fn parse_nonfinite_floats(mode: &str) -> PyResult<NonFiniteFloats> {
+    Ok(match mode {
+        "marker" => NonFiniteFloats::Marker,
+        "null" => NonFiniteFloats::Null,
+        _ => {
+            return Err(CodecError::InvalidData(format!(
+                "unknown non-finite float mode: {mode} (expected 'marker' or 'null')"
+            ))
+            .into())
+        }
+    })
+}
+
+/// The duplicate key mode named `mode`: `"last-wins"`, `"error"` or
+/// `"preserve"`.
+fn parse_duplicate_keys(mode: &str) -> PyResult<DuplicateKeys> {
+    Ok(match mode {
+        "last-wins" => DuplicateKeys::LastWins,
+        "error" => DuplicateKeys::Error,
+        "preserve" => DuplicateKeys::Preserve,
+        _ => {
+            return Err(CodecError::InvalidData(format!(
+                "unknown duplicate keys mode: {mode} (expected 'last-wins', 'error' or 'preserve')"
+            ))
+            .into())
+        }
+    })
+}
+
+/// The compact ref OID format named `format`: `"hex"` or `"int"`.
+fn parse_ref_format(format: &str) -> PyResult<RefFormat> {
+    Ok(match format {
+        "hex" => RefFormat::Hex,
+        "int" => RefFormat::Int,
+        _ => {
+            return Err(CodecError::InvalidData(format!(
+                "unknown ref format: {format} (expected 'hex' or 'int')"
+            ))
+            .into())
+        }
+    })
+}
+
+fn quota_duration(seconds: Option<f64>) -> PyResult<Option<std::time::Duration>> {
+    seconds
+        .map(|s| {
+            std::time::Duration::try_from_secs_f64(s).map_err(|_| {
+                CodecError::InvalidData(format!("invalid max_time: {s}")).into()
+            })
+        })
+        .transpose()
+}
+
+/// Forward the codec's `tracing` spans and events to Python `logging`.
+///
+/// At `DEBUG`, every record decoded or encoded through the record
+/// functions logs its size, class and duration, and generic `@reduce`
+/// fallbacks are logged. The fields are also available as a dict in the
+/// log record's `tracing_fields` attribute. `level=None` stops forwarding.
+/// The configuration is process-wide.
+#[pyfunction]
+#[pyo3(signature = (level=Some("DEBUG"), logger="zodb_json_codec"))]
+fn configure_logging(py: Python<'_>, level: Option<&str>, logger: &str) -> PyResult<()> {
+    let level = level.map(logbridge::parse_level).transpose()?;
+    let logger = py.import("logging")?.call_method1("getLogger", (logger,))?;
+    logbridge::configure(logger.unbind(), level)?;
+    Ok(())
+}
+
+/// Describe this build: crate version, marker format version, markers,
+/// decoded opcodes, protocols, known-type handlers and feature flags.
+#[pyfunction(name = "codec_info")]
+fn py_codec_info(py: Python<'_>) -> PyResult<Py<PyAny>> {
+    let info = codec_info();
+    let dict = PyDict::new(py);
+    dict.set_item("version", info.version)?;
+    dict.set_item("marker_format", info.marker_format)?;
+    dict.set_item("markers", PyList::new(py, info.markers)?)?;
+    dict.set_item("opcodes", PyList::new(py, &info.opcodes)?)?;
+    dict.set_item("decode_protocols", PyList::new(py, info.decode_protocols)?)?;
+    dict.set_item("encode_protocols", PyList::new(py, info.encode_protocols)?)?;
+    let known_types = PyDict::new(py);
+    for (marker, class) in info.known_types {
+        known_types.set_item(marker, class)?;
+    }
+    dict.set_item("known_types", known_types)?;
+    let features = PyDict::new(py);
+    for (name, enabled) in &info.features {
+        features.set_item(name, enabled)?;
+    }
+    dict.set_item("features", features)?;
+    Ok(dict.into_any().unbind())
+}
+
+/// Return the hex SHA-256 digest of a raw pickle, as used by the
+/// `RawPicklePolicy` allowlist.
+#[pyfunction]
+fn raw_pickle_sha256(data: &[u8]) -> String {
+    binenc::hex_encode(raw_pickle::digest(data))
+}
+
+/// Register a class outside the `BTrees` package as BTree-compatible.
+///
+/// `kind` is one of `"BTree"`, `"Bucket"`, `"TreeSet"` or `"Set"`.
+#[pyfunction(name = "register_btree_class")]
+fn py_register_btree_class(module: &str, name: &str, kind: &str) -> PyResult<()> {
+    let kind = match kind {
+        "BTree" => BTreeNodeKind::BTree,
+        "Bucket" => BTreeNodeKind::Bucket,
+        "TreeSet" => BTreeNodeKind::TreeSet,
+        "Set" => BTreeNodeKind::Set,
+        _ => {
+            return Err(CodecError::InvalidData(format!(
+                "unknown BTree kind {kind:?} (expected BTree, Bucket, TreeSet or Set)"
+            ))
+            .into())
+        }
+    };
+    register_btree_class(module, name, kind);
+    Ok(())
+}
+
+/// Treat classes in the module `prefix` and its submodules like `BTrees.*`
+/// classes (classified by their name suffix).
+#[pyfunction(name = "register_btree_module_prefix")]
+fn py_register_btree_module_prefix(prefix: &str) {
+    register_btree_module_prefix(prefix);
+}
+
+/// Remove all BTree class and module prefix registrations.
+#[pyfunction(name = "clear_btree_registrations")]
+fn py_clear_btree_registrations() {
+    clear_btree_registrations();
+}
+
+/// Convert `module.name` to and from `{marker: ...}` like a built-in known
+/// type.
+///
+/// `spec` is `"arg"` (`module.name(arg)`, the marker holds `arg`),
+/// `"args"` (`module.name(*args)`, the marker holds the argument list) or
+/// `"state"` (an instance, the marker holds its state). Built-in handlers
+/// take precedence; `marker` must start with `@` and not be a built-in
+/// marker. Registrations are process-wide.
+#[pyfunction(name = "register_type_handler")]
+#[pyo3(signature = (module, name, marker, spec="arg"))]
+fn py_register_type_handler(module: &str, name: &str, marker: &str, spec: &str) -> PyResult<()> {
+    let spec = match spec {
+        "arg" => TypeSpec::Arg,
+        "args" => TypeSpec::Args,
+        "state" => TypeSpec::State,
+        _ => {
+            return Err(CodecError::InvalidData(format!(
+                "unknown type spec {spec:?} (expected arg, args or state)"
+            ))
+            .into())
+        }
+    };
+    Ok(register_type_handler(module, name, marker, spec)?)
+}
+
+/// Remove the handler registered for `module.name`. Returns whether one
+/// was registered.
+#[pyfunction(name = "unregister_type_handler")]
+fn py_unregister_type_handler(module: &str, name: &str) -> bool {
+    unregister_type_handler(module, name)
+}
+
+/// Classify a BTree class.
+///
+/// Returns `None` for non-BTree classes, otherwise a dict with `kind`
+/// (`"BTree"`, `"Bucket"`, `"TreeSet"` or `"Set"`), `is_map`, and the
+/// `key_type` and `value_type` codes (`"O"`, `"I"`, `"L"`, `"U"`, `"Q"`,
+/// `"F"`, `"fs"`, or `None` if unknown or, for values, a set type).
+#[pyfunction(name = "classify_btree")]
+fn py_classify_btree<'py>(
+    py: Python<'py>,
+    module: &str,
+    name: &str,
+) -> PyResult<Option<Bound<'py, PyDict>>> {
+    let Some(info) = classify_btree(module, name) else {
+        return Ok(None);
+    };
+    let kind = match info.kind {
+        BTreeNodeKind::BTree => "BTree",
+        BTreeNodeKind::Bucket => "Bucket",
+        BTreeNodeKind::TreeSet => "TreeSet",
+        BTreeNodeKind::Set => "Set",
+    };
+    let dict = PyDict::new(py);
+    dict.set_item("kind", kind)?;
+    dict.set_item("is_map", info.is_map)?;
+    dict.set_item("key_type", info.key_type.map(|t| t.code()))?;
+    dict.set_item("value_type", info.value_type.map(|t| t.code()))?;
+    Ok(Some(dict))
+}
+
+/// Python module definition
+#[pymodule]
+fn _rust(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("CodecError", m.py().get_type::<error::py::CodecError>())?;
+    m.add_function(wrap_pyfunction!(pickle_to_json, m)?)?;
+    m.add_function(wrap_pyfunction!(py_pickle_to_cbor, m)?)?;
+    m.add_function(wrap_pyfunction!(py_cbor_to_pickle, m)?)?;
+    m.add_function(wrap_pyfunction!(json_to_pickle, m)?)?;
+    m.add_function(wrap_pyfunction!(py_canonicalize_json, m)?)?;
+    m.add_function(wrap_pyfunction!(py_canonicalize_pickle, m)?)?;
+    m.add_function(wrap_pyfunction!(py_state_fingerprint, m)?)?;
+    m.add_function(wrap_pyfunction!(pickle_to_dict, m)?)?;
+    m.add_function(wrap_pyfunction!(dict_to_pickle, m)?)?;
+    m.add_function(wrap_pyfunction!(py_decode_pickle_ast, m)?)?;
+    m.add_function(wrap_pyfunction!(py_encode_pickle_ast, m)?)?;
+    pyast::add_classes(m)?;
+    m.add_function(wrap_pyfunction!(decode_zodb_record, m)?)?;
+    m.add_function(wrap_pyfunction!(decode_zodb_record_for_pg, m)?)?;
+    m.add_function(wrap_pyfunction!(decode_zodb_record_for_pg_json, m)?)?;
+    m.add_function(wrap_pyfunction!(decode_batch_async, m)?)?;
+    m.add_function(wrap_pyfunction!(encode_zodb_record, m)?)?;
+    m.add_function(wrap_pyfunction!(py_decode_zodb_state, m)?)?;
+    m.add_function(wrap_pyfunction!(py_encode_zodb_state, m)?)?;
+    m.add_function(wrap_pyfunction!(py_decode_persistent_id, m)?)?;
+    m.add_function(wrap_pyfunction!(py_encode_persistent_id, m)?)?;
+    m.add_function(wrap_pyfunction!(encode_zodb_records_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(py_count_refs, m)?)?;
+    m.add_function(wrap_pyfunction!(py_has_ref_to, m)?)?;
+    m.add_function(wrap_pyfunction!(py_oid_to_hex, m)?)?;
+    m.add_function(wrap_pyfunction!(py_hex_to_oid, m)?)?;
+    m.add_function(wrap_pyfunction!(py_tid_to_timestamp, m)?)?;
+    m.add_function(wrap_pyfunction!(py_timestamp_to_tid, m)?)?;
+    m.add_function(wrap_pyfunction!(py_collect_refs_ex, m)?)?;
+    m.add_function(wrap_pyfunction!(py_record_refs_to_edges, m)?)?;
+    m.add_function(wrap_pyfunction!(py_record_refs_to_edges_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(py_reachable_oids, m)?)?;
+    m.add_function(wrap_pyfunction!(py_analyze_pickle, m)?)?;
+    m.add_function(wrap_pyfunction!(py_find_class_references, m)?)?;
+    m.add_function(wrap_pyfunction!(py_estimate_decoded_size, m)?)?;
+    m.add_function(wrap_pyfunction!(py_extract_subtree, m)?)?;
+    m.add_function(wrap_pyfunction!(py_extract_paths, m)?)?;
+    m.add_function(wrap_pyfunction!(py_iter_pickle_events, m)?)?;
+    m.add_class::<PyPickleEvents>()?;
+    m.add_function(wrap_pyfunction!(py_graft_subtree, m)?)?;
+    m.add_function(wrap_pyfunction!(py_diff_zodb_records, m)?)?;
+    m.add_function(wrap_pyfunction!(py_verify_roundtrip, m)?)?;
+    m.add_function(wrap_pyfunction!(py_apply_patch_to_record, m)?)?;
+    m.add_function(wrap_pyfunction!(py_remap_storage, m)?)?;
+    m.add_function(wrap_pyfunction!(py_remap_oids, m)?)?;
+    m.add_function(wrap_pyfunction!(read_zeo_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(py_decode_transaction, m)?)?;
+    m.add_function(wrap_pyfunction!(py_open_filestorage, m)?)?;
+    m.add_class::<PyFileStorageIterator>()?;
+    m.add_function(wrap_pyfunction!(py_lint_record, m)?)?;
+    m.add_class::<PyRawPicklePolicy>()?;
+    m.add_class::<PyClassQuotas>()?;
+    m.add_class::<PyDecodePolicy>()?;
+    m.add_class::<PyClassRenames>()?;
+    m.add_class::<PyLineLimits>()?;
+    m.add_class::<PyDecodeLimits>()?;
+    m.add_class::<PyEncodeLimits>()?;
+    m.add_function(wrap_pyfunction!(raw_pickle_sha256, m)?)?;
+    m.add_function(wrap_pyfunction!(py_codec_info, m)?)?;
+    m.add_function(wrap_pyfunction!(configure_logging, m)?)?;
+    m.add_function(wrap_pyfunction!(py_register_btree_class, m)?)?;
+    m.add_function(wrap_pyfunction!(py_register_btree_module_prefix, m)?)?;
+    m.add_function(wrap_pyfunction!(py_clear_btree_registrations, m)?)?;
+    m.add_function(wrap_pyfunction!(py_classify_btree, m)?)?;
+    m.add_function(wrap_pyfunction!(py_register_type_handler, m)?)?;
+    m.add_function(wrap_pyfunction!(py_unregister_type_handler, m)?)?;
+    Ok(())
+}
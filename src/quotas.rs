@@ -0,0 +1,168 @@
+//! Per-class size and time quotas for record decoding.
+//!
+//! A decoding service shared by several sites cannot use one global limit:
+//! file and image classes legitimately carry hundreds of megabytes while a
+//! 200 MB "document" is almost certainly noise from a misbehaving tenant.
+//! Quotas are keyed by the dotted class name, passed per call in
+//! `DecodeOptions` (so services decoding for several tenants can give each
+//! its own) and consulted in `decode_zodb_pickles_with_options` after the
+//! class pickle is parsed, before any state is decoded:
+//!
+//! - `max_size` bounds the whole record (class + state pickle) in bytes and
+//!   is checked up front, so an oversized record costs nothing.
+//! - `max_time` bounds the wall-clock time of decoding the record; it is
+//!   checked periodically while the state pickle is decoded.
+//!
+//! Classes without an entry fall back to the default quota. Without quotas
+//! (the default) records are only subject to the fixed decoder limits.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::error::CodecError;
+
+/// Size and time budget for decoding one record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ClassQuota {
+    /// Largest record (class + state pickle) accepted, in bytes.
+    pub max_size: Option<usize>,
+    /// Longest time decoding a record may take.
+    pub max_time: Option<Duration>,
+}
+
+impl ClassQuota {
+    /// A quota with the given size and time bounds (`None` = unbounded).
+    pub fn new(max_size: Option<usize>, max_time: Option<Duration>) -> Self {
+        ClassQuota { max_size, max_time }
+    }
+}
+
+/// Quotas keyed by dotted class name (`"module.Name"`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ClassQuotas {
+    /// Quota for classes without an entry in `classes`.
+    pub default: ClassQuota,
+    /// Per-class overrides.
+    pub classes: HashMap<String, ClassQuota>,
+}
+
+impl ClassQuotas {
+    /// Quotas applying `default` to every class without an override.
+    pub fn new(default: ClassQuota) -> Self {
+        ClassQuotas {
+            default,
+            classes: HashMap::new(),
+        }
+    }
+
+    /// Add or replace the quota of the class `module.name`.
+    pub fn with_class(mut self, dotted_name: impl Into<String>, quota: ClassQuota) -> Self {
+        self.classes.insert(dotted_name.into(), quota);
+        self
+    }
+
+    /// The quota applying to class `module.name`.
+    pub fn for_class(&self, module: &str, name: &str) -> ClassQuota {
+        if self.classes.is_empty() {
+            return self.default;
+        }
+        self.classes
+            .get(&format!("{module}.{name}"))
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Check a record of `size` bytes of class `module.name` against its
+    /// quota. Returns the decoding deadline, if the quota has a time bound.
+    pub(crate) fn admit(
+        &self,
+        module: &str,
+        name: &str,
+        size: usize,
+        started: Instant,
+    ) -> Result<Option<Deadline>, CodecError> {
+        let quota = self.for_class(module, name);
+        if let Some(max) = quota.max_size {
+            if size > max {
+                return Err(CodecError::LimitExceeded(format!(
+                    "record of class {module}.{name} is {size} bytes, quota is {max} bytes"
+                )));
+            }
+        }
+        Ok(quota.max_time.map(|budget| Deadline {
+            at: started + budget,
+            budget,
+        }))
+    }
+}
+
+/// Point in time at which decoding a record is abandoned.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline {
+    at: Instant,
+    budget: Duration,
+}
+
+impl Deadline {
+    pub(crate) fn check(&self) -> Result<(), CodecError> {
+        if Instant::now() > self.at {
+            return Err(CodecError::LimitExceeded(format!(
+                "record decoding exceeded its time quota of {} ms",
+                self.budget.as_millis()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: usize = 1024 * 1024;
+
+    fn quotas() -> ClassQuotas {
+        ClassQuotas::new(ClassQuota::new(Some(8 * MB), None))
+            .with_class("plone.app.blob.content.ATBlob", ClassQuota::new(Some(256 * MB), None))
+            .with_class(
+                "myapp.Report",
+                ClassQuota::new(None, Some(Duration::from_millis(50))),
+            )
+    }
+
+    #[test]
+    fn test_for_class() {
+        let q = quotas();
+        assert_eq!(q.for_class("plone.app.blob.content", "ATBlob").max_size, Some(256 * MB));
+        assert_eq!(q.for_class("myapp", "Document").max_size, Some(8 * MB));
+        assert_eq!(q.for_class("myapp", "Report").max_size, None);
+    }
+
+    #[test]
+    fn test_admit_size() {
+        let q = quotas();
+        let now = Instant::now();
+        assert!(q.admit("plone.app.blob.content", "ATBlob", 100 * MB, now).is_ok());
+        let err = q.admit("myapp", "Document", 100 * MB, now).unwrap_err();
+        assert!(matches!(err.root(), CodecError::LimitExceeded(_)), "{err}");
+        assert!(err.to_string().contains("myapp.Document"), "{err}");
+        // Exactly at the quota is fine
+        assert!(q.admit("myapp", "Document", 8 * MB, now).is_ok());
+    }
+
+    #[test]
+    fn test_admit_deadline() {
+        let q = quotas();
+        let now = Instant::now();
+        assert!(q.admit("myapp", "Document", 10, now).unwrap().is_none());
+        let deadline = q.admit("myapp", "Report", 10, now).unwrap().unwrap();
+        assert!(deadline.check().is_ok());
+        let expired = q
+            .admit("myapp", "Report", 10, now - Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(expired.check(), Err(CodecError::LimitExceeded(_))));
+    }
+}
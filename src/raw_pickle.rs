@@ -0,0 +1,243 @@
+//! Validation for `@pkl` raw pickle payloads.
+//!
+//! `@pkl` is the escape hatch for data the codec can't represent
+//! structurally, which also makes it the one marker where JSON input can
+//! carry arbitrary opcodes into storage. Every payload is therefore checked
+//! before it becomes a `PickleValue::RawPickle`:
+//!
+//! 1. Size cap — rejected from the base64 length, before decoding.
+//! 2. Opcode walk — the payload must be exactly one complete pickle
+//!    (known opcodes only, nothing after STOP).
+//! 3. Optional digest allowlist — the SHA-256 of the payload (hashed while
+//!    the base64 is decoded) must be a registered one.
+//!
+//! The policy of the encodings run inside [`with_raw_pickle_policy`] is
+//! kept per thread, so that it applies equally to the JSON path
+//! (`json.rs`) and the direct PyObject path (`pyconv.rs`). Outside it,
+//! the default policy applies.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+
+use crate::binenc::{b64_decode_into, hex_encode};
+use crate::error::CodecError;
+use crate::types::PickleValue;
+use crate::zodb::find_pickle_end;
+
+/// Default upper bound for a decoded `@pkl` payload (16 MB).
+pub const DEFAULT_MAX_RAW_PICKLE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Base64 input is decoded in chunks of this many characters (a multiple
+/// of 4, so every chunk is a complete base64 block sequence).
+const B64_CHUNK: usize = 64 * 1024;
+
+/// Acceptance policy for `@pkl` payloads.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RawPicklePolicy {
+    /// Largest decoded payload accepted, in bytes.
+    pub max_size: usize,
+    /// When set, only payloads whose SHA-256 digest is listed are accepted.
+    pub allowed_digests: Option<HashSet<[u8; 32]>>,
+}
+
+impl RawPicklePolicy {
+    const fn new() -> Self {
+        RawPicklePolicy {
+            max_size: DEFAULT_MAX_RAW_PICKLE_SIZE,
+            allowed_digests: None,
+        }
+    }
+}
+
+impl Default for RawPicklePolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static DEFAULT_POLICY: RawPicklePolicy = RawPicklePolicy::new();
+
+thread_local! {
+    /// The `@pkl` policy of this thread's encodings; `None` is the default.
+    static POLICY: RefCell<Option<Arc<RawPicklePolicy>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with the encodings it makes on this thread validating `@pkl`
+/// payloads against `policy` instead of the default one.
+///
+/// ```
+/// use zodb_json_codec::{json_to_pickle_value, with_raw_pickle_policy, RawPicklePolicy};
+///
+/// let json = serde_json::json!({"@pkl": "gANOLg=="});
+/// assert!(json_to_pickle_value(&json).is_ok());
+/// let mut policy = RawPicklePolicy::default();
+/// policy.max_size = 2;
+/// assert!(with_raw_pickle_policy(policy, || json_to_pickle_value(&json)).is_err());
+/// ```
+pub fn with_raw_pickle_policy<R>(
+    policy: impl Into<Arc<RawPicklePolicy>>,
+    f: impl FnOnce() -> R,
+) -> R {
+    let _scope = RawPicklePolicyScope::enter(Some(policy.into()));
+    f()
+}
+
+/// Sets the `@pkl` policy of the current thread while alive.
+pub(crate) struct RawPicklePolicyScope {
+    previous: Option<Arc<RawPicklePolicy>>,
+}
+
+impl RawPicklePolicyScope {
+    pub(crate) fn enter(policy: Option<Arc<RawPicklePolicy>>) -> Self {
+        RawPicklePolicyScope {
+            previous: POLICY.with(|p| p.replace(policy)),
+        }
+    }
+}
+
+impl Drop for RawPicklePolicyScope {
+    fn drop(&mut self) {
+        POLICY.with(|p| *p.borrow_mut() = self.previous.take());
+    }
+}
+
+#[cfg(any(test, feature = "python"))]
+/// SHA-256 digest of a raw pickle payload.
+pub fn digest(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Decode and validate the base64 value of an `@pkl` marker.
+pub fn decode_raw_pickle_marker(b64: &str) -> Result<PickleValue, CodecError> {
+    decode_raw_pickle(b64).map(PickleValue::RawPickle)
+}
+
+/// Decode and validate an `@pkl` payload to its pickle bytes.
+pub(crate) fn decode_raw_pickle(b64: &str) -> Result<Vec<u8>, CodecError> {
+    match POLICY.with(|p| p.borrow().clone()) {
+        Some(policy) => decode_raw_pickle_with(b64, &policy),
+        None => decode_raw_pickle_with(b64, &DEFAULT_POLICY),
+    }
+}
+
+fn decode_raw_pickle_with(b64: &str, policy: &RawPicklePolicy) -> Result<Vec<u8>, CodecError> {
+    // Upper bound from the encoded length — never allocate an oversized payload.
+    let padding = b64.bytes().rev().take(2).filter(|&b| b == b'=').count();
+    let decoded_len = (b64.len() / 4 * 3).saturating_sub(padding);
+    if decoded_len > policy.max_size {
+        return Err(CodecError::InvalidData(format!(
+            "@pkl payload of {decoded_len} bytes exceeds limit of {} bytes",
+            policy.max_size
+        )));
+    }
+
+    // Decode chunk-wise, hashing as we go when an allowlist is configured.
+    let mut hasher = policy.allowed_digests.as_ref().map(|_| Sha256::new());
+    let mut data = Vec::with_capacity(decoded_len);
+    for chunk in b64.as_bytes().chunks(B64_CHUNK) {
+        let start = data.len();
+        b64_decode_into(chunk, &mut data)?;
+        if let Some(h) = hasher.as_mut() {
+            h.update(&data[start..]);
+        }
+    }
+
+    match find_pickle_end(&data) {
+        Ok(end) if end == data.len() => {}
+        Ok(end) => {
+            return Err(CodecError::InvalidData(format!(
+                "@pkl payload has {} trailing bytes after STOP",
+                data.len() - end
+            )))
+        }
+        Err(e) => return Err(CodecError::InvalidData(format!("@pkl payload rejected: {e}"))),
+    }
+
+    if let (Some(allowed), Some(h)) = (policy.allowed_digests.as_ref(), hasher) {
+        let d: [u8; 32] = h.finalize().into();
+        if !allowed.contains(&d) {
+            return Err(CodecError::InvalidData(format!(
+                "@pkl payload sha256 {} is not in the allowlist",
+                hex_encode(d)
+            )));
+        }
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binenc::b64_encode;
+
+    // PROTO 3, NONE, STOP
+    const NONE_PICKLE: &[u8] = &[0x80, 0x03, b'N', b'.'];
+
+    #[test]
+    fn test_valid_payload_accepted() {
+        let b64 = b64_encode(NONE_PICKLE);
+        let data = decode_raw_pickle_with(&b64, &RawPicklePolicy::default()).unwrap();
+        assert_eq!(data, NONE_PICKLE);
+    }
+
+    #[test]
+    fn test_oversized_payload_rejected() {
+        let b64 = b64_encode(NONE_PICKLE);
+        let policy = RawPicklePolicy {
+            max_size: 3,
+            allowed_digests: None,
+        };
+        let err = decode_raw_pickle_with(&b64, &policy).unwrap_err();
+        assert!(err.to_string().contains("exceeds limit"), "{err}");
+    }
+
+    #[test]
+    fn test_trailing_data_rejected() {
+        let b64 = b64_encode([0x80, 0x03, b'N', b'.', b'N']);
+        let err = decode_raw_pickle_with(&b64, &RawPicklePolicy::default()).unwrap_err();
+        assert!(err.to_string().contains("trailing"), "{err}");
+    }
+
+    #[test]
+    fn test_truncated_and_unknown_opcodes_rejected() {
+        let truncated = b64_encode([0x80, 0x03, b'N']);
+        assert!(decode_raw_pickle_with(&truncated, &RawPicklePolicy::default()).is_err());
+        let unknown = b64_encode([0x80, 0x03, 0xff, b'.']);
+        assert!(decode_raw_pickle_with(&unknown, &RawPicklePolicy::default()).is_err());
+    }
+
+    #[test]
+    fn test_digest_allowlist() {
+        let b64 = b64_encode(NONE_PICKLE);
+        let mut policy = RawPicklePolicy {
+            max_size: DEFAULT_MAX_RAW_PICKLE_SIZE,
+            allowed_digests: Some(HashSet::new()),
+        };
+        let err = decode_raw_pickle_with(&b64, &policy).unwrap_err();
+        assert!(err.to_string().contains("allowlist"), "{err}");
+
+        policy.allowed_digests = Some([digest(NONE_PICKLE)].into_iter().collect());
+        assert!(decode_raw_pickle_with(&b64, &policy).is_ok());
+    }
+
+    #[test]
+    fn test_chunked_decode_matches_whole() {
+        // Payload spanning several base64 chunks: one long BINBYTES value.
+        let mut pickle = vec![0x80, 0x03, b'B'];
+        let payload = vec![0xabu8; B64_CHUNK];
+        pickle.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        pickle.extend_from_slice(&payload);
+        pickle.push(b'.');
+        let policy = RawPicklePolicy {
+            max_size: DEFAULT_MAX_RAW_PICKLE_SIZE,
+            allowed_digests: Some([digest(&pickle)].into_iter().collect()),
+        };
+        let data = decode_raw_pickle_with(&b64_encode(&pickle), &policy).unwrap();
+        assert_eq!(data, pickle);
+    }
+}
@@ -0,0 +1,313 @@
+//! Persistent reference graphs.
+//!
+//! Referential-integrity and garbage analysis work on the object graph of
+//! a storage: which object references which. `record_refs_to_edges` turns
+//! one record into its outgoing edges `(source oid, target oid, target
+//! class)`, and `record_refs_to_edges_batch` does a whole set of records,
+//! filling in target classes from the records themselves. The edge lists
+//! can be written as Graphviz DOT or CSV for external tools.
+//! `reachable_oids` follows the edges from a root through a caller's
+//! loader, for pack and verification scripts.
+//!
+//! Only references into the same database with 8-byte oids become edges;
+//! cross-database references point into another storage.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+use crate::decode::decode_zodb_pickles;
+use crate::error::CodecError;
+use crate::refscan::collect_refs_ex;
+use crate::zodb::extract_class_info;
+
+/// One persistent reference between two objects of a storage.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct RefEdge {
+    /// The oid of the referencing record.
+    pub source: u64,
+    /// The referenced oid.
+    pub target: u64,
+    /// The dotted class name of the target (`"myapp.models.Folder"`), or
+    /// an empty string when it is not known.
+    pub class: String,
+}
+
+/// The outgoing edges of the ZODB record `record` stored under `oid`, one
+/// per referenced oid, in pickle order.
+///
+/// The target class comes from the class hint of the reference; weak
+/// references and references written without one have an empty class.
+///
+/// ```
+/// use zodb_json_codec::record_refs_to_edges;
+///
+/// // class myapp.Folder, state {"child": ref to oid 7 of class myapp.Doc}
+/// let record = b"\x80\x03cmyapp\nFolder\nq\x00.\x80\x03}X\x05\x00\x00\x00child\
+///     C\x08\x00\x00\x00\x00\x00\x00\x00\x07cmyapp\nDoc\n\x86Qs.";
+/// let edges = record_refs_to_edges(1, record)?;
+/// assert_eq!((edges[0].source, edges[0].target), (1, 7));
+/// assert_eq!(edges[0].class, "myapp.Doc");
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn record_refs_to_edges(oid: u64, record: &[u8]) -> Result<Vec<RefEdge>, CodecError> {
+    let (_class_val, state_val) = decode_zodb_pickles(record)?;
+    let mut edges: Vec<RefEdge> = Vec::new();
+    let mut seen: HashMap<u64, usize> = HashMap::new();
+    for info in collect_refs_ex(&state_val) {
+        let Ok(target) = <[u8; 8]>::try_from(info.oid.as_slice()) else {
+            continue;
+        };
+        if info.database.is_some() {
+            continue;
+        }
+        let class = info.class.map(|(module, name)| format!("{module}.{name}")).unwrap_or_default();
+        let target = u64::from_be_bytes(target);
+        match seen.entry(target) {
+            // A later reference may carry the class hint an earlier one lacked
+            Entry::Occupied(e) => {
+                let edge = &mut edges[*e.get()];
+                if edge.class.is_empty() {
+                    edge.class = class;
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert(edges.len());
+                edges.push(RefEdge {
+                    source: oid,
+                    target,
+                    class,
+                });
+            }
+        }
+    }
+    Ok(edges)
+}
+
+/// The edges of all `(oid, record)` pairs, in record order.
+///
+/// Targets without a class hint get the class of their record when it is
+/// part of `records`. On failure, returns the index of the failing record
+/// with its error.
+pub fn record_refs_to_edges_batch(records: &[(u64, &[u8])]) -> Result<Vec<RefEdge>, (usize, CodecError)> {
+    let mut edges = Vec::new();
+    let mut classes = HashMap::new();
+    for (i, &(oid, record)) in records.iter().enumerate() {
+        let (class_val, _) = decode_zodb_pickles(record).map_err(|e| (i, e))?;
+        let (module, name) = extract_class_info(&class_val);
+        classes.insert(oid, format!("{module}.{name}"));
+        edges.extend(record_refs_to_edges(oid, record).map_err(|e| (i, e))?);
+    }
+    for edge in edges.iter_mut().filter(|e| e.class.is_empty()) {
+        if let Some(class) = classes.get(&edge.target) {
+            edge.class.clone_from(class);
+        }
+    }
+    Ok(edges)
+}
+
+/// The objects reachable from a root, found by [`reachable_oids`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ReachableOids {
+    /// The oids of the reachable records, the root included.
+    pub oids: HashSet<u64>,
+    /// Referenced oids the loader had no record for, in the order found.
+    pub missing: Vec<u64>,
+}
+
+/// Load the record of `root` with `load`, then every record it references,
+/// transitively, and return the oids reached.
+///
+/// `load` returns `None` for an oid without a record (a dangling
+/// reference). References are followed like [`record_refs_to_edges`]
+/// finds them, weak references included; cross-database references are
+/// not. Each record is loaded once.
+///
+/// ```
+/// use std::collections::HashMap;
+/// use zodb_json_codec::{reachable_oids, CodecError};
+///
+/// // oid 0 references oid 7, which has no record
+/// let root = b"\x80\x03cmyapp\nFolder\nq\x00.\x80\x03}X\x05\x00\x00\x00child\
+///     C\x08\x00\x00\x00\x00\x00\x00\x00\x07cmyapp\nDoc\n\x86Qs.";
+/// let storage = HashMap::from([(0, root.to_vec())]);
+/// let found = reachable_oids(0, |oid| Ok::<_, CodecError>(storage.get(&oid).cloned()))?;
+/// assert_eq!(found.oids.len(), 1);
+/// assert_eq!(found.missing, [7]);
+/// # Ok::<(), CodecError>(())
+/// ```
+pub fn reachable_oids<E: From<CodecError>>(
+    root: u64,
+    mut load: impl FnMut(u64) -> Result<Option<Vec<u8>>, E>,
+) -> Result<ReachableOids, E> {
+    let mut found = ReachableOids::default();
+    let mut seen = HashSet::from([root]);
+    let mut pending = vec![root];
+    while let Some(oid) = pending.pop() {
+        let Some(record) = load(oid)? else {
+            found.missing.push(oid);
+            continue;
+        };
+        found.oids.insert(oid);
+        let (_class_val, state_val) = decode_zodb_pickles(&record)?;
+        for info in collect_refs_ex(&state_val) {
+            if info.database.is_some() {
+                continue;
+            }
+            if let Ok(target) = <[u8; 8]>::try_from(info.oid.as_slice()) {
+                let target = u64::from_be_bytes(target);
+                if seen.insert(target) {
+                    pending.push(target);
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Write `edges` as a Graphviz DOT digraph. Nodes are named by decimal
+/// oid; targets with a known class are labelled with it.
+///
+/// ```
+/// use zodb_json_codec::{record_refs_to_edges, write_edges_dot};
+///
+/// let record = b"\x80\x03cmyapp\nFolder\nq\x00.\x80\x03}X\x05\x00\x00\x00child\
+///     C\x08\x00\x00\x00\x00\x00\x00\x00\x07cmyapp\nDoc\n\x86Qs.";
+/// let mut dot = Vec::new();
+/// write_edges_dot(&record_refs_to_edges(1, record)?, &mut dot)?;
+/// assert_eq!(
+///     String::from_utf8(dot).unwrap(),
+///     "digraph refs {\n  7 [label=\"7\\nmyapp.Doc\"];\n  1 -> 7;\n}\n"
+/// );
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn write_edges_dot(edges: &[RefEdge], mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "digraph refs {{")?;
+    let mut labelled = HashSet::new();
+    for edge in edges {
+        if !edge.class.is_empty() && labelled.insert(edge.target) {
+            let class = edge.class.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(out, "  {} [label=\"{}\\n{class}\"];", edge.target, edge.target)?;
+        }
+    }
+    for edge in edges {
+        writeln!(out, "  {} -> {};", edge.source, edge.target)?;
+    }
+    writeln!(out, "}}")
+}
+
+/// Write `edges` as CSV with a `source,target,class` header.
+pub fn write_edges_csv(edges: &[RefEdge], mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "source,target,class")?;
+    for edge in edges {
+        let class = if edge.class.contains([',', '"', '\n']) {
+            format!("\"{}\"", edge.class.replace('"', "\"\""))
+        } else {
+            edge.class.clone()
+        };
+        writeln!(out, "{},{},{class}", edge.source, edge.target)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::encode_pickle;
+    use crate::types::PickleValue;
+
+    fn global(module: &str, name: &str) -> PickleValue {
+        PickleValue::Global {
+            module: module.into(),
+            name: name.into(),
+        }
+    }
+
+    fn oid(n: u64) -> PickleValue {
+        PickleValue::Bytes(n.to_be_bytes().to_vec())
+    }
+
+    fn record(class: &str, refs: Vec<PickleValue>) -> Vec<u8> {
+        let mut data = encode_pickle(&global("myapp", class)).unwrap();
+        data.extend(encode_pickle(&PickleValue::List(refs)).unwrap());
+        data
+    }
+
+    fn typed(n: u64, class: &str) -> PickleValue {
+        PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![oid(n), global("myapp", class)])))
+    }
+
+    fn edge(source: u64, target: u64, class: &str) -> RefEdge {
+        RefEdge {
+            source,
+            target,
+            class: class.into(),
+        }
+    }
+
+    #[test]
+    fn test_edges_deduplicated() {
+        let bare = PickleValue::PersistentRef(Box::new(oid(3)));
+        let data = record("Folder", vec![bare, typed(3, "Doc"), typed(2, "Doc")]);
+        let edges = record_refs_to_edges(1, &data).unwrap();
+        assert_eq!(edges, [edge(1, 3, "myapp.Doc"), edge(1, 2, "myapp.Doc")]);
+    }
+
+    #[test]
+    fn test_cross_database_skipped() {
+        let xdb = PickleValue::PersistentRef(Box::new(PickleValue::List(vec![
+            PickleValue::String("n".into()),
+            PickleValue::Tuple(vec![PickleValue::String("other".into()), oid(5)]),
+        ])));
+        let data = record("Folder", vec![xdb]);
+        assert!(record_refs_to_edges(1, &data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_batch_fills_classes() {
+        let weak = PickleValue::PersistentRef(Box::new(PickleValue::List(vec![oid(2)])));
+        let root = record("Root", vec![weak]);
+        let doc = record("Doc", vec![typed(0, "Root")]);
+        let edges = record_refs_to_edges_batch(&[(0, &root), (2, &doc)]).unwrap();
+        assert_eq!(edges, [edge(0, 2, "myapp.Doc"), edge(2, 0, "myapp.Root")]);
+        let (index, _) = record_refs_to_edges_batch(&[(0, &root), (2, &doc[..4])]).unwrap_err();
+        assert_eq!(index, 1);
+    }
+
+    #[test]
+    fn test_reachable_cycle() {
+        let weak = PickleValue::PersistentRef(Box::new(PickleValue::List(vec![oid(2)])));
+        let storage = HashMap::from([
+            (0, record("Root", vec![typed(1, "Doc"), typed(9, "Doc")])),
+            (1, record("Doc", vec![typed(0, "Root"), weak])),
+            (2, record("Doc", vec![])),
+            (3, record("Doc", vec![typed(0, "Root")])),
+        ]);
+        let mut loads = 0;
+        let found = reachable_oids(0, |oid| {
+            loads += 1;
+            Ok::<_, CodecError>(storage.get(&oid).cloned())
+        })
+        .unwrap();
+        assert_eq!(found.oids, HashSet::from([0, 1, 2]));
+        assert_eq!(found.missing, [9]);
+        assert_eq!(loads, 4);
+    }
+
+    #[test]
+    fn test_reachable_errors() {
+        let err = reachable_oids(0, |_| Ok::<_, CodecError>(Some(b"\x80\x03".to_vec()))).unwrap_err();
+        assert!(matches!(err.root(), CodecError::UnexpectedEof));
+        let err = reachable_oids(0, |_| Err::<Option<Vec<u8>>, _>(CodecError::InvalidData("gone".into())));
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_csv_quoting() {
+        let mut csv = Vec::new();
+        write_edges_csv(&[edge(1, 2, "a,b"), edge(1, 3, "")], &mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "source,target,class\n1,2,\"a,b\"\n1,3,\n");
+    }
+}
@@ -0,0 +1,793 @@
+//! Persistent reference scanning without decoding.
+//!
+//! Pack and GC pre-scans only need to know which objects a record points
+//! to. These functions walk the opcode stream with `skip_opcode` and look
+//! only at BINPERSID/PERSID, 8-byte strings and the stack and memo
+//! opcodes, so no strings, containers or `PickleValue` trees are ever
+//! built.
+//!
+//! A ZODB reference is pickled as `(oid, klass)` (or `oid`, or a
+//! `['w'|'m', (...)]` list for weak and cross-database references)
+//! followed by BINPERSID. In all of these forms the oid is the last 8-byte
+//! bytes value in the persistent id. Repeated oids may come from the memo
+//! (BINGET), so memoized 8-byte values are tracked as well.
+//!
+//! `collect_refs_ex` works on a decoded `PickleValue` instead, for callers
+//! that need every reference in full: oids of any width, the class hint
+//! and the database name of cross-database references.
+
+use std::collections::HashMap;
+
+use crate::error::CodecError;
+use crate::limits::LineLimits;
+use crate::opcodes::*;
+use crate::types::PickleValue;
+use crate::zodb::skip_opcode;
+
+/// Count the persistent references (BINPERSID and PERSID opcodes) in
+/// `data`, which may hold one pickle or a whole ZODB record.
+pub fn count_refs(data: &[u8]) -> Result<usize, CodecError> {
+    let mut count = 0;
+    let limits = LineLimits::default();
+    let mut pos = 0;
+    let mut op = STOP;
+    while pos < data.len() {
+        let next;
+        (op, next) = skip_opcode(data, pos, &limits)?;
+        if next > data.len() {
+            return Err(CodecError::UnexpectedEof);
+        }
+        if op == BINPERSID || op == PERSID {
+            count += 1;
+        }
+        pos = next;
+    }
+    if op != STOP {
+        return Err(CodecError::UnexpectedEof);
+    }
+    Ok(count)
+}
+
+/// Return whether `data` (one pickle or a whole ZODB record) contains a
+/// persistent reference to the 8-byte `oid`.
+///
+/// Each stack slot only remembers the oid its value would name as a
+/// persistent id: an 8-byte binary string, or the last such string in a
+/// tuple or list built from it. A BINPERSID matches when the slot it
+/// pops holds `oid`, a PERSID when its line is the 8 raw bytes of `oid`.
+pub fn has_ref_to(data: &[u8], oid: &[u8; 8]) -> Result<bool, CodecError> {
+    // Oids stored in the memo, by memo index; the memo is shared between
+    // the pickles of a record
+    let mut memo: HashMap<u32, [u8; 8]> = HashMap::new();
+    let mut next_memo: u32 = 0;
+    let mut stack: Vec<Option<[u8; 8]>> = Vec::new();
+    let mut marks: Vec<usize> = Vec::new();
+
+    let limits = LineLimits::default();
+    let mut pos = 0;
+    let mut op = STOP;
+    while pos < data.len() {
+        let next;
+        (op, next) = skip_opcode(data, pos, &limits)?;
+        if next > data.len() {
+            return Err(CodecError::UnexpectedEof);
+        }
+        let arg = &data[pos + 1..next];
+        match op {
+            SHORT_BINBYTES | SHORT_BINSTRING => stack.push(arg[1..].try_into().ok()),
+            BINBYTES | BINSTRING => stack.push(arg[4..].try_into().ok()),
+            BINPUT | LONG_BINPUT | MEMOIZE | PUT => {
+                let idx = match op {
+                    BINPUT => arg[0] as u32,
+                    LONG_BINPUT => u32::from_le_bytes(arg.try_into().unwrap()),
+                    PUT => text_index(arg)?,
+                    _ => next_memo,
+                };
+                next_memo = next_memo.max(idx.saturating_add(1));
+                match stack.last().copied().flatten() {
+                    Some(v) => memo.insert(idx, v),
+                    None => memo.remove(&idx),
+                };
+            }
+            BINGET | LONG_BINGET | GET => {
+                let idx = match op {
+                    BINGET => arg[0] as u32,
+                    LONG_BINGET => u32::from_le_bytes(arg.try_into().unwrap()),
+                    _ => text_index(arg)?,
+                };
+                stack.push(memo.get(&idx).copied());
+            }
+            BINPERSID => {
+                if stack.pop().flatten().as_ref() == Some(oid) {
+                    return Ok(true);
+                }
+                stack.push(None);
+            }
+            PERSID => {
+                if arg.strip_suffix(b"\n").is_some_and(|pid| pid == oid) {
+                    return Ok(true);
+                }
+                stack.push(None);
+            }
+            MARK => marks.push(stack.len()),
+            TUPLE | LIST => {
+                let items = pop_mark(&mut stack, &mut marks);
+                stack.push(items);
+            }
+            DICT | FROZENSET | INST | OBJ => {
+                pop_mark(&mut stack, &mut marks);
+                stack.push(None);
+            }
+            SETITEMS | ADDITEMS => {
+                pop_mark(&mut stack, &mut marks);
+            }
+            APPENDS => {
+                if let Some(v) = pop_mark(&mut stack, &mut marks) {
+                    if let Some(list) = stack.last_mut() {
+                        *list = Some(v);
+                    }
+                }
+            }
+            APPEND => {
+                if let Some(v) = stack.pop().flatten() {
+                    if let Some(list) = stack.last_mut() {
+                        *list = Some(v);
+                    }
+                }
+            }
+            TUPLE1 | TUPLE2 | TUPLE3 => {
+                let n = (op - TUPLE1 + 1) as usize;
+                let items = stack.split_off(stack.len().saturating_sub(n));
+                stack.push(items.into_iter().flatten().next_back());
+            }
+            POP => {
+                if marks.last() == Some(&stack.len()) {
+                    marks.pop();
+                } else {
+                    stack.pop();
+                }
+            }
+            DUP => stack.push(stack.last().copied().flatten()),
+            BUILD => {
+                stack.pop();
+            }
+            SETITEM => {
+                stack.truncate(stack.len().saturating_sub(2));
+            }
+            REDUCE | NEWOBJ | STACK_GLOBAL => {
+                stack.truncate(stack.len().saturating_sub(2));
+                stack.push(None);
+            }
+            NEWOBJ_EX => {
+                stack.truncate(stack.len().saturating_sub(3));
+                stack.push(None);
+            }
+            STOP => {
+                stack.clear();
+                marks.clear();
+            }
+            FRAME | PROTO | READONLY_BUFFER => {}
+            // Everything else pushes a value that is not an oid
+            _ => stack.push(None),
+        }
+        pos = next;
+    }
+    if op != STOP {
+        return Err(CodecError::UnexpectedEof);
+    }
+    Ok(false)
+}
+
+/// Pop the slots above the topmost MARK; returns the last oid among them.
+fn pop_mark(stack: &mut Vec<Option<[u8; 8]>>, marks: &mut Vec<usize>) -> Option<[u8; 8]> {
+    let start = marks.pop().unwrap_or(0).min(stack.len());
+    stack.drain(start..).flatten().next_back()
+}
+
+/// The memo index of a text-mode PUT or GET argument.
+fn text_index(arg: &[u8]) -> Result<u32, CodecError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .ok_or_else(|| CodecError::InvalidData("invalid memo index".to_string()))
+}
+
+/// A persistent reference found by [`collect_refs_ex`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistentRefInfo {
+    /// The referenced oid, whatever its width.
+    pub oid: Vec<u8>,
+    /// The `(module, name)` class hint, when the reference carries one.
+    pub class: Option<(String, String)>,
+    /// The database name of a cross-database reference.
+    pub database: Option<String>,
+}
+
+/// Collect every persistent reference in `val`, in pickle order.
+///
+/// Unlike the `refs` of `decode_zodb_record_for_pg`, which are 8-byte
+/// oids as integers, this keeps oids of any width and understands every
+/// persistent id `ZODB.serialize` writes: `oid`, `(oid, klass)`, the
+/// legacy weak `[oid]`, and `['w', (oid[, db])]`, `['n', (db, oid)]` and
+/// `['m', (db, oid, klass)]`. Python 2 byte-string tags and names are
+/// accepted. Persistent ids of any other shape are skipped.
+///
+/// ```
+/// use zodb_json_codec::{collect_refs_ex, PersistentRefInfo, PickleValue};
+///
+/// let weak = PickleValue::PersistentRef(Box::new(PickleValue::List(vec![
+///     PickleValue::String("w".into()),
+///     PickleValue::Tuple(vec![
+///         PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, 7]),
+///         PickleValue::String("catalog".into()),
+///     ]),
+/// ])));
+/// let refs = collect_refs_ex(&PickleValue::List(vec![weak]));
+/// assert_eq!(
+///     refs,
+///     [PersistentRefInfo {
+///         oid: vec![0, 0, 0, 0, 0, 0, 0, 7],
+///         class: None,
+///         database: Some("catalog".into()),
+///     }]
+/// );
+/// ```
+pub fn collect_refs_ex(val: &PickleValue) -> Vec<PersistentRefInfo> {
+    let mut refs = Vec::new();
+    collect_refs_ex_into(val, &mut refs);
+    refs
+}
+
+fn collect_refs_ex_into(val: &PickleValue, refs: &mut Vec<PersistentRefInfo>) {
+    match val {
+        PickleValue::PersistentRef(inner) => refs.extend(persistent_ref_info(inner)),
+        PickleValue::List(items)
+        | PickleValue::Tuple(items)
+        | PickleValue::Set(items)
+        | PickleValue::FrozenSet(items) => {
+            for item in items {
+                collect_refs_ex_into(item, refs);
+            }
+        }
+        PickleValue::Dict(pairs) => {
+            for (k, v) in pairs {
+                collect_refs_ex_into(k, refs);
+                collect_refs_ex_into(v, refs);
+            }
+        }
+        PickleValue::Instance(inst) => {
+            collect_refs_ex_into(&inst.state, refs);
+            collect_items(
+                inst.dict_items.as_ref().map(|v| v.as_slice()),
+                inst.list_items.as_ref().map(|v| v.as_slice()),
+                refs,
+            );
+        }
+        PickleValue::Reduce {
+            args,
+            dict_items,
+            list_items,
+            ..
+        } => {
+            collect_refs_ex_into(args, refs);
+            collect_items(
+                dict_items.as_ref().map(|v| v.as_slice()),
+                list_items.as_ref().map(|v| v.as_slice()),
+                refs,
+            );
+        }
+        PickleValue::NewObjEx { args, kwargs, .. } => {
+            collect_refs_ex_into(args, refs);
+            collect_refs_ex_into(kwargs, refs);
+        }
+        PickleValue::Shared { value, .. } => collect_refs_ex_into(value, refs),
+        _ => {}
+    }
+}
+
+fn collect_items(
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
+    list_items: Option<&[PickleValue]>,
+    refs: &mut Vec<PersistentRefInfo>,
+) {
+    for (k, v) in dict_items.unwrap_or_default() {
+        collect_refs_ex_into(k, refs);
+        collect_refs_ex_into(v, refs);
+    }
+    for item in list_items.unwrap_or_default() {
+        collect_refs_ex_into(item, refs);
+    }
+}
+
+/// Describe one persistent id, or `None` if it has no known shape.
+fn persistent_ref_info(pid: &PickleValue) -> Option<PersistentRefInfo> {
+    let info = |oid: &[u8], class, database| PersistentRefInfo {
+        oid: oid.to_vec(),
+        class,
+        database,
+    };
+    match pid {
+        PickleValue::Bytes(oid) => Some(info(oid, None, None)),
+        PickleValue::Tuple(items) => match items.as_slice() {
+            [PickleValue::Bytes(oid), klass] => Some(info(oid, class_hint(klass), None)),
+            _ => None,
+        },
+        PickleValue::List(items) => match items.as_slice() {
+            [PickleValue::Bytes(oid)] => Some(info(oid, None, None)),
+            [tag, PickleValue::Tuple(args)] => match (text(tag)?.as_str(), args.as_slice()) {
+                ("w", [PickleValue::Bytes(oid)]) => Some(info(oid, None, None)),
+                ("w", [PickleValue::Bytes(oid), db]) => Some(info(oid, None, Some(text(db)?))),
+                ("n", [db, PickleValue::Bytes(oid)]) => Some(info(oid, None, Some(text(db)?))),
+                ("m", [db, PickleValue::Bytes(oid), klass]) => {
+                    Some(info(oid, class_hint(klass), Some(text(db)?)))
+                }
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The `(module, name)` of a reference's class metadata: a class, a
+/// `(class, args)` pair, or a `(module, name)` pair of strings.
+fn class_hint(klass: &PickleValue) -> Option<(String, String)> {
+    match klass {
+        PickleValue::Global { module, name } => Some((module.clone(), name.clone())),
+        PickleValue::Tuple(items) => match items.as_slice() {
+            [PickleValue::Global { module, name }, _] => Some((module.clone(), name.clone())),
+            [module, name] => Some((text(module)?, text(name)?)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A tag or name written as `str`, or as a Python 2 byte string.
+fn text(val: &PickleValue) -> Option<String> {
+    match val {
+        PickleValue::String(s) => Some(s.to_string()),
+        PickleValue::Bytes(b) => Some(String::from_utf8_lossy(b).into_owned()),
+        _ => None,
+    }
+}
+
+#[cfg(any(test, feature = "python"))]
+/// Collect all persistent reference OIDs from a PickleValue tree.
+///
+/// OIDs are returned as i64 (big-endian interpretation of 8-byte ZODB OID).
+/// Cross-database refs (non-8-byte OIDs) are skipped.
+pub fn collect_refs_from_pickle_value(val: &PickleValue, refs: &mut Vec<i64>) {
+    match val {
+        PickleValue::PersistentRef(inner) => {
+            // Extract OID from Tuple([Bytes(oid), ...])
+            if let PickleValue::Tuple(items) = inner.as_ref() {
+                if let Some(PickleValue::Bytes(oid)) = items.first() {
+                    if oid.len() == 8 {
+                        if let Ok(arr) = <[u8; 8]>::try_from(oid.as_slice()) {
+                            refs.push(i64::from_be_bytes(arr));
+                        }
+                    }
+                }
+            }
+        }
+        PickleValue::List(items)
+        | PickleValue::Tuple(items)
+        | PickleValue::Set(items)
+        | PickleValue::FrozenSet(items) => {
+            for item in items {
+                collect_refs_from_pickle_value(item, refs);
+            }
+        }
+        PickleValue::Dict(pairs) => {
+            for (k, v) in pairs {
+                collect_refs_from_pickle_value(k, refs);
+                collect_refs_from_pickle_value(v, refs);
+            }
+        }
+        PickleValue::Instance(inst) => {
+            collect_refs_from_pickle_value(&inst.state, refs);
+            if let Some(pairs) = &inst.dict_items {
+                for (k, v) in pairs.iter() {
+                    collect_refs_from_pickle_value(k, refs);
+                    collect_refs_from_pickle_value(v, refs);
+                }
+            }
+            if let Some(items) = &inst.list_items {
+                for item in items.iter() {
+                    collect_refs_from_pickle_value(item, refs);
+                }
+            }
+        }
+        PickleValue::Reduce { args, dict_items, list_items, .. } => {
+            collect_refs_from_pickle_value(args, refs);
+            if let Some(pairs) = dict_items {
+                for (k, v) in pairs.iter() {
+                    collect_refs_from_pickle_value(k, refs);
+                    collect_refs_from_pickle_value(v, refs);
+                }
+            }
+            if let Some(items) = list_items {
+                for item in items.iter() {
+                    collect_refs_from_pickle_value(item, refs);
+                }
+            }
+        }
+        PickleValue::NewObjEx { args, kwargs, .. } => {
+            collect_refs_from_pickle_value(args, refs);
+            collect_refs_from_pickle_value(kwargs, refs);
+        }
+        PickleValue::Shared { value, .. } => collect_refs_from_pickle_value(value, refs),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::encode_pickle;
+    use crate::types::InstanceData;
+
+    const OID1: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+    const OID2: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 2];
+
+    fn pref(oid: [u8; 8]) -> PickleValue {
+        PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(oid.to_vec()),
+            PickleValue::Global {
+                module: "myapp".into(),
+                name: "Doc".into(),
+            },
+        ])))
+    }
+
+    #[test]
+    fn test_count_refs() {
+        let val = PickleValue::Dict(vec![
+            (PickleValue::String("a".into()), pref(OID1)),
+            (
+                PickleValue::String("b".into()),
+                PickleValue::List(vec![pref(OID2), pref(OID1)]),
+            ),
+        ]);
+        assert_eq!(count_refs(&encode_pickle(&val).unwrap()).unwrap(), 3);
+        let none = encode_pickle(&PickleValue::Bytes(OID1.to_vec())).unwrap();
+        assert_eq!(count_refs(&none).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_has_ref_to() {
+        let val = PickleValue::List(vec![pref(OID1)]);
+        let data = encode_pickle(&val).unwrap();
+        assert!(has_ref_to(&data, &OID1).unwrap());
+        assert!(!has_ref_to(&data, &OID2).unwrap());
+
+        // Python 2 records store oids as SHORT_BINSTRING
+        let mut data = vec![0x80, 2, b'U', 8];
+        data.extend_from_slice(&OID2);
+        data.extend_from_slice(&[b'N', 0x86, b'Q', b'.']);
+        assert!(has_ref_to(&data, &OID2).unwrap());
+    }
+
+    #[test]
+    fn test_plain_bytes_is_not_a_ref() {
+        let val = PickleValue::List(vec![PickleValue::Bytes(OID2.to_vec()), pref(OID1)]);
+        let data = encode_pickle(&val).unwrap();
+        assert!(!has_ref_to(&data, &OID2).unwrap());
+    }
+
+    #[test]
+    fn test_memoized_oid() {
+        // Python: oid = b'\0'*7 + b'\1'; refs to it twice, second via BINGET
+        // PROTO 3, EMPTY_LIST, MARK,
+        //   SHORT_BINBYTES oid, BINPUT 0, NONE, TUPLE2, BINPERSID,
+        //   BINGET 0, NONE, TUPLE2, BINPERSID,
+        // APPENDS, STOP
+        let mut data = vec![0x80, 3, b']', b'(', b'C', 8];
+        data.extend_from_slice(&OID1);
+        data.extend_from_slice(&[
+            b'q', 0, b'N', 0x86, b'Q', b'h', 0, b'N', 0x86, b'Q', b'e', b'.',
+        ]);
+        assert_eq!(count_refs(&data).unwrap(), 2);
+        assert!(has_ref_to(&data, &OID1).unwrap());
+
+        // OID1 is pushed, memoized and popped; only its BINGET is a reference
+        let mut data = vec![0x80, 3, b']', b'(', b'C', 8];
+        data.extend_from_slice(&OID1);
+        data.extend_from_slice(&[b'q', 0, b'0', b'C', 8]);
+        data.extend_from_slice(&OID2);
+        data.extend_from_slice(&[b'N', 0x86, b'Q', b'h', 0, b'N', 0x86, b'Q', b'e', b'.']);
+        assert!(has_ref_to(&data, &OID1).unwrap());
+    }
+
+    #[test]
+    fn test_shared_memo_class() {
+        // CPython record: the ref's class is a BINGET of the class pickle's
+        // memo entry, pushed after the oid
+        let data = b"\x80\x03ccollections\nOrderedDict\nq\x00N\x86q\x01.\
+            \x80\x03}q\x02X\x05\x00\x00\x00childq\x05C\x08\x00\x00\x00\x00\x00\x00\x00\x02q\x06\
+            h\x00\x86q\x07Qs.";
+        assert!(has_ref_to(data, &OID2).unwrap());
+        assert!(!has_ref_to(data, &OID1).unwrap());
+    }
+
+    #[test]
+    fn test_stale_oid_is_not_a_ref() {
+        // [OID2, persistent id from a memoized non-oid value]:
+        // PROTO 3, EMPTY_LIST, MARK, SHORT_BINBYTES OID2,
+        //   SHORT_BINUNICODE "x", BINPUT 0, POP, BINGET 0, BINPERSID,
+        // APPENDS, STOP
+        let mut data = vec![0x80, 3, b']', b'(', b'C', 8];
+        data.extend_from_slice(&OID2);
+        data.extend_from_slice(&[0x8c, 1, b'x', b'q', 0, b'0', b'h', 0, b'Q', b'e', b'.']);
+        assert_eq!(count_refs(&data).unwrap(), 1);
+        assert!(!has_ref_to(&data, &OID2).unwrap());
+
+        // An oid followed by an integer persistent id
+        let mut data = vec![0x80, 3, b']', b'(', b'C', 8];
+        data.extend_from_slice(&OID2);
+        data.extend_from_slice(&[b'K', 5, b'Q', b'e', b'.']);
+        assert!(!has_ref_to(&data, &OID2).unwrap());
+
+        // An oid inside a dict that is the persistent id's sibling
+        let mut data = vec![0x80, 3, b'(', b'}', b'C', 1, b'k', b'C', 8];
+        data.extend_from_slice(&OID2);
+        data.extend_from_slice(&[b's', b'C', 8]);
+        data.extend_from_slice(&OID1);
+        data.extend_from_slice(b"Qt.");
+        assert!(has_ref_to(&data, &OID1).unwrap());
+        assert!(!has_ref_to(&data, &OID2).unwrap());
+    }
+
+    #[test]
+    fn test_weak_ref_list() {
+        // ['w', (OID1,)] built with EMPTY_LIST and APPENDS
+        let mut data = vec![0x80, 3, b']', b'(', 0x8c, 1, b'w', b'C', 8];
+        data.extend_from_slice(&OID1);
+        data.extend_from_slice(&[0x85, b'e', b'Q', b'.']);
+        assert!(has_ref_to(&data, &OID1).unwrap());
+    }
+
+    #[test]
+    fn test_text_mode_memo() {
+        // Protocol 1 oid, memoized with a text PUT and read back with GET:
+        // (MARK, SHORT_BINSTRING OID1, PUT 7, NONE, TUPLE2, BINPERSID,
+        //  GET 7, NONE, TUPLE2, BINPERSID, LIST, STOP)
+        let mut data = vec![b'(', b'U', 8];
+        data.extend_from_slice(&OID1);
+        data.extend_from_slice(b"p7\nN\x86Qg7\nN\x86Ql.");
+        assert_eq!(count_refs(&data).unwrap(), 2);
+        assert!(has_ref_to(&data, &OID1).unwrap());
+
+        // Only the GET is a reference
+        let mut data = vec![b'(', b'U', 8];
+        data.extend_from_slice(&OID1);
+        data.extend_from_slice(b"p7\n0g7\nQl.");
+        assert!(has_ref_to(&data, &OID1).unwrap());
+        let mut data = vec![b'(', b'U', 8];
+        data.extend_from_slice(&OID1);
+        data.extend_from_slice(b"p7\n0NQl.");
+        assert!(!has_ref_to(&data, &OID1).unwrap());
+        assert!(has_ref_to(b"(g\nl.", &OID1).is_err());
+    }
+
+    #[test]
+    fn test_persid() {
+        let mut data = vec![b'(', b'P'];
+        data.extend_from_slice(&OID2);
+        data.extend_from_slice(b"\nPfoo\nl.");
+        assert_eq!(count_refs(&data).unwrap(), 2);
+        assert!(has_ref_to(&data, &OID2).unwrap());
+        assert!(!has_ref_to(&data, &OID1).unwrap());
+    }
+
+    #[test]
+    fn test_collect_refs_ex() {
+        let s = |v: &str| PickleValue::String(v.into());
+        let b = |v: &[u8]| PickleValue::Bytes(v.to_vec());
+        let pid = |v| PickleValue::PersistentRef(Box::new(v));
+        let tagged = |tag, args| pid(PickleValue::List(vec![tag, PickleValue::Tuple(args)]));
+        let doc = || PickleValue::Global {
+            module: "myapp".into(),
+            name: "Doc".into(),
+        };
+        let info = |oid: &[u8], class: bool, database: Option<&str>| PersistentRefInfo {
+            oid: oid.to_vec(),
+            class: class.then(|| ("myapp".to_string(), "Doc".to_string())),
+            database: database.map(str::to_string),
+        };
+        let val = PickleValue::List(vec![
+            pref(OID1),
+            pid(b(&[1, 2, 3])),
+            pid(PickleValue::List(vec![b(&OID2)])),
+            tagged(s("w"), vec![b(&OID1)]),
+            tagged(s("w"), vec![b(&OID2), s("other")]),
+            tagged(s("n"), vec![s("other"), b(&OID1)]),
+            tagged(s("m"), vec![s("other"), b(&OID2), doc()]),
+            // Python 2 byte-string tag and database name
+            tagged(b(b"n"), vec![b(b"legacy"), b(&OID1)]),
+            // Class metadata as (class, args)
+            pid(PickleValue::Tuple(vec![
+                b(&OID2),
+                PickleValue::Tuple(vec![doc(), PickleValue::None]),
+            ])),
+            // Unknown shapes are skipped
+            tagged(s("x"), vec![b(&OID1)]),
+            pid(PickleValue::Int(5)),
+        ]);
+        assert_eq!(
+            collect_refs_ex(&val),
+            vec![
+                info(&OID1, true, None),
+                info(&[1, 2, 3], false, None),
+                info(&OID2, false, None),
+                info(&OID1, false, None),
+                info(&OID2, false, Some("other")),
+                info(&OID1, false, Some("other")),
+                info(&OID2, true, Some("other")),
+                info(&OID1, false, Some("legacy")),
+                info(&OID2, true, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_refs_ex_items() {
+        let mut inst = crate::types::InstanceData::new("myapp", "Doc", PickleValue::None);
+        inst.list_items = Some(Box::new(vec![pref(OID1)]));
+        let reduce = PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global {
+                module: "m".into(),
+                name: "f".into(),
+            }),
+            args: Box::new(PickleValue::Tuple(vec![pref(OID2)])),
+            dict_items: Some(Box::new(vec![(PickleValue::Int(1), pref(OID1))])),
+            list_items: None,
+            newobj: false,
+        };
+        let val = PickleValue::Dict(vec![(PickleValue::Instance(Box::new(inst)), reduce)]);
+        let oids: Vec<Vec<u8>> = collect_refs_ex(&val).into_iter().map(|r| r.oid).collect();
+        assert_eq!(oids, [OID1.to_vec(), OID2.to_vec(), OID1.to_vec()]);
+    }
+
+    #[test]
+    fn test_whole_record_and_truncated() {
+        let mut record = encode_pickle(&PickleValue::Tuple(vec![
+            PickleValue::String("myapp".into()),
+            PickleValue::String("Doc".into()),
+        ]))
+        .unwrap();
+        record.extend(encode_pickle(&pref(OID2)).unwrap());
+        assert_eq!(count_refs(&record).unwrap(), 1);
+        assert!(has_ref_to(&record, &OID2).unwrap());
+        assert!(count_refs(&record[..record.len() - 1]).is_err());
+        assert!(has_ref_to(&record[..record.len() - 1], &OID1).is_err());
+    }
+
+    #[test]
+    fn test_collect_refs_empty() {
+        let val = PickleValue::Dict(vec![]);
+        let mut refs = Vec::new();
+        collect_refs_from_pickle_value(&val, &mut refs);
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn test_collect_refs_persistent_ref() {
+        // OID = 8 bytes big-endian for value 42
+        let oid = vec![0, 0, 0, 0, 0, 0, 0, 42];
+        let val = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(oid),
+            PickleValue::None,
+        ])));
+        let mut refs = Vec::new();
+        collect_refs_from_pickle_value(&val, &mut refs);
+        assert_eq!(refs, vec![42]);
+    }
+
+    #[test]
+    fn test_collect_refs_nested_in_dict() {
+        let oid1 = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        let oid2 = vec![0, 0, 0, 0, 0, 0, 0, 2];
+        let ref1 = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(oid1),
+            PickleValue::None,
+        ])));
+        let ref2 = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(oid2),
+            PickleValue::None,
+        ])));
+        let val = PickleValue::Dict(vec![
+            (PickleValue::String("a".into()), ref1),
+            (PickleValue::String("b".into()), ref2),
+        ]);
+        let mut refs = Vec::new();
+        collect_refs_from_pickle_value(&val, &mut refs);
+        assert_eq!(refs, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_collect_refs_in_list() {
+        let oid = vec![0, 0, 0, 0, 0, 0, 0, 99];
+        let pref = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(oid),
+            PickleValue::None,
+        ])));
+        let val = PickleValue::List(vec![
+            PickleValue::String("hello".into()),
+            pref,
+        ]);
+        let mut refs = Vec::new();
+        collect_refs_from_pickle_value(&val, &mut refs);
+        assert_eq!(refs, vec![99]);
+    }
+
+    #[test]
+    fn test_collect_refs_skips_short_oid() {
+        // Cross-database refs may have different OID lengths
+        let oid = vec![1, 2, 3];  // Not 8 bytes → skip
+        let val = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(oid),
+            PickleValue::None,
+        ])));
+        let mut refs = Vec::new();
+        collect_refs_from_pickle_value(&val, &mut refs);
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn test_collect_refs_in_instance() {
+        let oid = vec![0, 0, 0, 0, 0, 0, 0, 7];
+        let pref = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(oid),
+            PickleValue::None,
+        ])));
+        let val = PickleValue::Instance(Box::new(InstanceData {
+            module: "myapp".to_string(),
+            name: "Obj".to_string(),
+            state: Box::new(PickleValue::Dict(vec![
+                (PickleValue::String("ref".into()), pref),
+            ])),
+            dict_items: None,
+            list_items: None,
+        }));
+        let mut refs = Vec::new();
+        collect_refs_from_pickle_value(&val, &mut refs);
+        assert_eq!(refs, vec![7]);
+    }
+
+    #[test]
+    fn test_collect_refs_in_reduce() {
+        let oid = vec![0, 0, 0, 0, 0, 0, 0, 5];
+        let pref = PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(oid),
+            PickleValue::None,
+        ])));
+        let val = PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global {
+                module: "builtins".to_string(),
+                name: "set".to_string(),
+            }),
+            args: Box::new(PickleValue::Tuple(vec![
+                PickleValue::List(vec![pref]),
+            ])),
+            dict_items: None,
+            list_items: None,
+            newobj: false,
+        };
+        let mut refs = Vec::new();
+        collect_refs_from_pickle_value(&val, &mut refs);
+        assert_eq!(refs, vec![5]);
+    }
+
+    #[test]
+    fn test_collect_refs_no_refs() {
+        let val = PickleValue::Dict(vec![
+            (PickleValue::String("title".into()), PickleValue::String("Hello".into())),
+            (PickleValue::String("count".into()), PickleValue::Int(42)),
+        ]);
+        let mut refs = Vec::new();
+        collect_refs_from_pickle_value(&val, &mut refs);
+        assert!(refs.is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+//! Known types registered at runtime.
+//!
+//! The built-in handlers in `known_types.rs` cover the standard library,
+//! `persistent` and common Zope/Plone classes. Deployments with their own
+//! "transparent" value types register them here instead of waiting for a
+//! release. A registration maps a class to a marker with one of the
+//! declarative [`TypeSpec`] shapes:
+//!
+//! - `Arg`: `module.name(arg)` (REDUCE with one argument) ↔ `{"@marker": arg}`
+//! - `Args`: `module.name(*args)` ↔ `{"@marker": [args...]}`
+//! - `State`: an instance (NEWOBJ + BUILD) ↔ `{"@marker": state}`
+//!
+//! Built-in handlers take precedence, so a registration cannot change how
+//! an already known class is converted. Markers may not reuse a built-in
+//! marker key. No types are registered by default.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::error::CodecError;
+use crate::info;
+use crate::types::{InstanceData, PickleValue};
+
+/// How a registered class is pickled, and so how its marker is built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypeSpec {
+    /// REDUCE with a single argument; the marker holds the argument.
+    Arg,
+    /// REDUCE with any arguments; the marker holds the argument list.
+    Args,
+    /// Instance with a state; the marker holds the state.
+    State,
+}
+
+/// A class registered with [`register_type_handler`].
+#[derive(Debug)]
+pub(crate) struct TypeHandler {
+    pub module: String,
+    pub name: String,
+    pub marker: String,
+    pub spec: TypeSpec,
+}
+
+/// What a registered marker holds, borrowed from the pickle value.
+pub(crate) enum Payload<'a> {
+    One(&'a PickleValue),
+    Many(&'a [PickleValue]),
+}
+
+/// Fast path: false while nothing is registered.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static HANDLERS: RwLock<Vec<Arc<TypeHandler>>> = RwLock::new(Vec::new());
+
+/// Register `module.name` to be converted to and from `{marker: ...}`.
+///
+/// Registering a class again replaces its handler. Errors if `marker`
+/// does not start with `@`, is a built-in marker, or is registered for
+/// another class.
+pub fn register_type_handler(
+    module: &str,
+    name: &str,
+    marker: &str,
+    spec: TypeSpec,
+) -> Result<(), CodecError> {
+    if marker.len() < 2 || !marker.starts_with('@') {
+        return Err(CodecError::InvalidData(format!(
+            "marker must start with '@', got {marker:?}"
+        )));
+    }
+    if info::is_builtin_marker(marker) {
+        return Err(CodecError::InvalidData(format!(
+            "{marker} is a built-in marker"
+        )));
+    }
+    let mut handlers = HANDLERS.write().unwrap_or_else(|e| e.into_inner());
+    if let Some(other) = handlers
+        .iter()
+        .find(|h| h.marker == marker && (h.module != module || h.name != name))
+    {
+        return Err(CodecError::InvalidData(format!(
+            "{marker} is already registered for {}.{}",
+            other.module, other.name
+        )));
+    }
+    handlers.retain(|h| h.module != module || h.name != name);
+    handlers.push(Arc::new(TypeHandler {
+        module: module.to_string(),
+        name: name.to_string(),
+        marker: marker.to_string(),
+        spec,
+    }));
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Remove the handler of `module.name`. Returns whether one was registered.
+pub fn unregister_type_handler(module: &str, name: &str) -> bool {
+    let mut handlers = HANDLERS.write().unwrap_or_else(|e| e.into_inner());
+    let before = handlers.len();
+    handlers.retain(|h| h.module != module || h.name != name);
+    ACTIVE.store(!handlers.is_empty(), Ordering::Relaxed);
+    handlers.len() != before
+}
+
+fn find(pred: impl Fn(&TypeHandler) -> bool) -> Option<Arc<TypeHandler>> {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return None;
+    }
+    let handlers = HANDLERS.read().unwrap_or_else(|e| e.into_inner());
+    handlers.iter().find(|h| pred(h)).cloned()
+}
+
+/// The handler registered for a marker key, if any.
+pub(crate) fn for_marker(marker: &str) -> Option<Arc<TypeHandler>> {
+    find(|h| h.marker == marker)
+}
+
+/// Handler and marker payload of a `callable(*args)` REDUCE of a class
+/// registered as `Arg` or `Args`. REDUCEs followed by SETITEMS keep the
+/// generic form.
+pub(crate) fn reduce_payload<'a>(
+    module: &str,
+    name: &str,
+    args: &'a PickleValue,
+    dict_items: Option<&[(PickleValue, PickleValue)]>,
+) -> Option<(Arc<TypeHandler>, Payload<'a>)> {
+    if dict_items.is_some() {
+        return None;
+    }
+    let PickleValue::Tuple(items) = args else {
+        return None;
+    };
+    let handler = find(|h| h.module == module && h.name == name)?;
+    let payload = match (handler.spec, items.as_slice()) {
+        (TypeSpec::Arg, [arg]) => Payload::One(arg),
+        (TypeSpec::Args, items) => Payload::Many(items),
+        _ => return None,
+    };
+    Some((handler, payload))
+}
+
+/// Handler of an instance of a class registered as `State`.
+pub(crate) fn instance_handler(module: &str, name: &str) -> Option<Arc<TypeHandler>> {
+    find(|h| h.spec == TypeSpec::State && h.module == module && h.name == name)
+}
+
+impl TypeHandler {
+    /// The pickle value for the converted payload of this handler's marker.
+    pub(crate) fn build(&self, value: PickleValue) -> Result<PickleValue, CodecError> {
+        let callable = || {
+            Box::new(PickleValue::Global {
+                module: self.module.clone(),
+                name: self.name.clone(),
+            })
+        };
+        let args = match (self.spec, value) {
+            (TypeSpec::State, state) => {
+                return Ok(PickleValue::Instance(Box::new(InstanceData::new(
+                    self.module.as_str(),
+                    self.name.as_str(),
+                    state,
+                ))))
+            }
+            (TypeSpec::Arg, arg) => vec![arg],
+            (TypeSpec::Args, PickleValue::List(args)) => args,
+            (TypeSpec::Args, _) => {
+                return Err(CodecError::InvalidData(format!(
+                    "{} must hold a list of arguments",
+                    self.marker
+                )))
+            }
+        };
+        Ok(PickleValue::Reduce {
+            callable: callable(),
+            args: Box::new(PickleValue::Tuple(args)),
+            dict_items: None,
+            list_items: None,
+            newobj: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{json_to_pickle_value, pickle_value_to_json};
+    use serde_json::json;
+
+    fn reduce(module: &str, name: &str, args: Vec<PickleValue>) -> PickleValue {
+        PickleValue::Reduce {
+            callable: Box::new(PickleValue::Global {
+                module: module.into(),
+                name: name.into(),
+            }),
+            args: Box::new(PickleValue::Tuple(args)),
+            dict_items: None,
+            list_items: None,
+            newobj: false,
+        }
+    }
+
+    #[test]
+    fn test_registered_types_roundtrip() {
+        register_type_handler("regtest.units", "Quantity", "@regtest_qty", TypeSpec::Args).unwrap();
+        register_type_handler("regtest.ids", "Sku", "@regtest_sku", TypeSpec::Arg).unwrap();
+        register_type_handler("regtest.geo", "Point", "@regtest_pt", TypeSpec::State).unwrap();
+
+        let qty = reduce(
+            "regtest.units",
+            "Quantity",
+            vec![PickleValue::Float(1.5), PickleValue::String("kg".into())],
+        );
+        let sku = reduce(
+            "regtest.ids",
+            "Sku",
+            vec![PickleValue::String("A-1".into())],
+        );
+        let point = PickleValue::Instance(Box::new(InstanceData::new(
+            "regtest.geo",
+            "Point",
+            PickleValue::Dict(vec![(PickleValue::String("x".into()), PickleValue::Int(3))]),
+        )));
+        for (val, expected) in [
+            (&qty, json!({"@regtest_qty": [1.5, "kg"]})),
+            (&sku, json!({"@regtest_sku": "A-1"})),
+            (&point, json!({"@regtest_pt": {"x": 3}})),
+        ] {
+            let json = pickle_value_to_json(val).unwrap();
+            assert_eq!(json, expected);
+            assert_eq!(&json_to_pickle_value(&json).unwrap(), val);
+            let pg = crate::json::pickle_value_to_json_string_pg(val, "", "").unwrap();
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&pg).unwrap(),
+                expected
+            );
+        }
+
+        // Arity mismatch keeps the generic form
+        let two = reduce(
+            "regtest.ids",
+            "Sku",
+            vec![PickleValue::None, PickleValue::None],
+        );
+        assert!(pickle_value_to_json(&two).unwrap().get("@reduce").is_some());
+        let err = json_to_pickle_value(&json!({"@regtest_qty": 1})).unwrap_err();
+        assert!(err.to_string().contains("list of arguments"), "{err}");
+
+        assert!(unregister_type_handler("regtest.ids", "Sku"));
+        assert!(!unregister_type_handler("regtest.ids", "Sku"));
+        assert!(pickle_value_to_json(&sku).unwrap().get("@reduce").is_some());
+        unregister_type_handler("regtest.units", "Quantity");
+        unregister_type_handler("regtest.geo", "Point");
+    }
+
+    #[test]
+    fn test_registration_errors() {
+        for marker in ["regtest", "@", "@dt", "@ref"] {
+            assert!(register_type_handler("regtest.err", "A", marker, TypeSpec::Arg).is_err());
+        }
+        register_type_handler("regtest.err", "A", "@regtest_a", TypeSpec::Arg).unwrap();
+        let err =
+            register_type_handler("regtest.err", "B", "@regtest_a", TypeSpec::Arg).unwrap_err();
+        assert!(err.to_string().contains("regtest.err.A"), "{err}");
+        // Re-registering the same class replaces it
+        register_type_handler("regtest.err", "A", "@regtest_a", TypeSpec::Args).unwrap();
+        assert_eq!(for_marker("@regtest_a").unwrap().spec, TypeSpec::Args);
+        unregister_type_handler("regtest.err", "A");
+    }
+}
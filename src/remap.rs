@@ -0,0 +1,476 @@
+//! OID remapping of ZODB records.
+//!
+//! Storage resharding and zodbconvert-style migrations renumber objects,
+//! so every persistent reference in every record must be rewritten through
+//! an old → new OID mapping. `remap_record` does this for one record on the
+//! `PickleValue` AST; `remap_storage` streams a whole storage through it
+//! with a memory-mapped mapping file, so neither the records nor the
+//! mapping have to fit in memory.
+//!
+//! Only references into the same database are rewritten: `(oid, klass)`
+//! tuples, bare oids and same-database weak references (`['w', (oid,)]`).
+//! Cross-database references (`'m'`, `'n'`, and `['w', (oid, db)]`) point
+//! into another storage and are left alone.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::decode::decode_zodb_pickles;
+use crate::encode::encode_pickle;
+use crate::error::CodecError;
+use crate::refscan::count_refs;
+use crate::types::PickleValue;
+use crate::zodb::split_zodb_record;
+
+/// Size of one mapping file entry: old OID then new OID, 8 bytes each.
+pub const OID_MAPPING_ENTRY_SIZE: usize = 16;
+
+/// An old → new OID mapping backed by a file of fixed-size entries.
+///
+/// The file is a flat array of 16-byte entries, each the old OID followed
+/// by the new OID (both big-endian, as ZODB stores them), sorted by old
+/// OID with no duplicates. Lookups are binary searches over the mapped
+/// file, so a multi-GB mapping costs no heap memory.
+pub struct OidMapping {
+    data: MappingData,
+}
+
+enum MappingData {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl OidMapping {
+    /// Memory-map a mapping file and validate its layout.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CodecError> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is only read. Like any mmap, it is the
+        // caller's responsibility not to truncate the file while it is in
+        // use.
+        let mmap = unsafe { Mmap::map(&file)? };
+        Self::validated(MappingData::Mapped(mmap))
+    }
+
+    /// Build a mapping from an in-memory buffer in the file format.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, CodecError> {
+        Self::validated(MappingData::Owned(data))
+    }
+
+    fn validated(data: MappingData) -> Result<Self, CodecError> {
+        let mapping = OidMapping { data };
+        let bytes = mapping.bytes();
+        if !bytes.len().is_multiple_of(OID_MAPPING_ENTRY_SIZE) {
+            return Err(CodecError::InvalidData(format!(
+                "OID mapping size {} is not a multiple of {OID_MAPPING_ENTRY_SIZE}",
+                bytes.len()
+            )));
+        }
+        // Binary search needs strictly ascending old OIDs
+        let mut prev: Option<&[u8]> = None;
+        for (i, entry) in bytes.chunks_exact(OID_MAPPING_ENTRY_SIZE).enumerate() {
+            if prev.is_some_and(|p| p >= &entry[..8]) {
+                return Err(CodecError::InvalidData(format!(
+                    "OID mapping is not sorted by old OID at entry {i}"
+                )));
+            }
+            prev = Some(&entry[..8]);
+        }
+        Ok(mapping)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.data {
+            MappingData::Mapped(m) => m,
+            MappingData::Owned(v) => v,
+        }
+    }
+
+    /// Number of entries.
+    pub fn len(&self) -> usize {
+        self.bytes().len() / OID_MAPPING_ENTRY_SIZE
+    }
+
+    /// Whether the mapping has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The new OID for `oid`, if it is mapped.
+    pub fn get(&self, oid: &[u8; 8]) -> Option<[u8; 8]> {
+        let bytes = self.bytes();
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let entry = &bytes[mid * OID_MAPPING_ENTRY_SIZE..(mid + 1) * OID_MAPPING_ENTRY_SIZE];
+            match entry[..8].cmp(oid.as_slice()) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return entry[8..].try_into().ok(),
+            }
+        }
+        None
+    }
+}
+
+/// Rewrite the persistent references of one ZODB record through `lookup`.
+///
+/// OIDs for which `lookup` returns `None` are kept. A record without any
+/// rewritten reference is returned byte-for-byte; otherwise the class
+/// pickle is copied and the state pickle re-encoded.
+pub fn remap_record<F>(record: &[u8], lookup: F) -> Result<Vec<u8>, CodecError>
+where
+    F: Fn(&[u8; 8]) -> Option<[u8; 8]>,
+{
+    let (class_pickle, state_pickle) = split_zodb_record(record)?;
+    if count_refs(state_pickle)? == 0 {
+        return Ok(record.to_vec());
+    }
+    // The state pickle may refer to memo entries of the class pickle
+    let (_, mut state) = decode_zodb_pickles(record)?;
+    if !remap_value(&mut state, &lookup) {
+        return Ok(record.to_vec());
+    }
+    let state_bytes = encode_pickle(&state)?;
+    let mut out = Vec::with_capacity(class_pickle.len() + state_bytes.len());
+    out.extend_from_slice(class_pickle);
+    out.extend_from_slice(&state_bytes);
+    Ok(out)
+}
+
+/// Remap every same-database reference below `val`; returns whether
+/// anything changed.
+fn remap_value<F>(val: &mut PickleValue, lookup: &F) -> bool
+where
+    F: Fn(&[u8; 8]) -> Option<[u8; 8]>,
+{
+    match val {
+        PickleValue::PersistentRef(pid) => remap_pid(pid, lookup),
+        PickleValue::List(items)
+        | PickleValue::Tuple(items)
+        | PickleValue::Set(items)
+        | PickleValue::FrozenSet(items) => remap_all(items.iter_mut(), lookup),
+        PickleValue::Dict(pairs) => remap_pairs(pairs, lookup),
+        PickleValue::Instance(inst) => {
+            let mut changed = remap_value(&mut inst.state, lookup);
+            if let Some(pairs) = &mut inst.dict_items {
+                changed |= remap_pairs(pairs, lookup);
+            }
+            if let Some(items) = &mut inst.list_items {
+                changed |= remap_all(items.iter_mut(), lookup);
+            }
+            changed
+        }
+        PickleValue::Reduce {
+            args,
+            dict_items,
+            list_items,
+            ..
+        } => {
+            let mut changed = remap_value(args, lookup);
+            if let Some(pairs) = dict_items {
+                changed |= remap_pairs(pairs, lookup);
+            }
+            if let Some(items) = list_items {
+                changed |= remap_all(items.iter_mut(), lookup);
+            }
+            changed
+        }
+        PickleValue::NewObjEx { args, kwargs, .. } => {
+            remap_value(args, lookup) | remap_value(kwargs, lookup)
+        }
+        PickleValue::Shared { value, .. } => remap_value(value, lookup),
+        _ => false,
+    }
+}
+
+fn remap_all<'a, F>(items: impl Iterator<Item = &'a mut PickleValue>, lookup: &F) -> bool
+where
+    F: Fn(&[u8; 8]) -> Option<[u8; 8]>,
+{
+    items.fold(false, |changed, item| remap_value(item, lookup) | changed)
+}
+
+fn remap_pairs<F>(pairs: &mut [(PickleValue, PickleValue)], lookup: &F) -> bool
+where
+    F: Fn(&[u8; 8]) -> Option<[u8; 8]>,
+{
+    pairs.iter_mut().fold(false, |changed, (k, v)| {
+        remap_value(k, lookup) | remap_value(v, lookup) | changed
+    })
+}
+
+/// Remap the oid inside a persistent id, if it refers to this database.
+fn remap_pid<F>(pid: &mut PickleValue, lookup: &F) -> bool
+where
+    F: Fn(&[u8; 8]) -> Option<[u8; 8]>,
+{
+    let oid = match pid {
+        PickleValue::Bytes(oid) => oid,
+        PickleValue::Tuple(items) => match items.first_mut() {
+            Some(PickleValue::Bytes(oid)) => oid,
+            _ => return false,
+        },
+        PickleValue::List(items) => match items.as_mut_slice() {
+            [PickleValue::String(tag), PickleValue::Tuple(args)]
+                if &**tag == "w" && args.len() == 1 =>
+            {
+                match &mut args[0] {
+                    PickleValue::Bytes(oid) => oid,
+                    _ => return false,
+                }
+            }
+            _ => return false,
+        },
+        _ => return false,
+    };
+    let Ok(old) = <[u8; 8]>::try_from(oid.as_slice()) else {
+        return false;
+    };
+    match lookup(&old) {
+        Some(new) if new != old => {
+            oid.copy_from_slice(&new);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Append one remapped record to `out` in the stream format written by
+/// [`remap_storage`].
+pub(crate) fn write_remapped(
+    out: &mut Vec<u8>,
+    oid: &[u8; 8],
+    record: &[u8],
+    mapping: &OidMapping,
+) -> Result<(), CodecError> {
+    let new_oid = mapping.get(oid).unwrap_or(*oid);
+    let data = remap_record(record, |o| mapping.get(o))?;
+    let len = u32::try_from(data.len()).map_err(|_| {
+        CodecError::LimitExceeded(format!("record of {} bytes exceeds 4 GB", data.len()))
+    })?;
+    out.extend_from_slice(&new_oid);
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(&data);
+    Ok(())
+}
+
+/// Stream `(oid, record)` pairs through `mapping` into `out`.
+///
+/// Both the record's own OID and the references in its state are
+/// remapped; unmapped OIDs are kept. Each output record is written as the
+/// 8-byte OID, a 4-byte big-endian length and the record bytes. Only one
+/// record is held in memory at a time. Returns the number of records.
+pub fn remap_storage<I, W>(records: I, mapping: &OidMapping, out: &mut W) -> Result<usize, CodecError>
+where
+    I: IntoIterator<Item = Result<([u8; 8], Vec<u8>), CodecError>>,
+    W: Write,
+{
+    let mut buf = Vec::new();
+    let mut count = 0;
+    for item in records {
+        let (oid, record) = item?;
+        buf.clear();
+        write_remapped(&mut buf, &oid, &record, mapping)?;
+        out.write_all(&buf)?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oid(n: u64) -> [u8; 8] {
+        n.to_be_bytes()
+    }
+
+    fn mapping(pairs: &[(u64, u64)]) -> OidMapping {
+        let mut data = Vec::new();
+        for (old, new) in pairs {
+            data.extend_from_slice(&oid(*old));
+            data.extend_from_slice(&oid(*new));
+        }
+        OidMapping::from_bytes(data).unwrap()
+    }
+
+    fn pref(n: u64) -> PickleValue {
+        PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(oid(n).to_vec()),
+            PickleValue::Global {
+                module: "myapp".into(),
+                name: "Doc".into(),
+            },
+        ])))
+    }
+
+    fn record(state: PickleValue) -> Vec<u8> {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::String("myapp".into()),
+            PickleValue::String("Folder".into()),
+        ]);
+        let mut out = encode_pickle(&class).unwrap();
+        out.extend(encode_pickle(&state).unwrap());
+        out
+    }
+
+    fn state_of(record: &[u8]) -> PickleValue {
+        decode_zodb_pickles(record).unwrap().1
+    }
+
+    #[test]
+    fn test_mapping_lookup_and_validation() {
+        let m = mapping(&[(1, 10), (5, 50), (7, 70)]);
+        assert_eq!(m.len(), 3);
+        assert_eq!(m.get(&oid(5)), Some(oid(50)));
+        assert_eq!(m.get(&oid(7)), Some(oid(70)));
+        assert_eq!(m.get(&oid(6)), None);
+        assert!(OidMapping::from_bytes(vec![0; 15]).is_err());
+        let mut unsorted = Vec::new();
+        for n in [2u64, 20, 1, 10] {
+            unsorted.extend_from_slice(&oid(n));
+        }
+        assert!(OidMapping::from_bytes(unsorted).is_err());
+    }
+
+    #[test]
+    fn test_remap_record() {
+        let m = mapping(&[(1, 10), (2, 20)]);
+        let data = record(PickleValue::Dict(vec![(
+            PickleValue::String("items".into()),
+            PickleValue::List(vec![pref(1), pref(2), pref(3)]),
+        )]));
+        let out = remap_record(&data, |o| m.get(o)).unwrap();
+        assert_eq!(split_zodb_record(&out).unwrap().0, split_zodb_record(&data).unwrap().0);
+        assert_eq!(
+            state_of(&out),
+            PickleValue::Dict(vec![(
+                PickleValue::String("items".into()),
+                PickleValue::List(vec![pref(10), pref(20), pref(3)]),
+            )])
+        );
+    }
+
+    #[test]
+    fn test_remap_shared_memo_record() {
+        // Written by one CPython pickler, as ZODB does: the ref to oid 2 is
+        // (oid, OrderedDict) with the class a BINGET of the class pickle's
+        // memo entry (`h\x00`).
+        let data = b"\x80\x03ccollections\nOrderedDict\nq\x00N\x86q\x01.\
+            \x80\x03}q\x02(X\x05\x00\x00\x00titleq\x03X\x05\x00\x00\x00Helloq\x04\
+            X\x05\x00\x00\x00childq\x05C\x08\x00\x00\x00\x00\x00\x00\x00\x02q\x06h\x00\x86q\x07Qu.";
+        let m = mapping(&[(2, 20)]);
+        let out = remap_record(data, |o| m.get(o)).unwrap();
+        assert_eq!(split_zodb_record(&out).unwrap().0, split_zodb_record(data).unwrap().0);
+        let odict = PickleValue::Global {
+            module: "collections".into(),
+            name: "OrderedDict".into(),
+        };
+        assert_eq!(
+            state_of(&out),
+            PickleValue::Dict(vec![
+                (PickleValue::String("title".into()), PickleValue::String("Hello".into())),
+                (
+                    PickleValue::String("child".into()),
+                    PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+                        PickleValue::Bytes(oid(20).to_vec()),
+                        odict,
+                    ]))),
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_unchanged_record_is_byte_identical() {
+        let m = mapping(&[(1, 10)]);
+        let data = record(PickleValue::List(vec![pref(3)]));
+        assert_eq!(remap_record(&data, |o| m.get(o)).unwrap(), data);
+        let data = record(PickleValue::String("no refs".into()));
+        assert_eq!(remap_record(&data, |o| m.get(o)).unwrap(), data);
+    }
+
+    #[test]
+    fn test_weak_and_cross_database_refs() {
+        let m = mapping(&[(1, 10)]);
+        let weak = PickleValue::PersistentRef(Box::new(PickleValue::List(vec![
+            PickleValue::String("w".into()),
+            PickleValue::Tuple(vec![PickleValue::Bytes(oid(1).to_vec())]),
+        ])));
+        let cross = PickleValue::PersistentRef(Box::new(PickleValue::List(vec![
+            PickleValue::String("m".into()),
+            PickleValue::Tuple(vec![
+                PickleValue::String("other".into()),
+                PickleValue::Bytes(oid(1).to_vec()),
+                PickleValue::None,
+            ]),
+        ])));
+        let data = record(PickleValue::List(vec![weak, cross.clone()]));
+        let out = remap_record(&data, |o| m.get(o)).unwrap();
+        let PickleValue::List(items) = state_of(&out) else {
+            panic!("expected list");
+        };
+        assert_eq!(
+            items[0],
+            PickleValue::PersistentRef(Box::new(PickleValue::List(vec![
+                PickleValue::String("w".into()),
+                PickleValue::Tuple(vec![PickleValue::Bytes(oid(10).to_vec())]),
+            ])))
+        );
+        assert_eq!(items[1], cross);
+    }
+
+    #[test]
+    fn test_btree_children_and_next_link() {
+        let m = mapping(&[(1, 10), (2, 20), (4, 40)]);
+        // BTree: ((child, key, child), firstbucket)
+        let btree = PickleValue::Tuple(vec![
+            PickleValue::Tuple(vec![pref(1), PickleValue::String("m".into()), pref(2)]),
+            pref(1),
+        ]);
+        let out = remap_record(&record(btree), |o| m.get(o)).unwrap();
+        assert_eq!(
+            state_of(&out),
+            PickleValue::Tuple(vec![
+                PickleValue::Tuple(vec![pref(10), PickleValue::String("m".into()), pref(20)]),
+                pref(10),
+            ])
+        );
+        // Bucket: ((key, value, ...), next)
+        let bucket = PickleValue::Tuple(vec![
+            PickleValue::Tuple(vec![PickleValue::String("a".into()), pref(3)]),
+            pref(4),
+        ]);
+        let out = remap_record(&record(bucket), |o| m.get(o)).unwrap();
+        assert_eq!(
+            state_of(&out),
+            PickleValue::Tuple(vec![
+                PickleValue::Tuple(vec![PickleValue::String("a".into()), pref(3)]),
+                pref(40),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_remap_storage_stream() {
+        let m = mapping(&[(1, 10), (2, 20)]);
+        let records = vec![
+            Ok((oid(1), record(PickleValue::List(vec![pref(2)])))),
+            Ok((oid(3), record(PickleValue::None))),
+        ];
+        let mut out = Vec::new();
+        assert_eq!(remap_storage(records, &m, &mut out).unwrap(), 2);
+
+        assert_eq!(&out[..8], &oid(10));
+        let len = u32::from_be_bytes(out[8..12].try_into().unwrap()) as usize;
+        assert_eq!(state_of(&out[12..12 + len]), PickleValue::List(vec![pref(20)]));
+        let rest = &out[12 + len..];
+        assert_eq!(&rest[..8], &oid(3));
+        let len = u32::from_be_bytes(rest[8..12].try_into().unwrap()) as usize;
+        assert_eq!(rest.len(), 12 + len);
+    }
+}
@@ -0,0 +1,209 @@
+//! Class renaming (aliasing) for decoding and encoding.
+//!
+//! Migrations that move classes between packages need every class path
+//! in every record rewritten: instance classes, the callables of reduce
+//! values and the classes carried by persistent references. A rename
+//! table maps old class paths to new ones and is applied to every
+//! GLOBAL / STACK_GLOBAL the decoder reads and every global the encoder
+//! writes, so the JSON and the re-encoded pickles both use the new names.
+//!
+//! Entries rename one class (`("old.module", "Name")` →
+//! `("new.module", "Name")`) or a whole module, keeping the class names.
+//! A class entry wins over a module entry. Renames are not chained: the
+//! result of a rename is not looked up again.
+//!
+//! A decoding call takes its table as
+//! [`DecodeOptions::with_renames`](crate::DecodeOptions::with_renames);
+//! encodings use the table of [`with_encode_renames`]. No renames apply
+//! by default.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::types::PickleValue;
+
+/// Old → new class paths, applied when decoding or encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ClassRenames {
+    /// Renamed classes: `(module, name)` → `(module, name)`.
+    pub classes: HashMap<(String, String), (String, String)>,
+    /// Renamed modules: every class of the old module moves to the new one.
+    pub modules: HashMap<String, String>,
+}
+
+impl Default for ClassRenames {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClassRenames {
+    /// An empty table.
+    pub fn new() -> Self {
+        ClassRenames {
+            classes: HashMap::new(),
+            modules: HashMap::new(),
+        }
+    }
+
+    /// Rename the class `from` to `to`, both `(module, name)` pairs.
+    pub fn rename_class(
+        mut self,
+        from: (impl Into<String>, impl Into<String>),
+        to: (impl Into<String>, impl Into<String>),
+    ) -> Self {
+        self.classes
+            .insert((from.0.into(), from.1.into()), (to.0.into(), to.1.into()));
+        self
+    }
+
+    /// Move every class of module `from` to module `to`.
+    pub fn rename_module(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.modules.insert(from.into(), to.into());
+        self
+    }
+
+    /// The new `(module, name)` of the class `module.name`, or `None` if
+    /// it is not renamed.
+    pub fn lookup(&self, module: &str, name: &str) -> Option<(String, String)> {
+        if !self.classes.is_empty() {
+            if let Some(to) = self.classes.get(&(module.to_string(), name.to_string())) {
+                return Some(to.clone());
+            }
+        }
+        self.modules
+            .get(module)
+            .map(|to| (to.clone(), name.to_string()))
+    }
+}
+
+/// Rename the `(module, name)` strings of a ZODB class pickle, the
+/// `((module, name), None)` and `(module, name)` forms. Class objects are
+/// renamed by the decoder like any other global.
+pub(crate) fn rename_class_pickle(renames: &ClassRenames, val: &mut PickleValue) {
+    let PickleValue::Tuple(items) = val else {
+        return;
+    };
+    let pair = match items.as_mut_slice() {
+        [PickleValue::Tuple(inner), _] if inner.len() == 2 => inner.as_mut_slice(),
+        pair @ [PickleValue::String(_) | PickleValue::Bytes(_), _] => pair,
+        _ => return,
+    };
+    let text = |v: &PickleValue| match v {
+        PickleValue::String(s) => Some(s.to_string()),
+        PickleValue::Bytes(b) => String::from_utf8(b.clone()).ok(),
+        _ => None,
+    };
+    let (Some(module), Some(name)) = (text(&pair[0]), text(&pair[1])) else {
+        return;
+    };
+    if let Some((module, name)) = renames.lookup(&module, &name) {
+        pair[0] = PickleValue::String(module.into());
+        pair[1] = PickleValue::String(name.into());
+    }
+}
+
+thread_local! {
+    /// The renames applied by this thread's encodings, if any.
+    static ENCODE_RENAMES: RefCell<Option<Arc<ClassRenames>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with the encodings it makes on this thread writing every
+/// global through `renames`. Decoding takes its renames from
+/// [`DecodeOptions::with_renames`](crate::DecodeOptions::with_renames).
+///
+/// ```
+/// use zodb_json_codec::{decode_pickle, encode_pickle, with_encode_renames};
+/// use zodb_json_codec::{ClassRenames, PickleValue};
+///
+/// let renames = ClassRenames::new().rename_module("old", "new");
+/// let global = PickleValue::Global { module: "old".into(), name: "Doc".into() };
+/// let data = with_encode_renames(renames, || encode_pickle(&global))?;
+/// let renamed = PickleValue::Global { module: "new".into(), name: "Doc".into() };
+/// assert_eq!(decode_pickle(&data)?, renamed);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn with_encode_renames<R>(renames: impl Into<Arc<ClassRenames>>, f: impl FnOnce() -> R) -> R {
+    let _scope = EncodeRenamesScope::enter(Some(renames.into()));
+    f()
+}
+
+/// Sets the encode renames of the current thread while alive.
+pub(crate) struct EncodeRenamesScope {
+    previous: Option<Arc<ClassRenames>>,
+}
+
+impl EncodeRenamesScope {
+    pub(crate) fn enter(renames: Option<Arc<ClassRenames>>) -> Self {
+        EncodeRenamesScope {
+            previous: ENCODE_RENAMES.with(|r| r.replace(renames)),
+        }
+    }
+}
+
+impl Drop for EncodeRenamesScope {
+    fn drop(&mut self) {
+        ENCODE_RENAMES.with(|r| *r.borrow_mut() = self.previous.take());
+    }
+}
+
+#[cfg(feature = "python")]
+/// Whether renames apply to this thread's encodings.
+pub(crate) fn encoding() -> bool {
+    ENCODE_RENAMES.with(|r| r.borrow().is_some())
+}
+
+/// The new name of a global about to be encoded, if it is renamed.
+#[inline]
+pub(crate) fn encode_rename(module: &str, name: &str) -> Option<(String, String)> {
+    ENCODE_RENAMES.with(|r| r.borrow().as_ref()?.lookup(module, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let r = ClassRenames::new()
+            .rename_class(("Products.Archetypes", "Foo"), ("plone.app", "Foo"))
+            .rename_module("Products.Archetypes", "plone.archetypes")
+            .rename_module("plone.archetypes", "never.chained");
+        assert_eq!(
+            r.lookup("Products.Archetypes", "Foo"),
+            Some(("plone.app".into(), "Foo".into()))
+        );
+        assert_eq!(
+            r.lookup("Products.Archetypes", "Bar"),
+            Some(("plone.archetypes".into(), "Bar".into()))
+        );
+        assert_eq!(r.lookup("Products.Archetypes.sub", "Bar"), None);
+        assert_eq!(r.lookup("myapp", "Foo"), None);
+    }
+
+    #[test]
+    fn test_rename_class_pickle() {
+        let r = ClassRenames::new().rename_module("old", "new");
+        let s = |v: &str| PickleValue::String(v.into());
+        let mut nested = PickleValue::Tuple(vec![
+            PickleValue::Tuple(vec![s("old"), s("Doc")]),
+            PickleValue::None,
+        ]);
+        rename_class_pickle(&r, &mut nested);
+        assert_eq!(
+            nested,
+            PickleValue::Tuple(vec![
+                PickleValue::Tuple(vec![s("new"), s("Doc")]),
+                PickleValue::None
+            ])
+        );
+        let mut flat = PickleValue::Tuple(vec![PickleValue::Bytes(b"old".to_vec()), s("Doc")]);
+        rename_class_pickle(&r, &mut flat);
+        assert_eq!(flat, PickleValue::Tuple(vec![s("new"), s("Doc")]));
+        let mut other = PickleValue::Tuple(vec![s("other"), s("Doc")]);
+        rename_class_pickle(&r, &mut other);
+        assert_eq!(other, PickleValue::Tuple(vec![s("other"), s("Doc")]));
+    }
+}
@@ -0,0 +1,614 @@
+//! Shared and cyclic containers.
+//!
+//! Pickle's memo lets one container appear several times in an object
+//! graph, including inside itself (`l = []; l.append(l)`, an instance
+//! whose state refers back to the instance). The decoder used to copy memo
+//! entries at every `GET`, which turns a cycle into a truncated copy of the
+//! container as it was when the `GET` ran.
+//!
+//! A container that is referred to again is now wrapped in a
+//! `PickleValue::Shared { id, .. }` node at its first occurrence, and later
+//! occurrences become `PickleValue::BackRef(id)`. In JSON they are
+//! `{"@shared": [id, value]}` and `{"@backref": id}`; ids are numbered
+//! from 0 in document order. The encoders write a `PUT` right after the
+//! container is created and a `GET` for each back-reference, which is how
+//! CPython pickles such graphs.
+//!
+//! - Cycles (a back-reference inside the container it refers to) are
+//!   always kept: there is no finite copy of them.
+//! - Aliasing (the same container in two places, without a cycle) is kept
+//!   only with `DecodeOptions::shared_references` set. By default each
+//!   occurrence decodes to its own copy, as before.
+//!
+//! Only mutable containers are shared: lists, dicts, sets, instances and
+//! reduce results. Strings and tuples are immutable, so copies are exact.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::decode::MAX_MEMO_SIZE;
+use crate::encode::MAX_DEPTH;
+use crate::error::CodecError;
+use crate::types::PickleValue;
+
+/// Whether `val` is a mutable container, the only values worth sharing.
+#[inline]
+pub(crate) fn is_shareable(val: &PickleValue) -> bool {
+    matches!(
+        val,
+        PickleValue::List(_)
+            | PickleValue::Dict(_)
+            | PickleValue::Set(_)
+            | PickleValue::Instance(_)
+            | PickleValue::Reduce { .. }
+            | PickleValue::NewObjEx { .. }
+    )
+}
+
+/// Call `f` on the children of `val`, in the order the encoders write them.
+pub(crate) fn for_each_child<'a>(val: &'a PickleValue, f: &mut impl FnMut(&'a PickleValue)) {
+    match val {
+        PickleValue::List(items)
+        | PickleValue::Tuple(items)
+        | PickleValue::Set(items)
+        | PickleValue::FrozenSet(items) => items.iter().for_each(f),
+        PickleValue::Dict(pairs) => pairs.iter().for_each(|(k, v)| {
+            f(k);
+            f(v);
+        }),
+        PickleValue::Instance(inst) => {
+            f(&inst.state);
+            for (k, v) in inst.dict_items.iter().flat_map(|p| p.iter()) {
+                f(k);
+                f(v);
+            }
+            inst.list_items.iter().flat_map(|l| l.iter()).for_each(f);
+        }
+        PickleValue::Reduce {
+            callable,
+            args,
+            dict_items,
+            list_items,
+            ..
+        } => {
+            f(callable);
+            f(args);
+            for (k, v) in dict_items.iter().flat_map(|p| p.iter()) {
+                f(k);
+                f(v);
+            }
+            list_items.iter().flat_map(|l| l.iter()).for_each(f);
+        }
+        PickleValue::NewObjEx { cls, args, kwargs } => {
+            f(cls);
+            f(args);
+            f(kwargs);
+        }
+        PickleValue::PersistentRef(inner) | PickleValue::Shared { value: inner, .. } => f(inner),
+        _ => {}
+    }
+}
+
+/// Mutable counterpart of [`for_each_child`].
+pub(crate) fn for_each_child_mut(val: &mut PickleValue, f: &mut impl FnMut(&mut PickleValue)) {
+    match val {
+        PickleValue::List(items)
+        | PickleValue::Tuple(items)
+        | PickleValue::Set(items)
+        | PickleValue::FrozenSet(items) => items.iter_mut().for_each(f),
+        PickleValue::Dict(pairs) => pairs.iter_mut().for_each(|(k, v)| {
+            f(k);
+            f(v);
+        }),
+        PickleValue::Instance(inst) => {
+            f(&mut inst.state);
+            for (k, v) in inst.dict_items.iter_mut().flat_map(|p| p.iter_mut()) {
+                f(k);
+                f(v);
+            }
+            inst.list_items
+                .iter_mut()
+                .flat_map(|l| l.iter_mut())
+                .for_each(f);
+        }
+        PickleValue::Reduce {
+            callable,
+            args,
+            dict_items,
+            list_items,
+            ..
+        } => {
+            f(callable);
+            f(args);
+            for (k, v) in dict_items.iter_mut().flat_map(|p| p.iter_mut()) {
+                f(k);
+                f(v);
+            }
+            list_items.iter_mut().flat_map(|l| l.iter_mut()).for_each(f);
+        }
+        PickleValue::NewObjEx { cls, args, kwargs } => {
+            f(cls);
+            f(args);
+            f(kwargs);
+        }
+        PickleValue::PersistentRef(inner) | PickleValue::Shared { value: inner, .. } => f(inner),
+        _ => {}
+    }
+}
+
+/// Turn the provisional `Shared`/`BackRef` nodes left by the decoder into
+/// their final form.
+///
+/// The decoder wraps a container in `Shared` (keyed by memo index) when it
+/// leaves the stack and may have been referred to, and pushes a `BackRef`
+/// for each such reference. This pass keeps the cycles, and the aliases
+/// when `aliasing` is set, replaces every other back-reference with a copy
+/// of the container, and renumbers the ids in document order. `aliases`
+/// maps memo indices bound to the same container to the one used as id;
+/// `memo` supplies containers that are not part of `root`.
+pub(crate) fn resolve(
+    mut root: PickleValue,
+    memo: &[PickleValue],
+    aliasing: bool,
+    aliases: &HashMap<u32, u32>,
+) -> PickleValue {
+    let mut census = Census {
+        aliases,
+        ancestors: Vec::new(),
+        cyclic: HashSet::new(),
+        referenced: HashSet::new(),
+        defs: HashMap::new(),
+    };
+    census.walk(&root, 0);
+    let defs = census
+        .defs
+        .iter()
+        .filter(|(id, _)| census.referenced.contains(id))
+        .map(|(&id, &value)| (id, value.clone()))
+        .collect();
+    let mut keep = census.cyclic;
+    if aliasing {
+        keep.extend(census.referenced);
+    }
+    let mut resolver = Resolver {
+        memo,
+        aliases,
+        aliasing,
+        keep,
+        defs,
+        ids: HashMap::new(),
+        ancestors: Vec::new(),
+        emitted: HashSet::new(),
+        next: 0,
+    };
+    resolver.visit(&mut root, 0);
+    root
+}
+
+fn alias(aliases: &HashMap<u32, u32>, id: u32) -> u32 {
+    aliases.get(&id).copied().unwrap_or(id)
+}
+
+/// Which shared containers are referenced, and which are cyclic.
+struct Census<'a> {
+    aliases: &'a HashMap<u32, u32>,
+    ancestors: Vec<u32>,
+    cyclic: HashSet<u32>,
+    referenced: HashSet<u32>,
+    /// The first `Shared` node of each id.
+    defs: HashMap<u32, &'a PickleValue>,
+}
+
+impl<'a> Census<'a> {
+    fn walk(&mut self, val: &'a PickleValue, depth: usize) {
+        if depth > MAX_DEPTH {
+            return;
+        }
+        match val {
+            PickleValue::Shared { id, value } => {
+                self.defs.entry(*id).or_insert(value);
+                self.ancestors.push(*id);
+                self.walk(value, depth + 1);
+                self.ancestors.pop();
+            }
+            PickleValue::BackRef(id) => {
+                let id = alias(self.aliases, *id);
+                self.referenced.insert(id);
+                if self.ancestors.contains(&id) {
+                    self.cyclic.insert(id);
+                }
+            }
+            _ => for_each_child(val, &mut |child| self.walk(child, depth + 1)),
+        }
+    }
+}
+
+struct Resolver<'a> {
+    memo: &'a [PickleValue],
+    aliases: &'a HashMap<u32, u32>,
+    aliasing: bool,
+    /// Ids whose `Shared` node stays in the output.
+    keep: HashSet<u32>,
+    /// Contents of the referenced containers, for copies.
+    defs: HashMap<u32, PickleValue>,
+    /// Output id of each memo index in scope.
+    ids: HashMap<u32, u32>,
+    ancestors: Vec<u32>,
+    /// Ids whose `Shared` node was written (aliasing mode).
+    emitted: HashSet<u32>,
+    next: u32,
+}
+
+impl Resolver<'_> {
+    fn visit(&mut self, val: &mut PickleValue, depth: usize) {
+        if depth > MAX_DEPTH {
+            return;
+        }
+        match val {
+            PickleValue::Shared { id, value } => {
+                let memo_id = *id;
+                if !self.keep.contains(&memo_id) {
+                    *val = std::mem::replace(value.as_mut(), PickleValue::None);
+                    self.visit(val, depth);
+                    return;
+                }
+                if self.aliasing && self.emitted.contains(&memo_id) {
+                    *val = PickleValue::BackRef(self.ids[&memo_id]);
+                    return;
+                }
+                *id = self.next;
+                self.next += 1;
+                let outer = self.ids.insert(memo_id, *id);
+                self.emitted.insert(memo_id);
+                self.ancestors.push(memo_id);
+                self.visit(value, depth + 1);
+                self.ancestors.pop();
+                if !self.aliasing {
+                    // A copy of a cycle: references after it get their own
+                    match outer {
+                        Some(outer) => self.ids.insert(memo_id, outer),
+                        None => self.ids.remove(&memo_id),
+                    };
+                }
+            }
+            PickleValue::BackRef(id) => {
+                let memo_id = alias(self.aliases, *id);
+                if self.ancestors.contains(&memo_id)
+                    || (self.aliasing && self.emitted.contains(&memo_id))
+                {
+                    *id = self.ids[&memo_id];
+                    return;
+                }
+                *val = self.copy_of(memo_id);
+                self.visit(val, depth);
+            }
+            _ => for_each_child_mut(val, &mut |child| self.visit(child, depth + 1)),
+        }
+    }
+
+    /// A copy of the container with this memo id, to replace a reference.
+    fn copy_of(&mut self, memo_id: u32) -> PickleValue {
+        let value = match self.defs.get(&memo_id) {
+            Some(value) => value.clone(),
+            None => {
+                // Not in the output (popped, or from the class pickle of a
+                // record): keep it shared if it refers to itself
+                let value = self
+                    .memo
+                    .get(memo_id as usize)
+                    .cloned()
+                    .unwrap_or(PickleValue::None);
+                if refers_to(&value, memo_id, self.aliases, 0) {
+                    self.keep.insert(memo_id);
+                }
+                value
+            }
+        };
+        if self.keep.contains(&memo_id) {
+            PickleValue::Shared {
+                id: memo_id,
+                value: Box::new(value),
+            }
+        } else {
+            value
+        }
+    }
+}
+
+/// Whether `val` contains a back-reference to `memo_id`.
+fn refers_to(val: &PickleValue, memo_id: u32, aliases: &HashMap<u32, u32>, depth: usize) -> bool {
+    if depth > MAX_DEPTH {
+        return false;
+    }
+    if let PickleValue::BackRef(id) = val {
+        return alias(aliases, *id) == memo_id;
+    }
+    let mut found = false;
+    for_each_child(val, &mut |child| {
+        found = found || refers_to(child, memo_id, aliases, depth + 1);
+    });
+    found
+}
+
+/// `val` with each `Shared` node moved to the first place, in encoding
+/// order, where its id is used.
+///
+/// JSON objects come back with sorted keys, which can put a
+/// `{"@backref": id}` before the `{"@shared": [id, ...]}` it refers to;
+/// the encoders need the definition first. Trees that are already in
+/// order are returned as they are.
+pub(crate) fn in_definition_order(val: &PickleValue) -> Cow<'_, PickleValue> {
+    if !defined_after_use(val, &mut HashSet::new(), 0) {
+        return Cow::Borrowed(val);
+    }
+    let mut val = val.clone();
+    let mut values = HashMap::new();
+    take_shared(&mut val, &mut values, 0);
+    place_shared(&mut val, &mut values, 0);
+    Cow::Owned(val)
+}
+
+/// Whether a `Shared` node in `val` comes after a `BackRef` to it.
+fn defined_after_use(val: &PickleValue, used: &mut HashSet<u32>, depth: usize) -> bool {
+    match val {
+        _ if depth > MAX_DEPTH => return false,
+        PickleValue::BackRef(id) => {
+            used.insert(*id);
+        }
+        PickleValue::Shared { id, .. } if used.contains(id) => return true,
+        _ => {}
+    }
+    let mut found = false;
+    for_each_child(val, &mut |child| {
+        found = found || defined_after_use(child, used, depth + 1);
+    });
+    found
+}
+
+/// Replace every `Shared` node in `val` by a `BackRef`, collecting the
+/// values by id.
+fn take_shared(val: &mut PickleValue, values: &mut HashMap<u32, PickleValue>, depth: usize) {
+    if depth > MAX_DEPTH {
+        return;
+    }
+    if let PickleValue::Shared { id, .. } = val {
+        let id = *id;
+        if let PickleValue::Shared { value, .. } = std::mem::replace(val, PickleValue::BackRef(id))
+        {
+            let mut value = *value;
+            take_shared(&mut value, values, depth + 1);
+            values.insert(id, value);
+        }
+        return;
+    }
+    for_each_child_mut(val, &mut |child| take_shared(child, values, depth + 1));
+}
+
+/// Turn the first `BackRef` to each collected id back into its `Shared`
+/// node.
+fn place_shared(val: &mut PickleValue, values: &mut HashMap<u32, PickleValue>, depth: usize) {
+    if depth > MAX_DEPTH {
+        return;
+    }
+    if let PickleValue::BackRef(id) = val {
+        if let Some(value) = values.remove(id) {
+            *val = PickleValue::Shared {
+                id: *id,
+                value: Box::new(value),
+            };
+        }
+    }
+    for_each_child_mut(val, &mut |child| place_shared(child, values, depth + 1));
+}
+
+/// Memo indices of the `Shared` ids written so far by an encoder.
+#[derive(Debug, Default)]
+pub(crate) struct SharedIds {
+    indices: HashMap<u32, u32>,
+    /// Next memo index, for encoders that write no other memo entries.
+    next: u32,
+    /// Whether a `BackRef` was met before its `Shared` node.
+    unresolved: bool,
+}
+
+impl SharedIds {
+    pub(crate) fn define(&mut self, id: u32, index: u32) {
+        self.indices.insert(id, index);
+    }
+
+    /// Memo index of the `Shared` node with this id.
+    pub(crate) fn index_of(&mut self, id: u32) -> Result<u32, CodecError> {
+        let index = self.indices.get(&id).copied();
+        self.unresolved |= index.is_none();
+        index.ok_or_else(|| {
+            CodecError::InvalidData(format!("@backref {id} does not follow its @shared node"))
+        })
+    }
+
+    /// Take the next memo index.
+    pub(crate) fn next_index(&mut self) -> Result<u32, CodecError> {
+        let index = self.next;
+        if index as usize >= MAX_MEMO_SIZE {
+            return Err(memo_full());
+        }
+        self.next += 1;
+        Ok(index)
+    }
+}
+
+pub(crate) fn memo_full() -> CodecError {
+    CodecError::InvalidData(format!(
+        "more than {MAX_MEMO_SIZE} memo entries needed for @shared nodes"
+    ))
+}
+
+thread_local! {
+    static SCOPED_IDS: RefCell<Option<SharedIds>> = const { RefCell::new(None) };
+}
+
+#[cfg(any(test, feature = "python"))]
+/// Keeps one [`SharedIds`] for the current thread while alive.
+///
+/// The direct PyObject encoder in `pyconv.rs` hands marker dicts to
+/// `encode_value_into` one at a time; within a scope those calls number
+/// their memo entries consecutively and resolve each other's `@shared` ids.
+/// Nested scopes share the outer one.
+pub(crate) struct SharedIdsScope {
+    owner: bool,
+}
+
+#[cfg(any(test, feature = "python"))]
+impl SharedIdsScope {
+    pub(crate) fn enter() -> Self {
+        let owner = SCOPED_IDS.with(|ids| {
+            let mut ids = ids.borrow_mut();
+            let owner = ids.is_none();
+            if owner {
+                *ids = Some(SharedIds::default());
+            }
+            owner
+        });
+        SharedIdsScope { owner }
+    }
+
+    /// Whether a `BackRef` encoded in this scope came before its `Shared`
+    /// node, so the scope's piecewise encoding cannot resolve it.
+    #[cfg(feature = "python")]
+    pub(crate) fn unresolved_backref(&self) -> bool {
+        SCOPED_IDS.with(|ids| ids.borrow().as_ref().is_some_and(|ids| ids.unresolved))
+    }
+}
+
+#[cfg(any(test, feature = "python"))]
+impl Drop for SharedIdsScope {
+    fn drop(&mut self) {
+        if self.owner {
+            SCOPED_IDS.with(|ids| ids.borrow_mut().take());
+        }
+    }
+}
+
+/// Run `f` with the scope's ids, or with fresh ones outside a scope.
+pub(crate) fn with_scoped_ids<R>(f: impl FnOnce(&mut SharedIds) -> R) -> R {
+    let scoped = SCOPED_IDS.with(|ids| ids.borrow_mut().take());
+    let active = scoped.is_some();
+    let mut ids = scoped.unwrap_or_default();
+    let result = f(&mut ids);
+    if active {
+        SCOPED_IDS.with(|cell| *cell.borrow_mut() = Some(ids));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(items: Vec<PickleValue>) -> PickleValue {
+        PickleValue::List(items)
+    }
+
+    fn shared(id: u32, value: PickleValue) -> PickleValue {
+        PickleValue::Shared {
+            id,
+            value: Box::new(value),
+        }
+    }
+
+    #[test]
+    fn test_cycle_kept_and_renumbered() {
+        // [l] where l = [l]: memo index 3 becomes id 0
+        let root = list(vec![shared(3, list(vec![PickleValue::BackRef(3)]))]);
+        let resolved = resolve(root, &[], false, &HashMap::new());
+        assert_eq!(
+            resolved,
+            list(vec![shared(0, list(vec![PickleValue::BackRef(0)]))])
+        );
+    }
+
+    #[test]
+    fn test_alias_copied_by_default() {
+        let inner = list(vec![PickleValue::Int(1)]);
+        let root = list(vec![shared(1, inner.clone()), PickleValue::BackRef(1)]);
+        let resolved = resolve(root.clone(), &[], false, &HashMap::new());
+        assert_eq!(resolved, list(vec![inner.clone(), inner.clone()]));
+        let resolved = resolve(root, &[], true, &HashMap::new());
+        assert_eq!(
+            resolved,
+            list(vec![shared(0, inner), PickleValue::BackRef(0)])
+        );
+    }
+
+    #[test]
+    fn test_alias_of_cycle_copied() {
+        // [l, l] where l = [l]: without aliasing each occurrence is its own cycle
+        let l = shared(2, list(vec![PickleValue::BackRef(2)]));
+        let root = list(vec![l, PickleValue::BackRef(2)]);
+        let resolved = resolve(root, &[], false, &HashMap::new());
+        assert_eq!(
+            resolved,
+            list(vec![
+                shared(0, list(vec![PickleValue::BackRef(0)])),
+                shared(1, list(vec![PickleValue::BackRef(1)])),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_unreferenced_unwrapped_and_memo_fallback() {
+        let root = list(vec![shared(0, list(vec![])), PickleValue::BackRef(5)]);
+        let mut memo = vec![PickleValue::None; 6];
+        memo[5] = PickleValue::Dict(vec![]);
+        let resolved = resolve(root, &memo, false, &HashMap::new());
+        assert_eq!(
+            resolved,
+            list(vec![list(vec![]), PickleValue::Dict(vec![])])
+        );
+        memo[5] = list(vec![PickleValue::BackRef(5)]);
+        let resolved = resolve(
+            list(vec![PickleValue::BackRef(5)]),
+            &memo,
+            false,
+            &HashMap::new(),
+        );
+        assert_eq!(
+            resolved,
+            list(vec![shared(0, list(vec![PickleValue::BackRef(0)]))])
+        );
+    }
+
+    #[test]
+    fn test_definition_order() {
+        let int_list = || list(vec![PickleValue::Int(1)]);
+        let ordered = list(vec![shared(0, int_list()), PickleValue::BackRef(0)]);
+        assert!(matches!(in_definition_order(&ordered), Cow::Borrowed(_)));
+        // The back-reference first, and a nested shared node inside the value
+        let root = list(vec![
+            PickleValue::BackRef(0),
+            PickleValue::BackRef(1),
+            shared(0, list(vec![shared(1, int_list()), PickleValue::BackRef(0)])),
+        ]);
+        assert_eq!(
+            in_definition_order(&root).into_owned(),
+            list(vec![
+                shared(0, list(vec![shared(1, int_list()), PickleValue::BackRef(0)])),
+                PickleValue::BackRef(1),
+                PickleValue::BackRef(0),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_scoped_ids() {
+        let _scope = SharedIdsScope::enter();
+        with_scoped_ids(|ids| {
+            let index = ids.next_index().unwrap();
+            ids.define(7, index);
+        });
+        assert_eq!(with_scoped_ids(|ids| ids.index_of(7).unwrap()), 0);
+        assert_eq!(with_scoped_ids(|ids| ids.next_index().unwrap()), 1);
+        drop(_scope);
+        assert!(with_scoped_ids(|ids| ids.index_of(7)).is_err());
+    }
+}
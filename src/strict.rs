@@ -0,0 +1,196 @@
+//! Strict mode: reject values that only decode to generic fallbacks.
+//!
+//! A storage is fully representable in the queryable JSON schema when no
+//! value falls back to `@reduce` (a REDUCE without a typed marker, which
+//! includes datetimes with a timezone the codec does not know) or to `@pkl`
+//! (a pickle lenient decoding could not parse). [`check_strict`] walks a
+//! decoded value and fails on the first one, naming its class and its JSON
+//! path, so a migration can be certified record by record.
+
+use crate::error::{CodecError, PathSegment};
+use crate::json::pickle_value_to_json;
+use crate::known_types::try_reduce_to_typed_json;
+use crate::types::PickleValue;
+
+/// Check that `value` converts to JSON without `@reduce` or `@pkl`
+/// fallbacks.
+///
+/// Typed markers registered with `register_type_handler` count as known.
+/// The error is a [`CodecError::Strict`] located at the JSON path of the
+/// offending value.
+///
+/// ```
+/// use zodb_json_codec::{check_strict, decode_pickle, CodecError};
+///
+/// // {"items": [myapp.make()]}: myapp.make has no typed marker
+/// let data = b"\x80\x03}X\x05\x00\x00\x00items]cmyapp\nmake\n)Ras.";
+/// let err = check_strict(&decode_pickle(data)?).unwrap_err();
+/// assert!(matches!(err.root(), CodecError::Strict(_)));
+/// assert_eq!(
+///     err.to_string(),
+///     "strict mode: no typed marker for myapp.make, would be stored as @reduce at $.items[0]"
+/// );
+/// # Ok::<(), CodecError>(())
+/// ```
+pub fn check_strict(value: &PickleValue) -> Result<(), CodecError> {
+    match value {
+        PickleValue::List(items) => check_items(items),
+        PickleValue::Tuple(items) => check_items(items).map_err(in_key("@t")),
+        PickleValue::Set(items) => check_items(items).map_err(in_key("@set")),
+        PickleValue::FrozenSet(items) => check_items(items).map_err(in_key("@fset")),
+        PickleValue::Dict(pairs) => check_pairs(pairs),
+        PickleValue::Instance(inst) => {
+            let key = if inst.is_anonymous() { "@inst" } else { "@s" };
+            check_strict(&inst.state).map_err(in_key(key))?;
+            if let Some(pairs) = &inst.dict_items {
+                check_item_pairs(pairs).map_err(in_key("@items"))?;
+            }
+            if let Some(items) = &inst.list_items {
+                check_items(items).map_err(in_key("@appends"))?;
+            }
+            Ok(())
+        }
+        PickleValue::Reduce {
+            callable,
+            args,
+            dict_items,
+            ..
+        } => {
+            let items = dict_items.as_deref().map(Vec::as_slice);
+            if try_reduce_to_typed_json(callable, args, items, &pickle_value_to_json)?.is_some() {
+                return Ok(());
+            }
+            Err(CodecError::Strict(reduce_message(callable, args)))
+        }
+        PickleValue::NewObjEx { args, kwargs, .. } => {
+            check_strict(args).map_err(in_key("args"))?;
+            check_strict(kwargs).map_err(in_key("kwargs")).map_err(in_key("@newobj_ex"))
+        }
+        PickleValue::Shared { value, .. } => check_strict(value),
+        PickleValue::RawPickle(data) => Err(CodecError::Strict(format!(
+            "undecodable pickle of {} bytes, would be stored as @pkl",
+            data.len()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn reduce_message(callable: &PickleValue, args: &PickleValue) -> String {
+    match (callable, args) {
+        (PickleValue::Global { module, name }, PickleValue::Tuple(items))
+            if module == "datetime" && matches!(name.as_str(), "datetime" | "time") && items.len() == 2 =>
+        {
+            format!("{module}.{name} with an unknown timezone, would be stored as @reduce")
+        }
+        (PickleValue::Global { module, name }, _) => {
+            format!("no typed marker for {module}.{name}, would be stored as @reduce")
+        }
+        _ => "REDUCE with a non-global callable, would be stored as @reduce".to_string(),
+    }
+}
+
+fn in_key(key: &str) -> impl Fn(CodecError) -> CodecError + '_ {
+    move |e| e.in_path(PathSegment::Key(key))
+}
+
+fn check_items(items: &[PickleValue]) -> Result<(), CodecError> {
+    for (i, item) in items.iter().enumerate() {
+        check_strict(item).map_err(|e| e.in_path(PathSegment::Index(i)))?;
+    }
+    Ok(())
+}
+
+/// A dict: by key when all keys are strings, else as `@d` pairs.
+fn check_pairs(pairs: &[(PickleValue, PickleValue)]) -> Result<(), CodecError> {
+    if !pairs.iter().all(|(k, _)| matches!(k, PickleValue::String(_))) {
+        return check_item_pairs(pairs).map_err(in_key("@d"));
+    }
+    for (k, v) in pairs {
+        if let PickleValue::String(key) = k {
+            check_strict(v).map_err(|e| e.in_path(PathSegment::Key(key)))?;
+        }
+    }
+    Ok(())
+}
+
+/// `[key, value]` pairs, as in `@d` and `@items`.
+fn check_item_pairs(pairs: &[(PickleValue, PickleValue)]) -> Result<(), CodecError> {
+    for (i, (k, v)) in pairs.iter().enumerate() {
+        check_strict(k)
+            .map_err(|e| e.in_path(PathSegment::Index(0)))
+            .and_then(|()| check_strict(v).map_err(|e| e.in_path(PathSegment::Index(1))))
+            .map_err(|e| e.in_path(PathSegment::Index(i)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::InstanceData;
+
+    fn global(module: &str, name: &str) -> PickleValue {
+        PickleValue::Global {
+            module: module.into(),
+            name: name.into(),
+        }
+    }
+
+    fn reduce(callable: PickleValue, args: Vec<PickleValue>) -> PickleValue {
+        PickleValue::Reduce {
+            callable: Box::new(callable),
+            args: Box::new(PickleValue::Tuple(args)),
+            dict_items: None,
+            list_items: None,
+            newobj: false,
+        }
+    }
+
+    fn path(err: &CodecError) -> &str {
+        err.context().unwrap().path.as_deref().unwrap()
+    }
+
+    #[test]
+    fn test_known_types_pass() {
+        let date = reduce(global("datetime", "date"), vec![PickleValue::Bytes(vec![7, 233, 1, 2])]);
+        let value = PickleValue::Dict(vec![
+            (PickleValue::String("when".into()), date),
+            (PickleValue::String("tags".into()), PickleValue::Set(vec![PickleValue::Int(1)])),
+        ]);
+        check_strict(&value).unwrap();
+    }
+
+    #[test]
+    fn test_reduce_path() {
+        let value = PickleValue::Instance(Box::new(InstanceData::new(
+            "myapp",
+            "Doc",
+            PickleValue::Dict(vec![(
+                PickleValue::Int(1),
+                PickleValue::Tuple(vec![reduce(global("myapp", "make"), vec![])]),
+            )]),
+        )));
+        let err = check_strict(&value).unwrap_err();
+        assert_eq!(path(&err), r#"$["@s"]["@d"][0][1]["@t"][0]"#);
+        assert!(err.to_string().contains("no typed marker for myapp.make"));
+    }
+
+    #[test]
+    fn test_unknown_timezone() {
+        let tz = reduce(global("myapp.tz", "Zone"), vec![]);
+        let dt = reduce(
+            global("datetime", "datetime"),
+            vec![PickleValue::Bytes(vec![7, 233, 1, 2, 3, 4, 5, 0, 0, 0]), tz],
+        );
+        let err = check_strict(&PickleValue::List(vec![dt])).unwrap_err();
+        assert_eq!(path(&err), "$[0]");
+        assert!(err.to_string().contains("datetime.datetime with an unknown timezone"));
+    }
+
+    #[test]
+    fn test_raw_pickle() {
+        let err = check_strict(&PickleValue::RawPickle(b"\x80\x03a.".to_vec())).unwrap_err();
+        assert!(matches!(err.root(), CodecError::Strict(_)));
+        assert_eq!(err.to_string(), "strict mode: undecodable pickle of 4 bytes, would be stored as @pkl");
+    }
+}
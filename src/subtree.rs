@@ -0,0 +1,249 @@
+//! Subtree extraction and grafting on ZODB records.
+//!
+//! Both operations work purely on the `PickleValue` AST: the record is
+//! decoded with the memo its class and state pickles share, the value at a
+//! path is cut out or replaced, and the result is re-encoded. Persistent
+//! references stay `PersistentRef` nodes throughout, so they come out as the
+//! same BINPERSID references without ever loading the referenced objects.
+//! The class pickle of a grafted record is copied byte-for-byte; the new
+//! state pickle has its own memo and doesn't refer back into it.
+//!
+//! Paths are `/`-separated segments relative to the object state, e.g.
+//! `"title"` or `"items/2/name"`. Dict keys match string, bytes and integer
+//! keys; list, tuple and set elements are addressed by index. Instances
+//! (non-persistent objects pickled inline) are traversed through their
+//! state. `~1` and `~0` escape `/` and `~` inside a key, as in JSON Pointer.
+//! The empty path addresses the whole state.
+
+use crate::decode::{decode_pickle, decode_zodb_pickles};
+use crate::encode::encode_pickle;
+use crate::error::CodecError;
+use crate::types::PickleValue;
+use crate::zodb::split_zodb_record;
+
+/// Return a standalone pickle of the value at `path` in a record's state.
+pub fn extract_subtree(record: &[u8], path: &str) -> Result<Vec<u8>, CodecError> {
+    let (_, state) = decode_zodb_pickles(record)?;
+    let mut node = &state;
+    for seg in parse_path(path) {
+        node = child(node, &seg).ok_or_else(|| not_found(path, &seg))?;
+    }
+    encode_pickle(node)
+}
+
+/// Return a copy of `record` with the value at `path` replaced by the value
+/// of the standalone pickle `src`.
+///
+/// The last segment may name a dict key that doesn't exist yet (it is
+/// added) or be `-` on a list (the value is appended).
+pub fn graft_subtree(record: &[u8], path: &str, src: &[u8]) -> Result<Vec<u8>, CodecError> {
+    let (class_pickle, _) = split_zodb_record(record)?;
+    let (_, mut state) = decode_zodb_pickles(record)?;
+    let value = decode_pickle(src)?;
+
+    let segments = parse_path(path);
+    match segments.split_last() {
+        None => state = value,
+        Some((last, parents)) => {
+            let mut node = &mut state;
+            for seg in parents {
+                node = child_mut(node, seg).ok_or_else(|| not_found(path, seg))?;
+            }
+            set_child(node, last, value).map_err(|()| not_found(path, last))?;
+        }
+    }
+
+    let state_bytes = encode_pickle(&state)?;
+    let mut out = Vec::with_capacity(class_pickle.len() + state_bytes.len());
+    out.extend_from_slice(class_pickle);
+    out.extend_from_slice(&state_bytes);
+    Ok(out)
+}
+
+fn parse_path(path: &str) -> Vec<String> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    if path.is_empty() {
+        return Vec::new();
+    }
+    path.split('/')
+        .map(|s| s.replace("~1", "/").replace("~0", "~"))
+        .collect()
+}
+
+fn not_found(path: &str, seg: &str) -> CodecError {
+    CodecError::InvalidData(format!("path {path:?}: segment {seg:?} not found"))
+}
+
+fn key_matches(key: &PickleValue, seg: &str) -> bool {
+    match key {
+        PickleValue::String(s) => **s == *seg,
+        PickleValue::Bytes(b) => b == seg.as_bytes(),
+        PickleValue::Int(i) => seg.parse::<i64>() == Ok(*i),
+        _ => false,
+    }
+}
+
+fn child<'a>(node: &'a PickleValue, seg: &str) -> Option<&'a PickleValue> {
+    match node {
+        PickleValue::Dict(pairs) => pairs.iter().find(|(k, _)| key_matches(k, seg)).map(|(_, v)| v),
+        PickleValue::List(items)
+        | PickleValue::Tuple(items)
+        | PickleValue::Set(items)
+        | PickleValue::FrozenSet(items) => items.get(seg.parse::<usize>().ok()?),
+        PickleValue::Instance(inst) => child(&inst.state, seg),
+        PickleValue::Shared { value, .. } => child(value, seg),
+        _ => None,
+    }
+}
+
+fn child_mut<'a>(node: &'a mut PickleValue, seg: &str) -> Option<&'a mut PickleValue> {
+    match node {
+        PickleValue::Dict(pairs) => pairs
+            .iter_mut()
+            .find(|(k, _)| key_matches(k, seg))
+            .map(|(_, v)| v),
+        PickleValue::List(items)
+        | PickleValue::Tuple(items)
+        | PickleValue::Set(items)
+        | PickleValue::FrozenSet(items) => items.get_mut(seg.parse::<usize>().ok()?),
+        PickleValue::Instance(inst) => child_mut(&mut inst.state, seg),
+        PickleValue::Shared { value, .. } => child_mut(value, seg),
+        _ => None,
+    }
+}
+
+fn set_child(node: &mut PickleValue, seg: &str, value: PickleValue) -> Result<(), ()> {
+    match node {
+        PickleValue::Dict(pairs) => {
+            match pairs.iter_mut().find(|(k, _)| key_matches(k, seg)) {
+                Some((_, v)) => *v = value,
+                None => pairs.push((PickleValue::String(seg.into()), value)),
+            }
+            Ok(())
+        }
+        PickleValue::List(items) if seg == "-" => {
+            items.push(value);
+            Ok(())
+        }
+        PickleValue::Instance(inst) => set_child(&mut inst.state, seg, value),
+        PickleValue::Shared { value: shared, .. } => set_child(shared, seg, value),
+        _ => {
+            *child_mut(node, seg).ok_or(())? = value;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(state: PickleValue) -> Vec<u8> {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::String("myapp".into()),
+            PickleValue::String("Doc".into()),
+        ]);
+        let mut out = encode_pickle(&class).unwrap();
+        out.extend(encode_pickle(&state).unwrap());
+        out
+    }
+
+    fn pref(oid: u8) -> PickleValue {
+        PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, oid]),
+            PickleValue::None,
+        ])))
+    }
+
+    fn sample_state() -> PickleValue {
+        PickleValue::Dict(vec![
+            (PickleValue::String("title".into()), PickleValue::String("Hello".into())),
+            (
+                PickleValue::String("refs".into()),
+                PickleValue::List(vec![pref(1), pref(2)]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_extract_preserves_persistent_refs() {
+        let data = record(sample_state());
+        let sub = decode_pickle(&extract_subtree(&data, "refs").unwrap()).unwrap();
+        assert_eq!(sub, PickleValue::List(vec![pref(1), pref(2)]));
+        let one = decode_pickle(&extract_subtree(&data, "/refs/1").unwrap()).unwrap();
+        assert_eq!(one, pref(2));
+    }
+
+    #[test]
+    fn test_extract_whole_state_and_missing_path() {
+        let data = record(sample_state());
+        let all = decode_pickle(&extract_subtree(&data, "").unwrap()).unwrap();
+        assert_eq!(all, sample_state());
+        let err = extract_subtree(&data, "refs/5").unwrap_err();
+        assert!(err.to_string().contains("\"5\" not found"), "{err}");
+    }
+
+    #[test]
+    fn test_graft_replace_add_and_append() {
+        let data = record(sample_state());
+        let src = encode_pickle(&pref(3)).unwrap();
+
+        let out = graft_subtree(&data, "refs/0", &src).unwrap();
+        let (class, state) = split_zodb_record(&out).unwrap();
+        assert_eq!(class, split_zodb_record(&data).unwrap().0);
+        let state = decode_pickle(state).unwrap();
+        assert_eq!(child(&state, "refs"), Some(&PickleValue::List(vec![pref(3), pref(2)])));
+
+        let out = graft_subtree(&data, "refs/-", &src).unwrap();
+        let state = decode_pickle(split_zodb_record(&out).unwrap().1).unwrap();
+        assert_eq!(child(&state, "refs").and_then(|r| child(r, "2")), Some(&pref(3)));
+
+        let out = graft_subtree(&data, "a~1b", &src).unwrap();
+        let state = decode_pickle(split_zodb_record(&out).unwrap().1).unwrap();
+        assert_eq!(child(&state, "a/b"), Some(&pref(3)));
+    }
+
+    #[test]
+    fn test_extract_graft_roundtrip_between_records() {
+        let src_record = record(sample_state());
+        let dst_record = record(PickleValue::Dict(vec![]));
+        let sub = extract_subtree(&src_record, "refs").unwrap();
+        let out = graft_subtree(&dst_record, "refs", &sub).unwrap();
+        assert_eq!(
+            extract_subtree(&out, "refs").unwrap(),
+            extract_subtree(&src_record, "refs").unwrap()
+        );
+    }
+
+    /// A record as ZODB's `pickle.Pickler` writes it: the persistent ref
+    /// of `child` is `(oid, OrderedDict)` and reuses the class pickle's
+    /// memo entry for the class (`h\x00`).
+    const SHARED_MEMO_RECORD: &[u8] = b"\x80\x03ccollections\nOrderedDict\nq\x00N\x86q\x01.\
+        \x80\x03}q\x02(X\x05\x00\x00\x00titleq\x03X\x05\x00\x00\x00Helloq\x04\
+        X\x05\x00\x00\x00childq\x05C\x08\x00\x00\x00\x00\x00\x00\x00\x02q\x06h\x00\x86q\x07Qu.";
+
+    fn odict_ref() -> PickleValue {
+        PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+            PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, 2]),
+            PickleValue::Global {
+                module: "collections".into(),
+                name: "OrderedDict".into(),
+            },
+        ])))
+    }
+
+    #[test]
+    fn test_extract_uses_shared_memo() {
+        let sub = extract_subtree(SHARED_MEMO_RECORD, "child").unwrap();
+        assert_eq!(decode_pickle(&sub).unwrap(), odict_ref());
+    }
+
+    #[test]
+    fn test_graft_uses_shared_memo() {
+        let src = encode_pickle(&PickleValue::String("Bye".into())).unwrap();
+        let out = graft_subtree(SHARED_MEMO_RECORD, "title", &src).unwrap();
+        let (_, state) = decode_zodb_pickles(&out).unwrap();
+        assert_eq!(child(&state, "title"), Some(&PickleValue::String("Bye".into())));
+        assert_eq!(child(&state, "child"), Some(&odict_ref()));
+    }
+}
@@ -0,0 +1,203 @@
+//! Strings with lone surrogates.
+//!
+//! A Python `str` can hold lone surrogates (`"\udc80"`), usually from
+//! Python 2 data decoded with `surrogateescape`. pickle writes them with
+//! the `surrogatepass` error handler, as the 3-byte UTF-8 form of the
+//! surrogate code point, which is not valid UTF-8, so `str::from_utf8`
+//! rejects the whole record.
+//!
+//! [`SurrogatePolicy`] decides what the decoder does with such strings;
+//! it is chosen per call (see `DecodeOptions::with_surrogates`).
+//! Preserved strings are `PickleValue::SurrogateString`s holding the
+//! pickled bytes. In JSON they become `{"@su": text}`, where `text` is the
+//! string with every surrogate written as a `\uXXXX` escape (NUL too, so
+//! the marker is safe for PostgreSQL, and backslashes doubled), and they
+//! encode back to the same BINUNICODE payload.
+
+use std::fmt::Write as _;
+
+use crate::error::CodecError;
+use crate::types::PickleValue;
+use crate::warnings::{self, WarningCode};
+
+/// How the decoder handles strings with lone surrogates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SurrogatePolicy {
+    /// Fail with `CodecError::InvalidUtf8`.
+    #[default]
+    Error,
+    /// Replace each surrogate with U+FFFD, losing it.
+    Replace,
+    /// Keep the string losslessly as an `@su` marker.
+    Preserve,
+}
+
+/// A run of text or one surrogate code point of a pickled string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Piece<'a> {
+    Text(&'a str),
+    Surrogate(u16),
+}
+
+/// Split the UTF-8 payload of a pickled string into text and surrogates.
+/// Fails on bytes that are invalid for any other reason.
+pub(crate) fn for_each_piece<'a>(
+    mut bytes: &'a [u8],
+    mut f: impl FnMut(Piece<'a>),
+) -> Result<(), CodecError> {
+    loop {
+        let (text, rest) = match std::str::from_utf8(bytes) {
+            Ok(text) => (text, &[][..]),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                // SAFETY: from_utf8 validated the bytes up to valid_up_to
+                (unsafe { std::str::from_utf8_unchecked(valid) }, rest)
+            }
+        };
+        if !text.is_empty() {
+            f(Piece::Text(text));
+        }
+        match rest {
+            [] => return Ok(()),
+            [0xED, b1 @ 0xA0..=0xBF, b2 @ 0x80..=0xBF, tail @ ..] => {
+                f(Piece::Surrogate(0xD000 | u16::from(b1 & 0x3F) << 6 | u16::from(b2 & 0x3F)));
+                bytes = tail;
+            }
+            _ => return Err(CodecError::InvalidUtf8),
+        }
+    }
+}
+
+/// Decode the UTF-8 payload of a unicode opcode under `policy`.
+pub(crate) fn decode_text(bytes: &[u8], policy: SurrogatePolicy) -> Result<PickleValue, CodecError> {
+    if let Ok(s) = std::str::from_utf8(bytes) {
+        return Ok(PickleValue::String(s.into()));
+    }
+    match policy {
+        SurrogatePolicy::Error => Err(CodecError::InvalidUtf8),
+        SurrogatePolicy::Replace => {
+            let mut out = String::with_capacity(bytes.len());
+            let mut count = 0;
+            for_each_piece(bytes, |piece| match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Surrogate(_) => {
+                    out.push(char::REPLACEMENT_CHARACTER);
+                    count += 1;
+                }
+            })?;
+            warnings::warn(WarningCode::Surrogates, || {
+                format!("string with {count} lone surrogates, replaced with U+FFFD")
+            });
+            Ok(PickleValue::String(out.into()))
+        }
+        SurrogatePolicy::Preserve => {
+            for_each_piece(bytes, |_| {})?;
+            Ok(PickleValue::SurrogateString(bytes.to_vec()))
+        }
+    }
+}
+
+/// The `@su` text of a surrogate string.
+pub(crate) fn escape(bytes: &[u8]) -> Result<String, CodecError> {
+    let mut out = String::with_capacity(bytes.len() + 8);
+    for_each_piece(bytes, |piece| match piece {
+        Piece::Text(text) => {
+            for c in text.chars() {
+                match c {
+                    '\\' => out.push_str("\\\\"),
+                    '\0' => out.push_str("\\u0000"),
+                    _ => out.push(c),
+                }
+            }
+        }
+        Piece::Surrogate(unit) => {
+            let _ = write!(out, "\\u{unit:04x}");
+        }
+    })?;
+    Ok(out)
+}
+
+/// The string of an `@su` marker: a `String` when it has no surrogates.
+pub(crate) fn unescape(text: &str) -> Result<PickleValue, CodecError> {
+    let invalid = || CodecError::Json(format!("invalid @su escape in {text:?}"));
+    let mut out = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push(b'\\'),
+            Some('u') => {
+                let hex = chars.as_str().get(..4).ok_or_else(invalid)?;
+                let unit = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+                chars.nth(3);
+                push_code_point(&mut out, unit);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(match String::from_utf8(out) {
+        Ok(s) => PickleValue::String(s.into()),
+        Err(e) => PickleValue::SurrogateString(e.into_bytes()),
+    })
+}
+
+/// Append the UTF-8 form of a code point, writing surrogates as
+/// `surrogatepass` does.
+pub(crate) fn push_code_point(out: &mut Vec<u8>, cp: u32) {
+    match char::from_u32(cp) {
+        Some(c) => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        None => out.extend_from_slice(&[
+            0xE0 | (cp >> 12) as u8,
+            0x80 | ((cp >> 6) & 0x3F) as u8,
+            0x80 | (cp & 0x3F) as u8,
+        ]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `"a\udc80b"` as pickle writes it.
+    const LONE: &[u8] = b"a\xed\xb2\x80b";
+
+    #[test]
+    fn test_pieces() {
+        let mut pieces = Vec::new();
+        for_each_piece(LONE, |p| pieces.push(p)).unwrap();
+        assert_eq!(pieces, [Piece::Text("a"), Piece::Surrogate(0xdc80), Piece::Text("b")]);
+        assert!(for_each_piece(b"a\xff", |_| {}).is_err());
+        assert!(for_each_piece(b"\xed\xb2", |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_policies() {
+        assert!(decode_text(LONE, SurrogatePolicy::Error).is_err());
+        assert_eq!(
+            decode_text(LONE, SurrogatePolicy::Replace).unwrap(),
+            PickleValue::String("a\u{fffd}b".into())
+        );
+        assert_eq!(
+            decode_text(LONE, SurrogatePolicy::Preserve).unwrap(),
+            PickleValue::SurrogateString(LONE.to_vec())
+        );
+        // Other invalid UTF-8 still fails
+        assert!(decode_text(b"a\xff", SurrogatePolicy::Preserve).is_err());
+    }
+
+    #[test]
+    fn test_escape_roundtrip() {
+        // A surrogate pair stays two code units, as in Python
+        let data = b"\\\x00\xed\xa0\xbd\xed\xb8\x80\xed\xb2\x80";
+        let text = escape(data).unwrap();
+        assert_eq!(text, r"\\\u0000\ud83d\ude00\udc80");
+        assert_eq!(unescape(&text).unwrap(), PickleValue::SurrogateString(data.to_vec()));
+        assert_eq!(unescape(r"é").unwrap(), PickleValue::String("\u{e9}".into()));
+        assert!(unescape(r"\x").is_err());
+        assert!(unescape(r"\u12").is_err());
+    }
+}
@@ -1,8 +1,17 @@
+use std::sync::Arc;
+
 use num_bigint::BigInt;
 
+use crate::warnings::{self, WarningCode};
+
 /// Data for an object instance (result of BUILD or REDUCE+BUILD).
 /// Boxed inside the enum to keep PickleValue small.
+///
+/// The `Option<Box<Vec<..>>>` item fields are deliberate: a boxed vec is a
+/// single pointer, so the rarely-used subclass items cost 8 bytes instead of 24.
+#[allow(clippy::box_collection)]
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub struct InstanceData {
     pub module: String,
     pub name: String,
@@ -13,17 +22,130 @@ pub struct InstanceData {
     pub list_items: Option<Box<Vec<PickleValue>>>,
 }
 
+impl InstanceData {
+    /// Create an instance of `module.name` with the given state and no
+    /// subclass items.
+    pub fn new(module: impl Into<String>, name: impl Into<String>, state: PickleValue) -> Self {
+        InstanceData {
+            module: module.into(),
+            name: name.into(),
+            state: Box::new(state),
+            dict_items: None,
+            list_items: None,
+        }
+    }
+
+    /// Anonymous instance for `callable args REDUCE state BUILD` when the
+    /// callable is not a class reference.
+    pub(crate) fn anonymous_reduce(
+        callable: PickleValue,
+        args: PickleValue,
+        state: PickleValue,
+    ) -> Self {
+        warnings::warn(WarningCode::AnonymousInstance, || {
+            "REDUCE with a non-global callable and BUILD state, kept as @inst".to_string()
+        });
+        Self::new(
+            "",
+            "",
+            PickleValue::Dict(vec![
+                (PickleValue::String(INST_CALLABLE.into()), callable),
+                (PickleValue::String(INST_ARGS.into()), args),
+                (PickleValue::String(INST_STATE.into()), state),
+            ]),
+        )
+    }
+
+    /// Anonymous instance for `obj state BUILD` on any other object.
+    pub(crate) fn anonymous_object(obj: PickleValue, state: PickleValue) -> Self {
+        warnings::warn(WarningCode::AnonymousInstance, || {
+            "BUILD on something other than a class, kept as @inst".to_string()
+        });
+        Self::new(
+            "",
+            "",
+            PickleValue::Dict(vec![
+                (PickleValue::String(INST_OBJ.into()), obj),
+                (PickleValue::String(INST_STATE.into()), state),
+            ]),
+        )
+    }
+
+    /// Whether this is an anonymous instance (`@inst` in JSON): the
+    /// result of BUILD on something other than a class.
+    pub(crate) fn is_anonymous(&self) -> bool {
+        self.module.is_empty() && self.name.is_empty()
+    }
+
+    /// How an anonymous instance was built, if its state has one of the
+    /// shapes created by [`anonymous_reduce`](Self::anonymous_reduce) and
+    /// [`anonymous_object`](Self::anonymous_object).
+    pub(crate) fn anonymous_build(&self) -> Option<AnonymousBuild<'_>> {
+        if !self.is_anonymous() {
+            return None;
+        }
+        let PickleValue::Dict(pairs) = self.state.as_ref() else {
+            return None;
+        };
+        let get = |key: &str| {
+            pairs.iter().find_map(|(k, v)| match k {
+                PickleValue::String(s) if **s == *key => Some(v),
+                _ => None,
+            })
+        };
+        let state = get(INST_STATE)?;
+        match (pairs.len(), get(INST_CALLABLE), get(INST_ARGS), get(INST_OBJ)) {
+            (3, Some(callable), Some(args), None) => Some(AnonymousBuild::Reduce {
+                callable,
+                args,
+                state,
+            }),
+            (2, None, None, Some(obj)) => Some(AnonymousBuild::Object { obj, state }),
+            _ => None,
+        }
+    }
+}
+
+/// State keys of anonymous instances.
+pub(crate) const INST_CALLABLE: &str = "@callable";
+pub(crate) const INST_ARGS: &str = "@args";
+pub(crate) const INST_OBJ: &str = "@obj";
+pub(crate) const INST_STATE: &str = "@state";
+
+/// The opcodes that built an anonymous instance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AnonymousBuild<'a> {
+    /// `callable args REDUCE state BUILD`
+    Reduce {
+        callable: &'a PickleValue,
+        args: &'a PickleValue,
+        state: &'a PickleValue,
+    },
+    /// `obj state BUILD`
+    Object {
+        obj: &'a PickleValue,
+        state: &'a PickleValue,
+    },
+}
+
 /// Intermediate representation of a pickle value.
 /// This AST sits between pickle bytes and JSON — it can be losslessly
 /// converted in both directions.
+#[allow(clippy::box_collection)]
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum PickleValue {
     None,
     Bool(bool),
     Int(i64),
     BigInt(BigInt),
     Float(f64),
-    String(String),
+    /// Shared, so a memo hit (`BINGET`) copies a pointer rather than the
+    /// text: a catalog repeats the same keys thousands of times.
+    String(Arc<str>),
+    /// A `str` with lone surrogates, kept by `SurrogatePolicy::Preserve`
+    /// as the UTF-8 bytes with surrogate code points that pickle writes.
+    SurrogateString(Vec<u8>),
     Bytes(Vec<u8>),
     List(Vec<PickleValue>),
     Tuple(Vec<PickleValue>),
@@ -35,12 +157,18 @@ pub enum PickleValue {
         module: String,
         name: String,
     },
+    /// A class reference rejected by the decode policy in block mode
+    /// (encodes back to the original GLOBAL).
+    Blocked {
+        module: String,
+        name: String,
+    },
     /// An object instance: class + state (boxed to reduce enum size)
     Instance(Box<InstanceData>),
     /// ZODB persistent reference (the argument to BINPERSID)
     PersistentRef(Box<PickleValue>),
-    /// Result of REDUCE that we don't have a specific handler for.
-    /// Stores the callable Global and args tuple.
+    /// Result of REDUCE or NEWOBJ that we don't have a specific handler
+    /// for. Stores the callable Global and args tuple.
     Reduce {
         callable: Box<PickleValue>,
         args: Box<PickleValue>,
@@ -48,9 +176,25 @@ pub enum PickleValue {
         dict_items: Option<Box<Vec<(PickleValue, PickleValue)>>>,
         /// List items appended via APPENDS/APPEND after REDUCE (list subclasses)
         list_items: Option<Box<Vec<PickleValue>>>,
+        /// Created by NEWOBJ (`callable.__new__(callable, *args)`) rather
+        /// than REDUCE (`callable(*args)`).
+        newobj: bool,
+    },
+    /// Result of NEWOBJ_EX: `cls.__new__(cls, *args, **kwargs)`, as
+    /// pickled for classes with `__getnewargs_ex__`.
+    NewObjEx {
+        cls: Box<PickleValue>,
+        args: Box<PickleValue>,
+        kwargs: Box<PickleValue>,
     },
     /// Escape hatch: raw pickle bytes we couldn't meaningfully decode
     RawPickle(Vec<u8>),
+    /// A container referred to elsewhere in the same pickle by
+    /// `BackRef(id)`: a cycle, or aliasing when shared references are
+    /// enabled (see `shared.rs`).
+    Shared { id: u32, value: Box<PickleValue> },
+    /// A reference to the enclosing or earlier `Shared` node with this id.
+    BackRef(u32),
 }
 
 #[cfg(test)]
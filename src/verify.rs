@@ -0,0 +1,418 @@
+//! Round-trip verification of the JSONB format.
+//!
+//! [`verify_roundtrip`] takes a record through the PostgreSQL JSON path
+//! and back: the state is written as by `decode_zodb_record_for_pg_json`,
+//! parsed, encoded to a record again and decoded. The two decoded
+//! `PickleValue` trees are then compared. A difference means storing the
+//! record as JSONB would lose information.
+//!
+//! The comparison is structural rather than `==`: dict items are matched
+//! by key, since JSON objects do not keep their order; an `Int` equals a
+//! `BigInt` of the same value; floats compare by bit pattern, so NaN
+//! equals NaN and `-0.0` does not equal `0.0`. Only the class's module
+//! and name are compared, not the form of the class pickle.
+
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::decode::decode_zodb_pickles;
+use crate::error::CodecError;
+use crate::json::{pickle_value_to_json, pickle_value_to_json_string_pg};
+use crate::null_strings::with_pg_safe_input;
+use crate::types::PickleValue;
+use crate::zodb::{encode_zodb_record, extract_class_info};
+
+/// Where a record stops surviving the round trip.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct RoundtripMismatch {
+    /// Path of the first differing value: `/@cls`, or `/@s` followed by
+    /// dict keys and indices, with the marker names of the JSON form for
+    /// tuples (`@t`), sets (`@set`, `@fset`), instance states (`@s`) and
+    /// so on. Empty if re-encoding or re-decoding failed.
+    pub path: String,
+    /// What differs, or why the round trip failed.
+    pub reason: String,
+}
+
+/// Round-trip a ZODB record through the JSONB format and report the first
+/// difference, or `None` if the record comes back unchanged.
+///
+/// Errors only if `record` itself cannot be decoded; a failure later in
+/// the round trip is reported as a mismatch.
+///
+/// ```
+/// use zodb_json_codec::verify_roundtrip;
+///
+/// // Class pickle ("mod", "Cls") followed by {"a": 1}
+/// let record = b"\x80\x03X\x03\x00\x00\x00modX\x03\x00\x00\x00Cls\x86N\x86.\
+///                \x80\x03}X\x01\x00\x00\x00aK\x01s.";
+/// assert_eq!(verify_roundtrip(record)?, None);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn verify_roundtrip(record: &[u8]) -> Result<Option<RoundtripMismatch>, CodecError> {
+    let (class_val, state) = decode_zodb_pickles(record)?;
+    let (module, name) = extract_class_info(&class_val);
+    let (class_back, state_back) = match roundtrip(&module, &name, &state) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            return Ok(Some(RoundtripMismatch {
+                path: String::new(),
+                reason: format!("round trip failed: {e}"),
+            }))
+        }
+    };
+    if extract_class_info(&class_back) != (module.clone(), name.clone()) {
+        return Ok(Some(RoundtripMismatch {
+            path: "/@cls".to_string(),
+            reason: format!("class {module}.{name} changed"),
+        }));
+    }
+    let mut path = "/@s".to_string();
+    Ok(compare(&state, &state_back, &mut path).map(|reason| RoundtripMismatch { path, reason }))
+}
+
+fn roundtrip(
+    module: &str,
+    name: &str,
+    state: &PickleValue,
+) -> Result<(PickleValue, PickleValue), CodecError> {
+    let state_json = pickle_value_to_json_string_pg(state, module, name)?;
+    let state_json: Value =
+        serde_json::from_str(&state_json).map_err(|e| CodecError::Json(e.to_string()))?;
+    let record = json!({"@cls": [module, name], "@s": state_json});
+    let encoded = with_pg_safe_input(|| encode_zodb_record(record))?;
+    decode_zodb_pickles(&encoded)
+}
+
+/// Compare `a` (original) with `b` (after the round trip). On the first
+/// difference, returns its description with `path` pointing at it.
+fn compare(a: &PickleValue, b: &PickleValue, path: &mut String) -> Option<String> {
+    use PickleValue as P;
+    match (a, b) {
+        (P::Int(x), P::BigInt(y)) | (P::BigInt(y), P::Int(x)) if *y == (*x).into() => None,
+        (P::Float(x), P::Float(y)) if x.to_bits() == y.to_bits() || x.is_nan() && y.is_nan() => {
+            None
+        }
+        (P::Float(_), P::Float(_)) => Some(mismatch(a, b)),
+        (P::List(x), P::List(y)) => items(x, y, path, ""),
+        (P::Tuple(x), P::Tuple(y)) => items(x, y, path, "@t"),
+        (P::Set(x), P::Set(y)) => items(x, y, path, "@set"),
+        (P::FrozenSet(x), P::FrozenSet(y)) => items(x, y, path, "@fset"),
+        (P::Dict(x), P::Dict(y)) => compare_dicts(x, y, path),
+        (P::Instance(x), P::Instance(y)) if (&x.module, &x.name) == (&y.module, &y.name) => {
+            within(&x.state, &y.state, path, "@s").or_else(|| {
+                compare_items(
+                    x.dict_items.as_deref().map(Vec::as_slice),
+                    y.dict_items.as_deref().map(Vec::as_slice),
+                    x.list_items.as_deref().map(Vec::as_slice),
+                    y.list_items.as_deref().map(Vec::as_slice),
+                    path,
+                )
+            })
+        }
+        (P::PersistentRef(x), P::PersistentRef(y)) => within(x, y, path, "@ref"),
+        (
+            P::Reduce {
+                callable: ca,
+                args: aa,
+                dict_items: da,
+                list_items: la,
+                newobj: na,
+            },
+            P::Reduce {
+                callable: cb,
+                args: ab,
+                dict_items: db,
+                list_items: lb,
+                newobj: nb,
+            },
+        ) => {
+            if na != nb {
+                let (a, b) = if *na { ("NEWOBJ", "REDUCE") } else { ("REDUCE", "NEWOBJ") };
+                return Some(format!("{b} instead of {a}"));
+            }
+            let len = push_segment(path, if *na { "@newobj" } else { "@reduce" });
+            let found = within(ca, cb, path, "callable")
+                .or_else(|| within(aa, ab, path, "args"))
+                .or_else(|| {
+                    compare_items(
+                        da.as_deref().map(Vec::as_slice),
+                        db.as_deref().map(Vec::as_slice),
+                        la.as_deref().map(Vec::as_slice),
+                        lb.as_deref().map(Vec::as_slice),
+                        path,
+                    )
+                });
+            if found.is_none() {
+                path.truncate(len);
+            }
+            found
+        }
+        (
+            P::NewObjEx {
+                cls: ca,
+                args: aa,
+                kwargs: ka,
+            },
+            P::NewObjEx {
+                cls: cb,
+                args: ab,
+                kwargs: kb,
+            },
+        ) => {
+            let len = push_segment(path, "@newobj_ex");
+            let found = within(ca, cb, path, "cls")
+                .or_else(|| within(aa, ab, path, "args"))
+                .or_else(|| within(ka, kb, path, "kwargs"));
+            if found.is_none() {
+                path.truncate(len);
+            }
+            found
+        }
+        (P::Shared { id: ia, value: va }, P::Shared { id: ib, value: vb }) if ia == ib => {
+            within(va, vb, path, "@shared")
+        }
+        _ if a == b => None,
+        _ => Some(mismatch(a, b)),
+    }
+}
+
+/// `compare` one level down, at `path/segment`.
+fn within(a: &PickleValue, b: &PickleValue, path: &mut String, segment: &str) -> Option<String> {
+    let len = push_segment(path, segment);
+    let found = compare(a, b, path);
+    if found.is_none() {
+        path.truncate(len);
+    }
+    found
+}
+
+/// Compare sequences item by item, under `marker` if not empty.
+fn items(a: &[PickleValue], b: &[PickleValue], path: &mut String, marker: &str) -> Option<String> {
+    if a.len() != b.len() {
+        return Some(format!("{} items instead of {}", b.len(), a.len()));
+    }
+    let len = match marker {
+        "" => path.len(),
+        _ => push_segment(path, marker),
+    };
+    for (i, (va, vb)) in a.iter().zip(b).enumerate() {
+        if let found @ Some(_) = within(va, vb, path, &i.to_string()) {
+            return found;
+        }
+    }
+    path.truncate(len);
+    None
+}
+
+/// Compare dict items by key, as JSON objects come back in key order.
+fn compare_dicts(
+    a: &[(PickleValue, PickleValue)],
+    b: &[(PickleValue, PickleValue)],
+    path: &mut String,
+) -> Option<String> {
+    if a.len() != b.len() {
+        return Some(format!("{} items instead of {}", b.len(), a.len()));
+    }
+    let by_str: HashMap<&str, &PickleValue> = b
+        .iter()
+        .filter_map(|(k, v)| match k {
+            PickleValue::String(s) => Some((&**s, v)),
+            _ => None,
+        })
+        .collect();
+    for (i, (ka, va)) in a.iter().enumerate() {
+        let (segment, vb) = match ka {
+            PickleValue::String(s) => (s.to_string(), by_str.get(&**s).copied()),
+            _ => (
+                format!("@d/{i}/1"),
+                b.iter()
+                    .find(|(kb, _)| compare(ka, kb, &mut String::new()).is_none())
+                    .map(|(_, v)| v),
+            ),
+        };
+        let len = push_segment(path, &segment);
+        match vb {
+            Some(vb) => {
+                if let found @ Some(_) = compare(va, vb, path) {
+                    return found;
+                }
+            }
+            None => return Some("key missing after the round trip".to_string()),
+        }
+        path.truncate(len);
+    }
+    None
+}
+
+/// Compare the SETITEMS/APPENDS items of two instances or REDUCEs.
+fn compare_items(
+    da: Option<&[(PickleValue, PickleValue)]>,
+    db: Option<&[(PickleValue, PickleValue)]>,
+    la: Option<&[PickleValue]>,
+    lb: Option<&[PickleValue]>,
+    path: &mut String,
+) -> Option<String> {
+    match (da, db) {
+        (None, None) => {}
+        (Some(a), Some(b)) => {
+            let len = push_segment(path, "@items");
+            if let found @ Some(_) = compare_dicts(a, b, path) {
+                return found;
+            }
+            path.truncate(len);
+        }
+        _ => return Some("@items added or lost".to_string()),
+    }
+    match (la, lb) {
+        (None, None) => None,
+        (Some(a), Some(b)) => items(a, b, path, "@appends"),
+        _ => Some("@appends added or lost".to_string()),
+    }
+}
+
+fn mismatch(a: &PickleValue, b: &PickleValue) -> String {
+    format!("{} instead of {}", describe(b), describe(a))
+}
+
+/// Short description of a value for mismatch reasons.
+fn describe(val: &PickleValue) -> String {
+    const MAX: usize = 80;
+    let text = match val {
+        // JSON has no NaN, infinities or signed zero
+        PickleValue::Float(x) => format!("{x:?}"),
+        _ => match pickle_value_to_json(val) {
+            Ok(json) => json.to_string(),
+            Err(_) => format!("{val:?}"),
+        },
+    };
+    match text.char_indices().nth(MAX) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+/// Append `/segment` to `path`, escaped as in JSON Pointer. Returns the
+/// length to truncate back to.
+fn push_segment(path: &mut String, segment: &str) -> usize {
+    let len = path.len();
+    path.push('/');
+    path.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::encode_pickle;
+
+    fn record(state: &PickleValue) -> Vec<u8> {
+        let class = PickleValue::Tuple(vec![
+            PickleValue::Tuple(vec![
+                PickleValue::String("myapp".into()),
+                PickleValue::String("Doc".into()),
+            ]),
+            PickleValue::None,
+        ]);
+        [
+            encode_pickle(&class).unwrap(),
+            encode_pickle(state).unwrap(),
+        ]
+        .concat()
+    }
+
+    fn s(v: &str) -> PickleValue {
+        PickleValue::String(v.into())
+    }
+
+    #[test]
+    fn test_clean_roundtrip() {
+        let state = PickleValue::Dict(vec![
+            (s("z"), PickleValue::Float(1.5)),
+            (
+                s("a"),
+                PickleValue::Tuple(vec![PickleValue::Int(1), s("x")]),
+            ),
+            (
+                PickleValue::Int(3),
+                PickleValue::PersistentRef(Box::new(PickleValue::Tuple(vec![
+                    PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, 7]),
+                    PickleValue::None,
+                ]))),
+            ),
+            (
+                s("big"),
+                PickleValue::BigInt(num_bigint::BigInt::from(1) << 100),
+            ),
+        ]);
+        assert_eq!(verify_roundtrip(&record(&state)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_reports_first_difference() {
+        // A key that reads as the @ns: form of "a\0b" comes back as that
+        let state = PickleValue::Dict(vec![(
+            s("items"),
+            PickleValue::List(vec![
+                s("ok"),
+                PickleValue::Tuple(vec![PickleValue::Dict(vec![(s("@ns:YQBi"), s("x"))])]),
+            ]),
+        )]);
+        let mismatch = verify_roundtrip(&record(&state)).unwrap().unwrap();
+        assert_eq!(mismatch.path, "/@s/items/1/@t/0/@ns:YQBi");
+        assert_eq!(mismatch.reason, "key missing after the round trip");
+
+        // Null bytes survive as @ns markers
+        let state = PickleValue::Dict(vec![(s("a\0b"), s("c\0d"))]);
+        assert_eq!(verify_roundtrip(&record(&state)).unwrap(), None);
+
+        // NaN survives as an @f marker
+        let state = PickleValue::Dict(vec![(s("x"), PickleValue::Float(f64::NAN))]);
+        assert_eq!(verify_roundtrip(&record(&state)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut path = String::new();
+        assert_eq!(
+            compare(
+                &PickleValue::Int(5),
+                &PickleValue::BigInt(5.into()),
+                &mut path
+            ),
+            None
+        );
+        assert!(compare(
+            &PickleValue::Float(0.0),
+            &PickleValue::Float(-0.0),
+            &mut path
+        )
+        .is_some());
+        let a = PickleValue::Dict(vec![
+            (s("a"), PickleValue::Int(1)),
+            (s("b"), PickleValue::Int(2)),
+        ]);
+        let b = PickleValue::Dict(vec![
+            (s("b"), PickleValue::Int(2)),
+            (s("a"), PickleValue::Int(1)),
+        ]);
+        let mut path = String::new();
+        assert_eq!(compare(&a, &b, &mut path), None);
+        let c = PickleValue::Dict(vec![
+            (s("b"), PickleValue::Int(2)),
+            (s("c"), PickleValue::Int(1)),
+        ]);
+        let reason = compare(&a, &c, &mut path).unwrap();
+        assert_eq!(
+            (path.as_str(), reason.as_str()),
+            ("/a", "key missing after the round trip")
+        );
+    }
+
+    #[test]
+    fn test_invalid_record() {
+        assert!(verify_roundtrip(b"\x80\x03N.").is_err());
+    }
+}
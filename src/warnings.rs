@@ -0,0 +1,160 @@
+//! Warnings for lossy or fallback conversions.
+//!
+//! Some values decode to generic markers that keep the data but lose its
+//! meaning, or that the encoder cannot always turn back into the original
+//! pickle: a REDUCE without a typed marker (`@reduce`), a pickle the lenient
+//! decoder could not parse (`@pkl`), BUILD on something other than a class
+//! (`@inst`) and strings with null bytes rewritten for PostgreSQL (`@ns`).
+//! Inside [`collect_warnings`] each of these is recorded, so callers can
+//! log which records contain them instead of finding out on re-encode.
+//!
+//! Warnings are collected per thread; outside a collection the hooks cost
+//! one thread-local access.
+
+use std::cell::RefCell;
+
+/// The kind of a conversion warning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WarningCode {
+    /// A REDUCE with no typed marker, stored as `@reduce`
+    Reduce,
+    /// An undecodable pickle kept as `@pkl` by lenient decoding
+    RawPickle,
+    /// BUILD on something other than a class, stored as `@inst`
+    AnonymousInstance,
+    /// A string or dict key with null bytes, stored as `@ns`
+    NullBytes,
+    /// Lone surrogates replaced with U+FFFD (`SurrogatePolicy::Replace`)
+    Surrogates,
+    /// A dict key repeated in the pickle; only its last value was kept
+    DuplicateKeys,
+}
+
+impl WarningCode {
+    /// The kebab-case name used in reports (`"raw-pickle"`, ...).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WarningCode::Reduce => "reduce",
+            WarningCode::RawPickle => "raw-pickle",
+            WarningCode::AnonymousInstance => "anonymous-instance",
+            WarningCode::NullBytes => "null-bytes",
+            WarningCode::Surrogates => "surrogates",
+            WarningCode::DuplicateKeys => "duplicate-keys",
+        }
+    }
+}
+
+/// One conversion warning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConversionWarning {
+    pub code: WarningCode,
+    pub message: String,
+    /// How many times the same warning came up in the conversion.
+    pub count: usize,
+}
+
+thread_local! {
+    static COLLECTED: RefCell<Option<Vec<ConversionWarning>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` and return its result with the warnings of the conversions it
+/// made on this thread. Identical warnings are reported once, with their
+/// `count`.
+///
+/// ```
+/// use zodb_json_codec::{collect_warnings, decode_pickle, pickle_value_to_json, WarningCode};
+///
+/// // myapp.make() has no typed marker
+/// let data = b"\x80\x03cmyapp\nmake\n)R.";
+/// let (json, warnings) = collect_warnings(|| pickle_value_to_json(&decode_pickle(data)?));
+/// assert!(json?.get("@reduce").is_some());
+/// assert_eq!(warnings[0].code, WarningCode::Reduce);
+/// assert_eq!(warnings[0].message, "no typed marker for myapp.make, kept as @reduce");
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn collect_warnings<R>(f: impl FnOnce() -> R) -> (R, Vec<ConversionWarning>) {
+    let scope = Scope(COLLECTED.with(|c| c.replace(Some(Vec::new()))));
+    let result = f();
+    (result, scope.finish())
+}
+
+/// The enclosing collection, restored when a collection ends (or panics).
+struct Scope(Option<Vec<ConversionWarning>>);
+
+impl Scope {
+    fn finish(mut self) -> Vec<ConversionWarning> {
+        let warnings = COLLECTED.with(|c| c.borrow_mut().take()).unwrap_or_default();
+        // An enclosing collection sees the warnings too
+        if let Some(outer) = &mut self.0 {
+            for w in &warnings {
+                push(outer, w.code, &w.message, w.count);
+            }
+        }
+        warnings
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let outer = self.0.take();
+        COLLECTED.with(|c| *c.borrow_mut() = outer);
+    }
+}
+
+fn push(warnings: &mut Vec<ConversionWarning>, code: WarningCode, message: &str, count: usize) {
+    match warnings.iter_mut().find(|w| w.code == code && w.message == message) {
+        Some(w) => w.count += count,
+        None => warnings.push(ConversionWarning {
+            code,
+            message: message.to_string(),
+            count,
+        }),
+    }
+}
+
+/// Record a warning if warnings are being collected. `message` is only
+/// built then.
+pub(crate) fn warn(code: WarningCode, message: impl FnOnce() -> String) {
+    COLLECTED.with(|c| {
+        if let Some(warnings) = c.borrow_mut().as_mut() {
+            push(warnings, code, &message(), 1);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_collected_outside() {
+        warn(WarningCode::Reduce, || unreachable!());
+    }
+
+    #[test]
+    fn test_identical_warnings_merged() {
+        let ((), warnings) = collect_warnings(|| {
+            warn(WarningCode::NullBytes, || "a".into());
+            warn(WarningCode::NullBytes, || "a".into());
+            warn(WarningCode::Reduce, || "a".into());
+        });
+        let counts: Vec<_> = warnings.iter().map(|w| (w.code, w.count)).collect();
+        assert_eq!(counts, [(WarningCode::NullBytes, 2), (WarningCode::Reduce, 1)]);
+    }
+
+    #[test]
+    fn test_nested_collections() {
+        let (inner, outer) = collect_warnings(|| {
+            warn(WarningCode::Reduce, || "outer".into());
+            let ((), inner) = collect_warnings(|| warn(WarningCode::RawPickle, || "inner".into()));
+            inner
+        });
+        assert_eq!(inner.len(), 1);
+        let codes: Vec<_> = outer.iter().map(|w| w.code).collect();
+        assert_eq!(codes, [WarningCode::Reduce, WarningCode::RawPickle]);
+        let ((), after) = collect_warnings(|| ());
+        assert!(after.is_empty());
+    }
+}
@@ -1,19 +1,16 @@
-#[cfg(test)]
-use crate::error::CodecError;
-use crate::types::PickleValue;
-
-#[cfg(test)]
-use base64::Engine as _;
-#[cfg(test)]
+use crate::binenc::{b64_decode, b64_encode, hex_decode, hex_encode};
 use crate::btrees;
-#[cfg(test)]
-use crate::encode::encode_pickle;
-#[cfg(test)]
+use crate::encode::{encode_pickle, write_string};
+use crate::error::{CodecError, PathSegment};
 use crate::json::{json_to_pickle_value, pickle_value_to_json};
-#[cfg(test)]
-use crate::pyconv;
-#[cfg(test)]
+use crate::known_types;
+use crate::limits::{find_line_end, LineLimits};
+use crate::opcodes::{NONE, PROTO, STOP, TUPLE2};
+use crate::raw_pickle;
+use crate::rename;
+use crate::types::PickleValue;
 use serde_json::{json, Value};
+use std::cell::Cell;
 
 /// A ZODB record consists of two concatenated pickles:
 /// 1. Class pickle: (module, classname)
@@ -21,7 +18,16 @@ use serde_json::{json, Value};
 ///
 /// We need to find the boundary between the two pickles.
 /// The first pickle ends at its STOP opcode (0x2e = '.').
-#[cfg(test)]
+///
+/// ```
+/// use zodb_json_codec::split_zodb_record;
+///
+/// // Class pickle `N.` followed by state pickle `}.`
+/// let (class_pickle, state_pickle) = split_zodb_record(b"N.}.")?;
+/// assert_eq!(class_pickle, b"N.");
+/// assert_eq!(state_pickle, b"}.");
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
 pub fn split_zodb_record(data: &[u8]) -> Result<(&[u8], &[u8]), CodecError> {
     // We need to properly walk the first pickle to find its STOP opcode.
     // Simple approach: scan for STOP, but STOP byte (0x2e) can appear inside
@@ -33,143 +39,280 @@ pub fn split_zodb_record(data: &[u8]) -> Result<(&[u8], &[u8]), CodecError> {
 /// Find the end (exclusive) of the first pickle in the data.
 /// This walks the pickle opcodes to correctly skip over string/bytes
 /// data that might contain the STOP byte.
-#[cfg(test)]
-fn find_pickle_end(data: &[u8]) -> Result<usize, CodecError> {
-    use crate::opcodes::*;
+pub fn find_pickle_end(data: &[u8]) -> Result<usize, CodecError> {
+    let limits = LineLimits::default();
     let mut pos = 0;
-
     loop {
-        if pos >= data.len() {
-            return Err(CodecError::UnexpectedEof);
+        let (op, next) = skip_opcode(data, pos, &limits)?;
+        pos = next;
+        if op == crate::opcodes::STOP {
+            return Ok(pos);
         }
-        let op = data[pos];
-        pos += 1;
+    }
+}
+
+/// Skip the opcode starting at `pos` without decoding its argument.
+/// Returns the opcode and the position just past its argument.
+///
+/// The returned position may lie beyond the end of `data` when an
+/// argument is truncated; callers detect that on the next call.
+/// Text-mode arguments are bounded by `limits`.
+#[inline]
+pub(crate) fn skip_opcode(
+    data: &[u8],
+    mut pos: usize,
+    limits: &LineLimits,
+) -> Result<(u8, usize), CodecError> {
+    use crate::opcodes::*;
 
-        match op {
-            STOP => return Ok(pos),
-            PROTO => pos += 1,
-            FRAME => pos += 8,
+    if pos >= data.len() {
+        return Err(CodecError::UnexpectedEof);
+    }
+    let op = data[pos];
+    pos += 1;
 
-            // Zero-argument opcodes
-            NONE | NEWTRUE | NEWFALSE | EMPTY_DICT | EMPTY_LIST | EMPTY_TUPLE | EMPTY_SET
-            | MARK | POP | DUP | APPEND | APPENDS | BUILD | SETITEM | SETITEMS | ADDITEMS
-            | REDUCE | NEWOBJ | BINPERSID | TUPLE | TUPLE1 | TUPLE2 | TUPLE3 | LIST | DICT
-            | FROZENSET | STACK_GLOBAL | MEMOIZE | NEWOBJ_EX => {}
+    match op {
+        STOP => {}
+        PROTO => pos += 1,
+        FRAME => pos += 8,
 
-            // 1-byte argument
-            BININT1 | BINPUT | BINGET => pos += 1,
+        // Zero-argument opcodes
+        NONE | NEWTRUE | NEWFALSE | EMPTY_DICT | EMPTY_LIST | EMPTY_TUPLE | EMPTY_SET
+        | MARK | POP | DUP | APPEND | APPENDS | BUILD | SETITEM | SETITEMS | ADDITEMS
+        | REDUCE | NEWOBJ | BINPERSID | TUPLE | TUPLE1 | TUPLE2 | TUPLE3 | LIST | DICT
+        | FROZENSET | STACK_GLOBAL | MEMOIZE | NEWOBJ_EX | OBJ => {}
 
-            // 2-byte argument
-            BININT2 => pos += 2,
+        // 1-byte argument
+        BININT1 | BINPUT | BINGET => pos += 1,
 
-            // 4-byte argument
-            BININT | LONG_BINPUT | LONG_BINGET => pos += 4,
+        // 2-byte argument
+        BININT2 => pos += 2,
 
-            // 8-byte argument
-            BINFLOAT => pos += 8,
+        // 4-byte argument
+        BININT | LONG_BINPUT | LONG_BINGET => pos += 4,
 
-            // Counted binary data (4-byte length)
-            BINUNICODE | BINSTRING | BINBYTES => {
-                if pos + 4 > data.len() {
-                    return Err(CodecError::UnexpectedEof);
-                }
-                let n = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
-                pos += 4 + n;
+        // 8-byte argument
+        BINFLOAT => pos += 8,
+
+        // Counted binary data (4-byte length)
+        BINUNICODE | BINSTRING | BINBYTES => {
+            if pos + 4 > data.len() {
+                return Err(CodecError::UnexpectedEof);
             }
+            let n = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            pos = pos.saturating_add(4).saturating_add(n);
+        }
 
-            // Short counted binary data (1-byte length)
-            SHORT_BINUNICODE | SHORT_BINSTRING | SHORT_BINBYTES => {
-                if pos >= data.len() {
-                    return Err(CodecError::UnexpectedEof);
-                }
-                let n = data[pos] as usize;
-                pos += 1 + n;
+        // Short counted binary data (1-byte length)
+        SHORT_BINUNICODE | SHORT_BINSTRING | SHORT_BINBYTES => {
+            if pos >= data.len() {
+                return Err(CodecError::UnexpectedEof);
             }
+            let n = data[pos] as usize;
+            pos += 1 + n;
+        }
 
-            // 8-byte length variants
-            BINUNICODE8 | BINBYTES8 | BYTEARRAY8 => {
-                if pos + 8 > data.len() {
-                    return Err(CodecError::UnexpectedEof);
-                }
-                let n = u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap()) as usize;
-                pos += 8 + n;
+        // 8-byte length variants
+        BINUNICODE8 | BINBYTES8 | BYTEARRAY8 => {
+            if pos + 8 > data.len() {
+                return Err(CodecError::UnexpectedEof);
             }
+            let n = u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap()) as usize;
+            pos = pos.saturating_add(8).saturating_add(n);
+        }
 
-            // LONG1: 1-byte length + data
-            LONG1 => {
-                if pos >= data.len() {
-                    return Err(CodecError::UnexpectedEof);
-                }
-                let n = data[pos] as usize;
-                pos += 1 + n;
+        // LONG1: 1-byte length + data
+        LONG1 => {
+            if pos >= data.len() {
+                return Err(CodecError::UnexpectedEof);
             }
+            let n = data[pos] as usize;
+            pos += 1 + n;
+        }
 
-            // LONG4: 4-byte length + data
-            LONG4 => {
-                if pos + 4 > data.len() {
-                    return Err(CodecError::UnexpectedEof);
-                }
-                let n = i32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
-                pos += 4 + n;
+        // LONG4: 4-byte length + data
+        LONG4 => {
+            if pos + 4 > data.len() {
+                return Err(CodecError::UnexpectedEof);
             }
+            let n = i32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            pos = pos.saturating_add(4).saturating_add(n);
+        }
 
-            // Text-mode opcodes (newline-terminated)
-            INT | LONG | FLOAT | STRING | UNICODE | GLOBAL | PUT | GET | PERSID => {
-                // Read until newline
-                while pos < data.len() && data[pos] != b'\n' {
-                    pos += 1;
-                }
-                pos += 1; // skip newline
-                // GLOBAL has TWO newline-terminated lines
-                if op == GLOBAL {
-                    while pos < data.len() && data[pos] != b'\n' {
-                        pos += 1;
-                    }
-                    pos += 1;
-                }
+        // Text-mode opcodes (newline-terminated, bounded by the line limits)
+        INT | LONG | FLOAT | STRING | UNICODE | GLOBAL | INST | PUT | GET | PERSID => {
+            let max = limits.for_opcode(op);
+            pos = find_line_end(data, pos, max, op)? + 1;
+            // GLOBAL and INST have TWO newline-terminated lines
+            if op == GLOBAL || op == INST {
+                pos = find_line_end(data, pos, max, op)? + 1;
             }
+        }
 
-            NEXT_BUFFER | READONLY_BUFFER => {}
+        NEXT_BUFFER | READONLY_BUFFER => {}
 
-            _ => {
-                return Err(CodecError::UnknownOpcode(op));
-            }
+        _ => {
+            return Err(CodecError::UnknownOpcode(op));
         }
     }
+    Ok((op, pos))
+}
+
+/// Module and name of the ZODB blob class.
+const BLOB_CLASS: (&str, &str) = ("ZODB.blob", "Blob");
+
+/// A blob record as ZODB writes it: the class by reference, and no state
+/// (the data lives in the blob file).
+pub(crate) const BLOB_RECORD: &[u8] = b"\x80\x03cZODB.blob\nBlob\nq\x00.\x80\x03N.";
+
+/// Whether a record of class `module.name` is a blob stub. Such records
+/// are marked `"@blob": true` and encode back to [`BLOB_RECORD`].
+pub(crate) fn is_blob(module: &str, name: &str, state_is_none: bool) -> bool {
+    state_is_none && (module, name) == BLOB_CLASS
+}
+
+/// Check a record marked `@blob`, whose `@cls` and `@s` may be left out
+/// but must be a blob's if given.
+pub(crate) fn check_blob_record(cls: Option<(&str, &str)>, state_is_none: bool) -> Result<(), CodecError> {
+    let (module, name) = cls.unwrap_or(BLOB_CLASS);
+    if !is_blob(module, name, state_is_none) {
+        return Err(CodecError::InvalidData(
+            "@blob record must be a ZODB.blob.Blob without state".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Build the class pickle bytes for a ZODB record: PROTO 2 + ((module, name), None) + STOP.
+/// This is the format produced by ZODB's PersistentPickler and expected
+/// by ZODB's standard unpickling (ObjectReader and zodb_unpickle).
+/// The encode class renames apply.
+pub(crate) fn build_class_pickle(module: &str, name: &str) -> Vec<u8> {
+    if let Some((module, name)) = rename::encode_rename(module, name) {
+        return build_class_pickle_as(&module, &name);
+    }
+    build_class_pickle_as(module, name)
+}
+
+fn build_class_pickle_as(module: &str, name: &str) -> Vec<u8> {
+    let cap = 8 + (5 + module.len()) + (5 + name.len());
+    let mut buf = Vec::with_capacity(cap);
+    buf.extend_from_slice(&[PROTO, 2]);
+    write_string(&mut buf, module);
+    write_string(&mut buf, name);
+    buf.push(TUPLE2); // inner tuple: (module, name)
+    buf.push(NONE);
+    buf.push(TUPLE2); // outer tuple: ((module, name), None)
+    buf.push(STOP);
+    buf
+}
+
+#[cfg(any(test, feature = "python"))]
+/// The class pickle of `record`, for the `@cls_raw` marker, if it is the
+/// class pickle of `module.name` as decoded (not renamed).
+pub(crate) fn class_pickle_to_keep<'a>(
+    record: &'a [u8],
+    module: &str,
+    name: &str,
+) -> Result<Option<&'a [u8]>, CodecError> {
+    let (class_pickle, _) = split_zodb_record(record)?;
+    let (raw_module, raw_name) = crate::decode::class_pickle_info(class_pickle)?;
+    Ok(((raw_module.as_str(), raw_name.as_str()) == (module, name)).then_some(class_pickle))
+}
+
+/// Whether the `@cls_raw` class pickle `raw` can be written for a record
+/// of class `module.name`: it must still be a class pickle of that class,
+/// and no encode rename may apply. Fails if `raw` is not one pickle.
+pub(crate) fn keeps_class_pickle(raw: &[u8], module: &str, name: &str) -> Result<bool, CodecError> {
+    let (raw_module, raw_name) = crate::decode::class_pickle_info(raw)
+        .map_err(|e| CodecError::InvalidData(format!("invalid @cls_raw: {e}")))?;
+    Ok((raw_module.as_str(), raw_name.as_str()) == (module, name)
+        && crate::rename::encode_rename(module, name).is_none())
 }
 
 /// Decode a ZODB record (two concatenated pickles) into a JSON value.
-/// (serde_json path — used by Rust tests; Python API uses pyconv instead)
+/// (serde_json path — used by `diff_zodb_records`, the C API and the
+/// `zodbjson` CLI; Python API uses pyconv instead)
 ///
-/// Returns: `{"@cls": ["module", "name"], "@s": { ... state ... }}`
-#[cfg(test)]
-fn decode_zodb_record(data: &[u8]) -> Result<Value, CodecError> {
+/// Returns: `{"@cls": ["module", "name"], "@s": { ... state ... }}`, with
+/// BTree and container states flattened and compact `@ref`s, as
+/// `decode_zodb_record` does in Python.
+///
+/// ```
+/// use zodb_json_codec::{decode_zodb_record, encode_zodb_record};
+///
+/// let doc = serde_json::json!({"@cls": ["myapp", "Doc"], "@s": {"title": "Hello"}});
+/// let record = encode_zodb_record(doc.clone())?;
+/// assert_eq!(decode_zodb_record(&record)?, doc);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn decode_zodb_record(data: &[u8]) -> Result<Value, CodecError> {
     let (class_val, state_val) = crate::decode::decode_zodb_pickles(data)?;
 
     // Extract class info
     let (module, name) = extract_class_info(&class_val);
-
-    // Use BTree-specific state conversion if applicable
-    let state_json = if let Some(info) = btrees::classify_btree(&module, &name) {
-        btrees::btree_state_to_json(&info, &state_val, &pickle_value_to_json)?
-    } else {
-        pickle_value_to_json(&state_val)?
+    let state_json = match typed_state_to_json(&module, &name, &state_val)? {
+        Some(typed) => typed,
+        None => value_to_record_json(&state_val)?,
     };
 
-    // Post-process: convert ZODB persistent references to compact form
-    let state_json = transform_persistent_refs(state_json);
-
-    Ok(json!({
+    let mut record = json!({
         "@cls": [module, name],
         "@s": state_json,
-    }))
+    });
+    if is_blob(&module, &name, state_val == PickleValue::None) {
+        record["@blob"] = Value::Bool(true);
+    }
+    Ok(record)
+}
+
+/// JSON of a BTree or container class state (`@kv`, `@pmap`, ...), or
+/// `None` if the class has no state-specific conversion.
+pub(crate) fn typed_state_to_json(
+    module: &str,
+    name: &str,
+    state_val: &PickleValue,
+) -> Result<Option<Value>, CodecError> {
+    let typed = if let Some(info) = btrees::classify_btree(module, name) {
+        btrees::btree_state_to_json(&info, state_val, &pickle_value_to_json)?
+    } else if let Some(typed) = known_types::try_container_state_to_typed_json(
+        module,
+        name,
+        state_val,
+        &pickle_value_to_json,
+    )? {
+        typed
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(transform_persistent_refs(typed)))
+}
+
+/// JSON of a value inside a record state, with persistent references in
+/// compact form.
+pub(crate) fn value_to_record_json(val: &PickleValue) -> Result<Value, CodecError> {
+    Ok(transform_persistent_refs(pickle_value_to_json(val)?))
 }
 
 /// Encode a ZODB JSON record back into two concatenated pickles.
-/// (serde_json path — used by Rust tests; Python API uses pyconv instead)
+/// (serde_json path — used by `apply_patch_to_record`, the C API and the
+/// `zodbjson` CLI; Python API uses pyconv instead)
 /// Takes ownership to avoid cloning the state tree for persistent ref restoration.
-#[cfg(test)]
-fn encode_zodb_record(mut json_val: Value) -> Result<Vec<u8>, CodecError> {
+pub fn encode_zodb_record(mut json_val: Value) -> Result<Vec<u8>, CodecError> {
+    match json_val.get("@blob") {
+        None => {}
+        Some(Value::Bool(true)) => {
+            let cls = match json_val.get("@cls").map(|cls| cls.as_array().map(Vec::as_slice)) {
+                None => None,
+                Some(Some([Value::String(module), Value::String(name)])) => Some((module.as_str(), name.as_str())),
+                Some(_) => return Err(CodecError::InvalidData("@cls must be [module, name]".to_string())),
+            };
+            let state_is_none = json_val.get("@s").is_none_or(Value::is_null);
+            check_blob_record(cls, state_is_none)?;
+            return Ok(BLOB_RECORD.to_vec());
+        }
+        Some(_) => return Err(CodecError::InvalidData("@blob must be true".to_string())),
+    }
     let cls = json_val
         .get("@cls")
         .ok_or_else(|| CodecError::InvalidData("missing @cls in ZODB record".to_string()))?;
@@ -190,7 +333,18 @@ fn encode_zodb_record(mut json_val: Value) -> Result<Vec<u8>, CodecError> {
     // Check for BTree class before moving module/name into Global
     let btree_info = btrees::classify_btree(&module, &name);
 
-    let class_bytes = pyconv::build_class_pickle(&module, &name);
+    let class_bytes = match json_val.get("@cls_raw") {
+        None => build_class_pickle(&module, &name),
+        Some(Value::String(raw)) => {
+            let raw = b64_decode(raw)?;
+            if keeps_class_pickle(&raw, &module, &name)? {
+                raw
+            } else {
+                build_class_pickle(&module, &name)
+            }
+        }
+        Some(_) => return Err(CodecError::InvalidData("@cls_raw must be base64 text".to_string())),
+    };
 
     // Take ownership of @s to avoid cloning, then restore persistent refs
     let state = json_val
@@ -199,12 +353,27 @@ fn encode_zodb_record(mut json_val: Value) -> Result<Vec<u8>, CodecError> {
         .unwrap_or(Value::Null);
     let state = restore_persistent_refs(state);
 
+    // A state kept as @pkl by lenient decoding is the state pickle itself
+    if let Some(raw) = raw_state(&state) {
+        let data = raw_pickle::decode_raw_pickle(raw)
+            .map_err(|e| e.in_path(PathSegment::Key("@s")))?;
+        let mut result = class_bytes;
+        result.extend_from_slice(&data);
+        return Ok(result);
+    }
+
     // Use BTree-specific state decoding if applicable
     let state_val = if let Some(info) = btree_info {
-        btrees::json_to_btree_state(&info, &state, &json_to_pickle_value)?
+        btrees::json_to_btree_state(&info, &state, &json_to_pickle_value)
     } else {
-        json_to_pickle_value(&state)?
+        known_types::try_typed_json_to_container_state(&module, &name, &state, &json_to_pickle_value)
+            .transpose()
+            .unwrap_or_else(|| json_to_pickle_value(&state))
     };
+    let state_val = state_val.map_err(|e| e.in_path(PathSegment::Key("@s")))?;
+    if is_blob(&module, &name, state_val == PickleValue::None) {
+        return Ok(BLOB_RECORD.to_vec());
+    }
     let state_bytes = encode_pickle(&state_val)?;
 
     // Concatenate
@@ -213,7 +382,129 @@ fn encode_zodb_record(mut json_val: Value) -> Result<Vec<u8>, CodecError> {
     Ok(result)
 }
 
-#[cfg(test)]
+/// The base64 payload of a record state that is a single `@pkl` marker,
+/// as lenient decoding writes for a state pickle it cannot decode.
+fn raw_state(state: &Value) -> Option<&str> {
+    match state.as_object() {
+        Some(map) if map.len() == 1 => map.get("@pkl").and_then(Value::as_str),
+        _ => None,
+    }
+}
+
+/// Decode a standalone persistent id pickle, as `ZODB.serialize.referencesf`
+/// and undo logs handle them, into its `{"@ref": ...}` marker in the
+/// compact form of [`decode_zodb_record`].
+///
+/// ```
+/// use zodb_json_codec::{decode_persistent_id, encode_persistent_id};
+///
+/// // (oid, None) as ZODB pickles it
+/// let data = b"\x80\x03C\x08\x00\x00\x00\x00\x00\x00\x00\x03N\x86q\x00.";
+/// let json = decode_persistent_id(data)?;
+/// assert_eq!(json, serde_json::json!({"@ref": "0000000000000003"}));
+/// assert_eq!(decode_persistent_id(&encode_persistent_id(json.clone())?)?, json);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn decode_persistent_id(data: &[u8]) -> Result<Value, CodecError> {
+    let pid = crate::decode::decode_pickle(data)?;
+    value_to_record_json(&PickleValue::PersistentRef(Box::new(pid)))
+}
+
+/// Encode an `{"@ref": ...}` marker, compact or generic, as a standalone
+/// persistent id pickle (protocol 3).
+pub fn encode_persistent_id(json_val: Value) -> Result<Vec<u8>, CodecError> {
+    match json_to_pickle_value(&restore_persistent_refs(json_val))? {
+        PickleValue::PersistentRef(pid) => encode_pickle(&pid),
+        _ => Err(CodecError::InvalidData("a persistent id must be an @ref marker".to_string())),
+    }
+}
+
+/// How compact persistent refs write their OID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RefFormat {
+    /// 16 lowercase hex digits: `{"@ref": "000000000000002a"}`.
+    #[default]
+    Hex,
+    /// A signed 64-bit integer, as in the `refs` of
+    /// `decode_zodb_record_for_pg`: `{"@ref": 42}`. OIDs that are not
+    /// 8 bytes stay hex.
+    Int,
+}
+
+thread_local! {
+    /// Whether compact refs use [`RefFormat::Int`] (hex by default).
+    static INT_REFS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f` with the compact refs written by the conversions it makes on
+/// this thread using `format` for their OID. The encoder reads both
+/// formats regardless.
+///
+/// ```
+/// use zodb_json_codec::{decode_persistent_id, with_ref_format, RefFormat};
+///
+/// // (oid, None) as ZODB pickles it
+/// let data = b"\x80\x03C\x08\x00\x00\x00\x00\x00\x00\x00\x03N\x86q\x00.";
+/// let json = with_ref_format(RefFormat::Int, || decode_persistent_id(data))?;
+/// assert_eq!(json, serde_json::json!({"@ref": 3}));
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub fn with_ref_format<R>(format: RefFormat, f: impl FnOnce() -> R) -> R {
+    let _scope = RefFormatScope::enter(format);
+    f()
+}
+
+/// Sets the compact ref OID format on the current thread while alive.
+pub(crate) struct RefFormatScope {
+    previous: bool,
+}
+
+impl RefFormatScope {
+    pub(crate) fn enter(format: RefFormat) -> Self {
+        RefFormatScope {
+            previous: INT_REFS.with(|i| i.replace(format == RefFormat::Int)),
+        }
+    }
+}
+
+impl Drop for RefFormatScope {
+    fn drop(&mut self) {
+        INT_REFS.with(|i| i.set(self.previous));
+    }
+}
+
+/// The integer OID to write for `oid` in a compact ref, or `None` to
+/// write hex.
+#[inline]
+pub(crate) fn int_ref_oid(oid: &[u8]) -> Option<i64> {
+    if !INT_REFS.with(Cell::get) {
+        return None;
+    }
+    Some(i64::from_be_bytes(oid.try_into().ok()?))
+}
+
+/// The JSON of an OID in a compact ref.
+pub(crate) fn ref_oid_json(oid: &[u8]) -> Value {
+    match int_ref_oid(oid) {
+        Some(n) => Value::from(n),
+        None => Value::String(hex_encode(oid)),
+    }
+}
+
+/// The OID of a compact ref from either format: hex digits or an integer
+/// (signed or unsigned 64-bit).
+pub(crate) fn ref_oid_from_json(val: &Value) -> Option<Vec<u8>> {
+    match val {
+        Value::String(hex) => hex_decode(hex).ok(),
+        Value::Number(n) => n
+            .as_i64()
+            .map(i64::to_be_bytes)
+            .or_else(|| n.as_u64().map(u64::to_be_bytes))
+            .map(|b| b.to_vec()),
+        _ => None,
+    }
+}
+
 /// Transform ZODB persistent references from generic form to compact form.
 ///
 /// ZODB persistent references in pickle are tuples: (oid_bytes, class_info)
@@ -226,6 +517,8 @@ fn encode_zodb_record(mut json_val: Value) -> Result<Vec<u8>, CodecError> {
 /// Compact ZODB form:
 ///   {"@ref": "0000000000000003"}                          (oid only)
 ///   {"@ref": ["0000000000000003", "mod.Cls"]}             (oid + class)
+///   {"@ref": ["0000000000000003"]}                        (legacy weak ref)
+///   {"@ref": {"oid": "0000000000000003", "weak": true}}   (see `ExtendedRef`)
 fn transform_persistent_refs(val: Value) -> Value {
     match val {
         Value::Object(mut map) => {
@@ -250,9 +543,14 @@ fn transform_persistent_refs(val: Value) -> Value {
     }
 }
 
-#[cfg(test)]
 /// Try to convert a generic persistent ref value to compact ZODB form.
 fn try_compact_ref(ref_val: &Value) -> Option<Value> {
+    // Legacy weak and extended refs are lists: [oid] or ["w", (oid,)] etc.
+    if ref_val.is_array() {
+        let inner = json_to_pickle_value(ref_val).ok()?;
+        return ExtendedRef::parse(&inner).map(|r| r.to_json());
+    }
+
     // Expected: {"@t": [{"@b": "base64_oid"}, class_or_null]}
     let tuple_items = ref_val.as_object()?.get("@t")?.as_array()?;
     if tuple_items.len() != 2 {
@@ -261,24 +559,18 @@ fn try_compact_ref(ref_val: &Value) -> Option<Value> {
 
     // First element: oid bytes as {"@b": "base64..."}
     let oid_b64 = tuple_items[0].as_object()?.get("@b")?.as_str()?;
-    let oid_bytes = base64::engine::general_purpose::STANDARD
-        .decode(oid_b64)
-        .ok()?;
-    let oid_hex = hex::encode(&oid_bytes);
+    let oid_bytes = b64_decode(oid_b64).ok()?;
+    let oid = ref_oid_json(&oid_bytes);
 
     // Second element: None or {"@cls": ["module", "name"]}
     if tuple_items[1].is_null() {
-        Some(Value::String(oid_hex))
+        Some(oid)
     } else if let Some(cls_arr) = tuple_items[1].as_object()?.get("@cls")?.as_array() {
         if cls_arr.len() == 2 {
             let module = cls_arr[0].as_str().unwrap_or("");
             let name = cls_arr[1].as_str().unwrap_or("");
-            let class_path = if module.is_empty() {
-                name.to_string()
-            } else {
-                format!("{module}.{name}")
-            };
-            Some(json!([oid_hex, class_path]))
+            let class_path = compact_class_path(module, name)?;
+            Some(json!([oid, class_path]))
         } else {
             None
         }
@@ -287,12 +579,12 @@ fn try_compact_ref(ref_val: &Value) -> Option<Value> {
     }
 }
 
-#[cfg(test)]
 /// Restore compact ZODB persistent refs back to the generic form for encoding.
 ///
-/// Compact: {"@ref": "0000000000000003"} or {"@ref": ["oid_hex", "mod.Cls"]}
+/// Compact: {"@ref": "0000000000000003"} or {"@ref": ["oid_hex", "mod.Cls"]},
+/// plus the `ExtendedRef` forms
 /// Generic: {"@ref": {"@t": [{"@b": "base64"}, null_or_cls]}}
-fn restore_persistent_refs(val: Value) -> Value {
+pub(crate) fn restore_persistent_refs(val: Value) -> Value {
     match val {
         Value::Object(mut map) => {
             if let Some(ref_val) = map.get("@ref").cloned() {
@@ -314,66 +606,245 @@ fn restore_persistent_refs(val: Value) -> Value {
     }
 }
 
-#[cfg(test)]
 /// Expand a compact ref back to generic tuple form.
 fn try_expand_ref(ref_val: &Value) -> Option<Value> {
     match ref_val {
-        // Simple string oid: {"@ref": "0000000000000003"}
-        Value::String(oid_hex) => {
-            let oid_bytes = hex::decode(oid_hex).ok()?;
-            let oid_b64 = base64::engine::general_purpose::STANDARD.encode(&oid_bytes);
+        // Simple oid: {"@ref": "0000000000000003"} or {"@ref": 3}
+        Value::String(_) | Value::Number(_) => {
+            let oid_bytes = ref_oid_from_json(ref_val)?;
+            let oid_b64 = b64_encode(&oid_bytes);
             Some(json!({"@t": [{"@b": oid_b64}, null]}))
         }
         // Array [oid, class]: {"@ref": ["0000000000000003", "mod.Cls"]}
         Value::Array(arr) if arr.len() == 2 => {
-            let oid_hex = arr[0].as_str()?;
             let class_path = arr[1].as_str()?;
-            let oid_bytes = hex::decode(oid_hex).ok()?;
-            let oid_b64 = base64::engine::general_purpose::STANDARD.encode(&oid_bytes);
+            let oid_bytes = ref_oid_from_json(&arr[0])?;
+            let oid_b64 = b64_encode(&oid_bytes);
 
-            // Split "module.ClassName" back into ["module", "ClassName"]
-            let (module, name) = if let Some(dot_pos) = class_path.rfind('.') {
-                (&class_path[..dot_pos], &class_path[dot_pos + 1..])
-            } else {
-                ("", class_path)
-            };
+            let (module, name) = split_class_path(class_path);
 
             Some(json!({"@t": [{"@b": oid_b64}, {"@cls": [module, name]}]}))
         }
+        // Legacy weak ref: {"@ref": ["0000000000000003"]}
+        Value::Array(arr) if arr.len() == 1 => {
+            let oid_bytes = ref_oid_from_json(&arr[0])?;
+            Some(json!([{"@b": b64_encode(&oid_bytes)}]))
+        }
+        // Extended ref: {"@ref": {"oid": "0000000000000003", "weak": true}} etc.
+        Value::Object(map) => {
+            let inner = expand_extended_ref_json(map).ok()??;
+            pickle_value_to_json(&inner).ok()
+        }
         _ => None,
     }
 }
 
+/// The `"module.Name"` class hint of a compact `@ref`, or `None` when the
+/// class cannot be written in that form.
+///
+/// The hint is split at its last dot, so it only round-trips when `name`
+/// has no dot (a protocol 4 qualified name like `Outer.Inner` does). An
+/// empty module is written as the bare name. Refs without a compact hint
+/// keep the generic `{"@t": [{"@b": oid}, {"@cls": [module, name]}]}` form.
+pub(crate) fn compact_class_path(module: &str, name: &str) -> Option<String> {
+    if name.contains('.') {
+        None
+    } else if module.is_empty() {
+        Some(name.to_string())
+    } else {
+        Some(format!("{module}.{name}"))
+    }
+}
+
+/// Split a compact `@ref` class hint back into (module, name).
+pub(crate) fn split_class_path(class_path: &str) -> (&str, &str) {
+    match class_path.rfind('.') {
+        Some(dot) => (&class_path[..dot], &class_path[dot + 1..]),
+        None => ("", class_path),
+    }
+}
+
+/// A ZODB persistent id other than `(oid, class)`, in compact `@ref` form.
+///
+/// `ZODB.serialize` writes the legacy weak reference `[oid]` and the
+/// extended references `['w', (oid,)]`, `['w', (oid, db)]`,
+/// `['n', (db, oid)]` and `['m', (db, oid, klass)]`. The legacy form
+/// compacts to `["hex_oid"]`, the extended ones to an object:
+/// `{"oid": "hex_oid", "weak": true, "db": "name", "cls": "mod.Cls"}`,
+/// with only the keys the form carries. Ids with Python 2 byte-string
+/// tags, or a class without a compact hint, keep the generic form.
+pub(crate) enum ExtendedRef<'a> {
+    /// `[oid]`
+    Legacy(&'a [u8]),
+    /// `['w', ...]`, `['n', ...]` or `['m', ...]`
+    Extended {
+        oid: &'a [u8],
+        weak: bool,
+        db: Option<&'a str>,
+        class: Option<String>,
+    },
+}
+
+impl<'a> ExtendedRef<'a> {
+    /// Recognise an extended persistent id (the inner value of a
+    /// `PersistentRef`).
+    pub(crate) fn parse(inner: &'a PickleValue) -> Option<Self> {
+        let PickleValue::List(items) = inner else {
+            return None;
+        };
+        match items.as_slice() {
+            [PickleValue::Bytes(oid)] => Some(ExtendedRef::Legacy(oid)),
+            [PickleValue::String(tag), PickleValue::Tuple(args)] => {
+                match (&**tag, args.as_slice()) {
+                    ("w", [PickleValue::Bytes(oid)]) => Some(ExtendedRef::Extended {
+                        oid,
+                        weak: true,
+                        db: None,
+                        class: None,
+                    }),
+                    ("w", [PickleValue::Bytes(oid), PickleValue::String(db)]) => {
+                        Some(ExtendedRef::Extended {
+                            oid,
+                            weak: true,
+                            db: Some(db),
+                            class: None,
+                        })
+                    }
+                    ("n", [PickleValue::String(db), PickleValue::Bytes(oid)]) => {
+                        Some(ExtendedRef::Extended {
+                            oid,
+                            weak: false,
+                            db: Some(db),
+                            class: None,
+                        })
+                    }
+                    (
+                        "m",
+                        [PickleValue::String(db), PickleValue::Bytes(oid), PickleValue::Global { module, name }],
+                    ) => Some(ExtendedRef::Extended {
+                        oid,
+                        weak: false,
+                        db: Some(db),
+                        class: Some(compact_class_path(module, name)?),
+                    }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The compact `@ref` value.
+    pub(crate) fn to_json(&self) -> Value {
+        match self {
+            ExtendedRef::Legacy(oid) => json!([ref_oid_json(oid)]),
+            ExtendedRef::Extended { oid, weak, db, class } => {
+                let mut map = serde_json::Map::new();
+                map.insert("oid".to_string(), ref_oid_json(oid));
+                if *weak {
+                    map.insert("weak".to_string(), Value::Bool(true));
+                }
+                if let Some(db) = db {
+                    map.insert("db".to_string(), Value::String(db.to_string()));
+                }
+                if let Some(class) = class {
+                    map.insert("cls".to_string(), Value::String(class.clone()));
+                }
+                Value::Object(map)
+            }
+        }
+    }
+}
+
+/// Rebuild the persistent id of an object-form `@ref` value from its
+/// fields; `None` when the fields name no ZODB reference form.
+pub(crate) fn expand_extended_ref(
+    oid: Vec<u8>,
+    weak: bool,
+    db: Option<&str>,
+    class_path: Option<&str>,
+) -> Result<Option<PickleValue>, CodecError> {
+    let oid = PickleValue::Bytes(oid);
+    let string = |s: &str| PickleValue::String(s.into());
+    let (tag, args) = match (weak, db, class_path) {
+        (true, None, None) => ("w", vec![oid]),
+        (true, Some(db), None) => ("w", vec![oid, string(db)]),
+        (false, Some(db), None) => ("n", vec![string(db), oid]),
+        (false, Some(db), Some(class_path)) => {
+            let (module, name) = split_class_path(class_path);
+            let class = PickleValue::Global {
+                module: module.to_string(),
+                name: name.to_string(),
+            };
+            ("m", vec![string(db), oid, class])
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(PickleValue::List(vec![string(tag), PickleValue::Tuple(args)])))
+}
+
+/// Rebuild the persistent id of an object-form `@ref` value, or `None`
+/// when the object has other keys or names no ZODB reference form.
+pub(crate) fn expand_extended_ref_json(
+    map: &serde_json::Map<String, Value>,
+) -> Result<Option<PickleValue>, CodecError> {
+    let oid = match map.get("oid") {
+        Some(Value::String(hex)) => hex_decode(hex)?,
+        Some(oid @ Value::Number(_)) => match ref_oid_from_json(oid) {
+            Some(oid) => oid,
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let weak = match map.get("weak") {
+        None => false,
+        Some(Value::Bool(true)) => true,
+        Some(_) => return Ok(None),
+    };
+    let mut fields = 1 + usize::from(map.contains_key("weak"));
+    let mut text = |key: &str| match map.get(key) {
+        None => Ok(None),
+        Some(Value::String(s)) => {
+            fields += 1;
+            Ok(Some(s.as_str()))
+        }
+        Some(_) => Err(()),
+    };
+    let (Ok(db), Ok(class_path)) = (text("db"), text("cls")) else {
+        return Ok(None);
+    };
+    if fields != map.len() {
+        return Ok(None);
+    }
+    expand_extended_ref(oid, weak, db, class_path)
+}
+
 /// Extract (module, name) from a class pickle value.
 ///
 /// ZODB class pickles come in several formats:
 ///   1. GLOBAL opcode: `PickleValue::Global { module, name }`
 ///   2. Nested tuple: `((module, name), None_or_args)` — from PersistentPickler
 ///   3. Flat tuple: `(module, name)` — legacy/simplified
+///
+/// A class blocked by the decode policy is reported by name like a GLOBAL.
 pub fn extract_class_info(val: &PickleValue) -> (String, String) {
     match val {
-        PickleValue::Global { module, name } => (module.clone(), name.clone()),
+        PickleValue::Global { module, name } | PickleValue::Blocked { module, name } => {
+            (module.clone(), name.clone())
+        }
         PickleValue::Tuple(items) if items.len() == 2 => {
             match &items[0] {
                 // Nested tuple: ((module, name), None_or_args)
                 PickleValue::Tuple(inner) if inner.len() == 2 => {
-                    let module = match &inner[0] {
-                        PickleValue::String(s) => s.clone(),
-                        _ => String::new(),
-                    };
-                    let name = match &inner[1] {
-                        PickleValue::String(s) => s.clone(),
-                        _ => String::new(),
-                    };
-                    (module, name)
+                    (class_name_part(&inner[0]), class_name_part(&inner[1]))
+                }
+                // Class object: (klass, None_or_args), as written by Python 2 ZODB
+                PickleValue::Global { module, name } | PickleValue::Blocked { module, name } => {
+                    (module.clone(), name.clone())
                 }
                 // Flat tuple: (module_str, name_str)
-                PickleValue::String(module) => {
-                    let name = match &items[1] {
-                        PickleValue::String(s) => s.clone(),
-                        _ => String::new(),
-                    };
-                    (module.clone(), name)
+                first @ (PickleValue::String(_) | PickleValue::Bytes(_)) => {
+                    (class_name_part(first), class_name_part(&items[1]))
                 }
                 _ => (String::new(), String::new()),
             }
@@ -382,10 +853,194 @@ pub fn extract_class_info(val: &PickleValue) -> (String, String) {
     }
 }
 
+/// A module or class name from a class pickle: text, or a Python 2 byte
+/// string (`STRING` opcode).
+fn class_name_part(val: &PickleValue) -> String {
+    match val {
+        PickleValue::String(s) => s.to_string(),
+        PickleValue::Bytes(b) => String::from_utf8(b.clone()).unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// ZEO client cache files
+// ---------------------------------------------------------------------------
+
+/// Magic bytes at the start of a ZEO client cache file.
+const ZEO_CACHE_MAGIC: &[u8; 4] = b"ZEC3";
+/// Magic plus the 8-byte last transaction id.
+const ZEO_CACHE_HEADER_SIZE: usize = 12;
+/// Status, size, oid, start/end tid, version and data lengths, trailing oid.
+const ZEO_CACHE_RECORD_OVERHEAD: usize = 1 + 4 + 8 + 8 + 8 + 2 + 4 + 8;
+
+/// One object revision stored in a ZEO client cache file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ZeoCacheRecord<'a> {
+    pub oid: [u8; 8],
+    /// Transaction that wrote this revision.
+    pub start_tid: [u8; 8],
+    /// Transaction that superseded it; `None` for the current revision.
+    pub end_tid: Option<[u8; 8]>,
+    /// The ZODB record (class pickle + state pickle).
+    pub data: &'a [u8],
+}
+
+/// A parsed ZEO client cache file (ZEO 4/5, `ZEC3` format).
+///
+/// The file is a 12-byte header (magic and last tid) followed by blocks:
+/// `a` allocated blocks holding one object revision, `f` free blocks with
+/// a 4-byte size, and `1`-`8` free blocks of that many bytes.
+///
+/// ```
+/// use zodb_json_codec::ZeoCache;
+///
+/// let mut file = b"ZEC3".to_vec();
+/// file.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 9]); // last tid
+/// file.extend_from_slice(b"4..."); // a 4-byte free block
+/// let cache = ZeoCache::parse(&file)?;
+/// assert_eq!(cache.last_tid(), [0, 0, 0, 0, 0, 0, 0, 9]);
+/// assert_eq!(cache.records().count(), 0);
+/// # Ok::<(), zodb_json_codec::CodecError>(())
+/// ```
+pub struct ZeoCache<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ZeoCache<'a> {
+    /// Check the header of a cache file's contents.
+    pub fn parse(data: &'a [u8]) -> Result<Self, CodecError> {
+        if data.len() < ZEO_CACHE_HEADER_SIZE || &data[..4] != ZEO_CACHE_MAGIC {
+            return Err(CodecError::InvalidData(
+                "not a ZEO cache file (expected ZEC3 header)".to_string(),
+            ));
+        }
+        Ok(ZeoCache { data })
+    }
+
+    /// The last transaction id the cache was synchronized to.
+    pub fn last_tid(&self) -> [u8; 8] {
+        self.data[4..ZEO_CACHE_HEADER_SIZE].try_into().unwrap()
+    }
+
+    /// Iterate over the allocated records, in file order.
+    pub fn records(&self) -> ZeoCacheRecords<'a> {
+        ZeoCacheRecords {
+            data: self.data,
+            pos: ZEO_CACHE_HEADER_SIZE,
+        }
+    }
+}
+
+/// Iterator over the records of a [`ZeoCache`]. Stops after the first
+/// error.
+pub struct ZeoCacheRecords<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ZeoCacheRecords<'a> {
+    fn read_u32(&self, at: usize) -> Result<usize, CodecError> {
+        let b = self.data.get(at..at + 4).ok_or(CodecError::UnexpectedEof)?;
+        Ok(u32::from_be_bytes(b.try_into().unwrap()) as usize)
+    }
+
+    fn next_record(&mut self) -> Result<Option<ZeoCacheRecord<'a>>, CodecError> {
+        while self.pos < self.data.len() {
+            let start = self.pos;
+            match self.data[start] {
+                b'a' => {
+                    let size = self.read_u32(start + 1)?;
+                    let block = self
+                        .data
+                        .get(start..start + size)
+                        .filter(|b| b.len() >= ZEO_CACHE_RECORD_OVERHEAD)
+                        .ok_or_else(|| bad_block(start, "truncated record"))?;
+                    let oid: [u8; 8] = block[5..13].try_into().unwrap();
+                    let start_tid: [u8; 8] = block[13..21].try_into().unwrap();
+                    let end_tid: [u8; 8] = block[21..29].try_into().unwrap();
+                    let lver = u16::from_be_bytes([block[29], block[30]]);
+                    let ldata = u32::from_be_bytes(block[31..35].try_into().unwrap()) as usize;
+                    if lver != 0 {
+                        return Err(bad_block(start, "versioned records are not supported"));
+                    }
+                    if ZEO_CACHE_RECORD_OVERHEAD + ldata != size
+                        || block[35 + ldata..] != oid
+                    {
+                        return Err(bad_block(start, "inconsistent record size"));
+                    }
+                    self.pos = start + size;
+                    return Ok(Some(ZeoCacheRecord {
+                        oid,
+                        start_tid,
+                        end_tid: (end_tid != [0; 8]).then_some(end_tid),
+                        data: &block[35..35 + ldata],
+                    }));
+                }
+                b'f' => {
+                    let size = self.read_u32(start + 1)?;
+                    if size < 5 {
+                        return Err(bad_block(start, "free block too small"));
+                    }
+                    self.pos = start + size;
+                }
+                n @ b'1'..=b'8' => self.pos = start + (n - b'0') as usize,
+                other => {
+                    return Err(bad_block(start, &format!("unknown status byte 0x{other:02x}")));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn bad_block(offset: usize, msg: &str) -> CodecError {
+    CodecError::InvalidData(format!("ZEO cache block at offset {offset}: {msg}"))
+}
+
+impl<'a> Iterator for ZeoCacheRecords<'a> {
+    type Item = Result<ZeoCacheRecord<'a>, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.next_record().transpose();
+        if matches!(result, Some(Err(_))) {
+            self.pos = self.data.len();
+        }
+        result
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_lenient_state_encoded_back() {
+        // GET of a missing memo entry, kept as @pkl by lenient decoding
+        let state = b"\x80\x03h\x05.";
+        let record = json!({"@cls": ["myapp", "Doc"], "@s": {"@pkl": b64_encode(state)}});
+        let encoded = encode_zodb_record(record).unwrap();
+        let (class_pickle, state_pickle) = split_zodb_record(&encoded).unwrap();
+        assert_eq!(class_pickle, build_class_pickle("myapp", "Doc"));
+        assert_eq!(state_pickle, state);
+        // Without a STOP the payload is no pickle to write back
+        let record = json!({"@cls": ["myapp", "Doc"], "@s": {"@pkl": b64_encode(b"\x80\x03\xff")}});
+        let err = encode_zodb_record(record).unwrap_err();
+        assert!(err.to_string().contains("@pkl payload rejected"), "{err}");
+    }
+
+    #[test]
+    fn test_extract_class_info_python2_forms() {
+        use crate::decode::decode_pickle;
+        // (klass, None) with klass a GLOBAL
+        let class_val = decode_pickle(b"(cOFS.Folder\nFolder\np1\nNt.").unwrap();
+        assert_eq!(extract_class_info(&class_val), ("OFS.Folder".into(), "Folder".into()));
+        // ((module, name), None) with Python 2 byte strings
+        let class_val = decode_pickle(b"((S'OFS.Folder'\nS'Folder'\ntNt.").unwrap();
+        assert_eq!(extract_class_info(&class_val), ("OFS.Folder".into(), "Folder".into()));
+    }
+
     #[test]
     fn test_split_zodb_record() {
         // Two simple pickles concatenated: None + True
@@ -398,18 +1053,55 @@ mod tests {
         assert_eq!(p2, b"\x80\x02\x88.");
     }
 
+    #[test]
+    fn test_class_pickle_raw() {
+        // Protocol 1 class pickle (module, name) with memo opcodes
+        let class_pickle = b"(X\x05\x00\x00\x00myappq\x00X\x03\x00\x00\x00Docq\x01tq\x02.";
+        let record = [&class_pickle[..], b"\x80\x02}q\x00."].concat();
+        assert_eq!(
+            class_pickle_to_keep(&record, "myapp", "Doc").unwrap(),
+            Some(&class_pickle[..])
+        );
+        assert_eq!(class_pickle_to_keep(&record, "other", "Doc").unwrap(), None);
+        assert!(keeps_class_pickle(class_pickle, "myapp", "Doc").unwrap());
+        assert!(!keeps_class_pickle(class_pickle, "myapp", "Folder").unwrap());
+        assert!(keeps_class_pickle(&record, "myapp", "Doc").is_err());
+
+        let mut json = decode_zodb_record(&record).unwrap();
+        json["@cls_raw"] = Value::String(b64_encode(class_pickle));
+        let encoded = encode_zodb_record(json.clone()).unwrap();
+        assert!(encoded.starts_with(class_pickle));
+        // A changed class is written anew
+        json["@cls"] = json!(["myapp", "Folder"]);
+        let encoded = encode_zodb_record(json).unwrap();
+        assert!(encoded.starts_with(&build_class_pickle("myapp", "Folder")));
+    }
+
+    #[test]
+    fn test_find_pickle_end_bounds_text_lines() {
+        let mut data = b"c".to_vec();
+        data.extend(std::iter::repeat_n(b'm', crate::limits::DEFAULT_MAX_NAME_LINE + 1));
+        data.extend_from_slice(b"\nName\n.");
+        let err = find_pickle_end(&data).unwrap_err();
+        assert!(matches!(err.root(), CodecError::LimitExceeded(_)), "{err}");
+        assert_eq!(find_pickle_end(b"cmod\nName\n.N.").unwrap(), 11);
+        // INST also has two lines, OBJ none
+        assert_eq!(find_pickle_end(b"(imy.app\nDoc\n(db.N.").unwrap(), 17);
+        assert_eq!(find_pickle_end(b"(cmy.app\nDoc\no.N.").unwrap(), 15);
+    }
+
     #[test]
     fn test_decode_encode_roundtrip() {
         // Build a minimal ZODB-like record:
         // Class pickle: ("mymodule", "MyClass")
         // State pickle: {"title": "hello"}
         let class_val = PickleValue::Tuple(vec![
-            PickleValue::String("mymodule".to_string()),
-            PickleValue::String("MyClass".to_string()),
+            PickleValue::String("mymodule".into()),
+            PickleValue::String("MyClass".into()),
         ]);
         let state_val = PickleValue::Dict(vec![(
-            PickleValue::String("title".to_string()),
-            PickleValue::String("hello".to_string()),
+            PickleValue::String("title".into()),
+            PickleValue::String("hello".into()),
         )]);
 
         let class_bytes = encode_pickle(&class_val).unwrap();
@@ -431,4 +1123,292 @@ mod tests {
         assert_eq!(json["@cls"], json2["@cls"]);
         assert_eq!(json["@s"], json2["@s"]);
     }
+
+    #[test]
+    fn test_blob_record() {
+        let json = decode_zodb_record(BLOB_RECORD).unwrap();
+        assert_eq!(json, json!({"@cls": ["ZODB.blob", "Blob"], "@s": null, "@blob": true}));
+        assert_eq!(encode_zodb_record(json).unwrap(), BLOB_RECORD);
+        assert_eq!(encode_zodb_record(json!({"@blob": true})).unwrap(), BLOB_RECORD);
+        // Without the marker, a Blob record still encodes to ZODB's form
+        let unmarked = json!({"@cls": ["ZODB.blob", "Blob"], "@s": null});
+        assert_eq!(encode_zodb_record(unmarked).unwrap(), BLOB_RECORD);
+
+        for bad in [
+            json!({"@blob": true, "@cls": ["myapp", "Doc"]}),
+            json!({"@blob": true, "@s": {"x": 1}}),
+            json!({"@blob": 1}),
+        ] {
+            assert!(encode_zodb_record(bad).is_err());
+        }
+        assert_eq!(
+            crate::json::canonicalize_json(r#"{"@serial": "03e0000000000042", "@blob": true}"#).unwrap(),
+            r#"{"@blob":true,"@cls":["ZODB.blob","Blob"],"@s":null}"#
+        );
+        // Only ZODB.blob.Blob itself is a blob stub
+        let data = encode_zodb_record(json!({"@cls": ["myapp", "Blob"], "@s": null})).unwrap();
+        assert!(decode_zodb_record(&data).unwrap().get("@blob").is_none());
+    }
+
+    #[test]
+    fn test_persistent_mapping_record() {
+        let class_val = PickleValue::Tuple(vec![
+            PickleValue::String("persistent.mapping".into()),
+            PickleValue::String("PersistentMapping".into()),
+        ]);
+        let state_val = PickleValue::Dict(vec![(
+            PickleValue::String("data".into()),
+            PickleValue::Dict(vec![(
+                PickleValue::String("title".into()),
+                PickleValue::String("hello".into()),
+            )]),
+        )]);
+        let mut record = encode_pickle(&class_val).unwrap();
+        record.extend_from_slice(&encode_pickle(&state_val).unwrap());
+
+        let json = decode_zodb_record(&record).unwrap();
+        assert_eq!(json["@s"], json!({"@pmap": {"title": "hello"}}));
+        let re_encoded = encode_zodb_record(json).unwrap();
+        let (_, state) = crate::decode::decode_zodb_pickles(&re_encoded).unwrap();
+        assert_eq!(state, state_val);
+    }
+
+    #[test]
+    fn test_relation_value_record() {
+        let class_val = PickleValue::Tuple(vec![
+            PickleValue::String("z3c.relationfield.relation".into()),
+            PickleValue::String("RelationValue".into()),
+        ]);
+        let state_val = PickleValue::Dict(vec![
+            (
+                PickleValue::String("from_attribute".into()),
+                PickleValue::String("relatedItems".into()),
+            ),
+            (PickleValue::String("to_id".into()), PickleValue::Int(42)),
+        ]);
+        let mut record = encode_pickle(&class_val).unwrap();
+        record.extend_from_slice(&encode_pickle(&state_val).unwrap());
+
+        let json = decode_zodb_record(&record).unwrap();
+        assert_eq!(json["@s"], json!({"@rel": {"from_attribute": "relatedItems", "to_id": 42}}));
+        let re_encoded = encode_zodb_record(json).unwrap();
+        let (_, state) = crate::decode::decode_zodb_pickles(&re_encoded).unwrap();
+        assert_eq!(state, state_val);
+    }
+
+    #[test]
+    fn test_length_record() {
+        let class_val = PickleValue::Tuple(vec![
+            PickleValue::String("BTrees.Length".into()),
+            PickleValue::String("Length".into()),
+        ]);
+        let mut record = encode_pickle(&class_val).unwrap();
+        record.extend_from_slice(&encode_pickle(&PickleValue::Int(1_234)).unwrap());
+
+        let json = decode_zodb_record(&record).unwrap();
+        assert_eq!(json["@s"], json!({"@len": 1_234}));
+        let re_encoded = encode_zodb_record(json).unwrap();
+        let (_, state) = crate::decode::decode_zodb_pickles(&re_encoded).unwrap();
+        assert_eq!(state, PickleValue::Int(1_234));
+    }
+
+    fn zeo_block(oid: u8, start: u8, end: u8, data: &[u8]) -> Vec<u8> {
+        let size = (ZEO_CACHE_RECORD_OVERHEAD + data.len()) as u32;
+        let mut b = vec![b'a'];
+        b.extend_from_slice(&size.to_be_bytes());
+        b.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, oid]);
+        b.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, start]);
+        b.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, end]);
+        b.extend_from_slice(&0u16.to_be_bytes());
+        b.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        b.extend_from_slice(data);
+        b.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, oid]);
+        b
+    }
+
+    #[test]
+    fn test_zeo_cache_records() {
+        let mut file = b"ZEC3\0\0\0\0\0\0\0\x07".to_vec();
+        file.extend(zeo_block(1, 5, 0, b"N.}."));
+        file.extend_from_slice(b"f\0\0\0\x07xx"); // 7-byte free block
+        file.extend_from_slice(b"2x"); // 2-byte free block
+        file.extend(zeo_block(2, 3, 5, b"N.N."));
+
+        let cache = ZeoCache::parse(&file).unwrap();
+        assert_eq!(cache.last_tid(), [0, 0, 0, 0, 0, 0, 0, 7]);
+        let records: Vec<_> = cache.records().collect::<Result<_, _>>().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].oid, [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(records[0].end_tid, None);
+        assert_eq!(records[0].data, b"N.}.");
+        assert_eq!(records[1].start_tid, [0, 0, 0, 0, 0, 0, 0, 3]);
+        assert_eq!(records[1].end_tid, Some([0, 0, 0, 0, 0, 0, 0, 5]));
+        let (_, state) = split_zodb_record(records[1].data).unwrap();
+        assert_eq!(state, b"N.");
+    }
+
+    #[test]
+    fn test_zeo_cache_errors() {
+        assert!(ZeoCache::parse(b"ZEC2\0\0\0\0\0\0\0\0").is_err());
+
+        let mut file = b"ZEC3\0\0\0\0\0\0\0\0".to_vec();
+        let mut block = zeo_block(1, 1, 0, b"N.N.");
+        block.truncate(block.len() - 3);
+        file.extend(block);
+        let mut records = ZeoCache::parse(&file).unwrap().records();
+        assert!(records.next().unwrap().is_err());
+        assert!(records.next().is_none());
+
+        let mut file = b"ZEC3\0\0\0\0\0\0\0\0".to_vec();
+        file.push(b'z');
+        let err = ZeoCache::parse(&file).unwrap().records().next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("offset 12"), "{err}");
+    }
+
+    #[test]
+    fn test_extended_ref_transform_restore() {
+        let oid = PickleValue::Bytes(vec![0, 0, 0, 0, 0, 0, 0, 9]);
+        let refs = vec![
+            PickleValue::List(vec![oid.clone()]),
+            PickleValue::List(vec![
+                PickleValue::String("w".into()),
+                PickleValue::Tuple(vec![oid.clone(), PickleValue::String("other".into())]),
+            ]),
+            PickleValue::List(vec![
+                PickleValue::String("m".into()),
+                PickleValue::Tuple(vec![
+                    PickleValue::String("other".into()),
+                    oid.clone(),
+                    PickleValue::Global { module: "mod".to_string(), name: "Cls".to_string() },
+                ]),
+            ]),
+        ];
+        for inner in refs {
+            let val = PickleValue::PersistentRef(Box::new(inner));
+            let generic = pickle_value_to_json(&val).unwrap();
+            let compact = transform_persistent_refs(generic.clone());
+            assert_ne!(compact, generic);
+            assert_eq!(restore_persistent_refs(compact), generic);
+        }
+    }
+
+    #[test]
+    fn test_expand_extended_ref_json_rejects_other_shapes() {
+        let expand = |v: Value| expand_extended_ref_json(v.as_object().unwrap()).unwrap();
+        // Plain oid without weak or db is not an extended form
+        assert!(expand(json!({"oid": "0000000000000001"})).is_none());
+        // Weak refs carry no class
+        assert!(expand(json!({"oid": "0000000000000001", "weak": true, "cls": "a.B"})).is_none());
+        assert!(expand(json!({"oid": "0000000000000001", "weak": false, "db": "x"})).is_none());
+        assert!(expand(json!({"oid": "0000000000000001", "db": "x", "extra": 1})).is_none());
+        assert!(expand(json!({"oid": "0000000000000001", "db": "x"})).is_some());
+        assert!(expand_extended_ref_json(json!({"oid": "zz", "db": "x"}).as_object().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_int_ref_oids_restore_like_hex() {
+        let forms = [
+            (json!({"@ref": 3}), json!({"@ref": "0000000000000003"})),
+            (json!({"@ref": [3, "mod.Cls"]}), json!({"@ref": ["0000000000000003", "mod.Cls"]})),
+            (json!({"@ref": [3]}), json!({"@ref": ["0000000000000003"]})),
+            (
+                json!({"@ref": {"oid": 3, "weak": true}}),
+                json!({"@ref": {"oid": "0000000000000003", "weak": true}}),
+            ),
+            // Negative and unsigned forms of the same 64 bits
+            (json!({"@ref": -1}), json!({"@ref": "ffffffffffffffff"})),
+            (json!({"@ref": u64::MAX}), json!({"@ref": "ffffffffffffffff"})),
+        ];
+        for (int, hex) in forms {
+            assert_eq!(restore_persistent_refs(int.clone()), restore_persistent_refs(hex), "{int}");
+        }
+        assert_eq!(ref_oid_from_json(&json!(1.5)), None);
+    }
+
+    #[test]
+    fn test_ref_format_scope() {
+        let oid = 3u64.to_be_bytes();
+        assert_eq!(ref_oid_json(&oid), json!("0000000000000003"));
+        assert_eq!(with_ref_format(RefFormat::Int, || ref_oid_json(&oid)), json!(3));
+        assert_eq!(ref_oid_json(&oid), json!("0000000000000003"));
+    }
+
+    #[test]
+    fn test_persistent_id_roundtrip() {
+        for doc in [
+            json!({"@ref": ["0000000000000003", "mod.Cls"]}),
+            json!({"@ref": ["0000000000000003"]}),
+            json!({"@ref": {"oid": "0000000000000003", "db": "catalog"}}),
+        ] {
+            let data = encode_persistent_id(doc.clone()).unwrap();
+            assert_eq!(decode_persistent_id(&data).unwrap(), doc);
+        }
+        assert!(encode_persistent_id(json!({"oid": "0000000000000003"})).is_err());
+        assert!(encode_persistent_id(json!("0000000000000003")).is_err());
+    }
+
+    #[test]
+    fn test_compact_class_path() {
+        assert_eq!(compact_class_path("myapp.models", "Doc").as_deref(), Some("myapp.models.Doc"));
+        assert_eq!(compact_class_path("__main__", "Doc").as_deref(), Some("__main__.Doc"));
+        assert_eq!(compact_class_path("", "Doc").as_deref(), Some("Doc"));
+        // A qualified name would be split at the wrong dot
+        assert_eq!(compact_class_path("myapp", "Outer.Inner"), None);
+        for (module, name) in [("myapp.models", "Doc"), ("__main__", "Doc"), ("", "Doc")] {
+            let path = compact_class_path(module, name).unwrap();
+            assert_eq!(split_class_path(&path), (module, name));
+        }
+    }
+
+    #[test]
+    fn test_build_class_pickle_matches_pickle_value_encode() {
+        // Verify that build_class_pickle produces identical bytes to the
+        // PickleValue-based approach for various class names.
+        // Note: build_class_pickle uses PROTO 2 (matching production encode),
+        // encode_pickle uses PROTO 3. Both are valid; we compare after byte 1.
+        let cases = vec![
+            ("persistent.mapping", "PersistentMapping"),
+            ("BTrees.OOBTree", "OOBTree"),
+            ("BTrees.OOBTree", "OOBucket"),
+            ("BTrees.Length", "Length"),
+            ("myapp.models", "Article"),
+            ("a", "B"),  // short names
+            ("", ""),    // empty (edge case)
+        ];
+
+        for (module, name) in cases {
+            let cached = build_class_pickle(module, name);
+
+            // Build the same bytes via PickleValue, without the memo
+            // encode_pickle writes for the repeated "" of the last case
+            let class_val = PickleValue::Tuple(vec![
+                PickleValue::Tuple(vec![
+                    PickleValue::String(module.into()),
+                    PickleValue::String(name.into()),
+                ]),
+                PickleValue::None,
+            ]);
+            let mut reference = vec![PROTO, 3];
+            crate::encode::encode_value_into(&class_val, &mut reference).unwrap();
+            reference.push(STOP);
+
+            // Protocol byte differs (2 vs 3), rest must be identical
+            assert_eq!(cached[0], PROTO);
+            assert_eq!(cached[1], 2);
+            assert_eq!(reference[1], 3);
+            assert_eq!(
+                &cached[2..], &reference[2..],
+                "class pickle body mismatch for ({}, {})",
+                module, name
+            );
+        }
+    }
+
+    #[test]
+    fn test_build_class_pickle_starts_with_proto_ends_with_stop() {
+        let bytes = build_class_pickle("mod", "Cls");
+        assert_eq!(bytes[0], PROTO);
+        assert_eq!(bytes[1], 2);
+        assert_eq!(*bytes.last().unwrap(), STOP);
+    }
 }
@@ -0,0 +1,91 @@
+//! End-to-end tests for the `zodbjson` command line tool.
+//!
+//! Runs only with `cargo test --no-default-features --features cli`.
+
+#![cfg(feature = "cli")]
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use serde_json::{json, Value};
+
+/// Runs `zodbjson` with `args`, feeding `stdin` to it.
+fn zodbjson(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_zodbjson"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn zodbjson");
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout_json(out: &Output) -> Value {
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    serde_json::from_slice(&out.stdout).unwrap()
+}
+
+fn record() -> (Value, Vec<u8>) {
+    let doc = json!({
+        "@cls": ["myapp", "Doc"],
+        "@s": {"title": "Hi", "parent": {"@ref": "0000000000000003"}},
+    });
+    let out = zodbjson(&["encode"], doc.to_string().as_bytes());
+    assert!(out.status.success());
+    (doc, out.stdout)
+}
+
+#[test]
+fn test_record_roundtrip() {
+    let (doc, bytes) = record();
+    assert_eq!(stdout_json(&zodbjson(&["decode"], &bytes)), doc);
+    assert_eq!(stdout_json(&zodbjson(&["decode", "--indent", "2", "-"], &bytes)), doc);
+}
+
+#[test]
+fn test_plain_pickle_roundtrip() {
+    let out = zodbjson(&["encode"], br#"{"a": [1, 2.5, null]}"#);
+    assert!(out.status.success());
+    let decoded = stdout_json(&zodbjson(&["decode"], &out.stdout));
+    assert_eq!(decoded, json!({"a": [1, 2.5, null]}));
+}
+
+#[test]
+fn test_refs() {
+    let (_, bytes) = record();
+    let refs = stdout_json(&zodbjson(&["refs"], &bytes));
+    assert_eq!(
+        refs,
+        json!([{"oid": "0000000000000003", "class": null, "database": null}])
+    );
+}
+
+#[test]
+fn test_analyze() {
+    let (_, bytes) = record();
+    let stats = stdout_json(&zodbjson(&["analyze"], &bytes));
+    assert_eq!(stats["pickles"], 2);
+    assert_eq!(stats["persistent_refs"], 1);
+    assert_eq!(stats["size"], bytes.len());
+}
+
+#[test]
+fn test_usage_errors() {
+    let out = zodbjson(&[], b"");
+    assert_eq!(out.status.code(), Some(2));
+    let out = zodbjson(&["bogus"], b"");
+    assert_eq!(out.status.code(), Some(2));
+    let out = zodbjson(&["refs", "--indent", "2"], b"");
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn test_bad_input() {
+    let out = zodbjson(&["decode"], b"garbage");
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).starts_with("zodbjson: error: "));
+    let out = zodbjson(&["encode"], b"{not json");
+    assert_eq!(out.status.code(), Some(1));
+}
@@ -0,0 +1,185 @@
+//! Cross-validation against CPython's `pickle` module.
+//!
+//! For every case in the corpus, CPython pickles the Python expression and
+//! the codec must decode it to the expected JSON; in the other direction,
+//! the codec encodes the expected JSON and CPython must load an object
+//! equal to (and of the same type as) the expression's value.
+//!
+//! Runs only with `cargo test --features cpython-interop` and needs a
+//! Python 3 interpreter (`python3`, or `$PYTHON`).
+
+#![cfg(feature = "cpython-interop")]
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use serde_json::{json, Value};
+use zodb_json_codec::{decode_pickle, encode_pickle, json_to_pickle_value, pickle_value_to_json};
+
+/// Protocols CPython pickles each case with. Protocol 2 is left out: it
+/// pickles `bytes` through `_codecs.encode`, which ZODB never emits.
+const PROTOCOLS: [u8; 3] = [3, 4, 5];
+
+/// Evaluates each case, pickles it with every protocol and checks whether
+/// the codec's encoding loads to an equal object.
+const HARNESS: &str = r#"
+import datetime, decimal, json, pickle, sys, uuid
+from datetime import timezone, timedelta
+
+cases = json.load(sys.stdin)
+protocols = cases.pop(0)
+out = []
+for case in cases:
+    expected = eval(case["expr"])
+    res = {"pickles": {str(p): pickle.dumps(expected, protocol=p).hex() for p in protocols}}
+    try:
+        loaded = pickle.loads(bytes.fromhex(case["encoded"]))
+        res["equal"] = type(loaded) is type(expected) and loaded == expected
+        res["got"] = repr(loaded)
+    except Exception as e:
+        res["equal"] = False
+        res["got"] = "%s: %s" % (type(e).__name__, e)
+    out.append(res)
+json.dump(out, sys.stdout)
+"#;
+
+/// (Python expression, expected JSON) pairs.
+fn corpus() -> Vec<(&'static str, Value)> {
+    vec![
+        // Native types
+        ("None", json!(null)),
+        ("True", json!(true)),
+        ("False", json!(false)),
+        ("0", json!(0)),
+        ("-1", json!(-1)),
+        ("255", json!(255)),
+        ("65536", json!(65536)),
+        ("-2**31 - 1", json!(-2147483649i64)),
+        ("2**62", json!(4611686018427387904i64)),
+        ("2**64", json!({"@bi": "18446744073709551616"})),
+        ("-2**100", json!({"@bi": "-1267650600228229401496703205376"})),
+        ("1.5", json!(1.5)),
+        ("-0.25", json!(-0.25)),
+        ("1e300", json!(1e300)),
+        ("''", json!("")),
+        ("'hello'", json!("hello")),
+        (r"'üñî € \U0001F600'", json!("üñî € 😀")),
+        ("'x' * 300", json!("x".repeat(300))),
+        // Structural markers
+        ("b''", json!({"@b": ""})),
+        ("b'\\x01\\x02\\x03\\xff'", json!({"@b": "AQID/w=="})),
+        ("()", json!({"@t": []})),
+        ("(1,)", json!({"@t": [1]})),
+        ("(1, 2, 3)", json!({"@t": [1, 2, 3]})),
+        ("(1, 2, 3, 4)", json!({"@t": [1, 2, 3, 4]})),
+        ("[]", json!([])),
+        ("[1, 'a', None, [2.5]]", json!([1, "a", null, [2.5]])),
+        ("{}", json!({})),
+        ("{'a': 1, 'b': {'c': [1, 2]}}", json!({"a": 1, "b": {"c": [1, 2]}})),
+        ("{1: 'a', 2: 'b'}", json!({"@d": [[1, "a"], [2, "b"]]})),
+        ("{(1, 2): None}", json!({"@d": [[{"@t": [1, 2]}, null]]})),
+        ("set()", json!({"@set": []})),
+        ("{1, 2, 3}", json!({"@set": [1, 2, 3]})),
+        ("frozenset([1, 2])", json!({"@fset": [1, 2]})),
+        // Known types
+        (
+            "datetime.datetime(2025, 6, 15, 12, 30, 45)",
+            json!({"@dt": "2025-06-15T12:30:45"}),
+        ),
+        (
+            "datetime.datetime(2025, 6, 15, 12, 30, 45, 123456)",
+            json!({"@dt": "2025-06-15T12:30:45.123456"}),
+        ),
+        (
+            "datetime.datetime(2025, 1, 1, tzinfo=timezone.utc)",
+            json!({"@dt": "2025-01-01T00:00:00+00:00"}),
+        ),
+        (
+            "datetime.datetime(2025, 1, 1, tzinfo=timezone(timedelta(hours=5, minutes=30)))",
+            json!({"@dt": "2025-01-01T00:00:00+05:30"}),
+        ),
+        ("datetime.date(2025, 6, 15)", json!({"@date": "2025-06-15"})),
+        ("datetime.time(12, 30, 45)", json!({"@time": "12:30:45"})),
+        (
+            "datetime.time(12, 30, 45, 123456)",
+            json!({"@time": "12:30:45.123456"}),
+        ),
+        (
+            "timedelta(days=7, seconds=3600, microseconds=500000)",
+            json!({"@td": [7, 3600, 500000]}),
+        ),
+        ("decimal.Decimal('3.14159')", json!({"@dec": "3.14159"})),
+        ("decimal.Decimal('-Infinity')", json!({"@dec": "-Infinity"})),
+        (
+            "uuid.UUID('12345678-1234-5678-1234-567812345678')",
+            json!({"@uuid": "12345678-1234-5678-1234-567812345678"}),
+        ),
+        // Mixed
+        (
+            "{'when': datetime.date(2024, 1, 2), 'tags': {'x'}, 'raw': b'\\x00'}",
+            json!({"when": {"@date": "2024-01-02"}, "tags": {"@set": ["x"]}, "raw": {"@b": "AA=="}}),
+        ),
+    ]
+}
+
+fn run_python(input: &Value) -> Vec<Value> {
+    let python = std::env::var("PYTHON").unwrap_or_else(|_| "python3".to_string());
+    let mut child = Command::new(&python)
+        .args(["-c", HARNESS])
+        .env("PYTHONHASHSEED", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .unwrap_or_else(|e| panic!("cannot run {python}: {e}"));
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.to_string().as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{python} harness failed");
+    serde_json::from_slice(&output.stdout).expect("harness output is JSON")
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Vec<u8> {
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn cpython_cross_validation() {
+    let corpus = corpus();
+    let mut input = vec![json!(PROTOCOLS)];
+    for (expr, expected) in &corpus {
+        let val = json_to_pickle_value(expected)
+            .unwrap_or_else(|e| panic!("{expr}: cannot convert expected JSON: {e}"));
+        let encoded = encode_pickle(&val).unwrap_or_else(|e| panic!("{expr}: encode failed: {e}"));
+        input.push(json!({"expr": expr, "encoded": to_hex(&encoded)}));
+    }
+    let results = run_python(&Value::Array(input));
+    assert_eq!(results.len(), corpus.len());
+
+    let mut failures = Vec::new();
+    for ((expr, expected), res) in corpus.iter().zip(&results) {
+        if res["equal"] != json!(true) {
+            failures.push(format!("encode {expr}: CPython loaded {}", res["got"]));
+        }
+        for proto in PROTOCOLS {
+            let data = from_hex(res["pickles"][proto.to_string()].as_str().unwrap());
+            match decode_pickle(&data).and_then(|v| pickle_value_to_json(&v)) {
+                Ok(got) if &got == expected => {}
+                Ok(got) => failures.push(format!("decode {expr} (protocol {proto}): got {got}")),
+                Err(e) => failures.push(format!("decode {expr} (protocol {proto}): {e}")),
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{} mismatches:\n{}", failures.len(), failures.join("\n"));
+}
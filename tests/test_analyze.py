@@ -0,0 +1,133 @@
+"""Test analyze_pickle statistics and find_class_references."""
+
+import collections
+import datetime
+import decimal
+import io
+import pickle
+import pickletools
+import pytest
+import zodb_json_codec
+
+
+class Ref:
+    def __init__(self, oid):
+        self.oid = oid
+
+
+class RefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return (obj.oid, None)
+        return None
+
+
+def make_record(state, protocol=3):
+    buf = io.BytesIO()
+    RefPickler(buf, protocol=protocol).dump(state)
+    return pickle.dumps(("myapp.models", "Folder"), protocol=protocol) + buf.getvalue()
+
+
+class ClassRefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return (obj.oid, Ref)
+        return None
+
+
+OID = b"\x00" * 7 + b"\x01"
+
+
+class TestAnalyzePickle:
+    @pytest.mark.parametrize("protocol", [0, 1, 2, 3, 4, 5])
+    def test_opcode_counts_match_pickletools(self, protocol):
+        data = pickle.dumps(
+            {"title": "Hello", "items": [1, 2.5, None, True], "t": (1, 2)},
+            protocol=protocol,
+        )
+        expected = collections.Counter(op.name for op, _, _ in pickletools.genops(data))
+        stats = zodb_json_codec.analyze_pickle(data)
+        assert stats["opcodes"] == dict(expected)
+        assert stats["pickles"] == 1
+        assert stats["size"] == len(data)
+
+    @pytest.mark.parametrize("protocol", [3, 4, 5])
+    def test_record(self, protocol):
+        state = {"title": "x" * 300, "data": b"\x00" * 1000, "refs": [Ref(OID), Ref(OID)]}
+        stats = zodb_json_codec.analyze_pickle(make_record(state, protocol))
+        assert stats["pickles"] == 2
+        assert stats["persistent_refs"] == 2
+        assert stats["max_string"] == 300
+        assert stats["max_bytes"] == 1000
+        assert stats["memo_size"] > 0
+
+    @pytest.mark.parametrize("protocol", [3, 4])
+    def test_classes(self, protocol):
+        data = pickle.dumps(
+            [datetime.date(2024, 1, 1), datetime.date(2024, 1, 2), decimal.Decimal("1")],
+            protocol=protocol,
+        )
+        stats = zodb_json_codec.analyze_pickle(data)
+        assert stats["classes"] == [("datetime", "date"), ("decimal", "Decimal")]
+
+    def test_max_stack_depth(self):
+        shallow = zodb_json_codec.analyze_pickle(pickle.dumps([1] * 100, protocol=2))
+        nested = zodb_json_codec.analyze_pickle(
+            pickle.dumps([[[[[[1]]]]]], protocol=2)
+        )
+        # The list plus its 100 items, appended in one batch
+        assert shallow["max_stack_depth"] == 101
+        # Six lists and the innermost item
+        assert nested["max_stack_depth"] == 7
+
+    def test_bytes_like_input(self):
+        data = pickle.dumps({"a": 1}, protocol=3)
+        expected = zodb_json_codec.analyze_pickle(data)
+        assert zodb_json_codec.analyze_pickle(memoryview(data)) == expected
+        assert zodb_json_codec.analyze_pickle(bytearray(data)) == expected
+
+    def test_truncated_raises(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.analyze_pickle(pickle.dumps([1, 2, 3], protocol=3)[:-1])
+
+
+class TestFindClassReferences:
+    @pytest.mark.parametrize("protocol", [3, 4, 5])
+    def test_record(self, protocol):
+        buf = io.BytesIO()
+        state = {"when": datetime.date(2024, 1, 1), "ref": Ref(OID), "n": 1}
+        ClassRefPickler(buf, protocol=protocol).dump(state)
+        record = pickle.dumps(("myapp.models", "Folder"), protocol=protocol)
+        classes = zodb_json_codec.find_class_references(record + buf.getvalue())
+        assert classes == [
+            ("myapp.models", "Folder"),
+            ("datetime", "date"),
+            (__name__, "Ref"),
+        ]
+
+    @pytest.mark.parametrize("protocol", [0, 1, 2])
+    def test_record_class_old_protocols(self, protocol):
+        record = pickle.dumps(("myapp.models", "Folder"), protocol=protocol)
+        record += pickle.dumps({"n": 1}, protocol=protocol)
+        classes = zodb_json_codec.find_class_references(record)
+        assert classes == [("myapp.models", "Folder")]
+
+    def test_record_class_once(self):
+        record = pickle.dumps((Ref, None), protocol=3) + pickle.dumps(
+            {"r": Ref}, protocol=3
+        )
+        assert zodb_json_codec.find_class_references(record) == [(__name__, "Ref")]
+
+    def test_plain_pickle(self):
+        data = pickle.dumps(["myapp", "Doc", decimal.Decimal("1")], protocol=3)
+        assert zodb_json_codec.find_class_references(data) == [("decimal", "Decimal")]
+
+    def test_matches_analyze_classes(self):
+        data = make_record({"d": datetime.date(2024, 1, 1)})
+        classes = zodb_json_codec.find_class_references(data)
+        assert classes[0] == ("myapp.models", "Folder")
+        assert classes[1:] == zodb_json_codec.analyze_pickle(data)["classes"]
+
+    def test_truncated_raises(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.find_class_references(make_record({})[:-1])
@@ -8,9 +8,13 @@ For each type, we:
 5. Unpickle and verify we get back the original value
 """
 
+import asyncio
+import base64
+import io
 import json
 import pickle
 import pytest
+import threading
 import zodb_json_codec
 
 
@@ -163,6 +167,25 @@ class TestDict:
         assert pickle.loads(restored_pickle) == val
 
 
+class TestJsonIndent:
+    VALUE = {"a": [1, (2, None)], "b": {}, "c": b"x"}
+
+    def test_default_is_two_spaces(self):
+        json_str = zodb_json_codec.pickle_to_json(pickle.dumps(self.VALUE, protocol=3))
+        assert json_str == json.dumps(json.loads(json_str), indent=2)
+
+    @pytest.mark.parametrize("indent", [None, 0, 4])
+    def test_indent(self, indent):
+        data = pickle.dumps(self.VALUE, protocol=3)
+        json_str = zodb_json_codec.pickle_to_json(data, indent=indent)
+        expected = json.loads(zodb_json_codec.pickle_to_json(data))
+        if indent is None:
+            assert json_str == json.dumps(expected, separators=(",", ":"))
+        else:
+            assert json_str == json.dumps(expected, indent=indent)
+        assert pickle.loads(zodb_json_codec.json_to_pickle(json_str)) == self.VALUE
+
+
 class TestSharedReferences:
     """Test that pickle memo sharing (same object in multiple places) roundtrips.
 
@@ -243,3 +266,751 @@ class TestPickleToDict:
         data = pickle.dumps(val, protocol=3)
         result = zodb_json_codec.pickle_to_dict(data)
         assert result == val
+
+
+class TestRawPickle:
+    """Validation of `@pkl` escape-hatch payloads on encode."""
+
+    def _pkl(self, raw):
+        return json.dumps({"@pkl": base64.b64encode(raw).decode()})
+
+    def test_valid_payload(self):
+        raw = pickle.dumps(None, protocol=3)
+        assert zodb_json_codec.json_to_pickle(self._pkl(raw))
+
+    def test_valid_payload_dict_api(self):
+        raw = pickle.dumps(None, protocol=3)
+        obj = {"x": {"@pkl": base64.b64encode(raw).decode()}}
+        assert zodb_json_codec.dict_to_pickle(obj)
+
+    def test_trailing_bytes_rejected(self):
+        raw = pickle.dumps(None, protocol=3) + b"N."
+        with pytest.raises(ValueError, match="trailing"):
+            zodb_json_codec.json_to_pickle(self._pkl(raw))
+
+    def test_truncated_payload_rejected(self):
+        raw = pickle.dumps("hello", protocol=3)[:-3]
+        with pytest.raises(ValueError, match="@pkl"):
+            zodb_json_codec.json_to_pickle(self._pkl(raw))
+
+    def test_size_cap(self):
+        raw = pickle.dumps(b"x" * 1000, protocol=3)
+        policy = zodb_json_codec.RawPicklePolicy(max_size=100)
+        with pytest.raises(ValueError, match="exceeds limit"):
+            zodb_json_codec.json_to_pickle(self._pkl(raw), raw_pickle_policy=policy)
+
+    def test_digest_allowlist(self):
+        known = pickle.dumps(None, protocol=3)
+        other = pickle.dumps(True, protocol=3)
+        policy = zodb_json_codec.RawPicklePolicy(
+            allowed_sha256=[zodb_json_codec.raw_pickle_sha256(known)]
+        )
+        assert zodb_json_codec.json_to_pickle(self._pkl(known), raw_pickle_policy=policy)
+        with pytest.raises(ValueError, match="allowlist"):
+            zodb_json_codec.json_to_pickle(self._pkl(other), raw_pickle_policy=policy)
+
+    def test_record_encoders(self):
+        raw = pickle.dumps(b"x" * 1000, protocol=3)
+        policy = zodb_json_codec.RawPicklePolicy(max_size=100)
+        state = {"x": {"@pkl": base64.b64encode(raw).decode()}}
+        record = {"@cls": ["myapp", "Doc"], "@s": state}
+        assert zodb_json_codec.encode_zodb_record(record)
+        with pytest.raises(ValueError, match="exceeds limit"):
+            zodb_json_codec.encode_zodb_record(record, raw_pickle_policy=policy)
+        with pytest.raises(ValueError, match="exceeds limit"):
+            zodb_json_codec.encode_zodb_records_batch([record], raw_pickle_policy=policy)
+        with pytest.raises(ValueError, match="exceeds limit"):
+            zodb_json_codec.dict_to_pickle(state, raw_pickle_policy=policy)
+
+    def test_per_call(self):
+        raw = pickle.dumps(b"x" * 1000, protocol=3)
+        policy = zodb_json_codec.RawPicklePolicy(max_size=100)
+        with pytest.raises(ValueError, match="exceeds limit"):
+            zodb_json_codec.json_to_pickle(self._pkl(raw), raw_pickle_policy=policy)
+        assert zodb_json_codec.json_to_pickle(self._pkl(raw))
+
+    def test_invalid_digest_rejected(self):
+        with pytest.raises(ValueError, match="sha256"):
+            zodb_json_codec.RawPicklePolicy(allowed_sha256=["abc"])
+
+
+class TestChunkedFrames:
+    """Protocol 4 output with content-defined FRAME boundaries."""
+
+    @staticmethod
+    def frames(data):
+        assert data[:2] == b"\x80\x04"
+        pos, out = 2, []
+        while pos < len(data):
+            assert data[pos] == 0x95
+            n = int.from_bytes(data[pos + 1 : pos + 9], "little")
+            out.append(data[pos + 9 : pos + 9 + n])
+            pos += 9 + n
+        return out
+
+    @staticmethod
+    def sample(edited=None):
+        return {
+            "items": [
+                {"id": i, "text": "edited" if i == edited else f"item {i} text"}
+                for i in range(3000)
+            ]
+        }
+
+    def test_dict_to_pickle_chunked_roundtrip(self):
+        data = zodb_json_codec.dict_to_pickle(self.sample(), chunk_size=1024)
+        assert len(self.frames(data)) > 10
+        assert pickle.loads(data) == self.sample()
+
+    def test_json_to_pickle_chunked_roundtrip(self):
+        json_str = zodb_json_codec.pickle_to_json(pickle.dumps(self.sample()))
+        data = zodb_json_codec.json_to_pickle(json_str, chunk_size=1024)
+        assert pickle.loads(data) == self.sample()
+
+    def test_small_change_small_diff(self):
+        before = self.frames(
+            zodb_json_codec.dict_to_pickle(self.sample(), chunk_size=1024)
+        )
+        after = self.frames(
+            zodb_json_codec.dict_to_pickle(self.sample(edited=1500), chunk_size=1024)
+        )
+        changed = [f for f in after if f not in before]
+        assert len(changed) <= 2
+
+    def test_default_is_unframed(self):
+        data = zodb_json_codec.dict_to_pickle({"a": 1})
+        assert data[:2] in (b"\x80\x02", b"\x80\x03")
+        assert b"\x95" not in data
+
+
+class TestLineLimits:
+    """Bounded text-mode (protocol 0) opcode arguments."""
+
+    def test_overlong_global_rejected(self):
+        data = b"c" + b"m" * 100_000 + b"\nName\n."
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.pickle_to_dict(data)
+
+    def test_overlong_class_pickle_in_record(self):
+        record = b"c" + b"m" * 100_000 + b"\nName\n." + pickle.dumps({}, protocol=3)
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.decode_zodb_record(record)
+
+    def test_protocol_0_within_limits(self):
+        data = pickle.dumps({"a": [1, 2.5, "x" * 1000]}, protocol=0)
+        assert zodb_json_codec.pickle_to_dict(data) == {"a": [1, 2.5, "x" * 1000]}
+
+    def test_configurable(self):
+        data = pickle.dumps("x" * 100, protocol=0)
+        limits = zodb_json_codec.LineLimits(max_string=50)
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.pickle_to_dict(data, line_limits=limits)
+        assert zodb_json_codec.pickle_to_dict(data) == "x" * 100
+
+    def test_record_decoders(self):
+        record = b"cmodule\nName\n." + pickle.dumps("x" * 100, protocol=0)
+        limits = zodb_json_codec.LineLimits(max_name=1)
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.decode_zodb_record(record, line_limits=limits)
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.decode_zodb_record_for_pg_json(record, line_limits=limits)
+        assert zodb_json_codec.decode_zodb_record(record)["@s"] == "x" * 100
+
+
+class TestDecodeLimits:
+    """Configurable decoder resource limits."""
+
+    @staticmethod
+    def doubling_pickle(levels):
+        # Nested lists of two memo GETs each: 2**levels copies of "a"
+        data = b"\x80\x02(X\x01\x00\x00\x00alq\x000"
+        for i in range(1, levels + 1):
+            data += bytes([0x28, 0x68, i - 1, 0x68, i - 1, 0x6C, 0x71, i, 0x30])
+        return data + bytes([0x68, levels]) + b"."
+
+    def test_allocation(self):
+        limits = zodb_json_codec.DecodeLimits(max_allocation=1 << 20)
+        value = zodb_json_codec.pickle_to_dict(self.doubling_pickle(3), limits=limits)
+        assert len(value) == 2
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.pickle_to_dict(self.doubling_pickle(40), limits=limits)
+
+    def test_containers(self):
+        data = pickle.dumps([[i] for i in range(100)], protocol=3)
+        limits = zodb_json_codec.DecodeLimits(max_containers=50)
+        with pytest.raises(ValueError, match="more than 50 containers"):
+            zodb_json_codec.pickle_to_dict(data, limits=limits)
+        assert len(zodb_json_codec.pickle_to_dict(data)) == 100
+
+    def test_stack_items(self):
+        data = pickle.dumps(tuple(range(100)), protocol=3)
+        limits = zodb_json_codec.DecodeLimits(max_stack_items=10)
+        with pytest.raises(ValueError, match="on the pickle stack"):
+            zodb_json_codec.pickle_to_dict(data, limits=limits)
+
+    def test_memo_entries(self):
+        data = pickle.dumps([[i] for i in range(10)], protocol=3)
+        limits = zodb_json_codec.DecodeLimits(max_memo_entries=5)
+        with pytest.raises(ValueError, match="memo index"):
+            zodb_json_codec.pickle_to_dict(data, limits=limits)
+
+    def test_string_length(self):
+        record = pickle.dumps(("myapp", "Doc"), protocol=3) + pickle.dumps(
+            {"body": "x" * 1000}, protocol=3
+        )
+        limits = zodb_json_codec.DecodeLimits(max_string_length=100)
+        with pytest.raises(ValueError, match="BINUNICODE data too large"):
+            zodb_json_codec.decode_zodb_record(record, limits=limits)
+        with pytest.raises(ValueError, match="BINUNICODE data too large"):
+            zodb_json_codec.decode_zodb_record_for_pg_json(record, limits=limits)
+        unlimited = zodb_json_codec.DecodeLimits(max_string_length=None)
+        state = zodb_json_codec.decode_zodb_record(record, limits=unlimited)["@s"]
+        assert state["body"] == "x" * 1000
+
+
+class TestEncodeLimits:
+    """Configurable encoder resource limits."""
+
+    @pytest.mark.parametrize(
+        "value",
+        [
+            {"l": list(range(100))},
+            {"@t": list(range(100))},
+            {str(i): i for i in range(100)},
+        ],
+    )
+    def test_collection_length(self, value):
+        limits = zodb_json_codec.EncodeLimits(max_collection_length=50)
+        with pytest.raises(ValueError, match="collection of 100 items"):
+            zodb_json_codec.dict_to_pickle(value, limits=limits)
+        with pytest.raises(ValueError, match="collection of 100 items"):
+            zodb_json_codec.json_to_pickle(json.dumps(value), limits=limits)
+        assert zodb_json_codec.pickle_to_dict(zodb_json_codec.dict_to_pickle(value))
+
+    def test_depth(self):
+        value = {"a": [[[["deep"]]]]}
+        limits = zodb_json_codec.EncodeLimits(max_depth=3)
+        with pytest.raises(ValueError, match="nesting depth exceeds 3"):
+            zodb_json_codec.dict_to_pickle(value, limits=limits)
+        with pytest.raises(ValueError, match="nesting depth exceeds 3"):
+            zodb_json_codec.json_to_pickle(json.dumps(value), limits=limits)
+        limits = zodb_json_codec.EncodeLimits(max_depth=5)
+        data = zodb_json_codec.dict_to_pickle(value, limits=limits)
+        assert zodb_json_codec.pickle_to_dict(data)
+
+    def test_output_bytes(self):
+        record = {"@cls": ["myapp", "Doc"], "@s": {"a": "x" * 1000, "b": 1}}
+        limits = zodb_json_codec.EncodeLimits(max_output_bytes=500)
+        with pytest.raises(ValueError, match="exceeds 500 bytes"):
+            zodb_json_codec.encode_zodb_record(record, limits=limits)
+        with pytest.raises(ValueError, match="exceeds 500 bytes"):
+            zodb_json_codec.encode_zodb_records_batch([record], limits=limits)
+        limits = zodb_json_codec.EncodeLimits(max_output_bytes=2000)
+        assert zodb_json_codec.encode_zodb_record(record, limits=limits)
+
+    def test_protocol0(self):
+        limits = zodb_json_codec.EncodeLimits(max_collection_length=5)
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.dict_to_pickle(
+                {"@t": list(range(10))}, protocol=0, limits=limits
+            )
+
+    def test_per_call(self):
+        value = {"l": list(range(100))}
+        limits = zodb_json_codec.EncodeLimits(max_collection_length=50)
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.dict_to_pickle(value, limits=limits)
+        assert zodb_json_codec.dict_to_pickle(value)
+
+
+class TestBigIntPolicy:
+    """Integers outside i64 as @bi strings or plain JSON numbers."""
+
+    def test_default_uses_marker(self):
+        data = pickle.dumps(2**64, protocol=3)
+        assert json.loads(zodb_json_codec.pickle_to_json(data)) == {"@bi": str(2**64)}
+        assert zodb_json_codec.pickle_to_dict(data) == {"@bi": str(2**64)}
+
+    @pytest.mark.parametrize("val", [2**64, -(2**100), 2**126])
+    def test_numbers_within_limit(self, val):
+        data = pickle.dumps(val, protocol=3)
+        json_str = zodb_json_codec.pickle_to_json(data, bigint_max_bits=127)
+        assert json.loads(json_str) == val
+        assert pickle.loads(zodb_json_codec.json_to_pickle(json_str)) == val
+        assert zodb_json_codec.pickle_to_dict(data, bigint_max_bits=127) == val
+        assert pickle.loads(zodb_json_codec.dict_to_pickle({"v": val})) == {"v": val}
+
+    def test_marker_beyond_limit(self):
+        data = pickle.dumps([2**79, 2**80], protocol=3)
+        assert json.loads(zodb_json_codec.pickle_to_json(data, bigint_max_bits=80)) == [
+            2**79,
+            {"@bi": str(2**80)},
+        ]
+
+    def test_pg_json_path(self):
+        record = pickle.dumps(("m", "C"), protocol=3) + pickle.dumps(
+            {"n": -(2**90)}, protocol=3
+        )
+        _, _, state_json, _ = zodb_json_codec.decode_zodb_record_for_pg_json(
+            record, bigint_max_bits=100
+        )
+        assert json.loads(state_json) == {"n": -(2**90)}
+
+    def test_batch_async(self):
+        record = pickle.dumps(("m", "C"), protocol=3) + pickle.dumps({"n": 2**70}, protocol=3)
+
+        async def main():
+            return await zodb_json_codec.decode_batch_async([record], bigint_max_bits=100)
+
+        [(_, _, state_json, _)] = asyncio.run(main())
+        assert json.loads(state_json) == {"n": 2**70}
+
+    def test_per_call(self):
+        data = pickle.dumps(2**64, protocol=3)
+        assert zodb_json_codec.pickle_to_dict(data, bigint_max_bits=127) == 2**64
+        assert zodb_json_codec.pickle_to_dict(data) == {"@bi": str(2**64)}
+
+    def test_big_numbers_parse_exactly_without_policy(self):
+        val = 2**200 + 1
+        restored = zodb_json_codec.json_to_pickle(json.dumps({"n": val}))
+        assert pickle.loads(restored) == {"n": val}
+        assert pickle.loads(zodb_json_codec.dict_to_pickle({"n": val})) == {"n": val}
+
+    @pytest.mark.parametrize("bits", [0, 63, 128])
+    def test_invalid_max_bits(self, bits):
+        with pytest.raises(ValueError, match="max_bits"):
+            zodb_json_codec.pickle_to_dict(b"N.", bigint_max_bits=bits)
+
+
+class TestLenientDecoding:
+    """Rescuing dicts built with an odd number of items."""
+
+    # EMPTY_DICT MARK "a" 1 "b" SETITEMS
+    ODD_SETITEMS = b"\x80\x02}(X\x01\x00\x00\x00aK\x01X\x01\x00\x00\x00bu."
+
+    def test_strict_by_default(self):
+        with pytest.raises(ValueError, match="odd number of items"):
+            zodb_json_codec.pickle_to_dict(self.ODD_SETITEMS)
+
+    def test_dangling_item_kept(self):
+        assert zodb_json_codec.pickle_to_dict(self.ODD_SETITEMS, lenient=True) == {
+            "a": 1,
+            "@dangling": "b",
+        }
+        assert json.loads(zodb_json_codec.pickle_to_json(self.ODD_SETITEMS, lenient=True)) == {
+            "a": 1,
+            "@dangling": "b",
+        }
+
+    def test_per_call(self):
+        zodb_json_codec.pickle_to_dict(self.ODD_SETITEMS, lenient=True)
+        with pytest.raises(ValueError, match="odd number of items"):
+            zodb_json_codec.pickle_to_dict(self.ODD_SETITEMS)
+
+    def test_record_state(self):
+        record = pickle.dumps(("myapp", "Doc"), protocol=2) + self.ODD_SETITEMS
+        result = zodb_json_codec.decode_zodb_record(record, lenient=True)
+        assert result["@s"] == {"a": 1, "@dangling": "b"}
+        state = zodb_json_codec.decode_zodb_state(record, lenient=True)
+        assert state == {"a": 1, "@dangling": "b"}
+
+    def test_valid_pickles_unchanged(self):
+        data = pickle.dumps({"a": 1, "b": 2}, protocol=3)
+        assert zodb_json_codec.pickle_to_dict(data, lenient=True) == {"a": 1, "b": 2}
+
+    def test_dangling_rejected_on_encode(self):
+        repaired = zodb_json_codec.pickle_to_dict(self.ODD_SETITEMS, lenient=True)
+        with pytest.raises(ValueError, match="@dangling"):
+            zodb_json_codec.dict_to_pickle(repaired)
+        with pytest.raises(ValueError, match="@dangling"):
+            zodb_json_codec.json_to_pickle(json.dumps(repaired))
+        with pytest.raises(ValueError, match="@dangling"):
+            zodb_json_codec.dict_to_pickle({"@dangling": "b"})
+        with pytest.raises(ValueError, match="@dangling"):
+            zodb_json_codec.encode_zodb_record({"@cls": ["myapp", "Doc"], "@s": repaired})
+
+    def test_dangling_dropped_on_encode(self):
+        repaired = zodb_json_codec.pickle_to_dict(self.ODD_SETITEMS, lenient=True)
+        data = zodb_json_codec.dict_to_pickle(repaired, drop_dangling=True)
+        assert pickle.loads(data) == {"a": 1}
+        data = zodb_json_codec.json_to_pickle(json.dumps(repaired), drop_dangling=True)
+        assert pickle.loads(data) == {"a": 1}
+        data = zodb_json_codec.dict_to_pickle({"@dangling": "b"}, drop_dangling=True)
+        assert pickle.loads(data) == {}
+        record = zodb_json_codec.encode_zodb_record(
+            {"@cls": ["myapp", "Doc"], "@s": repaired}, drop_dangling=True
+        )
+        assert zodb_json_codec.decode_zodb_record(record)["@s"] == {"a": 1}
+
+    def test_undecodable_state_kept_as_pkl(self):
+        # Unknown opcode 0xff inside the state pickle
+        state = b"\x80\x03}X\x01\x00\x00\x00a\xff."
+        record = pickle.dumps(("myapp", "Doc"), protocol=3) + state
+        with pytest.raises(ValueError, match="unknown pickle opcode"):
+            zodb_json_codec.decode_zodb_record(record)
+        expected = {"@pkl": base64.b64encode(state).decode()}
+        result = zodb_json_codec.decode_zodb_record(record, lenient=True)
+        assert result == {"@cls": ["myapp", "Doc"], "@s": expected}
+        _, _, state, _ = zodb_json_codec.decode_zodb_record_for_pg(record, lenient=True)
+        assert state == expected
+        _, _, state_json, _ = zodb_json_codec.decode_zodb_record_for_pg_json(
+            record, lenient=True
+        )
+        assert json.loads(state_json) == expected
+
+    def test_undecodable_state_encoded_back(self):
+        # GET of a missing memo entry: undecodable, but a complete pickle
+        state = b"\x80\x03h\x05."
+        record = pickle.dumps(("myapp", "Doc"), protocol=3) + state
+        result = zodb_json_codec.decode_zodb_record(record, lenient=True)
+        assert result["@s"] == {"@pkl": base64.b64encode(state).decode()}
+        for encoded in [
+            zodb_json_codec.encode_zodb_record(result),
+            zodb_json_codec.encode_zodb_state("myapp", "Doc", result["@s"]),
+            zodb_json_codec.encode_zodb_records_batch([result])[0],
+        ]:
+            f = io.BytesIO(encoded)
+            assert pickle.load(f) == (("myapp", "Doc"), None)
+            # The state pickle is written back as it was
+            assert f.read() == state
+
+    def test_truncated_state_not_encoded(self):
+        # Unknown opcode: no STOP can be found, the @pkl is not a pickle
+        state = b"\x80\x03}X\x01\x00\x00\x00a\xff."
+        record = pickle.dumps(("myapp", "Doc"), protocol=3) + state
+        result = zodb_json_codec.decode_zodb_record(record, lenient=True)
+        with pytest.raises(ValueError, match="@pkl payload rejected"):
+            zodb_json_codec.encode_zodb_record(result)
+
+    def test_undecodable_pickle_cut_at_stop(self):
+        # GET of a missing memo entry; a valid pickle follows
+        data = b"\x80\x03h\x05."
+        assert zodb_json_codec.pickle_to_dict(data + b"trailing", lenient=True) == {
+            "@pkl": base64.b64encode(data).decode()
+        }
+
+    def test_policy_errors_not_hidden(self):
+        policy = zodb_json_codec.DecodePolicy(denied=[("os", "*")])
+        data = b"\x80\x03cos\nsystem\n."
+        with pytest.raises(ValueError, match="os.system"):
+            zodb_json_codec.pickle_to_dict(data, lenient=True, policy=policy)
+
+
+class TestBytesKeyPromotion:
+    """Python 2 str keys promoted to JSON object keys under "@bk"."""
+
+    LEGACY = {b"title": b"Front", b"id": b"index_html", b"count": 3}
+
+    def test_off_by_default(self):
+        data = pickle.dumps(self.LEGACY, protocol=2)
+        assert "@d" in zodb_json_codec.pickle_to_dict(data)
+
+    def test_promoted(self):
+        data = pickle.dumps(self.LEGACY, protocol=2)
+        expected = {
+            "@bk": True,
+            "title": {"@b": "RnJvbnQ="},
+            "id": {"@b": "aW5kZXhfaHRtbA=="},
+            "count": 3,
+        }
+        assert zodb_json_codec.pickle_to_dict(data, promote_bytes_keys=True) == expected
+        result = zodb_json_codec.pickle_to_json(data, promote_bytes_keys=True)
+        assert json.loads(result) == expected
+
+    def test_per_call(self):
+        data = pickle.dumps(self.LEGACY, protocol=2)
+        assert "@bk" in zodb_json_codec.pickle_to_dict(data, promote_bytes_keys=True)
+        assert "@d" in zodb_json_codec.pickle_to_dict(data)
+        assert "@d" in json.loads(zodb_json_codec.pickle_to_json(data))
+
+    def test_roundtrip_restores_bytes(self):
+        data = pickle.dumps(self.LEGACY, protocol=2)
+        d = zodb_json_codec.pickle_to_dict(data, promote_bytes_keys=True)
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(d)) == self.LEGACY
+        j = zodb_json_codec.pickle_to_json(data, promote_bytes_keys=True)
+        assert pickle.loads(zodb_json_codec.json_to_pickle(j)) == self.LEGACY
+
+    def test_record_roundtrip(self):
+        state = {b"k%d" % i: i for i in range(6)}
+        state[b"nested"] = {b"a": 1}
+        record = pickle.dumps(("myapp", "Doc"), protocol=2) + pickle.dumps(state, protocol=2)
+        result = zodb_json_codec.decode_zodb_record(record, promote_bytes_keys=True)
+        assert result["@s"]["@bk"] is True
+        assert result["@s"]["nested"] == {"@bk": True, "a": 1}
+        stream = io.BytesIO(zodb_json_codec.encode_zodb_record(result))
+        unpickler = pickle.Unpickler(stream)
+        unpickler.load()  # class pickle
+        assert unpickler.load() == state
+        state_only = zodb_json_codec.decode_zodb_state(record, promote_bytes_keys=True)
+        assert state_only == result["@s"]
+        _, _, pg_state, _ = zodb_json_codec.decode_zodb_record_for_pg(
+            record, promote_bytes_keys=True
+        )
+        assert pg_state["nested"] == {"@bk": True, "a": 1}
+        _, _, state_json, _ = zodb_json_codec.decode_zodb_record_for_pg_json(
+            record, promote_bytes_keys=True
+        )
+        assert json.loads(state_json)["nested"] == {"@bk": True, "a": 1}
+
+    def test_not_promoted(self):
+        for value in (
+            {b"a": 1, "b": 2},  # mixed key types
+            {b"caf\xe9": 1},  # not ASCII
+            {b"@cls": 1},  # could be read as a marker
+        ):
+            data = pickle.dumps(value, protocol=2)
+            result = zodb_json_codec.pickle_to_dict(data, promote_bytes_keys=True)
+            assert "@d" in result, value
+
+
+class Point:
+    """Reduces to a classmethod constructor plus state."""
+
+    def __init__(self, x):
+        self.x = x
+
+    @classmethod
+    def restore(cls, x):
+        return cls(x)
+
+    def __reduce__(self):
+        return (Point.restore, (self.x,), {"label": "p"})
+
+
+class TestAnonymousInstance:
+    """@inst: BUILD on objects that are not created from a class."""
+
+    def test_json_roundtrip(self):
+        data = pickle.dumps({"point": Point(3)}, protocol=3)
+        result = json.loads(zodb_json_codec.pickle_to_json(data))
+        inst = result["point"]["@inst"]
+        assert inst["@args"] == {"@t": [3]}
+        assert inst["@state"] == {"label": "p"}
+        assert inst["@callable"]["@reduce"]["callable"] == {"@cls": ["builtins", "getattr"]}
+        restored = pickle.loads(zodb_json_codec.json_to_pickle(json.dumps(result)))
+        assert type(restored["point"]) is Point
+        assert vars(restored["point"]) == {"x": 3, "label": "p"}
+
+    def test_dict_roundtrip(self):
+        data = pickle.dumps([Point(4)], protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert "@inst" in result[0]
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle({"v": result}))
+        assert vars(restored["v"][0]) == {"x": 4, "label": "p"}
+
+    def test_protocol0_output(self):
+        json_str = zodb_json_codec.pickle_to_json(pickle.dumps(Point(5), protocol=3))
+        restored = pickle.loads(zodb_json_codec.json_to_pickle(json_str, protocol=0))
+        assert vars(restored) == {"x": 5, "label": "p"}
+
+
+class Attrs(dict):
+    """A dict subclass with attributes: state plus SETITEMS."""
+
+
+class Items(list):
+    """A list subclass with attributes: state plus APPENDS."""
+
+
+class Pairs(dict):
+    """Pickled by REDUCE with dict items, without state."""
+
+    def __reduce__(self):
+        return (Pairs, (), None, None, iter(self.items()))
+
+
+def make_containers():
+    attrs = Attrs(a=1, b=[2])
+    attrs.title = "t"
+    items = Items([1, "two"])
+    items.title = "t"
+    return {"attrs": attrs, "items": items, "pairs": Pairs(k=1)}
+
+
+class TestContainerSubclasses:
+    """@items / @appends of dict and list subclasses survive every path."""
+
+    def check(self, restored):
+        assert type(restored["attrs"]) is Attrs
+        assert restored["attrs"] == {"a": 1, "b": [2]}
+        assert restored["attrs"].title == "t"
+        assert type(restored["items"]) is Items
+        assert restored["items"] == [1, "two"] and restored["items"].title == "t"
+        assert type(restored["pairs"]) is Pairs and restored["pairs"] == {"k": 1}
+
+    def test_dict_form(self):
+        data = pickle.dumps(make_containers(), protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result["attrs"]["@items"] == [["a", 1], ["b", [2]]]
+        assert result["items"]["@appends"] == [1, "two"]
+        assert result["pairs"]["@reduce"]["items"] == [["k", 1]]
+
+    def test_dict_roundtrip(self):
+        data = pickle.dumps(make_containers(), protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data)
+        self.check(pickle.loads(zodb_json_codec.dict_to_pickle(result)))
+
+    def test_json_roundtrip(self):
+        data = pickle.dumps(make_containers(), protocol=3)
+        json_str = zodb_json_codec.pickle_to_json(data)
+        self.check(pickle.loads(zodb_json_codec.json_to_pickle(json_str)))
+
+    def test_dict_matches_json(self):
+        data = pickle.dumps(make_containers(), protocol=3)
+        json_str = zodb_json_codec.pickle_to_json(data)
+        assert zodb_json_codec.pickle_to_dict(data) == json.loads(json_str)
+
+    def test_anonymous_instance_items(self):
+        cls = {"@cls": [__name__, "Attrs"]}
+        value = {
+            "@inst": {"@callable": cls, "@args": {"@t": []}, "@state": {"x": 1}},
+            "@items": [["a", 1]],
+        }
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle(value))
+        assert type(restored) is Attrs and restored == {"a": 1} and restored.x == 1
+
+
+class Tag(str):
+    """Pickled with NEWOBJ and no state, so __init__ must not run on load."""
+
+    inits = 0
+
+    def __init__(self, value):
+        Tag.inits += 1
+
+
+class TestNewObj:
+    """@newobj: NEWOBJ without BUILD stays NEWOBJ when encoded again."""
+
+    def test_json_form(self):
+        data = pickle.dumps({"t": Tag("x")}, protocol=3)
+        result = json.loads(zodb_json_codec.pickle_to_json(data))
+        assert result["t"] == {
+            "@newobj": {"cls": {"@cls": [__name__, "Tag"]}, "args": {"@t": ["x"]}}
+        }
+
+    @pytest.mark.parametrize("protocol", [0, 2, 3, 4])
+    def test_json_roundtrip(self, protocol):
+        data = pickle.dumps({"t": Tag("x")}, protocol=3)
+        json_str = zodb_json_codec.pickle_to_json(data)
+        encoded = zodb_json_codec.json_to_pickle(json_str, protocol=protocol)
+        Tag.inits = 0
+        assert pickle.loads(encoded) == {"t": "x"}
+        assert Tag.inits == 0
+        decoded = zodb_json_codec.pickle_to_json(encoded)
+        assert json.loads(decoded) == json.loads(json_str)
+
+    def test_dict_roundtrip(self):
+        data = pickle.dumps({"t": Tag("x")}, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert "@newobj" in result["t"]
+        encoded = zodb_json_codec.dict_to_pickle(result)
+        assert pickle.NEWOBJ in encoded
+        Tag.inits = 0
+        restored = pickle.loads(encoded)
+        assert type(restored["t"]) is Tag and Tag.inits == 0
+
+    def test_reduce_still_calls(self):
+        cls = {"@cls": [__name__, "Tag"]}
+        state = {"t": {"@reduce": {"callable": cls, "args": {"@t": ["x"]}}}}
+        Tag.inits = 0
+        pickle.loads(zodb_json_codec.dict_to_pickle(state))
+        assert Tag.inits == 1
+
+
+class Vector:
+    """Created with keyword-only arguments via __getnewargs_ex__."""
+
+    def __new__(cls, x, *, scale=1):
+        self = super().__new__(cls)
+        self.x = x
+        self.scale = scale
+        return self
+
+    def __getnewargs_ex__(self):
+        return (self.x,), {"scale": self.scale}
+
+    def __eq__(self, other):
+        return type(other) is Vector and vars(other) == vars(self)
+
+
+class TestNewObjEx:
+    """@newobj_ex: NEWOBJ_EX with keyword arguments."""
+
+    def test_json_form(self):
+        data = pickle.dumps(Vector(3, scale=2), protocol=4)
+        result = json.loads(zodb_json_codec.pickle_to_json(data))
+        assert result["@inst"]["@obj"] == {
+            "@newobj_ex": {
+                "cls": {"@cls": [__name__, "Vector"]},
+                "args": {"@t": [3]},
+                "kwargs": {"scale": 2},
+            }
+        }
+
+    @pytest.mark.parametrize("protocol", [0, 2, 3, 4])
+    def test_roundtrip(self, protocol):
+        data = pickle.dumps([Vector(3, scale=2)], protocol=4)
+        json_str = zodb_json_codec.pickle_to_json(data)
+        encoded = zodb_json_codec.json_to_pickle(json_str, protocol=protocol)
+        assert pickle.loads(encoded) == [Vector(3, scale=2)]
+        decoded = zodb_json_codec.pickle_to_json(encoded)
+        assert json.loads(decoded) == json.loads(json_str)
+
+    def test_protocol4_writes_newobj_ex(self):
+        data = pickle.dumps(Vector(1), protocol=4)
+        result = zodb_json_codec.pickle_to_dict(data)
+        encoded = zodb_json_codec.dict_to_pickle(result, protocol=4)
+        assert pickle.NEWOBJ_EX in encoded
+        assert pickle.loads(encoded) == Vector(1)
+
+
+def nested(depth, wrap):
+    value = 1
+    for _ in range(depth):
+        value = wrap(value)
+    return value
+
+
+def run_with_large_stack(func):
+    """Run `func` in a thread with a 64 MB stack and return its result.
+
+    Debug builds use much larger stack frames than release builds; the
+    extra room lets the depth limit trip before the stack runs out.
+    """
+    result = {}
+
+    def target():
+        try:
+            result["value"] = func()
+        except Exception as e:  # re-raised in the calling thread
+            result["error"] = e
+
+    old_size = threading.stack_size(64 * 1024 * 1024)
+    try:
+        thread = threading.Thread(target=target)
+        thread.start()
+        thread.join()
+    finally:
+        threading.stack_size(old_size)
+    if "error" in result:
+        raise result["error"]
+    return result["value"]
+
+
+class TestNestingLimit:
+    @pytest.mark.parametrize("wrap", [lambda v: [v], lambda v: {"k": v}])
+    def test_deep_input_raises(self, wrap):
+        value = nested(100_000, wrap)
+        record = {"@cls": ["myapp", "Doc"], "@s": {"v": value}}
+        with pytest.raises(ValueError, match="nesting depth"):
+            run_with_large_stack(lambda: zodb_json_codec.dict_to_pickle({"v": value}))
+        with pytest.raises(ValueError, match="nesting depth"):
+            run_with_large_stack(lambda: zodb_json_codec.encode_zodb_record(record))
+        with pytest.raises(ValueError, match="nesting depth"):
+            run_with_large_stack(lambda: zodb_json_codec.encode_zodb_records_batch([record]))
+
+    @pytest.mark.parametrize("wrap", [lambda v: [v], lambda v: {"k": v}])
+    def test_moderate_nesting_roundtrips(self, wrap):
+        value = nested(200, wrap)
+        assert pickle.loads(zodb_json_codec.dict_to_pickle({"v": value})) == {"v": value}
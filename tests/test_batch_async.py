@@ -0,0 +1,63 @@
+"""Test decode_batch_async — non-blocking batch decoding for asyncio."""
+
+import asyncio
+import json
+import pickle
+import pytest
+import zodb_json_codec
+
+
+def make_record(state):
+    return pickle.dumps(("myapp", "Doc"), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+class TestDecodeBatchAsync:
+    def test_matches_sync_decoder(self):
+        records = [make_record({"n": i, "title": "x" * i}) for i in range(200)]
+
+        async def main():
+            return await zodb_json_codec.decode_batch_async(records)
+
+        results = asyncio.run(main())
+        assert results == [
+            zodb_json_codec.decode_zodb_record_for_pg_json(r) for r in records
+        ]
+        assert json.loads(results[5][2]) == {"n": 5, "title": "xxxxx"}
+
+    def test_empty_batch(self):
+        async def main():
+            return await zodb_json_codec.decode_batch_async([])
+
+        assert asyncio.run(main()) == []
+
+    def test_error_names_record(self):
+        records = [make_record({}), b"\x80\x03garbage"]
+
+        async def main():
+            await zodb_json_codec.decode_batch_async(records)
+
+        with pytest.raises(ValueError, match="record 1"):
+            asyncio.run(main())
+
+    def test_concurrent_batches(self):
+        async def main():
+            batches = [[make_record([i, j]) for j in range(20)] for i in range(10)]
+            return await asyncio.gather(
+                *(zodb_json_codec.decode_batch_async(b) for b in batches)
+            )
+
+        results = asyncio.run(main())
+        assert json.loads(results[3][7][2]) == [3, 7]
+
+    def test_cancelled_future_is_ignored(self):
+        async def main():
+            fut = zodb_json_codec.decode_batch_async([make_record({})] * 1000)
+            fut.cancel()
+            await asyncio.sleep(0.2)
+            return fut.cancelled()
+
+        assert asyncio.run(main())
+
+    def test_requires_running_loop(self):
+        with pytest.raises(RuntimeError):
+            zodb_json_codec.decode_batch_async([make_record({})])
@@ -0,0 +1,64 @@
+"""Parallel batch encoding of ZODB JSON records."""
+
+from datetime import datetime
+
+import pickle
+import pytest
+import zodb_json_codec
+
+
+def make_record(module, name, state):
+    return pickle.dumps((module, name), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+def records():
+    docs = [
+        make_record("myapp", "Document", {"title": f"Doc {i}", "n": i, "data": b"\x00" * i})
+        for i in range(20)
+    ]
+    docs.append(make_record("myapp", "Event", {"start": datetime(2025, 6, 15, 12, 0)}))
+    docs.append(
+        make_record("BTrees.OOBTree", "OOBucket", ((("a", 1, "b", 2),),)[0])
+    )
+    return [zodb_json_codec.decode_zodb_record(r) for r in docs]
+
+
+class TestEncodeBatch:
+    def test_matches_single_encode(self):
+        batch = records()
+        encoded = zodb_json_codec.encode_zodb_records_batch(batch)
+        assert len(encoded) == len(batch)
+        for obj, data in zip(batch, encoded):
+            assert isinstance(data, bytes)
+            assert zodb_json_codec.decode_zodb_record(data) == obj
+            single = zodb_json_codec.encode_zodb_record(obj)
+            assert zodb_json_codec.decode_zodb_record(single) == obj
+
+    def test_output_loads_with_pickle(self):
+        (data,) = zodb_json_codec.encode_zodb_records_batch(
+            [{"@cls": ["myapp", "Doc"], "@s": {"title": "Hello", "tags": ["a", "b"]}}]
+        )
+        unpickler = pickle.Unpickler(__import__("io").BytesIO(data))
+        assert unpickler.load() == (("myapp", "Doc"), None)
+        assert unpickler.load() == {"title": "Hello", "tags": ["a", "b"]}
+
+    def test_persistent_refs(self):
+        obj = {"@cls": ["myapp", "Folder"], "@s": {"child": {"@ref": "0000000000000003"}}}
+        (data,) = zodb_json_codec.encode_zodb_records_batch([obj])
+        assert zodb_json_codec.decode_zodb_record(data) == obj
+
+    def test_empty(self):
+        assert zodb_json_codec.encode_zodb_records_batch([]) == []
+
+    def test_error_names_record(self):
+        batch = records()[:3] + [{"@s": {}}]
+        with pytest.raises(ValueError, match="record 3: .*missing @cls"):
+            zodb_json_codec.encode_zodb_records_batch(batch)
+
+    def test_encode_error_names_record(self):
+        deep = []
+        for _ in range(1500):
+            deep = [deep]
+        batch = records()[:2] + [{"@cls": ["myapp", "Deep"], "@s": deep}]
+        with pytest.raises(ValueError, match="record 2: "):
+            zodb_json_codec.encode_zodb_records_batch(batch)
@@ -0,0 +1,61 @@
+"""ZODB blob records: the @blob marker."""
+
+import pickle
+
+import pytest
+import zodb_json_codec
+
+# A ZODB.blob.Blob record as ZODB writes it
+BLOB_RECORD = b"\x80\x03cZODB.blob\nBlob\nq\x00.\x80\x03N."
+SERIAL = b"\x03\xe0\x00\x00\x00\x00\x00\x42"
+
+
+def make_record(state, module="myapp", name="Doc"):
+    return pickle.dumps((module, name), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+class TestBlobRecords:
+    def test_decode_marks_blob(self):
+        assert zodb_json_codec.decode_zodb_record(BLOB_RECORD) == {
+            "@cls": ["ZODB.blob", "Blob"],
+            "@s": None,
+            "@blob": True,
+        }
+
+    def test_serial(self):
+        result = zodb_json_codec.decode_zodb_record(BLOB_RECORD, serial=SERIAL)
+        assert result["@serial"] == "03e0000000000042"
+
+    def test_serial_only_for_blobs(self):
+        result = zodb_json_codec.decode_zodb_record(make_record({}), serial=SERIAL)
+        assert "@serial" not in result and "@blob" not in result
+
+    def test_serial_must_be_8_bytes(self):
+        with pytest.raises(ValueError, match="8 bytes"):
+            zodb_json_codec.decode_zodb_record(BLOB_RECORD, serial=b"\x01")
+
+    def test_encode_exact_pickle(self):
+        decoded = zodb_json_codec.decode_zodb_record(BLOB_RECORD, serial=SERIAL)
+        assert zodb_json_codec.encode_zodb_record(decoded) == BLOB_RECORD
+        assert zodb_json_codec.encode_zodb_record({"@blob": True}) == BLOB_RECORD
+
+    def test_encode_batch(self):
+        records = [{"@blob": True}, {"@cls": ["ZODB.blob", "Blob"], "@s": None}]
+        assert zodb_json_codec.encode_zodb_records_batch(records) == [
+            BLOB_RECORD,
+            BLOB_RECORD,
+        ]
+
+    def test_invalid_marker(self):
+        for record in [
+            {"@blob": True, "@cls": ["myapp", "Doc"], "@s": None},
+            {"@blob": True, "@s": {"x": 1}},
+            {"@blob": 1},
+        ]:
+            with pytest.raises(ValueError, match="@blob"):
+                zodb_json_codec.encode_zodb_record(record)
+
+    def test_other_class_named_blob(self):
+        record = make_record(None, name="Blob")
+        assert "@blob" not in zodb_json_codec.decode_zodb_record(record)
+
@@ -199,7 +199,7 @@ class TestEmptyBTree:
 
 
 class TestLength:
-    """BTrees.Length stores just an integer. No change needed."""
+    """BTrees.Length stores just an integer, marked as @len."""
 
     def test_format(self):
         record = make_zodb_record(
@@ -209,7 +209,28 @@ class TestLength:
         )
         result = zodb_json_codec.decode_zodb_record(record)
         assert result["@cls"] == ["BTrees.Length", "Length"]
-        assert result["@s"] == 42
+        assert result["@s"] == {"@len": 42}
+
+    def test_pg_json(self):
+        record = make_zodb_record("BTrees.Length", "Length", 42)
+        _, _, json_str, _ = zodb_json_codec.decode_zodb_record_for_pg_json(record)
+        assert json.loads(json_str) == {"@len": 42}
+
+    def test_exact_state(self):
+        record = make_zodb_record("BTrees.Length", "Length", 2**40)
+        decoded = zodb_json_codec.decode_zodb_record(record)
+        assert decoded["@s"] == {"@len": 2**40}
+        re_encoded = zodb_json_codec.encode_zodb_record(decoded)
+        assert zodb_json_codec.decode_zodb_record(re_encoded) == decoded
+
+    def test_non_int_rejected(self):
+        doc = {"@cls": ["BTrees.Length", "Length"], "@s": {"@len": "42"}}
+        with pytest.raises(ValueError, match="@len must hold an int"):
+            zodb_json_codec.encode_zodb_record(doc)
+
+    def test_inline_marker(self):
+        data = zodb_json_codec.dict_to_pickle({"count": {"@len": 3}})
+        assert zodb_json_codec.pickle_to_dict(data) == {"count": {"@len": 3}}
 
     def test_roundtrip(self):
         record = make_zodb_record(
@@ -389,13 +410,32 @@ class TestRealZODB:
         result = zodb_json_codec.decode_zodb_record(data)
 
         assert result["@cls"] == ["BTrees.Length", "Length"]
-        assert result["@s"] == 42
+        assert result["@s"] == {"@len": 42}
 
         # Roundtrip
         re_encoded = zodb_json_codec.encode_zodb_record(result)
         result2 = zodb_json_codec.decode_zodb_record(re_encoded)
         assert result == result2
 
+    def test_large_oobtree_materialized(self, zodb):
+        """A load callback inlines all buckets of a large OOBTree."""
+        from BTrees.OOBTree import OOBTree
+
+        import transaction
+
+        db, _conn, root = zodb
+        tree = OOBTree()
+        for i in range(1000):
+            tree[f"key_{i:04d}"] = i
+        root["large"] = tree
+        transaction.commit()
+
+        def load(oid):
+            return db.storage.load(oid)[0]
+
+        result = zodb_json_codec.decode_zodb_record(load(tree._p_oid), load=load)
+        assert result["@s"] == {"@kv": [[k, v] for k, v in tree.items()]}
+
     def test_empty_oobtree(self, zodb):
         from BTrees.OOBTree import OOBTree
 
@@ -516,3 +556,264 @@ class TestPickleRoundtrip:
         json_str2 = zodb_json_codec.pickle_to_json(restored_bytes)
         result2 = json.loads(json_str2)
         assert result == result2
+
+
+class TestRegisteredBTreeClasses:
+    """BTree subclasses outside the BTrees package, registered explicitly."""
+
+    def teardown_method(self, method):
+        zodb_json_codec.clear_btree_registrations()
+
+    def test_unregistered_not_flattened(self):
+        record = make_zodb_record("zc.intid.utility", "IOBTree", (((1, "a"),),))
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert "@kv" not in result["@s"]
+
+    def test_module_prefix(self):
+        zodb_json_codec.register_btree_module_prefix("zc.intid")
+        state = ((((1, "a", 2, "b"),),),)
+        record = make_zodb_record("zc.intid.utility", "IOBTree", state)
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert result["@s"] == {"@kv": [[1, "a"], [2, "b"]]}
+
+        re_encoded = zodb_json_codec.encode_zodb_record(result)
+        assert zodb_json_codec.decode_zodb_record(re_encoded) == result
+
+    def test_module_prefix_is_a_package(self):
+        zodb_json_codec.register_btree_module_prefix("zc.intid")
+        record = make_zodb_record("zc.intidfoo", "IOBTree", (((1, "a"),),))
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert "@kv" not in result["@s"]
+
+    def test_explicit_class(self):
+        zodb_json_codec.register_btree_class("myapp.catalog", "Index", "TreeSet")
+        state = ((((1, 2, 3),),),)
+        record = make_zodb_record("myapp.catalog", "Index", state)
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert result["@s"] == {"@ks": [1, 2, 3]}
+
+        re_encoded = zodb_json_codec.encode_zodb_record(result)
+        assert zodb_json_codec.decode_zodb_record(re_encoded) == result
+
+    def test_json_path(self):
+        zodb_json_codec.register_btree_class("myapp.catalog", "Bucket", "Bucket")
+        doc = {"@cls": ["myapp.catalog", "Bucket"], "@s": {"@kv": [["k", "v"]]}}
+        restored = zodb_json_codec.json_to_pickle(json.dumps(doc))
+        assert json.loads(zodb_json_codec.pickle_to_json(restored)) == doc
+
+    def test_unknown_kind_rejected(self):
+        with pytest.raises(ValueError, match="unknown BTree kind"):
+            zodb_json_codec.register_btree_class("a", "B", "Tree")
+
+
+class TestClassifyBTree:
+    """Node kind and key/value type codes per BTree family."""
+
+    def teardown_method(self, method):
+        zodb_json_codec.clear_btree_registrations()
+
+    def test_map_family(self):
+        info = zodb_json_codec.classify_btree("BTrees.IOBTree", "IOBTree")
+        assert info == {
+            "kind": "BTree",
+            "is_map": True,
+            "key_type": "I",
+            "value_type": "O",
+        }
+
+    def test_set_has_no_value_type(self):
+        info = zodb_json_codec.classify_btree("BTrees.LLBTree", "LLTreeSet")
+        assert info["kind"] == "TreeSet"
+        assert info["is_map"] is False
+        assert info["key_type"] == "L"
+        assert info["value_type"] is None
+
+    def test_fsbtree(self):
+        info = zodb_json_codec.classify_btree("BTrees.fsBTree", "fsBucket")
+        assert (info["key_type"], info["value_type"]) == ("fs", "fs")
+
+    def test_non_btree(self):
+        assert zodb_json_codec.classify_btree("BTrees.Length", "Length") is None
+        assert zodb_json_codec.classify_btree("myapp", "Doc") is None
+
+    def test_registered_class_types_unknown(self):
+        zodb_json_codec.register_btree_class("myapp.catalog", "Index", "Bucket")
+        info = zodb_json_codec.classify_btree("myapp.catalog", "Index")
+        assert info["kind"] == "Bucket"
+        assert info["key_type"] is None
+        assert info["value_type"] is None
+
+
+class TestTypedKeyValidation:
+    """Keys and values must fit the BTree family's types on encode."""
+
+    def test_string_key_in_iibucket_rejected(self):
+        doc = {
+            "@cls": ["BTrees.IIBTree", "IIBucket"],
+            "@s": {"@kv": [[1, 10], ["two", 20]]},
+        }
+        with pytest.raises(ValueError, match=r"@kv\[1\]\[0\]: BTree key of type I"):
+            zodb_json_codec.encode_zodb_record(doc)
+        with pytest.raises(ValueError, match=r"@kv\[1\]\[0\]"):
+            zodb_json_codec.json_to_pickle(json.dumps(doc))
+
+    def test_out_of_range_value_rejected(self):
+        doc = {
+            "@cls": ["BTrees.OIBTree", "OIBTree"],
+            "@s": {"@kv": [["a", 2**31]]},
+        }
+        with pytest.raises(ValueError, match=r"@kv\[0\]\[1\]: BTree value of type I"):
+            zodb_json_codec.encode_zodb_record(doc)
+
+    def test_float_key_in_treeset_rejected(self):
+        doc = {"@cls": ["BTrees.LLBTree", "LLTreeSet"], "@s": {"@ks": [1, 2.5]}}
+        with pytest.raises(ValueError, match=r"@ks\[1\]"):
+            zodb_json_codec.encode_zodb_record(doc)
+
+    def test_children_separator_key_rejected(self):
+        doc = {
+            "@cls": ["BTrees.IOBTree", "IOBTree"],
+            "@s": {
+                "@children": [
+                    {"@ref": "0000000000000002"},
+                    "sep",
+                    {"@ref": "0000000000000003"},
+                ],
+                "@first": {"@ref": "0000000000000002"},
+            },
+        }
+        with pytest.raises(ValueError, match=r"@children\[1\]"):
+            zodb_json_codec.encode_zodb_record(doc)
+
+    def test_valid_typed_values_roundtrip(self):
+        record = make_zodb_record(
+            "BTrees.IFBTree", "IFBucket", ((1, 0.5, 2, 3, -(2**31), 1.0),)
+        )
+        decoded = zodb_json_codec.decode_zodb_record(record)
+        re_encoded = zodb_json_codec.encode_zodb_record(decoded)
+        assert zodb_json_codec.decode_zodb_record(re_encoded) == decoded
+
+    def test_object_family_unchecked(self):
+        doc = {"@cls": ["BTrees.OOBTree", "OOBucket"], "@s": {"@kv": [[1, "a"], ["b", 2.5]]}}
+        zodb_json_codec.encode_zodb_record(doc)
+
+
+class TestMaterializeBTree:
+    """decode_zodb_record(load=...) inlines the buckets of a large BTree."""
+
+    LARGE = {
+        "@cls": ["BTrees.IIBTree", "IIBTree"],
+        "@s": {
+            "@children": [{"@ref": "0000000000000002"}, 3, {"@ref": "0000000000000003"}],
+            "@first": {"@ref": "0000000000000002"},
+        },
+    }
+
+    def bucket(self, kv, next_oid=None):
+        state = {"@kv": kv}
+        if next_oid is not None:
+            state["@next"] = {"@ref": next_oid}
+        return zodb_json_codec.encode_zodb_record(
+            {"@cls": ["BTrees.IIBTree", "IIBucket"], "@s": state}
+        )
+
+    def storage(self):
+        return {
+            bytes.fromhex("0000000000000002"): self.bucket(
+                [[1, 10], [2, 20]], "0000000000000003"
+            ),
+            bytes.fromhex("0000000000000003"): self.bucket([[3, 30]]),
+        }
+
+    def test_flattened(self):
+        storage = self.storage()
+        record = zodb_json_codec.encode_zodb_record(self.LARGE)
+        result = zodb_json_codec.decode_zodb_record(record, load=storage.__getitem__)
+        assert result == {
+            "@cls": ["BTrees.IIBTree", "IIBTree"],
+            "@s": {"@kv": [[1, 10], [2, 20], [3, 30]]},
+        }
+        state = zodb_json_codec.decode_zodb_state(record, load=storage.__getitem__)
+        assert state == result["@s"]
+
+    def test_without_load_unchanged(self):
+        record = zodb_json_codec.encode_zodb_record(self.LARGE)
+        assert zodb_json_codec.decode_zodb_record(record) == self.LARGE
+
+    def test_small_tree_not_loaded(self):
+        doc = {"@cls": ["BTrees.IIBTree", "IIBTree"], "@s": {"@kv": [[1, 2]]}}
+        record = zodb_json_codec.encode_zodb_record(doc)
+
+        def load(oid):
+            raise AssertionError("loaded")
+
+        assert zodb_json_codec.decode_zodb_record(record, load=load) == doc
+
+    def test_load_error_propagates(self):
+        record = zodb_json_codec.encode_zodb_record(self.LARGE)
+        with pytest.raises(KeyError):
+            zodb_json_codec.decode_zodb_record(record, load={}.__getitem__)
+
+    def test_chain_loop_rejected(self):
+        storage = self.storage()
+        storage[bytes.fromhex("0000000000000003")] = self.bucket(
+            [[3, 30]], "0000000000000002"
+        )
+        record = zodb_json_codec.encode_zodb_record(self.LARGE)
+        with pytest.raises(ValueError, match="loops at oid 0000000000000002"):
+            zodb_json_codec.decode_zodb_record(record, load=storage.__getitem__)
+
+
+class TestSplitBTree:
+    """encode_zodb_record(new_oid=...) splits large trees into buckets."""
+
+    def oids(self):
+        counter = iter(range(100, 1000))
+        return lambda: next(counter).to_bytes(8, "big")
+
+    def test_split_and_materialize(self):
+        kv = [[i, i * 10] for i in range(250)]
+        doc = {"@cls": ["BTrees.IIBTree", "IIBTree"], "@s": {"@kv": kv}}
+        record, buckets = zodb_json_codec.encode_zodb_record(doc, new_oid=self.oids())
+        # 120 entries per IIBucket
+        assert [len(oid) for oid, _ in buckets] == [8, 8, 8]
+        result = zodb_json_codec.decode_zodb_record(record)
+        bucket_ref = [buckets[0][0].hex(), "BTrees.IIBTree.IIBucket"]
+        assert result["@s"]["@first"] == {"@ref": bucket_ref}
+        assert result["@s"]["@children"][1] == 120
+        first = zodb_json_codec.decode_zodb_record(buckets[0][1])
+        assert first["@cls"] == ["BTrees.IIBTree", "IIBucket"]
+        assert first["@s"]["@next"]["@ref"][0] == buckets[1][0].hex()
+        storage = dict(buckets)
+        assert zodb_json_codec.decode_zodb_record(record, load=storage.__getitem__) == doc
+
+    def test_tree_set_bucket_size(self):
+        doc = {"@cls": ["BTrees.OOBTree", "OOTreeSet"], "@s": {"@ks": ["a", "b", "c"]}}
+        record, buckets = zodb_json_codec.encode_zodb_record(
+            doc, new_oid=self.oids(), bucket_size=2
+        )
+        assert len(buckets) == 2
+        assert zodb_json_codec.decode_zodb_record(buckets[1][1]) == {
+            "@cls": ["BTrees.OOBTree", "OOSet"],
+            "@s": {"@ks": ["c"]},
+        }
+
+    def test_small_tree_unchanged(self):
+        doc = {"@cls": ["BTrees.OOBTree", "OOBTree"], "@s": {"@kv": [["a", 1]]}}
+
+        def new_oid():
+            raise AssertionError("allocated")
+
+        record, buckets = zodb_json_codec.encode_zodb_record(doc, new_oid=new_oid)
+        assert buckets == []
+        assert record == zodb_json_codec.encode_zodb_record(doc)
+
+    def test_bucket_size_needs_new_oid(self):
+        doc = {"@cls": ["BTrees.OOBTree", "OOBTree"], "@s": None}
+        with pytest.raises(ValueError, match="needs new_oid"):
+            zodb_json_codec.encode_zodb_record(doc, bucket_size=10)
+
+    def test_oid_width_checked(self):
+        doc = {"@cls": ["BTrees.OOBTree", "OOTreeSet"], "@s": {"@ks": ["a", "b"]}}
+        with pytest.raises(ValueError, match="8-byte oids"):
+            zodb_json_codec.encode_zodb_record(doc, new_oid=lambda: b"\x01", bucket_size=1)
@@ -0,0 +1,153 @@
+"""Test bytes-like decode input and binary_mode."""
+
+import io
+import pickle
+import pytest
+import zodb_json_codec
+
+
+STATE = {"title": "Hello", "blob": b"\x00\x01\x02" * 100}
+
+
+def make_record(state):
+    return pickle.dumps(("myapp.models", "Folder"), protocol=3) + pickle.dumps(
+        state, protocol=3
+    )
+
+
+RECORD = make_record(STATE)
+
+
+def load_state(record):
+    f = io.BytesIO(record)
+    pickle.load(f)
+    return pickle.load(f)
+
+
+class TestBytesLikeInput:
+    @pytest.mark.parametrize("wrap", [bytes, bytearray, memoryview])
+    def test_pickle_to_dict(self, wrap):
+        data = pickle.dumps(STATE, protocol=3)
+        assert zodb_json_codec.pickle_to_dict(wrap(data)) == zodb_json_codec.pickle_to_dict(data)
+
+    @pytest.mark.parametrize("wrap", [bytes, bytearray, memoryview])
+    def test_pickle_to_json(self, wrap):
+        data = pickle.dumps(STATE, protocol=3)
+        assert zodb_json_codec.pickle_to_json(wrap(data)) == zodb_json_codec.pickle_to_json(data)
+
+    @pytest.mark.parametrize("wrap", [bytes, bytearray, memoryview])
+    def test_decode_zodb_record(self, wrap):
+        expected = zodb_json_codec.decode_zodb_record(RECORD)
+        assert zodb_json_codec.decode_zodb_record(wrap(RECORD)) == expected
+
+    @pytest.mark.parametrize("wrap", [bytearray, memoryview])
+    def test_pg_paths(self, wrap):
+        assert zodb_json_codec.decode_zodb_record_for_pg_json(
+            wrap(RECORD)
+        ) == zodb_json_codec.decode_zodb_record_for_pg_json(RECORD)
+        assert zodb_json_codec.decode_zodb_record_for_pg(
+            wrap(RECORD)
+        ) == zodb_json_codec.decode_zodb_record_for_pg(RECORD)
+
+    def test_memoryview_slice(self):
+        padded = b"xx" + RECORD + b"yy"
+        view = memoryview(padded)[2:-2]
+        expected = zodb_json_codec.decode_zodb_record(RECORD)
+        assert zodb_json_codec.decode_zodb_record(view) == expected
+
+    def test_scanning_functions(self):
+        view = memoryview(RECORD)
+        assert zodb_json_codec.count_refs(view) == 0
+        assert zodb_json_codec.collect_refs_ex(bytearray(RECORD)) == []
+        assert zodb_json_codec.lint_record(view) == zodb_json_codec.lint_record(RECORD)
+
+    def test_out_of_band_buffers(self):
+        payload = bytearray(b"out-of-band")
+        buffers = []
+        data = pickle.dumps(
+            pickle.PickleBuffer(payload), protocol=5, buffer_callback=buffers.append
+        )
+        result = zodb_json_codec.pickle_to_dict(memoryview(data), buffers=buffers)
+        assert result == zodb_json_codec.pickle_to_dict(
+            data, buffers=[bytes(b.raw()) for b in buffers]
+        )
+
+    def test_non_contiguous_raises(self):
+        view = memoryview(RECORD + RECORD)[::2]
+        with pytest.raises(BufferError):
+            zodb_json_codec.decode_zodb_record(view)
+
+    def test_str_raises(self):
+        with pytest.raises(TypeError):
+            zodb_json_codec.pickle_to_dict("not bytes")
+
+
+class TestBinaryMode:
+    def test_default_is_base64(self):
+        result = zodb_json_codec.decode_zodb_record(RECORD)
+        assert isinstance(result["@s"]["blob"]["@b"], str)
+
+    def test_decode_zodb_record(self):
+        result = zodb_json_codec.decode_zodb_record(RECORD, binary_mode=True)
+        assert result["@s"]["blob"] == {"@b": STATE["blob"]}
+        assert result["@s"]["title"] == "Hello"
+
+    def test_pickle_to_dict(self):
+        data = pickle.dumps([b"abc", {b"key": 1}], protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data, binary_mode=True)
+        assert result[0] == {"@b": b"abc"}
+
+    def test_mode_does_not_leak(self):
+        zodb_json_codec.decode_zodb_record(RECORD, binary_mode=True)
+        result = zodb_json_codec.decode_zodb_record(RECORD)
+        assert isinstance(result["@s"]["blob"]["@b"], str)
+
+    def test_encode_roundtrip(self):
+        result = zodb_json_codec.decode_zodb_record(RECORD, binary_mode=True)
+        assert load_state(zodb_json_codec.encode_zodb_record(result)) == STATE
+
+    def test_dict_to_pickle_roundtrip(self):
+        value = {"a": b"abc", "b": (b"\x00", 1)}
+        data = pickle.dumps(value, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data, binary_mode=True)
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result)) == value
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result, protocol=2)) == value
+
+
+class TestRawBytes:
+    def test_decode_zodb_record(self):
+        result = zodb_json_codec.decode_zodb_record(RECORD, raw_bytes=True)
+        assert result["@s"]["blob"] == STATE["blob"]
+        assert result["@s"]["title"] == "Hello"
+
+    def test_pickle_to_dict_nested(self):
+        value = {"a": [b"x", (b"y", 1)], "b": {"c": b""}}
+        data = pickle.dumps(value, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data, raw_bytes=True)
+        assert result == {"a": [b"x", {"@t": [b"y", 1]}], "b": {"c": b""}}
+
+    def test_bytes_keys(self):
+        data = pickle.dumps({b"k": 1}, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data, raw_bytes=True)
+        assert pickle.loads(zodb_json_codec.dict_to_pickle({"v": result}))["v"] == {b"k": 1}
+
+    def test_overrides_binary_mode(self):
+        result = zodb_json_codec.decode_zodb_record(RECORD, binary_mode=True, raw_bytes=True)
+        assert result["@s"]["blob"] == STATE["blob"]
+
+    def test_encode_roundtrip(self):
+        result = zodb_json_codec.decode_zodb_record(RECORD, raw_bytes=True)
+        assert load_state(zodb_json_codec.encode_zodb_record(result)) == STATE
+        (encoded,) = zodb_json_codec.encode_zodb_records_batch([result])
+        assert load_state(encoded) == STATE
+
+    def test_dict_to_pickle_roundtrip(self):
+        value = {"a": b"abc", "b": (b"\x00", 1)}
+        data = pickle.dumps(value, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data, raw_bytes=True)
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result)) == value
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result, protocol=2)) == value
+
+    def test_plain_bytes_encode_as_bytes(self):
+        data = zodb_json_codec.dict_to_pickle({"a": b"\xff\x00"})
+        assert pickle.loads(data) == {"a": b"\xff\x00"}
@@ -0,0 +1,144 @@
+"""Test canonicalize_json, canonicalize_pickle and state_fingerprint."""
+
+import json
+import pickle
+import pytest
+import zodb_json_codec
+
+
+class TestCanonicalizeJson:
+    def test_sorted_compact_output(self):
+        out = zodb_json_codec.canonicalize_json('{"b": [1, 2],  "a": {"@t": [1]}}')
+        assert out == '{"a":{"@t":[1]},"b":[1,2]}'
+
+    def test_idempotent(self):
+        data = pickle.dumps({"x": (1, b"\x00"), "y": {1, 2}}, protocol=3)
+        doc = zodb_json_codec.pickle_to_json(data)
+        once = zodb_json_codec.canonicalize_json(doc)
+        assert zodb_json_codec.canonicalize_json(once) == once
+        assert json.loads(once) == json.loads(doc)
+
+    def test_refs_normalized(self):
+        generic = '{"@ref": {"@t": [{"@b": "AAAAAAAAAAM="}, null]}}'
+        upper = '{"@ref": "0000000000000003"}'
+        assert zodb_json_codec.canonicalize_json(generic) == '{"@ref":"0000000000000003"}'
+        assert zodb_json_codec.canonicalize_json(upper) == '{"@ref":"0000000000000003"}'
+        cls_ref = '{"@ref": ["00000000000000AB", "myapp.Doc"]}'
+        assert (
+            zodb_json_codec.canonicalize_json(cls_ref)
+            == '{"@ref":["00000000000000ab","myapp.Doc"]}'
+        )
+
+    def test_datetime_normalized(self):
+        out = zodb_json_codec.canonicalize_json('{"@dt": "2025-06-15T12:30:45.000000"}')
+        assert out == '{"@dt":"2025-06-15T12:30:45"}'
+
+    def test_record_matches_decoder(self):
+        record = pickle.dumps(("myapp", "Doc"), protocol=3) + pickle.dumps(
+            {"title": "Hello", "n": 2}, protocol=3
+        )
+        decoded = zodb_json_codec.decode_zodb_record(record)
+        shuffled = json.dumps({"@s": decoded["@s"], "@cls": decoded["@cls"]}, indent=2)
+        assert zodb_json_codec.canonicalize_json(shuffled) == json.dumps(
+            decoded, sort_keys=True, separators=(",", ":")
+        )
+
+    def test_invalid_json(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.canonicalize_json("{")
+
+
+def make_record(state, protocol=3):
+    return pickle.dumps(("myapp", "Doc"), protocol=protocol) + pickle.dumps(
+        state, protocol=protocol
+    )
+
+
+class TestCanonicalizePickle:
+    @pytest.mark.parametrize("protocol", [0, 1, 2, 3, 4, 5])
+    def test_protocols_agree(self, protocol):
+        state = {"title": "Hello", "tags": ["a", "b"], "n": 2**40, "raw": b"\x00"}
+        expected = zodb_json_codec.canonicalize_pickle(make_record(state))
+        record = make_record(state, protocol=protocol)
+        assert zodb_json_codec.canonicalize_pickle(record) == expected
+
+    def test_memo_usage_ignored(self):
+        # The same string object is memoized; equal copies are not
+        shared = "x" * 20
+        one = pickle.dumps([shared, shared], protocol=3)
+        two = pickle.dumps(["x" * 20, "".join(["x"] * 20)], protocol=3)
+        assert one != two
+        canonical = zodb_json_codec.canonicalize_pickle(one)
+        assert zodb_json_codec.canonicalize_pickle(two) == canonical
+        assert pickle.loads(canonical) == [shared, shared]
+
+    def test_idempotent(self):
+        data = pickle.dumps({"x": (1, 2.5), "y": {1, 2}, "z": None}, protocol=2)
+        once = zodb_json_codec.canonicalize_pickle(data)
+        assert zodb_json_codec.canonicalize_pickle(once) == once
+        assert pickle.loads(once) == pickle.loads(data)
+
+    def test_aliasing_kept(self):
+        inner = [1]
+        aliased = zodb_json_codec.canonicalize_pickle(
+            pickle.dumps([inner, inner], protocol=3)
+        )
+        copies = zodb_json_codec.canonicalize_pickle(pickle.dumps([[1], [1]], protocol=3))
+        assert aliased != copies
+        restored = pickle.loads(aliased)
+        assert restored[0] is restored[1]
+
+    def test_dict_order_kept(self):
+        first = zodb_json_codec.canonicalize_pickle(pickle.dumps({"a": 1, "b": 2}))
+        second = zodb_json_codec.canonicalize_pickle(pickle.dumps({"b": 2, "a": 1}))
+        assert first != second
+
+    def test_record_decodes_identically(self):
+        record = make_record({"title": "Hello", "n": 2}, protocol=2)
+        canonical = zodb_json_codec.canonicalize_pickle(bytearray(record))
+        assert zodb_json_codec.decode_zodb_record(
+            canonical
+        ) == zodb_json_codec.decode_zodb_record(record)
+
+    def test_invalid(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.canonicalize_pickle(b"\x80\x03")
+
+
+class TestStateFingerprint:
+    @pytest.mark.parametrize("protocol", [0, 2, 3, 5])
+    def test_protocols_agree(self, protocol):
+        state = {"title": "Hello", "tags": ["a", "b"], "n": 2**40}
+        expected = zodb_json_codec.state_fingerprint(make_record(state))
+        record = make_record(state, protocol=protocol)
+        assert zodb_json_codec.state_fingerprint(record) == expected
+
+    def test_memo_usage_ignored(self):
+        shared = "x" * 20
+        one = make_record({"a": shared, "b": shared})
+        two = make_record({"a": "x" * 20, "b": "".join(["x"] * 20)})
+        assert one != two
+        fingerprint = zodb_json_codec.state_fingerprint(one)
+        assert len(fingerprint) == 64
+        assert zodb_json_codec.state_fingerprint(two) == fingerprint
+
+    def test_set_order_ignored(self):
+        one = b"\x80\x04\x8f(K\x01K\x02\x90."
+        two = b"\x80\x04\x8f(K\x02K\x01\x90."
+        fp = zodb_json_codec.state_fingerprint
+        assert fp(one) == fp(two)
+
+    def test_state_change_detected(self):
+        fp = zodb_json_codec.state_fingerprint
+        assert fp(make_record({"title": "Hello"})) != fp(make_record({"title": "Hi"}))
+
+    def test_class_ignored(self):
+        state = pickle.dumps({"title": "Hello"}, protocol=3)
+        old = pickle.dumps(("old.app", "Doc"), protocol=3) + state
+        new = pickle.dumps(("new.app", "Doc"), protocol=3) + state
+        fp = zodb_json_codec.state_fingerprint
+        assert fp(old) == fp(new) == fp(state)
+
+    def test_invalid(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.state_fingerprint(b"\x80\x03")
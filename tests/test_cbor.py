@@ -0,0 +1,71 @@
+"""pickle_to_cbor / cbor_to_pickle: CBOR with semantic tags for markers."""
+
+import datetime
+import decimal
+import pickle
+import uuid
+
+import pytest
+import zodb_json_codec
+
+
+def roundtrip(obj):
+    cbor = zodb_json_codec.pickle_to_cbor(pickle.dumps(obj, protocol=3))
+    return pickle.loads(zodb_json_codec.cbor_to_pickle(cbor))
+
+
+class TestCbor:
+    @pytest.mark.parametrize(
+        "obj",
+        [
+            None,
+            True,
+            -7,
+            2**64,
+            -(2**100),
+            1.25,
+            "héllo",
+            b"\x00\xff" * 100,
+            [1, [2, (3, "x")]],
+            {"a": 1, 2: "b", (1, 2): None},
+            {1, 2, 3},
+            frozenset({"a"}),
+            datetime.datetime(2025, 6, 15, 12, 30, tzinfo=datetime.timezone.utc),
+            datetime.datetime(2025, 6, 15, 12, 30),
+            datetime.date(2025, 1, 2),
+            decimal.Decimal("1.50"),
+            uuid.UUID("12345678-1234-5678-1234-567812345678"),
+        ],
+    )
+    def test_roundtrip(self, obj):
+        assert roundtrip(obj) == obj
+
+    def test_bytes_are_raw(self):
+        blob = bytes(range(256)) * 4
+        cbor = zodb_json_codec.pickle_to_cbor(pickle.dumps(blob, protocol=3))
+        # Byte string head (0x59 + 2-byte length) followed by the data
+        assert cbor == b"\x59\x04\x00" + blob
+
+    def test_bignum_tag(self):
+        cbor = zodb_json_codec.pickle_to_cbor(pickle.dumps(2**64, protocol=3))
+        assert cbor == b"\xc2\x49\x01" + b"\x00" * 8
+
+    def test_aware_datetime_tag(self):
+        dt = datetime.datetime(2025, 6, 15, 12, 0, tzinfo=datetime.timezone.utc)
+        cbor = zodb_json_codec.pickle_to_cbor(pickle.dumps(dt, protocol=3))
+        assert cbor == b"\xc0\x78\x19" + b"2025-06-15T12:00:00+00:00"
+
+    def test_protocol(self):
+        cbor = zodb_json_codec.pickle_to_cbor(pickle.dumps([1], protocol=3))
+        data = zodb_json_codec.cbor_to_pickle(cbor, protocol=2)
+        assert data.startswith(b"\x80\x02")
+        assert pickle.loads(data) == [1]
+
+    def test_invalid_cbor(self):
+        for data in [b"", b"\x82\x01", b"\xd9\x01\x00\x01", b"\x01\x02"]:
+            with pytest.raises(ValueError):
+                zodb_json_codec.cbor_to_pickle(data)
+
+    def test_invalid_pickle(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.pickle_to_cbor(b"\x80\x03")
@@ -0,0 +1,93 @@
+"""Class references that cannot be imported elsewhere: `__main__` classes
+(pickled from scripts), empty module names and qualified nested names.
+The codec keeps them verbatim and `lint_record` flags them."""
+
+import base64
+import json
+import pickle
+import zodb_json_codec
+
+
+OID = b"\x00" * 7 + b"\x01"
+
+# GLOBAL __main__.Script, EMPTY_TUPLE NEWOBJ, {"a": 1} BUILD
+MAIN_INSTANCE = b"\x80\x03c__main__\nScript\n)\x81}X\x01\x00\x00\x00aK\x01sb."
+# The same with an empty module line
+ORPHAN_INSTANCE = b"\x80\x03c\nOrphan\n)\x81}X\x01\x00\x00\x00aK\x01sb."
+# Protocol 4 state {"r": persistent ref (oid, myapp.Outer.Inner)} via STACK_GLOBAL
+QUALNAME_REF_STATE = (
+    b"\x80\x04}\x8c\x01rC\x08" + OID + b"\x8c\x05myapp\x8c\x0bOuter.Inner\x93\x86Qs."
+)
+
+
+def record(module, name, state_pickle):
+    return pickle.dumps((module, name), protocol=3) + state_pickle
+
+
+class TestVerbatim:
+    def test_main_instance(self):
+        result = zodb_json_codec.pickle_to_dict(MAIN_INSTANCE)
+        assert result == {"@cls": ["__main__", "Script"], "@s": {"a": 1}}
+        assert zodb_json_codec.pickle_to_dict(zodb_json_codec.dict_to_pickle(result)) == result
+        j = zodb_json_codec.pickle_to_json(MAIN_INSTANCE)
+        assert zodb_json_codec.pickle_to_json(zodb_json_codec.json_to_pickle(j)) == j
+
+    def test_empty_module_instance(self):
+        result = zodb_json_codec.pickle_to_dict(ORPHAN_INSTANCE)
+        assert result == {"@cls": ["", "Orphan"], "@s": {"a": 1}}
+        encoded = zodb_json_codec.dict_to_pickle(result)
+        assert b"c\nOrphan\n" in encoded
+        assert zodb_json_codec.pickle_to_dict(encoded) == result
+
+    def test_record_classes(self):
+        for module in ("__main__", ""):
+            data = record(module, "Script", pickle.dumps({"a": 1}, protocol=3))
+            result = zodb_json_codec.decode_zodb_record(data)
+            assert result["@cls"] == [module, "Script"]
+            again = zodb_json_codec.decode_zodb_record(zodb_json_codec.encode_zodb_record(result))
+            assert again == result
+
+    def test_compact_ref_hints(self):
+        for module, name, hint in (("__main__", "Script", "__main__.Script"), ("", "Orphan", "Orphan")):
+            state = (
+                b"\x80\x03}X\x01\x00\x00\x00rC\x08" + OID
+                + b"c" + module.encode() + b"\n" + name.encode() + b"\n\x86Qs."
+            )
+            result = zodb_json_codec.decode_zodb_record(record("myapp", "Doc", state))
+            assert result["@s"]["r"] == {"@ref": ["0000000000000001", hint]}
+            again = zodb_json_codec.decode_zodb_record(zodb_json_codec.encode_zodb_record(result))
+            assert again == result
+
+    def test_qualified_name_ref_not_compacted(self):
+        data = record("myapp", "Doc", QUALNAME_REF_STATE)
+        expected = {
+            "@ref": {
+                "@t": [
+                    {"@b": base64.b64encode(OID).decode()},
+                    {"@cls": ["myapp", "Outer.Inner"]},
+                ]
+            }
+        }
+        result = zodb_json_codec.decode_zodb_record(data)
+        assert result["@s"]["r"] == expected
+        again = zodb_json_codec.decode_zodb_record(zodb_json_codec.encode_zodb_record(result))
+        assert again == result
+        _, _, state_json, refs = zodb_json_codec.decode_zodb_record_for_pg_json(data)
+        assert json.loads(state_json)["r"] == expected
+        assert refs == [1]
+
+
+class TestLint:
+    def test_flagged(self):
+        data = record("__main__", "Doc", pickle.dumps({"x": 1}, protocol=3))
+        warnings = zodb_json_codec.lint_record(data)
+        assert [w["code"] for w in warnings] == ["unresolvable-class"]
+
+    def test_nested_instance(self):
+        state = (
+            b"\x80\x03}X\x04\x00\x00\x00note"
+            + ORPHAN_INSTANCE[2:-1]
+            + b"s."
+        )
+        warnings = zodb_json_codec.lint_record(record("myapp", "Doc", state))
+        assert [(w["code"], w["path"]) for w in warnings] == [("unresolvable-class", "note")]
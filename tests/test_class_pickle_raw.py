@@ -0,0 +1,69 @@
+"""Test byte-identical class pickles via @cls_raw (keep_class_pickle)."""
+
+import base64
+import pickle
+import pytest
+import zodb_json_codec
+
+# Protocol 1 class pickle, with memo opcodes, as older ZODB versions wrote
+CLASS_PICKLE = pickle.dumps(("myapp", "Doc"), protocol=1)
+# The state pickle as the codec writes it
+RECORD = zodb_json_codec.encode_zodb_state(
+    "myapp", "Doc", {"title": "Hi"}, record=CLASS_PICKLE + b"N."
+)
+
+
+class TestClassPickleRaw:
+    def test_not_kept_by_default(self):
+        assert "@cls_raw" not in zodb_json_codec.decode_zodb_record(RECORD)
+
+    def test_decode(self):
+        record = zodb_json_codec.decode_zodb_record(RECORD, keep_class_pickle=True)
+        assert record["@cls_raw"] == base64.b64encode(CLASS_PICKLE).decode()
+        binary = zodb_json_codec.decode_zodb_record(
+            RECORD, keep_class_pickle=True, binary_mode=True
+        )
+        assert binary["@cls_raw"] == CLASS_PICKLE
+
+    @pytest.mark.parametrize("binary_mode", [False, True])
+    def test_unchanged_record_is_byte_identical(self, binary_mode):
+        record = zodb_json_codec.decode_zodb_record(
+            RECORD, keep_class_pickle=True, binary_mode=binary_mode
+        )
+        assert zodb_json_codec.encode_zodb_record(record) == RECORD
+        [data] = zodb_json_codec.encode_zodb_records_batch([record])
+        assert data == RECORD
+
+    def test_state_change_keeps_class_pickle(self):
+        record = zodb_json_codec.decode_zodb_record(RECORD, keep_class_pickle=True)
+        record["@s"]["title"] = "Changed"
+        data = zodb_json_codec.encode_zodb_record(record)
+        assert data.startswith(CLASS_PICKLE)
+        assert zodb_json_codec.decode_zodb_record(data)["@s"] == {"title": "Changed"}
+
+    def test_class_change_rewrites_class_pickle(self):
+        record = zodb_json_codec.decode_zodb_record(RECORD, keep_class_pickle=True)
+        record["@cls"] = ["myapp", "Folder"]
+        data = zodb_json_codec.encode_zodb_record(record)
+        assert not data.startswith(CLASS_PICKLE)
+        assert zodb_json_codec.decode_zodb_record(data)["@cls"] == ["myapp", "Folder"]
+
+    def test_renamed_class_is_not_kept(self):
+        renames = zodb_json_codec.ClassRenames(modules={"myapp": "newapp"})
+        record = zodb_json_codec.decode_zodb_record(
+            RECORD, keep_class_pickle=True, renames=renames
+        )
+        assert record["@cls"] == ["newapp", "Doc"]
+        assert "@cls_raw" not in record
+
+    def test_encode_rename_wins(self):
+        record = zodb_json_codec.decode_zodb_record(RECORD, keep_class_pickle=True)
+        renames = zodb_json_codec.ClassRenames(modules={"myapp": "newapp"})
+        data = zodb_json_codec.encode_zodb_record(record, renames=renames)
+        assert zodb_json_codec.decode_zodb_record(data)["@cls"] == ["newapp", "Doc"]
+
+    @pytest.mark.parametrize("raw", ["AAAA", 42, base64.b64encode(RECORD).decode()])
+    def test_invalid(self, raw):
+        record = {"@cls": ["myapp", "Doc"], "@s": {}, "@cls_raw": raw}
+        with pytest.raises(ValueError, match="@cls_raw"):
+            zodb_json_codec.encode_zodb_record(record)
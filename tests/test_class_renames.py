@@ -0,0 +1,100 @@
+"""Class renaming during decode and encode (ClassRenames, renames=)."""
+
+import pytest
+import zodb_json_codec
+
+OLD = {
+    "@cls": ["Products.Archetypes", "Foo"],
+    "@s": {
+        "parent": {"@ref": ["0000000000000007", "Products.Archetypes.Folder"]},
+        "widget": {
+            "@reduce": {
+                "callable": {"@cls": ["old.widgets", "Text"]},
+                "args": {"@t": [1]},
+            }
+        },
+        "kind": {"@cls": ["myapp", "Kind"]},
+    },
+}
+NEW = {
+    "@cls": ["plone.app", "Foo"],
+    "@s": {
+        "parent": {"@ref": ["0000000000000007", "Products.Archetypes.Folder"]},
+        "widget": {
+            "@reduce": {
+                "callable": {"@cls": ["new.widgets", "Text"]},
+                "args": {"@t": [1]},
+            }
+        },
+        "kind": {"@cls": ["myapp", "Kind"]},
+    },
+}
+CLASSES = {"Products.Archetypes.Foo": "plone.app.Foo"}
+MODULES = {"old.widgets": "new.widgets"}
+RENAMES = zodb_json_codec.ClassRenames(CLASSES, MODULES)
+
+
+class TestClassRenames:
+    def test_no_renames_by_default(self):
+        record = zodb_json_codec.encode_zodb_record(OLD)
+        assert zodb_json_codec.decode_zodb_record(record) == OLD
+
+    def test_decode(self):
+        record = zodb_json_codec.encode_zodb_record(OLD)
+        assert zodb_json_codec.decode_zodb_record(record, renames=RENAMES) == NEW
+
+    def test_encode(self):
+        record = zodb_json_codec.encode_zodb_record(OLD, renames=RENAMES)
+        assert zodb_json_codec.decode_zodb_record(record) == NEW
+        [data] = zodb_json_codec.encode_zodb_records_batch([OLD], renames=RENAMES)
+        assert data == record
+
+    def test_ref_class(self):
+        renames = zodb_json_codec.ClassRenames(
+            {"Products.Archetypes.Folder": "plone.app.Folder"}
+        )
+        record = zodb_json_codec.encode_zodb_record(OLD)
+        state = zodb_json_codec.decode_zodb_record(record, renames=renames)["@s"]
+        assert state["parent"] == {"@ref": ["0000000000000007", "plone.app.Folder"]}
+        decoded = zodb_json_codec.decode_zodb_record_for_pg(record, renames=renames)
+        state = decoded[2]
+        assert state["parent"] == {"@ref": ["0000000000000007", "plone.app.Folder"]}
+
+    def test_record_class_for_pg(self):
+        record = zodb_json_codec.encode_zodb_record(OLD)
+        renames = zodb_json_codec.ClassRenames(CLASSES)
+        decoded = zodb_json_codec.decode_zodb_record_for_pg(record, renames=renames)
+        module, name, _, _ = decoded
+        assert (module, name) == ("plone.app", "Foo")
+
+    def test_class_entry_wins_over_module(self):
+        renames = zodb_json_codec.ClassRenames(
+            {"old.widgets.Text": "fancy.Text"}, {"old.widgets": "new.widgets"}
+        )
+        data = zodb_json_codec.dict_to_pickle({"@cls": ["old.widgets", "Text"]})
+        decoded = zodb_json_codec.pickle_to_dict(data, renames=renames)
+        assert decoded == {"@cls": ["fancy", "Text"]}
+
+    def test_renames_are_not_chained(self):
+        renames = zodb_json_codec.ClassRenames(modules={"a": "b", "b": "c"})
+        data = zodb_json_codec.dict_to_pickle({"@cls": ["a", "X"]})
+        decoded = zodb_json_codec.pickle_to_dict(data, renames=renames)
+        assert decoded == {"@cls": ["b", "X"]}
+
+    @pytest.mark.parametrize("protocol", [0, 2, 3, 4])
+    def test_encode_protocols(self, protocol):
+        value = {"@cls": ["old.widgets", "Text"]}
+        data = zodb_json_codec.dict_to_pickle(value, protocol=protocol, renames=RENAMES)
+        assert zodb_json_codec.pickle_to_dict(data) == {"@cls": ["new.widgets", "Text"]}
+
+    def test_per_call(self):
+        record = zodb_json_codec.encode_zodb_record(OLD)
+        assert zodb_json_codec.decode_zodb_record(record, renames=RENAMES) == NEW
+        assert zodb_json_codec.decode_zodb_record(record) == OLD
+        zodb_json_codec.encode_zodb_record(OLD, renames=RENAMES)
+        assert zodb_json_codec.encode_zodb_record(OLD) == record
+
+    @pytest.mark.parametrize("path", ["Foo", ".Foo", "plone."])
+    def test_invalid_class_path(self, path):
+        with pytest.raises(ValueError, match="module.Name"):
+            zodb_json_codec.ClassRenames({path: "plone.app.Foo"})
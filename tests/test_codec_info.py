@@ -0,0 +1,51 @@
+"""Capability and version introspection."""
+
+import pickletools
+import zodb_json_codec
+
+
+class TestCodecInfo:
+    def test_shape(self):
+        info = zodb_json_codec.codec_info()
+        assert set(info) >= {
+            "version",
+            "marker_format",
+            "markers",
+            "opcodes",
+            "decode_protocols",
+            "encode_protocols",
+            "known_types",
+            "features",
+        }
+        assert info["version"].count(".") == 2
+        assert info["marker_format"] == int(info["version"].split(".")[0])
+
+    def test_opcodes_are_pickletools_names(self):
+        names = {op.name for op in pickletools.opcodes}
+        info = zodb_json_codec.codec_info()
+        assert set(info["opcodes"]) <= names
+        assert "BINUNICODE" in info["opcodes"]
+
+    def test_protocols(self):
+        info = zodb_json_codec.codec_info()
+        assert 3 in info["decode_protocols"]
+        assert 0 in info["encode_protocols"]
+        assert 3 in info["encode_protocols"]
+
+    def test_known_types(self):
+        info = zodb_json_codec.codec_info()
+        assert info["known_types"]["@dt"] == "datetime.datetime"
+        assert info["known_types"]["@uuid"] == "uuid.UUID"
+        for marker in info["known_types"]:
+            assert marker in info["markers"]
+
+    def test_features(self):
+        features = zodb_json_codec.codec_info()["features"]
+        assert features["pg_sanitization"] is True
+        assert features["msgpack"] is False
+        assert all(isinstance(v, bool) for v in features.values())
+
+    def test_returns_fresh_dict(self):
+        info = zodb_json_codec.codec_info()
+        info["markers"].clear()
+        assert zodb_json_codec.codec_info()["markers"]
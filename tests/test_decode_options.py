@@ -0,0 +1,96 @@
+"""Test the compact_refs and pg_safe options of pickle_to_dict and decode_zodb_record."""
+
+import io
+import pickle
+
+import zodb_json_codec
+
+
+OID = b"\x00" * 7 + b"\x03"
+HEX = "0000000000000003"
+
+
+class Ref:
+    """Stand-in for a persistent object, pickled with a given persistent id."""
+
+    def __init__(self, pid):
+        self.pid = pid
+
+
+class RefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return obj.pid
+        return None
+
+
+class Document:
+    pass
+
+
+def dump_state(state):
+    buf = io.BytesIO()
+    RefPickler(buf, protocol=3).dump(state)
+    return buf.getvalue()
+
+
+def make_record(state, module="myapp", name="Folder"):
+    return pickle.dumps((module, name), protocol=3) + dump_state(state)
+
+
+STATE = {"doc": Ref((OID, Document)), "title": "a\x00b"}
+COMPACT = {"@ref": [HEX, f"{__name__}.Document"]}
+GENERIC = {"@ref": {"@t": [{"@b": "AAAAAAAAAAM="}, {"@cls": [__name__, "Document"]}]}}
+SANITIZED = {"@ns": "YQBi"}
+
+
+class TestDecodeZodbRecord:
+    def test_defaults(self):
+        state = zodb_json_codec.decode_zodb_record(make_record(STATE))["@s"]
+        assert state == {"doc": COMPACT, "title": "a\x00b"}
+
+    def test_generic_refs(self):
+        record = make_record(STATE)
+        state = zodb_json_codec.decode_zodb_record(record, compact_refs=False)["@s"]
+        assert state == {"doc": GENERIC, "title": "a\x00b"}
+
+    def test_pg_safe_matches_for_pg(self):
+        record = make_record(STATE)
+        state = zodb_json_codec.decode_zodb_record(record, pg_safe=True)["@s"]
+        assert state == {"doc": COMPACT, "title": SANITIZED}
+        assert state == zodb_json_codec.decode_zodb_record_for_pg(record)[2]
+
+    def test_pg_safe_btree(self):
+        record = make_record(
+            (("k", "a\x00b"),), module="BTrees.OOBTree", name="OOBucket"
+        )
+        result = zodb_json_codec.decode_zodb_record(record, pg_safe=True)
+        assert result["@s"] == {"@kv": [["k", SANITIZED]]}
+
+    def test_pg_safe_container(self):
+        record = make_record(
+            {"data": {"t": "a\x00b"}}, module="persistent.mapping", name="PersistentMapping"
+        )
+        result = zodb_json_codec.decode_zodb_record(record, pg_safe=True)
+        assert result["@s"] == {"@pmap": {"t": SANITIZED}}
+
+    def test_generic_refs_encode_back(self):
+        record = make_record(STATE)
+        result = zodb_json_codec.decode_zodb_record(record, compact_refs=False)
+        encoded = zodb_json_codec.encode_zodb_record(result)
+        expected = zodb_json_codec.decode_zodb_record(record)
+        assert zodb_json_codec.decode_zodb_record(encoded) == expected
+
+
+class TestPickleToDict:
+    def test_defaults(self):
+        result = zodb_json_codec.pickle_to_dict(dump_state(STATE))
+        assert result == {"doc": GENERIC, "title": "a\x00b"}
+
+    def test_compact_refs(self):
+        result = zodb_json_codec.pickle_to_dict(dump_state(STATE), compact_refs=True)
+        assert result == {"doc": COMPACT, "title": "a\x00b"}
+
+    def test_pg_safe(self):
+        result = zodb_json_codec.pickle_to_dict(dump_state(STATE), pg_safe=True)
+        assert result == {"doc": GENERIC, "title": SANITIZED}
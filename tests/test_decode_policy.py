@@ -0,0 +1,101 @@
+"""Class allowlist/denylist policy for decoding."""
+
+import datetime
+import io
+import json
+import pickle
+import pytest
+import zodb_json_codec
+
+
+class Legacy:
+    pass
+
+
+def make_record(state):
+    return pickle.dumps(("myapp.models", "Folder"), protocol=3) + pickle.dumps(
+        state, protocol=3
+    )
+
+
+def load_state(record):
+    f = io.BytesIO(record)
+    pickle.load(f)
+    return pickle.load(f)
+
+
+LEGACY = [__name__, "Legacy"]
+
+
+class TestDecodePolicy:
+    def test_no_policy_by_default(self):
+        result = zodb_json_codec.pickle_to_dict(pickle.dumps(Legacy, protocol=3))
+        assert result == {"@cls": LEGACY}
+
+    def test_denied_class_raises(self):
+        policy = zodb_json_codec.DecodePolicy(denied=[(__name__, "Legacy")])
+        with pytest.raises(ValueError, match=f"{__name__}.Legacy"):
+            zodb_json_codec.decode_zodb_record(make_record({"k": Legacy}), policy=policy)
+
+    def test_allowlist(self):
+        policy = zodb_json_codec.DecodePolicy(allowed=[("datetime", "*")])
+        state = {"when": datetime.date(2024, 1, 2)}
+        assert zodb_json_codec.decode_zodb_record(make_record(state), policy=policy)["@s"] == {
+            "when": {"@date": "2024-01-02"}
+        }
+        with pytest.raises(ValueError, match="decode policy"):
+            zodb_json_codec.decode_zodb_record(make_record({"k": Legacy}), policy=policy)
+
+    def test_record_class_reported_by_name(self):
+        policy = zodb_json_codec.DecodePolicy(allowed=[], on_violation="block")
+        result = zodb_json_codec.decode_zodb_record(make_record({"title": "x"}), policy=policy)
+        assert result == {"@cls": ["myapp.models", "Folder"], "@s": {"title": "x"}}
+
+    def test_block_mode(self):
+        policy = zodb_json_codec.DecodePolicy(denied=[(__name__, "*")], on_violation="block")
+        record = make_record({"k": Legacy, "items": [Legacy]})
+        result = zodb_json_codec.decode_zodb_record(record, policy=policy)
+        assert result["@s"] == {
+            "k": {"@blocked": LEGACY},
+            "items": [{"@blocked": LEGACY}],
+        }
+        _, _, state_json, _ = zodb_json_codec.decode_zodb_record_for_pg_json(
+            record, policy=policy
+        )
+        assert json.loads(state_json)["k"] == {"@blocked": LEGACY}
+        _, _, state, _ = zodb_json_codec.decode_zodb_record_for_pg(record, policy=policy)
+        assert state["k"] == {"@blocked": LEGACY}
+
+    def test_block_mode_instance(self):
+        policy = zodb_json_codec.DecodePolicy(
+            denied=[(__name__, "Legacy")], on_violation="block"
+        )
+        obj = Legacy()
+        obj.x = 1
+        result = zodb_json_codec.pickle_to_dict(pickle.dumps(obj, protocol=2), policy=policy)
+        assert result["@inst"]["@callable"] == {"@blocked": LEGACY}
+        assert result["@inst"]["@state"] == {"x": 1}
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result)).__dict__ == {"x": 1}
+
+    def test_blocked_roundtrip(self):
+        policy = zodb_json_codec.DecodePolicy(
+            denied=[(__name__, "Legacy")], on_violation="block"
+        )
+        state = {"k": Legacy}
+        result = zodb_json_codec.decode_zodb_record(make_record(state), policy=policy)
+        assert load_state(zodb_json_codec.encode_zodb_record(result)) == state
+        (encoded,) = zodb_json_codec.encode_zodb_records_batch([result])
+        assert load_state(encoded) == state
+        data = zodb_json_codec.dict_to_pickle({"@blocked": LEGACY})
+        assert pickle.loads(data) is Legacy
+
+    def test_per_call(self):
+        policy = zodb_json_codec.DecodePolicy(denied=[(__name__, "Legacy")])
+        data = pickle.dumps(Legacy, protocol=3)
+        with pytest.raises(ValueError, match="decode policy"):
+            zodb_json_codec.pickle_to_dict(data, policy=policy)
+        assert zodb_json_codec.pickle_to_dict(data) == {"@cls": LEGACY}
+
+    def test_invalid_action(self):
+        with pytest.raises(ValueError, match="on_violation"):
+            zodb_json_codec.DecodePolicy(allowed=[], on_violation="ignore")
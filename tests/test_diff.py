@@ -0,0 +1,60 @@
+"""diff_zodb_records: structured comparison of two records."""
+
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+def make_record(state, module="myapp", name="Doc"):
+    return pickle.dumps((module, name), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+class TestDiffRecords:
+    def test_identical(self):
+        record = make_record({"title": "Hello"})
+        assert zodb_json_codec.diff_zodb_records(record, record) == {
+            "added": [],
+            "removed": [],
+            "changed": [],
+        }
+
+    def test_state_changes(self):
+        a = make_record({"title": "Old", "tags": ["a", "b"], "gone": 1})
+        b = make_record({"title": "New", "tags": ["a"], "extra": {"x": None}})
+        diff = zodb_json_codec.diff_zodb_records(a, b)
+        assert diff["changed"] == [{"path": "/@s/title", "old": "Old", "new": "New"}]
+        assert diff["removed"] == [
+            {"path": "/@s/gone", "value": 1},
+            {"path": "/@s/tags/1", "value": "b"},
+        ]
+        assert diff["added"] == [{"path": "/@s/extra", "value": {"x": None}}]
+
+    def test_markers_compared_as_json(self):
+        a = make_record({"raw": b"\x00", "when": (1, 2)})
+        b = make_record({"raw": b"\x01", "when": (1, 3)})
+        diff = zodb_json_codec.diff_zodb_records(a, b)
+        assert diff["changed"] == [
+            {"path": "/@s/raw/@b", "old": "AA==", "new": "AQ=="},
+            {"path": "/@s/when/@t/1", "old": 2, "new": 3},
+        ]
+
+    def test_class_change(self):
+        a = make_record({}, name="Doc")
+        b = make_record({}, name="Folder")
+        diff = zodb_json_codec.diff_zodb_records(a, b)
+        assert diff["changed"] == [{"path": "/@cls/1", "old": "Doc", "new": "Folder"}]
+
+    def test_bucket_items_by_key(self):
+        a = make_record(((1, "one", 3, "three", 5, "five"),), "BTrees.IOBTree", "IOBucket")
+        b = make_record(((1, "one", 2, "two", 3, "THREE"),), "BTrees.IOBTree", "IOBucket")
+        diff = zodb_json_codec.diff_zodb_records(a, b)
+        assert diff == {
+            "added": [{"path": "/@s/@kv/2", "value": "two"}],
+            "removed": [{"path": "/@s/@kv/5", "value": "five"}],
+            "changed": [{"path": "/@s/@kv/3", "old": "three", "new": "THREE"}],
+        }
+
+    def test_invalid(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.diff_zodb_records(b"", make_record({}))
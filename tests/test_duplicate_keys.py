@@ -0,0 +1,109 @@
+"""Test dicts whose pickle repeats a key (the duplicate_keys= keyword)."""
+
+import asyncio
+import json
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+def short(s):
+    return b"X" + len(s).to_bytes(4, "little") + s.encode()
+
+
+# {"a": 1, "b": 2, "a": 3} as SETITEMS builds it; pickle.loads keeps
+# "a" first with the value 3
+DUPLICATES = (
+    b"\x80\x02}(" + short("a") + b"K\x01" + short("b") + b"K\x02" + short("a") + b"K\x03u."
+)
+# {1: "x", 1: "y"}
+INT_DUPLICATES = b"\x80\x02}(K\x01" + short("x") + b"K\x01" + short("y") + b"u."
+
+
+def make_record(state_pickle):
+    return pickle.dumps(("myapp", "Doc"), protocol=2) + state_pickle
+
+
+class TestLastWins:
+    def test_matches_pickle(self):
+        expected = pickle.loads(DUPLICATES)
+        assert list(expected.items()) == [("a", 3), ("b", 2)]
+        result = zodb_json_codec.pickle_to_dict(DUPLICATES)
+        assert list(result.items()) == [("a", 3), ("b", 2)]
+
+    def test_json_agrees(self):
+        text = zodb_json_codec.pickle_to_json(DUPLICATES)
+        assert list(json.loads(text).items()) == [("a", 3), ("b", 2)]
+        _, _, text, _ = zodb_json_codec.decode_zodb_record_for_pg_json(make_record(DUPLICATES))
+        assert list(json.loads(text).items()) == [("a", 3), ("b", 2)]
+
+    def test_warning(self):
+        warnings = []
+        zodb_json_codec.pickle_to_dict(DUPLICATES, warnings=warnings)
+        assert [w["code"] for w in warnings] == ["duplicate-keys"]
+        assert '"a"' in warnings[0]["message"]
+
+    def test_json_input(self):
+        data = zodb_json_codec.json_to_pickle('{"a": 1, "a": 2}')
+        assert pickle.loads(data) == {"a": 2}
+
+
+class TestError:
+    def test_dict(self):
+        with pytest.raises(zodb_json_codec.CodecError, match="duplicate dict key"):
+            zodb_json_codec.pickle_to_dict(DUPLICATES, duplicate_keys="error")
+
+    def test_json(self):
+        with pytest.raises(zodb_json_codec.CodecError, match="duplicate dict key"):
+            zodb_json_codec.pickle_to_json(DUPLICATES, duplicate_keys="error")
+
+    def test_record(self):
+        with pytest.raises(zodb_json_codec.CodecError, match="duplicate dict key"):
+            zodb_json_codec.decode_zodb_record(make_record(DUPLICATES), duplicate_keys="error")
+
+    def test_batch_async(self):
+        async def main():
+            return await zodb_json_codec.decode_batch_async(
+                [make_record(DUPLICATES)], duplicate_keys="error"
+            )
+
+        with pytest.raises(ValueError, match="duplicate dict key"):
+            asyncio.run(main())
+
+    def test_unique_keys_pass(self):
+        data = pickle.dumps({"a": 1, "b": 2}, protocol=3)
+        assert zodb_json_codec.pickle_to_dict(data, duplicate_keys="error") == {"a": 1, "b": 2}
+
+    def test_per_call(self):
+        with pytest.raises(zodb_json_codec.CodecError):
+            zodb_json_codec.pickle_to_dict(DUPLICATES, duplicate_keys="error")
+        assert zodb_json_codec.pickle_to_dict(DUPLICATES) == {"a": 3, "b": 2}
+
+
+class TestPreserve:
+    def test_pairs(self):
+        pairs = {"@d": [["a", 1], ["b", 2], ["a", 3]]}
+        result = zodb_json_codec.pickle_to_dict(DUPLICATES, duplicate_keys="preserve")
+        assert result == pairs
+        text = zodb_json_codec.pickle_to_json(DUPLICATES, duplicate_keys="preserve")
+        assert json.loads(text) == pairs
+
+    def test_roundtrip(self):
+        result = zodb_json_codec.pickle_to_dict(DUPLICATES, duplicate_keys="preserve")
+        encoded = zodb_json_codec.dict_to_pickle(result)
+        assert zodb_json_codec.pickle_to_dict(encoded, duplicate_keys="preserve") == result
+        assert pickle.loads(encoded) == pickle.loads(DUPLICATES)
+
+
+class TestNonStringKeys:
+    @pytest.mark.parametrize("mode", ["last-wins", "error", "preserve"])
+    def test_pairs_keep_every_item(self, mode):
+        result = zodb_json_codec.pickle_to_dict(INT_DUPLICATES, duplicate_keys=mode)
+        assert result == {"@d": [[1, "x"], [1, "y"]]}
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result)) == {1: "y"}
+
+
+def test_unknown_mode():
+    with pytest.raises(ValueError):
+        zodb_json_codec.pickle_to_dict(b"N.", duplicate_keys="first-wins")
@@ -0,0 +1,63 @@
+"""CodecError: where decoding or encoding failed."""
+
+import pickle
+import pytest
+import zodb_json_codec
+
+
+TRUNCATED = b"\x80\x03X\x05\x00\x00\x00a"  # BINUNICODE announcing 5 bytes
+
+
+def test_is_value_error():
+    assert issubclass(zodb_json_codec.CodecError, ValueError)
+    with pytest.raises(ValueError):
+        zodb_json_codec.pickle_to_dict(TRUNCATED)
+
+
+def test_decode_location():
+    with pytest.raises(zodb_json_codec.CodecError) as exc:
+        zodb_json_codec.pickle_to_json(TRUNCATED)
+    err = exc.value
+    assert err.reason == "unexpected end of pickle stream"
+    assert (err.offset, err.opcode, err.opcode_name) == (2, ord("X"), "BINUNICODE")
+    assert (err.context, err.context_offset) == (TRUNCATED, 0)
+    assert err.path is None
+    assert "at byte 2 (BINUNICODE), near 80 03 [58] 05" in str(err)
+
+
+def test_unknown_opcode():
+    with pytest.raises(zodb_json_codec.CodecError) as exc:
+        zodb_json_codec.pickle_to_dict(b"\x80\x03\xff.")
+    err = exc.value
+    assert (err.offset, err.opcode, err.opcode_name) == (2, 0xFF, None)
+    assert "(opcode 0xff)" in str(err)
+
+
+def test_record_state_offset():
+    record = pickle.dumps(("myapp", "Doc"), protocol=3) + b"\x80\x03)"
+    with pytest.raises(zodb_json_codec.CodecError) as exc:
+        zodb_json_codec.decode_zodb_record(record)
+    assert exc.value.offset == len(record)
+    assert exc.value.opcode is None
+
+
+def test_json_path():
+    with pytest.raises(zodb_json_codec.CodecError) as exc:
+        zodb_json_codec.json_to_pickle('{"a": [1, {"@t": [1, {"@dt": "bogus"}]}]}')
+    err = exc.value
+    assert err.path == '$.a[1]["@t"][1]'
+    assert err.offset is None and err.context is None
+    assert str(err) == f"{err.reason} at {err.path}"
+
+
+def test_dict_path():
+    with pytest.raises(zodb_json_codec.CodecError) as exc:
+        zodb_json_codec.dict_to_pickle({"a b": [1, {"@dt": "bogus"}]})
+    assert exc.value.path == '$["a b"][1]'
+
+
+def test_record_state_path():
+    record = {"@cls": ["myapp", "Doc"], "@s": {"items": [{"@dt": "bogus"}]}}
+    with pytest.raises(zodb_json_codec.CodecError) as exc:
+        zodb_json_codec.encode_zodb_record(record)
+    assert exc.value.path == '$["@s"].items[0]'
@@ -0,0 +1,83 @@
+"""Test estimate_decoded_size against the real decoded size."""
+
+import datetime
+import io
+import json
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+class Doc:
+    pass
+
+
+class RefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Doc):
+            return (b"\x00" * 7 + b"\x01", Doc)
+        return None
+
+
+def make_record(state):
+    buf = io.BytesIO()
+    pickler = RefPickler(buf, protocol=3)
+    pickler.dump((Doc, None))
+    pickler.dump(state)
+    return buf.getvalue()
+
+
+def decoded_size(record):
+    decoded = zodb_json_codec.decode_zodb_record(record)
+    return len(json.dumps(decoded, separators=(",", ":"), ensure_ascii=False).encode())
+
+
+STATE = {
+    "title": "A document",
+    "body": "Lorem ipsum \"dolor\" sit amet\n" * 20,
+    "tags": ("a", "b", "c"),
+    "created": datetime.datetime(2025, 1, 2, 3, 4, 5),
+    "parent": Doc(),
+    "children": [Doc(), Doc()],
+    "data": b"\x00\x01" * 100,
+    "counts": {"views": 12345, "ratio": 0.25, "big": 2**100},
+    "extra": {1: "one", 2: "two"},
+}
+
+
+class TestEstimate:
+    def test_close_to_decoded_size(self):
+        record = make_record(STATE)
+        estimate = zodb_json_codec.estimate_decoded_size(record)
+        exact = decoded_size(record)
+        assert abs(estimate["json_bytes"] - exact) <= exact * 0.05
+
+    def test_markers(self):
+        estimate = zodb_json_codec.estimate_decoded_size(make_record(STATE))
+        assert estimate["markers"] == {
+            "@b": 1,
+            "@bi": 1,
+            "@cls": 1,
+            "@d": 1,
+            "@dt": 1,
+            "@ref": 3,
+            "@t": 1,
+        }
+
+    def test_memo_repeats(self):
+        shared = {"x": "y" * 100}
+        small = zodb_json_codec.estimate_decoded_size(make_record({"a": shared}))
+        large = zodb_json_codec.estimate_decoded_size(make_record({"a": shared, "b": shared}))
+        assert large["json_bytes"] - small["json_bytes"] > 100
+        # "b" and a copy of the dict, its key and its value
+        assert large["nodes"] == small["nodes"] + 4
+
+    def test_single_pickle(self):
+        data = pickle.dumps([1, "two", None], protocol=3)
+        estimate = zodb_json_codec.estimate_decoded_size(data)
+        assert estimate == {"json_bytes": len('[1,"two",null]'), "nodes": 4, "markers": {}}
+
+    def test_malformed(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.estimate_decoded_size(b"\x80\x03}q\x00")
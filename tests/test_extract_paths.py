@@ -0,0 +1,54 @@
+"""extract_paths: selected fields of a record without a full conversion."""
+
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+def make_record(state, module="myapp", name="Doc"):
+    return pickle.dumps((module, name), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+class TestExtractPaths:
+    def test_fields(self):
+        record = make_record(
+            {"title": "Hello", "tags": ["a", "b"], "meta": {"size": 3}, "pos": (1, 2)}
+        )
+        result = zodb_json_codec.extract_paths(
+            record, ["@s/title", "@s/tags/1", "/@s/meta/size", "@s/pos/@t/0", "@cls"]
+        )
+        assert result == {
+            "@s/title": "Hello",
+            "@s/tags/1": "b",
+            "/@s/meta/size": 3,
+            "@s/pos/@t/0": 1,
+            "@cls": ["myapp", "Doc"],
+        }
+
+    def test_matches_decoded_record(self):
+        record = make_record({"raw": b"\x00\x01", "nums": {1, 2}, "t": "x"})
+        decoded = zodb_json_codec.decode_zodb_record(record)
+        result = zodb_json_codec.extract_paths(record, ["@s/raw", "@s/nums", "@s", ""])
+        assert result == {
+            "@s/raw": decoded["@s"]["raw"],
+            "@s/nums": decoded["@s"]["nums"],
+            "@s": decoded["@s"],
+            "": decoded,
+        }
+
+    def test_missing_paths_omitted(self):
+        record = make_record({"title": "Hello", "empty": None})
+        result = zodb_json_codec.extract_paths(
+            record, ["@s/nope", "@s/title/x", "@s/empty", "other"]
+        )
+        assert result == {"@s/empty": None}
+
+    def test_btree_bucket(self):
+        record = make_record(((1, "one", 2, "two"),), "BTrees.IOBTree", "IOBucket")
+        result = zodb_json_codec.extract_paths(record, ["@s/@kv/0/1", "@s/@kv/1"])
+        assert result == {"@s/@kv/0/1": "one", "@s/@kv/1": [2, "two"]}
+
+    def test_invalid(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.extract_paths(b"\x80\x03N.", ["@s"])
@@ -0,0 +1,203 @@
+"""decode_transaction and open_filestorage: FileStorage files."""
+
+import pickle
+import struct
+
+import pytest
+import zodb_json_codec
+
+TID = b"\x03\xe0\x00\x00\x00\x00\x00\x42"
+
+
+def make_record(state, module="myapp", name="Doc"):
+    return pickle.dumps((module, name), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+def data_record(oid, data, prev=0, tloc=4, backpointer=None):
+    header = struct.pack(">8s8sQQHQ", oid, TID, prev, tloc, 0, len(data))
+    if backpointer is not None:
+        return header + struct.pack(">Q", backpointer)
+    return header + data
+
+
+def transaction(
+    records, user=b"/ alice", description=b"Edit", extension=b"", status=b" "
+):
+    body = b"".join(records)
+    tlen = 23 + len(user) + len(description) + len(extension) + len(body)
+    header = struct.pack(
+        ">8sQcHHH", TID, tlen, status, len(user), len(description), len(extension)
+    )
+    return header + user + description + extension + body + struct.pack(">Q", tlen)
+
+
+class TestDecodeTransaction:
+    def test_objects(self):
+        oid1, oid2 = b"\x00" * 7 + b"\x01", b"\x00" * 7 + b"\x02"
+        txn = transaction(
+            [
+                data_record(oid1, make_record({"title": "Hello"}), prev=1234),
+                data_record(oid2, b"", backpointer=5678),
+            ]
+        )
+        assert zodb_json_codec.decode_transaction(txn) == [
+            {
+                "oid": oid1,
+                "tid": TID,
+                "prev": 1234,
+                "tloc": 4,
+                "backpointer": None,
+                "record": {"@cls": ["myapp", "Doc"], "@s": {"title": "Hello"}},
+            },
+            {
+                "oid": oid2,
+                "tid": TID,
+                "prev": 0,
+                "tloc": 4,
+                "backpointer": 5678,
+                "record": None,
+            },
+        ]
+
+    def test_empty_transaction(self):
+        assert zodb_json_codec.decode_transaction(transaction([])) == []
+
+    def test_trailing_bytes_ignored(self):
+        txn = transaction([data_record(b"\x00" * 8, make_record({}))])
+        assert len(zodb_json_codec.decode_transaction(txn + b"next")) == 1
+
+    def test_truncated(self):
+        txn = transaction([data_record(b"\x00" * 8, make_record({}))])
+        with pytest.raises(ValueError, match="truncated transaction"):
+            zodb_json_codec.decode_transaction(txn[:-1])
+
+    def test_bad_record_names_oid(self):
+        txn = transaction([data_record(b"\x00" * 7 + b"\x09", b"not a pickle")])
+        with pytest.raises(ValueError, match="oid 0x0000000000000009"):
+            zodb_json_codec.decode_transaction(txn)
+
+
+OID1 = b"\x00" * 7 + b"\x01"
+OID2 = b"\x00" * 7 + b"\x02"
+# Position of the first data record of a transaction at position 4 with the
+# default user and description
+FIRST_DATA = 4 + 23 + len(b"/ alice") + len(b"Edit")
+
+
+def write_storage(path, transactions, magic=b"FS21"):
+    """Write a Data.fs of transactions, each a list of data_record() kwargs."""
+    data = magic
+    for records in transactions:
+        data += transaction([data_record(tloc=len(data), **r) for r in records])
+    path.write_bytes(data)
+    return path
+
+
+class TestOpenFileStorage:
+    def test_raw_records(self, tmp_path):
+        first, second = make_record({"v": 1}), make_record({"v": 2})
+        path = write_storage(
+            tmp_path / "Data.fs",
+            [
+                [dict(oid=OID1, data=first), dict(oid=OID2, data=second)],
+                [dict(oid=OID1, data=b"", backpointer=FIRST_DATA)],
+                [dict(oid=OID2, data=b"", backpointer=0)],
+            ],
+        )
+        assert list(zodb_json_codec.open_filestorage(path)) == [
+            (OID1, TID, first),
+            (OID2, TID, second),
+            (OID1, TID, first),
+            (OID2, TID, None),
+        ]
+
+    def test_decode(self, tmp_path):
+        path = write_storage(
+            tmp_path / "Data.fs",
+            [[dict(oid=OID1, data=make_record({"title": "Hello"}))]],
+            magic=b"FS30",
+        )
+        assert list(zodb_json_codec.open_filestorage(str(path), decode=True)) == [
+            (OID1, TID, {"@cls": ["myapp", "Doc"], "@s": {"title": "Hello"}}),
+        ]
+
+    def test_decode_lenient(self, tmp_path):
+        state = b"\x80\x03}X\x01\x00\x00\x00a\xff."
+        record = pickle.dumps(("myapp", "Doc"), protocol=3) + state
+        path = write_storage(tmp_path / "Data.fs", [[dict(oid=OID1, data=record)]])
+        with pytest.raises(ValueError, match="oid 0x0000000000000001"):
+            list(zodb_json_codec.open_filestorage(path, decode=True))
+        [(_, _, decoded)] = zodb_json_codec.open_filestorage(path, decode=True, lenient=True)
+        assert "@pkl" in decoded["@s"]
+
+    def test_decode_ref_format(self, tmp_path):
+        # {"r": <persistent ref (OID2, None)>}
+        state = b"\x80\x03}X\x01\x00\x00\x00rC\x08" + OID2 + b"N\x86Qs."
+        record = pickle.dumps(("myapp", "Doc"), protocol=3) + state
+        path = write_storage(tmp_path / "Data.fs", [[dict(oid=OID1, data=record)]])
+        [(_, _, decoded)] = zodb_json_codec.open_filestorage(path, decode=True, ref_format="int")
+        assert decoded["@s"]["r"] == {"@ref": 2}
+
+    def test_empty_file(self, tmp_path):
+        path = write_storage(tmp_path / "Data.fs", [])
+        assert list(zodb_json_codec.open_filestorage(path)) == []
+
+    def test_not_a_filestorage(self, tmp_path):
+        path = tmp_path / "Data.fs"
+        path.write_bytes(b"ZEC4")
+        with pytest.raises(ValueError, match="not a FileStorage file"):
+            zodb_json_codec.open_filestorage(path)
+
+    def test_missing_file(self, tmp_path):
+        with pytest.raises(ValueError):
+            zodb_json_codec.open_filestorage(tmp_path / "missing.fs")
+
+    def test_commit_in_progress_ends_iteration(self, tmp_path):
+        path = write_storage(
+            tmp_path / "Data.fs", [[dict(oid=OID1, data=make_record({}))]]
+        )
+        with open(path, "ab") as f:
+            f.write(transaction([], status=b"c"))
+        assert len(list(zodb_json_codec.open_filestorage(path))) == 1
+
+    def test_truncated_file(self, tmp_path):
+        path = write_storage(
+            tmp_path / "Data.fs",
+            [
+                [dict(oid=OID1, data=make_record({}))],
+                [dict(oid=OID2, data=make_record({}))],
+            ],
+        )
+        path.write_bytes(path.read_bytes()[:-1])
+        records = zodb_json_codec.open_filestorage(path)
+        assert next(records)[0] == OID1
+        with pytest.raises(ValueError, match="truncated transaction"):
+            next(records)
+        assert list(records) == []
+
+    def test_bad_backpointer(self, tmp_path):
+        path = write_storage(
+            tmp_path / "Data.fs",
+            [
+                [dict(oid=OID1, data=make_record({}))],
+                [dict(oid=OID2, data=b"", backpointer=FIRST_DATA)],
+            ],
+        )
+        records = zodb_json_codec.open_filestorage(path)
+        next(records)
+        with pytest.raises(ValueError, match="another object"):
+            next(records)
+
+    def test_blob_serial(self, tmp_path):
+        blob = b"\x80\x03cZODB.blob\nBlob\nq\x00.\x80\x03N."
+        path = write_storage(tmp_path / "Data.fs", [[dict(oid=OID1, data=blob)]])
+        [(_, _, record)] = zodb_json_codec.open_filestorage(path, decode=True)
+        assert record["@blob"] is True
+        assert record["@serial"] == TID.hex()
+
+    def test_bad_record_names_oid(self, tmp_path):
+        path = write_storage(
+            tmp_path / "Data.fs", [[dict(oid=OID2, data=b"not a pickle")]]
+        )
+        with pytest.raises(ValueError, match="oid 0x0000000000000002"):
+            list(zodb_json_codec.open_filestorage(path, decode=True))
@@ -0,0 +1,66 @@
+"""oid_to_hex / hex_to_oid / tid_to_timestamp / timestamp_to_tid."""
+
+import calendar
+import struct
+import time
+
+import pytest
+import zodb_json_codec
+
+# 2024-05-01T12:30:15.249999 (see the @tid marker)
+TID = bytes.fromhex("03f84a6e41111111")
+
+
+def reference_tid(t):
+    """TimeStamp(*time.gmtime(t)[:5] + (t % 60,)).raw(), in Python."""
+    y, mo, d, h, mi = time.gmtime(t)[:5]
+    high = ((((y - 1900) * 12 + mo - 1) * 31 + d - 1) * 24 + h) * 60 + mi
+    return struct.pack(">II", high, int((t % 60) / (60 / 2**32)))
+
+
+class TestOids:
+    def test_oid_to_hex(self):
+        assert zodb_json_codec.oid_to_hex(b"\x00" * 7 + b"\x2a") == "000000000000002a"
+        assert zodb_json_codec.oid_to_hex(42) == "000000000000002a"
+
+    def test_hex_to_oid(self):
+        assert zodb_json_codec.hex_to_oid("000000000000002A") == b"\x00" * 7 + b"\x2a"
+        assert zodb_json_codec.hex_to_oid("0x2a") == b"\x00" * 7 + b"\x2a"
+
+    def test_roundtrip_with_ref_markers(self):
+        oid = b"\x01\x02\x03\x04\x05\x06\x07\x08"
+        assert zodb_json_codec.hex_to_oid(zodb_json_codec.oid_to_hex(oid)) == oid
+
+    def test_invalid(self):
+        with pytest.raises(ValueError, match="8 bytes"):
+            zodb_json_codec.oid_to_hex(b"\x01")
+        for bad in ["", "xyz", "1" * 17]:
+            with pytest.raises(ValueError, match="invalid oid hex"):
+                zodb_json_codec.hex_to_oid(bad)
+
+
+class TestTids:
+    def test_tid_to_timestamp(self):
+        expected = calendar.timegm((2024, 5, 1, 12, 30, 15)) + 0.25
+        result = zodb_json_codec.tid_to_timestamp(TID)
+        assert result == pytest.approx(expected, abs=1e-5)
+
+    def test_timestamp_to_tid_matches_timestamp(self):
+        for t in [0.0, 951782400.5, 1714566615.25, 1760000000.123456]:
+            assert zodb_json_codec.timestamp_to_tid(t) == reference_tid(t)
+
+    def test_roundtrip(self):
+        t = 1714566615.25
+        tid = zodb_json_codec.timestamp_to_tid(t)
+        assert zodb_json_codec.tid_to_timestamp(tid) == pytest.approx(t, abs=1e-6)
+
+    def test_tids_sort_by_time(self):
+        tids = [zodb_json_codec.timestamp_to_tid(t) for t in (1e9, 1e9 + 0.001, 2e9)]
+        assert tids == sorted(tids)
+
+    def test_invalid(self):
+        with pytest.raises(ValueError, match="8 bytes"):
+            zodb_json_codec.tid_to_timestamp(b"\x01")
+        for bad in [-3e9, float("nan"), float("inf")]:
+            with pytest.raises(ValueError, match="out of TID range"):
+                zodb_json_codec.timestamp_to_tid(bad)
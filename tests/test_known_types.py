@@ -4,13 +4,17 @@ These types are commonly stored as inline values in ZODB object state dicts.
 Instead of generic @reduce JSON, they get human-readable, queryable forms.
 """
 
+from collections import OrderedDict
+from collections import defaultdict
 from datetime import date
 from datetime import datetime
 from datetime import time
 from datetime import timedelta
 from datetime import timezone
 from decimal import Decimal
+from fractions import Fraction
 
+import io
 import json
 import pickle
 import pytest
@@ -275,6 +279,153 @@ class TestDecimal:
         assert restored.is_nan()
 
 
+class TestComplex:
+    @pytest.mark.parametrize("protocol", [0, 2, 3, 4])
+    def test_roundtrip(self, protocol):
+        c = complex(1.5, -2.0)
+        data = pickle.dumps(c, protocol=protocol)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result == {"@complex": [1.5, -2.0]}
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle(result))
+        assert restored == c
+        assert type(restored) is complex
+
+    def test_json_roundtrip(self):
+        data = pickle.dumps({"z": 3j}, protocol=3)
+        json_str = zodb_json_codec.pickle_to_json(data)
+        assert json.loads(json_str) == {"z": {"@complex": [0.0, 3.0]}}
+        assert pickle.loads(zodb_json_codec.json_to_pickle(json_str)) == {"z": 3j}
+
+    def test_infinite_part_uses_generic_form(self):
+        data = pickle.dumps(complex(float("inf"), 1.0), protocol=3)
+        assert "@reduce" in zodb_json_codec.pickle_to_dict(data)
+
+
+class TestFraction:
+    @pytest.mark.parametrize(
+        "val", [Fraction(3, 7), Fraction(-1, 3), Fraction(5), Fraction(10**30, 7)]
+    )
+    def test_roundtrip(self, val):
+        data = pickle.dumps(val, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result == {"@frac": str(val)}
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle(result))
+        assert restored == val
+        assert type(restored) is Fraction
+
+    def test_json_roundtrip(self):
+        data = pickle.dumps([Fraction(3, 7)], protocol=2)
+        json_str = zodb_json_codec.pickle_to_json(data)
+        assert json.loads(json_str) == [{"@frac": "3/7"}]
+        assert pickle.loads(zodb_json_codec.json_to_pickle(json_str)) == [
+            Fraction(3, 7)
+        ]
+
+    def test_string_form(self):
+        # Older Pythons pickle Fraction as Fraction(str(self))
+        data = b"\x80\x03cfractions\nFraction\nX\x03\x00\x00\x003/7\x85R."
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result == {"@frac": "3/7"}
+
+    def test_invalid_rejected(self):
+        with pytest.raises(ValueError, match="@frac"):
+            zodb_json_codec.dict_to_pickle({"@frac": "1/0"})
+
+
+def zdt_pickle(state, protocol=3):
+    """A Zope DateTime pickle: NEWOBJ of DateTime.DateTime.DateTime + BUILD."""
+    body = pickle.dumps(state, protocol=protocol)[2:-1]
+    return (
+        bytes([0x80, protocol])
+        + b"cDateTime.DateTime\nDateTime\n)\x81"
+        + body
+        + b"b."
+    )
+
+
+class TestZopeDateTime:
+    MICROS = 1709284830000000  # 2024-03-01T09:20:30Z
+
+    def test_decode(self):
+        result = zodb_json_codec.pickle_to_dict(
+            zdt_pickle((self.MICROS, False, "GMT+1"))
+        )
+        assert result == {
+            "@zdt": "2024-03-01T10:20:30+01:00",
+            "@zdt_raw": [self.MICROS, False, "GMT+1"],
+        }
+
+    @pytest.mark.parametrize("tz", ["GMT+1", "UTC", "Europe/Vienna"])
+    def test_roundtrip_exact(self, tz):
+        data = zdt_pickle((self.MICROS + 123, False, tz))
+        result = zodb_json_codec.pickle_to_dict(data)
+        back = zodb_json_codec.dict_to_pickle(result)
+        assert zodb_json_codec.pickle_to_dict(back) == result
+        json_str = zodb_json_codec.pickle_to_json(data)
+        back = zodb_json_codec.json_to_pickle(json_str)
+        assert zodb_json_codec.pickle_to_json(back) == json_str
+
+    def test_record_fields(self):
+        record = (
+            pickle.dumps(("plone.app.contenttypes.content", "Document"), protocol=3)
+            + b"\x80\x03}X\x0d\x00\x00\x00creation_date"
+            + zdt_pickle((self.MICROS, False, "GMT+1"))[2:-1]
+            + b"s."
+        )
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert result["@s"]["creation_date"]["@zdt"] == "2024-03-01T10:20:30+01:00"
+        encoded = zodb_json_codec.encode_zodb_record(result)
+        assert zodb_json_codec.decode_zodb_record(encoded) == result
+
+    def test_iso_only(self):
+        data = zodb_json_codec.dict_to_pickle({"@zdt": "2024-03-01T10:20:30+01:00"})
+        assert zodb_json_codec.pickle_to_dict(data) == {
+            "@zdt": "2024-03-01T10:20:30+01:00",
+            "@zdt_raw": [self.MICROS, False, "GMT+1"],
+        }
+
+
+class TestProvides:
+    # directlyProvides(doc, IFeatured) on a Document, as stored in
+    # __provides__: Provides(Document, IFeatured)
+    PROVIDES = (
+        b"czope.interface.declarations\nProvides\n"
+        b"cmyapp.content\nDocument\ncmyapp.interfaces\nIFeatured\n\x86R"
+    )
+
+    def record(self):
+        return (
+            b"\x80\x03cmyapp.content\nDocument\nN\x86."
+            b"\x80\x03}X\x0c\x00\x00\x00__provides__" + self.PROVIDES + b"s."
+        )
+
+    def test_decode(self):
+        result = zodb_json_codec.pickle_to_dict(b"\x80\x03" + self.PROVIDES + b".")
+        assert result == {
+            "@provides": ["myapp.content.Document", "myapp.interfaces.IFeatured"]
+        }
+
+    def test_record_roundtrip(self):
+        record = self.record()
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert result["@s"]["__provides__"] == {
+            "@provides": ["myapp.content.Document", "myapp.interfaces.IFeatured"]
+        }
+        encoded = zodb_json_codec.encode_zodb_record(result)
+        assert b"zope.interface.declarations\nProvides\n" in encoded
+        assert zodb_json_codec.decode_zodb_record(encoded) == result
+
+    def test_json_roundtrip(self):
+        data = b"\x80\x03" + self.PROVIDES + b"."
+        json_str = zodb_json_codec.pickle_to_json(data)
+        back = zodb_json_codec.json_to_pickle(json_str)
+        assert zodb_json_codec.pickle_to_json(back) == json_str
+
+    def test_empty_rejected(self):
+        with pytest.raises(ValueError, match="@provides"):
+            zodb_json_codec.dict_to_pickle({"@provides": []})
+
+
 class TestUUID:
     def test_format(self):
         u = uuid.UUID("12345678-1234-5678-1234-567812345678")
@@ -353,3 +504,260 @@ class TestFrozenset:
         json_str = zodb_json_codec.pickle_to_json(data)
         restored = pickle.loads(zodb_json_codec.json_to_pickle(json_str))
         assert restored == s
+
+
+TID = bytes.fromhex("03f84a6e41111111")  # 2024-05-01 12:30:15.249999 UTC
+TIMESTAMP_PICKLE = (
+    b"\x80\x03cpersistent.TimeStamp\nTimeStamp\nC\x08" + TID + b"\x85R."
+)
+
+
+class TestTid:
+    def test_timestamp_format(self):
+        result = json.loads(zodb_json_codec.pickle_to_json(TIMESTAMP_PICKLE))
+        assert result == {
+            "@tid": [
+                "03f84a6e41111111",
+                "2024-05-01T12:30:15.249999",
+                "persistent.TimeStamp",
+            ]
+        }
+
+    def test_timestamp_dict_format(self):
+        result = zodb_json_codec.pickle_to_dict(TIMESTAMP_PICKLE)
+        assert result["@tid"][0] == "03f84a6e41111111"
+        assert result["@tid"][2] == "persistent.TimeStamp"
+
+    def test_timestamp_roundtrip_byte_exact(self):
+        json_str = zodb_json_codec.pickle_to_json(TIMESTAMP_PICKLE)
+        assert zodb_json_codec.json_to_pickle(json_str) == TIMESTAMP_PICKLE
+        d = zodb_json_codec.pickle_to_dict(TIMESTAMP_PICKLE)
+        assert zodb_json_codec.pickle_to_dict(zodb_json_codec.dict_to_pickle(d)) == d
+
+    def test_real_timestamp(self):
+        TimeStamp = pytest.importorskip("persistent.TimeStamp").TimeStamp
+        ts = TimeStamp(2024, 5, 1, 12, 30, 15.25)
+        data = pickle.dumps({"ts": ts}, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result["ts"]["@tid"][0] == ts.raw().hex()
+        assert result["ts"]["@tid"][1].startswith("2024-05-01T12:30:15.2")
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle(result))
+        assert restored["ts"] == ts
+
+    def test_raw_tid_off_by_default(self):
+        data = pickle.dumps({"serial": TID}, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert "@b" in result["serial"]
+
+    def test_raw_tid_detection(self):
+        oid = b"\x00" * 7 + b"\x01"
+        data = pickle.dumps({"serial": TID, "oid": oid}, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data, detect_raw_tids=True)
+        assert result["serial"] == {
+            "@tid": ["03f84a6e41111111", "2024-05-01T12:30:15.249999"]
+        }
+        # Implausible dates (e.g. small oids) stay plain bytes
+        assert "@b" in result["oid"]
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle(result))
+        assert restored == {"serial": TID, "oid": oid}
+        json_str = zodb_json_codec.pickle_to_json(data, detect_raw_tids=True)
+        assert json.loads(json_str)["serial"]["@tid"][0] == TID.hex()
+        assert pickle.loads(zodb_json_codec.json_to_pickle(json_str))["serial"] == TID
+
+    def test_raw_tid_detection_per_call(self):
+        data = pickle.dumps({"serial": TID}, protocol=3)
+        record = pickle.dumps(("myapp", "Doc"), protocol=3) + data
+        result = zodb_json_codec.decode_zodb_record(record, detect_raw_tids=True)
+        assert "@tid" in result["@s"]["serial"]
+        assert "@b" in zodb_json_codec.decode_zodb_record(record)["@s"]["serial"]
+
+
+def container_record(module, name, state, protocol=3):
+    return pickle.dumps((module, name), protocol=protocol) + pickle.dumps(
+        state, protocol=protocol
+    )
+
+
+def record_state(data):
+    """The state pickle of a ZODB record, unpickled."""
+    f = io.BytesIO(data)
+    pickle.load(f)
+    return pickle.load(f)
+
+
+class TestPersistentContainers:
+    PMAP = ("persistent.mapping", "PersistentMapping")
+    PLIST = ("persistent.list", "PersistentList")
+
+    @pytest.mark.parametrize("protocol", [2, 3, 4])
+    def test_mapping_record(self, protocol):
+        state = {"data": {"title": "Hello", "count": 42}}
+        data = container_record(*self.PMAP, state, protocol)
+        result = zodb_json_codec.decode_zodb_record(data)
+        assert result == {
+            "@cls": list(self.PMAP),
+            "@s": {"@pmap": {"title": "Hello", "count": 42}},
+        }
+        assert record_state(zodb_json_codec.encode_zodb_record(result)) == state
+
+    def test_list_record(self):
+        state = {"data": [1, "two", None]}
+        data = container_record(*self.PLIST, state)
+        result = zodb_json_codec.decode_zodb_record(data)
+        assert result["@s"] == {"@plist": [1, "two", None]}
+        assert record_state(zodb_json_codec.encode_zodb_record(result)) == state
+        [encoded] = zodb_json_codec.encode_zodb_records_batch([result])
+        assert record_state(encoded) == state
+
+    def test_pg_paths(self):
+        data = container_record(*self.PMAP, {"data": {"a": "x\x00y"}})
+        _, _, state, _ = zodb_json_codec.decode_zodb_record_for_pg(data)
+        assert list(state) == ["@pmap"]
+        assert "@ns" in state["@pmap"]["a"]
+        _, _, json_str, _ = zodb_json_codec.decode_zodb_record_for_pg_json(data)
+        assert json.loads(json_str) == state
+
+    def test_non_string_keys(self):
+        state = {"data": {1: "one"}}
+        result = zodb_json_codec.decode_zodb_record(container_record(*self.PMAP, state))
+        assert result["@s"] == {"@pmap": {"@d": [[1, "one"]]}}
+        assert record_state(zodb_json_codec.encode_zodb_record(result)) == state
+
+    def test_other_shapes_unchanged(self):
+        for cls, state in [
+            (self.PMAP, {"data": {}, "extra": 1}),
+            (self.PMAP, {"data": [1]}),
+            (self.PLIST, {"data": {}}),
+            (("myapp", "PersistentMapping"), {"data": {}}),
+        ]:
+            result = zodb_json_codec.decode_zodb_record(container_record(*cls, state))
+            assert result["@s"] == state
+
+    def test_legacy_state_still_encodes(self):
+        record = {"@cls": list(self.PMAP), "@s": {"data": {"a": 1}}}
+        encoded = zodb_json_codec.encode_zodb_record(record)
+        assert record_state(encoded) == {"data": {"a": 1}}
+
+    def test_wrong_marker_type(self):
+        record = {"@cls": list(self.PLIST), "@s": {"@plist": {"a": 1}}}
+        with pytest.raises(ValueError, match="@plist must hold a list"):
+            zodb_json_codec.encode_zodb_record(record)
+
+    def test_inline_marker(self):
+        inline = {"@pmap": {"a": 1}}
+        data = zodb_json_codec.dict_to_pickle({"child": inline})
+        assert zodb_json_codec.pickle_to_dict(data) == {"child": inline}
+        json_str = zodb_json_codec.pickle_to_json(data)
+        assert json.loads(json_str) == {"child": inline}
+        assert zodb_json_codec.json_to_pickle(json_str) == data
+
+
+class TestRelationValue:
+    REL = ("z3c.relationfield.relation", "RelationValue")
+    STATE = {
+        "__parent__": None,
+        "from_attribute": "relatedItems",
+        "from_object": None,
+        "to_id": 1938572034,
+    }
+
+    @pytest.mark.parametrize("protocol", [2, 3])
+    def test_record(self, protocol):
+        data = container_record(*self.REL, self.STATE, protocol)
+        result = zodb_json_codec.decode_zodb_record(data)
+        assert result == {"@cls": list(self.REL), "@s": {"@rel": self.STATE}}
+        assert record_state(zodb_json_codec.encode_zodb_record(result)) == self.STATE
+        [encoded] = zodb_json_codec.encode_zodb_records_batch([result])
+        assert record_state(encoded) == self.STATE
+
+    def test_pg_json(self):
+        data = container_record(*self.REL, self.STATE)
+        _, _, json_str, _ = zodb_json_codec.decode_zodb_record_for_pg_json(data)
+        assert json.loads(json_str) == {"@rel": self.STATE}
+
+    def test_without_to_id_unchanged(self):
+        state = {"from_attribute": "relatedItems", "to_id": None}
+        result = zodb_json_codec.decode_zodb_record(container_record(*self.REL, state))
+        assert result["@s"] == state
+
+    def test_inline_marker(self):
+        inline = {"@rel": {"to_id": 7, "from_attribute": "image"}}
+        data = zodb_json_codec.dict_to_pickle({"rel": inline})
+        assert zodb_json_codec.pickle_to_dict(data) == {"rel": inline}
+        json_str = zodb_json_codec.pickle_to_json(data)
+        assert json.loads(json_str) == {"rel": inline}
+
+
+class TestOrderedDict:
+    @pytest.mark.parametrize("protocol", [0, 2, 3, 4])
+    def test_roundtrip(self, protocol):
+        od = OrderedDict([("b", 2), ("a", 1), ("c", [3])])
+        data = pickle.dumps(od, protocol=protocol)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result == {"@odict": [["b", 2], ["a", 1], ["c", [3]]]}
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle(result))
+        assert type(restored) is OrderedDict
+        assert list(restored.items()) == list(od.items())
+
+    def test_json_path(self):
+        od = OrderedDict([("z", None), ("a", {"nested": True})])
+        json_str = zodb_json_codec.pickle_to_json(pickle.dumps({"od": od}, protocol=3))
+        assert json.loads(json_str) == {"od": {"@odict": [["z", None], ["a", {"nested": True}]]}}
+        restored = pickle.loads(zodb_json_codec.json_to_pickle(json_str))
+        assert list(restored["od"].items()) == list(od.items())
+
+    def test_empty(self):
+        data = pickle.dumps(OrderedDict(), protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result == {"@odict": []}
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result)) == OrderedDict()
+
+    def test_python2_form(self):
+        # Python 2: collections.OrderedDict([[u'a', 1], [u'b', 2]])
+        data = b"ccollections\nOrderedDict\n((lp0\n(lp1\nVa\naI1\naa(lp2\nVb\naI2\naatR."
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result == {"@odict": [["a", 1], ["b", 2]]}
+
+
+class TestDefaultDict:
+    @pytest.mark.parametrize("protocol", [3, 4])
+    def test_roundtrip(self, protocol):
+        dd = defaultdict(list, {"tags": ["x", "y"], "empty": []})
+        data = pickle.dumps(dd, protocol=protocol)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result == {
+            "@ddict": {
+                "factory": {"@cls": ["builtins", "list"]},
+                "items": {"tags": ["x", "y"], "empty": []},
+            }
+        }
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle(result))
+        assert type(restored) is defaultdict
+        assert restored.default_factory is list
+        assert restored == dd
+
+    def test_python2_factory_name(self):
+        dd = defaultdict(list, {"a": [1]})
+        result = zodb_json_codec.pickle_to_dict(pickle.dumps(dd, protocol=2))
+        assert result["@ddict"]["factory"] == {"@cls": ["__builtin__", "list"]}
+        assert result["@ddict"]["items"] == {"a": [1]}
+
+    def test_no_factory_and_int_keys(self):
+        dd = defaultdict(None, {1: "one"})
+        json_str = zodb_json_codec.pickle_to_json(pickle.dumps(dd, protocol=3))
+        assert json.loads(json_str) == {
+            "@ddict": {"factory": None, "items": {"@d": [[1, "one"]]}}
+        }
+        restored = pickle.loads(zodb_json_codec.json_to_pickle(json_str))
+        assert restored.default_factory is None
+        assert restored == dd
+
+    def test_in_record_state(self):
+        state = {"counts": defaultdict(int, {"a": 1})}
+        record = pickle.dumps(("myapp", "Stats"), protocol=3) + pickle.dumps(
+            state, protocol=3
+        )
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert result["@s"]["counts"]["@ddict"]["items"] == {"a": 1}
+        encoded = zodb_json_codec.encode_zodb_record(result)
+        assert record_state(encoded) == state
@@ -0,0 +1,77 @@
+"""Test record linting (lint_record)."""
+
+import ipaddress
+import pickle
+import pytest
+import zodb_json_codec
+
+
+def make_record(state, protocol=3):
+    return pickle.dumps(("myapp.models", "Doc"), protocol=protocol) + pickle.dumps(
+        state, protocol=protocol
+    )
+
+
+def codes(warnings):
+    return [w["code"] for w in warnings]
+
+
+class TestLintRecord:
+    def test_clean_record(self):
+        record = make_record({"title": "Hello", "tags": ["a", "b"]})
+        assert zodb_json_codec.lint_record(record) == []
+
+    def test_huge_string(self):
+        record = make_record({"body": "x" * 100})
+        [w] = zodb_json_codec.lint_record(record, max_string_len=10)
+        assert w == {
+            "code": "huge-string",
+            "path": "body",
+            "message": "string of 100 bytes",
+        }
+
+    def test_deep_nesting(self):
+        state = []
+        for _ in range(20):
+            state = [state]
+        warnings = zodb_json_codec.lint_record(make_record(state), max_depth=8)
+        assert codes(warnings) == ["deep-nesting"]
+
+    def test_non_string_keys(self):
+        record = make_record({"a": 1, 2: "b", (3, 4): "c"})
+        [w] = zodb_json_codec.lint_record(record)
+        assert w["code"] == "non-string-key"
+        assert w["message"] == "2 of 3 dict keys are not strings"
+
+    def test_legacy_opcodes(self):
+        record = make_record({"a": 1, "b": 2.5}, protocol=0)
+        warnings = zodb_json_codec.lint_record(record)
+        assert set(codes(warnings)) == {"legacy-opcode"}
+        messages = " ".join(w["message"] for w in warnings)
+        assert "INT" in messages
+        assert "FLOAT" in messages
+
+    def test_unknown_class(self):
+        # ipaddress pickles via REDUCE and has no typed marker
+        record = make_record({"host": ipaddress.IPv4Address("10.0.0.1")})
+        [w] = zodb_json_codec.lint_record(record)
+        assert w["code"] == "unknown-class"
+        assert w["path"] == "host"
+        assert "ipaddress.IPv4Address" in w["message"]
+
+    def test_known_types_not_reported(self):
+        import datetime
+        import decimal
+
+        record = make_record(
+            {
+                "when": datetime.datetime(2024, 1, 1, 12, 0),
+                "amount": decimal.Decimal("1.5"),
+                "tags": {"a", "b"},
+            }
+        )
+        assert zodb_json_codec.lint_record(record) == []
+
+    def test_malformed_record(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.lint_record(b"\x80\x03N")
@@ -0,0 +1,70 @@
+"""Test configure_logging — tracing spans forwarded to Python logging."""
+
+import datetime
+import logging
+import pickle
+import pytest
+import zodb_json_codec
+
+
+class ListHandler(logging.Handler):
+    def __init__(self):
+        super().__init__(level=logging.DEBUG)
+        self.records = []
+
+    def emit(self, record):
+        self.records.append(record)
+
+
+def make_record(state):
+    return pickle.dumps(("myapp", "Doc"), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+class TestConfigureLogging:
+    def setup_method(self, method):
+        self.handler = ListHandler()
+        self.logger = logging.getLogger("zodb_json_codec.test")
+        self.logger.setLevel(logging.DEBUG)
+        self.logger.addHandler(self.handler)
+
+    def teardown_method(self, method):
+        zodb_json_codec.configure_logging(level=None)
+        self.logger.removeHandler(self.handler)
+
+    def test_decode_span_fields(self):
+        zodb_json_codec.configure_logging(logger="zodb_json_codec.test")
+        data = make_record({"title": "Hello"})
+        zodb_json_codec.decode_zodb_record(data)
+        [rec] = [r for r in self.handler.records if r.msg.startswith("decode_zodb_record ")]
+        assert rec.levelno == logging.DEBUG
+        fields = rec.tracing_fields
+        assert fields["size"] == len(data)
+        assert (fields["module"], fields["name"]) == ("myapp", "Doc")
+        assert fields["elapsed_us"] >= 0
+
+    def test_encode_and_pg_json_spans(self):
+        zodb_json_codec.configure_logging(logger="zodb_json_codec.test")
+        data = make_record({"n": 1})
+        zodb_json_codec.decode_zodb_record_for_pg_json(data)
+        out = zodb_json_codec.encode_zodb_record({"@cls": ["myapp", "Doc"], "@s": {"n": 1}})
+        names = [r.msg.split()[0] for r in self.handler.records]
+        assert names == ["decode_zodb_record_for_pg_json", "encode_zodb_record"]
+        assert self.handler.records[1].tracing_fields["size"] == len(out)
+
+    def test_reduce_fallback_event(self):
+        zodb_json_codec.configure_logging(logger="zodb_json_codec.test")
+        zodb_json_codec.decode_zodb_record(make_record({"d": datetime.timezone.utc}))
+        messages = [r.getMessage() for r in self.handler.records]
+        assert any(m.startswith("no typed marker, using @reduce") for m in messages), messages
+
+    def test_level_filter_and_disable(self):
+        zodb_json_codec.configure_logging(level="INFO", logger="zodb_json_codec.test")
+        zodb_json_codec.decode_zodb_record(make_record({}))
+        assert self.handler.records == []
+        zodb_json_codec.configure_logging(level=None)
+        zodb_json_codec.decode_zodb_record(make_record({}))
+        assert self.handler.records == []
+
+    def test_unknown_level(self):
+        with pytest.raises(ValueError, match="unknown log level"):
+            zodb_json_codec.configure_logging(level="LOUD")
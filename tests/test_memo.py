@@ -0,0 +1,49 @@
+"""Memo opcodes for repeated values in encoder output."""
+
+import json
+import pickle
+import pickletools
+
+import pytest
+import zodb_json_codec
+
+ROWS = [
+    {"title": f"Doc {i}", "state": "published", "tags": ["news", "local"]}
+    for i in range(200)
+]
+
+
+def opnames(data):
+    return [op.name for op, _, _ in pickletools.genops(data)]
+
+
+class TestMemo:
+    @pytest.mark.parametrize("protocol", [2, 3, 4])
+    def test_loads_with_pickle(self, protocol):
+        data = zodb_json_codec.json_to_pickle(json.dumps(ROWS), protocol=protocol)
+        assert pickle.loads(data) == ROWS
+        assert zodb_json_codec.pickle_to_dict(pickle.dumps(pickle.loads(data))) == ROWS
+
+    def test_size_comparable_to_cpython(self):
+        data = zodb_json_codec.json_to_pickle(json.dumps(ROWS))
+        assert len(data) <= len(pickle.dumps(ROWS, protocol=3))
+        ops = opnames(data)
+        assert "BINGET" in ops
+        assert ops.count("BINPUT") == 6  # the keys, "published", "news", "local"
+
+    def test_containers_stay_distinct(self):
+        data = zodb_json_codec.json_to_pickle(json.dumps([[1], [1], {"a": 1}, {"a": 1}]))
+        a, b, c, d = pickle.loads(data)
+        assert a == b and a is not b
+        assert c == d and c is not d
+
+    def test_shared_persistent_refs(self):
+        ref = {"@ref": ["0000000000000001", "myapp.Folder"]}
+        state = {"items": [ref] * 50}
+        data = zodb_json_codec.json_to_pickle(json.dumps(state))
+        assert opnames(data).count("BINPERSID") == 50
+        assert zodb_json_codec.pickle_to_dict(data) == state
+
+    def test_unique_values_get_no_memo(self):
+        data = zodb_json_codec.json_to_pickle(json.dumps({"a": "x", "b": "y"}))
+        assert not {"BINPUT", "MEMOIZE"} & set(opnames(data))
@@ -0,0 +1,103 @@
+"""Test NaN and infinities in JSON output (the nonfinite_floats= keyword)."""
+
+import asyncio
+import io
+import json
+import math
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+STATE = {"nan": float("nan"), "inf": float("inf"), "ninf": float("-inf"), "x": 1.5}
+
+
+def make_record(state):
+    return pickle.dumps(("myapp", "Doc"), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+def assert_restored(state):
+    assert math.isnan(state["nan"])
+    assert state["inf"] == math.inf
+    assert state["ninf"] == -math.inf
+    assert state["x"] == 1.5
+
+
+class TestMarkers:
+    def test_json(self):
+        text = zodb_json_codec.pickle_to_json(pickle.dumps(STATE, protocol=3))
+        assert json.loads(text) == {
+            "nan": {"@f": "nan"},
+            "inf": {"@f": "inf"},
+            "ninf": {"@f": "-inf"},
+            "x": 1.5,
+        }
+        assert_restored(pickle.loads(zodb_json_codec.json_to_pickle(text)))
+
+    def test_dict(self):
+        result = zodb_json_codec.pickle_to_dict(pickle.dumps(STATE, protocol=3))
+        assert result["inf"] == {"@f": "inf"}
+        assert_restored(pickle.loads(zodb_json_codec.dict_to_pickle(result)))
+
+    def test_pg_json(self):
+        record = make_record(STATE)
+        _, _, text, _ = zodb_json_codec.decode_zodb_record_for_pg_json(record)
+        assert json.loads(text)["nan"] == {"@f": "nan"}
+        record = {"@cls": ["myapp", "Doc"], "@s": json.loads(text)}
+        unpickler = pickle.Unpickler(io.BytesIO(zodb_json_codec.encode_zodb_record(record)))
+        unpickler.load()
+        assert_restored(unpickler.load())
+
+    def test_python_floats_encode(self):
+        data = zodb_json_codec.dict_to_pickle({"v": float("inf")})
+        assert pickle.loads(data) == {"v": math.inf}
+
+    def test_invalid_marker(self):
+        with pytest.raises(zodb_json_codec.CodecError):
+            zodb_json_codec.json_to_pickle('{"@f": "infinity"}')
+
+
+class TestNull:
+    NULLS = {"nan": None, "inf": None, "ninf": None, "x": 1.5}
+
+    def test_json(self):
+        data = pickle.dumps(STATE, protocol=3)
+        text = zodb_json_codec.pickle_to_json(data, nonfinite_floats="null")
+        assert json.loads(text) == self.NULLS
+
+    def test_dict(self):
+        data = pickle.dumps(STATE, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data, nonfinite_floats="null")
+        assert result == self.NULLS
+
+    def test_record(self):
+        record = make_record(STATE)
+        result = zodb_json_codec.decode_zodb_record(record, nonfinite_floats="null")
+        assert result["@s"] == self.NULLS
+        _, _, text, _ = zodb_json_codec.decode_zodb_record_for_pg_json(
+            record, nonfinite_floats="null"
+        )
+        assert json.loads(text) == self.NULLS
+
+    def test_batch_async(self):
+        records = [make_record(STATE)] * 8
+
+        async def main():
+            return await zodb_json_codec.decode_batch_async(records, nonfinite_floats="null")
+
+        for _, _, text, _ in asyncio.run(main()):
+            assert json.loads(text) == self.NULLS
+
+    def test_per_call(self):
+        data = pickle.dumps(STATE, protocol=3)
+        zodb_json_codec.pickle_to_dict(data, nonfinite_floats="null")
+        assert zodb_json_codec.pickle_to_dict(data)["inf"] == {"@f": "inf"}
+
+    def test_markers_still_read(self):
+        data = zodb_json_codec.json_to_pickle('{"v": {"@f": "-inf"}}')
+        assert pickle.loads(data) == {"v": -math.inf}
+
+    def test_unknown_mode(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.pickle_to_dict(b"N.", nonfinite_floats="string")
@@ -0,0 +1,85 @@
+"""apply_patch_to_record: JSON Patch operations on ZODB records."""
+
+import datetime
+import io
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+def make_record(state, protocol=3):
+    return pickle.dumps(("myapp", "Doc"), protocol=protocol) + pickle.dumps(
+        state, protocol=protocol
+    )
+
+
+def load_state(record):
+    unpickler = pickle.Unpickler(io.BytesIO(record))
+    assert unpickler.load() == (("myapp", "Doc"), None)
+    return unpickler.load()
+
+
+class TestApplyPatch:
+    def test_remove_attribute(self):
+        record = make_record({"title": "Hi", "broken": object.__new__(object)})
+        patched = zodb_json_codec.apply_patch_to_record(
+            record, [{"op": "remove", "path": "/@s/broken"}]
+        )
+        assert load_state(patched) == {"title": "Hi"}
+
+    def test_operations(self):
+        record = make_record({"title": "Hi", "tags": ["a", "c"]}, protocol=2)
+        patch = [
+            {"op": "test", "path": "/@s/title", "value": "Hi"},
+            {"op": "replace", "path": "/@s/title", "value": "Hello"},
+            {"op": "add", "path": "/@s/tags/1", "value": "b"},
+            {"op": "add", "path": "/@s/tags/-", "value": "d"},
+            {"op": "copy", "from": "/@s/title", "path": "/@s/label"},
+            {"op": "move", "from": "/@s/label", "path": "/@s/name"},
+        ]
+        patched = zodb_json_codec.apply_patch_to_record(bytearray(record), patch)
+        assert load_state(patched) == {
+            "title": "Hello",
+            "tags": ["a", "b", "c", "d"],
+            "name": "Hello",
+        }
+
+    def test_marker_values(self):
+        record = make_record({"when": None, "point": None})
+        patch = [
+            {"op": "replace", "path": "/@s/when", "value": {"@dt": "2025-06-01T12:00:00"}},
+            {"op": "replace", "path": "/@s/point", "value": {"@t": [1, 2]}},
+        ]
+        patched = zodb_json_codec.apply_patch_to_record(record, patch)
+        assert load_state(patched) == {
+            "when": datetime.datetime(2025, 6, 1, 12, 0),
+            "point": (1, 2),
+        }
+
+    def test_diff_paths_apply(self):
+        old = make_record({"title": "Old", "extra": 1})
+        new = make_record({"title": "New"})
+        diff = zodb_json_codec.diff_zodb_records(old, new)
+        patch = [{"op": "replace", "path": c["path"], "value": c["new"]} for c in diff["changed"]]
+        patch += [{"op": "remove", "path": r["path"]} for r in diff["removed"]]
+        patched = zodb_json_codec.apply_patch_to_record(old, patch)
+        assert zodb_json_codec.diff_zodb_records(patched, new) == {
+            "added": [],
+            "removed": [],
+            "changed": [],
+        }
+
+    @pytest.mark.parametrize(
+        "patch, match",
+        [
+            ([{"op": "remove", "path": "/@s/missing"}], "not found"),
+            ([{"op": "test", "path": "/@s/title", "value": "x"}], "test failed"),
+            ([{"op": "jump", "path": "/@s"}], "unknown op"),
+            ([{"op": "add", "path": "/@s/x", "value": {1, 2}}], "not JSON-compatible"),
+            ([{"op": "add", "path": "/@s/x", "value": 1}, {"op": "remove"}], "operation 1"),
+        ],
+    )
+    def test_errors(self, patch, match):
+        with pytest.raises(ValueError, match=match):
+            zodb_json_codec.apply_patch_to_record(make_record({"title": "Hi"}), patch)
@@ -0,0 +1,58 @@
+"""Test standalone persistent id pickles (decode/encode_persistent_id)."""
+
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+class Folder:
+    pass
+
+
+OID = b"\x00" * 7 + b"\x03"
+HEX = "0000000000000003"
+
+
+def dumps(pid):
+    return pickle.dumps(pid, protocol=3)
+
+
+class TestDecode:
+    def test_oid_only(self):
+        assert zodb_json_codec.decode_persistent_id(dumps((OID, None))) == {"@ref": HEX}
+
+    def test_with_class(self):
+        result = zodb_json_codec.decode_persistent_id(dumps((OID, Folder)))
+        assert result == {"@ref": [HEX, f"{__name__}.Folder"]}
+
+    def test_weak_and_multi_database(self):
+        assert zodb_json_codec.decode_persistent_id(dumps([OID])) == {"@ref": [HEX]}
+        result = zodb_json_codec.decode_persistent_id(dumps(["n", ("catalog", OID)]))
+        assert result == {"@ref": {"oid": HEX, "db": "catalog"}}
+
+    def test_int_ref_format(self):
+        result = zodb_json_codec.decode_persistent_id(dumps((OID, None)), ref_format="int")
+        assert result == {"@ref": 3}
+
+    def test_invalid(self):
+        with pytest.raises(zodb_json_codec.CodecError):
+            zodb_json_codec.decode_persistent_id(dumps((OID, None))[:-1])
+
+
+class TestEncode:
+    @pytest.mark.parametrize(
+        "pid", [(OID, None), (OID, Folder), [OID], ["w", (OID,)], ["m", ("db", OID, Folder)]]
+    )
+    def test_roundtrip(self, pid):
+        ref = zodb_json_codec.decode_persistent_id(dumps(pid))
+        data = zodb_json_codec.encode_persistent_id(ref)
+        assert pickle.loads(data) == pid
+
+    def test_compact_forms(self):
+        for ref in [{"@ref": HEX}, {"@ref": 3}]:
+            assert pickle.loads(zodb_json_codec.encode_persistent_id(ref)) == (OID, None)
+
+    def test_not_a_ref(self):
+        with pytest.raises(zodb_json_codec.CodecError, match="@ref"):
+            zodb_json_codec.encode_persistent_id({"oid": HEX})
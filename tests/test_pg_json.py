@@ -12,6 +12,7 @@ from uuid import UUID
 
 import json
 import pickle
+import pytest
 import zodb_json_codec
 
 
@@ -95,6 +96,11 @@ class TestPgJsonMatchesDict:
         record = make_zodb_record("myapp", "Obj", state)
         self._assert_match(record)
 
+    def test_null_bytes(self):
+        state = {"a\x00b": "c\x00d", "items": ["e\x00", ("f\x00",)]}
+        record = make_zodb_record("myapp", "Obj", state)
+        self._assert_match(record)
+
 
 class TestPgJsonKnownTypes:
     """Verify known type markers are identical between paths."""
@@ -180,3 +186,68 @@ class TestPgJsonReturnType:
         record = make_zodb_record("myapp", "Obj", {"x": 1})
         _, _, _, refs = zodb_json_codec.decode_zodb_record_for_pg_json(record)
         assert isinstance(refs, list)
+
+
+class TestPgNullBytesRoundtrip:
+    """Verify @ns strings and @ns: keys encode back to the original strings."""
+
+    STATE = {
+        "a\x00b": "c\x00d",
+        "items": ["e\x00", ("f\x00",)],
+        "nested": {"g\x00": 1},
+        # Not the @ns: form of a string with null bytes: kept as is
+        "@ns:YWJj": 2,
+    }
+
+    def _assert_roundtrip(self, module, name, state):
+        record = make_zodb_record(module, name, state)
+        expected = zodb_json_codec.decode_zodb_record(record)
+        mod, cls, state_dict, _ = zodb_json_codec.decode_zodb_record_for_pg(record)
+        _, _, state_json, _ = zodb_json_codec.decode_zodb_record_for_pg_json(record)
+        for pg_state in (state_dict, json.loads(state_json)):
+            encoded = zodb_json_codec.encode_zodb_record(
+                {"@cls": [mod, cls], "@s": pg_state}, pg_safe=True
+            )
+            assert zodb_json_codec.decode_zodb_record(encoded) == expected
+            (batch,) = zodb_json_codec.encode_zodb_records_batch(
+                [{"@cls": [mod, cls], "@s": pg_state}], pg_safe=True
+            )
+            assert zodb_json_codec.decode_zodb_record(batch) == expected
+            state_only = zodb_json_codec.encode_zodb_state(mod, cls, pg_state, pg_safe=True)
+            assert zodb_json_codec.decode_zodb_record(state_only) == expected
+
+    def test_record(self):
+        self._assert_roundtrip("myapp", "Obj", self.STATE)
+
+    def test_bucket(self):
+        state = (("k\x00", "v\x00", "w", "x\x00"),)
+        self._assert_roundtrip("BTrees.OOBTree", "OOBucket", state)
+
+    def test_pickle(self):
+        _, _, state, _ = zodb_json_codec.decode_zodb_record_for_pg(
+            make_zodb_record("myapp", "Obj", self.STATE)
+        )
+        for protocol in (2, 3):
+            data = zodb_json_codec.dict_to_pickle(state, protocol=protocol, pg_safe=True)
+            assert pickle.loads(data) == self.STATE
+        data = zodb_json_codec.json_to_pickle(json.dumps(state), pg_safe=True)
+        assert pickle.loads(data) == self.STATE
+
+    def test_verify_roundtrip(self):
+        record = make_zodb_record("myapp", "Obj", self.STATE)
+        assert zodb_json_codec.verify_roundtrip(record)["equal"]
+
+    def test_invalid_marker(self):
+        with pytest.raises(ValueError, match="not UTF-8"):
+            zodb_json_codec.dict_to_pickle({"x": {"@ns": "/wA="}}, pg_safe=True)
+
+    def test_ns_data_kept_without_pg_safe(self):
+        # Genuine "@ns:" keys and "@ns" dicts, not from the PostgreSQL form
+        state = {"@ns:YQBi": 1, "x": {"@ns": "YQBi"}}
+        record = make_zodb_record("myapp", "Obj", state)
+        decoded = zodb_json_codec.decode_zodb_record(record)
+        encoded = zodb_json_codec.encode_zodb_record(decoded)
+        assert zodb_json_codec.decode_zodb_record(encoded) == decoded
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(state)) == state
+        data = zodb_json_codec.json_to_pickle(zodb_json_codec.pickle_to_json(pickle.dumps(state)))
+        assert pickle.loads(data) == state
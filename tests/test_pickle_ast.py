@@ -0,0 +1,161 @@
+"""Test the pickle AST (decode_pickle_ast, encode_pickle_ast and nodes)."""
+
+import datetime
+import decimal
+import io
+import pickle
+import pytest
+import zodb_json_codec
+from zodb_json_codec import nodes
+
+
+class Doc:
+    def __init__(self, title):
+        self.title = title
+
+
+class Ref:
+    def __init__(self, oid):
+        self.oid = oid
+
+
+class RefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return (obj.oid, None)
+        return None
+
+
+OID = b"\x00" * 7 + b"\x05"
+
+
+class TestDecode:
+    def test_scalars(self):
+        value = [None, True, 42, 2**70, 1.5, "text", b"\x00\xff"]
+        ast = zodb_json_codec.decode_pickle_ast(pickle.dumps(value, protocol=3))
+        assert ast == nodes.List(
+            [
+                nodes.Null(),
+                nodes.Bool(True),
+                nodes.Int(42),
+                nodes.Int(2**70),
+                nodes.Float(1.5),
+                nodes.Str("text"),
+                nodes.Bytes(b"\x00\xff"),
+            ]
+        )
+
+    def test_containers(self):
+        value = {"t": (1,), "s": {2}, "f": frozenset([3]), 4: []}
+        ast = zodb_json_codec.decode_pickle_ast(pickle.dumps(value, protocol=4))
+        assert isinstance(ast, nodes.Dict)
+        assert ast.items == [
+            (nodes.Str("t"), nodes.Tuple([nodes.Int(1)])),
+            (nodes.Str("s"), nodes.Set([nodes.Int(2)])),
+            (nodes.Str("f"), nodes.FrozenSet([nodes.Int(3)])),
+            (nodes.Int(4), nodes.List([])),
+        ]
+
+    def test_known_types_keep_their_pickle_form(self):
+        data = pickle.dumps(datetime.date(2020, 1, 2), protocol=3)
+        ast = zodb_json_codec.decode_pickle_ast(data)
+        assert ast == nodes.Reduce(
+            nodes.Global("datetime", "date"),
+            nodes.Tuple([nodes.Bytes(b"\x07\xe4\x01\x02")]),
+        )
+
+    def test_instance(self):
+        data = pickle.dumps(Doc("Hello"), protocol=3)
+        ast = zodb_json_codec.decode_pickle_ast(data)
+        assert isinstance(ast, nodes.Instance)
+        assert (ast.module, ast.name) == (__name__, "Doc")
+        assert ast.state == nodes.Dict([(nodes.Str("title"), nodes.Str("Hello"))])
+
+    def test_persistent_ref(self):
+        buf = io.BytesIO()
+        RefPickler(buf, protocol=3).dump({"ref": Ref(OID)})
+        ast = zodb_json_codec.decode_pickle_ast(buf.getvalue())
+        [(_, ref)] = ast.items
+        assert ref == nodes.PersistentRef(nodes.Tuple([nodes.Bytes(OID), nodes.Null()]))
+
+    def test_invalid_pickle(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.decode_pickle_ast(b"\x80\x03")
+
+
+class TestEncode:
+    @pytest.mark.parametrize("protocol", [0, 2, 3, 4])
+    def test_roundtrip(self, protocol):
+        value = {"a": [1, 2**70, (b"x", None)], "d": datetime.date(2020, 1, 2)}
+        ast = zodb_json_codec.decode_pickle_ast(pickle.dumps(value, protocol=3))
+        data = zodb_json_codec.encode_pickle_ast(ast, protocol=protocol)
+        assert zodb_json_codec.decode_pickle_ast(data) == ast
+        if protocol > 0:
+            # Protocol 0 writes bytes as Python 2 str, for zodbpickle
+            assert pickle.loads(data) == value
+
+    def test_mutate_in_place(self):
+        ast = zodb_json_codec.decode_pickle_ast(pickle.dumps({"a": [1]}, protocol=3))
+        key, items = ast.items[0]
+        key.value = "b"
+        items.items.append(nodes.Str("two"))
+        assert pickle.loads(zodb_json_codec.encode_pickle_ast(ast)) == {"b": [1, "two"]}
+
+    def test_swap_class(self):
+        data = pickle.dumps(decimal.Decimal("1.5"), protocol=3)
+        ast = zodb_json_codec.decode_pickle_ast(data)
+        assert ast.callable == nodes.Global("decimal", "Decimal")
+        ast.callable = nodes.Global("builtins", "float")
+        assert pickle.loads(zodb_json_codec.encode_pickle_ast(ast)) == 1.5
+
+    def test_build_from_scratch(self):
+        ast = nodes.Dict(
+            [
+                (nodes.Str("ref"), nodes.PersistentRef(nodes.Bytes(OID))),
+                (nodes.Str("n"), nodes.Int(-(2**80))),
+            ]
+        )
+        data = zodb_json_codec.encode_pickle_ast(ast)
+        assert zodb_json_codec.decode_pickle_ast(data) == ast
+
+    def test_child_must_be_a_node(self):
+        with pytest.raises(TypeError, match="PickleNode"):
+            zodb_json_codec.encode_pickle_ast(nodes.List([1]))
+
+    def test_cycle_fails(self):
+        cyclic = nodes.List([])
+        cyclic.items.append(cyclic)
+        limits = zodb_json_codec.EncodeLimits(max_depth=50)
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.encode_pickle_ast(cyclic, limits=limits)
+
+    def test_invalid_protocol(self):
+        with pytest.raises(ValueError, match="unsupported pickle protocol"):
+            zodb_json_codec.encode_pickle_ast(nodes.Null(), protocol=1)
+
+
+class TestNodes:
+    def test_repr(self):
+        node = nodes.Global("myapp", "Doc")
+        assert repr(node) == "Global(module='myapp', name='Doc')"
+        assert repr(nodes.Null()) == "Null()"
+
+    def test_match(self):
+        match nodes.Reduce(nodes.Global("myapp", "make"), nodes.Tuple([])):
+            case nodes.Reduce(nodes.Global(module, name)):
+                assert (module, name) == ("myapp", "make")
+            case _:
+                pytest.fail("no match")
+
+    def test_equality(self):
+        assert nodes.Int(1) == nodes.Int(1)
+        assert nodes.Int(1) != nodes.Float(1.0)
+        assert nodes.Int(1) != 1
+
+    def test_not_hashable(self):
+        with pytest.raises(TypeError):
+            hash(nodes.Null())
+
+    def test_base_class_is_abstract(self):
+        with pytest.raises(TypeError):
+            nodes.PickleNode()
@@ -0,0 +1,73 @@
+"""iter_pickle_events: SAX-style events over pickles and records."""
+
+import datetime
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+def make_record(state, module="myapp", name="Doc"):
+    return pickle.dumps((module, name), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+class TestIterPickleEvents:
+    def test_nested_containers(self):
+        data = pickle.dumps({"a": [1, (None, True)], "b": {2.5}}, protocol=3)
+        assert list(zodb_json_codec.iter_pickle_events(data)) == [
+            ("start_dict", None),
+            ("key", "a"),
+            ("start_list", None),
+            ("int", 1),
+            ("start_tuple", None),
+            ("none", None),
+            ("bool", True),
+            ("end_tuple", None),
+            ("end_list", None),
+            ("key", "b"),
+            ("start_set", None),
+            ("float", 2.5),
+            ("end_set", None),
+            ("end_dict", None),
+        ]
+
+    def test_scalars(self):
+        data = pickle.dumps(["x", b"\x00\xff", 2**100, frozenset()], protocol=3)
+        assert list(zodb_json_codec.iter_pickle_events(data)) == [
+            ("start_list", None),
+            ("str", "x"),
+            ("bytes", b"\x00\xff"),
+            ("int", 2**100),
+            ("start_frozenset", None),
+            ("end_frozenset", None),
+            ("end_list", None),
+        ]
+
+    def test_record(self):
+        record = make_record({"title": "Hi", "when": datetime.date(2025, 1, 2)})
+        events = list(zodb_json_codec.iter_pickle_events(record))
+        assert events == [
+            ("class", ("myapp", "Doc")),
+            ("start_dict", None),
+            ("key", "title"),
+            ("str", "Hi"),
+            ("key", "when"),
+            ("value", {"@date": "2025-01-02"}),
+            ("end_dict", None),
+        ]
+
+    def test_global(self):
+        data = pickle.dumps([len], protocol=3)
+        events = list(zodb_json_codec.iter_pickle_events(data))
+        assert events[1] == ("global", ("builtins", "len"))
+
+    def test_is_iterator(self):
+        it = zodb_json_codec.iter_pickle_events(pickle.dumps(1, protocol=3))
+        assert iter(it) is it
+        assert next(it) == ("int", 1)
+        with pytest.raises(StopIteration):
+            next(it)
+
+    def test_invalid_data(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.iter_pickle_events(b"\x80\x03")
@@ -0,0 +1,215 @@
+"""Protocol 0 (text) pickles: decoding legacy records and emitting them.
+
+The corpus below mirrors records written by Python 2 ZODB (Zope 2.10
+era) with text pickles: `S'...'` byte strings, raw-unicode-escaped `V`
+text, MARK-based dict/list/tuple flows with `p`/`g` memo lines,
+`copy_reg._reconstructor` instances, INST/OBJ classic class instances
+and `P` persistent ids.
+"""
+
+import base64
+import json
+import pickle
+import pytest
+import zodb_json_codec
+
+
+class Layout:
+    """Plain class: below protocol 2 pickled via copy_reg._reconstructor."""
+
+
+class Span:
+    """Stand-in for a Python 2 classic class with __getinitargs__."""
+
+    def __init__(self, start, end):
+        self.start, self.end = start, end
+
+
+# cPickle.dumps(Folder, 0) class pickle followed by the state pickle
+FOLDER_RECORD = (
+    b"(cOFS.Folder\nFolder\np1\nNt."
+    b"(dp1\nS'title'\np2\nS'Front page'\np3\n"
+    b"sS'_objects'\np4\n((dp5\nS'meta_type'\np6\nS'Document'\np7\n"
+    b"sS'id'\np8\nS'index_html'\np9\nstp10\n"
+    b"sS'index_html'\np11\nP0000000000000002\n"
+    b"sS'description'\np12\nVCaf\\u00e9 \\u20ac\np13\n"
+    b"sS'ids'\np14\n(lp15\ng9\naS'about'\np16\nas."
+)
+
+# Non-persistent new-style instance inside a state, Python 2 style
+RECONSTRUCTOR_STATE = (
+    b"(dp1\nS'layout'\np2\nccopy_reg\n_reconstructor\np3\n"
+    b"(cmyapp.layout\nGrid\np4\nc__builtin__\nobject\np5\nNtp6\nRp7\n"
+    b"(dp8\nS'columns'\np9\nI3\nsS'ratio'\np10\nF0.5\nsbsS'tags'\np11\n"
+    b"c__builtin__\nset\np12\n((lp13\nS'news'\np14\naS'home'\np15\natp16\n"
+    b"Rp17\ns."
+)
+
+
+# Python 2 classic class instances: INST (protocol 0) and OBJ (protocol 1)
+CLASSIC_STATE = (
+    b"(dp1\nS'layout'\np2\n(imyapp.layout\nGrid\np3\n"
+    b"(dp4\nS'columns'\np5\nI3\nsbsS'span'\np6\n"
+    b"(I1\nI5\nimyapp.layout\nSpan\np7\ns."
+)
+CLASSIC_STATE_BIN = (
+    b"}q\x01(U\x06layoutq\x02(cmyapp.layout\nGrid\nq\x03oq\x04}q\x05"
+    b"U\x07columnsq\x06K\x03sbU\x04spanq\x07(cmyapp.layout\nSpan\n"
+    b"q\x08K\x01K\x05oq\tu."
+)
+
+
+def b64(data):
+    return {"@b": base64.b64encode(data).decode()}
+
+
+class TestDecodeLegacyRecords:
+    def test_folder_record(self):
+        result = zodb_json_codec.decode_zodb_record(FOLDER_RECORD)
+        assert result["@cls"] == ["OFS.Folder", "Folder"]
+        pairs = result["@s"]["@d"]
+        assert len(pairs) == 5
+        assert pairs[0] == [b64(b"title"), b64(b"Front page")]
+        assert pairs[2] == [b64(b"index_html"), {"@ref": "0000000000000002"}]
+        assert pairs[3] == [b64(b"description"), "Café €"]
+        # g9 refers back to the memoized 'index_html' string
+        assert pairs[4] == [b64(b"ids"), [b64(b"index_html"), b64(b"about")]]
+
+    def test_folder_record_refs(self):
+        assert zodb_json_codec.count_refs(FOLDER_RECORD) == 1
+
+    def test_reconstructor_instance(self):
+        result = zodb_json_codec.pickle_to_dict(RECONSTRUCTOR_STATE)
+        pairs = dict(
+            (json.dumps(k, sort_keys=True), v) for k, v in result["@d"]
+        )
+        layout = pairs[json.dumps(b64(b"layout"))]
+        assert layout["@cls"] == ["myapp.layout", "Grid"]
+        assert layout["@s"]["@d"] == [[b64(b"columns"), 3], [b64(b"ratio"), 0.5]]
+        assert pairs[json.dumps(b64(b"tags"))] == {
+            "@set": [b64(b"news"), b64(b"home")]
+        }
+
+    @pytest.mark.parametrize("data", [CLASSIC_STATE, CLASSIC_STATE_BIN])
+    def test_classic_instances(self, data):
+        result = zodb_json_codec.pickle_to_dict(data)
+        pairs = dict((json.dumps(k, sort_keys=True), v) for k, v in result["@d"])
+        layout = pairs[json.dumps(b64(b"layout"))]
+        assert layout == {
+            "@cls": ["myapp.layout", "Grid"],
+            "@s": {"@d": [[b64(b"columns"), 3]]},
+        }
+        assert pairs[json.dumps(b64(b"span"))] == {
+            "@reduce": {
+                "callable": {"@cls": ["myapp.layout", "Span"]},
+                "args": {"@t": [1, 5]},
+            }
+        }
+        assert json.loads(zodb_json_codec.pickle_to_json(data)) == result
+
+    def test_classic_instance_record(self):
+        record = b"(cmyapp.layout\nGrid\nNt." + CLASSIC_STATE
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert result["@cls"] == ["myapp.layout", "Grid"]
+        assert len(result["@s"]["@d"]) == 2
+
+    def test_classic_instances_restore(self):
+        data = CLASSIC_STATE.replace(b"myapp.layout", __name__.encode())
+        data = data.replace(b"Grid", b"Layout")
+        restored = pickle.loads(
+            zodb_json_codec.json_to_pickle(zodb_json_codec.pickle_to_json(data))
+        )
+        assert type(restored[b"layout"]) is Layout
+        # Python 2 str keys come back as bytes
+        assert vars(restored[b"layout"]) == {b"columns": 3}
+        span = restored[b"span"]
+        assert type(span) is Span and (span.start, span.end) == (1, 5)
+
+    @pytest.mark.parametrize(
+        "val",
+        [
+            {"title": "Café", "n": 2**40, "neg": -7, "f": 1e-300},
+            ["a", ("b", None), [True, False], {"x": {"y": []}}],
+            "back\\slash\nnewline\r\x00 \u20ac \U0001f600",
+            b"\x00\xff'\"\\",
+            {1, 2, 3},
+            frozenset(["x"]),
+            2**100,
+        ],
+    )
+    def test_python3_protocol0(self, val):
+        for protocol in (0, 1):
+            data = pickle.dumps(val, protocol=protocol)
+            via_json = zodb_json_codec.json_to_pickle(zodb_json_codec.pickle_to_json(data))
+            assert pickle.loads(via_json) == val
+
+    def test_python3_protocol0_instance(self):
+        obj = Layout()
+        obj.columns = 3
+        data = pickle.dumps({"layout": obj}, protocol=0)
+        assert zodb_json_codec.pickle_to_dict(data) == {
+            "layout": {"@cls": [__name__, "Layout"], "@s": {"columns": 3}}
+        }
+        restored = pickle.loads(
+            zodb_json_codec.json_to_pickle(zodb_json_codec.pickle_to_json(data))
+        )
+        assert type(restored["layout"]) is Layout
+        assert restored["layout"].columns == 3
+
+
+class TestProtocol0Output:
+    @pytest.mark.parametrize(
+        "json_val",
+        [
+            {"title": "Café €", "n": 42, "big": 2**40, "f": 2.5, "none": None},
+            {"t": {"@t": [1, "two", [3]]}, "b": {"@b": "AP8n"}},
+            {"s": {"@set": [1, 2]}, "fs": {"@fset": ["x"]}, "bi": {"@bi": str(2**80)}},
+            {"dt": {"@dt": "2025-01-02T03:04:05"}, "ref": {"@ref": "0000000000000003"}},
+            {"obj": {"@cls": ["collections", "OrderedDict"], "@s": {"a": 1}}},
+        ],
+    )
+    def test_roundtrip(self, json_val):
+        json_str = json.dumps(json_val)
+        data = zodb_json_codec.json_to_pickle(json_str, protocol=0)
+        assert data.isascii()
+        assert not data.startswith(b"\x80")
+        assert json.loads(zodb_json_codec.pickle_to_json(data)) == json.loads(
+            zodb_json_codec.pickle_to_json(zodb_json_codec.json_to_pickle(json_str))
+        )
+
+    def test_readable_by_pickle(self):
+        data = zodb_json_codec.json_to_pickle(
+            json.dumps({"text": "Café \U0001f600", "items": [1, 2.5, None, True], "t": {"@t": [1]}}),
+            protocol=0,
+        )
+        assert pickle.loads(data) == {
+            "text": "Café \U0001f600",
+            "items": [1, 2.5, None, True],
+            "t": (1,),
+        }
+
+    def test_bytes_as_python2_str(self):
+        data = zodb_json_codec.json_to_pickle('{"@b": "AP8n"}', protocol=0)
+        assert data == b"S'\\x00\\xff\\''\n."
+        assert pickle.loads(data, encoding="bytes") == b"\x00\xff'"
+
+    def test_instance_readable_by_pickle(self):
+        data = zodb_json_codec.json_to_pickle(
+            json.dumps({"@cls": [__name__, "Layout"], "@s": {"columns": 2}}),
+            protocol=0,
+        )
+        obj = pickle.loads(data)
+        assert type(obj) is Layout
+        assert obj.columns == 2
+
+    def test_dict_to_pickle(self):
+        val = {"a": [1, {"@t": ["x"]}], "b": {"@b": "AAE="}}
+        data = zodb_json_codec.dict_to_pickle(val, protocol=0)
+        assert data.isascii()
+        assert zodb_json_codec.pickle_to_dict(data) == val
+
+    def test_invalid_protocol(self):
+        with pytest.raises(ValueError, match="unsupported pickle protocol"):
+            zodb_json_codec.json_to_pickle("{}", protocol=1)
+        with pytest.raises(ValueError, match="chunk_size requires protocol 3"):
+            zodb_json_codec.dict_to_pickle({}, protocol=0, chunk_size=1024)
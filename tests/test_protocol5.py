@@ -0,0 +1,44 @@
+"""Protocol 5: BYTEARRAY8 and out-of-band buffers."""
+
+import json
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+def dumps_oob(obj):
+    buffers = []
+    data = pickle.dumps(obj, protocol=5, buffer_callback=buffers.append)
+    return data, [bytes(b.raw()) for b in buffers]
+
+
+class TestProtocol5:
+    def test_bytearray(self):
+        data = pickle.dumps({"blob": bytearray(b"\x00\x01")}, protocol=5)
+        assert zodb_json_codec.pickle_to_dict(data) == {"blob": {"@b": "AAE="}}
+
+    def test_out_of_band_buffers(self):
+        obj = {"a": pickle.PickleBuffer(b"abc"), "b": pickle.PickleBuffer(bytearray(b"xy"))}
+        data, buffers = dumps_oob(obj)
+        assert len(buffers) == 2
+        result = zodb_json_codec.pickle_to_dict(data, buffers=buffers)
+        assert result == {"a": {"@b": "YWJj"}, "b": {"@b": "eHk="}}
+        as_json = zodb_json_codec.pickle_to_json(data, buffers=buffers)
+        assert json.loads(as_json) == result
+
+    def test_in_band_buffers(self):
+        data = pickle.dumps([pickle.PickleBuffer(b"abc")], protocol=5)
+        assert zodb_json_codec.pickle_to_dict(data) == [{"@b": "YWJj"}]
+
+    def test_missing_buffers(self):
+        data, buffers = dumps_oob([pickle.PickleBuffer(b"a"), pickle.PickleBuffer(b"b")])
+        with pytest.raises(ValueError, match="none were given"):
+            zodb_json_codec.pickle_to_dict(data)
+        with pytest.raises(ValueError, match="not enough out-of-band buffers"):
+            zodb_json_codec.pickle_to_dict(data, buffers=buffers[:1])
+
+    def test_reencodes_as_bytes(self):
+        data, buffers = dumps_oob({"a": pickle.PickleBuffer(b"abc")})
+        result = zodb_json_codec.pickle_to_dict(data, buffers=buffers)
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result)) == {"a": b"abc"}
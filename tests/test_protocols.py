@@ -0,0 +1,79 @@
+"""Pickle protocol selection for encoder output."""
+
+import json
+import pickle
+import pickletools
+
+import pytest
+import zodb_json_codec
+
+STATE = {
+    "title": "Hello",
+    "data": {"@b": "AID/"},
+    "empty": {"@b": ""},
+    "tags": {"@set": ["a", "b"]},
+    "frozen": {"@fset": [1]},
+    "nested": [1, 2.5, None, True, {"@t": ["x", "y"]}],
+}
+EXPECTED = {
+    "title": "Hello",
+    "data": b"\x00\x80\xff",
+    "empty": b"",
+    "tags": {"a", "b"},
+    "frozen": frozenset([1]),
+    "nested": [1, 2.5, None, True, ("x", "y")],
+}
+
+
+def opnames(data):
+    return {op.name for op, _, _ in pickletools.genops(data)}
+
+
+class TestProtocols:
+    @pytest.mark.parametrize("protocol", [0, 2, 3, 4])
+    def test_json_to_pickle(self, protocol):
+        data = zodb_json_codec.json_to_pickle(json.dumps(STATE), protocol=protocol)
+        assert pickle.loads(data, encoding="bytes") == EXPECTED
+        assert zodb_json_codec.pickle_to_dict(data) == STATE
+
+    @pytest.mark.parametrize("protocol", [0, 2, 3, 4])
+    def test_dict_to_pickle(self, protocol):
+        data = zodb_json_codec.dict_to_pickle(STATE, protocol=protocol)
+        assert pickle.loads(data, encoding="bytes") == EXPECTED
+        assert zodb_json_codec.pickle_to_dict(data) == STATE
+
+
+class TestProtocolOpcodes:
+    def test_protocol2(self):
+        data = zodb_json_codec.dict_to_pickle(STATE, protocol=2)
+        assert data[:2] == b"\x80\x02"
+        assert not opnames(data) & {"BINBYTES", "SHORT_BINBYTES", "FRAME"}
+
+    def test_protocol3(self):
+        data = zodb_json_codec.dict_to_pickle(STATE, protocol=3)
+        assert data[:2] == b"\x80\x03"
+        assert "SHORT_BINBYTES" in opnames(data)
+
+    def test_protocol4(self):
+        data = zodb_json_codec.dict_to_pickle(STATE, protocol=4)
+        assert data[:2] == b"\x80\x04"
+        ops = opnames(data)
+        assert {"FRAME", "SHORT_BINUNICODE", "EMPTY_SET", "FROZENSET"} <= ops
+        assert "BINUNICODE" not in ops
+
+    def test_protocol4_large_frames(self):
+        big = {"items": [f"{i:>100}" for i in range(2000)]}
+        data = zodb_json_codec.dict_to_pickle(big, protocol=4)
+        frames = [arg for op, arg, _ in pickletools.genops(data) if op.name == "FRAME"]
+        assert len(frames) > 1
+        assert pickle.loads(data) == big
+
+    def test_protocol4_chunked(self):
+        big = {"items": [f"item {i}" for i in range(5000)]}
+        data = zodb_json_codec.dict_to_pickle(big, protocol=4, chunk_size=1024)
+        assert "SHORT_BINUNICODE" in opnames(data)
+        assert pickle.loads(data) == big
+
+    def test_chunk_size_requires_binary_protocol(self):
+        with pytest.raises(ValueError, match="chunk_size requires protocol 3 or 4"):
+            zodb_json_codec.json_to_pickle("{}", protocol=2, chunk_size=1024)
@@ -0,0 +1,82 @@
+"""Test decoding Python 2 str values as text (py2_strings=...)."""
+
+import json
+
+import pytest
+import zodb_json_codec
+
+
+# A protocol 2 record as Python 2 writes it: str keys and values, a
+# persistent ref, a datetime and a str that is not UTF-8.
+RECORD = (
+    b"\x80\x02cmyapp\nDoc\nq\x01.\x80\x02}q\x02(U\x05titleq\x03U\x05Caf\xc3\xa9q\x04"
+    b"U\x03refq\x05U\x08\x00\x00\x00\x00\x00\x00\x00\x07cmyapp\nDoc\n\x86Q"
+    b"U\x04whenq\x06"
+    b"cdatetime\ndatetime\nU\n\x07\xd0\x01\x01\x00\x00\x00\x00\x00\x00\x85R"
+    b"U\x03rawU\x02\xff\xfeu."
+)
+# The same in protocol 1, with a weak ref
+RECORD_WEAK = (
+    b"cmyapp\nDoc\nq\x01.}q\x02(U\x04nameq\x03U\x03abcq\x04"
+    b"U\x04peerq\x05(U\x01wU\x08\x00\x00\x00\x00\x00\x00\x00\x08\x85lQu."
+)
+
+REF = {"@ref": ["0000000000000007", "myapp.Doc"]}
+WHEN = {"@dt": "2000-01-01T00:00:00"}
+
+
+class TestDecode:
+    def test_bytes_is_the_default(self):
+        state = zodb_json_codec.decode_zodb_record(RECORD)["@s"]
+        assert state["@d"][0] == [{"@b": "dGl0bGU="}, {"@b": "Q2Fmw6k="}]
+
+    def test_utf8(self):
+        state = zodb_json_codec.decode_zodb_record(RECORD, py2_strings="utf8")["@s"]
+        raw = {"@b": "//4="}
+        assert state == {"title": "Café", "ref": REF, "when": WHEN, "raw": raw}
+
+    def test_latin1(self):
+        state = zodb_json_codec.decode_zodb_record(RECORD, py2_strings="latin1")["@s"]
+        assert state == {"title": "CafÃ©", "ref": REF, "when": WHEN, "raw": "ÿþ"}
+
+    @pytest.mark.parametrize("mode", ["latin1", "utf8"])
+    def test_weak_ref_stays_compact(self, mode):
+        state = zodb_json_codec.decode_zodb_record(RECORD_WEAK, py2_strings=mode)["@s"]
+        assert state == {
+            "name": "abc",
+            "peer": {"@ref": {"oid": "0000000000000008", "weak": True}},
+        }
+
+    @pytest.mark.parametrize("mode", ["latin1", "utf8"])
+    def test_for_pg_refs(self, mode):
+        _, _, state, refs = zodb_json_codec.decode_zodb_record_for_pg(RECORD, py2_strings=mode)
+        assert state["title"] in ("Café", "CafÃ©")
+        assert refs == [7]
+        _, _, state_json, refs = zodb_json_codec.decode_zodb_record_for_pg_json(
+            RECORD, py2_strings=mode
+        )
+        assert json.loads(state_json)["title"] == state["title"]
+        assert refs == [7]
+
+    def test_pickles(self):
+        py2 = b"\x80\x02}U\x05titleU\x05Caf\xc3\xa9s."
+        assert zodb_json_codec.pickle_to_dict(py2, py2_strings="utf8") == {"title": "Café"}
+        assert json.loads(zodb_json_codec.pickle_to_json(py2, py2_strings="utf8")) == {
+            "title": "Café"
+        }
+
+    def test_per_call(self):
+        zodb_json_codec.decode_zodb_record(RECORD, py2_strings="utf8")
+        state = zodb_json_codec.decode_zodb_record(RECORD)["@s"]
+        assert state["@d"][0] == [{"@b": "dGl0bGU="}, {"@b": "Q2Fmw6k="}]
+
+    def test_unknown_mode_raises(self):
+        with pytest.raises(ValueError, match="unknown py2_strings mode"):
+            zodb_json_codec.decode_zodb_record(RECORD, py2_strings="ascii")
+
+
+class TestEncode:
+    def test_text_encodes_as_str(self):
+        decoded = zodb_json_codec.decode_zodb_record(RECORD, py2_strings="utf8")
+        record = zodb_json_codec.encode_zodb_record(decoded)
+        assert zodb_json_codec.decode_zodb_record(record)["@s"] == decoded["@s"]
@@ -0,0 +1,91 @@
+"""Per-class size and time quotas for record decoding."""
+
+import asyncio
+import pickle
+import pytest
+import zodb_json_codec
+
+
+MB = 1024 * 1024
+
+
+def make_record(name, state):
+    return pickle.dumps(("myapp", name), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+class TestClassQuotas:
+    def test_no_quotas_by_default(self):
+        record = make_record("Document", {"data": b"x" * MB})
+        assert zodb_json_codec.decode_zodb_record(record)["@cls"] == ["myapp", "Document"]
+
+    def test_default_size(self):
+        quotas = zodb_json_codec.ClassQuotas(max_size=1024)
+        with pytest.raises(ValueError, match="myapp.Document"):
+            zodb_json_codec.decode_zodb_record(
+                make_record("Document", {"data": b"x" * 2048}), quotas=quotas
+            )
+        record = make_record("Document", {"title": "ok"})
+        assert zodb_json_codec.decode_zodb_record(record, quotas=quotas)
+
+    def test_class_override(self):
+        quotas = zodb_json_codec.ClassQuotas(
+            {"myapp.File": {"max_size": 2 * MB}}, max_size=1024
+        )
+        big = {"data": b"x" * MB}
+        result = zodb_json_codec.decode_zodb_record(make_record("File", big), quotas=quotas)
+        assert result["@cls"] == ["myapp", "File"]
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.decode_zodb_record(make_record("Image", big), quotas=quotas)
+
+    def test_applies_to_all_record_decoders(self):
+        quotas = zodb_json_codec.ClassQuotas(max_size=64)
+        record = make_record("Document", {"data": "x" * 100})
+        for decode in (
+            zodb_json_codec.decode_zodb_record,
+            zodb_json_codec.decode_zodb_state,
+            zodb_json_codec.decode_zodb_record_for_pg,
+            zodb_json_codec.decode_zodb_record_for_pg_json,
+        ):
+            with pytest.raises(ValueError, match="limit exceeded"):
+                decode(record, quotas=quotas)
+
+    def test_batch_async(self):
+        quotas = zodb_json_codec.ClassQuotas(max_size=64)
+        records = [make_record("Document", {}), make_record("Document", {"data": "x" * 100})]
+
+        async def decode():
+            return await zodb_json_codec.decode_batch_async(records, quotas=quotas)
+
+        with pytest.raises(ValueError, match="record 1: .*limit exceeded"):
+            asyncio.run(decode())
+
+    def test_time(self):
+        quotas = zodb_json_codec.ClassQuotas(
+            {"myapp.Report": {"max_time": 60.0}}, max_time=0.0
+        )
+        state = list(range(20_000))
+        with pytest.raises(ValueError, match="time quota"):
+            zodb_json_codec.decode_zodb_record(make_record("Index", state), quotas=quotas)
+        result = zodb_json_codec.decode_zodb_record(make_record("Report", state), quotas=quotas)
+        assert result["@s"] == state
+
+    def test_per_call(self):
+        # Quotas given to one call do not affect any other
+        record = make_record("Document", {"title": "x" * 100})
+        strict = zodb_json_codec.ClassQuotas(max_size=16)
+        with pytest.raises(ValueError, match="limit exceeded"):
+            zodb_json_codec.decode_zodb_record(record, quotas=strict)
+        assert zodb_json_codec.decode_zodb_record(record)
+        assert zodb_json_codec.decode_zodb_record(
+            record, quotas=zodb_json_codec.ClassQuotas(max_size=MB)
+        )
+
+    def test_invalid_spec(self):
+        with pytest.raises(ValueError, match="unknown quota key"):
+            zodb_json_codec.ClassQuotas({"myapp.File": {"size": 1}})
+        with pytest.raises(ValueError, match="must be a dict"):
+            zodb_json_codec.ClassQuotas({"myapp.File": 1})
+        with pytest.raises(ValueError, match="invalid max_time"):
+            zodb_json_codec.ClassQuotas(max_time=-1.0)
+        with pytest.raises(TypeError):
+            zodb_json_codec.decode_zodb_record(make_record("Document", {}), quotas={})
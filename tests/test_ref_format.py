@@ -0,0 +1,151 @@
+"""Test the int OID format of compact persistent refs (ref_format)."""
+
+import asyncio
+import io
+import json
+import pickle
+import pytest
+import zodb_json_codec
+
+
+class Ref:
+    """Stand-in for a persistent object, pickled with a given persistent id."""
+
+    def __init__(self, pid):
+        self.pid = pid
+
+
+class RefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return obj.pid
+        return None
+
+
+class Index:
+    pass
+
+
+class PidUnpickler(pickle.Unpickler):
+    def persistent_load(self, pid):
+        return ("pid", pid)
+
+
+def make_record(state, protocol=3):
+    buf = io.BytesIO()
+    RefPickler(buf, protocol=protocol).dump(state)
+    return pickle.dumps(("myapp.models", "Folder"), protocol=protocol) + buf.getvalue()
+
+
+def load_state(record):
+    f = io.BytesIO(record)
+    pickle.load(f)
+    return PidUnpickler(f).load()
+
+
+OID = (42).to_bytes(8, "big")
+HIGH_OID = b"\xff" * 8
+
+FORMS = [
+    ((OID, None), 42),
+    ((OID, Index), [42, f"{__name__}.Index"]),
+    ([OID], [42]),
+    (["w", (OID,)], {"oid": 42, "weak": True}),
+    (["n", ("catalog", OID)], {"oid": 42, "db": "catalog"}),
+    # Above i64::MAX, matching the signed refs of decode_zodb_record_for_pg
+    ((HIGH_OID, None), -1),
+]
+
+
+class TestDecode:
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_decode_zodb_record(self, pid, compact):
+        record = make_record({"r": Ref(pid)})
+        result = zodb_json_codec.decode_zodb_record(record, ref_format="int")
+        assert result["@s"]["r"] == {"@ref": compact}
+
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_decode_zodb_state(self, pid, compact):
+        record = make_record({"r": Ref(pid)})
+        state = zodb_json_codec.decode_zodb_state(record, ref_format="int")
+        assert state["r"] == {"@ref": compact}
+
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_decode_for_pg(self, pid, compact):
+        record = make_record({"r": Ref(pid)})
+        _, _, state, _ = zodb_json_codec.decode_zodb_record_for_pg(record, ref_format="int")
+        assert state["r"] == {"@ref": compact}
+
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_decode_for_pg_json(self, pid, compact):
+        record = make_record({"r": Ref(pid)})
+        _, _, state_json, _ = zodb_json_codec.decode_zodb_record_for_pg_json(
+            record, ref_format="int"
+        )
+        assert json.loads(state_json)["r"] == {"@ref": compact}
+
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_decode_batch_async(self, pid, compact):
+        records = [make_record({"r": Ref(pid)})] * 3
+
+        async def decode():
+            return await zodb_json_codec.decode_batch_async(records, ref_format="int")
+
+        for _, _, state_json, _ in asyncio.run(decode()):
+            assert json.loads(state_json)["r"] == {"@ref": compact}
+
+    def test_matches_collected_refs(self):
+        record = make_record({"a": Ref((OID, None)), "b": Ref((HIGH_OID, None))})
+        _, _, state, refs = zodb_json_codec.decode_zodb_record_for_pg(record, ref_format="int")
+        assert sorted([state["a"]["@ref"], state["b"]["@ref"]]) == sorted(refs)
+
+    def test_hex_is_the_default(self):
+        record = make_record({"r": Ref((OID, None))})
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert result["@s"]["r"] == {"@ref": "000000000000002a"}
+
+    def test_per_call(self):
+        record = make_record({"r": Ref((OID, None))})
+        zodb_json_codec.decode_zodb_record(record, ref_format="int")
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert result["@s"]["r"] == {"@ref": "000000000000002a"}
+
+    def test_unknown_format_raises(self):
+        record = make_record({"r": Ref((OID, None))})
+        with pytest.raises(ValueError, match="unknown ref format"):
+            zodb_json_codec.decode_zodb_record(record, ref_format="decimal")
+
+
+class TestEncode:
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_roundtrip(self, pid, compact):
+        record = make_record({"r": Ref(pid)})
+        decoded = zodb_json_codec.decode_zodb_record(record, ref_format="int")
+        encoded = zodb_json_codec.encode_zodb_record(decoded)
+        assert load_state(encoded) == load_state(record)
+
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_int_refs_encode_in_hex_mode(self, pid, compact):
+        record = make_record({"r": Ref(pid)})
+        state = {"r": {"@ref": compact}}
+        cls = ["myapp.models", "Folder"]
+        encoded = zodb_json_codec.encode_zodb_record({"@cls": cls, "@s": state})
+        assert load_state(encoded) == load_state(record)
+        (batch,) = zodb_json_codec.encode_zodb_records_batch(
+            [{"@cls": cls, "@s": state}]
+        )
+        assert load_state(batch) == load_state(record)
+
+    def test_unsigned_oid(self):
+        state = {"r": {"@ref": 2**64 - 1}}
+        encoded = zodb_json_codec.encode_zodb_record(
+            {"@cls": ["myapp.models", "Folder"], "@s": state}
+        )
+        assert load_state(encoded) == {"r": ("pid", (HIGH_OID, None))}
+
+    def test_oid_out_of_range_raises(self):
+        state = {"r": {"@ref": 2**64}}
+        with pytest.raises(ValueError):
+            zodb_json_codec.encode_zodb_record(
+                {"@cls": ["myapp.models", "Folder"], "@s": state}
+            )
@@ -0,0 +1,120 @@
+"""Test weak and multi-database persistent reference forms in @ref."""
+
+import io
+import json
+import pickle
+import pytest
+import zodb_json_codec
+
+
+class Ref:
+    """Stand-in for a persistent object, pickled with a given persistent id."""
+
+    def __init__(self, pid):
+        self.pid = pid
+
+
+class RefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return obj.pid
+        return None
+
+
+class Index:
+    pass
+
+
+class PidUnpickler(pickle.Unpickler):
+    def persistent_load(self, pid):
+        return ("pid", pid)
+
+
+def make_record(state, protocol=3):
+    buf = io.BytesIO()
+    RefPickler(buf, protocol=protocol).dump(state)
+    return pickle.dumps(("myapp.models", "Folder"), protocol=protocol) + buf.getvalue()
+
+
+def load_state(record):
+    f = io.BytesIO(record)
+    pickle.load(f)
+    return PidUnpickler(f).load()
+
+
+OID = b"\x00" * 7 + b"\x07"
+HEX = "0000000000000007"
+
+FORMS = [
+    ([OID], [HEX]),
+    (["w", (OID,)], {"oid": HEX, "weak": True}),
+    (["w", (OID, "catalog")], {"oid": HEX, "weak": True, "db": "catalog"}),
+    (["n", ("catalog", OID)], {"oid": HEX, "db": "catalog"}),
+    (
+        ["m", ("catalog", OID, Index)],
+        {"oid": HEX, "db": "catalog", "cls": f"{__name__}.Index"},
+    ),
+]
+
+
+class TestDecode:
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_decode_zodb_record(self, pid, compact):
+        result = zodb_json_codec.decode_zodb_record(make_record({"r": Ref(pid)}))
+        assert result["@s"]["r"] == {"@ref": compact}
+
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_decode_for_pg_json(self, pid, compact):
+        record = make_record({"r": Ref(pid)})
+        _, _, state_json, _ = zodb_json_codec.decode_zodb_record_for_pg_json(record)
+        assert json.loads(state_json)["r"] == {"@ref": compact}
+
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_decode_for_pg(self, pid, compact):
+        record = make_record({"r": Ref(pid)})
+        _, _, state, _ = zodb_json_codec.decode_zodb_record_for_pg(record)
+        assert state["r"] == {"@ref": compact}
+
+    def test_unknown_tag_uses_generic_form(self):
+        record = make_record({"r": Ref(["x", (OID,)])})
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert result["@s"]["r"] == {"@ref": ["x", {"@t": [{"@b": "AAAAAAAAAAc="}]}]}
+
+
+class TestRoundtrip:
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_encode_zodb_record(self, pid, compact):
+        record = make_record({"r": Ref(pid)})
+        decoded = zodb_json_codec.decode_zodb_record(record)
+        encoded = zodb_json_codec.encode_zodb_record(decoded)
+        assert load_state(encoded) == load_state(record)
+
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_encode_zodb_records_batch(self, pid, compact):
+        record = make_record({"r": [Ref(pid), Ref(pid)]})
+        decoded = zodb_json_codec.decode_zodb_record(record)
+        (encoded,) = zodb_json_codec.encode_zodb_records_batch([decoded])
+        assert load_state(encoded) == load_state(record)
+
+    @pytest.mark.parametrize("pid,compact", FORMS)
+    def test_from_pg_json(self, pid, compact):
+        record = make_record({"r": Ref(pid)})
+        _, _, state_json, _ = zodb_json_codec.decode_zodb_record_for_pg_json(record)
+        encoded = zodb_json_codec.encode_zodb_record(
+            {"@cls": ["myapp.models", "Folder"], "@s": json.loads(state_json)}
+        )
+        assert load_state(encoded) == load_state(record)
+
+    def test_hand_written_ref(self):
+        state = {"r": {"@ref": {"oid": HEX, "weak": True, "db": "catalog"}}}
+        encoded = zodb_json_codec.encode_zodb_record(
+            {"@cls": ["myapp.models", "Folder"], "@s": state}
+        )
+        assert load_state(encoded) == {"r": ("pid", ["w", (OID, "catalog")])}
+
+    def test_invalid_oid_hex_raises(self):
+        state = {"r": {"@ref": {"oid": "zz", "db": "catalog"}}}
+        with pytest.raises(ValueError):
+            zodb_json_codec.encode_zodb_record(
+                {"@cls": ["myapp.models", "Folder"], "@s": state}
+            )
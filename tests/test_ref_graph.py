@@ -0,0 +1,144 @@
+"""Test persistent reference graphs (record_refs_to_edges / reachable_oids)."""
+
+import io
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+class Ref:
+    """Stand-in for a persistent object, pickled as a persistent reference."""
+
+    def __init__(self, oid, klass=None):
+        self.oid = oid
+        self.klass = klass
+
+
+class Folder:
+    pass
+
+
+class RefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            if obj.klass is None:
+                return obj.oid
+            return (obj.oid, obj.klass)
+        return None
+
+
+def make_record(state, cls=("myapp.models", "Folder")):
+    buf = io.BytesIO()
+    RefPickler(buf, protocol=3).dump(state)
+    return pickle.dumps(cls, protocol=3) + buf.getvalue()
+
+
+def p64(n):
+    return n.to_bytes(8, "big")
+
+
+class TestRecordRefsToEdges:
+    def test_edges(self):
+        record = make_record({"a": Ref(p64(2), Folder), "b": [Ref(p64(3))]})
+        edges = zodb_json_codec.record_refs_to_edges(1, record)
+        assert edges == [(1, 2, f"{__name__}.Folder"), (1, 3, "")]
+
+    def test_bytes_oid(self):
+        record = make_record({"a": Ref(p64(2))})
+        assert zodb_json_codec.record_refs_to_edges(p64(1), record) == [(1, 2, "")]
+
+    def test_one_edge_per_target(self):
+        record = make_record([Ref(p64(2)), Ref(p64(2), Folder), Ref(p64(2))])
+        edges = zodb_json_codec.record_refs_to_edges(1, record)
+        assert edges == [(1, 2, f"{__name__}.Folder")]
+
+    def test_cross_database_skipped(self):
+        record = make_record({"a": Ref(["m", ("other", p64(2), Folder)])})
+        assert zodb_json_codec.record_refs_to_edges(1, record) == []
+
+    def test_truncated_raises(self):
+        record = make_record({"a": Ref(p64(2))})
+        with pytest.raises(ValueError):
+            zodb_json_codec.record_refs_to_edges(1, record[:-1])
+
+
+class TestRecordRefsToEdgesBatch:
+    def records(self):
+        return [
+            (0, make_record({"docs": [Ref(p64(1)), Ref(p64(2))]}, ("myapp", "Root"))),
+            (1, make_record({"parent": Ref(p64(0))}, ("myapp", "Doc"))),
+        ]
+
+    def test_classes_filled_from_records(self):
+        edges = zodb_json_codec.record_refs_to_edges_batch(self.records())
+        assert edges == [(0, 1, "myapp.Doc"), (0, 2, ""), (1, 0, "myapp.Root")]
+
+    def test_dot(self):
+        dot = io.StringIO()
+        zodb_json_codec.record_refs_to_edges_batch(self.records(), dot=dot)
+        assert dot.getvalue() == (
+            "digraph refs {\n"
+            '  1 [label="1\\nmyapp.Doc"];\n'
+            '  0 [label="0\\nmyapp.Root"];\n'
+            "  0 -> 1;\n"
+            "  0 -> 2;\n"
+            "  1 -> 0;\n"
+            "}\n"
+        )
+
+    def test_csv(self):
+        out = io.StringIO()
+        zodb_json_codec.record_refs_to_edges_batch(self.records(), csv=out)
+        assert out.getvalue().splitlines() == [
+            "source,target,class",
+            "0,1,myapp.Doc",
+            "0,2,",
+            "1,0,myapp.Root",
+        ]
+
+    def test_error_names_record(self):
+        records = self.records()
+        records[1] = (1, records[1][1][:-1])
+        with pytest.raises(ValueError, match="record 1"):
+            zodb_json_codec.record_refs_to_edges_batch(records)
+
+
+class TestReachableOids:
+    def storage(self):
+        return {
+            0: make_record({"a": Ref(p64(1)), "b": Ref(p64(9))}),
+            1: make_record({"parent": Ref(p64(0)), "weak": Ref([p64(2)])}),
+            2: make_record({}),
+            3: make_record({"parent": Ref(p64(0))}),
+        }
+
+    def test_closure(self):
+        storage = self.storage()
+        loaded = []
+
+        def load(oid):
+            loaded.append(oid)
+            return storage.get(oid)
+
+        assert zodb_json_codec.reachable_oids(0, load) == {0, 1, 2}
+        assert sorted(loaded) == [0, 1, 2, 9]
+
+    def test_missing(self):
+        missing = []
+        reachable = zodb_json_codec.reachable_oids(p64(0), self.storage().get, missing=missing)
+        assert 9 not in reachable
+        assert missing == [9]
+
+    def test_loader_error_propagates(self):
+        def load(oid):
+            raise KeyError(oid)
+
+        with pytest.raises(KeyError):
+            zodb_json_codec.reachable_oids(0, load)
+
+    def test_decode_error_names_oid(self):
+        storage = self.storage()
+        storage[2] = storage[2][:-1]
+        with pytest.raises(ValueError, match="oid 2"):
+            zodb_json_codec.reachable_oids(0, storage.get)
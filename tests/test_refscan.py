@@ -0,0 +1,138 @@
+"""Test persistent reference scanning (count_refs / has_ref_to)."""
+
+import io
+import pickle
+import pytest
+import zodb_json_codec
+
+
+class Ref:
+    """Stand-in for a persistent object, pickled as a persistent reference."""
+
+    def __init__(self, oid):
+        self.oid = oid
+
+
+class RefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return (obj.oid, None)
+        return None
+
+
+def make_record(state, protocol=3):
+    buf = io.BytesIO()
+    RefPickler(buf, protocol=protocol).dump(state)
+    return pickle.dumps(("myapp.models", "Folder"), protocol=protocol) + buf.getvalue()
+
+
+OID1 = b"\x00" * 7 + b"\x01"
+OID2 = b"\x00" * 7 + b"\x02"
+OID3 = b"\x00" * 7 + b"\x03"
+
+
+class TestCountRefs:
+    def test_no_refs(self):
+        assert zodb_json_codec.count_refs(make_record({"a": OID1})) == 0
+
+    def test_counts_every_occurrence(self):
+        record = make_record({"a": Ref(OID1), "b": [Ref(OID2), Ref(OID1)]})
+        assert zodb_json_codec.count_refs(record) == 3
+
+    def test_truncated_raises(self):
+        record = make_record({"a": Ref(OID1)})
+        with pytest.raises(ValueError):
+            zodb_json_codec.count_refs(record[:-1])
+
+
+class TestHasRefTo:
+    def test_bytes_oid(self):
+        record = make_record({"a": Ref(OID1), "b": [Ref(OID2)]})
+        assert zodb_json_codec.has_ref_to(record, OID1)
+        assert zodb_json_codec.has_ref_to(record, OID2)
+        assert not zodb_json_codec.has_ref_to(record, OID3)
+
+    def test_int_oid(self):
+        record = make_record({"a": Ref(OID2)})
+        assert zodb_json_codec.has_ref_to(record, 2)
+        assert not zodb_json_codec.has_ref_to(record, 1)
+
+    def test_plain_bytes_value_is_not_a_ref(self):
+        record = make_record({"raw": OID3, "a": Ref(OID1)})
+        assert not zodb_json_codec.has_ref_to(record, OID3)
+
+    @pytest.mark.parametrize("protocol", [3, 4])
+    def test_memoized_oid(self, protocol):
+        # The same oid bytes object is pickled once and fetched via the memo
+        state = {"a": Ref(OID1), "b": Ref(OID2), "c": Ref(OID1)}
+        record = make_record(state, protocol=protocol)
+        assert zodb_json_codec.count_refs(record) == 3
+        assert zodb_json_codec.has_ref_to(record, OID1)
+        assert not zodb_json_codec.has_ref_to(record, OID3)
+
+    def test_invalid_oid_length(self):
+        with pytest.raises(ValueError, match="8 bytes"):
+            zodb_json_codec.has_ref_to(make_record({}), b"\x01")
+
+
+class PidRef:
+    """Stand-in pickled with an arbitrary persistent id."""
+
+    def __init__(self, pid):
+        self.pid = pid
+
+
+class PidPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return (obj.oid, None)
+        if isinstance(obj, PidRef):
+            return obj.pid
+        return None
+
+
+class Catalog:
+    pass
+
+
+def make_pid_record(state):
+    buf = io.BytesIO()
+    PidPickler(buf, protocol=3).dump(state)
+    return pickle.dumps(("myapp.models", "Folder"), protocol=3) + buf.getvalue()
+
+
+class TestCollectRefsEx:
+    def test_all_forms(self):
+        record = make_pid_record(
+            [
+                Ref(OID1),
+                PidRef((OID2, Catalog)),
+                PidRef([OID3]),
+                PidRef(["w", (OID1,)]),
+                PidRef(["w", (OID2, "other")]),
+                PidRef(["n", ("other", OID3)]),
+                PidRef(["m", ("other", OID1, Catalog)]),
+            ]
+        )
+        cls = (__name__, "Catalog")
+        assert zodb_json_codec.collect_refs_ex(record) == [
+            (OID1, None, None),
+            (OID2, cls, None),
+            (OID3, None, None),
+            (OID1, None, None),
+            (OID2, None, "other"),
+            (OID3, None, "other"),
+            (OID1, cls, "other"),
+        ]
+
+    def test_any_oid_width(self):
+        record = make_pid_record({"a": PidRef((b"\x01\x02", None))})
+        assert zodb_json_codec.collect_refs_ex(record) == [(b"\x01\x02", None, None)]
+
+    def test_no_refs(self):
+        assert zodb_json_codec.collect_refs_ex(make_record({"a": OID1})) == []
+
+    def test_truncated_raises(self):
+        record = make_record({"a": Ref(OID1)})
+        with pytest.raises(ValueError):
+            zodb_json_codec.collect_refs_ex(record[:-1])
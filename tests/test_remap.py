@@ -0,0 +1,194 @@
+"""Test OID remapping (remap_oids, remap_storage)."""
+
+import io
+import pickle
+import struct
+from collections import OrderedDict
+
+import pytest
+import zodb_json_codec
+
+
+class Ref:
+    """Stand-in for a persistent object, pickled as a persistent reference."""
+
+    def __init__(self, oid):
+        self.oid = oid
+
+
+class RefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return (obj.oid, None)
+        return None
+
+
+class RefUnpickler(pickle.Unpickler):
+    def persistent_load(self, pid):
+        return ("ref", pid[0])
+
+
+def make_record(state, protocol=3):
+    buf = io.BytesIO()
+    RefPickler(buf, protocol=protocol).dump(state)
+    return pickle.dumps(("myapp.models", "Folder"), protocol=protocol) + buf.getvalue()
+
+
+class ClassRefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return (obj.oid, OrderedDict)
+        return None
+
+
+def make_shared_record(cls, state):
+    """A record written by one pickler, as ZODB does: a ref's class can be
+    a memo reference into the class pickle."""
+    buf = io.BytesIO()
+    pickler = ClassRefPickler(buf, protocol=3)
+    pickler.dump((cls, None))
+    pickler.dump(state)
+    return buf.getvalue()
+
+
+def load_state(record):
+    f = io.BytesIO(record)
+    pickle.load(f)
+    return RefUnpickler(f).load()
+
+
+def oid(n):
+    return struct.pack(">Q", n)
+
+
+def write_mapping(path, pairs):
+    with open(path, "wb") as f:
+        for old, new in sorted(pairs):
+            f.write(oid(old) + oid(new))
+    return str(path)
+
+
+def read_stream(data):
+    out = []
+    pos = 0
+    while pos < len(data):
+        length = struct.unpack(">I", data[pos + 8 : pos + 12])[0]
+        out.append((data[pos : pos + 8], data[pos + 12 : pos + 12 + length]))
+        pos += 12 + length
+    return out
+
+
+class TestRemapStorage:
+    def test_rewrites_oids_and_refs(self, tmp_path):
+        mapping = write_mapping(tmp_path / "map.bin", [(1, 101), (2, 102)])
+        records = [
+            (oid(1), make_record({"child": Ref(oid(2)), "other": Ref(oid(3))})),
+            (oid(2), make_record({"title": "leaf"})),
+        ]
+        out = io.BytesIO()
+        assert zodb_json_codec.remap_storage(iter(records), mapping, out) == 2
+
+        result = read_stream(out.getvalue())
+        assert [o for o, _ in result] == [oid(101), oid(102)]
+        assert load_state(result[0][1]) == {
+            "child": ("ref", oid(102)),
+            "other": ("ref", oid(3)),
+        }
+        # No rewritten refs: record bytes are untouched
+        assert result[1][1] == records[1][1]
+
+    def test_unmapped_record_oid_kept(self, tmp_path):
+        mapping = write_mapping(tmp_path / "map.bin", [(1, 101)])
+        record = make_record([Ref(oid(1))])
+        out = io.BytesIO()
+        zodb_json_codec.remap_storage([(oid(7), record)], mapping, out)
+        [(new_oid, data)] = read_stream(out.getvalue())
+        assert new_oid == oid(7)
+        assert load_state(data) == [("ref", oid(101))]
+
+    def test_protocol_4_record(self, tmp_path):
+        mapping = write_mapping(tmp_path / "map.bin", [(5, 6)])
+        record = make_record({"a": Ref(oid(5))}, protocol=4)
+        out = io.BytesIO()
+        zodb_json_codec.remap_storage([(oid(9), record)], mapping, out)
+        [(_, data)] = read_stream(out.getvalue())
+        assert load_state(data) == {"a": ("ref", oid(6))}
+
+    def test_shared_memo_record(self, tmp_path):
+        mapping = write_mapping(tmp_path / "map.bin", [(2, 20)])
+        record = make_shared_record(OrderedDict, {"child": Ref(oid(2))})
+        out = io.BytesIO()
+        zodb_json_codec.remap_storage([(oid(1), record)], mapping, out)
+        [(_, data)] = read_stream(out.getvalue())
+        f = io.BytesIO(data)
+        assert pickle.load(f) == (OrderedDict, None)
+        unpickler = pickle.Unpickler(f)
+        unpickler.persistent_load = lambda pid: pid
+        assert unpickler.load() == {"child": (oid(20), OrderedDict)}
+
+    def test_unsorted_mapping_rejected(self, tmp_path):
+        path = tmp_path / "map.bin"
+        path.write_bytes(oid(2) + oid(20) + oid(1) + oid(10))
+        with pytest.raises(ValueError, match="not sorted"):
+            zodb_json_codec.remap_storage([], str(path), io.BytesIO())
+
+    def test_truncated_mapping_rejected(self, tmp_path):
+        path = tmp_path / "map.bin"
+        path.write_bytes(oid(1) + oid(10)[:4])
+        with pytest.raises(ValueError, match="multiple of 16"):
+            zodb_json_codec.remap_storage([], str(path), io.BytesIO())
+
+    def test_missing_mapping_file(self, tmp_path):
+        with pytest.raises(ValueError, match="I/O error"):
+            zodb_json_codec.remap_storage([], str(tmp_path / "nope"), io.BytesIO())
+
+    def test_bad_oid_length(self, tmp_path):
+        mapping = write_mapping(tmp_path / "map.bin", [])
+        with pytest.raises(ValueError, match="8 bytes"):
+            zodb_json_codec.remap_storage(
+                [(b"\x01", make_record({}))], mapping, io.BytesIO()
+            )
+
+
+class TestRemapOids:
+    def test_rewrites_refs(self):
+        record = make_record({"child": Ref(oid(2)), "other": Ref(oid(3))})
+        result = zodb_json_codec.remap_oids(record, {oid(2): oid(102)})
+        assert load_state(result) == {
+            "child": ("ref", oid(102)),
+            "other": ("ref", oid(3)),
+        }
+        # The class pickle is copied as-is
+        assert pickle.loads(result) == ("myapp.models", "Folder")
+
+    def test_btree_children_and_next_link(self):
+        # OOBTree state: ((child, key, child), firstbucket)
+        btree = make_record(((Ref(oid(1)), "m", Ref(oid(2))), Ref(oid(1))))
+        result = zodb_json_codec.remap_oids(btree, {oid(1): oid(11), oid(2): oid(12)})
+        assert load_state(result) == (
+            (("ref", oid(11)), "m", ("ref", oid(12))),
+            ("ref", oid(11)),
+        )
+        # OOBucket state: ((key, value, ...), next)
+        bucket = make_record((("a", 1), Ref(oid(4))))
+        result = zodb_json_codec.remap_oids(bucket, {oid(4): oid(14)})
+        assert load_state(result) == (("a", 1), ("ref", oid(14)))
+
+    def test_unchanged_record_returned_as_is(self):
+        record = make_record([Ref(oid(3))])
+        assert zodb_json_codec.remap_oids(record, {oid(1): oid(2)}) == record
+        assert zodb_json_codec.remap_oids(bytearray(record), {}) == record
+
+    def test_decoded_record_matches(self):
+        record = make_record({"items": [Ref(oid(1)), Ref(oid(1))]})
+        result = zodb_json_codec.decode_zodb_record(
+            zodb_json_codec.remap_oids(record, {oid(1): oid(5)})
+        )
+        assert result["@s"]["items"] == [{"@ref": "0000000000000005"}] * 2
+
+    def test_bad_mapping_oid(self):
+        record = make_record({})
+        with pytest.raises(ValueError, match="8 bytes"):
+            zodb_json_codec.remap_oids(record, {b"\x01": oid(2)})
+        with pytest.raises(TypeError):
+            zodb_json_codec.remap_oids(record, {"0001": oid(2)})
@@ -0,0 +1,133 @@
+"""Cyclic and shared containers via the @shared / @backref markers."""
+
+import io
+import json
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+class Node:
+    pass
+
+
+def make_record(state, protocol=3):
+    return pickle.dumps(("myapp", "Folder"), protocol=protocol) + pickle.dumps(
+        state, protocol=protocol
+    )
+
+
+class TestCycles:
+    @pytest.mark.parametrize("protocol", [0, 2, 3, 4, 5])
+    def test_list_containing_itself(self, protocol):
+        lst = [1]
+        lst.append(lst)
+        result = zodb_json_codec.pickle_to_dict(pickle.dumps(lst, protocol=protocol))
+        assert result == {"@shared": [0, [1, {"@backref": 0}]]}
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle(result))
+        assert restored[0] == 1
+        assert restored[1] is restored
+
+    def test_dict_containing_itself_json(self):
+        d = {"name": "root"}
+        d["self"] = d
+        json_str = zodb_json_codec.pickle_to_json(pickle.dumps(d, protocol=3))
+        restored = pickle.loads(zodb_json_codec.json_to_pickle(json_str))
+        assert restored["name"] == "root"
+        assert restored["self"] is restored
+
+    def test_instance_referring_to_itself(self):
+        node = Node()
+        node.me = node
+        data = pickle.dumps(node, protocol=2)
+        restored = pickle.loads(
+            zodb_json_codec.dict_to_pickle(zodb_json_codec.pickle_to_dict(data))
+        )
+        assert isinstance(restored, Node)
+        assert restored.me is restored
+
+    def test_record_state_cycle(self):
+        children = []
+        state = {"title": "Root", "children": children}
+        children.append(state)
+        result = zodb_json_codec.decode_zodb_record(make_record(state))
+        assert result["@s"] == {
+            "@shared": [0, {"title": "Root", "children": [{"@backref": 0}]}]
+        }
+        encoded = zodb_json_codec.encode_zodb_record(result)
+        unpickler = pickle.Unpickler(io.BytesIO(encoded))
+        assert unpickler.load() == (("myapp", "Folder"), None)
+        restored = unpickler.load()
+        assert restored["children"][0] is restored
+
+    def test_plain_pickles_unchanged(self):
+        val = {"a": [1, 2], "b": {"c": None}}
+        assert zodb_json_codec.pickle_to_dict(pickle.dumps(val, protocol=3)) == val
+
+    def test_backref_without_shared_rejected(self):
+        with pytest.raises(ValueError, match="@backref 3"):
+            zodb_json_codec.dict_to_pickle({"items": [{"@backref": 3}]})
+
+
+class TestAliasing:
+    def test_aliases_copied_by_default(self):
+        inner = [1, 2]
+        result = zodb_json_codec.pickle_to_dict(
+            pickle.dumps([inner, inner], protocol=3)
+        )
+        assert result == [[1, 2], [1, 2]]
+
+    def test_aliases_kept_when_enabled(self):
+        inner = [1, 2]
+        result = zodb_json_codec.pickle_to_dict(
+            pickle.dumps([inner, inner], protocol=3), shared_references=True
+        )
+        assert result == [{"@shared": [0, [1, 2]]}, {"@backref": 0}]
+        restored = pickle.loads(zodb_json_codec.json_to_pickle(json.dumps(result)))
+        assert restored[0] is restored[1]
+        # Only that call keeps them
+        assert zodb_json_codec.pickle_to_dict(
+            pickle.dumps([inner, inner], protocol=3)
+        ) == [[1, 2], [1, 2]]
+
+    def test_backref_key_sorted_before_shared(self):
+        d = [1]
+        json_str = zodb_json_codec.pickle_to_json(
+            pickle.dumps({"b": d, "a": d}), shared_references=True
+        )
+        assert json.loads(json_str) == {
+            "a": {"@backref": 0},
+            "b": {"@shared": [0, [1]]},
+        }
+        restored = pickle.loads(zodb_json_codec.json_to_pickle(json_str))
+        assert restored == {"a": [1], "b": [1]}
+        assert restored["a"] is restored["b"]
+        # A dict from json.loads, with sorted keys, through the direct encoders
+        sorted_dict = dict(sorted(json.loads(json_str).items()))
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle(sorted_dict))
+        assert restored["a"] is restored["b"]
+        record = zodb_json_codec.decode_zodb_record(
+            make_record({"b": d, "a": d}), shared_references=True
+        )
+        record = json.loads(json.dumps(record, sort_keys=True))
+        unpickler = pickle.Unpickler(
+            io.BytesIO(zodb_json_codec.encode_zodb_record(record))
+        )
+        assert unpickler.load() == (("myapp", "Folder"), None)
+        restored = unpickler.load()
+        assert restored["a"] is restored["b"]
+
+    def test_aliased_dicts_in_record(self):
+        sizes = [{"label": "Square"}, {"label": "Large"}]
+        state = {"sizes": sizes, "by_label": {s["label"]: s for s in sizes}}
+        result = zodb_json_codec.decode_zodb_record(
+            make_record(state), shared_references=True
+        )
+        by_label = result["@s"]["by_label"]
+        assert by_label == {"Square": {"@backref": 0}, "Large": {"@backref": 1}}
+        encoded = zodb_json_codec.encode_zodb_record(result)
+        unpickler = pickle.Unpickler(io.BytesIO(encoded))
+        unpickler.load()
+        restored = unpickler.load()
+        assert restored["by_label"]["Square"] is restored["sizes"][0]
@@ -0,0 +1,70 @@
+"""Test deterministic key order (sort_keys=True)."""
+
+import json
+import pickle
+
+import zodb_json_codec
+
+
+STATE = {"title": "Doc", "body": {"z": 1, "a": [{"y": 2, "b": 3}]}, "Z": None}
+# Same dict, other insertion order
+REORDERED = {"Z": None, "body": {"a": [{"b": 3, "y": 2}], "z": 1}, "title": "Doc"}
+
+
+def make_record(state):
+    return pickle.dumps(("myapp", "Doc"), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+def keys(obj):
+    """All object keys, depth first."""
+    if isinstance(obj, dict):
+        return [(k, keys(v)) for k, v in obj.items()]
+    if isinstance(obj, list):
+        return [keys(v) for v in obj]
+    return None
+
+
+class TestPickleToJson:
+    def test_sorted(self):
+        text = zodb_json_codec.pickle_to_json(pickle.dumps(STATE, protocol=3), sort_keys=True)
+        assert text == json.dumps(STATE, indent=2, sort_keys=True)
+
+    def test_hash_stable(self):
+        a = zodb_json_codec.pickle_to_json(pickle.dumps(STATE, protocol=3), sort_keys=True)
+        b = zodb_json_codec.pickle_to_json(pickle.dumps(REORDERED, protocol=3), sort_keys=True)
+        assert a == b
+
+    def test_default_keeps_insertion_order(self):
+        text = zodb_json_codec.pickle_to_json(pickle.dumps(STATE, protocol=3), indent=None)
+        assert list(json.loads(text)) == ["title", "body", "Z"]
+
+    def test_pair_lists_keep_order(self):
+        data = pickle.dumps({"k": {3: "c", 1: "a", 2: "b"}}, protocol=3)
+        text = zodb_json_codec.pickle_to_json(data, indent=None, sort_keys=True)
+        assert text == '{"k":{"@d":[[3,"c"],[1,"a"],[2,"b"]]}}'
+        assert pickle.loads(zodb_json_codec.json_to_pickle(text)) == {"k": {3: "c", 1: "a", 2: "b"}}
+
+
+class TestDecodeZodbRecord:
+    def test_sorted(self):
+        result = zodb_json_codec.decode_zodb_record(make_record(STATE), sort_keys=True)
+        assert list(result) == ["@cls", "@s"]
+        assert keys(result["@s"]) == keys(json.loads(json.dumps(STATE, sort_keys=True)))
+
+    def test_hash_stable(self):
+        a = zodb_json_codec.decode_zodb_record(make_record(STATE), sort_keys=True)
+        b = zodb_json_codec.decode_zodb_record(make_record(REORDERED), sort_keys=True)
+        assert json.dumps(a) == json.dumps(b)
+
+    def test_btree_items_keep_order(self):
+        state = (("b", 1, "a", 2),)
+        record = pickle.dumps(("BTrees.OOBTree", "OOBucket"), protocol=3) + pickle.dumps(
+            state, protocol=3
+        )
+        result = zodb_json_codec.decode_zodb_record(record, sort_keys=True)
+        assert result["@s"] == {"@kv": [["b", 1], ["a", 2]]}
+
+    def test_scope_ends(self):
+        zodb_json_codec.decode_zodb_record(make_record(STATE), sort_keys=True)
+        result = zodb_json_codec.pickle_to_dict(pickle.dumps(STATE, protocol=3))
+        assert list(result) == ["title", "body", "Z"]
@@ -0,0 +1,91 @@
+"""strict=True: reject values that would fall back to @reduce or @pkl."""
+
+from datetime import date
+from datetime import datetime
+from datetime import timedelta
+from datetime import timezone
+from datetime import tzinfo
+
+import pickle
+import pytest
+import zodb_json_codec
+
+
+class Zone(tzinfo):
+    """A timezone class the codec has no typed marker for."""
+
+    def utcoffset(self, dt):
+        return timedelta(hours=1)
+
+
+class Thing:
+    def __reduce__(self):
+        return (Thing, ())
+
+
+def make_record(state):
+    return pickle.dumps(("myapp", "Doc"), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+def test_known_types_pass():
+    state = {
+        "when": datetime(2025, 1, 2, 3, 4, 5, tzinfo=timezone.utc),
+        "day": date(2025, 1, 2),
+        "tags": {"a", "b"},
+        "pair": (1, "x"),
+    }
+    record = make_record(state)
+    assert zodb_json_codec.decode_zodb_record(record, strict=True)["@cls"] == ["myapp", "Doc"]
+    assert zodb_json_codec.pickle_to_dict(pickle.dumps(state, protocol=3), strict=True)
+
+
+def test_not_strict_by_default():
+    result = zodb_json_codec.pickle_to_dict(pickle.dumps([Thing()], protocol=3))
+    assert "@reduce" in result[0]
+
+
+def test_unknown_reduce():
+    data = pickle.dumps({"items": [1, Thing()]}, protocol=3)
+    for decode in (zodb_json_codec.pickle_to_dict, zodb_json_codec.pickle_to_json):
+        with pytest.raises(zodb_json_codec.CodecError, match="strict mode") as exc:
+            decode(data, strict=True)
+        assert exc.value.path == "$.items[1]"
+        assert f"no typed marker for {__name__}.Thing" in exc.value.reason
+
+
+def test_unknown_timezone():
+    record = make_record({"when": datetime(2025, 1, 2, tzinfo=Zone())})
+    with pytest.raises(zodb_json_codec.CodecError) as exc:
+        zodb_json_codec.decode_zodb_record(record, strict=True)
+    assert exc.value.path == '$["@s"].when'
+    assert "datetime.datetime with an unknown timezone" in exc.value.reason
+
+
+def test_record_functions():
+    record = make_record({"nested": (Thing(),)})
+    for decode in (
+        zodb_json_codec.decode_zodb_state,
+        zodb_json_codec.decode_zodb_record_for_pg,
+        zodb_json_codec.decode_zodb_record_for_pg_json,
+    ):
+        decode(record)
+        with pytest.raises(zodb_json_codec.CodecError) as exc:
+            decode(record, strict=True)
+        assert exc.value.path == '$.nested["@t"][0]'
+
+
+def test_raw_pickle():
+    state = b"\x80\x03}X\x01\x00\x00\x00a\xff."
+    record = pickle.dumps(("myapp", "Doc"), protocol=3) + state
+    assert "@pkl" in zodb_json_codec.decode_zodb_record(record, lenient=True)["@s"]
+    with pytest.raises(zodb_json_codec.CodecError, match="would be stored as @pkl"):
+        zodb_json_codec.decode_zodb_record(record, strict=True, lenient=True)
+
+
+def test_registered_type_counts_as_known():
+    zodb_json_codec.register_type_handler(__name__, "Thing", "@thing", spec="args")
+    try:
+        data = pickle.dumps([Thing()], protocol=3)
+        zodb_json_codec.pickle_to_dict(data, strict=True)
+    finally:
+        zodb_json_codec.unregister_type_handler(__name__, "Thing")
@@ -0,0 +1,129 @@
+"""Test subtree extraction and grafting on ZODB records."""
+
+import io
+import pickle
+from collections import OrderedDict
+
+import pytest
+import zodb_json_codec
+
+
+class Ref:
+    """Stand-in for a persistent object, pickled as a persistent reference."""
+
+    def __init__(self, oid):
+        self.oid = oid
+
+
+class RefPickler(pickle.Pickler):
+    def persistent_id(self, obj):
+        if isinstance(obj, Ref):
+            return (obj.oid, getattr(obj, "cls", None))
+        return None
+
+
+class RefUnpickler(pickle.Unpickler):
+    def persistent_load(self, pid):
+        return ("ref", pid[0])
+
+
+def dumps(obj):
+    buf = io.BytesIO()
+    RefPickler(buf, protocol=3).dump(obj)
+    return buf.getvalue()
+
+
+def loads(data):
+    return RefUnpickler(io.BytesIO(data)).load()
+
+
+def make_record(state):
+    return pickle.dumps(("myapp.models", "Folder"), protocol=3) + dumps(state)
+
+
+def make_shared_record(cls, state):
+    """A record written by one pickler, as ZODB does: the state pickle can
+    refer to memo entries of the class pickle."""
+    buf = io.BytesIO()
+    pickler = RefPickler(buf, protocol=3)
+    pickler.dump((cls, None))
+    pickler.dump(state)
+    return buf.getvalue()
+
+
+def class_ref(oid, cls):
+    ref = Ref(oid)
+    ref.cls = cls
+    return ref
+
+
+OID1 = b"\x00" * 7 + b"\x01"
+OID2 = b"\x00" * 7 + b"\x02"
+
+
+class TestExtractSubtree:
+    def test_extract_nested_value(self):
+        record = make_record({"meta": {"title": "Hello", "tags": ["a", "b"]}})
+        sub = zodb_json_codec.extract_subtree(record, "meta/tags")
+        assert pickle.loads(sub) == ["a", "b"]
+
+    def test_persistent_refs_preserved(self):
+        record = make_record({"items": [Ref(OID1), Ref(OID2)]})
+        sub = zodb_json_codec.extract_subtree(record, "items")
+        assert loads(sub) == [("ref", OID1), ("ref", OID2)]
+
+    def test_shared_memo_record(self):
+        # The ref's class is a BINGET of the class pickle's memo entry
+        record = make_shared_record(
+            OrderedDict, {"child": class_ref(OID1, OrderedDict)}
+        )
+        sub = zodb_json_codec.extract_subtree(record, "child")
+        unpickler = pickle.Unpickler(io.BytesIO(sub))
+        unpickler.persistent_load = lambda pid: pid
+        assert unpickler.load() == (OID1, OrderedDict)
+
+    def test_graft_shared_memo_record(self):
+        record = make_shared_record(
+            OrderedDict, {"title": "Old", "child": class_ref(OID1, OrderedDict)}
+        )
+        result = zodb_json_codec.graft_subtree(record, "title", dumps("New"))
+        decoded = zodb_json_codec.decode_zodb_record(result)
+        assert decoded["@s"]["title"] == "New"
+        assert decoded["@s"]["child"] == zodb_json_codec.decode_zodb_record(record)["@s"]["child"]
+
+    def test_empty_path_is_whole_state(self):
+        state = {"a": 1, "b": [2, 3]}
+        sub = zodb_json_codec.extract_subtree(make_record(state), "")
+        assert pickle.loads(sub) == state
+
+    def test_missing_path_raises(self):
+        record = make_record({"a": 1})
+        with pytest.raises(ValueError, match="not found"):
+            zodb_json_codec.extract_subtree(record, "b")
+
+
+class TestGraftSubtree:
+    def test_replace_value(self):
+        record = make_record({"title": "Old", "n": 1})
+        result = zodb_json_codec.graft_subtree(record, "title", dumps("New"))
+        decoded = zodb_json_codec.decode_zodb_record(result)
+        assert decoded["@cls"] == ["myapp.models", "Folder"]
+        assert decoded["@s"] == {"title": "New", "n": 1}
+
+    def test_add_key_and_append(self):
+        record = make_record({"items": [1]})
+        result = zodb_json_codec.graft_subtree(record, "new", dumps(True))
+        result = zodb_json_codec.graft_subtree(result, "items/-", dumps(2))
+        decoded = zodb_json_codec.decode_zodb_record(result)
+        assert decoded["@s"] == {"items": [1, 2], "new": True}
+
+    def test_splice_between_records(self):
+        src = make_record({"children": {"x": Ref(OID1)}})
+        dst = make_record({"title": "Target"})
+        sub = zodb_json_codec.extract_subtree(src, "children")
+        result = zodb_json_codec.graft_subtree(dst, "children", sub)
+        assert zodb_json_codec.extract_subtree(result, "children") == sub
+        assert loads(zodb_json_codec.extract_subtree(result, "children/x")) == (
+            "ref",
+            OID1,
+        )
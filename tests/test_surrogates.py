@@ -0,0 +1,92 @@
+"""Test strings with lone surrogates (the surrogates= keyword)."""
+
+import io
+import json
+import pickle
+
+import pytest
+import zodb_json_codec
+from zodb_json_codec.nodes import Dict
+
+
+# Python 2 bytes decoded with surrogateescape
+LONE = b"caf\xe9".decode("utf-8", "surrogateescape")
+STATE = {"title": LONE, "ok": "plain"}
+
+
+def make_record(state):
+    return pickle.dumps(("myapp", "Doc"), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+class TestPolicies:
+    def test_error_is_the_default(self):
+        with pytest.raises(zodb_json_codec.CodecError):
+            zodb_json_codec.pickle_to_dict(pickle.dumps(STATE, protocol=3))
+
+    def test_replace(self):
+        warnings = []
+        result = zodb_json_codec.pickle_to_dict(
+            pickle.dumps(STATE, protocol=3), warnings=warnings, surrogates="replace"
+        )
+        assert result == {"title": "caf�", "ok": "plain"}
+        assert [w["code"] for w in warnings] == ["surrogates"]
+
+    def test_preserve(self):
+        data = pickle.dumps(STATE, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data, surrogates="preserve")
+        assert result == {"title": {"@su": "caf\\udce9"}, "ok": "plain"}
+
+    def test_per_call(self):
+        data = pickle.dumps(STATE, protocol=3)
+        zodb_json_codec.pickle_to_dict(data, surrogates="preserve")
+        with pytest.raises(zodb_json_codec.CodecError):
+            zodb_json_codec.pickle_to_dict(data)
+
+    def test_unknown_policy(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.pickle_to_dict(b"N.", surrogates="ignore")
+
+
+class TestRoundtrip:
+    def test_json(self):
+        data = pickle.dumps(STATE, protocol=3)
+        text = zodb_json_codec.pickle_to_json(data, surrogates="preserve")
+        assert json.loads(text)["title"] == {"@su": "caf\\udce9"}
+        assert pickle.loads(zodb_json_codec.json_to_pickle(text)) == STATE
+
+    def test_dict(self):
+        data = pickle.dumps(STATE, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data, surrogates="preserve")
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result)) == STATE
+
+    def test_zodb_record(self):
+        state = {"items": [LONE, "😀"]}
+        decoded = zodb_json_codec.decode_zodb_record(make_record(state), surrogates="preserve")
+        assert decoded["@s"]["items"] == [{"@su": "caf\\udce9"}, "😀"]
+        encoded = zodb_json_codec.encode_zodb_record(decoded)
+        unpickler = pickle.Unpickler(io.BytesIO(encoded))
+        assert unpickler.load() == (("myapp", "Doc"), None)
+        assert unpickler.load() == state
+
+    def test_protocol_0(self):
+        data = pickle.dumps(STATE, protocol=0)
+        result = zodb_json_codec.pickle_to_dict(data, surrogates="preserve")
+        assert result["title"] == {"@su": "caf\\udce9"}
+
+    def test_backslash_and_null(self):
+        value = "a\\b\x00" + LONE
+        data = pickle.dumps({"v": value}, protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data, surrogates="preserve")
+        assert result == {"v": {"@su": "a\\\\b\\u0000caf\\udce9"}}
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result)) == {"v": value}
+
+    def test_python_str_with_surrogates(self):
+        data = zodb_json_codec.dict_to_pickle({"title": LONE})
+        assert pickle.loads(data) == {"title": LONE}
+
+    def test_pickle_ast(self):
+        data = pickle.dumps(STATE, protocol=3)
+        tree = zodb_json_codec.decode_pickle_ast(data, surrogates="preserve")
+        assert isinstance(tree, Dict)
+        assert tree.items[0][1].value == LONE
+        assert pickle.loads(zodb_json_codec.encode_pickle_ast(tree)) == STATE
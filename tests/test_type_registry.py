@@ -0,0 +1,131 @@
+"""Known types registered at runtime with register_type_handler()."""
+
+import json
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+class Sku:
+    def __init__(self, code):
+        self.code = code
+
+    def __reduce__(self):
+        return (Sku, (self.code,))
+
+    def __eq__(self, other):
+        return isinstance(other, Sku) and other.code == self.code
+
+
+class Quantity:
+    def __init__(self, amount, unit):
+        self.amount = amount
+        self.unit = unit
+
+    def __reduce__(self):
+        return (Quantity, (self.amount, self.unit))
+
+    def __eq__(self, other):
+        return isinstance(other, Quantity) and (other.amount, other.unit) == (
+            self.amount,
+            self.unit,
+        )
+
+
+class Point:
+    def __init__(self, x, y):
+        self.x = x
+        self.y = y
+
+
+MODULE = Sku.__module__
+
+
+class TestRegisteredTypes:
+    def teardown_method(self, method):
+        for name in ("Sku", "Quantity", "Point"):
+            zodb_json_codec.unregister_type_handler(MODULE, name)
+
+    @pytest.mark.parametrize("protocol", [2, 3, 4])
+    def test_single_arg(self, protocol):
+        zodb_json_codec.register_type_handler(MODULE, "Sku", "@sku")
+        data = pickle.dumps({"sku": Sku("A-100")}, protocol=protocol)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result == {"sku": {"@sku": "A-100"}}
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result)) == {
+            "sku": Sku("A-100")
+        }
+        json_str = zodb_json_codec.pickle_to_json(data)
+        assert json.loads(json_str) == result
+        assert pickle.loads(zodb_json_codec.json_to_pickle(json_str)) == {
+            "sku": Sku("A-100")
+        }
+
+    def test_args(self):
+        zodb_json_codec.register_type_handler(MODULE, "Quantity", "@qty", "args")
+        data = pickle.dumps(Quantity(2.5, "kg"), protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result == {"@qty": [2.5, "kg"]}
+        assert pickle.loads(zodb_json_codec.dict_to_pickle(result)) == Quantity(
+            2.5, "kg"
+        )
+
+    def test_state(self):
+        zodb_json_codec.register_type_handler(MODULE, "Point", "@point", "state")
+        data = pickle.dumps(Point(1, 2), protocol=3)
+        result = zodb_json_codec.pickle_to_dict(data)
+        assert result == {"@point": {"x": 1, "y": 2}}
+        restored = pickle.loads(zodb_json_codec.dict_to_pickle(result))
+        assert (type(restored), restored.x, restored.y) == (Point, 1, 2)
+
+    def test_in_record(self):
+        zodb_json_codec.register_type_handler(MODULE, "Sku", "@sku")
+        record = pickle.dumps(("myapp", "Product"), protocol=3) + pickle.dumps(
+            {"sku": Sku("B-7")}, protocol=3
+        )
+        result = zodb_json_codec.decode_zodb_record(record)
+        assert result["@s"] == {"sku": {"@sku": "B-7"}}
+        _, _, json_str, _ = zodb_json_codec.decode_zodb_record_for_pg_json(record)
+        assert json.loads(json_str) == {"sku": {"@sku": "B-7"}}
+        encoded = zodb_json_codec.encode_zodb_record(result)
+        assert zodb_json_codec.decode_zodb_record(encoded) == result
+
+    def test_unregister(self):
+        zodb_json_codec.register_type_handler(MODULE, "Sku", "@sku")
+        assert zodb_json_codec.unregister_type_handler(MODULE, "Sku")
+        assert not zodb_json_codec.unregister_type_handler(MODULE, "Sku")
+        result = zodb_json_codec.pickle_to_dict(pickle.dumps(Sku("x"), protocol=3))
+        assert "@reduce" in result
+        # The marker is an ordinary key again
+        assert zodb_json_codec.pickle_to_dict(
+            zodb_json_codec.dict_to_pickle({"@sku": "x"})
+        ) == {"@sku": "x"}
+
+    def test_builtin_types_take_precedence(self):
+        zodb_json_codec.register_type_handler("decimal", "Decimal", "@mydec")
+        try:
+            from decimal import Decimal
+
+            data = pickle.dumps(Decimal("1.5"), protocol=3)
+            assert zodb_json_codec.pickle_to_dict(data) == {"@dec": "1.5"}
+        finally:
+            zodb_json_codec.unregister_type_handler("decimal", "Decimal")
+
+    @pytest.mark.parametrize(
+        "marker, spec, match",
+        [
+            ("sku", "arg", "must start with '@'"),
+            ("@dt", "arg", "built-in marker"),
+            ("@tz", "arg", "built-in marker"),
+            ("@sku", "kwargs", "unknown type spec"),
+        ],
+    )
+    def test_invalid_registration(self, marker, spec, match):
+        with pytest.raises(ValueError, match=match):
+            zodb_json_codec.register_type_handler(MODULE, "Sku", marker, spec)
+
+    def test_marker_taken(self):
+        zodb_json_codec.register_type_handler(MODULE, "Sku", "@sku")
+        with pytest.raises(ValueError, match="already registered"):
+            zodb_json_codec.register_type_handler(MODULE, "Quantity", "@sku")
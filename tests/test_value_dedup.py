@@ -0,0 +1,83 @@
+"""Sharing of identical leaf values within a decoded record."""
+
+import pickle
+import zodb_json_codec
+
+
+def make_record(state):
+    return pickle.dumps(("myapp", "Bucket"), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+def distinct(text, n):
+    """n equal strings that are separate objects (so pickle doesn't memoize)."""
+    return ["".join([text[:1], text[1:]]) for _ in range(n)]
+
+
+def decode(record):
+    return zodb_json_codec.decode_zodb_record(record, value_dedup=True)
+
+
+class TestValueDedup:
+    def test_off_by_default(self):
+        state = distinct("visible", 3)
+        result = zodb_json_codec.decode_zodb_record(make_record(state))
+        assert result["@s"] == ["visible"] * 3
+        assert result["@s"][0] is not result["@s"][1]
+
+    def test_strings_shared(self):
+        state = distinct("visible", 100) + distinct("private", 100)
+        values = decode(make_record(state))["@s"]
+        assert values == ["visible"] * 100 + ["private"] * 100
+        assert len({id(v) for v in values}) == 2
+
+    def test_dict_keys_and_values_shared(self):
+        state = [
+            {k: v for k, v in zip(distinct("review_state", 1), distinct("published", 1))}
+            for _ in range(50)
+        ]
+        rows = decode(make_record(state))["@s"]
+        keys = [next(iter(row)) for row in rows]
+        assert len({id(k) for k in keys}) == 1
+        assert len({id(row["review_state"]) for row in rows}) == 1
+
+    def test_numbers_shared(self):
+        state = [float("0.75") for _ in range(10)] + [int("100000") for _ in range(10)]
+        values = decode(make_record(state))["@s"]
+        assert values == [0.75] * 10 + [100000] * 10
+        assert len({id(v) for v in values}) == 2
+        # -0.0 and 0.0 compare equal but are different values
+        values = decode(make_record([0.0, -0.0]))["@s"]
+        assert str(values) == "[0.0, -0.0]"
+
+    def test_markers_not_shared(self):
+        state = [bytes([1, 2, 3]) for _ in range(3)]
+        values = decode(make_record(state))["@s"]
+        values[0]["@b"] = "changed"
+        assert values[1] == {"@b": "AQID"}
+
+    def test_not_shared_across_records(self):
+        record = make_record(distinct("visible", 2))
+        first = decode(record)["@s"]
+        second = decode(record)["@s"]
+        assert first[0] is first[1]
+        assert first[0] is not second[0]
+
+    def test_pg_path(self):
+        record = make_record(distinct("visible", 5))
+        _, _, state, _ = zodb_json_codec.decode_zodb_record_for_pg(record, value_dedup=True)
+        assert len({id(v) for v in state}) == 1
+
+    def test_dict_and_state(self):
+        data = pickle.dumps(distinct("visible", 5), protocol=3)
+        values = zodb_json_codec.pickle_to_dict(data, value_dedup=True)
+        assert len({id(v) for v in values}) == 1
+        record = make_record(distinct("visible", 5))
+        values = zodb_json_codec.decode_zodb_state(record, value_dedup=True)
+        assert len({id(v) for v in values}) == 1
+
+    def test_per_call(self):
+        record = make_record(distinct("visible", 2))
+        values = decode(record)["@s"]
+        assert values[0] is values[1]
+        values = zodb_json_codec.decode_zodb_record(record)["@s"]
+        assert values[0] is not values[1]
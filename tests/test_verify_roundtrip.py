@@ -0,0 +1,83 @@
+"""verify_roundtrip: checking records survive the JSONB round trip."""
+
+import io
+import pickle
+
+import pytest
+import zodb_json_codec
+
+
+def make_record(state, module="myapp", name="Doc"):
+    return pickle.dumps((module, name), protocol=3) + pickle.dumps(state, protocol=3)
+
+
+class TestVerifyRoundtrip:
+    def test_equal(self):
+        record = make_record(
+            {
+                "title": "Hello",
+                "tags": {"a", "b"},
+                "when": (1, 2),
+                "raw": b"\xff",
+                "big": 2**100,
+                1: "int key",
+            }
+        )
+        assert zodb_json_codec.verify_roundtrip(record) == {
+            "equal": True,
+            "path": None,
+            "reason": None,
+        }
+
+    def test_persistent_refs(self):
+        class P:
+            def __init__(self, oid):
+                self.oid = oid
+
+        class Pickler(pickle.Pickler):
+            def persistent_id(self, obj):
+                if isinstance(obj, P):
+                    return (obj.oid, None)
+                return None
+
+        buf = io.BytesIO()
+        pickle.Pickler(buf, protocol=3).dump(("myapp", "Doc"))
+        Pickler(buf, protocol=3).dump({"child": P(b"\x00" * 7 + b"\x05")})
+        assert zodb_json_codec.verify_roundtrip(buf.getvalue())["equal"]
+
+    def test_btree(self):
+        record = make_record(
+            ((("a", 1, "b", 2),),), module="BTrees.OOBTree", name="OOBTree"
+        )
+        assert zodb_json_codec.verify_roundtrip(record)["equal"]
+
+    def test_nan_is_kept(self):
+        record = make_record({"ok": 1.5, "x": [float("nan"), float("-inf")]})
+        assert zodb_json_codec.verify_roundtrip(record)["equal"]
+
+    def test_nan_is_lost_as_null(self):
+        result = zodb_json_codec.verify_roundtrip(
+            make_record({"ok": 1.5, "x": [float("nan")]}), nonfinite_floats="null"
+        )
+        assert result == {
+            "equal": False,
+            "path": "/@s/x/0",
+            "reason": "null instead of NaN",
+        }
+
+    def test_null_bytes_survive(self):
+        record = make_record({"s": "a\x00b", "k\x00": 1})
+        assert zodb_json_codec.verify_roundtrip(record)["equal"]
+
+    def test_ns_key_is_lost(self):
+        # A plain key that reads as the @ns: form of "a\x00b"
+        result = zodb_json_codec.verify_roundtrip(make_record({"@ns:YQBi": 1}))
+        assert result == {
+            "equal": False,
+            "path": "/@s/@ns:YQBi",
+            "reason": "key missing after the round trip",
+        }
+
+    def test_invalid_record(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.verify_roundtrip(b"not a pickle")
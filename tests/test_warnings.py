@@ -0,0 +1,75 @@
+"""Warnings for lossy or fallback conversions (warnings= argument)."""
+
+import pickle
+import zodb_json_codec
+
+
+# myapp.make() has no typed marker
+REDUCE = b"\x80\x03cmyapp\nmake\n)R."
+
+
+def make_record(state_pickle):
+    return pickle.dumps(("myapp", "Doc"), protocol=3) + state_pickle
+
+
+def codes(warnings):
+    return [(w["code"], w["count"]) for w in warnings]
+
+
+def test_no_warnings_for_plain_data():
+    warnings = []
+    zodb_json_codec.pickle_to_dict(pickle.dumps({"a": [1, "x"]}, protocol=3), warnings=warnings)
+    assert warnings == []
+
+
+def test_reduce():
+    for decode in (zodb_json_codec.pickle_to_dict, zodb_json_codec.pickle_to_json):
+        warnings = []
+        decode(REDUCE, warnings=warnings)
+        assert warnings == [
+            {
+                "code": "reduce",
+                "message": "no typed marker for myapp.make, kept as @reduce",
+                "count": 1,
+            }
+        ]
+
+
+def test_anonymous_instance():
+    # EMPTY_LIST EMPTY_DICT BUILD: BUILD on something other than a class
+    warnings = []
+    result = zodb_json_codec.pickle_to_dict(b"\x80\x03]}b.", warnings=warnings)
+    assert "@inst" in result
+    assert codes(warnings) == [("anonymous-instance", 1)]
+
+
+def test_raw_pickle():
+    state = b"\x80\x03}X\x01\x00\x00\x00a\xff."
+    warnings = []
+    result = zodb_json_codec.decode_zodb_record(
+        make_record(state), warnings=warnings, lenient=True
+    )
+    assert "@pkl" in result["@s"]
+    assert codes(warnings) == [("raw-pickle", 1)]
+    assert "unknown pickle opcode: 0xff" in warnings[0]["message"]
+
+
+def test_null_bytes_only_for_pg():
+    record = make_record(pickle.dumps({"a": "x\0", "b": ["y\0"]}, protocol=3))
+    warnings = []
+    zodb_json_codec.decode_zodb_record(record, warnings=warnings)
+    assert warnings == []
+    for decode in (
+        zodb_json_codec.decode_zodb_record_for_pg,
+        zodb_json_codec.decode_zodb_record_for_pg_json,
+    ):
+        warnings = []
+        decode(record, warnings=warnings)
+        assert codes(warnings) == [("null-bytes", 2)]
+
+
+def test_record_state():
+    warnings = []
+    zodb_json_codec.decode_zodb_state(make_record(REDUCE), warnings=warnings)
+    assert codes(warnings) == [("reduce", 1)]
+
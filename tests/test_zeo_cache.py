@@ -0,0 +1,80 @@
+"""Test reading ZEO client cache files (read_zeo_cache)."""
+
+import pickle
+import struct
+import pytest
+import zodb_json_codec
+
+
+Z64 = b"\x00" * 8
+
+
+def p64(n):
+    return struct.pack(">Q", n)
+
+
+def make_record(module, classname, state):
+    return pickle.dumps((module, classname), protocol=3) + pickle.dumps(
+        state, protocol=3
+    )
+
+
+def cache_block(oid, start_tid, end_tid, data):
+    # Same layout as ZEO.cache.ClientCache._store
+    size = 43 + len(data)
+    return (
+        b"a"
+        + struct.pack(">I8s8s8sHI", size, oid, start_tid, end_tid, 0, len(data))
+        + data
+        + oid
+    )
+
+
+def write_cache(path, blocks, last_tid=p64(9)):
+    with open(path, "wb") as f:
+        f.write(b"ZEC3" + last_tid)
+        for block in blocks:
+            f.write(block)
+    return str(path)
+
+
+class TestReadZeoCache:
+    def test_records(self, tmp_path):
+        current = make_record("myapp", "Doc", {"title": "new"})
+        old = make_record("myapp", "Doc", {"title": "old"})
+        path = write_cache(
+            tmp_path / "1.zec",
+            [
+                cache_block(p64(1), p64(5), Z64, current),
+                b"f" + struct.pack(">I", 10) + b"\x00" * 5,
+                b"3\x00\x00",
+                cache_block(p64(1), p64(2), p64(5), old),
+            ],
+        )
+        records = zodb_json_codec.read_zeo_cache(path)
+        assert records == [
+            {"oid": p64(1), "start_tid": p64(5), "end_tid": None, "data": current},
+            {"oid": p64(1), "start_tid": p64(2), "end_tid": p64(5), "data": old},
+        ]
+
+    def test_decode(self, tmp_path):
+        data = make_record("myapp", "Doc", {"title": "Hello"})
+        path = write_cache(tmp_path / "1.zec", [cache_block(p64(7), p64(3), Z64, data)])
+        [record] = zodb_json_codec.read_zeo_cache(path, decode=True)
+        assert "data" not in record
+        assert record["record"] == {"@cls": ["myapp", "Doc"], "@s": {"title": "Hello"}}
+
+    def test_empty_cache(self, tmp_path):
+        assert zodb_json_codec.read_zeo_cache(write_cache(tmp_path / "1.zec", [])) == []
+
+    def test_not_a_cache_file(self, tmp_path):
+        path = tmp_path / "x.zec"
+        path.write_bytes(b"FS21" + b"\x00" * 20)
+        with pytest.raises(ValueError, match="ZEC3"):
+            zodb_json_codec.read_zeo_cache(str(path))
+
+    def test_truncated_block(self, tmp_path):
+        block = cache_block(p64(1), p64(1), Z64, make_record("m", "C", {}))
+        path = write_cache(tmp_path / "1.zec", [block[:-4]])
+        with pytest.raises(ValueError, match="offset 12"):
+            zodb_json_codec.read_zeo_cache(path)
@@ -88,10 +88,10 @@ class TestDecodeZodbRecord:
         assert result["@s"]["@t"][2] == "UTC"
 
     def test_scalar_state(self):
-        """BTrees.Length stores just an integer as state."""
-        record = make_zodb_record("BTrees.Length", "Length", 42)
+        """A class whose __getstate__ returns just an integer."""
+        record = make_zodb_record("myapp", "Counter", 42)
         result = zodb_json_codec.decode_zodb_record(record)
-        assert result["@cls"] == ["BTrees.Length", "Length"]
+        assert result["@cls"] == ["myapp", "Counter"]
         assert result["@s"] == 42
 
     def test_none_values_in_state(self):
@@ -182,8 +182,9 @@ class TestRealZODB:
         result = zodb_json_codec.decode_zodb_record(data)
 
         assert result["@cls"] == ["persistent.mapping", "PersistentMapping"]
-        assert result["@s"]["data"]["key"] == "value"
-        assert result["@s"]["data"]["num"] == 42
+        assert result["@s"] == {"@pmap": {"key": "value", "num": 42}}
+        re_encoded = zodb_json_codec.encode_zodb_record(result)
+        assert zodb_json_codec.decode_zodb_record(re_encoded) == result
 
     def test_persistent_list(self, zodb):
         from persistent.list import PersistentList
@@ -198,7 +199,7 @@ class TestRealZODB:
         result = zodb_json_codec.decode_zodb_record(data)
 
         assert result["@cls"] == ["persistent.list", "PersistentList"]
-        assert result["@s"]["data"] == [10, 20, 30]
+        assert result["@s"] == {"@plist": [10, 20, 30]}
 
     def test_persistent_reference_format(self, zodb):
         """Persistent refs should use compact hex oid format."""
@@ -215,7 +216,7 @@ class TestRealZODB:
         data, _ = db.storage.load(root._p_oid)
         result = zodb_json_codec.decode_zodb_record(data)
 
-        ref = result["@s"]["data"]["child"]["@ref"]
+        ref = result["@s"]["@pmap"]["child"]["@ref"]
         # ref should be a string (hex oid) or [hex_oid, class_path]
         if isinstance(ref, str):
             # oid-only ref: hex string, 16 chars for 8-byte oid
@@ -0,0 +1,60 @@
+"""Test state-only record functions (decode_zodb_state, encode_zodb_state)."""
+
+import pickle
+import pytest
+import zodb_json_codec
+
+
+def make_zodb_record(module, classname, state, protocol=3):
+    class_pickle = pickle.dumps((module, classname), protocol=protocol)
+    return class_pickle + pickle.dumps(state, protocol=protocol)
+
+
+class TestZodbState:
+    PMAP = {
+        "@cls": ["persistent.mapping", "PersistentMapping"],
+        "@s": {"@pmap": {"a": 1, "ref": {"@ref": "0000000000000003"}}},
+    }
+
+    def test_decode_matches_record(self):
+        record = make_zodb_record("myapp", "Doc", {"title": "Hello", "n": (1, 2)})
+        state = zodb_json_codec.decode_zodb_state(record)
+        assert state == zodb_json_codec.decode_zodb_record(record)["@s"]
+        assert state == {"title": "Hello", "n": {"@t": [1, 2]}}
+
+    def test_decode_container_state(self):
+        record = zodb_json_codec.encode_zodb_record(self.PMAP)
+        assert zodb_json_codec.decode_zodb_state(record) == self.PMAP["@s"]
+
+    def test_decode_binary_mode(self):
+        record = make_zodb_record("myapp", "Doc", {"data": b"\x00\x01"})
+        state = zodb_json_codec.decode_zodb_state(record, binary_mode=True)
+        assert state == {"data": {"@b": b"\x00\x01"}}
+
+    def test_encode_matches_record(self):
+        state = self.PMAP["@s"]
+        data = zodb_json_codec.encode_zodb_state(
+            "persistent.mapping", "PersistentMapping", state
+        )
+        assert data == zodb_json_codec.encode_zodb_record(self.PMAP)
+
+    def test_encode_keeps_class_pickle(self):
+        # Protocol 1, (module, name) form: encode_zodb_record would rewrite it
+        record = make_zodb_record("myapp", "Doc", {"title": "Old"}, protocol=1)
+        class_pickle = pickle.dumps(("myapp", "Doc"), protocol=1)
+        state = zodb_json_codec.decode_zodb_state(record)
+        state["title"] = "New"
+        data = zodb_json_codec.encode_zodb_state("myapp", "Doc", state, record=record)
+        assert data.startswith(class_pickle)
+        assert zodb_json_codec.decode_zodb_record(data) == {
+            "@cls": ["myapp", "Doc"],
+            "@s": {"title": "New"},
+        }
+
+    def test_encode_blob(self):
+        data = zodb_json_codec.encode_zodb_state("ZODB.blob", "Blob", None)
+        assert data == zodb_json_codec.encode_zodb_record({"@blob": True})
+
+    def test_encode_invalid_record(self):
+        with pytest.raises(ValueError):
+            zodb_json_codec.encode_zodb_state("myapp", "Doc", {}, record=b"\x80\x03")